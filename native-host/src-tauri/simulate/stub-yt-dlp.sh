@@ -0,0 +1,81 @@
+#!/bin/sh
+# Stands in for yt-dlp in extension simulations. The URL picks the behaviour:
+# .../slow runs until cancelled, .../fail fails like a missing video, and
+# anything else writes a small file the way a finished download would.
+# .../unicode titles the video in Japanese with an emoji, and .../latin1
+# prints its path in cp1252 the way a frozen yt-dlp.exe on a Western
+# Windows console does.
+
+if [ "$1" = "--version" ]; then
+    echo "2099.01.01-stub"
+    exit 0
+fi
+
+# The simulator checks these pids for orphans once the host has exited
+if [ -n "$IMGVAULT_SIM_PIDS" ]; then
+    echo $$ >> "$IMGVAULT_SIM_PIDS"
+fi
+
+url="$1"
+output=""
+while [ $# -gt 0 ]; do
+    if [ "$1" = "-o" ]; then
+        shift
+        output="$1"
+    fi
+    shift
+done
+
+echo "[generic] Extracting URL: $url"
+echo "[info] stub: Downloading 1 format(s): 0"
+
+case "$url" in
+    */fail*)
+        echo "ERROR: [generic] Unable to download webpage: HTTP Error 404: Not Found" >&2
+        exit 1
+        ;;
+esac
+
+title="stub"
+case "$url" in
+    */unicode*)
+        # What Python does with a title its pipe's code page cannot hold
+        if [ "$PYTHONIOENCODING" != "utf-8" ]; then
+            echo "ERROR: 'charmap' codec can't encode characters in position 0-4: character maps to <undefined>" >&2
+            exit 1
+        fi
+        title="東京タワー 🗼 夜景"
+        ;;
+    */latin1*)
+        title="Café"
+        ;;
+esac
+
+path=$(echo "$output" | sed -e "s/%(title)[^a-zA-Z]*s/$title/g" -e 's/%(id)[^a-zA-Z]*s/stub/g' -e 's/%(ext)s/mkv/g' -e 's/%([a-z_]*)[^a-zA-Z]*[a-zA-Z]/NA/g')
+echo "[download] Destination: $path"
+
+case "$url" in
+    */slow*)
+        percent=0
+        while true; do
+            echo "[download]  $percent.0% of 10.00MiB at 1.00MiB/s ETA 00:10"
+            percent=$(( (percent + 1) % 100 ))
+            sleep 0.2
+        done
+        ;;
+esac
+
+for percent in 25.0 50.0 75.0 100.0; do
+    echo "[download] $percent% of 4.00KiB at 1.00MiB/s ETA 00:00"
+done
+mkdir -p "$(dirname "$path")"
+head -c 4096 /dev/zero > "$path"
+case "$url" in
+    */latin1*)
+        # é as the single byte 0xE9
+        printf '%s\n' "$path" | LC_ALL=C sed "s/$(printf '\303\251')/$(printf '\351')/g"
+        ;;
+    *)
+        echo "$path"
+        ;;
+esac
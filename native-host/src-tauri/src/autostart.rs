@@ -0,0 +1,128 @@
+use serde::Serialize;
+use tracing::info;
+
+// Launch argument that starts the GUI hidden in the tray
+pub const MINIMIZED_FLAG: &str = "--minimized";
+#[cfg(target_os = "windows")]
+pub const RUN_KEY_PATH: &str = r"Software\Microsoft\Windows\CurrentVersion\Run";
+// Name of the Run value we own; installer entries use other names and are never touched
+#[cfg(target_os = "windows")]
+pub const RUN_VALUE_NAME: &str = "ImgVault Native Host (user)";
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AutostartStatus {
+    pub supported: bool,
+    // Whether ImgVault starts at login at all, through our entry or the installer's
+    pub enabled: bool,
+    // Our own entry, the only one set_autostart(false) removes
+    pub user_entry: bool,
+    // Where the installer registered its own autostart entry, if it did
+    pub installer_entry: Option<String>,
+}
+
+#[cfg(target_os = "windows")]
+pub fn get_autostart() -> Result<AutostartStatus, String> {
+    use winreg::enums::HKEY_CURRENT_USER;
+    use winreg::RegKey;
+
+    let user_entry = RegKey::predef(HKEY_CURRENT_USER)
+        .open_subkey(RUN_KEY_PATH)
+        .and_then(|key| key.get_value::<String, _>(RUN_VALUE_NAME))
+        .is_ok();
+    let installer_entry = find_installer_entry()?;
+
+    Ok(AutostartStatus {
+        supported: true,
+        enabled: user_entry || installer_entry.is_some(),
+        user_entry,
+        installer_entry,
+    })
+}
+
+#[cfg(target_os = "windows")]
+pub fn set_autostart(enabled: bool) -> Result<AutostartStatus, String> {
+    use winreg::enums::{HKEY_CURRENT_USER, KEY_READ, KEY_WRITE};
+    use winreg::RegKey;
+
+    let (key, _) = RegKey::predef(HKEY_CURRENT_USER)
+        .create_subkey_with_flags(RUN_KEY_PATH, KEY_READ | KEY_WRITE)
+        .map_err(|e| format!("Failed to open the Run registry key: {}", e))?;
+
+    if enabled {
+        // Starting twice would only show a second launch being forwarded, but
+        // the duplicate would confuse users looking at Task Manager
+        if let Some(location) = find_installer_entry()? {
+            info!("Autostart already managed by the installer ({}), not adding our own", location);
+        } else {
+            let exe = std::env::current_exe()
+                .map_err(|e| format!("Failed to resolve executable path: {}", e))?;
+            let command = format!("\"{}\" {}", exe.display(), MINIMIZED_FLAG);
+            key.set_value(RUN_VALUE_NAME, &command)
+                .map_err(|e| format!("Failed to enable run at login: {}", e))?;
+            info!("Enabled run at login");
+        }
+    } else {
+        match key.delete_value(RUN_VALUE_NAME) {
+            Ok(()) => info!("Disabled run at login"),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+            Err(error) => return Err(format!("Failed to disable run at login: {}", error)),
+        }
+    }
+
+    get_autostart()
+}
+
+// Run values, per-user or machine-wide, that start our executable under a name
+// we did not write; MSI and NSIS installers register those
+#[cfg(target_os = "windows")]
+fn find_installer_entry() -> Result<Option<String>, String> {
+    use winreg::enums::{HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE};
+    use winreg::{RegKey, HKEY};
+
+    let exe_name = std::env::current_exe()
+        .map_err(|e| format!("Failed to resolve executable path: {}", e))?
+        .file_name()
+        .map(|name| name.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    if exe_name.is_empty() {
+        return Ok(None);
+    }
+
+    let roots: [(HKEY, &str); 2] = [(HKEY_CURRENT_USER, "HKCU"), (HKEY_LOCAL_MACHINE, "HKLM")];
+    for (root, root_name) in roots {
+        let Ok(key) = RegKey::predef(root).open_subkey(RUN_KEY_PATH) else {
+            continue;
+        };
+        for (name, _) in key.enum_values().flatten() {
+            if root_name == "HKCU" && name == RUN_VALUE_NAME {
+                continue;
+            }
+            let Ok(command) = key.get_value::<String, _>(&name) else {
+                continue;
+            };
+            if command.to_ascii_lowercase().contains(&exe_name) {
+                return Ok(Some(format!(r"{}\{}\{}", root_name, RUN_KEY_PATH, name)));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+// XDG autostart and LaunchAgents follow once those builds ship
+#[cfg(not(target_os = "windows"))]
+pub fn get_autostart() -> Result<AutostartStatus, String> {
+    Ok(AutostartStatus {
+        supported: false,
+        enabled: false,
+        user_entry: false,
+        installer_entry: None,
+    })
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn set_autostart(_enabled: bool) -> Result<AutostartStatus, String> {
+    info!("Run at login requested on an unsupported platform");
+    Err("Run at login is only supported on Windows for now".to_string())
+}
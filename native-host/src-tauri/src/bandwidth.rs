@@ -0,0 +1,170 @@
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+use tracing::{debug, warn};
+
+use crate::settings::Settings;
+use crate::{current_timestamp_millis, timestamps};
+
+const MINUTES_PER_DAY: i64 = 24 * 60;
+const MINUTES_PER_WEEK: i64 = 7 * MINUTES_PER_DAY;
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+// One entry of the bandwidth schedule, in local time. The first window that
+// covers the current minute sets the limit; outside every window downloads
+// run at full speed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BandwidthWindow {
+    // Days the window starts on, e.g. ["mon", "fri"]; empty means every day
+    pub days: Vec<String>,
+    // HH:MM; an end at or before the start runs past midnight
+    pub start: String,
+    pub end: String,
+    // 0 lifts the limit, e.g. for a full speed night window inside a limited day
+    pub limit_bytes_per_second: u64,
+}
+
+impl Default for BandwidthWindow {
+    fn default() -> Self {
+        BandwidthWindow {
+            days: Vec::new(),
+            start: "09:00".to_string(),
+            end: "17:00".to_string(),
+            limit_bytes_per_second: 1024 * 1024,
+        }
+    }
+}
+
+// The limit in force right now and when it next changes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BandwidthStatus {
+    // None means full speed
+    pub limit_bytes_per_second: Option<u64>,
+    // RFC 3339; None when the limit never changes
+    pub next_change_at: Option<String>,
+    pub next_limit_bytes_per_second: Option<u64>,
+}
+
+pub fn validate(window: &BandwidthWindow) -> Result<(), String> {
+    parse_time_of_day(&window.start)?;
+    parse_time_of_day(&window.end)?;
+    if let Some(day) = window.days.iter().find(|day| day_index(day).is_none()) {
+        return Err(format!("Unknown day {}; use {}", day, DAY_NAMES.join(", ")));
+    }
+    Ok(())
+}
+
+// Limit for a yt-dlp process starting now. yt-dlp cannot change its rate
+// mid-transfer, so a running job keeps the limit it started with.
+pub fn current_limit(settings: &Settings) -> Option<u64> {
+    limit_now(&settings.bandwidth_schedule)
+}
+
+// The image fetch child asks this throughout its transfer, so it follows the
+// schedule into the next window
+pub fn limit_now(windows: &[BandwidthWindow]) -> Option<u64> {
+    if windows.is_empty() {
+        return None;
+    }
+    let minute = minute_of_week(current_timestamp_millis(), timestamps::local_utc_offset_minutes());
+    limit_at(windows, minute)
+}
+
+pub fn status(settings: &Settings) -> BandwidthStatus {
+    let windows = &settings.bandwidth_schedule;
+    if windows.is_empty() {
+        return BandwidthStatus {
+            limit_bytes_per_second: None,
+            next_change_at: None,
+            next_limit_bytes_per_second: None,
+        };
+    }
+
+    let now = current_timestamp_millis();
+    let minute = minute_of_week(now, timestamps::local_utc_offset_minutes());
+    let limit = limit_at(windows, minute);
+    // A week of minutes is cheap to walk and gets every wrap-around right
+    let next_change = (1..=MINUTES_PER_WEEK)
+        .map(|ahead| (ahead, limit_at(windows, (minute + ahead) % MINUTES_PER_WEEK)))
+        .find(|(_, next_limit)| *next_limit != limit);
+    let start_of_minute = now - now.rem_euclid(60_000);
+    BandwidthStatus {
+        limit_bytes_per_second: limit,
+        next_change_at: next_change.map(|(ahead, _)| timestamps::format_rfc3339(start_of_minute + ahead * 60_000)),
+        next_limit_bytes_per_second: next_change.and_then(|(_, next_limit)| next_limit),
+    }
+}
+
+// Adds --limit-rate when the schedule limits the current minute
+pub fn add_limit_rate_argument(command: &mut Command, settings: &Settings, job_id: &str) {
+    if let Some(limit) = current_limit(settings) {
+        debug!(job_id, limit_bytes_per_second = limit, "Applying bandwidth schedule");
+        command.arg("--limit-rate").arg(limit.to_string());
+    }
+}
+
+// The image fetch child gets the whole schedule rather than today's limit
+pub fn add_image_fetch_argument(command: &mut Command, settings: &Settings) {
+    if settings.bandwidth_schedule.is_empty() {
+        return;
+    }
+    match serde_json::to_string(&settings.bandwidth_schedule) {
+        Ok(schedule) => {
+            command.arg("--bandwidth-schedule").arg(schedule);
+        }
+        Err(error) => warn!("Failed to pass the bandwidth schedule to the image fetch: {}", error),
+    }
+}
+
+fn limit_at(windows: &[BandwidthWindow], minute_of_week: i64) -> Option<u64> {
+    windows
+        .iter()
+        .find(|window| covers(window, minute_of_week))
+        .map(|window| window.limit_bytes_per_second)
+        .filter(|limit| *limit > 0)
+}
+
+fn covers(window: &BandwidthWindow, minute_of_week: i64) -> bool {
+    let (Ok(start), Ok(end)) = (parse_time_of_day(&window.start), parse_time_of_day(&window.end)) else {
+        return false;
+    };
+    let day = minute_of_week / MINUTES_PER_DAY;
+    let minute = minute_of_week % MINUTES_PER_DAY;
+    let starts_on = |day: i64| {
+        window.days.is_empty() || window.days.iter().any(|name| day_index(name) == Some(day))
+    };
+
+    if start < end {
+        starts_on(day) && (start..end).contains(&minute)
+    } else {
+        // Runs past midnight: the tail belongs to the previous day's window
+        (starts_on(day) && minute >= start) || (starts_on((day + 6) % 7) && minute < end)
+    }
+}
+
+// Minutes since Monday 00:00 local time
+fn minute_of_week(millis: i64, utc_offset_minutes: i64) -> i64 {
+    let local_minutes = millis.div_euclid(60_000) + utc_offset_minutes;
+    // 1970-01-01 was a Thursday
+    (local_minutes + 3 * MINUTES_PER_DAY).rem_euclid(MINUTES_PER_WEEK)
+}
+
+fn parse_time_of_day(value: &str) -> Result<i64, String> {
+    let invalid = || format!("{} is not a time of day such as 09:30", value);
+    let (hours, minutes) = value.trim().split_once(':').ok_or_else(invalid)?;
+    let hours = hours.parse::<i64>().map_err(|_| invalid())?;
+    let minutes = minutes.parse::<i64>().map_err(|_| invalid())?;
+    if !(0..24).contains(&hours) || !(0..60).contains(&minutes) {
+        return Err(invalid());
+    }
+    Ok(hours * 60 + minutes)
+}
+
+fn day_index(name: &str) -> Option<i64> {
+    let name = name.trim().to_ascii_lowercase();
+    DAY_NAMES
+        .iter()
+        .position(|day| name.starts_with(day))
+        .map(|index| index as i64)
+}
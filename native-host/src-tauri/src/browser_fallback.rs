@@ -0,0 +1,145 @@
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use tracing::info;
+
+use crate::downloader::{self, classify_download_error, Backend};
+use crate::history::{DownloadStatus, History, NewHistoryEntry};
+use crate::settings::Settings;
+use crate::source_page::SourcePage;
+use crate::{current_timestamp_millis, image_fetch, logging, long_path};
+
+// Starts the message of a download handed back to the extension, which
+// classifies as fallback_to_browser and is recorded as delegated
+pub const DELEGATED_PREFIX: &str = "Left to the browser: ";
+// History source of a file the browser downloaded after a fallback
+pub const BROWSER_SOURCE: &str = "browser";
+// What yt-dlp says of media it will never download
+const DRM_WORDS: &[&str] = &["drm", "protected content"];
+
+// The link and file name the extension passes to chrome.downloads.download
+pub struct BrowserFallback {
+    pub direct_url: String,
+    pub file_name: String,
+}
+
+// Whether a failure is one no backend gets past but the browser, with the
+// user's own session, still might. Network errors and the like are not:
+// retrying those here works just as well.
+pub fn declined(message: &str) -> bool {
+    if matches!(classify_download_error(message), "unsupported_url" | "auth_required" | "http_403") {
+        return true;
+    }
+    let message = message.to_lowercase();
+    DRM_WORDS.iter().any(|word| message.contains(word))
+}
+
+// The backend to try once the routed one declined `url`: the direct fetch,
+// unless that is what declined it. None when the request asked for a backend.
+pub fn next_backend(url: &str, settings: &Settings, requested: Option<Backend>) -> Option<Backend> {
+    if requested.is_some() {
+        return None;
+    }
+    match downloader::route(url, settings, None).backend() {
+        Backend::YtDlp | Backend::GalleryDl => Some(Backend::HttpImage),
+        Backend::HttpImage | Backend::Fake => None,
+    }
+}
+
+// `url` is the link the extension asked for, before any refresh from its page
+pub fn for_url(url: &str, source_page: &SourcePage) -> BrowserFallback {
+    info!(url = logging::loggable_url(url), "Every backend declined; leaving the download to the browser");
+    BrowserFallback {
+        direct_url: url.to_string(),
+        file_name: file_name(url, source_page),
+    }
+}
+
+// Adds directUrl and fileName to a response's data
+pub fn describe(fallback: &BrowserFallback, data: &mut Option<Value>) {
+    let fields = [
+        ("directUrl", Value::String(fallback.direct_url.clone())),
+        ("fileName", Value::String(fallback.file_name.clone())),
+    ];
+    match data {
+        Some(Value::Object(object)) => {
+            for (key, value) in fields {
+                object.insert(key.to_string(), value);
+            }
+        }
+        _ => *data = Some(Value::Object(fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect())),
+    }
+}
+
+// The path the extension reported must be a file the browser finished
+pub fn check_file(file_path: &str) -> Result<(), String> {
+    let path = Path::new(file_path);
+    if !path.is_absolute() {
+        return Err(format!("Not an absolute path: {}", file_path));
+    }
+    if !fs::metadata(long_path::extended(path)).is_ok_and(|metadata| metadata.is_file()) {
+        return Err(format!("No file at {}", file_path));
+    }
+    Ok(())
+}
+
+// Records the file the browser saved for a delegated download under the
+// same job, so the vault index learns of it like of any other
+pub fn register(
+    history: &History,
+    job_id: &str,
+    url: &str,
+    file_path: &str,
+    source_page: &SourcePage,
+) -> Result<i64, String> {
+    let now = current_timestamp_millis();
+    let entry = NewHistoryEntry {
+        job_id,
+        url,
+        file_path: Some(file_path),
+        status: DownloadStatus::Completed,
+        message: Some("Downloaded by the browser"),
+        source: BROWSER_SOURCE,
+        started_at: now,
+        finished_at: now,
+        object_key: None,
+        etag: None,
+        output_path: None,
+        upload: false,
+        page_url: source_page.page_url.as_deref(),
+        page_title: source_page.page_title.as_deref(),
+        media_info: None,
+        transfer: None,
+        session_id: logging::session_id(),
+        supervision: None,
+    };
+    history.record(&entry)
+}
+
+// The URL's own file name when it has one; a page gets its title instead,
+// and no extension, which the browser takes from the response
+fn file_name(url: &str, source_page: &SourcePage) -> String {
+    let (stem, extension) = image_fetch::url_file_name(url, "");
+    if has_extension(url) {
+        return format!("{}.{}", stem, extension);
+    }
+    source_page
+        .page_title
+        .as_deref()
+        .map(image_fetch::sanitize)
+        .filter(|title| !title.is_empty())
+        .unwrap_or(stem)
+}
+
+fn has_extension(url: &str) -> bool {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let Some((_, path)) = path.split_once("://").and_then(|(_, rest)| rest.split_once('/')) else {
+        return false;
+    };
+    path.rsplit('/')
+        .next()
+        .and_then(|name| name.rsplit_once('.'))
+        .is_some_and(|(_, extension)| {
+            (1..=5).contains(&extension.len()) && extension.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+}
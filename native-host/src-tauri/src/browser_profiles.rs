@@ -0,0 +1,248 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::settings::Settings;
+use crate::NATIVE_HOST_NAME;
+
+// The name in the extension's manifest.json, old and new extension alike
+const EXTENSION_NAME: &str = "ImgVault";
+// Label of the folders listed in the browser_user_data_dirs setting
+const CUSTOM_BROWSER: &str = "custom";
+
+// User data folders of the browsers the host registers for
+#[cfg(target_os = "windows")]
+const USER_DATA_DIRS: &[(&str, &str)] = &[
+    ("chrome", r"Google\Chrome\User Data"),
+    ("edge", r"Microsoft\Edge\User Data"),
+    ("brave", r"BraveSoftware\Brave-Browser\User Data"),
+    ("chromium", r"Chromium\User Data"),
+];
+#[cfg(target_os = "macos")]
+const USER_DATA_DIRS: &[(&str, &str)] = &[
+    ("chrome", "Google/Chrome"),
+    ("edge", "Microsoft Edge"),
+    ("brave", "BraveSoftware/Brave-Browser"),
+    ("chromium", "Chromium"),
+];
+#[cfg(all(unix, not(target_os = "macos")))]
+const USER_DATA_DIRS: &[(&str, &str)] = &[
+    ("chrome", "google-chrome"),
+    ("edge", "microsoft-edge"),
+    ("brave", "BraveSoftware/Brave-Browser"),
+    ("chromium", "chromium"),
+];
+
+// A browser profile with the ImgVault extension installed
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileRegistration {
+    pub browser: String,
+    pub user_data_dir: String,
+    // Folder name such as "Default" or "Profile 2"
+    pub profile: String,
+    // The name shown in the browser's profile menu
+    pub profile_name: String,
+    pub extension_id: String,
+    // Whether the manifest the browser reads lists this extension's origin;
+    // None when no manifest is registered for the browser at all
+    pub allowed: Option<bool>,
+}
+
+impl ProfileRegistration {
+    // "Chrome Profile 2 (Work)", for messages
+    pub fn describe(&self) -> String {
+        if self.profile_name.is_empty() || self.profile_name == self.profile {
+            format!("{} {}", self.browser, self.profile)
+        } else {
+            format!("{} {} ({})", self.browser, self.profile, self.profile_name)
+        }
+    }
+}
+
+// Every profile of every known browser, and of the folders in
+// browser_user_data_dirs, that has the extension installed
+pub fn scan(settings: &Settings) -> Vec<ProfileRegistration> {
+    let mut user_data_dirs = known_user_data_dirs();
+    user_data_dirs.extend(
+        settings
+            .browser_user_data_dirs
+            .iter()
+            .map(|directory| (CUSTOM_BROWSER, PathBuf::from(directory))),
+    );
+
+    let mut found = Vec::new();
+    for (browser, user_data_dir) in user_data_dirs {
+        if !user_data_dir.is_dir() {
+            continue;
+        }
+        let allowed_origins = allowed_origins(browser, &user_data_dir);
+        for (profile, profile_name) in profiles(&user_data_dir) {
+            for extension_id in installed_extension_ids(&user_data_dir.join(&profile)) {
+                let origin = format!("chrome-extension://{}/", extension_id);
+                found.push(ProfileRegistration {
+                    browser: browser.to_string(),
+                    user_data_dir: user_data_dir.display().to_string(),
+                    profile: profile.clone(),
+                    profile_name: profile_name.clone(),
+                    allowed: allowed_origins.as_ref().map(|origins| origins.contains(&origin)),
+                    extension_id,
+                });
+            }
+        }
+    }
+    found
+}
+
+fn known_user_data_dirs() -> Vec<(&'static str, PathBuf)> {
+    let Some(base) = user_data_base() else {
+        return Vec::new();
+    };
+    USER_DATA_DIRS
+        .iter()
+        .map(|(browser, relative)| (*browser, base.join(relative)))
+        .collect()
+}
+
+#[cfg(target_os = "windows")]
+fn user_data_base() -> Option<PathBuf> {
+    env::var("LOCALAPPDATA").ok().map(PathBuf::from)
+}
+
+#[cfg(target_os = "macos")]
+fn user_data_base() -> Option<PathBuf> {
+    env::var("HOME")
+        .ok()
+        .map(|home| PathBuf::from(home).join("Library").join("Application Support"))
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn user_data_base() -> Option<PathBuf> {
+    env::var("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| env::var("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .ok()
+}
+
+fn read_json(path: &Path) -> Option<Value> {
+    serde_json::from_str(&fs::read_to_string(path).ok()?).ok()
+}
+
+// Profile folders with their display names, from Local State; folders named
+// like profiles stand in when it cannot be read
+fn profiles(user_data_dir: &Path) -> Vec<(String, String)> {
+    let from_local_state = read_json(&user_data_dir.join("Local State"))
+        .and_then(|state| state["profile"]["info_cache"].as_object().cloned())
+        .map(|cache| {
+            cache
+                .iter()
+                .map(|(profile, info)| (profile.clone(), info["name"].as_str().unwrap_or_default().to_string()))
+                .collect::<Vec<_>>()
+        });
+    if let Some(profiles) = from_local_state.filter(|profiles| !profiles.is_empty()) {
+        return profiles;
+    }
+
+    fs::read_dir(user_data_dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter(|entry| entry.path().join("Preferences").is_file())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .filter(|name| name == "Default" || name.starts_with("Profile "))
+                .map(|name| (name, String::new()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// IDs of the ImgVault extensions installed in a profile. Store installs are
+// listed in Secure Preferences and unpacked ones often only in Preferences.
+fn installed_extension_ids(profile_dir: &Path) -> Vec<String> {
+    let mut extensions = BTreeMap::new();
+    for file_name in ["Preferences", "Secure Preferences"] {
+        if let Some(Value::Object(settings)) =
+            read_json(&profile_dir.join(file_name)).map(|preferences| preferences["extensions"]["settings"].clone())
+        {
+            extensions.extend(settings);
+        }
+    }
+    extensions
+        .into_iter()
+        .filter(|(_, extension)| is_imgvault(profile_dir, extension))
+        .map(|(id, _)| id)
+        .collect()
+}
+
+fn is_imgvault(profile_dir: &Path, extension: &Value) -> bool {
+    if let Some(name) = extension["manifest"]["name"].as_str() {
+        return name == EXTENSION_NAME;
+    }
+    // Unpacked extensions have an absolute path, store installs one below
+    // the profile's Extensions folder
+    let Some(path) = extension["path"].as_str() else {
+        return false;
+    };
+    let directory = if Path::new(path).is_absolute() {
+        PathBuf::from(path)
+    } else {
+        profile_dir.join("Extensions").join(path)
+    };
+    read_json(&directory.join("manifest.json")).is_some_and(|manifest| manifest["name"].as_str() == Some(EXTENSION_NAME))
+}
+
+// Origins the native host manifest the browser finds allows. Chromium-based
+// browsers fall back to Chrome's registration; a custom folder may belong to
+// any of them, so every registered manifest counts.
+#[cfg(target_os = "windows")]
+fn allowed_origins(browser: &str, _user_data_dir: &Path) -> Option<Vec<String>> {
+    use winreg::enums::HKEY_CURRENT_USER;
+    use winreg::RegKey;
+
+    let hkcu = RegKey::predef(HKEY_CURRENT_USER);
+    let manifest_origins = |parent: &str| {
+        let manifest_path = hkcu
+            .open_subkey(format!(r"{}\{}", parent, NATIVE_HOST_NAME))
+            .ok()?
+            .get_value::<String, _>("")
+            .ok()?;
+        manifest_allowed_origins(Path::new(&manifest_path))
+    };
+    let registered = crate::registration::BROWSER_REGISTRY_PATHS
+        .iter()
+        .filter_map(|(name, parent)| manifest_origins(parent).map(|origins| (*name, origins)))
+        .collect::<Vec<_>>();
+
+    if browser == CUSTOM_BROWSER {
+        return (!registered.is_empty()).then(|| registered.into_iter().flat_map(|(_, origins)| origins).collect());
+    }
+    registered
+        .iter()
+        .find(|(name, _)| *name == browser)
+        .or_else(|| registered.iter().find(|(name, _)| *name == "chrome"))
+        .map(|(_, origins)| origins.clone())
+}
+
+// Elsewhere each user data folder has a NativeMessagingHosts folder of its own
+#[cfg(not(target_os = "windows"))]
+fn allowed_origins(_browser: &str, user_data_dir: &Path) -> Option<Vec<String>> {
+    manifest_allowed_origins(
+        &user_data_dir
+            .join("NativeMessagingHosts")
+            .join(format!("{}.json", NATIVE_HOST_NAME)),
+    )
+}
+
+fn manifest_allowed_origins(manifest_path: &Path) -> Option<Vec<String>> {
+    let manifest = read_json(manifest_path)?;
+    Some(
+        manifest["allowed_origins"]
+            .as_array()?
+            .iter()
+            .filter_map(|origin| origin.as_str().map(str::to_string))
+            .collect(),
+    )
+}
@@ -0,0 +1,133 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+use crate::history::{History, HistoryEntry};
+use crate::settings::{
+    changed_fields, upgrade_settings_value, SettingsError, SettingsStore, PATH_FIELDS, SECRET_FIELDS,
+};
+
+// Single-file export of everything needed to set up ImgVault on another machine
+const BUNDLE_FORMAT: &str = "imgvault-settings";
+const BUNDLE_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SettingsBundle {
+    format: String,
+    schema_version: u32,
+    exported_at: i64,
+    settings: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    history: Option<Vec<HistoryEntry>>,
+}
+
+pub struct ExportOptions {
+    pub include_history: bool,
+    pub include_secrets: bool,
+}
+
+pub struct ImportOptions {
+    // Vault and tool paths rarely exist on the other machine
+    pub import_paths: bool,
+    pub import_history: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportSummary {
+    pub changed_fields: Vec<String>,
+    pub skipped_fields: Vec<String>,
+    pub history_imported: usize,
+}
+
+pub fn export_bundle(
+    settings: &SettingsStore,
+    history: &History,
+    path: &Path,
+    options: &ExportOptions,
+) -> Result<(), String> {
+    let mut settings_value = serde_json::to_value(settings.base())
+        .map_err(|e| format!("Failed to serialize settings: {}", e))?;
+    if !options.include_secrets {
+        if let Some(object) = settings_value.as_object_mut() {
+            for field in SECRET_FIELDS {
+                object.remove(*field);
+            }
+        }
+    }
+
+    let bundle = SettingsBundle {
+        format: BUNDLE_FORMAT.to_string(),
+        schema_version: BUNDLE_SCHEMA_VERSION,
+        exported_at: crate::current_timestamp_millis(),
+        settings: settings_value,
+        history: if options.include_history { Some(history.all()?) } else { None },
+    };
+
+    let contents = serde_json::to_string_pretty(&bundle)
+        .map_err(|e| format!("Failed to serialize settings export: {}", e))?;
+    fs::write(path, contents)
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+pub fn import_bundle(
+    settings: &SettingsStore,
+    history: &History,
+    path: &Path,
+    options: &ImportOptions,
+) -> Result<ImportSummary, SettingsError> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| SettingsError::new(format!("Failed to read {}: {}", path.display(), e)))?;
+    let bundle = parse_bundle(&contents).map_err(SettingsError::new)?;
+
+    let (settings_value, _) = upgrade_settings_value(bundle.settings).map_err(|e| {
+        SettingsError::new(format!("Settings in {} cannot be imported: {}", path.display(), e))
+    })?;
+    let Value::Object(mut patch) = settings_value else {
+        return Err(SettingsError::new("Settings section of the export is not an object"));
+    };
+
+    let mut skipped_fields = Vec::new();
+    if !options.import_paths {
+        for field in PATH_FIELDS {
+            if patch.remove(*field).is_some() {
+                skipped_fields.push(field.to_string());
+            }
+        }
+    }
+
+    let before = settings.base();
+    let after = settings.update(Value::Object(patch))?;
+
+    let history_imported = match (&bundle.history, options.import_history) {
+        (Some(entries), true) => history.import(entries).map_err(SettingsError::new)?,
+        _ => 0,
+    };
+
+    Ok(ImportSummary {
+        changed_fields: changed_fields(&before, &after),
+        skipped_fields,
+        history_imported,
+    })
+}
+
+fn parse_bundle(contents: &str) -> Result<SettingsBundle, String> {
+    let value = serde_json::from_str::<Value>(contents)
+        .map_err(|e| format!("Not a valid ImgVault settings export: {}", e))?;
+
+    if value.get("format").and_then(Value::as_str) != Some(BUNDLE_FORMAT) {
+        return Err("Not an ImgVault settings export".to_string());
+    }
+
+    let version = value.get("schemaVersion").and_then(Value::as_u64).unwrap_or(0);
+    if version == 0 || version > BUNDLE_SCHEMA_VERSION as u64 {
+        return Err(format!(
+            "This export uses format version {}, but this version of ImgVault only understands version {}. Update ImgVault on this machine and try again.",
+            version, BUNDLE_SCHEMA_VERSION
+        ));
+    }
+
+    serde_json::from_value(value).map_err(|e| format!("Settings export is damaged: {}", e))
+}
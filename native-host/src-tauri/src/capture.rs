@@ -0,0 +1,196 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::{info, warn};
+
+use crate::protocol::IncomingFrame;
+use crate::settings::Settings;
+use crate::{current_timestamp_millis, get_app_data_directory, redact};
+
+// `--native --capture <file>` records one session whatever the settings say
+pub const CAPTURE_FLAG: &str = "--capture";
+const CAPTURE_DIRECTORY_NAME: &str = "captures";
+const CAPTURE_EXTENSION: &str = ".jsonl";
+const KEEP_CAPTURES: usize = 20;
+// Bumped when the line format changes
+const CAPTURE_FORMAT: u32 = 1;
+const REDACTED: &str = "<redacted>";
+
+// Every frame of a native messaging session, one JSON line each, for bug
+// reports and --replay. The first line describes the session; each other line
+// has the milliseconds since it started, the direction and the frame after
+// redaction. Inbound frames the host could not read have `invalid` instead.
+#[derive(Clone)]
+pub struct Capture {
+    file: Arc<Mutex<File>>,
+    started: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    In,
+    Out,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Entry {
+    pub ms: u64,
+    pub direction: Direction,
+    pub frame: Option<Value>,
+    pub invalid: Option<String>,
+}
+
+impl Capture {
+    // None unless asked for on the command line or in settings; a capture that
+    // cannot be opened never stops the session
+    pub fn start(args: &[String], settings: &Settings, session_id: &str) -> Option<Capture> {
+        let path = match args.iter().position(|arg| arg == CAPTURE_FLAG) {
+            Some(index) => match args.get(index + 1) {
+                Some(path) => PathBuf::from(path),
+                None => {
+                    warn!("Missing path after {}; not capturing this session", CAPTURE_FLAG);
+                    return None;
+                }
+            },
+            None if settings.capture_sessions => match capture_directory() {
+                Ok(directory) => {
+                    prune(&directory);
+                    directory.join(format!("{}-{}{}", current_timestamp_millis(), session_id, CAPTURE_EXTENSION))
+                }
+                Err(error) => {
+                    warn!("Failed to find the capture folder: {}", error);
+                    return None;
+                }
+            },
+            None => return None,
+        };
+        match Capture::create(&path, session_id) {
+            Ok(capture) => {
+                info!(path = %path.display(), "Capturing the native messaging session");
+                Some(capture)
+            }
+            Err(error) => {
+                warn!("{}", error);
+                None
+            }
+        }
+    }
+
+    fn create(path: &Path, session_id: &str) -> Result<Capture, String> {
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).map_err(|e| format!("Failed to create capture folder: {}", e))?;
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(|e| format!("Failed to create capture {}: {}", path.display(), e))?;
+        let header = json!({
+            "capture": CAPTURE_FORMAT,
+            "version": env!("CARGO_PKG_VERSION"),
+            "platform": std::env::consts::OS,
+            "session": session_id,
+            "startedAt": current_timestamp_millis(),
+        });
+        writeln!(file, "{}", header).map_err(|e| format!("Failed to write capture {}: {}", path.display(), e))?;
+        Ok(Capture {
+            file: Arc::new(Mutex::new(file)),
+            started: Instant::now(),
+        })
+    }
+
+    pub fn record_in(&self, frame: &IncomingFrame) {
+        match frame {
+            IncomingFrame::Message(text) => self.record(Direction::In, "frame", redacted_frame(text)),
+            IncomingFrame::Invalid(reason) => self.record(Direction::In, "invalid", Value::String(reason.clone())),
+        }
+    }
+
+    // A frame as written, without its length prefix
+    pub fn record_out(&self, frame: &[u8]) {
+        self.record(Direction::Out, "frame", redacted_frame(&String::from_utf8_lossy(frame)));
+    }
+
+    fn record(&self, direction: Direction, key: &str, value: Value) {
+        let direction = match direction {
+            Direction::In => "in",
+            Direction::Out => "out",
+        };
+        let line = json!({
+            "ms": self.started.elapsed().as_millis() as u64,
+            "direction": direction,
+            key: value,
+        });
+        // One write per line, so a crash loses at most the frame being written
+        let mut file = self.file.lock().unwrap();
+        if let Err(error) = file.write_all(format!("{}\n", line).as_bytes()) {
+            warn!("Failed to write to the capture: {}", error);
+        }
+    }
+}
+
+pub fn capture_directory() -> Result<PathBuf, String> {
+    Ok(get_app_data_directory()?.join(CAPTURE_DIRECTORY_NAME))
+}
+
+// The frames of a capture in the order they crossed the port
+pub fn read(path: &Path) -> Result<Vec<Entry>, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("Failed to read capture: {}", e))?;
+    let mut lines = content.lines().filter(|line| !line.trim().is_empty());
+    let header: Value = lines
+        .next()
+        .ok_or("The capture is empty")
+        .and_then(|line| serde_json::from_str(line).map_err(|_| "The capture has no header"))?;
+    match header.get("capture").and_then(Value::as_u64) {
+        Some(format) if format == CAPTURE_FORMAT as u64 => {}
+        Some(format) => return Err(format!("Capture format {} is not supported", format)),
+        None => return Err("The file is not a native messaging capture".to_string()),
+    }
+    lines
+        .enumerate()
+        .map(|(index, line)| {
+            serde_json::from_str(line).map_err(|e| format!("Failed to parse capture line {}: {}", index + 2, e))
+        })
+        .collect()
+}
+
+// Redacted as text, then cookie values, which look like nothing in particular.
+// Frames that are not JSON are kept as a string.
+fn redacted_frame(text: &str) -> Value {
+    let redacted = redact::redact(text);
+    let Ok(mut frame) = serde_json::from_str::<Value>(&redacted) else {
+        return Value::String(redacted.into_owned());
+    };
+    if let Some(Value::Array(cookies)) = frame.get_mut("cookies_data") {
+        for cookie in cookies.iter_mut().filter_map(Value::as_object_mut) {
+            if cookie.contains_key("value") {
+                cookie.insert("value".to_string(), Value::String(REDACTED.to_string()));
+            }
+        }
+    }
+    frame
+}
+
+// Keeps the newest KEEP_CAPTURES; the timestamps in the names sort oldest first
+fn prune(directory: &Path) {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return;
+    };
+    let mut paths = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.to_string_lossy().ends_with(CAPTURE_EXTENSION))
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths.reverse();
+    // The one about to be created counts too
+    for path in paths.iter().skip(KEEP_CAPTURES.saturating_sub(1)) {
+        let _ = fs::remove_file(path);
+    }
+}
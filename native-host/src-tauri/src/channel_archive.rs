@@ -0,0 +1,286 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::process::Command;
+use tracing::{info, warn};
+
+use crate::dispatcher::Priority;
+use crate::downloader::DownloadContext;
+use crate::history::{ChannelSubscription, History};
+use crate::queue::generate_job_id;
+use crate::schedule::{self, ScheduledDownload};
+use crate::settings::Settings;
+use crate::source_page::SourcePage;
+use crate::{console_text, current_timestamp_millis, domain_policy, profiles, timestamps, tool_integrity, validate_download_url};
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+// One video or post of a channel, as --flat-playlist lists it
+#[derive(Debug, Clone, Deserialize)]
+struct FlatEntry {
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    url: Option<String>,
+    #[serde(default)]
+    webpage_url: Option<String>,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    ie_key: Option<String>,
+    // YYYYMMDD; flat listings often only have the timestamp, or neither
+    #[serde(default)]
+    upload_date: Option<String>,
+    #[serde(default)]
+    timestamp: Option<i64>,
+}
+
+impl FlatEntry {
+    fn link(&self) -> Option<&str> {
+        self.webpage_url.as_deref().or(self.url.as_deref())
+    }
+
+    fn upload_day(&self) -> Option<String> {
+        self.upload_date.clone().or_else(|| {
+            self.timestamp.map(|timestamp| {
+                let (year, month, day) = timestamps::civil_from_days(timestamp.div_euclid(SECONDS_PER_DAY));
+                format!("{:04}{:02}{:02}", year, month, day)
+            })
+        })
+    }
+
+    // The line yt-dlp's --download-archive records for it
+    fn archive_id(&self) -> Option<String> {
+        Some(format!("{} {}", self.ie_key.as_deref()?.to_ascii_lowercase(), self.id.as_deref()?))
+    }
+}
+
+// What archive_channel asks for
+pub struct ChannelRequest<'a> {
+    pub url: &'a str,
+    pub output_path: String,
+    pub max_items: Option<u32>,
+    // YYYYMMDD or YYYY-MM-DD
+    pub date_after: Option<&'a str>,
+    pub source: &'a str,
+    pub priority: Priority,
+}
+
+// The outcome of archive_channel; every queued job also gets its own
+// scheduled event
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelArchive {
+    pub batch_id: String,
+    pub subscription: ChannelSubscription,
+    // Items the channel listed within the cap and the date cutoff
+    pub found: usize,
+    // Of those, the ones downloaded, archived or queued before
+    pub known: usize,
+    pub queued: Vec<ScheduledDownload>,
+}
+
+// How many new items a subscription has, for check_subscriptions
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscriptionCheck {
+    pub subscription: ChannelSubscription,
+    pub new_items: usize,
+    pub error: Option<String>,
+}
+
+// "2024-03-01" and "20240301" both become "20240301"
+pub fn parse_date_after(value: &str) -> Result<String, String> {
+    let digits = value.trim().replace('-', "");
+    let valid = digits.len() == 8
+        && digits.chars().all(|c| c.is_ascii_digit())
+        && (1..=12).contains(&digits[4..6].parse::<u32>().unwrap_or(0))
+        && (1..=31).contains(&digits[6..8].parse::<u32>().unwrap_or(0));
+    if valid {
+        Ok(digits)
+    } else {
+        Err(format!("Invalid date_after \"{}\": expected YYYY-MM-DD", value))
+    }
+}
+
+// Lists the channel's entries without downloading anything, newest first
+// as the site orders them, cut off after `max_items`
+fn list_entries(settings: &Settings, url: &str, max_items: Option<u32>) -> Result<Vec<FlatEntry>, String> {
+    tool_integrity::verify(settings.yt_dlp_program())?;
+    let mut command = Command::new(settings.yt_dlp_program());
+    command.arg(url).arg("--flat-playlist").arg("-J").arg("--no-warnings");
+    if let Some(max_items) = max_items {
+        command.arg("--playlist-end").arg(max_items.to_string());
+    }
+    console_text::force_utf8(&mut command);
+
+    #[cfg(target_os = "windows")]
+    {
+        use std::os::windows::process::CommandExt;
+        const CREATE_NO_WINDOW: u32 = 0x08000000;
+        command.creation_flags(CREATE_NO_WINDOW);
+    }
+
+    let output = command
+        .output()
+        .map_err(|e| format!("Failed to execute yt-dlp: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(stderr
+            .lines()
+            .rev()
+            .find(|line| line.starts_with("ERROR:"))
+            .map(|line| line.trim().to_string())
+            .unwrap_or_else(|| format!("yt-dlp returned exit code {:?}", output.status.code())));
+    }
+    let listing: Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| format!("Failed to parse the channel listing: {}", e))?;
+
+    let mut entries = Vec::new();
+    flatten_entries(&listing, &mut entries);
+    if let Some(max_items) = max_items {
+        entries.truncate(max_items as usize);
+    }
+    Ok(entries)
+}
+
+// A channel page can list its tabs as playlists of their own
+fn flatten_entries(listing: &Value, entries: &mut Vec<FlatEntry>) {
+    let Some(items) = listing.get("entries").and_then(Value::as_array) else {
+        return;
+    };
+    for item in items {
+        if item.get("entries").is_some() {
+            flatten_entries(item, entries);
+        } else if let Ok(entry) = serde_json::from_value::<FlatEntry>(item.clone()) {
+            if entry.link().is_some() {
+                entries.push(entry);
+            }
+        }
+    }
+}
+
+// The subscription's entries within its cap and date cutoff
+fn subscribed_entries(settings: &Settings, subscription: &ChannelSubscription) -> Result<Vec<FlatEntry>, String> {
+    let entries = list_entries(settings, &subscription.url, subscription.max_items)?;
+    Ok(entries
+        .into_iter()
+        .filter(|entry| match (&subscription.date_after, entry.upload_day()) {
+            (Some(date_after), Some(day)) => day >= *date_after,
+            // Undated items are kept; the cap still bounds them
+            _ => true,
+        })
+        .collect())
+}
+
+// The entries nothing has downloaded, archived or queued yet, oldest first so
+// the vault fills in upload order
+fn new_entries(history: &History, settings: &Settings, entries: Vec<FlatEntry>) -> Result<Vec<FlatEntry>, String> {
+    let completed = history.completed_urls()?;
+    let archived = profiles::archived_ids(settings);
+    let queued = schedule::list()?
+        .into_iter()
+        .map(|download| download.url)
+        .collect::<HashSet<_>>();
+
+    Ok(entries
+        .into_iter()
+        .filter(|entry| {
+            let link = entry.link().unwrap_or_default();
+            !completed.contains(link)
+                && !queued.contains(link)
+                && !entry.archive_id().is_some_and(|id| archived.contains(&id))
+        })
+        .rev()
+        .collect())
+}
+
+// Queues every new item of the channel as one batch and remembers the
+// channel, so running it again only queues what was added since
+pub fn archive(history: &History, settings: &Settings, request: &ChannelRequest) -> Result<ChannelArchive, String> {
+    validate_download_url(request.url)?;
+    domain_policy::check(settings, request.url).map_err(|blocked| blocked.to_string())?;
+    let date_after = request.date_after.map(parse_date_after).transpose()?;
+    let now = current_timestamp_millis();
+    let mut subscription = match history.subscription(request.url)? {
+        Some(existing) => ChannelSubscription {
+            output_path: request.output_path.clone(),
+            max_items: request.max_items.or(existing.max_items),
+            date_after: date_after.or(existing.date_after.clone()),
+            ..existing
+        },
+        None => ChannelSubscription {
+            url: request.url.to_string(),
+            output_path: request.output_path.clone(),
+            max_items: request.max_items,
+            date_after,
+            created_at: now,
+            last_checked_at: None,
+        },
+    };
+
+    let listed = subscribed_entries(settings, &subscription)?;
+    let found = listed.len();
+    let entries = new_entries(history, settings, listed)?;
+    let batch_id = generate_job_id("channel");
+    let schedule_at = timestamps::format_rfc3339(now);
+    let mut queued = Vec::with_capacity(entries.len());
+    for (index, entry) in entries.iter().enumerate() {
+        let link = entry.link().unwrap_or_default();
+        if let Err(error) = validate_download_url(link) {
+            warn!(batch_id = %batch_id, "Skipping channel item: {}", error);
+            continue;
+        }
+        let job_id = format!("{}-{}", batch_id, index + 1);
+        let source_page = SourcePage::new(Some(subscription.url.clone()), entry.title.clone(), None);
+        let context = DownloadContext {
+            job_id: &job_id,
+            url: link,
+            output_path: &subscription.output_path,
+            upload: true,
+            priority: request.priority,
+            source_page: &source_page,
+            source: request.source,
+        };
+        match schedule::add(&context, &schedule_at) {
+            Ok(download) => queued.push(download),
+            Err(error) => warn!(job_id = %job_id, "Failed to queue channel item: {}", error),
+        }
+    }
+
+    subscription.last_checked_at = Some(now);
+    history.save_subscription(&subscription)?;
+    info!(batch_id = %batch_id, queued = queued.len(), "Archived channel");
+    Ok(ChannelArchive {
+        batch_id,
+        found,
+        known: found - entries.len(),
+        queued,
+        subscription,
+    })
+}
+
+// Lists every subscription again and counts what archive_channel would queue,
+// without queueing anything
+pub fn check_all(history: &History, settings: &Settings) -> Result<Vec<SubscriptionCheck>, String> {
+    Ok(history
+        .subscriptions()?
+        .into_iter()
+        .map(|subscription| {
+            let entries = subscribed_entries(settings, &subscription)
+                .and_then(|listed| new_entries(history, settings, listed));
+            match entries {
+                Ok(entries) => SubscriptionCheck {
+                    subscription,
+                    new_items: entries.len(),
+                    error: None,
+                },
+                Err(error) => SubscriptionCheck {
+                    subscription,
+                    new_items: 0,
+                    error: Some(error),
+                },
+            }
+        })
+        .collect())
+}
@@ -0,0 +1,150 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::process::Command;
+use tracing::debug;
+
+use crate::settings::Settings;
+
+// Starts with '[' so downloader::last_plain_line skips the line like yt-dlp's own
+const CHAPTERS_PREFIX: &str = "[imgvault:chapters] ";
+// Inside the per-video folder; the number keeps the pieces in order
+const CHAPTER_FILE_TEMPLATE: &str = "%(section_number)03d %(section_title)s.%(ext)s";
+// Left behind by an unfinished split
+const PARTIAL_EXTENSIONS: &[&str] = &["part", "ytdl", "temp"];
+
+// One chapter as yt-dlp's info dict has it
+#[derive(Debug, Clone, Deserialize)]
+struct Chapter {
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    start_time: Option<f64>,
+    #[serde(default)]
+    end_time: Option<f64>,
+}
+
+// A piece of a chapter-split download, reported in the completion payload and
+// recorded in history under the entry of the whole video
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChapterFile {
+    pub title: String,
+    pub file_path: String,
+    // Seconds into the whole video
+    pub start_time: Option<f64>,
+    pub end_time: Option<f64>,
+}
+
+// The settings with a request's split_chapters applied, like media_policy::for_request
+pub fn for_request(mut settings: Settings, split_chapters: Option<bool>) -> Settings {
+    if let Some(split_chapters) = split_chapters {
+        settings.split_chapters = split_chapters;
+    }
+    settings
+}
+
+// --split-chapters, with the pieces in a folder named like the whole video,
+// e.g. Vault/Title/001 Intro.mkv next to Vault/Title.mkv
+pub fn add_yt_dlp_arguments(command: &mut Command, settings: &Settings, output_path: &str) {
+    if !settings.split_chapters {
+        return;
+    }
+    command
+        .arg("--split-chapters")
+        .arg("-o")
+        .arg(format!("chapter:{}{}{}", template_stem(output_path), MAIN_SEPARATOR, CHAPTER_FILE_TEMPLATE))
+        .arg("--print")
+        .arg(format!("after_move:{}%(chapters)j", CHAPTERS_PREFIX));
+}
+
+// The output template without its extension, so the folder yt-dlp fills in
+// matches the name of the whole video
+fn template_stem(output_path: &str) -> &str {
+    if let Some(stem) = output_path.strip_suffix(".%(ext)s") {
+        return stem;
+    }
+    let name_start = output_path.rfind(['/', '\\']).map_or(0, |separator| separator + 1);
+    match output_path[name_start..].rfind('.') {
+        Some(dot) if dot > 0 => &output_path[..name_start + dot],
+        _ => output_path,
+    }
+}
+
+// The pieces of a finished download, in chapter order. Empty when splitting is
+// off; Err is a warning for the response when the video had no chapters or
+// they could not be found, in which case the whole video is the download.
+pub fn collect(settings: &Settings, stdout_text: &str, file_path: Option<&str>) -> Result<Vec<ChapterFile>, String> {
+    if !settings.split_chapters {
+        return Ok(Vec::new());
+    }
+    let chapters = parse(stdout_text);
+    if chapters.is_empty() {
+        return Err("The video has no chapters, so it was saved as one file".to_string());
+    }
+    let Some(file_path) = file_path else {
+        return Err("The chapter files could not be found; the video was saved as one file".to_string());
+    };
+
+    let pieces = pieces_in(&Path::new(file_path).with_extension(""));
+    if pieces.is_empty() {
+        return Err("The video could not be split into chapters; it was saved as one file".to_string());
+    }
+    if pieces.len() != chapters.len() {
+        debug!(chapters = chapters.len(), pieces = pieces.len(), "Chapter files do not match the chapter list");
+    }
+    Ok(pieces
+        .into_iter()
+        .enumerate()
+        .map(|(index, piece)| {
+            let chapter = chapters.get(index);
+            ChapterFile {
+                title: chapter
+                    .and_then(|chapter| chapter.title.clone())
+                    .unwrap_or_else(|| title_from_file_name(&piece)),
+                file_path: piece.display().to_string(),
+                start_time: chapter.and_then(|chapter| chapter.start_time),
+                end_time: chapter.and_then(|chapter| chapter.end_time),
+            }
+        })
+        .collect())
+}
+
+// yt-dlp prints NA or null for a video without chapters
+fn parse(stdout_text: &str) -> Vec<Chapter> {
+    stdout_text
+        .lines()
+        .rev()
+        .find_map(|line| line.trim().strip_prefix(CHAPTERS_PREFIX.trim_end()))
+        .and_then(|line| serde_json::from_str::<Option<Vec<Chapter>>>(line.trim()).ok())
+        .flatten()
+        .unwrap_or_default()
+}
+
+// Finished files in the chapter folder, sorted by their numbered names
+fn pieces_in(directory: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return Vec::new();
+    };
+    let mut pieces = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .filter(|path| {
+            !path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| PARTIAL_EXTENSIONS.contains(&extension))
+        })
+        .collect::<Vec<_>>();
+    pieces.sort();
+    pieces
+}
+
+// "003 Verse two.mkv" becomes "Verse two"
+fn title_from_file_name(path: &Path) -> String {
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    match stem.split_once(' ') {
+        Some((number, title)) if number.chars().all(|c| c.is_ascii_digit()) => title.to_string(),
+        _ => stem,
+    }
+}
@@ -0,0 +1,136 @@
+use std::future;
+use std::io;
+use std::process::ExitStatus;
+use std::sync::{Arc, OnceLock};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader, Split};
+use tokio::process::Child;
+use tokio::runtime::{Builder, Handle, Runtime};
+use tokio::sync::mpsc;
+use tokio::time::{self, MissedTickBehavior};
+use tracing::error;
+
+use crate::console_text;
+use crate::downloader::PAUSE_CHECK_INTERVAL;
+use crate::jobs::{JobHandle, JobRegistry};
+use crate::stall::Stall;
+
+// Lines waiting for the job loop; a port Chrome reads slowly holds the child
+// back on its pipe instead of piling its output up here
+pub const CHILD_EVENTS: usize = 256;
+
+// For downloads started outside a runtime, such as from the command line
+static FALLBACK_RUNTIME: OnceLock<Option<Runtime>> = OnceLock::new();
+
+pub enum ChildEvent {
+    // Stream name and line
+    Line(&'static str, String),
+    Stalled(Stall),
+}
+
+pub struct ChildExit {
+    pub status: io::Result<ExitStatus>,
+    // Everything the child wrote, for the outcome and failure details
+    pub stdout: String,
+    pub stderr: String,
+}
+
+// Reads both pipes of a download's child concurrently and hands each line to
+// the job loop, checking for a queue pause, the supervisor's verdict and a
+// stall between lines and while the child is quiet. Cancellation kills the
+// child by pid, which closes its pipes and ends this the same way as an exit.
+pub async fn supervise(
+    mut child: Child,
+    jobs: JobRegistry,
+    job: Option<Arc<JobHandle>>,
+    events: mpsc::Sender<ChildEvent>,
+) -> ChildExit {
+    let mut stdout = child.stdout.take().map(|pipe| BufReader::new(pipe).split(b'\n'));
+    let mut stderr = child.stderr.take().map(|pipe| BufReader::new(pipe).split(b'\n'));
+    let mut stdout_lines = Vec::new();
+    let mut stderr_lines = Vec::new();
+    let mut checks = time::interval(PAUSE_CHECK_INTERVAL);
+    checks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // Once the job loop is gone the pipes are still drained, so the child
+    // never blocks on a full one
+    let mut forwarding = true;
+
+    while stdout.is_some() || stderr.is_some() {
+        let event = tokio::select! {
+            segment = next_segment(&mut stdout) => {
+                read_line(segment, "stdout", &mut stdout, &mut stdout_lines)
+            }
+            segment = next_segment(&mut stderr) => {
+                read_line(segment, "stderr", &mut stderr, &mut stderr_lines)
+            }
+            _ = checks.tick() => {
+                job.as_deref().and_then(|job| {
+                    jobs.suspend_if_paused(job);
+                    jobs.check_supervisor(job);
+                    jobs.check_stall(job).map(ChildEvent::Stalled)
+                })
+            }
+        };
+        if let (Some(event), true) = (event, forwarding) {
+            forwarding = events.send(event).await.is_ok();
+        }
+    }
+
+    ChildExit {
+        status: child.wait().await,
+        stdout: stdout_lines.join("\n"),
+        stderr: stderr_lines.join("\n"),
+    }
+}
+
+// The runtime to read a child's pipes on: the caller's own, or a shared
+// one-worker runtime when it has none
+pub fn runtime() -> Result<Handle, String> {
+    if let Ok(handle) = Handle::try_current() {
+        return Ok(handle);
+    }
+    FALLBACK_RUNTIME
+        .get_or_init(|| match Builder::new_multi_thread().worker_threads(1).enable_all().build() {
+            Ok(runtime) => Some(runtime),
+            Err(error) => {
+                error!("Failed to start the download runtime: {}", error);
+                None
+            }
+        })
+        .as_ref()
+        .map(|runtime| runtime.handle().clone())
+        .ok_or_else(|| "Failed to start the download runtime".to_string())
+}
+
+// Never resolves for a pipe that has closed, so select! waits on the others
+async fn next_segment<R: AsyncRead + Unpin>(pipe: &mut Option<Split<BufReader<R>>>) -> io::Result<Option<Vec<u8>>> {
+    match pipe {
+        Some(pipe) => pipe.next_segment().await,
+        None => future::pending().await,
+    }
+}
+
+// Collects the line and returns it for the job loop unless it is blank;
+// closes the pipe at its end or on a read error
+fn read_line<R>(
+    segment: io::Result<Option<Vec<u8>>>,
+    stream: &'static str,
+    pipe: &mut Option<Split<BufReader<R>>>,
+    collected: &mut Vec<String>,
+) -> Option<ChildEvent> {
+    match segment {
+        Ok(Some(bytes)) => {
+            let line = console_text::decode(&bytes).trim_end_matches(['\r', '\n']).to_string();
+            collected.push(line.clone());
+            (!line.trim().is_empty()).then_some(ChildEvent::Line(stream, line))
+        }
+        Ok(None) => {
+            *pipe = None;
+            None
+        }
+        Err(error) => {
+            collected.push(format!("[ImgVault] Failed to read {}: {}", stream, error));
+            *pipe = None;
+            None
+        }
+    }
+}
@@ -0,0 +1,457 @@
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::db_backup::BACKUP_DIRECTORY;
+use crate::history::{History, HISTORY_FILE_NAME};
+use crate::profiles::{self, Profile, PROFILES_DIRECTORY};
+use crate::registration::{self, Artifact, ArtifactKind, ArtifactStatus};
+use crate::settings::{Settings, SETTINGS_FILE_NAME};
+use crate::{get_app_data_directory, logging, long_path, tool_integrity};
+
+// Registry artifacts are listed as HKCU\<path>
+const REGISTRY_PREFIX: &str = r"HKCU\";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CleanupItem {
+    #[serde(flatten)]
+    pub artifact: Artifact,
+    pub exists: bool,
+    // None for registry entries; a vault counts only the files history recorded
+    pub size_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CleanupReport {
+    pub success: bool,
+    pub items: Vec<CleanupItem>,
+}
+
+// How an item is removed
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+enum Target {
+    Registry(String),
+    Autostart,
+    ShellIntegration,
+    Path(PathBuf),
+    // The recorded files that are still there, inside the vault root
+    VaultFiles { root: PathBuf, files: Vec<PathBuf> },
+}
+
+// Everything ImgVault may have created, in the order perform_cleanup removes it
+pub fn preview(base: &Settings) -> Result<Vec<CleanupItem>, String> {
+    Ok(plan(base)?.into_iter().map(|(item, _)| item).collect())
+}
+
+// Removes every item preview lists, going on past failures so each one gets
+// its own result. The vault is only touched when `keep_vault` is false, and
+// then only the files history recorded in it.
+pub fn perform(base: &Settings, history: &History, keep_vault: bool) -> Result<CleanupReport, String> {
+    let mut planned = plan(base)?;
+    let mut released = false;
+    for (item, target) in planned.iter_mut() {
+        let result = if !item.exists {
+            Ok(ArtifactStatus::Missing)
+        } else if item.artifact.kind == ArtifactKind::Vault && keep_vault {
+            Ok(ArtifactStatus::Kept)
+        } else {
+            // SQLite keeps the file open, and Windows will not delete an open file
+            if item.artifact.kind == ArtifactKind::Database && !released {
+                released = true;
+                if let Err(error) = history.close() {
+                    warn!("{}", error);
+                }
+            }
+            // Same for the log file, which nothing is written to from here on
+            if item.artifact.kind == ArtifactKind::Logs {
+                info!("Closing the log file to delete it");
+                logging::close_file();
+            }
+            // Closing the database takes its -wal and -shm files with it
+            match target {
+                Target::Path(path) if fs::symlink_metadata(long_path::extended(path)).is_err() => {
+                    Ok(ArtifactStatus::Missing)
+                }
+                _ => remove(target).map(|()| ArtifactStatus::Removed),
+            }
+        };
+        match result {
+            Ok(status) => item.artifact.status = status,
+            Err(error) => {
+                warn!("{}", error);
+                item.artifact.status = ArtifactStatus::Failed;
+                item.artifact.error = Some(error);
+            }
+        }
+    }
+
+    let items = planned.into_iter().map(|(item, _)| item).collect::<Vec<_>>();
+    let success = !items.iter().any(|item| item.artifact.status == ArtifactStatus::Failed);
+    let removed = items.iter().filter(|item| item.artifact.status == ArtifactStatus::Removed).count();
+    info!(removed, keep_vault, success, "Cleaned up what ImgVault created");
+    Ok(CleanupReport { success, items })
+}
+
+// Registration's own artifacts, what the settings turn on outside the app
+// data folder, everything in it and the vault. New files in the app data
+// folder show up without being named here.
+fn plan(base: &Settings) -> Result<Vec<(CleanupItem, Target)>, String> {
+    let mut planned = Vec::new();
+    for artifact in registration::host_artifacts()? {
+        let target = match artifact.location.strip_prefix(REGISTRY_PREFIX) {
+            Some(key_path) => Target::Registry(key_path.to_string()),
+            None => Target::Path(PathBuf::from(&artifact.location)),
+        };
+        planned.push((describe(artifact, &target), target));
+    }
+    planned.extend(system_items()?);
+
+    let app_data = get_app_data_directory()?;
+    if let Ok(entries) = fs::read_dir(&app_data) {
+        let mut paths = entries.flatten().map(|entry| entry.path()).collect::<Vec<_>>();
+        paths.sort();
+        for path in paths {
+            let location = path.display().to_string();
+            if planned.iter().any(|(item, _)| item.artifact.location == location) {
+                continue;
+            }
+            let listed = artifact(app_data_kind(&path)?, location);
+            let target = Target::Path(path);
+            planned.push((describe(listed, &target), target));
+        }
+    }
+
+    for profile in profiles::list(base)? {
+        let Some(vault) = profile.vault_directory else {
+            continue;
+        };
+        let root = PathBuf::from(vault);
+        let files = recorded_files(&Profile::load(Some(profile.name.as_str()))?, &root)?;
+        let target = Target::VaultFiles { root: root.clone(), files };
+        planned.push((describe(artifact(ArtifactKind::Vault, root.display().to_string()), &target), target));
+    }
+
+    planned.sort_by_key(|(item, _)| order(item.artifact.kind));
+    Ok(planned)
+}
+
+// Browsers stop launching the host before its manifest goes; vault files are
+// found through history, so before the database; logs last, so this
+// cleanup is logged until the end
+fn order(kind: ArtifactKind) -> u8 {
+    match kind {
+        ArtifactKind::RegistryKey => 0,
+        ArtifactKind::UrlScheme | ArtifactKind::Autostart | ArtifactKind::ShellIntegration => 1,
+        ArtifactKind::Manifest => 2,
+        ArtifactKind::Vault => 3,
+        ArtifactKind::Settings | ArtifactKind::Database | ArtifactKind::ManagedTool | ArtifactKind::AppData => 4,
+        ArtifactKind::Logs => 5,
+    }
+}
+
+fn app_data_kind(path: &Path) -> Result<ArtifactKind, String> {
+    let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    Ok(if name == SETTINGS_FILE_NAME {
+        ArtifactKind::Settings
+    } else if name.starts_with(HISTORY_FILE_NAME) || name == BACKUP_DIRECTORY || name == PROFILES_DIRECTORY {
+        // With its -wal and -shm files; each profile keeps its own database
+        ArtifactKind::Database
+    } else if path == logging::get_log_directory()? {
+        ArtifactKind::Logs
+    } else if path == tool_integrity::tools_directory()? {
+        ArtifactKind::ManagedTool
+    } else {
+        ArtifactKind::AppData
+    })
+}
+
+fn artifact(kind: ArtifactKind, location: String) -> Artifact {
+    Artifact {
+        kind,
+        browser: None,
+        location,
+        required: true,
+        status: ArtifactStatus::Planned,
+        error: None,
+    }
+}
+
+fn describe(mut artifact: Artifact, target: &Target) -> CleanupItem {
+    artifact.status = ArtifactStatus::Planned;
+    let (exists, size_bytes) = match target {
+        Target::Registry(key_path) => (registry_key_exists(key_path), None),
+        // Only listed when there
+        Target::Autostart | Target::ShellIntegration => (true, None),
+        Target::Path(path) => match fs::symlink_metadata(long_path::extended(path)) {
+            Ok(_) => (true, Some(disk_size(path))),
+            Err(_) => (false, None),
+        },
+        Target::VaultFiles { files, .. } => (
+            !files.is_empty(),
+            Some(files.iter().map(|file| disk_size(file)).sum()),
+        ),
+    };
+    CleanupItem {
+        artifact,
+        exists,
+        size_bytes,
+    }
+}
+
+// Completed downloads of `profile` whose file is still in `root`; files
+// history does not know of, and whatever else the folder holds, stay
+fn recorded_files(profile: &Profile, root: &Path) -> Result<Vec<PathBuf>, String> {
+    // Opening a history creates it, and a profile without one has no files
+    if !profile.directory()?.join(HISTORY_FILE_NAME).is_file() {
+        return Ok(Vec::new());
+    }
+    let root = long_path::simplified(root);
+    let mut files = profile
+        .open_history()
+        .saved_files(None, None)?
+        .into_iter()
+        .map(|saved| long_path::simplified(Path::new(&saved.file_path)))
+        .filter(|path| path.starts_with(&root) && path != &root)
+        .filter(|path| fs::metadata(long_path::extended(path)).is_ok_and(|metadata| metadata.is_file()))
+        .collect::<Vec<_>>();
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+fn remove(target: &Target) -> Result<(), String> {
+    match target {
+        Target::Registry(key_path) => delete_registry_key(key_path),
+        Target::Autostart => crate::autostart::set_autostart(false).map(|_| ()),
+        Target::ShellIntegration => crate::shell_integration::remove_shell_integration().map(|_| ()),
+        Target::Path(path) => {
+            let extended = long_path::extended(path);
+            let removed = if extended.is_dir() {
+                fs::remove_dir_all(&extended)
+            } else {
+                fs::remove_file(&extended)
+            };
+            removed.map_err(|e| format!("Failed to delete {}: {}", path.display(), e))
+        }
+        Target::VaultFiles { root, files } => remove_vault_files(root, files),
+    }
+}
+
+// Folders the files leave empty go too, up to but never the vault root,
+// which may be the user's own Videos folder
+fn remove_vault_files(root: &Path, files: &[PathBuf]) -> Result<(), String> {
+    let mut errors = Vec::new();
+    for file in files {
+        if let Err(error) = fs::remove_file(long_path::extended(file)) {
+            errors.push(format!("{}: {}", file.display(), error));
+            continue;
+        }
+        let mut parent = file.parent();
+        while let Some(directory) = parent.filter(|directory| directory.starts_with(root) && *directory != root) {
+            if fs::remove_dir(long_path::extended(directory)).is_err() {
+                break;
+            }
+            parent = directory.parent();
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Failed to delete {} vault files: {}", errors.len(), errors.join("; ")))
+    }
+}
+
+fn disk_size(path: &Path) -> u64 {
+    let Ok(metadata) = fs::symlink_metadata(long_path::extended(path)) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    fs::read_dir(long_path::extended(path))
+        .map(|entries| entries.flatten().map(|entry| disk_size(&entry.path())).sum())
+        .unwrap_or_default()
+}
+
+// Our Run value and Explorer menu, when they are there
+#[cfg(target_os = "windows")]
+fn system_items() -> Result<Vec<(CleanupItem, Target)>, String> {
+    use crate::autostart::{get_autostart, RUN_KEY_PATH, RUN_VALUE_NAME};
+    use crate::shell_integration::{get_shell_integration, ASSOCIATIONS_KEY_PATH, VERB_KEY_NAME};
+
+    let mut items = Vec::new();
+    if get_autostart()?.user_entry {
+        let location = format!(r"{}{}\{}", REGISTRY_PREFIX, RUN_KEY_PATH, RUN_VALUE_NAME);
+        items.push((describe(artifact(ArtifactKind::Autostart, location), &Target::Autostart), Target::Autostart));
+    }
+    if !get_shell_integration()?.extensions.is_empty() {
+        let location = format!(r"{}{}\*\shell\{}", REGISTRY_PREFIX, ASSOCIATIONS_KEY_PATH, VERB_KEY_NAME);
+        items.push((
+            describe(artifact(ArtifactKind::ShellIntegration, location), &Target::ShellIntegration),
+            Target::ShellIntegration,
+        ));
+    }
+    Ok(items)
+}
+
+#[cfg(not(target_os = "windows"))]
+fn system_items() -> Result<Vec<(CleanupItem, Target)>, String> {
+    Ok(Vec::new())
+}
+
+#[cfg(target_os = "windows")]
+fn registry_key_exists(key_path: &str) -> bool {
+    use winreg::enums::HKEY_CURRENT_USER;
+    use winreg::RegKey;
+
+    RegKey::predef(HKEY_CURRENT_USER).open_subkey(key_path).is_ok()
+}
+
+#[cfg(not(target_os = "windows"))]
+fn registry_key_exists(_key_path: &str) -> bool {
+    false
+}
+
+#[cfg(target_os = "windows")]
+fn delete_registry_key(key_path: &str) -> Result<(), String> {
+    use winreg::enums::HKEY_CURRENT_USER;
+    use winreg::RegKey;
+
+    match RegKey::predef(HKEY_CURRENT_USER).delete_subkey_all(key_path) {
+        Ok(()) => Ok(()),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(error) => Err(format!("Failed to delete registry key {}{}: {}", REGISTRY_PREFIX, key_path, error)),
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn delete_registry_key(key_path: &str) -> Result<(), String> {
+    Err(format!("There is no registry to delete {} from", key_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::{DownloadStatus, NewHistoryEntry};
+    use crate::test_support::{self, AppData};
+
+    // Settings whose vault sits next to the app data folder, so only the
+    // vault item covers it, with a settings file and a log in the app data folder
+    fn installed(app_data: &AppData) -> (Settings, PathBuf) {
+        let vault = app_data.directory.with_file_name("cleanup-vault");
+        let _ = fs::remove_dir_all(&vault);
+        fs::write(app_data.directory.join(SETTINGS_FILE_NAME), "{}").expect("settings");
+        let logs = logging::get_log_directory().expect("log folder");
+        fs::create_dir_all(&logs).expect("log folder");
+        fs::write(logs.join("imgvault.log"), "a line").expect("log");
+        let base = Settings {
+            vault_root: Some(vault.display().to_string()),
+            ..Settings::default()
+        };
+        (base, vault)
+    }
+
+    // Saves a file at `path` and records it in `history`
+    fn download(history: &History, path: &Path) {
+        fs::create_dir_all(path.parent().expect("parent")).expect("folder");
+        fs::write(path, b"a picture").expect("download");
+        let file_path = path.display().to_string();
+        history
+            .record(&NewHistoryEntry {
+                job_id: &file_path,
+                url: "https://example.com/picture.png",
+                file_path: Some(&file_path),
+                status: DownloadStatus::Completed,
+                message: None,
+                source: "test",
+                started_at: 0,
+                finished_at: 0,
+                object_key: None,
+                etag: None,
+                output_path: None,
+                upload: false,
+                page_url: None,
+                page_title: None,
+                media_info: None,
+                transfer: None,
+                session_id: None,
+                supervision: None,
+            })
+            .expect("history entry");
+    }
+
+    fn statuses(report: &CleanupReport, kind: ArtifactKind) -> Vec<ArtifactStatus> {
+        report
+            .items
+            .iter()
+            .filter(|item| item.artifact.kind == kind)
+            .map(|item| item.artifact.status)
+            .collect()
+    }
+
+    #[test]
+    fn keeping_the_vault_removes_everything_else_and_no_vault_file() {
+        let app_data = test_support::app_data();
+        let (base, vault) = installed(&app_data);
+        let history = Profile::default_profile().open_history();
+        let picture = vault.join("Pictures").join("picture.png");
+        download(&history, &picture);
+
+        let report = perform(&base, &history, true).expect("cleanup");
+        assert!(report.success, "{:?}", report.items);
+        assert_eq!(statuses(&report, ArtifactKind::Vault), [ArtifactStatus::Kept]);
+        assert!(picture.is_file());
+        assert_eq!(fs::read_dir(&app_data.directory).expect("app data").count(), 0);
+    }
+
+    #[test]
+    fn the_vault_loses_only_recorded_files_and_logs_go_last() {
+        let app_data = test_support::app_data();
+        let (base, vault) = installed(&app_data);
+        let history = Profile::default_profile().open_history();
+        let picture = vault.join("Pictures").join("picture.png");
+        download(&history, &picture);
+        let own_file = vault.join("notes.txt");
+        fs::write(&own_file, "not a download").expect("own file");
+
+        let report = perform(&base, &history, false).expect("cleanup");
+        assert!(report.success, "{:?}", report.items);
+        assert_eq!(statuses(&report, ArtifactKind::Vault), [ArtifactStatus::Removed]);
+        assert!(!picture.exists() && !vault.join("Pictures").exists());
+        assert!(own_file.is_file());
+
+        let last = report.items.last().expect("items");
+        assert_eq!((last.artifact.kind, last.artifact.status), (ArtifactKind::Logs, ArtifactStatus::Removed));
+        assert_eq!(statuses(&report, ArtifactKind::Logs).len(), 1);
+        assert!(!logging::get_log_directory().expect("log folder").exists());
+    }
+
+    #[test]
+    fn a_failed_item_does_not_stop_the_ones_after_it() {
+        let app_data = test_support::app_data();
+        let (base, vault) = installed(&app_data);
+        let history = Profile::default_profile().open_history();
+        let picture = vault.join("picture.png");
+        download(&history, &picture);
+        // A second profile sharing the vault recorded the same file, which
+        // is gone by the time its vault item comes up
+        let shared = serde_json::json!({ "vault_root": vault.display().to_string() });
+        profiles::create(&base, "travel", Some(shared)).expect("profile");
+        download(&Profile::load(Some("travel")).expect("profile").open_history(), &picture);
+
+        let report = perform(&base, &history, false).expect("cleanup");
+        assert!(!report.success, "{:?}", report.items);
+        assert_eq!(
+            statuses(&report, ArtifactKind::Vault),
+            [ArtifactStatus::Removed, ArtifactStatus::Failed]
+        );
+        let failed = report.items.iter().find(|item| item.artifact.status == ArtifactStatus::Failed).expect("failed item");
+        assert!(failed.artifact.error.as_deref().is_some_and(|error| error.contains("picture.png")), "{:?}", failed);
+        assert_eq!(statuses(&report, ArtifactKind::Settings), [ArtifactStatus::Removed]);
+        assert_eq!(statuses(&report, ArtifactKind::Logs), [ArtifactStatus::Removed]);
+        assert_eq!(fs::read_dir(&app_data.directory).expect("app data").count(), 0);
+    }
+}
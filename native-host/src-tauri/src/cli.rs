@@ -0,0 +1,460 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+
+use crate::conflicts;
+use crate::diagnostics;
+use crate::dispatcher::Priority;
+use crate::drop_import;
+use crate::fake_download;
+use crate::downloader::{run_gui_download, DownloadContext};
+use crate::history::History;
+use crate::image_fetch;
+use crate::instance::{self, InstanceMessage};
+use crate::jobs::JobRegistry;
+use crate::media_policy::ImageDimensions;
+use crate::organize::{self, MediaHints};
+use crate::output_template;
+use crate::profiles::Profile;
+use crate::queue::generate_job_id;
+use crate::registration::{register_native_host, unregister_native_host, RegistrationReport};
+use crate::settings::{Settings, SettingsStore, VideoQuality};
+use crate::source_page::SourcePage;
+use crate::EXTENSION_ID;
+
+// First arguments that select the command line instead of the GUI. Anything
+// else, including the origin Chrome passes to native hosts, keeps the old paths.
+const SUBCOMMANDS: &[&str] = &[
+    "download",
+    "import",
+    "register",
+    "unregister",
+    "doctor",
+    "history",
+    "encrypt-history",
+    image_fetch::SUBCOMMAND,
+    fake_download::SUBCOMMAND,
+    "help",
+];
+
+const EXIT_SUCCESS: i32 = 0;
+const EXIT_FAILURE: i32 = 1;
+
+#[derive(Parser)]
+#[command(name = "imgvault", version, about = "Drive the ImgVault native host from scripts")]
+struct Cli {
+    /// Print machine-readable JSON on stdout
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Use this profile's vault, history and settings instead of the default profile's
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    #[command(subcommand)]
+    command: CliCommand,
+}
+
+#[derive(Subcommand)]
+enum CliCommand {
+    /// Download a URL with the same yt-dlp pipeline the GUI uses
+    Download {
+        url: String,
+        /// Output file or yt-dlp template; a folder gets the default template
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Quality preset (best, 1080p, 720p, 480p, audio_only) or a raw yt-dlp format selector
+        #[arg(short, long)]
+        format: Option<String>,
+        /// Keep the file local even when an S3 upload is configured
+        #[arg(long)]
+        no_upload: bool,
+    },
+    /// Import media files into the vault, through the running GUI when there is one
+    Import {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Register the native messaging host with a browser
+    Register {
+        #[arg(long, default_value = EXTENSION_ID)]
+        extension_id: String,
+        #[arg(long, value_enum, default_value_t = Browser::Chrome)]
+        browser: Browser,
+    },
+    /// Remove the native messaging host registration from a browser
+    Unregister {
+        #[arg(long, value_enum, default_value_t = Browser::Chrome)]
+        browser: Browser,
+    },
+    /// Check the installation and print the diagnostics report
+    Doctor,
+    /// List recent downloads, newest first
+    History {
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Encrypt URLs and page titles in the history database with a key kept in the OS keychain
+    EncryptHistory,
+    /// Fetch one image URL; run by the host itself as the HTTP image download backend
+    #[command(name = image_fetch::SUBCOMMAND, hide = true)]
+    FetchImage {
+        url: String,
+        #[arg(long)]
+        output: String,
+        #[arg(long)]
+        referer: Option<String>,
+        #[arg(long)]
+        user_agent: Option<String>,
+        #[arg(long)]
+        cookies: Option<PathBuf>,
+        /// Smaller images are deleted and the fetch fails, e.g. 800x600
+        #[arg(long)]
+        min_dimensions: Option<ImageDimensions>,
+        /// Keep an existing file instead of fetching it again
+        #[arg(long)]
+        no_overwrites: bool,
+        /// The job to stop on when a host cancels it
+        #[arg(long)]
+        job_id: Option<String>,
+        /// JSON list of bandwidth windows the transfer follows
+        #[arg(long)]
+        bandwidth_schedule: Option<String>,
+    },
+    /// Play a fake download scenario; run by the host itself as the fake download backend
+    #[command(name = fake_download::SUBCOMMAND, hide = true)]
+    FakeDownload {
+        url: String,
+        #[arg(long)]
+        output: String,
+        /// Passed like any backend's; only reported
+        #[arg(long)]
+        cookies: Option<PathBuf>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Browser {
+    Chrome,
+    Edge,
+    Brave,
+    Chromium,
+}
+
+impl Browser {
+    fn name(self) -> &'static str {
+        match self {
+            Browser::Chrome => "chrome",
+            Browser::Edge => "edge",
+            Browser::Brave => "brave",
+            Browser::Chromium => "chromium",
+        }
+    }
+}
+
+pub fn is_cli_invocation(args: &[String]) -> bool {
+    args.get(1).is_some_and(|arg| SUBCOMMANDS.contains(&arg.as_str()))
+}
+
+// Run one subcommand and return the process exit code. Results go to stdout,
+// human-readable errors to stderr; with --json both are a single JSON object.
+pub fn run(args: Vec<String>) -> i32 {
+    let cli = Cli::parse_from(args);
+    let json = cli.json;
+    let profile = cli.profile.as_deref();
+
+    let (success, output) = match cli.command {
+        CliCommand::Download { url, output, format, no_upload } => {
+            download(profile, &url, output.as_deref(), format.as_deref(), !no_upload)
+        }
+        CliCommand::Import { paths } => import(profile, paths),
+        CliCommand::Register { extension_id, browser } => {
+            registration_result(register_native_host(&extension_id, browser.name()), || {
+                format!("Registered {} for {}", extension_id, browser.name())
+            })
+        }
+        CliCommand::Unregister { browser } => registration_result(unregister_native_host(browser.name()), || {
+            format!("Unregistered from {}", browser.name())
+        }),
+        CliCommand::Doctor => doctor(profile),
+        CliCommand::History { limit } => history(profile, limit),
+        CliCommand::EncryptHistory => encrypt_history(profile),
+        // A download child: its output is the progress stream, not a result
+        CliCommand::FetchImage {
+            url,
+            output,
+            referer,
+            user_agent,
+            cookies,
+            min_dimensions,
+            no_overwrites,
+            job_id,
+            bandwidth_schedule,
+        } => {
+            let request = image_fetch::ImageRequest {
+                url: &url,
+                output: &output,
+                referer: referer.as_deref(),
+                user_agent: user_agent.as_deref(),
+                cookies: cookies.as_deref(),
+                min_dimensions,
+                no_overwrites,
+            };
+            return image_fetch::run(&request, job_id.as_deref(), bandwidth_schedule.as_deref());
+        }
+        CliCommand::FakeDownload { url, output, cookies } => {
+            return fake_download::run(&url, &output, cookies.as_deref());
+        }
+    };
+
+    // Native mode output goes through native_stdout instead
+    #[allow(clippy::print_stdout, clippy::print_stderr)]
+    if json {
+        println!("{}", output.json);
+    } else if success {
+        println!("{}", output.text);
+    } else {
+        eprintln!("{}", output.text);
+    }
+
+    if success {
+        EXIT_SUCCESS
+    } else {
+        EXIT_FAILURE
+    }
+}
+
+struct Output {
+    json: Value,
+    text: String,
+}
+
+// One line per artifact under the summary, and the full report with --json
+fn registration_result(result: Result<RegistrationReport, String>, message: impl FnOnce() -> String) -> (bool, Output) {
+    let report = match result {
+        Ok(report) => report,
+        Err(error) => return failure(error),
+    };
+    let mut lines = report
+        .artifacts
+        .iter()
+        .map(|artifact| {
+            let status = serde_json::to_value(artifact.status).unwrap_or_default();
+            let mut line = format!("  {:<12} {}", status.as_str().unwrap_or_default(), artifact.location);
+            if let Some(error) = &artifact.error {
+                line.push_str(&format!(" ({})", error));
+            }
+            line
+        })
+        .collect::<Vec<_>>();
+    let mut json = json!(report);
+    let success = report.success;
+    let summary = match report.into_result() {
+        Ok(_) => {
+            let message = message();
+            json["message"] = Value::from(message.clone());
+            message
+        }
+        Err(error) => {
+            json["error"] = Value::from(error.clone());
+            format!("Error: {}", error)
+        }
+    };
+    lines.insert(0, summary);
+    (success, Output { json, text: lines.join("\n") })
+}
+
+fn failure(error: String) -> (bool, Output) {
+    (
+        false,
+        Output {
+            json: json!({ "success": false, "error": error }),
+            text: format!("Error: {}", error),
+        },
+    )
+}
+
+// The settings and history of the --profile profile
+fn open_profile(name: Option<&str>) -> Result<(Settings, History), String> {
+    let profile = Profile::load(name)?;
+    Ok((profile.apply(SettingsStore::load().base()), profile.open_history()))
+}
+
+fn download(profile: Option<&str>, url: &str, output: Option<&str>, format: Option<&str>, upload: bool) -> (bool, Output) {
+    let (settings, history) = match open_profile(profile) {
+        Ok(opened) => opened,
+        Err(error) => return failure(error),
+    };
+    let jobs = JobRegistry::new();
+
+    let output_path = match output {
+        Some(output) if Path::new(output).is_dir() => {
+            Path::new(output).join(&settings.output_template).display().to_string()
+        }
+        Some(output) => output.to_string(),
+        None => match output_template::in_vault(&settings.output_template, &settings) {
+            Ok(output_path) => output_path,
+            Err(error) => return failure(error),
+        },
+    };
+    // Presets use the same selectors as the quality setting; anything else goes to yt-dlp as is
+    let format_selector = match format {
+        Some(format) => serde_json::from_value::<VideoQuality>(Value::from(format))
+            .map(|quality| quality.format_selector().to_string())
+            .unwrap_or_else(|_| format.to_string()),
+        None => settings.default_quality.format_selector().to_string(),
+    };
+
+    let job_id = generate_job_id("cli");
+    let (settings, output_path) =
+        match organize::organize_job(&history, settings, &job_id, url, &MediaHints::default(), &output_path) {
+            Ok(organized) => organized,
+            Err(message) => return failure(message),
+        };
+    let (settings, output_path) = conflicts::settle(settings, url, output_path, None);
+    let context = DownloadContext {
+        job_id: &job_id,
+        url,
+        output_path: &output_path,
+        upload,
+        priority: Priority::Normal,
+        source_page: &SourcePage::default(),
+        source: "gui",
+    };
+    match run_gui_download(&jobs, &history, &settings, &context, &format_selector, false, None) {
+        Ok(result) => {
+            let text = match result["filePath"].as_str() {
+                Some(file_path) => format!("Saved {}", file_path),
+                None => format!("Uploaded {}", result["upload"]["key"].as_str().unwrap_or(url)),
+            };
+            (true, Output { json: result, text })
+        }
+        // Failures come back as a JSON string carrying the yt-dlp output
+        Err(error) => match serde_json::from_str::<Value>(&error) {
+            Ok(mut result) => {
+                result["success"] = Value::from(false);
+                let text = format!("Download failed: {}", result["message"].as_str().unwrap_or("unknown error"));
+                (false, Output { json: result, text })
+            }
+            Err(_) => failure(error),
+        },
+    }
+}
+
+// Explorer's "Send to ImgVault" menu runs this once per file. Without
+// --profile the running GUI imports into its active profile.
+fn import(profile: Option<&str>, paths: Vec<PathBuf>) -> (bool, Output) {
+    // The running GUI has its own working directory
+    let paths = match std::env::current_dir() {
+        Ok(directory) => paths.into_iter().map(|path| directory.join(path)).collect::<Vec<_>>(),
+        Err(error) => return failure(format!("Failed to resolve the current directory: {}", error)),
+    };
+    let message = InstanceMessage::Import {
+        paths: paths.iter().map(|path| path.display().to_string()).collect(),
+    };
+    if profile.is_none() && instance::send_to_running_instance(&message).is_ok() {
+        let text = format!("Sent {} file(s) to the running ImgVault", paths.len());
+        return (true, Output { json: json!({ "success": true, "forwarded": true, "message": text }), text });
+    }
+
+    let (settings, history) = match open_profile(profile) {
+        Ok(opened) => opened,
+        Err(error) => return failure(error),
+    };
+    let mut imported = Vec::new();
+    let mut failed = Vec::new();
+    let mut lines = Vec::new();
+    for path in &paths {
+        let result = drop_import::check_media_file(path)
+            .and_then(|()| drop_import::import_file(&history, &settings, path, "shell"));
+        match result {
+            Ok(destination) => {
+                lines.push(format!("Imported {}", destination.display()));
+                imported.push(json!({ "path": path, "filePath": destination }));
+            }
+            Err(error) => {
+                lines.push(format!("Error: {}: {}", path.display(), error));
+                failed.push(json!({ "path": path, "error": error }));
+            }
+        }
+    }
+
+    let success = failed.is_empty();
+    let json = json!({ "success": success, "forwarded": false, "imported": imported, "failed": failed });
+    (success, Output { json, text: lines.join("\n") })
+}
+
+fn doctor(profile: Option<&str>) -> (bool, Output) {
+    let (settings, history) = match open_profile(profile) {
+        Ok(opened) => opened,
+        Err(error) => return failure(error),
+    };
+    let report = diagnostics::run_diagnostics(&settings, &history, None);
+
+    let checks = report["checks"].as_array().cloned().unwrap_or_default();
+    let failed = checks.iter().any(|check| check["status"] == "fail");
+    let text = checks
+        .iter()
+        .map(|check| {
+            let mut line = format!(
+                "[{}] {}: {}",
+                check["status"].as_str().unwrap_or("?"),
+                check["id"].as_str().unwrap_or("?"),
+                check["message"].as_str().unwrap_or("")
+            );
+            if let Some(hint) = check["hint"].as_str() {
+                line.push_str(&format!("\n       {}", hint));
+            }
+            line
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    (!failed, Output { json: report, text })
+}
+
+fn history(profile: Option<&str>, limit: usize) -> (bool, Output) {
+    let entries = match open_profile(profile).and_then(|(_, history)| history.recent(limit)) {
+        Ok(entries) => entries,
+        Err(error) => return failure(error),
+    };
+
+    let text = if entries.is_empty() {
+        "No downloads yet".to_string()
+    } else {
+        entries
+            .iter()
+            .map(|entry| {
+                // Retries show which entry they retry, e.g. "failed (retry 2 of #14)"
+                let status = match entry.retry_of {
+                    Some(first) => format!("{} (retry {} of #{})", entry.status, entry.attempt, first),
+                    None => entry.status.clone(),
+                };
+                format!(
+                    "{}\t{}\t{}\t{}",
+                    entry.id,
+                    status,
+                    entry.url,
+                    entry.file_path.as_deref().unwrap_or("-")
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+
+    (true, Output { json: serde_json::to_value(&entries).unwrap_or(Value::Null), text })
+}
+
+fn encrypt_history(profile: Option<&str>) -> (bool, Output) {
+    let encryption = match open_profile(profile).and_then(|(_, history)| history.encrypt_existing()) {
+        Ok(encryption) => encryption,
+        Err(error) => return failure(error),
+    };
+
+    let mut text = format!("Encrypted {} history entries", encryption.encrypted_rows);
+    if let Some(warning) = &encryption.warning {
+        text.push_str(&format!("\nNote: {}", warning));
+    }
+    let json = json!({ "success": true, "encryption": encryption });
+    (true, Output { json, text })
+}
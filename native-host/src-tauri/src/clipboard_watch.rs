@@ -0,0 +1,171 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::{AppHandle, ClipboardManager, Manager};
+use tracing::{debug, info};
+
+use crate::gui::start_forwarded_download;
+use crate::notifications::{self, DesktopNotification};
+use crate::settings::{Settings, SettingsStore};
+use crate::site_login::host_in_domain;
+use crate::{logging, url_host, validate_download_url};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Copying the same link again is not a new request
+const REMEMBERED_URLS: usize = 50;
+// Anything longer is a document that happens to start with a link
+const MAX_CLIPBOARD_URL_LENGTH: usize = 2048;
+
+pub const DEFAULT_CLIPBOARD_DOMAINS: &[&str] = &[
+    "youtube.com",
+    "youtu.be",
+    "vimeo.com",
+    "twitter.com",
+    "x.com",
+    "reddit.com",
+    "instagram.com",
+    "tiktok.com",
+    "twitch.tv",
+];
+
+// The copied link waiting for the user to confirm it, managed by the GUI
+#[derive(Clone, Default)]
+pub struct ClipboardPrompt {
+    pending: Arc<Mutex<Option<String>>>,
+}
+
+impl ClipboardPrompt {
+    // True when `url` was the link on offer; it is no longer on offer afterwards
+    pub fn take(&self, url: &str) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.as_deref() != Some(url) {
+            return false;
+        }
+        *pending = None;
+        true
+    }
+
+    pub fn dismiss(&self) {
+        self.pending.lock().unwrap().take();
+    }
+
+    fn offer(&self, url: &str) {
+        *self.pending.lock().unwrap() = Some(url.to_string());
+    }
+}
+
+// Polls the clipboard while clipboard_watch is on. Only text copied after the
+// watcher was turned on counts, so enabling it never offers an old link.
+pub fn spawn_clipboard_watcher(app: AppHandle) {
+    std::thread::spawn(move || {
+        let mut last_text: Option<String> = None;
+        let mut watching = false;
+        let mut seen: VecDeque<String> = VecDeque::new();
+
+        loop {
+            std::thread::sleep(POLL_INTERVAL);
+            let settings = app.state::<SettingsStore>().get();
+            if !settings.clipboard_watch {
+                watching = false;
+                continue;
+            }
+
+            let text = match app.clipboard_manager().read_text() {
+                Ok(text) => text,
+                Err(error) => {
+                    debug!("Failed to read the clipboard: {}", error);
+                    continue;
+                }
+            };
+            if !watching {
+                watching = true;
+                last_text = text;
+                continue;
+            }
+            if text == last_text {
+                continue;
+            }
+            last_text = text;
+
+            let Some(url) = last_text.as_deref().and_then(|text| clipboard_url(text, &settings)) else {
+                continue;
+            };
+            if seen.contains(&url) {
+                continue;
+            }
+            if seen.len() == REMEMBERED_URLS {
+                seen.pop_front();
+            }
+            seen.push_back(url.clone());
+            offer_download(&app, &settings, url);
+        }
+    });
+}
+
+// The copied text as a link to download, when it is a single http(s) URL on
+// one of clipboard_domains
+fn clipboard_url(text: &str, settings: &Settings) -> Option<String> {
+    let text = text.trim();
+    if text.len() > MAX_CLIPBOARD_URL_LENGTH || validate_download_url(text).is_err() {
+        return None;
+    }
+    let host = url_host(text)?;
+    settings
+        .clipboard_domains
+        .iter()
+        .any(|domain| host_in_domain(&host, domain))
+        .then(|| text.to_string())
+}
+
+fn offer_download(app: &AppHandle, settings: &Settings, url: String) {
+    let loggable = logging::loggable_url(&url).to_string();
+    if settings.clipboard_auto_download {
+        info!(url = %loggable, "Downloading copied link");
+        notifications::show(&DesktopNotification {
+            title: "Download queued".to_string(),
+            body: loggable,
+            reveal_path: None,
+        });
+        start_forwarded_download(app.clone(), url, None);
+        return;
+    }
+
+    info!(url = %loggable, "Offering to download copied link");
+    app.state::<ClipboardPrompt>().offer(&url);
+    // The window shows the prompt with Download and Dismiss buttons
+    let _ = app.emit_all("clipboard-url", url.clone());
+    show_prompt(app, url, loggable);
+}
+
+// Windows toasts download the link when clicked
+#[cfg(target_os = "windows")]
+fn show_prompt(app: &AppHandle, url: String, loggable: String) {
+    use tauri_winrt_notification::Toast;
+    use tracing::warn;
+
+    let app = app.clone();
+    let result = Toast::new(Toast::POWERSHELL_APP_ID)
+        .title("Download copied link?")
+        .text1(&loggable)
+        .text2("Click to download it with ImgVault")
+        .on_activated(move || {
+            if app.state::<ClipboardPrompt>().take(&url) {
+                start_forwarded_download(app.clone(), url.clone(), None);
+            }
+            Ok(())
+        })
+        .show();
+
+    if let Err(error) = result {
+        warn!("Failed to show notification: {}", error);
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn show_prompt(_app: &AppHandle, _url: String, loggable: String) {
+    notifications::show(&DesktopNotification {
+        title: "Download copied link?".to_string(),
+        body: format!("{}\nOpen ImgVault to download it", loggable),
+        reveal_path: None,
+    });
+}
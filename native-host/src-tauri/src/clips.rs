@@ -0,0 +1,320 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use tauri::{AppHandle, Manager};
+use tracing::{info, warn};
+
+use crate::diagnostics::hide_console_window;
+use crate::drop_import::unique_destination;
+use crate::history::History;
+use crate::{current_timestamp_millis, long_path};
+
+pub const CLIP_PROGRESS_EVENT: &str = "clip-progress";
+const CLIPS_DIRECTORY: &str = "Clips";
+// A GIF or animated WebP grows with every frame; longer clips belong in an mp4
+const MAX_ANIMATED_SECS: f64 = 30.0;
+const ANIMATED_FPS: u32 = 15;
+// Without max_width, animations are scaled down to this; mp4 keeps the source width
+const DEFAULT_ANIMATED_WIDTH: u32 = 480;
+const MIN_WIDTH: u32 = 16;
+const MAX_WIDTH: u32 = 7680;
+// The end may sit this far past the duration ffprobe reports, which rounds
+const DURATION_SLACK_SECS: f64 = 0.05;
+// Share of the progress bar the palette pass of a GIF takes
+const PALETTE_SHARE: f64 = 0.3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClipFormat {
+    Gif,
+    Webp,
+    Mp4,
+}
+
+impl ClipFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            ClipFormat::Gif => "gif",
+            ClipFormat::Webp => "webp",
+            ClipFormat::Mp4 => "mp4",
+        }
+    }
+
+    fn animated(&self) -> bool {
+        matches!(self, ClipFormat::Gif | ClipFormat::Webp)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ClipProgress {
+    file_id: i64,
+    // "palette" for the first pass of a GIF, then "encode"
+    phase: &'static str,
+    percent: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Clip {
+    pub path: String,
+    pub size_bytes: u64,
+    // The history entry linked to the source's
+    pub entry_id: i64,
+    pub duration_secs: f64,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClipRequest {
+    pub file_id: i64,
+    pub start: f64,
+    pub end: f64,
+    pub format: ClipFormat,
+    pub max_width: Option<u32>,
+}
+
+// Cuts `start`..`end` seconds of the file of history entry `file_id` into
+// Clips/ next to it, emitting clip-progress while ffmpeg runs, and records
+// the clip in history under the source's entry
+pub fn make_clip(app: &AppHandle, history: &History, request: &ClipRequest) -> Result<Clip, String> {
+    let ClipRequest { file_id, start, end, format, max_width } = *request;
+    let entry = history
+        .get(file_id)?
+        .ok_or_else(|| format!("History entry {} not found", file_id))?;
+    let source = entry
+        .file_path
+        .map(PathBuf::from)
+        .filter(|path| path.is_file())
+        .ok_or_else(|| format!("The file of history entry {} is not on disk", file_id))?;
+
+    let duration = probe_duration(&source)?;
+    validate_range(start, end, duration, format)?;
+    let width = match max_width {
+        Some(width) if !(MIN_WIDTH..=MAX_WIDTH).contains(&width) => {
+            return Err(format!("max_width must be between {} and {}", MIN_WIDTH, MAX_WIDTH));
+        }
+        Some(width) => Some(width),
+        None if format.animated() => Some(DEFAULT_ANIMATED_WIDTH),
+        None => None,
+    };
+
+    let directory = source
+        .parent()
+        .ok_or("The source file has no folder")?
+        .join(CLIPS_DIRECTORY);
+    fs::create_dir_all(long_path::extended(&directory))
+        .map_err(|e| format!("Failed to create clip folder {}: {}", directory.display(), e))?;
+    let stem = source.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    let file_name = format!("{} ({}s-{}s).{}", stem, seconds_label(start), seconds_label(end), format.extension());
+    let output = unique_destination(&directory, Path::new(&file_name));
+
+    let mut report = |phase: &'static str, percent: u32| {
+        let _ = app.emit_all(CLIP_PROGRESS_EVENT, ClipProgress { file_id, phase, percent });
+    };
+    let encoded = encode(&source, &output, start, end - start, format, width, &mut report);
+    if let Err(error) = encoded {
+        // Leave no half-written clip behind
+        let _ = fs::remove_file(long_path::extended(&output));
+        return Err(error);
+    }
+    report("encode", 100);
+
+    let size_bytes = fs::metadata(long_path::extended(&output))
+        .map(|metadata| metadata.len())
+        .map_err(|e| format!("Failed to read clip {}: {}", output.display(), e))?;
+    let path = output.display().to_string();
+    let message = format!("Clip of {}s to {}s", seconds_label(start), seconds_label(end));
+    let entry_id = history.record_derived(file_id, &path, &message, "clip", current_timestamp_millis())?;
+    info!(file_id, entry_id, size_bytes, "Made clip {}", path);
+
+    Ok(Clip {
+        path,
+        size_bytes,
+        entry_id,
+        duration_secs: end - start,
+    })
+}
+
+fn validate_range(start: f64, end: f64, duration: f64, format: ClipFormat) -> Result<(), String> {
+    if !start.is_finite() || !end.is_finite() || start < 0.0 {
+        return Err("The clip start and end must be seconds from the beginning".to_string());
+    }
+    if end <= start {
+        return Err("The clip must end after it starts".to_string());
+    }
+    if end > duration + DURATION_SLACK_SECS {
+        return Err(format!("The clip ends at {}s, after the video's {}s", seconds_label(end), seconds_label(duration)));
+    }
+    if format.animated() && end - start > MAX_ANIMATED_SECS {
+        return Err(format!(
+            "A {} clip can be at most {} seconds; make an mp4 for longer ones",
+            format.extension(),
+            MAX_ANIMATED_SECS
+        ));
+    }
+    Ok(())
+}
+
+// Length of the source in seconds, as ffprobe reads it from the container
+pub(crate) fn probe_duration(source: &Path) -> Result<f64, String> {
+    let mut command = Command::new("ffprobe");
+    command
+        .args(["-v", "error", "-show_entries", "format=duration", "-of", "default=noprint_wrappers=1:nokey=1"])
+        .arg(long_path::simplified(source));
+    hide_console_window(&mut command);
+
+    let output = command.output().map_err(|e| format!("Failed to run ffprobe: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("ffprobe failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|duration| duration.is_finite() && *duration > 0.0)
+        .ok_or_else(|| "ffprobe found no duration; the file may not be a video".to_string())
+}
+
+// GIFs take two passes: one builds a palette from the clip's own colors, the
+// second maps the frames onto it, which avoids the banding of the default one
+fn encode(
+    source: &Path,
+    output: &Path,
+    start: f64,
+    length: f64,
+    format: ClipFormat,
+    width: Option<u32>,
+    report: &mut dyn FnMut(&'static str, u32),
+) -> Result<(), String> {
+    let scale = match width {
+        Some(width) => format!("scale='min({},iw)':-2:flags=lanczos", width),
+        None => "scale=trunc(iw/2)*2:-2".to_string(),
+    };
+    let animated_filters = format!("fps={},{}", ANIMATED_FPS, scale);
+
+    match format {
+        ClipFormat::Gif => {
+            let palette = env::temp_dir().join(format!("imgvault-palette-{}.png", current_timestamp_millis()));
+            let mut first = ffmpeg_command(source, start, length);
+            first
+                .arg("-vf")
+                .arg(format!("{},palettegen=stats_mode=diff", animated_filters))
+                .arg(&palette);
+            let palette_pass = run_ffmpeg(first, length, &mut |fraction| {
+                report("palette", (fraction * PALETTE_SHARE * 100.0) as u32)
+            });
+            if let Err(error) = palette_pass {
+                let _ = fs::remove_file(&palette);
+                return Err(error);
+            }
+
+            let mut second = ffmpeg_command(source, start, length);
+            second
+                .arg("-i")
+                .arg(&palette)
+                .arg("-lavfi")
+                .arg(format!(
+                    "{} [x]; [x][1:v] paletteuse=dither=bayer:bayer_scale=5:diff_mode=rectangle",
+                    animated_filters
+                ))
+                .args(["-loop", "0"])
+                .arg(long_path::simplified(output));
+            let encoded = run_ffmpeg(second, length, &mut |fraction| {
+                report("encode", ((PALETTE_SHARE + fraction * (1.0 - PALETTE_SHARE)) * 100.0) as u32)
+            });
+            let _ = fs::remove_file(&palette);
+            encoded
+        }
+        ClipFormat::Webp => {
+            let mut command = ffmpeg_command(source, start, length);
+            command
+                .arg("-vf")
+                .arg(&animated_filters)
+                .args(["-c:v", "libwebp", "-q:v", "75", "-loop", "0", "-an"])
+                .arg(long_path::simplified(output));
+            run_ffmpeg(command, length, &mut |fraction| report("encode", (fraction * 100.0) as u32))
+        }
+        ClipFormat::Mp4 => {
+            let mut command = ffmpeg_command(source, start, length);
+            command
+                .arg("-vf")
+                .arg(&scale)
+                .args(["-c:v", "libx264", "-preset", "veryfast", "-crf", "23", "-pix_fmt", "yuv420p"])
+                .args(["-c:a", "aac", "-movflags", "+faststart"])
+                .arg(long_path::simplified(output));
+            run_ffmpeg(command, length, &mut |fraction| report("encode", (fraction * 100.0) as u32))
+        }
+    }
+}
+
+// ffmpeg reading `length` seconds from `start`, reporting progress on stdout
+fn ffmpeg_command(source: &Path, start: f64, length: f64) -> Command {
+    let mut command = Command::new("ffmpeg");
+    command
+        .args(["-v", "error", "-nostats", "-progress", "pipe:1", "-y"])
+        .arg("-ss")
+        .arg(format!("{:.3}", start))
+        .arg("-t")
+        .arg(format!("{:.3}", length))
+        .arg("-i")
+        .arg(long_path::simplified(source))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    hide_console_window(&mut command);
+    command
+}
+
+// Runs ffmpeg to the end, passing the share of `length` it has written
+fn run_ffmpeg(mut command: Command, length: f64, on_progress: &mut dyn FnMut(f64)) -> Result<(), String> {
+    let mut child = command.spawn().map_err(|e| format!("Failed to run ffmpeg: {}", e))?;
+    let stderr = child.stderr.take();
+    let stderr_reader = std::thread::spawn(move || {
+        let mut text = String::new();
+        if let Some(mut stderr) = stderr {
+            let _ = stderr.read_to_string(&mut text);
+        }
+        text
+    });
+
+    if let Some(stdout) = child.stdout.take() {
+        let mut last_percent = None;
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            // out_time_ms is in microseconds too, despite its name
+            let Some(micros) = line
+                .strip_prefix("out_time_us=")
+                .or_else(|| line.strip_prefix("out_time_ms="))
+                .and_then(|value| value.trim().parse::<f64>().ok())
+            else {
+                continue;
+            };
+            let fraction = (micros / 1_000_000.0 / length).clamp(0.0, 1.0);
+            let percent = (fraction * 100.0) as u32;
+            if last_percent != Some(percent) {
+                last_percent = Some(percent);
+                on_progress(fraction);
+            }
+        }
+    }
+
+    let status = child.wait().map_err(|e| format!("Failed to wait for ffmpeg: {}", e))?;
+    let stderr_text = stderr_reader.join().unwrap_or_default();
+    if status.success() {
+        return Ok(());
+    }
+    warn!("ffmpeg failed making a clip: {}", stderr_text.trim());
+    Err(match stderr_text.lines().rev().find(|line| !line.trim().is_empty()) {
+        Some(line) => format!("ffmpeg failed: {}", line.trim()),
+        None => format!("ffmpeg returned exit code {:?}", status.code()),
+    })
+}
+
+// 12.5 stays 12.5, 20.0 becomes 20
+pub(crate) fn seconds_label(seconds: f64) -> String {
+    let text = format!("{:.2}", seconds);
+    text.trim_end_matches('0').trim_end_matches('.').to_string()
+}
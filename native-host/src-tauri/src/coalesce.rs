@@ -0,0 +1,287 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::file_lock::{self, FileLock};
+use crate::history::{History, HistoryEntry};
+use crate::jobs::{self, JobRegistry};
+use crate::{current_timestamp_millis, get_app_data_directory};
+
+// Jobs between intake and their history entry, across the GUI and every
+// native messaging port, so a request sent twice runs once
+const STATE_FILE_NAME: &str = "in-flight-downloads.json";
+const LOCK_FILE_NAME: &str = "in-flight-downloads.lock";
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// Claims of jobs handed to the schedule queue, held until the timer ran them
+static QUEUED_CLAIMS: Mutex<Vec<InFlightGuard>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InFlightJob {
+    // Normalized URL and destination, see job_key
+    key: String,
+    job_id: String,
+    host_pid: u32,
+    since: i64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InFlightState {
+    jobs: Vec<InFlightJob>,
+}
+
+pub enum Claim {
+    // This request runs the job
+    Owner(InFlightGuard),
+    // An identical job is already queued or running; this request follows
+    // it. `since` is when that job was claimed.
+    Attached { job_id: String, since: i64 },
+}
+
+// Keeps the job claimed until dropped, once its outcome is in history
+pub struct InFlightGuard {
+    job_id: Option<String>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let Some(job_id) = &self.job_id else {
+            return;
+        };
+        if let Err(error) = update_state(|state| {
+            state.jobs.retain(|job| job.job_id != *job_id);
+            Ok(())
+        }) {
+            warn!(job_id = %job_id, "Failed to release in-flight download: {}", error);
+        }
+    }
+}
+
+// Keeps a job that waits in the schedule queue claimed, so a request sent
+// twice before the timer starts it still runs once
+pub fn hold_while_queued(guard: InFlightGuard) {
+    if guard.job_id.is_some() {
+        QUEUED_CLAIMS.lock().unwrap().push(guard);
+    }
+}
+
+// Releases a job held by hold_while_queued once it ran or left the queue
+pub fn release_queued(job_id: &str) {
+    let released = {
+        let mut claims = QUEUED_CLAIMS.lock().unwrap();
+        let position = claims.iter().position(|guard| guard.job_id.as_deref() == Some(job_id));
+        position.map(|position| claims.remove(position))
+    };
+    // Dropped outside the lock; releasing rewrites the state file
+    drop(released);
+}
+
+// Checked and recorded under one lock, so of two identical requests racing
+// through intake exactly one becomes the owner, even when the first is still
+// being dispatched. `force_new` runs the job regardless and claims nothing.
+pub fn claim(job_id: &str, url: &str, output_path: &str, force_new: bool) -> Result<Claim, String> {
+    if force_new {
+        debug!(job_id, "force_new set; not coalescing");
+        return Ok(Claim::Owner(InFlightGuard { job_id: None }));
+    }
+    let key = job_key(url, output_path);
+    let own_pid = std::process::id();
+    let claimed = update_state(|state| {
+        // A process that died never drops its guards
+        state
+            .jobs
+            .retain(|job| job.host_pid == own_pid || job.key != key || jobs::process_exists(job.host_pid));
+        if let Some(existing) = state.jobs.iter().find(|job| job.key == key && job.job_id != job_id) {
+            return Ok(Some((existing.job_id.clone(), existing.since)));
+        }
+        state.jobs.retain(|job| job.job_id != job_id);
+        state.jobs.push(InFlightJob {
+            key: key.clone(),
+            job_id: job_id.to_string(),
+            host_pid: own_pid,
+            since: current_timestamp_millis(),
+        });
+        Ok(None)
+    })?;
+    match claimed {
+        Some((existing, since)) => {
+            info!(job_id, existing = %existing, "Coalesced duplicate download request");
+            Ok(Claim::Attached { job_id: existing, since })
+        }
+        None => Ok(Claim::Owner(InFlightGuard {
+            job_id: Some(job_id.to_string()),
+        })),
+    }
+}
+
+// Blocks until the job a request attached to is done, then returns its
+// history entry. None when it ended without one, e.g. paused for resume, or
+// when the host began shutting down meanwhile.
+pub fn wait_for(jobs: &JobRegistry, history: &History, job_id: &str, since: i64) -> Result<Option<HistoryEntry>, String> {
+    loop {
+        if jobs.is_shutting_down() {
+            return Ok(None);
+        }
+        let running = {
+            let _lock = lock_state()?;
+            read_state()?
+                .jobs
+                .into_iter()
+                .find(|job| job.job_id == job_id)
+        };
+        match running {
+            Some(job) if job.host_pid == std::process::id() || jobs::process_exists(job.host_pid) => {
+                std::thread::sleep(POLL_INTERVAL)
+            }
+            _ => break,
+        }
+    }
+    history.latest_for_job(job_id, since)
+}
+
+// Scheme and host are case-insensitive, the fragment never reaches the
+// server, and a trailing slash names the same page. Paths are compared with
+// one kind of separator.
+fn job_key(url: &str, output_path: &str) -> String {
+    let url = url.trim();
+    let url = url.split('#').next().unwrap_or(url);
+    let url = match url.split_once("://") {
+        Some((scheme, rest)) => {
+            let (authority, path) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+            format!("{}://{}{}", scheme.to_ascii_lowercase(), authority.to_ascii_lowercase(), path)
+        }
+        None => url.to_string(),
+    };
+    let url = url.strip_suffix('/').unwrap_or(&url);
+    let destination = output_path.trim().replace('\\', "/");
+    // Windows paths differ only by case for the same file
+    #[cfg(target_os = "windows")]
+    let destination = destination.to_lowercase();
+    format!("{}\n{}", url, destination)
+}
+
+fn state_path() -> Result<PathBuf, String> {
+    Ok(get_app_data_directory()?.join(STATE_FILE_NAME))
+}
+
+fn read_state() -> Result<InFlightState, String> {
+    let path = state_path()?;
+    match fs::read_to_string(&path) {
+        // A damaged file only holds transient state; starting over is safe
+        Ok(contents) => Ok(serde_json::from_str(&contents).unwrap_or_default()),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(InFlightState::default()),
+        Err(error) => Err(format!("Failed to read in-flight downloads: {}", error)),
+    }
+}
+
+fn write_state(state: &InFlightState) -> Result<(), String> {
+    let path = state_path()?;
+    let contents = serde_json::to_string_pretty(state)
+        .map_err(|e| format!("Failed to serialize in-flight downloads: {}", e))?;
+    let temp_path = path.with_extension(format!("json.{}.tmp", std::process::id()));
+    fs::write(&temp_path, contents)
+        .map_err(|e| format!("Failed to write in-flight downloads: {}", e))?;
+    fs::rename(&temp_path, &path)
+        .map_err(|e| format!("Failed to save in-flight downloads: {}", e))
+}
+
+fn update_state<T>(change: impl FnOnce(&mut InFlightState) -> Result<T, String>) -> Result<T, String> {
+    let _lock = lock_state()?;
+    let mut state = read_state()?;
+    let result = change(&mut state)?;
+    write_state(&state)?;
+    Ok(result)
+}
+
+fn lock_state() -> Result<FileLock, String> {
+    file_lock::acquire(LOCK_FILE_NAME, "in-flight downloads")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use std::sync::Barrier;
+
+    const URL: &str = "https://videos.example/watch?v=twice";
+    const OUTPUT_PATH: &str = "/vault/%(title)s.%(ext)s";
+    const RACERS: usize = 8;
+
+    fn owner(claim: &Claim) -> Option<&str> {
+        match claim {
+            Claim::Owner(guard) => guard.job_id.as_deref(),
+            Claim::Attached { .. } => None,
+        }
+    }
+
+    // Every request reaches intake at the same moment, as a double submit
+    // from two ports does; the first through the lock owns the job
+    #[test]
+    fn racing_claims_make_exactly_one_owner() {
+        let _app_data = test_support::app_data();
+        let start = Barrier::new(RACERS);
+        let claims = std::thread::scope(|scope| {
+            let racing = (0..RACERS)
+                .map(|racer| {
+                    let start = &start;
+                    scope.spawn(move || {
+                        start.wait();
+                        claim(&format!("job-{}", racer), URL, OUTPUT_PATH, false).expect("claim")
+                    })
+                })
+                .collect::<Vec<_>>();
+            racing.into_iter().map(|racer| racer.join().unwrap()).collect::<Vec<_>>()
+        });
+
+        let owners = claims.iter().filter_map(owner).collect::<Vec<_>>();
+        assert_eq!(owners.len(), 1, "owners: {:?}", owners);
+        for claim in &claims {
+            if let Claim::Attached { job_id, .. } = claim {
+                assert_eq!(job_id, owners[0]);
+            }
+        }
+        assert_eq!(read_state().expect("state").jobs.len(), 1);
+    }
+
+    #[test]
+    fn the_same_page_in_another_spelling_is_a_duplicate() {
+        let _app_data = test_support::app_data();
+        let _first = claim("first", URL, OUTPUT_PATH, false).expect("claim");
+        for url in [
+            "HTTPS://Videos.Example/watch?v=twice",
+            "https://videos.example/watch?v=twice#t=10",
+            " https://videos.example/watch?v=twice ",
+        ] {
+            let duplicate = claim("second", url, OUTPUT_PATH, false).expect("claim");
+            assert!(matches!(duplicate, Claim::Attached { ref job_id, .. } if job_id == "first"), "{}", url);
+        }
+        let elsewhere = claim("third", URL, "/elsewhere/%(title)s.%(ext)s", false).expect("claim");
+        assert_eq!(owner(&elsewhere), Some("third"));
+    }
+
+    #[test]
+    fn a_finished_job_lets_the_next_request_run() {
+        let _app_data = test_support::app_data();
+        let first = claim("first", URL, OUTPUT_PATH, false).expect("claim");
+        assert_eq!(owner(&first), Some("first"));
+        drop(first);
+        let again = claim("again", URL, OUTPUT_PATH, false).expect("claim");
+        assert_eq!(owner(&again), Some("again"));
+    }
+
+    #[test]
+    fn force_new_runs_beside_the_job_and_claims_nothing() {
+        let _app_data = test_support::app_data();
+        let _first = claim("first", URL, OUTPUT_PATH, false).expect("claim");
+        let forced = claim("forced", URL, OUTPUT_PATH, true).expect("claim");
+        assert!(matches!(forced, Claim::Owner(InFlightGuard { job_id: None })));
+        let state = read_state().expect("state");
+        assert_eq!(state.jobs.iter().map(|job| job.job_id.as_str()).collect::<Vec<_>>(), ["first"]);
+    }
+}
@@ -0,0 +1,966 @@
+use serde::Deserialize;
+use std::path::Path;
+use tauri::{AppHandle, State};
+use tracing::{info, warn};
+
+#[cfg(target_os = "windows")]
+use winreg::enums::*;
+#[cfg(target_os = "windows")]
+use winreg::RegKey;
+
+use crate::bundle::{ExportOptions, ImportOptions, ImportSummary};
+use crate::clipboard_watch::ClipboardPrompt;
+use crate::coalesce::Claim;
+use crate::conflicts::{ConflictDecision, ConflictPrompts, DownloadConflict};
+use crate::dispatcher::Priority;
+use crate::downloader::{coalesced_gui_result, get_cookies_path, run_gui_download, Backend, DownloadContext};
+use crate::drop_import::DroppedItem;
+use crate::folder_import::{FolderImport, FolderImportSummary};
+use crate::gui::start_forwarded_download;
+use crate::history::{History, HistoryEncryption, VaultStats};
+use crate::history_backfill::{BackfillField, BackfillScope, BackfillSummary, HistoryBackfill};
+use crate::http_api::HttpApi;
+use crate::jobs::JobRegistry;
+use crate::log_viewer::LogFollower;
+use crate::organize::{MediaHints, OrganizeRule, RuleMatch, RuleSample};
+use crate::output_template::TemplatePreview;
+use crate::process_priority::ProcessPriority;
+use crate::profiles::Profile;
+use crate::queue::{
+    announce_scheduled, cancel_job, generate_job_id, pause_queue_jobs, queue_snapshot, reorder_queued_job,
+    resume_queue_jobs, set_queued_job_priority,
+};
+use crate::registration::{register_native_host, unregister_native_host, RegistrationReport};
+use crate::settings::{Settings, SettingsError, SettingsStore};
+use crate::source_page::SourcePage;
+use crate::vault_export::{ExportSummary, ItemExportOptions, VaultExporter};
+use crate::vault_verify::{VaultVerifier, VerifyScope};
+use crate::{
+    autostart, browser_profiles, bundle, channel_archive, chapters, cleanup, clips, coalesce, conflicts, crash, db_backup,
+    destination, diagnostics, domain_policy, drop_import, extractors, frames, history, http_api, keychain, log_viewer,
+    logging, media_policy, metrics, onboarding, organize, origin_tag, output_template, profiles, schedule, shell_integration,
+    speed_stats, tool_integrity, updates, url_diagnosis, validate_download_url, vault_events, webhooks,
+};
+#[cfg(target_os = "windows")]
+use crate::reload_windows_path_environment;
+
+// Cancel a GUI or native download; unknown and finished jobs are a no-op
+#[tauri::command]
+pub fn cancel_download(jobs: State<'_, JobRegistry>, job_id: String) -> Result<String, String> {
+    cancel_job(&jobs, &job_id)
+}
+
+#[tauri::command]
+pub fn pause_queue(jobs: State<'_, JobRegistry>, settings: State<'_, SettingsStore>) -> serde_json::Value {
+    pause_queue_jobs(&jobs);
+    queue_snapshot(&jobs, &settings.get())
+}
+
+#[tauri::command]
+pub fn resume_queue(jobs: State<'_, JobRegistry>, settings: State<'_, SettingsStore>) -> Result<serde_json::Value, String> {
+    resume_queue_jobs(&jobs)?;
+    Ok(queue_snapshot(&jobs, &settings.get()))
+}
+
+#[tauri::command]
+pub fn reorder_job(
+    jobs: State<'_, JobRegistry>,
+    settings: State<'_, SettingsStore>,
+    job_id: String,
+    new_index: usize,
+) -> Result<serde_json::Value, String> {
+    reorder_queued_job(&jobs, &job_id, new_index)?;
+    Ok(queue_snapshot(&jobs, &settings.get()))
+}
+
+#[tauri::command]
+pub fn set_job_priority(
+    jobs: State<'_, JobRegistry>,
+    settings: State<'_, SettingsStore>,
+    job_id: String,
+    priority: Priority,
+) -> Result<serde_json::Value, String> {
+    set_queued_job_priority(&jobs, &job_id, priority)?;
+    Ok(queue_snapshot(&jobs, &settings.get()))
+}
+
+// Include or exclude a failed job, found by any of its history entries, from auto-retry
+#[tauri::command]
+pub fn set_auto_retry(history: State<'_, History>, entry_id: i64, enabled: bool) -> Result<(), String> {
+    history.set_auto_retry(entry_id, enabled)?;
+    info!(entry_id, enabled, "Auto-retry preference changed");
+    Ok(())
+}
+
+// Download the copied link the clipboard watcher offered
+#[tauri::command]
+pub fn confirm_clipboard_download(app: AppHandle, prompt: State<'_, ClipboardPrompt>, url: String) -> Result<(), String> {
+    if !prompt.take(&url) {
+        return Err("That link is no longer waiting to be downloaded".to_string());
+    }
+    start_forwarded_download(app, url, None);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn dismiss_clipboard_download(prompt: State<'_, ClipboardPrompt>) {
+    prompt.dismiss();
+}
+
+// Answer a download awaiting a decision about the file at its path
+#[tauri::command]
+pub fn resolve_conflict(
+    prompts: State<'_, ConflictPrompts>,
+    job_id: String,
+    decision: ConflictDecision,
+    apply_to_all: Option<bool>,
+) -> Result<(), String> {
+    let apply_to_all = apply_to_all.unwrap_or(false);
+    info!(job_id = %job_id, decision = ?decision, apply_to_all, "Download conflict resolved");
+    prompts.resolve(&job_id, decision, apply_to_all)
+}
+
+#[tauri::command]
+pub fn get_pending_conflicts(prompts: State<'_, ConflictPrompts>) -> Vec<DownloadConflict> {
+    prompts.pending()
+}
+
+// Links dropped onto the page; dropped files arrive as a window event instead
+#[tauri::command]
+pub fn drop_text(app: AppHandle, items: Vec<String>) {
+    drop_import::handle_drop(app, items.into_iter().map(DroppedItem::Text).collect(), "drop");
+}
+
+// Download speed and success rate per site over the last `days` (30 by
+// default), optionally only for `domain` and its subdomains
+#[tauri::command]
+pub fn get_speed_stats(
+    history: State<'_, History>,
+    domain: Option<String>,
+    days: Option<u32>,
+) -> Result<Vec<speed_stats::DomainSpeedStats>, String> {
+    speed_stats::by_domain(&history, domain.as_deref(), days)
+}
+
+#[tauri::command]
+pub fn get_queue(jobs: State<'_, JobRegistry>, settings: State<'_, SettingsStore>) -> serde_json::Value {
+    queue_snapshot(&jobs, &settings.get())
+}
+
+// Moves one running download in or out of background priority
+#[tauri::command]
+pub fn set_process_priority(
+    jobs: State<'_, JobRegistry>,
+    settings: State<'_, SettingsStore>,
+    job_id: String,
+    priority: ProcessPriority,
+) -> Result<serde_json::Value, String> {
+    jobs.set_process_priority(&job_id, priority)?;
+    Ok(queue_snapshot(&jobs, &settings.get()))
+}
+
+// Queue a download to start at `schedule_at`, an RFC 3339 timestamp
+#[tauri::command]
+pub fn schedule_download(
+    jobs: State<'_, JobRegistry>,
+    url: String,
+    output_path: String,
+    schedule_at: String,
+    job_id: Option<String>,
+    upload: Option<bool>,
+    priority: Option<Priority>,
+) -> Result<schedule::ScheduledDownload, String> {
+    validate_download_url(&url)?;
+    let job_id = job_id.unwrap_or_else(|| generate_job_id("gui"));
+    let context = DownloadContext {
+        job_id: &job_id,
+        url: &url,
+        output_path: &output_path,
+        upload: upload.unwrap_or(true),
+        priority: priority.unwrap_or_default(),
+        source_page: &SourcePage::default(),
+        source: "gui",
+    };
+    let download = schedule::add(&context, &schedule_at)?;
+    announce_scheduled(&jobs, &download);
+    Ok(download)
+}
+
+#[tauri::command]
+pub fn list_scheduled_downloads() -> Result<Vec<schedule::ScheduledDownload>, String> {
+    schedule::list()
+}
+
+#[tauri::command]
+pub fn reschedule_download(
+    jobs: State<'_, JobRegistry>,
+    job_id: String,
+    schedule_at: String,
+) -> Result<schedule::ScheduledDownload, String> {
+    let download = schedule::reschedule(&job_id, &schedule_at)?;
+    announce_scheduled(&jobs, &download);
+    Ok(download)
+}
+
+#[tauri::command]
+pub fn run_scheduled_download_now(jobs: State<'_, JobRegistry>, job_id: String) -> Result<schedule::ScheduledDownload, String> {
+    let download = schedule::run_now(&job_id)?;
+    announce_scheduled(&jobs, &download);
+    Ok(download)
+}
+
+// The shared settings, without the active profile's overrides, so a save
+// never copies them into settings.json
+#[tauri::command]
+pub fn get_settings(settings: State<'_, SettingsStore>) -> Settings {
+    settings.base()
+}
+
+#[tauri::command]
+pub fn save_settings(
+    settings: State<'_, SettingsStore>,
+    http_api: State<'_, HttpApi>,
+    jobs: State<'_, JobRegistry>,
+    patch: serde_json::Value,
+) -> Result<Settings, SettingsError> {
+    let background_before = settings.get().background_priority;
+    let updated = settings.update(patch)?;
+    // Running downloads follow the switch; new ones read it when they start
+    if updated.background_priority != background_before {
+        jobs.set_all_process_priorities(ProcessPriority::from_setting(updated.background_priority));
+    }
+    http_api.apply().map_err(|e| {
+        SettingsError::new(format!("Settings saved, but the HTTP API could not start: {}", e))
+    })?;
+    Ok(updated)
+}
+
+#[tauri::command]
+pub fn export_settings(
+    settings: State<'_, SettingsStore>,
+    history: State<'_, History>,
+    path: String,
+    include_history: Option<bool>,
+    include_secrets: Option<bool>,
+) -> Result<(), String> {
+    let options = ExportOptions {
+        include_history: include_history.unwrap_or(false),
+        include_secrets: include_secrets.unwrap_or(false),
+    };
+    bundle::export_bundle(&settings, &history, Path::new(&path), &options)
+}
+
+#[tauri::command]
+pub fn import_settings(
+    settings: State<'_, SettingsStore>,
+    history: State<'_, History>,
+    http_api: State<'_, HttpApi>,
+    path: String,
+    import_paths: Option<bool>,
+    import_history: Option<bool>,
+) -> Result<ImportSummary, SettingsError> {
+    let options = ImportOptions {
+        import_paths: import_paths.unwrap_or(false),
+        import_history: import_history.unwrap_or(false),
+    };
+    let summary = bundle::import_bundle(&settings, &history, Path::new(&path), &options)?;
+    // A failure is kept in the HTTP API status; the import itself succeeded
+    let _ = http_api.apply();
+    Ok(summary)
+}
+
+// Bearer token the extension needs to call the HTTP API; created on first use
+#[tauri::command]
+pub fn get_http_api_token() -> Result<String, String> {
+    http_api::get_or_create_token()
+}
+
+#[tauri::command]
+pub fn get_http_api_status(http_api: State<'_, HttpApi>) -> http_api::HttpApiStatus {
+    http_api.status()
+}
+
+// Sends a sample payload to the URL and returns the HTTP status it answered with
+#[tauri::command]
+pub async fn test_webhook(url: String) -> Result<u16, String> {
+    tauri::async_runtime::spawn_blocking(move || webhooks::send_test(&url))
+        .await
+        .map_err(|e| format!("Webhook test failed: {}", e))?
+}
+
+// Shared secret receivers use to verify the webhook signature header
+#[tauri::command]
+pub fn get_webhook_secret() -> Result<String, String> {
+    webhooks::get_secret()
+}
+
+// Secrets go straight to the OS keychain and are never returned to the GUI;
+// settings refer to them as {secret:<name>}
+#[tauri::command]
+pub fn set_secret(name: String, value: String) -> Result<(), String> {
+    keychain::set_secret(&name, &value)
+}
+
+#[tauri::command]
+pub fn delete_secret(name: String) -> Result<(), String> {
+    keychain::validate_name(&name)?;
+    keychain::delete_secret(&name)
+}
+
+#[tauri::command]
+pub fn has_secret(name: String) -> bool {
+    keychain::has_secret(&name)
+}
+
+#[tauri::command]
+pub fn get_log_path() -> Result<String, String> {
+    logging::get_log_path().map(|path| path.display().to_string())
+}
+
+#[tauri::command]
+pub fn read_log_tail(lines: usize) -> Result<Vec<String>, String> {
+    let path = logging::get_log_path()?;
+    log_viewer::read_log_tail(&path, lines).map_err(|e| format!("Failed to read log: {}", e))
+}
+
+// Emits `log-lines` events with newly appended lines while enabled
+#[tauri::command]
+pub fn follow_log(app: AppHandle, follower: State<'_, LogFollower>, enable: bool) -> Result<(), String> {
+    follower.set_enabled(app, enable)
+}
+
+// Indexes an existing folder tree; emits `folder-import-progress` after
+// every file and returns the final counts
+#[tauri::command]
+pub async fn import_directory(
+    app: AppHandle,
+    importer: State<'_, FolderImport>,
+    history: State<'_, History>,
+    settings: State<'_, SettingsStore>,
+    path: String,
+    move_files: bool,
+    recursive: bool,
+) -> Result<FolderImportSummary, String> {
+    let importer = importer.inner().clone();
+    let history = history.inner().clone();
+    let settings = settings.get();
+
+    tauri::async_runtime::spawn_blocking(move || {
+        importer.run(&app, &history, &settings, Path::new(&path), move_files, recursive)
+    })
+    .await
+    .map_err(|e| format!("Folder import failed: {}", e))?
+}
+
+// Whether an import was running; it stops after the file in progress
+#[tauri::command]
+pub fn cancel_import_directory(importer: State<'_, FolderImport>) -> bool {
+    importer.cancel()
+}
+
+// Re-hashes saved files against history; emits `vault-verify-progress` and
+// returns the path of the JSON report
+#[tauri::command]
+pub async fn verify_vault(
+    app: AppHandle,
+    verifier: State<'_, VaultVerifier>,
+    history: State<'_, History>,
+    settings: State<'_, SettingsStore>,
+    scope: VerifyScope,
+) -> Result<String, String> {
+    let verifier = verifier.inner().clone();
+    let history = history.inner().clone();
+    let settings = settings.get();
+
+    tauri::async_runtime::spawn_blocking(move || verifier.run(&app, &history, &settings, scope))
+        .await
+        .map_err(|e| format!("Vault verification failed: {}", e))?
+}
+
+// Whether a verification was running; its report covers what was checked
+#[tauri::command]
+pub fn cancel_verify_vault(verifier: State<'_, VaultVerifier>) -> bool {
+    verifier.cancel()
+}
+
+// Fills in sizes, hashes and media info that older history entries lack;
+// emits `history-backfill-progress` and returns what was updated
+#[tauri::command]
+pub async fn backfill_history(
+    app: AppHandle,
+    backfill: State<'_, HistoryBackfill>,
+    history: State<'_, History>,
+    settings: State<'_, SettingsStore>,
+    scope: BackfillScope,
+    fields: Option<Vec<BackfillField>>,
+    refetch_remote: Option<bool>,
+) -> Result<BackfillSummary, String> {
+    let backfill = backfill.inner().clone();
+    let history = history.inner().clone();
+    let settings = settings.get();
+
+    tauri::async_runtime::spawn_blocking(move || {
+        backfill.run(&app, &history, &settings, scope, fields, refetch_remote.unwrap_or(false))
+    })
+    .await
+    .map_err(|e| format!("History backfill failed: {}", e))?
+}
+
+// Whether a backfill was running; what it filled in so far stays
+#[tauri::command]
+pub fn cancel_backfill_history(backfill: State<'_, HistoryBackfill>) -> bool {
+    backfill.cancel()
+}
+
+// Streams the files of history entries into a ZIP; emits
+// `vault-export-progress`. `flatten` drops the vault folders from the names.
+#[tauri::command]
+pub async fn export_items(
+    app: AppHandle,
+    exporter: State<'_, VaultExporter>,
+    history: State<'_, History>,
+    settings: State<'_, SettingsStore>,
+    file_ids: Vec<i64>,
+    destination_zip: String,
+    options: ItemExportOptions,
+) -> Result<ExportSummary, String> {
+    let exporter = exporter.inner().clone();
+    let history = history.inner().clone();
+    let settings = settings.get();
+
+    tauri::async_runtime::spawn_blocking(move || {
+        exporter.run(&app, &history, &settings, file_ids, Path::new(&destination_zip), &options)
+    })
+    .await
+    .map_err(|e| format!("Export failed: {}", e))?
+}
+
+// Whether an export was running; its partial archive is removed
+#[tauri::command]
+pub fn cancel_export_items(exporter: State<'_, VaultExporter>) -> bool {
+    exporter.cancel()
+}
+
+// Other entries whose files are links to the same content as this entry's;
+// the UI warns with these paths before deleting it
+#[tauri::command]
+pub fn get_shared_content(history: State<'_, History>, entry_id: i64) -> Result<Vec<String>, String> {
+    Ok(history
+        .shared_with(entry_id)?
+        .into_iter()
+        .map(|file| file.file_path)
+        .collect())
+}
+
+// A short clip of a saved video in Clips/ next to it; emits `clip-progress`
+// while ffmpeg runs and returns the clip's path and size
+#[tauri::command]
+pub async fn make_clip(app: AppHandle, history: State<'_, History>, request: clips::ClipRequest) -> Result<clips::Clip, String> {
+    let history = history.inner().clone();
+    tauri::async_runtime::spawn_blocking(move || clips::make_clip(&app, &history, &request))
+        .await
+        .map_err(|e| format!("Clip failed: {}", e))?
+}
+
+// Still frames of a saved video, at the given timestamps or one every
+// every_n_seconds, each recorded in history with a thumbnail
+#[tauri::command]
+pub async fn extract_frames(
+    history: State<'_, History>,
+    file_id: i64,
+    timestamps: Option<Vec<f64>>,
+    every_n_seconds: Option<f64>,
+    format: Option<frames::FrameFormat>,
+    quality: Option<u8>,
+) -> Result<frames::ExtractedFrames, String> {
+    let selection = match (timestamps, every_n_seconds) {
+        (Some(timestamps), None) => frames::FrameSelection::Timestamps(timestamps),
+        (None, Some(interval)) => frames::FrameSelection::EveryNSeconds(interval),
+        _ => return Err("Give either timestamps or every_n_seconds".to_string()),
+    };
+    let history = history.inner().clone();
+    let format = format.unwrap_or(frames::FrameFormat::Png);
+    tauri::async_runtime::spawn_blocking(move || frames::extract_frames(&history, file_id, selection, format, quality))
+        .await
+        .map_err(|e| format!("Frame extraction failed: {}", e))?
+}
+
+#[tauri::command]
+pub fn get_vault_stats(history: State<'_, History>) -> Result<VaultStats, String> {
+    history.vault_stats()
+}
+
+// Vault changes after `since_seq`, for tools that catch up on them
+#[tauri::command]
+pub async fn get_events(since_seq: Option<u64>, limit: Option<usize>) -> Result<vault_events::VaultEvents, String> {
+    tauri::async_runtime::spawn_blocking(move || vault_events::since(since_seq.unwrap_or(0), limit))
+        .await
+        .map_err(|e| format!("Reading vault events failed: {}", e))?
+}
+
+// Re-reads yt-dlp's extractor list now instead of when its version changes
+#[tauri::command]
+pub async fn refresh_extractor_cache(settings: State<'_, SettingsStore>) -> Result<extractors::ExtractorCacheStatus, String> {
+    let settings = settings.get();
+    tauri::async_runtime::spawn_blocking(move || extractors::refresh(&settings).map(|cache| cache.status()))
+        .await
+        .map_err(|e| format!("Extractor cache refresh failed: {}", e))?
+}
+
+// Counters of the GUI process since it started or was last reset; each
+// native messaging port answers get_metrics with its own
+#[tauri::command]
+pub fn get_metrics(jobs: State<'_, JobRegistry>) -> serde_json::Value {
+    metrics::snapshot(&jobs)
+}
+
+#[tauri::command]
+pub fn reset_metrics(jobs: State<'_, JobRegistry>) -> serde_json::Value {
+    metrics::reset();
+    metrics::snapshot(&jobs)
+}
+
+// How many new items each channel archive_channel was run on has
+#[tauri::command]
+pub async fn check_subscriptions(
+    history: State<'_, History>,
+    settings: State<'_, SettingsStore>,
+) -> Result<Vec<channel_archive::SubscriptionCheck>, String> {
+    let history = history.inner().clone();
+    let settings = settings.get();
+    tauri::async_runtime::spawn_blocking(move || channel_archive::check_all(&history, &settings))
+        .await
+        .map_err(|e| format!("Subscription check failed: {}", e))?
+}
+
+// Which organization rules a download would match and what they would do;
+// `rules` tries rules being edited before they are saved
+#[tauri::command]
+pub fn test_rules(settings: State<'_, SettingsStore>, sample_request: RuleSample, rules: Option<Vec<OrganizeRule>>) -> RuleMatch {
+    let rules = rules.unwrap_or_else(|| settings.get().organize_rules);
+    organize::evaluate(&rules, &sample_request.url, &sample_request.hints)
+}
+
+#[tauri::command]
+pub fn get_crash_reports() -> Result<Vec<crash::CrashReport>, String> {
+    crash::list_crash_reports()
+}
+
+// File name the template would give `url` and the user agent the download
+// would send, from a yt-dlp dry run; invalid templates fail before yt-dlp
+// runs, naming the offending token
+#[tauri::command]
+pub async fn preview_output_template(
+    template: String,
+    url: String,
+    user_agent: Option<String>,
+    settings: State<'_, SettingsStore>,
+) -> Result<TemplatePreview, String> {
+    validate_download_url(&url)?;
+    let settings = settings.get();
+    domain_policy::check(&settings, &url).map_err(|blocked| blocked.to_string())?;
+    let source_page = SourcePage::default().with_user_agents(user_agent, None)?;
+
+    tauri::async_runtime::spawn_blocking(move || output_template::preview(&template, &url, &settings, &source_page))
+        .await
+        .map_err(|e| format!("Template preview failed: {}", e))?
+}
+
+// Environment report for bug reports; runs a native protocol self-test, so off the main thread
+#[tauri::command]
+pub async fn run_diagnostics(
+    settings: State<'_, SettingsStore>,
+    history: State<'_, History>,
+    jobs: State<'_, JobRegistry>,
+) -> Result<serde_json::Value, String> {
+    let settings = settings.get();
+    let history = history.inner().clone();
+    let jobs = jobs.inner().clone();
+
+    tauri::async_runtime::spawn_blocking(move || diagnostics::run_diagnostics(&settings, &history, Some(&jobs)))
+        .await
+        .map_err(|e| format!("Diagnostics failed: {}", e))
+}
+
+// Step-by-step report of why `url` would fail to download, for bug reports
+#[tauri::command]
+pub async fn diagnose_url(
+    url: String,
+    referer: Option<String>,
+    settings: State<'_, SettingsStore>,
+) -> Result<serde_json::Value, String> {
+    let settings = settings.get();
+
+    tauri::async_runtime::spawn_blocking(move || url_diagnosis::diagnose(&settings, &url, referer.as_deref()))
+        .await
+        .map_err(|e| format!("URL diagnosis failed: {}", e))
+}
+
+// Which first-run steps are done, checked for real each time, so it doubles
+// as the health banner; includes the protocol self-test, so off the main thread
+#[tauri::command]
+pub async fn get_onboarding_state(
+    settings: State<'_, SettingsStore>,
+    history: State<'_, History>,
+) -> Result<onboarding::OnboardingState, String> {
+    let settings = settings.get();
+    let history = history.inner().clone();
+    tauri::async_runtime::spawn_blocking(move || onboarding::state(&settings, &history))
+        .await
+        .map_err(|e| format!("Setup check failed: {}", e))
+}
+
+#[tauri::command]
+pub async fn complete_onboarding_step(
+    settings: State<'_, SettingsStore>,
+    history: State<'_, History>,
+    step: onboarding::OnboardingStep,
+    extension_id: Option<String>,
+) -> Result<onboarding::StepState, String> {
+    let settings = settings.get();
+    let history = history.inner().clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        onboarding::complete_step(step, &settings, &history, extension_id.as_deref())
+    })
+    .await
+    .map_err(|e| format!("Setup step failed: {}", e))?
+}
+
+// Snapshots of the active profile's history database, newest first
+#[tauri::command]
+pub fn list_database_backups() -> Result<Vec<db_backup::DatabaseBackup>, String> {
+    db_backup::list(&Profile::active().directory()?)
+}
+
+// `restore_token` must be the one list_database_backups gave for the backup;
+// the current database is backed up before it is replaced
+#[tauri::command]
+pub async fn restore_database(
+    jobs: State<'_, JobRegistry>,
+    history: State<'_, History>,
+    backup_name: String,
+    restore_token: String,
+) -> Result<db_backup::DatabaseBackup, String> {
+    if jobs.in_flight() > 0 {
+        return Err("Wait for running downloads to finish before restoring the history database".to_string());
+    }
+    let history = history.inner().clone();
+    let directory = Profile::active().directory()?;
+    tauri::async_runtime::spawn_blocking(move || history.restore_backup(&directory, &backup_name, &restore_token))
+        .await
+        .map_err(|e| format!("Restore failed: {}", e))?
+}
+
+// Everything ImgVault created on this machine and whether it is still there,
+// in the order perform_cleanup removes it; nothing is changed
+#[tauri::command]
+pub async fn preview_cleanup(settings: State<'_, SettingsStore>) -> Result<Vec<cleanup::CleanupItem>, String> {
+    let base = settings.base();
+    tauri::async_runtime::spawn_blocking(move || cleanup::preview(&base))
+        .await
+        .map_err(|e| format!("Cleanup preview failed: {}", e))?
+}
+
+// Removes what preview_cleanup lists, with a result per item. Downloads in
+// the vault stay unless `keep_vault` is false.
+#[tauri::command]
+pub async fn perform_cleanup(
+    jobs: State<'_, JobRegistry>,
+    history: State<'_, History>,
+    settings: State<'_, SettingsStore>,
+    keep_vault: bool,
+) -> Result<cleanup::CleanupReport, String> {
+    if jobs.in_flight() > 0 {
+        return Err("Wait for running downloads to finish before cleaning up".to_string());
+    }
+    let history = history.inner().clone();
+    let base = settings.base();
+    tauri::async_runtime::spawn_blocking(move || cleanup::perform(&base, &history, keep_vault))
+        .await
+        .map_err(|e| format!("Cleanup failed: {}", e))?
+}
+
+// Reachability, latency and free space of a download folder, e.g. a vault
+// on \\nas\archive; reaching a share that is gone can take a while
+// Accepts a managed tool whose checksum no longer matches, for users who
+// patched their copy on purpose
+#[tauri::command]
+pub fn trust_managed_tool(path: String) -> Result<String, String> {
+    let sha256 = tool_integrity::record(Path::new(&path))?;
+    warn!(path = %path, sha256 = %sha256, "Managed tool trusted by the user");
+    Ok(format!("{} is trusted as it is now", path))
+}
+
+#[tauri::command]
+pub async fn check_destination(path: String) -> Result<destination::DestinationStatus, String> {
+    tauri::async_runtime::spawn_blocking(move || destination::check(Path::new(&path)))
+        .await
+        .map_err(|e| format!("Destination check failed: {}", e))
+}
+
+#[tauri::command]
+pub fn list_sessions(limit: usize) -> Result<Vec<history::NativeSession>, String> {
+    diagnostics::list_sessions(limit)
+}
+
+#[tauri::command]
+pub fn get_autostart() -> Result<autostart::AutostartStatus, String> {
+    autostart::get_autostart()
+}
+
+// Disabling removes only our own Run entry, never one written by the installer
+#[tauri::command]
+pub fn set_autostart(enabled: bool) -> Result<autostart::AutostartStatus, String> {
+    autostart::set_autostart(enabled)
+}
+
+// Whether Explorer offers "Send to ImgVault" for media files
+#[tauri::command]
+pub fn check_shell_integration() -> Result<shell_integration::ShellIntegrationStatus, String> {
+    shell_integration::get_shell_integration()
+}
+
+#[tauri::command]
+pub fn install_shell_integration() -> Result<shell_integration::ShellIntegrationStatus, String> {
+    shell_integration::install_shell_integration()
+}
+
+// Removes only the menu entries install_shell_integration wrote
+#[tauri::command]
+pub fn remove_shell_integration() -> Result<shell_integration::ShellIntegrationStatus, String> {
+    shell_integration::remove_shell_integration()
+}
+
+// Whether URLs and titles in the history database are encrypted
+#[tauri::command]
+pub fn get_history_encryption(history: State<'_, History>) -> Result<HistoryEncryption, String> {
+    history.encryption()
+}
+
+// Opt-in and one way: encrypts the existing history and every later entry
+#[tauri::command]
+pub fn encrypt_history(history: State<'_, History>) -> Result<HistoryEncryption, String> {
+    let encryption = history.encrypt_existing()?;
+    info!(rows = encryption.encrypted_rows, "Encrypted download history");
+    Ok(encryption)
+}
+
+// The default profile and every named one, each with its vault folder
+#[tauri::command]
+pub fn list_profiles(settings: State<'_, SettingsStore>) -> Result<Vec<profiles::ProfileInfo>, String> {
+    profiles::list(&settings.base())
+}
+
+// `overrides` holds the settings the profile sets differently, e.g. vault_root
+#[tauri::command]
+pub fn create_profile(
+    settings: State<'_, SettingsStore>,
+    name: String,
+    overrides: Option<serde_json::Value>,
+) -> Result<profiles::ProfileInfo, String> {
+    profiles::create(&settings.base(), &name, overrides)
+}
+
+// `confirm_name` must repeat the name; downloaded files are only deleted
+// with `delete_media`
+#[tauri::command]
+pub fn delete_profile(
+    settings: State<'_, SettingsStore>,
+    name: String,
+    confirm_name: String,
+    delete_media: Option<bool>,
+) -> Result<profiles::DeletedProfile, String> {
+    profiles::delete(&settings.base(), &name, &confirm_name, delete_media.unwrap_or(false))
+}
+
+// Points the GUI's history, settings and new downloads at another profile
+#[tauri::command]
+pub fn set_active_profile(
+    jobs: State<'_, JobRegistry>,
+    history: State<'_, History>,
+    settings: State<'_, SettingsStore>,
+    name: String,
+) -> Result<profiles::ProfileInfo, String> {
+    if jobs.in_flight() > 0 {
+        return Err("Wait for running downloads to finish before switching profiles".to_string());
+    }
+    let profile = Profile::load(Some(&name))?;
+    history.switch_directory(&profile.directory()?)?;
+    profiles::set_active(&profile)?;
+    settings.set_profile(profile.clone());
+    profiles::list(&settings.base())?
+        .into_iter()
+        .find(|info| info.name == profile.name)
+        .ok_or_else(|| format!("Profile \"{}\" does not exist", profile.name))
+}
+
+// Latest release on the configured channel; `force` skips the one-day cache
+#[tauri::command]
+pub async fn check_for_updates(
+    settings: State<'_, SettingsStore>,
+    force: Option<bool>,
+) -> Result<updates::UpdateInfo, String> {
+    let settings = settings.get();
+
+    tauri::async_runtime::spawn_blocking(move || {
+        updates::check_for_updates(&settings, force.unwrap_or(false))
+    })
+    .await
+    .map_err(|e| format!("Update check failed: {}", e))?
+}
+
+// Fetch and verify the installer; running it is left to the user
+#[tauri::command]
+pub async fn download_update(
+    settings: State<'_, SettingsStore>,
+) -> Result<updates::DownloadedUpdate, String> {
+    let settings = settings.get();
+
+    tauri::async_runtime::spawn_blocking(move || updates::download_update(&settings))
+        .await
+        .map_err(|e| format!("Update download failed: {}", e))?
+}
+
+// Check if the native messaging host is registered
+#[tauri::command]
+pub fn check_registration() -> Result<bool, String> {
+    #[cfg(target_os = "windows")]
+    {
+        let hkcu = RegKey::predef(HKEY_CURRENT_USER);
+        let path = r"Software\Google\Chrome\NativeMessagingHosts\com.imgvault.nativehost";
+        
+        match hkcu.open_subkey(path) {
+            Ok(_) => Ok(true),
+            Err(_) => Ok(false),
+        }
+    }
+    
+    #[cfg(not(target_os = "windows"))]
+    Ok(false)
+}
+
+// Browser profiles with the extension installed, and whether the registered
+// manifest allows each one's extension ID
+#[tauri::command]
+pub fn check_browser_profiles(settings: State<'_, SettingsStore>) -> Vec<browser_profiles::ProfileRegistration> {
+    browser_profiles::scan(&settings.get())
+}
+
+// Register the native messaging host, reporting each artifact created
+#[tauri::command]
+pub fn register_host(extension_id: String) -> Result<RegistrationReport, String> {
+    register_native_host(&extension_id, "chrome")
+}
+
+// Unregister the native messaging host, reporting each artifact removed
+#[tauri::command]
+pub fn unregister_host() -> Result<RegistrationReport, String> {
+    unregister_native_host("chrome")
+}
+
+#[tauri::command]
+pub fn reload_path() -> Result<String, String> {
+    #[cfg(target_os = "windows")]
+    {
+        reload_windows_path_environment()
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    {
+        Err("PATH reload is only supported on Windows".to_string())
+    }
+}
+
+#[tauri::command]
+pub fn check_cookies() -> Result<String, String> {
+    let cookies_path = get_cookies_path()?;
+    if cookies_path.exists() {
+        Ok(format!("cookies.txt found: {}", cookies_path.display()))
+    } else {
+        Err(format!("cookies.txt not found: {}", cookies_path.display()))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TestDownloadRequest {
+    url: String,
+    output_path: String,
+    hide_window: bool,
+    job_id: Option<String>,
+    upload: Option<bool>,
+    priority: Option<Priority>,
+    backend: Option<Backend>,
+    ignore_policy: Option<bool>,
+    hints: Option<MediaHints>,
+    force_new: Option<bool>,
+    split_chapters: Option<bool>,
+    tag_origin: Option<bool>,
+    user_agent: Option<String>,
+}
+
+// Test download with detailed output (for GUI)
+#[tauri::command]
+pub async fn test_download(
+    app: AppHandle,
+    jobs: State<'_, JobRegistry>,
+    history: State<'_, History>,
+    settings: State<'_, SettingsStore>,
+    request: TestDownloadRequest,
+) -> Result<serde_json::Value, String> {
+    let TestDownloadRequest {
+        url,
+        output_path,
+        hide_window,
+        job_id,
+        upload,
+        priority,
+        backend,
+        ignore_policy,
+        hints,
+        force_new,
+        split_chapters,
+        tag_origin,
+        user_agent,
+    } = request;
+    let jobs = jobs.inner().clone();
+    let history = history.inner().clone();
+    let settings = media_policy::for_request(settings.get(), ignore_policy.unwrap_or(false));
+    let settings = chapters::for_request(settings, split_chapters);
+    let settings = origin_tag::for_request(settings, tag_origin);
+    // The GUI is no browser, so "browser" falls back to the backend's own
+    let source_page = SourcePage::default().with_user_agents(user_agent, None)?;
+    let job_id = job_id.unwrap_or_else(|| generate_job_id("gui"));
+    let (settings, output_path) =
+        organize::organize_job(&history, settings, &job_id, &url, &hints.unwrap_or_default(), &output_path)?;
+
+    tauri::async_runtime::spawn_blocking(move || {
+        // May wait for the window to say what to do about an existing file
+        let (settings, output_path) = conflicts::settle_interactive(&app, settings, &job_id, &url, output_path, backend);
+        // Held until run_gui_download has recorded the outcome
+        let _claim = match coalesce::claim(&job_id, &url, &output_path, force_new.unwrap_or(false)) {
+            Ok(Claim::Owner(guard)) => Some(guard),
+            Ok(Claim::Attached { job_id: existing, since }) => {
+                let entry = coalesce::wait_for(&jobs, &history, &existing, since)?;
+                return coalesced_gui_result(&existing, entry);
+            }
+            Err(error) => {
+                warn!(job_id = %job_id, "Not checking for duplicate requests: {}", error);
+                None
+            }
+        };
+        let format_selector = settings.default_quality.format_selector();
+        let context = DownloadContext {
+            job_id: &job_id,
+            url: &url,
+            output_path: &output_path,
+            upload: upload.unwrap_or(true),
+            priority: priority.unwrap_or_default(),
+            source_page: &source_page,
+            source: "gui",
+        };
+        run_gui_download(&jobs, &history, &settings, &context, format_selector, hide_window, backend)
+    })
+    .await
+    .map_err(|e| format!("Download task failed: {}", e))?
+}
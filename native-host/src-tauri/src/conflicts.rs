@@ -0,0 +1,282 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+use tauri::{AppHandle, Manager};
+use tracing::{debug, info};
+
+use crate::diagnostics::hide_console_window;
+use crate::downloader::{self, Backend};
+use crate::organize::CollisionMode;
+use crate::settings::Settings;
+use crate::{console_text, current_timestamp_millis, drop_import, logging, long_path, tool_integrity, webhooks};
+
+// Sent to the window when a download waits for a decision, and when it has one
+pub const CONFLICT_EVENT: &str = "download-conflict";
+pub const CONFLICT_RESOLVED_EVENT: &str = "download-conflict-resolved";
+pub const MIN_CONFLICT_TIMEOUT_MINUTES: u64 = 1;
+pub const MAX_CONFLICT_TIMEOUT_MINUTES: u64 = 24 * 60;
+const AWAITING_DECISION: &str = "awaiting_decision";
+
+// What a download does about a file already at its path, when the collision
+// mode is ask
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictDecision {
+    // Download to "name (1).ext" and keep both
+    Rename,
+    Overwrite,
+    // Keep the existing file and download nothing
+    Skip,
+}
+
+// The file in the way and what the download would put there
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadConflict {
+    pub job_id: String,
+    pub url: String,
+    pub state: &'static str,
+    pub path: String,
+    pub existing_size: u64,
+    pub existing_modified_at: Option<i64>,
+    // None when the file could not be read
+    pub existing_sha256: Option<String>,
+    // yt-dlp's filesize, or its estimate; None when it gave neither
+    pub incoming_size: Option<u64>,
+    // Where a rename would put the download
+    pub renamed_path: String,
+    // Epoch millis at which the default is used
+    pub decide_by: i64,
+    pub default_decision: ConflictDecision,
+}
+
+// By job id, with where to send the decision
+type WaitingPrompts = HashMap<String, (DownloadConflict, mpsc::Sender<ConflictDecision>)>;
+
+// Downloads waiting for a decision, and the one given for all of them this
+// session; managed by the GUI
+#[derive(Clone, Default)]
+pub struct ConflictPrompts {
+    waiting: Arc<Mutex<WaitingPrompts>>,
+    remembered: Arc<Mutex<Option<ConflictDecision>>>,
+}
+
+impl ConflictPrompts {
+    // With apply_to_all the other waiting downloads get the same answer, and
+    // later conflicts are not asked about until the GUI restarts
+    pub fn resolve(&self, job_id: &str, decision: ConflictDecision, apply_to_all: bool) -> Result<(), String> {
+        let mut waiting = self.waiting.lock().unwrap();
+        let (_, sender) = waiting
+            .remove(job_id)
+            .ok_or_else(|| format!("Download {} is not waiting for a decision", job_id))?;
+        if apply_to_all {
+            *self.remembered.lock().unwrap() = Some(decision);
+            for (other, (_, other_sender)) in waiting.drain() {
+                info!(job_id = %other, decision = ?decision, "Applying the decision to a waiting download");
+                let _ = other_sender.send(decision);
+            }
+        }
+        sender
+            .send(decision)
+            .map_err(|_| format!("Download {} is no longer waiting for a decision", job_id))
+    }
+
+    // For a window opened while downloads wait
+    pub fn pending(&self) -> Vec<DownloadConflict> {
+        let mut pending = self
+            .waiting
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(conflict, _)| conflict.clone())
+            .collect::<Vec<_>>();
+        pending.sort_by_key(|conflict| conflict.decide_by);
+        pending
+    }
+
+    fn remembered(&self) -> Option<ConflictDecision> {
+        *self.remembered.lock().unwrap()
+    }
+}
+
+// Where a download would write, and whether something is there
+struct Existing {
+    path: PathBuf,
+    renamed: PathBuf,
+    incoming_size: Option<u64>,
+}
+
+// For downloads started from the window: asks it when collision_mode is ask
+// and the file exists, waiting up to conflict_timeout_minutes before using
+// conflict_default. Blocks, so call it off the async runtime.
+pub fn settle_interactive(
+    app: &AppHandle,
+    settings: Settings,
+    job_id: &str,
+    url: &str,
+    output_path: String,
+    backend: Option<Backend>,
+) -> (Settings, String) {
+    let prompts = app.state::<ConflictPrompts>().inner().clone();
+    settle_with(settings, url, output_path, backend, |settings, existing| {
+        if let Some(decision) = prompts.remembered() {
+            info!(job_id, decision = ?decision, "Using the decision given for all conflicts this session");
+            return decision;
+        }
+        let timeout = Duration::from_secs(settings.conflict_timeout_minutes * 60);
+        let conflict = describe(job_id, url, existing, settings, timeout);
+        let (decision_tx, decision_rx) = mpsc::channel();
+        prompts
+            .waiting
+            .lock()
+            .unwrap()
+            .insert(job_id.to_string(), (conflict.clone(), decision_tx));
+        info!(job_id, path = %conflict.path, "Download is awaiting a decision about the existing file");
+        let _ = app.emit_all(CONFLICT_EVENT, &conflict);
+
+        let decision = match decision_rx.recv_timeout(timeout) {
+            Ok(decision) => decision,
+            Err(_) => {
+                prompts.waiting.lock().unwrap().remove(job_id);
+                info!(job_id, decision = ?settings.conflict_default, "No decision in time; using the default");
+                settings.conflict_default
+            }
+        };
+        let _ = app.emit_all(
+            CONFLICT_RESOLVED_EVENT,
+            serde_json::json!({ "jobId": job_id, "decision": decision }),
+        );
+        decision
+    })
+}
+
+// For every other download: conflict_default, without asking
+pub fn settle(settings: Settings, url: &str, output_path: String, backend: Option<Backend>) -> (Settings, String) {
+    settle_with(settings, url, output_path, backend, |settings, existing| {
+        debug!(path = %existing.path.display(), decision = ?settings.conflict_default, "File exists; using the default");
+        settings.conflict_default
+    })
+}
+
+fn settle_with(
+    mut settings: Settings,
+    url: &str,
+    output_path: String,
+    backend: Option<Backend>,
+    decide: impl FnOnce(&Settings, &Existing) -> ConflictDecision,
+) -> (Settings, String) {
+    if settings.collision_mode != Some(CollisionMode::Ask) {
+        return (settings, output_path);
+    }
+    let backend = downloader::route(url, &settings, backend).backend();
+    let Some(existing) = find_existing(&settings, backend, url, &output_path) else {
+        // Nothing in the way now; one that turns up meanwhile is kept
+        return (settings, output_path);
+    };
+    match decide(&settings, &existing) {
+        ConflictDecision::Overwrite => {
+            settings.collision_mode = Some(CollisionMode::Overwrite);
+            (settings, output_path)
+        }
+        ConflictDecision::Skip => {
+            settings.collision_mode = Some(CollisionMode::Skip);
+            (settings, output_path)
+        }
+        ConflictDecision::Rename => {
+            settings.collision_mode = Some(CollisionMode::Skip);
+            let renamed = existing.renamed.display().to_string();
+            info!(url = logging::loggable_url(url), path = %renamed, "Downloading under a new name");
+            // The new name goes to yt-dlp as its template
+            let output_path = if backend == Backend::YtDlp { renamed.replace('%', "%%") } else { renamed };
+            (settings, output_path)
+        }
+    }
+}
+
+// yt-dlp names its file before downloading with --simulate; other backends
+// only when the output path is a file rather than a template
+fn find_existing(settings: &Settings, backend: Backend, url: &str, output_path: &str) -> Option<Existing> {
+    let (path, incoming_size) = match backend {
+        Backend::YtDlp => predict_with_yt_dlp(settings, url, output_path)?,
+        _ if !output_path.contains("%(") => (PathBuf::from(output_path), None),
+        _ => return None,
+    };
+    if !long_path::extended(&path).is_file() {
+        return None;
+    }
+    let renamed = drop_import::unique_destination(path.parent()?, Path::new(path.file_name()?));
+    Some(Existing {
+        path,
+        renamed,
+        incoming_size,
+    })
+}
+
+fn predict_with_yt_dlp(settings: &Settings, url: &str, output_path: &str) -> Option<(PathBuf, Option<u64>)> {
+    tool_integrity::verify(settings.yt_dlp_program()).ok()?;
+    let mut command = Command::new(settings.yt_dlp_program());
+    command
+        .arg(url)
+        .arg("--simulate")
+        .arg("--no-playlist")
+        .arg("--no-warnings")
+        .arg("-f")
+        .arg(settings.default_quality.format_selector())
+        .arg("--merge-output-format")
+        .arg("mkv")
+        .arg("-o")
+        .arg(output_path)
+        .arg("--print")
+        .arg("filename")
+        .arg("--print")
+        .arg("%(filesize,filesize_approx)s");
+    console_text::force_utf8(&mut command);
+    hide_console_window(&mut command);
+
+    let output = match command.output() {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            debug!(code = ?output.status.code(), "yt-dlp could not name the file; not checking for a conflict");
+            return None;
+        }
+        Err(error) => {
+            debug!("Failed to execute yt-dlp: {}", error);
+            return None;
+        }
+    };
+    let stdout = console_text::decode(&output.stdout);
+    let mut lines = stdout.lines().map(str::trim).filter(|line| !line.is_empty());
+    let path = PathBuf::from(lines.next()?);
+    let incoming_size = lines.next().and_then(|size| size.parse::<f64>().ok()).map(|size| size as u64);
+    Some((path, incoming_size))
+}
+
+fn describe(job_id: &str, url: &str, existing: &Existing, settings: &Settings, timeout: Duration) -> DownloadConflict {
+    let metadata = fs::metadata(long_path::extended(&existing.path)).ok();
+    let existing_modified_at = metadata
+        .as_ref()
+        .and_then(|metadata| metadata.modified().ok())
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_millis() as i64);
+    let existing_sha256 = webhooks::hash_file(&existing.path.to_string_lossy())
+        .ok()
+        .map(|(_, digest)| digest);
+    DownloadConflict {
+        job_id: job_id.to_string(),
+        url: logging::loggable_url(url).to_string(),
+        state: AWAITING_DECISION,
+        path: existing.path.display().to_string(),
+        existing_size: metadata.map_or(0, |metadata| metadata.len()),
+        existing_modified_at,
+        existing_sha256,
+        incoming_size: existing.incoming_size,
+        renamed_path: existing.renamed.display().to_string(),
+        decide_by: current_timestamp_millis() + timeout.as_millis() as i64,
+        default_decision: settings.conflict_default,
+    }
+}
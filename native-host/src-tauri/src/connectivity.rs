@@ -0,0 +1,147 @@
+use std::fs;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::jobs::JobRegistry;
+use crate::protocol::NativeResponse;
+use crate::settings::{Settings, SettingsStore};
+use crate::get_app_data_directory;
+
+// Present while the network is down, so every host process holds its
+// downloads back, like the paused queue file
+const OFFLINE_FILE_NAME: &str = "network-offline";
+pub const DEFAULT_PROBE_URL: &str = "http://www.gstatic.com/generate_204";
+pub const DEFAULT_CHECK_INTERVAL_SECS: u64 = 30;
+pub const MIN_CHECK_INTERVAL_SECS: u64 = 5;
+pub const MAX_CHECK_INTERVAL_SECS: u64 = 60 * 60;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+// One lost probe on a busy line is not an outage
+const FAILED_PROBES_BEFORE_OFFLINE: u32 = 2;
+// While it looks offline, the next probe comes sooner than the interval
+const OFFLINE_RECHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+pub fn is_offline() -> bool {
+    get_app_data_directory()
+        .map(|directory| directory.join(OFFLINE_FILE_NAME).exists())
+        .unwrap_or(false)
+}
+
+fn persist_offline(offline: bool) -> Result<(), String> {
+    let directory = get_app_data_directory()?;
+    let path = directory.join(OFFLINE_FILE_NAME);
+    if offline {
+        fs::create_dir_all(&directory)
+            .and_then(|_| fs::write(&path, b""))
+            .map_err(|e| format!("Failed to save offline state: {}", e))
+    } else {
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(format!("Failed to clear offline state: {}", error)),
+        }
+    }
+}
+
+// Any answer from the probe URL, even an error page, means the network is
+// there; only failing to reach it at all counts as offline. Always true when
+// monitoring is off.
+pub fn probe(settings: &Settings) -> bool {
+    if settings.connectivity_probe_url.is_empty() {
+        return true;
+    }
+    let agent = ureq::AgentBuilder::new().timeout(PROBE_TIMEOUT).redirects(0).build();
+    match agent.head(&settings.connectivity_probe_url).call() {
+        Ok(_) | Err(ureq::Error::Status(..)) => true,
+        Err(ureq::Error::Transport(_)) => false,
+    }
+}
+
+// Whether a download that failed to connect failed because the network is
+// gone: the monitor already says so, or a probe right now agrees
+pub fn offline_now(settings: &Settings) -> bool {
+    !settings.connectivity_probe_url.is_empty() && (is_offline() || !probe(settings))
+}
+
+// Probes every connectivity_check_secs, and right away when Windows reports
+// an address change, for the life of the GUI process. Going offline holds
+// back dispatch and retries in every host process; coming back wakes the
+// scheduler, which continues the downloads the outage stopped.
+pub fn start(settings: SettingsStore, jobs: JobRegistry) {
+    let changed = Arc::new((Mutex::new(false), Condvar::new()));
+    watch_network_changes(Arc::clone(&changed));
+
+    std::thread::spawn(move || {
+        // A marker left by a previous run is checked again before it holds anything back
+        let mut offline = is_offline();
+        let mut failed_probes = 0;
+        loop {
+            let current = settings.get();
+            if current.connectivity_probe_url.is_empty() || probe(&current) {
+                failed_probes = 0;
+            } else {
+                failed_probes += 1;
+            }
+            let now_offline = failed_probes >= FAILED_PROBES_BEFORE_OFFLINE;
+            if now_offline != offline {
+                transition(&jobs, now_offline);
+                offline = now_offline;
+            }
+
+            let wait = if failed_probes > 0 {
+                OFFLINE_RECHECK_INTERVAL
+            } else {
+                Duration::from_secs(current.connectivity_check_secs)
+            };
+            let (woken, condvar) = &*changed;
+            let mut woken = woken.lock().unwrap();
+            if !*woken {
+                woken = condvar.wait_timeout(woken, wait).unwrap().0;
+            }
+            *woken = false;
+        }
+    });
+}
+
+fn transition(jobs: &JobRegistry, offline: bool) {
+    if let Err(error) = persist_offline(offline) {
+        warn!("{}", error);
+    }
+    let (event, message) = if offline {
+        warn!("Network connection lost, holding downloads back");
+        ("offline", "Network connection lost; downloads wait until it is back")
+    } else {
+        info!("Network connection is back, continuing downloads");
+        ("online", "Network connection is back")
+    };
+    jobs.events().publish(&NativeResponse {
+        message: Some(message.to_string()),
+        ..NativeResponse::job_event(event, None)
+    });
+    if !offline {
+        jobs.scheduler().wake();
+    }
+}
+
+// NotifyAddrChange blocks until an interface gains or loses an address,
+// which is when Wi-Fi drops or comes back
+#[cfg(target_os = "windows")]
+fn watch_network_changes(changed: Arc<(Mutex<bool>, Condvar)>) {
+    use winapi::shared::winerror::NO_ERROR;
+    use winapi::um::iphlpapi::NotifyAddrChange;
+
+    std::thread::spawn(move || loop {
+        let result = unsafe { NotifyAddrChange(std::ptr::null_mut(), std::ptr::null()) };
+        if result != NO_ERROR {
+            warn!("Stopped watching for network changes: NotifyAddrChange returned {}", result);
+            return;
+        }
+        let (woken, condvar) = &*changed;
+        *woken.lock().unwrap() = true;
+        condvar.notify_all();
+    });
+}
+
+// Elsewhere the periodic probe is all there is
+#[cfg(not(target_os = "windows"))]
+fn watch_network_changes(_changed: Arc<(Mutex<bool>, Condvar)>) {}
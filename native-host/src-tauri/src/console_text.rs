@@ -0,0 +1,108 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use tracing::debug;
+
+use crate::long_path;
+
+// What from_utf8_lossy puts in place of bytes it could not read
+const REPLACEMENT_CHARACTER: char = '\u{FFFD}';
+
+// Python writes to a pipe in the ANSI code page on Windows, so a Japanese
+// title reaches us as cp932 bytes; these make yt-dlp and gallery-dl write
+// UTF-8 instead. Frozen builds that ignore them are handled by decode.
+pub fn force_utf8(command: &mut Command) {
+    command.env("PYTHONIOENCODING", "utf-8").env("PYTHONUTF8", "1");
+}
+
+// A line of a child's output. Bytes that are not UTF-8 are read in the
+// console's code page on Windows, and lossily everywhere else.
+pub fn decode(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => decode_code_page(bytes).unwrap_or_else(|| String::from_utf8_lossy(bytes).into_owned()),
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn decode_code_page(bytes: &[u8]) -> Option<String> {
+    use winapi::um::consoleapi::GetConsoleOutputCP;
+    use winapi::um::stringapiset::MultiByteToWideChar;
+    use winapi::um::winnls::GetACP;
+
+    let length = i32::try_from(bytes.len()).ok()?;
+    // Started by Chrome or from the tray there is no console, and Python
+    // falls back to the ANSI code page
+    let code_page = match unsafe { GetConsoleOutputCP() } {
+        0 => unsafe { GetACP() },
+        code_page => code_page,
+    };
+    let wide_length =
+        unsafe { MultiByteToWideChar(code_page, 0, bytes.as_ptr().cast(), length, std::ptr::null_mut(), 0) };
+    if wide_length <= 0 {
+        return None;
+    }
+    let mut wide = vec![0u16; wide_length as usize];
+    let written =
+        unsafe { MultiByteToWideChar(code_page, 0, bytes.as_ptr().cast(), length, wide.as_mut_ptr(), wide_length) };
+    if written <= 0 {
+        return None;
+    }
+    Some(String::from_utf16_lossy(&wide[..written as usize]))
+}
+
+#[cfg(not(target_os = "windows"))]
+fn decode_code_page(_bytes: &[u8]) -> Option<String> {
+    None
+}
+
+// The path a child reported, as its folder names the file. A name that lost
+// characters in decoding is found again by the rest of it; the path stays
+// as reported when no file or more than one file fits.
+pub fn on_disk(path: String) -> String {
+    let reported = Path::new(&path);
+    if !path.contains(REPLACEMENT_CHARACTER) || fs::symlink_metadata(long_path::extended(reported)).is_ok() {
+        return path;
+    }
+    let (Some(folder), Some(name)) = (reported.parent(), reported.file_name()) else {
+        return path;
+    };
+    let name = name.to_string_lossy();
+    let Ok(entries) = fs::read_dir(long_path::extended(folder)) else {
+        return path;
+    };
+    let found = entries
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|entry_name| fits(entry_name, &name))
+        .collect::<Vec<_>>();
+    match found.as_slice() {
+        [entry_name] => {
+            let actual = folder.join(entry_name).display().to_string();
+            debug!(reported = %path, actual = %actual, "Found the file under the name its folder has");
+            actual
+        }
+        _ => path,
+    }
+}
+
+// Whether `name` is `reported` with something, at least one character, in
+// place of each run of replacement characters
+fn fits(name: &str, reported: &str) -> bool {
+    let pieces = reported.split(REPLACEMENT_CHARACTER).collect::<Vec<_>>();
+    let (first, last) = (pieces[0], pieces[pieces.len() - 1]);
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    // Empty pieces are the gaps within a run
+    for piece in pieces[1..pieces.len() - 1].iter().filter(|piece| !piece.is_empty()) {
+        let Some(skipped) = rest.char_indices().nth(1).map(|(index, _)| &rest[index..]) else {
+            return false;
+        };
+        let Some(start) = skipped.find(piece) else {
+            return false;
+        };
+        rest = &skipped[start + piece.len()..];
+    }
+    rest.chars().count() > last.chars().count() && rest.ends_with(last)
+}
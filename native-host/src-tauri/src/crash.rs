@@ -0,0 +1,139 @@
+use serde::Serialize;
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::fs;
+use std::panic;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+use tracing::error;
+
+use crate::{current_timestamp_millis, get_app_data_directory};
+
+const CRASH_FILE_PREFIX: &str = "crash-";
+const KEEP_CRASH_REPORTS: usize = 20;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CrashReport {
+    pub path: String,
+    pub created_at: i64,
+    pub size_bytes: u64,
+    // First line of the report, the panic message
+    pub summary: String,
+}
+
+// Write every panic, with a backtrace, to crash-<timestamp>.log in the app data
+// directory and to the log. The default hook still runs so debug builds keep
+// printing to stderr.
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let message = panic_message(info.payload());
+        let location = info
+            .location()
+            .map(|location| format!("{}:{}", location.file(), location.line()))
+            .unwrap_or_else(|| "unknown location".to_string());
+        let thread = std::thread::current().name().unwrap_or("unnamed").to_string();
+
+        let report = format!(
+            "panic: {}\nlocation: {}\nthread: {}\nversion: {}\n\n{}",
+            message,
+            location,
+            thread,
+            env!("CARGO_PKG_VERSION"),
+            Backtrace::force_capture()
+        );
+        error!(location = %location, thread = %thread, "Panic: {}", message);
+        if let Err(write_error) = write_crash_report(&report) {
+            error!("Failed to write crash report: {}", write_error);
+        }
+
+        default_hook(info);
+    }));
+}
+
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+fn write_crash_report(report: &str) -> Result<(), String> {
+    let directory = get_app_data_directory()?;
+    fs::create_dir_all(&directory)
+        .map_err(|e| format!("Failed to create app data directory: {}", e))?;
+
+    let path = directory.join(format!(
+        "{}{}-{}.log",
+        CRASH_FILE_PREFIX,
+        current_timestamp_millis(),
+        std::process::id()
+    ));
+    fs::write(&path, report).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+    prune_crash_reports();
+    Ok(())
+}
+
+fn crash_report_paths() -> Result<Vec<PathBuf>, String> {
+    let directory = get_app_data_directory()?;
+    let entries = match fs::read_dir(&directory) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(Vec::new()),
+    };
+
+    let mut paths = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(CRASH_FILE_PREFIX) && name.ends_with(".log"))
+        })
+        .collect::<Vec<_>>();
+    // Timestamps in the names sort newest last; reverse for newest first
+    paths.sort();
+    paths.reverse();
+    Ok(paths)
+}
+
+fn prune_crash_reports() {
+    if let Ok(paths) = crash_report_paths() {
+        for path in paths.iter().skip(KEEP_CRASH_REPORTS) {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// Most recent crash reports, newest first
+pub fn list_crash_reports() -> Result<Vec<CrashReport>, String> {
+    let reports = crash_report_paths()?
+        .into_iter()
+        .filter_map(|path| {
+            let metadata = fs::metadata(&path).ok()?;
+            let created_at = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|duration| duration.as_millis() as i64)
+                .unwrap_or(0);
+            let summary = fs::read_to_string(&path)
+                .ok()
+                .and_then(|contents| contents.lines().next().map(|line| line.to_string()))
+                .unwrap_or_default();
+
+            Some(CrashReport {
+                path: path.display().to_string(),
+                created_at,
+                size_bytes: metadata.len(),
+                summary,
+            })
+        })
+        .collect();
+
+    Ok(reports)
+}
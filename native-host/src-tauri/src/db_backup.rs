@@ -0,0 +1,396 @@
+use rusqlite::{Connection, DatabaseName, OpenFlags};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+use tracing::{error, info, warn};
+
+use crate::history::History;
+use crate::{current_timestamp_millis, timestamps};
+
+// Snapshots of the history database, next to it, newest kept
+pub const BACKUP_DIRECTORY: &str = "backups";
+const KEEP_BACKUPS: usize = 7;
+const BACKUP_PREFIX: &str = "history-";
+const BACKUP_EXTENSION: &str = "db";
+// Enough of integrity_check's list to say what is wrong
+const MAX_INTEGRITY_MESSAGES: usize = 5;
+
+// Why the database was not opened this session, when it was not
+static OPEN_FAILURE: Mutex<Option<String>> = Mutex::new(None);
+// What the check at startup found; None until it has run
+static STARTUP_CHECK: Mutex<Option<Result<(), String>>> = Mutex::new(None);
+// The database and UTC day of the last daily snapshot this process made or found
+static LAST_DAILY: Mutex<Option<(String, String)>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotReason {
+    // On the first write of a day
+    Daily,
+    // Before the schema is upgraded
+    Migration,
+    // Before a backup replaces the database
+    BeforeRestore,
+    // A database that could not be opened, moved out of the way
+    Damaged,
+}
+
+impl SnapshotReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            SnapshotReason::Daily => "daily",
+            SnapshotReason::Migration => "migration",
+            SnapshotReason::BeforeRestore => "before-restore",
+            SnapshotReason::Damaged => "damaged",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DatabaseBackup {
+    pub name: String,
+    pub reason: String,
+    pub created_at: i64,
+    pub size_bytes: u64,
+    // restore_database wants it back, so a restore names the very file that
+    // was listed and not one written since under the same name
+    pub restore_token: String,
+}
+
+// Copies the database behind `conn` into the backup folder of `directory`
+// through SQLite's backup API, which reads a consistent state of a live WAL
+// database that a file copy would not
+pub fn snapshot(conn: &Connection, directory: &Path, reason: SnapshotReason) -> Result<PathBuf, String> {
+    let backups = directory.join(BACKUP_DIRECTORY);
+    fs::create_dir_all(&backups)
+        .map_err(|e| format!("Failed to create backup folder {}: {}", backups.display(), e))?;
+    let path = backups.join(backup_name(current_timestamp_millis(), reason));
+    let partial = path.with_extension("part");
+    conn.backup(DatabaseName::Main, &partial, None)
+        .map_err(|e| format!("Failed to back up the history database: {}", e))?;
+    fs::rename(&partial, &path).map_err(|e| format!("Failed to save backup {}: {}", path.display(), e))?;
+    info!(backup = %path.display(), reason = reason.as_str(), "Backed up the history database");
+    prune(&backups);
+    Ok(path)
+}
+
+// The daily snapshot, unless the database already has one from today (UTC)
+pub fn snapshot_daily(conn: &Connection) {
+    let Some(database) = conn.path().filter(|path| !path.is_empty()) else {
+        return;
+    };
+    let today = timestamps::format_rfc3339(current_timestamp_millis())[..10].to_string();
+    let done = (database.to_string(), today.clone());
+    let mut last = LAST_DAILY.lock().unwrap();
+    if last.as_ref() == Some(&done) {
+        return;
+    }
+    let Some(directory) = Path::new(database).parent() else {
+        return;
+    };
+    let daily_prefix = format!("{}{}T", BACKUP_PREFIX, today);
+    let daily_suffix = format!("-{}.{}", SnapshotReason::Daily.as_str(), BACKUP_EXTENSION);
+    let found = fs::read_dir(directory.join(BACKUP_DIRECTORY)).is_ok_and(|entries| {
+        entries.flatten().any(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            name.starts_with(&daily_prefix) && name.ends_with(&daily_suffix)
+        })
+    });
+    if !found {
+        if let Err(error) = snapshot(conn, directory, SnapshotReason::Daily) {
+            // Tried again on the next write
+            warn!("{}", error);
+            return;
+        }
+    }
+    *last = Some(done);
+}
+
+// Backups of the database in `directory`, newest first
+pub fn list(directory: &Path) -> Result<Vec<DatabaseBackup>, String> {
+    let backups = directory.join(BACKUP_DIRECTORY);
+    let entries = match fs::read_dir(&backups) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let mut listed = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let reason = parse_reason(&name)?;
+            let metadata = entry.metadata().ok().filter(|metadata| metadata.is_file())?;
+            let created_at = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|modified| modified.as_millis() as i64)
+                .unwrap_or_default();
+            Some(DatabaseBackup {
+                restore_token: restore_token(&name, metadata.len(), created_at),
+                name,
+                reason,
+                created_at,
+                size_bytes: metadata.len(),
+            })
+        })
+        .collect::<Vec<_>>();
+    // The name starts with the time, so it breaks ties of the same second
+    listed.sort_by(|a, b| b.created_at.cmp(&a.created_at).then_with(|| b.name.cmp(&a.name)));
+    Ok(listed)
+}
+
+// The backup `name` in `directory`, when `token` is the one list gave for it
+// and the file passes integrity_check
+pub fn find(directory: &Path, name: &str, token: &str) -> Result<(DatabaseBackup, PathBuf), String> {
+    let backup = list(directory)?
+        .into_iter()
+        .find(|backup| backup.name == name)
+        .ok_or_else(|| format!("There is no backup named {}", name))?;
+    if backup.restore_token != token.trim() {
+        return Err(format!(
+            "The confirmation token does not match backup {}; list the backups again and confirm the restore",
+            name
+        ));
+    }
+    let path = directory.join(BACKUP_DIRECTORY).join(&backup.name);
+    check_file(&path)?;
+    Ok((backup, path))
+}
+
+// Writes the backup at `path` over the database behind `conn`. Other
+// connections to it see the restored contents on their next read.
+pub fn copy_into(path: &Path, conn: &mut Connection) -> Result<(), String> {
+    conn.restore(DatabaseName::Main, path, None::<fn(rusqlite::backup::Progress)>)
+        .map_err(|e| format!("Failed to restore {}: {}", path.display(), e))
+}
+
+// Moves the database file of `directory` that could not be opened, with its
+// WAL, into the backup folder, so a backup can take its place
+pub fn set_aside(directory: &Path, file_name: &str) -> Result<(), String> {
+    let database = directory.join(file_name);
+    if !database.exists() {
+        return Ok(());
+    }
+    let backups = directory.join(BACKUP_DIRECTORY);
+    fs::create_dir_all(&backups)
+        .map_err(|e| format!("Failed to create backup folder {}: {}", backups.display(), e))?;
+    let aside = backups.join(backup_name(current_timestamp_millis(), SnapshotReason::Damaged));
+    fs::rename(&database, &aside)
+        .map_err(|e| format!("Failed to move the damaged database {} aside: {}", database.display(), e))?;
+    for suffix in ["-wal", "-shm"] {
+        let _ = fs::remove_file(directory.join(format!("{}{}", file_name, suffix)));
+    }
+    warn!(moved_to = %aside.display(), "Moved the damaged history database aside");
+    Ok(())
+}
+
+// PRAGMA integrity_check, whose single "ok" row means nothing is wrong
+pub fn integrity_check(conn: &Connection) -> Result<(), String> {
+    let mut statement = conn
+        .prepare("PRAGMA integrity_check")
+        .map_err(|e| format!("Failed to check the history database: {}", e))?;
+    let messages = statement
+        .query_map([], |row| row.get::<_, String>(0))
+        .and_then(|rows| rows.take(MAX_INTEGRITY_MESSAGES).collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("The history database is damaged: {}", e))?;
+    if messages.len() == 1 && messages[0] == "ok" {
+        Ok(())
+    } else {
+        Err(format!("The history database is damaged: {}", messages.join("; ")))
+    }
+}
+
+pub fn note_open_failure(error: &str) {
+    *OPEN_FAILURE.lock().unwrap() = Some(error.to_string());
+}
+
+pub fn open_failure() -> Option<String> {
+    OPEN_FAILURE.lock().unwrap().clone()
+}
+
+// Checks the database once when the GUI starts, off its main thread; a
+// damaged one is reported by onboarding and diagnostics with the newest
+// good backup, instead of failing at some later write
+pub fn check_at_startup(history: History) {
+    std::thread::spawn(move || {
+        let result = history.integrity_check();
+        if let Err(error) = &result {
+            error!("{}", error);
+        }
+        *STARTUP_CHECK.lock().unwrap() = Some(result);
+    });
+}
+
+// What the startup check found, or the check itself when it has not finished
+pub fn startup_state(history: &History) -> Result<(), String> {
+    if let Some(result) = STARTUP_CHECK.lock().unwrap().clone() {
+        return result;
+    }
+    history.integrity_check()
+}
+
+// Cleared once a restore replaced the damaged database
+pub fn clear_startup_state() {
+    *STARTUP_CHECK.lock().unwrap() = Some(Ok(()));
+    *OPEN_FAILURE.lock().unwrap() = None;
+}
+
+// The newest backup of `directory` that passes integrity_check
+pub fn newest_good(directory: &Path) -> Option<DatabaseBackup> {
+    list(directory).ok()?.into_iter().find(|backup| {
+        backup.reason != SnapshotReason::Damaged.as_str()
+            && check_file(&directory.join(BACKUP_DIRECTORY).join(&backup.name)).is_ok()
+    })
+}
+
+fn check_file(path: &Path) -> Result<(), String> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| format!("Failed to open backup {}: {}", path.display(), e))?;
+    integrity_check(&conn).map_err(|error| format!("Backup {} is damaged: {}", path.display(), error))
+}
+
+// history-2024-05-01T203000Z-daily.db
+fn backup_name(millis: i64, reason: SnapshotReason) -> String {
+    format!(
+        "{}{}-{}.{}",
+        BACKUP_PREFIX,
+        timestamps::format_rfc3339(millis).replace(':', ""),
+        reason.as_str(),
+        BACKUP_EXTENSION
+    )
+}
+
+fn parse_reason(name: &str) -> Option<String> {
+    let stem = name
+        .strip_prefix(BACKUP_PREFIX)?
+        .strip_suffix(&format!(".{}", BACKUP_EXTENSION))?;
+    // The time has no dash after its date, e.g. 2024-05-01T203000Z-daily
+    let (_, reason) = stem.split_once("Z-")?;
+    Some(reason.to_string())
+}
+
+fn restore_token(name: &str, size: u64, modified: i64) -> String {
+    let digest = Sha256::digest(format!("{}:{}:{}", name, size, modified).as_bytes());
+    digest[..6].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Keeps the newest KEEP_BACKUPS; a folder that cannot be read is left alone
+fn prune(backups: &Path) {
+    let Some(directory) = backups.parent() else {
+        return;
+    };
+    let Ok(listed) = list(directory) else {
+        return;
+    };
+    for backup in listed.into_iter().skip(KEEP_BACKUPS) {
+        let path = backups.join(&backup.name);
+        match fs::remove_file(&path) {
+            Ok(()) => info!(backup = %path.display(), "Removed old history backup"),
+            Err(error) => warn!("Failed to remove old backup {}: {}", path.display(), error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::{DownloadStatus, NewHistoryEntry, HISTORY_FILE_NAME};
+    use crate::test_support;
+
+    fn entry(job_id: &str) -> NewHistoryEntry<'_> {
+        NewHistoryEntry {
+            job_id,
+            url: "https://example.com/picture.png",
+            file_path: None,
+            status: DownloadStatus::Completed,
+            message: None,
+            source: "test",
+            started_at: 0,
+            finished_at: 0,
+            object_key: None,
+            etag: None,
+            output_path: None,
+            upload: false,
+            page_url: None,
+            page_title: None,
+            media_info: None,
+            transfer: None,
+            session_id: None,
+            supervision: None,
+        }
+    }
+
+    // A backup of the database in `directory` as it is now
+    fn back_up(directory: &Path) -> DatabaseBackup {
+        let conn = Connection::open(directory.join(HISTORY_FILE_NAME)).expect("database");
+        let path = snapshot(&conn, directory, SnapshotReason::Migration).expect("snapshot");
+        let name = path.file_name().expect("name").to_string_lossy().to_string();
+        list(directory).expect("list").into_iter().find(|backup| backup.name == name).expect("listed")
+    }
+
+    #[test]
+    fn a_wrong_or_stale_token_is_refused() {
+        let app_data = test_support::app_data();
+        let history = History::open_in_directory(Ok(app_data.directory.clone()));
+        history.record(&entry("first")).expect("record");
+        let backup = back_up(&app_data.directory);
+
+        let error = history.restore_backup(&app_data.directory, &backup.name, "0123456789ab").expect_err("wrong token");
+        assert!(error.contains("does not match"), "{}", error);
+
+        // Written again since it was listed, so the token names another file
+        let path = app_data.directory.join(BACKUP_DIRECTORY).join(&backup.name);
+        let mut contents = fs::read(&path).expect("backup");
+        contents.extend_from_slice(&[0; 512]);
+        fs::write(&path, contents).expect("backup");
+        let error = history
+            .restore_backup(&app_data.directory, &backup.name, &backup.restore_token)
+            .expect_err("stale token");
+        assert!(error.contains("does not match"), "{}", error);
+    }
+
+    #[test]
+    fn restoring_brings_back_the_entries_of_the_backup() {
+        let app_data = test_support::app_data();
+        let history = History::open_in_directory(Ok(app_data.directory.clone()));
+        let kept = history.record(&entry("kept")).expect("record");
+        let backup = back_up(&app_data.directory);
+        let later = history.record(&entry("later")).expect("record");
+
+        let restored = history
+            .restore_backup(&app_data.directory, &backup.name, &backup.restore_token)
+            .expect("restore");
+        assert_eq!(restored.name, backup.name);
+        assert!(history.get(kept).expect("get").is_some());
+        assert!(history.get(later).expect("get").is_none());
+        // What was there before can be restored in turn
+        let backups = list(&app_data.directory).expect("list");
+        assert!(backups.iter().any(|backup| backup.reason == "before-restore"), "{:?}", backups);
+    }
+
+    #[test]
+    fn a_corrupt_backup_leaves_the_database_alone() {
+        let app_data = test_support::app_data();
+        // Another test may have taken today's snapshot of this same path
+        *LAST_DAILY.lock().unwrap() = None;
+        let history = History::open_in_directory(Ok(app_data.directory.clone()));
+        let kept = history.record(&entry("kept")).expect("record");
+        let backups = app_data.directory.join(BACKUP_DIRECTORY);
+        fs::create_dir_all(&backups).expect("backup folder");
+        let name = backup_name(0, SnapshotReason::Migration);
+        fs::write(backups.join(&name), vec![0x5a; 8192]).expect("corrupt backup");
+        let corrupt = list(&app_data.directory).expect("list").into_iter().find(|backup| backup.name == name).expect("listed");
+
+        history
+            .restore_backup(&app_data.directory, &corrupt.name, &corrupt.restore_token)
+            .expect_err("corrupt backup");
+        assert!(history.get(kept).expect("get").is_some());
+        history.integrity_check().expect("intact");
+        let backups = list(&app_data.directory).expect("list");
+        assert!(!backups.iter().any(|backup| backup.reason == "before-restore"), "{:?}", backups);
+        assert!(newest_good(&app_data.directory).is_some_and(|backup| backup.name != name));
+    }
+}
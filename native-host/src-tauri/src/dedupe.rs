@@ -0,0 +1,344 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+use crate::history::History;
+use crate::long_path;
+use crate::settings::Settings;
+use crate::vault_events::{self, VaultChange};
+use crate::webhooks::hash_file;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DedupeMode {
+    // Identical downloads are kept as separate copies
+    #[default]
+    Off,
+    // A download identical to a file already saved becomes a hardlink to it,
+    // or a reflink where the filesystem has no hardlinks
+    Hardlink,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkKind {
+    Hardlink,
+    Reflink,
+}
+
+impl LinkKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LinkKind::Hardlink => "hardlink",
+            LinkKind::Reflink => "reflink",
+        }
+    }
+}
+
+// Run once a completed download is recorded: with dedupe_mode hardlink the
+// file is hashed, and when history has an identical file at another path the
+// new one is replaced by a link to it. The link is recorded, so deleting
+// either path can warn that the content is shared. Nothing here can fail
+// the download.
+pub fn deduplicate(history: &History, settings: &Settings, entry_id: i64, file_path: &str) {
+    if settings.dedupe_mode == DedupeMode::Off {
+        return;
+    }
+    match link_to_identical(history, entry_id, Path::new(file_path)) {
+        Ok(Some((kind, original))) => {
+            info!(entry_id, original = %original.display(), kind = kind.as_str(), "Linked duplicate download")
+        }
+        Ok(None) => {}
+        Err(error) => warn!(entry_id, "Deduplication failed: {}", error),
+    }
+}
+
+fn link_to_identical(history: &History, entry_id: i64, path: &Path) -> Result<Option<(LinkKind, PathBuf)>, String> {
+    let (size, sha256) = hash_file(&path.display().to_string()).map_err(|e| format!("Failed to hash {}: {}", path.display(), e))?;
+    history.set_sha256(entry_id, &sha256)?;
+    if size == 0 {
+        return Ok(None);
+    }
+
+    // The oldest identical file still on disk; it may have changed since it
+    // was hashed, so its size is compared and it is hashed again
+    let original = history
+        .files_with_sha256(&sha256)?
+        .into_iter()
+        .filter(|file| file.id != entry_id)
+        .find(|file| {
+            let original = Path::new(&file.file_path);
+            fs::metadata(original).is_ok_and(|metadata| metadata.is_file() && metadata.len() == size)
+                && !same_file(original, path)
+                && hash_file(&file.file_path).is_ok_and(|(_, hash)| hash == sha256)
+        });
+    let Some(original) = original else {
+        return Ok(None);
+    };
+    let original_path = PathBuf::from(&original.file_path);
+
+    let kind = match link_in_place(&original_path, path) {
+        Ok(Some(kind)) => kind,
+        Ok(None) => return Ok(None),
+        Err(error) => return Err(format!("Failed to link {} to {}: {}", path.display(), original_path.display(), error)),
+    };
+    history.record_shared_content(entry_id, original.id, kind.as_str(), size)?;
+    vault_events::record(VaultChange::Deduplicated {
+        entry_id,
+        linked_to: original.id,
+        path: path.display().to_string(),
+        link: kind.as_str().to_string(),
+        bytes: size,
+    });
+    Ok(Some((kind, original_path)))
+}
+
+// Replaces `duplicate` with a link to `original`, through a temporary name so
+// the path never goes missing. None when neither link works here, e.g. the
+// two are on different volumes, and the copy stays.
+fn link_in_place(original: &Path, duplicate: &Path) -> io::Result<Option<LinkKind>> {
+    let (original, duplicate) = (&long_path::extended(original), &long_path::extended(duplicate));
+    let file_name = duplicate.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let temporary = duplicate.with_file_name(format!(".{}.imgvault-link", file_name));
+    let _ = fs::remove_file(&temporary);
+
+    let kind = match fs::hard_link(original, &temporary) {
+        Ok(()) => LinkKind::Hardlink,
+        Err(error) if is_cross_volume(&error) => {
+            debug!(duplicate = %duplicate.display(), "Duplicate is on another volume; keeping the copy");
+            return Ok(None);
+        }
+        // Some filesystems, e.g. exFAT, have no hardlinks but may clone
+        Err(error) => match reflink(original, &temporary) {
+            Ok(()) => LinkKind::Reflink,
+            Err(reflink_error) => {
+                let _ = fs::remove_file(&temporary);
+                debug!(
+                    duplicate = %duplicate.display(),
+                    "No hardlink ({}) or reflink ({}); keeping the copy",
+                    error,
+                    reflink_error
+                );
+                return Ok(None);
+            }
+        },
+    };
+    if let Err(error) = fs::rename(&temporary, duplicate) {
+        let _ = fs::remove_file(&temporary);
+        return Err(error);
+    }
+    Ok(Some(kind))
+}
+
+#[cfg(unix)]
+fn is_cross_volume(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(libc::EXDEV)
+}
+
+#[cfg(windows)]
+fn is_cross_volume(error: &io::Error) -> bool {
+    const ERROR_NOT_SAME_DEVICE: i32 = 17;
+    error.raw_os_error() == Some(ERROR_NOT_SAME_DEVICE)
+}
+
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+// Linking a file to itself again is harmless, so paths are enough here
+#[cfg(windows)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+// FICLONE shares the extents on Btrfs and XFS
+#[cfg(target_os = "linux")]
+fn reflink(original: &Path, destination: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let source = fs::File::open(original)?;
+    let target = fs::File::create(destination)?;
+    if unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// clonefile on APFS
+#[cfg(target_os = "macos")]
+fn reflink(original: &Path, destination: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let to_c = |path: &Path| CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e));
+    let (source, target) = (to_c(original)?, to_c(destination)?);
+    if unsafe { libc::clonefile(source.as_ptr(), target.as_ptr(), 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn reflink(_original: &Path, _destination: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "reflinks are not supported here"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::{DownloadStatus, NewHistoryEntry, SavedFile};
+    use crate::test_support;
+    use std::io::{Seek, SeekFrom, Write};
+
+    const PICTURE: &[u8] = b"the same picture, downloaded more than once";
+
+    fn settings() -> Settings {
+        Settings {
+            dedupe_mode: DedupeMode::Hardlink,
+            ..Settings::default()
+        }
+    }
+
+    // Saves `content` at `path` and records it as a completed download
+    fn download(history: &History, path: &Path, content: &[u8]) -> i64 {
+        fs::create_dir_all(path.parent().expect("parent")).expect("vault folder");
+        fs::write(path, content).expect("download");
+        let file_path = path.display().to_string();
+        history
+            .record(&NewHistoryEntry {
+                job_id: &format!("job-{}", file_path),
+                url: "https://example.com/picture.png",
+                file_path: Some(&file_path),
+                status: DownloadStatus::Completed,
+                message: None,
+                source: "test",
+                started_at: 0,
+                finished_at: 0,
+                object_key: None,
+                etag: None,
+                output_path: None,
+                upload: false,
+                page_url: None,
+                page_title: None,
+                media_info: None,
+                transfer: None,
+                session_id: None,
+                supervision: None,
+            })
+            .expect("history entry")
+    }
+
+    fn ids(files: Vec<SavedFile>) -> Vec<i64> {
+        files.into_iter().map(|file| file.id).collect()
+    }
+
+    #[cfg(unix)]
+    fn link_count(path: &Path) -> u64 {
+        use std::os::unix::fs::MetadataExt;
+        fs::metadata(path).expect("metadata").nlink()
+    }
+
+    // What NTFS keeps in the file record; std only has it on nightly
+    #[cfg(windows)]
+    fn link_count(path: &Path) -> u64 {
+        use std::os::windows::io::AsRawHandle;
+        use winapi::um::fileapi::{GetFileInformationByHandle, BY_HANDLE_FILE_INFORMATION};
+        let file = fs::File::open(path).expect("open");
+        let mut information: BY_HANDLE_FILE_INFORMATION = unsafe { std::mem::zeroed() };
+        let ok = unsafe { GetFileInformationByHandle(file.as_raw_handle() as _, &mut information) };
+        assert_ne!(ok, 0, "{}", io::Error::last_os_error());
+        information.nNumberOfLinks as u64
+    }
+
+    #[test]
+    fn identical_downloads_share_one_file_and_count_as_saved() {
+        let app_data = test_support::app_data();
+        let history = History::open_default();
+        let vault = app_data.directory.join("vault");
+        let paths = [vault.join("first.png"), vault.join("again.png"), vault.join("nested").join("third.png")];
+        let other = vault.join("other.png");
+
+        let entries = paths
+            .iter()
+            .map(|path| {
+                let entry_id = download(&history, path, PICTURE);
+                deduplicate(&history, &settings(), entry_id, &path.display().to_string());
+                entry_id
+            })
+            .collect::<Vec<_>>();
+        let other_id = download(&history, &other, b"a different picture");
+        deduplicate(&history, &settings(), other_id, &other.display().to_string());
+
+        for path in &paths {
+            assert_eq!(link_count(path), 3, "{}", path.display());
+            assert_eq!(fs::read(path).expect("linked file"), PICTURE);
+        }
+        assert_eq!(link_count(&other), 1);
+        // No temporary link is left beside a duplicate
+        assert!(fs::read_dir(&vault).expect("vault").all(|entry| !entry
+            .expect("entry")
+            .file_name()
+            .to_string_lossy()
+            .ends_with(".imgvault-link")));
+
+        // One file on disk, so a write through one path shows in the others
+        let mut original = fs::OpenOptions::new().write(true).open(&paths[0]).expect("original");
+        original.seek(SeekFrom::Start(0)).and_then(|_| original.write_all(b"T")).expect("write");
+        drop(original);
+        assert!(fs::read(&paths[2]).expect("third").starts_with(b"T"));
+
+        let stats = history.vault_stats().expect("stats");
+        assert_eq!((stats.files, stats.hashed_files), (4, 4));
+        assert_eq!(stats.shared_files, 2);
+        assert_eq!(stats.bytes_saved, 2 * PICTURE.len() as u64);
+
+        // Every holder of the content is warned about the others
+        assert_eq!(ids(history.shared_with(entries[0]).expect("shared")), [entries[1], entries[2]]);
+        assert_eq!(ids(history.shared_with(entries[2]).expect("shared")), [entries[0], entries[1]]);
+        assert!(history.shared_with(other_id).expect("shared").is_empty());
+    }
+
+    #[test]
+    fn an_original_changed_since_it_was_hashed_keeps_the_copy() {
+        let app_data = test_support::app_data();
+        let history = History::open_default();
+        let first = app_data.directory.join("vault").join("first.png");
+        let again = app_data.directory.join("vault").join("again.png");
+
+        let first_id = download(&history, &first, PICTURE);
+        deduplicate(&history, &settings(), first_id, &first.display().to_string());
+        let mut edited = PICTURE.to_vec();
+        edited[0] = b'T';
+        fs::write(&first, &edited).expect("edit");
+
+        let again_id = download(&history, &again, PICTURE);
+        deduplicate(&history, &settings(), again_id, &again.display().to_string());
+
+        assert_eq!((link_count(&first), link_count(&again)), (1, 1));
+        assert_eq!(fs::read(&again).expect("copy"), PICTURE);
+        let stats = history.vault_stats().expect("stats");
+        assert_eq!((stats.shared_files, stats.bytes_saved), (0, 0));
+    }
+
+    #[test]
+    fn dedupe_off_leaves_duplicates_alone() {
+        let app_data = test_support::app_data();
+        let history = History::open_default();
+        let paths = [app_data.directory.join("first.png"), app_data.directory.join("again.png")];
+        for path in &paths {
+            let entry_id = download(&history, path, PICTURE);
+            deduplicate(&history, &Settings::default(), entry_id, &path.display().to_string());
+        }
+
+        assert_eq!((link_count(&paths[0]), link_count(&paths[1])), (1, 1));
+        let stats = history.vault_stats().expect("stats");
+        assert_eq!((stats.hashed_files, stats.shared_files, stats.bytes_saved), (0, 0, 0));
+    }
+}
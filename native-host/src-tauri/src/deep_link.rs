@@ -0,0 +1,142 @@
+use crate::validate_download_url;
+
+// imgvault://download?url=<percent-encoded URL>&filename=<optional name>
+pub const SCHEME: &str = "imgvault";
+#[cfg(target_os = "windows")]
+pub const SCHEME_REGISTRY_PATH: &str = r"Software\Classes\imgvault";
+const MAX_FILE_NAME_LENGTH: usize = 200;
+
+#[derive(Debug)]
+pub struct DeepLinkDownload {
+    pub url: String,
+    // Base name only; yt-dlp still picks the extension
+    pub file_name: Option<String>,
+}
+
+pub fn is_deep_link(arg: &str) -> bool {
+    arg.get(..SCHEME.len() + 1)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case(&format!("{}:", SCHEME)))
+}
+
+pub fn parse_deep_link(link: &str) -> Result<DeepLinkDownload, String> {
+    if !is_deep_link(link) {
+        return Err(format!("Not an {}:// link", SCHEME));
+    }
+    let rest = link[SCHEME.len() + 1..].trim_start_matches('/');
+    let (action, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+    if !action.trim_end_matches('/').eq_ignore_ascii_case("download") {
+        return Err(format!("Unsupported action \"{}\"; only download links are supported", action));
+    }
+
+    let mut url = None;
+    let mut file_name = None;
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = percent_decode(value).map_err(|e| format!("Invalid {} parameter: {}", key, e))?;
+        match key {
+            "url" => url = Some(value),
+            "filename" if !value.is_empty() => file_name = Some(value),
+            // Unknown parameters are ignored so newer links still work here
+            _ => {}
+        }
+    }
+
+    let url = url.ok_or("Link has no url parameter")?;
+    validate_download_url(&url)?;
+    if let Some(file_name) = &file_name {
+        validate_file_name(file_name)?;
+    }
+
+    Ok(DeepLinkDownload { url, file_name })
+}
+
+// Links come from web pages, so the name must stay inside the vault folder
+fn validate_file_name(file_name: &str) -> Result<(), String> {
+    if file_name.len() > MAX_FILE_NAME_LENGTH {
+        return Err(format!("File name is longer than {} characters", MAX_FILE_NAME_LENGTH));
+    }
+    if file_name == "." || file_name == ".." {
+        return Err("File name is not valid".to_string());
+    }
+    if let Some(invalid) = file_name
+        .chars()
+        .find(|c| c.is_control() || matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' | '%'))
+    {
+        return Err(format!("File name contains an invalid character: {:?}", invalid));
+    }
+    Ok(())
+}
+
+// Query string decoding: %XX escapes and '+' for space; the result must be UTF-8
+pub(crate) fn percent_decode(value: &str) -> Result<String, String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'%' => {
+                let hex = bytes
+                    .get(index + 1..index + 3)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or("malformed percent escape")?;
+                decoded.push(hex);
+                index += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                index += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| "not valid UTF-8".to_string())
+}
+
+// Per-user URL protocol pointing at this executable. The MSI installs the same
+// key from wix/deep-link.wxs and removes it on uninstall.
+#[cfg(target_os = "windows")]
+pub fn register_scheme() -> Result<(), String> {
+    use winreg::enums::HKEY_CURRENT_USER;
+    use winreg::RegKey;
+
+    let exe_path = std::env::current_exe()
+        .map_err(|e| format!("Failed to get executable path: {}", e))?;
+    let exe_path = exe_path.display();
+
+    let (key, _) = RegKey::predef(HKEY_CURRENT_USER)
+        .create_subkey(SCHEME_REGISTRY_PATH)
+        .map_err(|e| format!("Failed to register {}:// links: {}", SCHEME, e))?;
+    key.set_value("", &"URL:ImgVault Protocol")
+        .and_then(|_| key.set_value("URL Protocol", &""))
+        .map_err(|e| format!("Failed to register {}:// links: {}", SCHEME, e))?;
+
+    let (icon, _) = key
+        .create_subkey("DefaultIcon")
+        .map_err(|e| format!("Failed to register {}:// links: {}", SCHEME, e))?;
+    icon.set_value("", &format!("\"{}\",0", exe_path))
+        .map_err(|e| format!("Failed to register {}:// links: {}", SCHEME, e))?;
+
+    let (command, _) = key
+        .create_subkey(r"shell\open\command")
+        .map_err(|e| format!("Failed to register {}:// links: {}", SCHEME, e))?;
+    command
+        .set_value("", &format!("\"{}\" \"%1\"", exe_path))
+        .map_err(|e| format!("Failed to register {}:// links: {}", SCHEME, e))
+}
+
+#[cfg(target_os = "windows")]
+pub fn unregister_scheme() -> Result<(), String> {
+    use winreg::enums::HKEY_CURRENT_USER;
+    use winreg::RegKey;
+
+    match RegKey::predef(HKEY_CURRENT_USER).delete_subkey_all(SCHEME_REGISTRY_PATH) {
+        Ok(()) => Ok(()),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(error) => Err(format!("Failed to remove {}:// link registration: {}", SCHEME, error)),
+    }
+}
@@ -0,0 +1,177 @@
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::diagnostics::available_disk_space;
+use crate::{folder_protection, long_path};
+
+// How often a job waiting for its folder tries the share again; reaching a
+// server that is gone can take Windows several seconds per try
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(15);
+// Starts messages about a share that cannot be reached, so
+// classify_download_error reads them as destination_unavailable
+pub const UNAVAILABLE_PREFIX: &str = "Destination unavailable";
+
+pub enum DestinationError {
+    // The share or network drive cannot be reached right now; worth waiting for
+    Unavailable(String),
+    Failed(String),
+}
+
+// What check_destination reports about a folder
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DestinationStatus {
+    pub path: String,
+    // A \\server\share path or a drive mapped to one
+    pub network: bool,
+    pub reachable: bool,
+    pub writable: bool,
+    // Time to read the folder's metadata, which is one round trip to a share
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub free_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// Whether an error says the share, its server or the network is gone, as
+// opposed to a folder that is missing or not ours to write
+#[cfg(target_os = "windows")]
+pub fn is_unavailable(error: &io::Error) -> bool {
+    const ERROR_REM_NOT_LIST: i32 = 51;
+    const ERROR_BAD_NETPATH: i32 = 53;
+    const ERROR_NETWORK_BUSY: i32 = 54;
+    const ERROR_DEV_NOT_EXIST: i32 = 55;
+    const ERROR_UNEXP_NET_ERR: i32 = 59;
+    const ERROR_NETNAME_DELETED: i32 = 64;
+    const ERROR_BAD_NET_NAME: i32 = 67;
+    const ERROR_NO_NET_OR_BAD_PATH: i32 = 1222;
+    const ERROR_NETWORK_UNREACHABLE: i32 = 1231;
+    const ERROR_HOST_UNREACHABLE: i32 = 1232;
+    const ERROR_NOT_CONNECTED: i32 = 2250;
+    matches!(
+        error.raw_os_error(),
+        Some(
+            ERROR_REM_NOT_LIST
+                | ERROR_BAD_NETPATH
+                | ERROR_NETWORK_BUSY
+                | ERROR_DEV_NOT_EXIST
+                | ERROR_UNEXP_NET_ERR
+                | ERROR_NETNAME_DELETED
+                | ERROR_BAD_NET_NAME
+                | ERROR_NO_NET_OR_BAD_PATH
+                | ERROR_NETWORK_UNREACHABLE
+                | ERROR_HOST_UNREACHABLE
+                | ERROR_NOT_CONNECTED
+        )
+    )
+}
+
+// NFS and SMB mounts report a lost server through these
+#[cfg(unix)]
+pub fn is_unavailable(error: &io::Error) -> bool {
+    matches!(
+        error.raw_os_error(),
+        Some(
+            libc::ENOTCONN
+                | libc::EHOSTDOWN
+                | libc::EHOSTUNREACH
+                | libc::ENETDOWN
+                | libc::ENETUNREACH
+                | libc::ESTALE
+                | libc::ETIMEDOUT
+        )
+    )
+}
+
+// Creates the folder a download writes to, telling a share that is offline
+// apart from every other failure
+pub fn ensure(directory: &Path) -> Result<(), DestinationError> {
+    fs::create_dir_all(long_path::extended(directory)).map_err(|error| {
+        if let Some(message) = folder_protection::access_denied(&error, directory) {
+            return DestinationError::Failed(message);
+        }
+        let message = format!("Failed to create download directory {}: {}", directory.display(), error);
+        if is_unavailable(&error) {
+            DestinationError::Unavailable(format!("{}: {}", UNAVAILABLE_PREFIX, message))
+        } else {
+            DestinationError::Failed(message)
+        }
+    })
+}
+
+// \\server\share paths, and on Windows drive letters mapped to a share
+pub fn is_network(path: &Path) -> bool {
+    let text = long_path::simplified(path).display().to_string();
+    if text.starts_with(r"\\") || text.starts_with("//") {
+        return true;
+    }
+    is_remote_drive(&text)
+}
+
+#[cfg(target_os = "windows")]
+fn is_remote_drive(path: &str) -> bool {
+    use winapi::um::fileapi::GetDriveTypeW;
+    use winapi::um::winbase::DRIVE_REMOTE;
+
+    let Some(drive) = path.get(..2).filter(|drive| drive.ends_with(':')) else {
+        return false;
+    };
+    let root = crate::to_wide_null(&format!(r"{}\", drive));
+    unsafe { GetDriveTypeW(root.as_ptr()) == DRIVE_REMOTE }
+}
+
+// Mounted shares look like any other folder here
+#[cfg(not(target_os = "windows"))]
+fn is_remote_drive(_path: &str) -> bool {
+    false
+}
+
+// Reachability, latency, write access and free space of a destination folder,
+// for check_destination
+pub fn check(path: &Path) -> DestinationStatus {
+    let mut status = DestinationStatus {
+        path: path.display().to_string(),
+        network: is_network(path),
+        reachable: false,
+        writable: false,
+        latency_ms: 0,
+        free_bytes: None,
+        error: None,
+    };
+
+    let started = Instant::now();
+    let metadata = fs::metadata(long_path::extended(path));
+    status.latency_ms = started.elapsed().as_millis() as u64;
+    match metadata {
+        Ok(metadata) if metadata.is_dir() => status.reachable = true,
+        Ok(_) => {
+            status.reachable = true;
+            status.error = Some(format!("{} is not a folder", path.display()));
+            return status;
+        }
+        Err(error) => {
+            status.reachable = !is_unavailable(&error);
+            status.error = Some(if status.reachable {
+                format!("Failed to read {}: {}", path.display(), error)
+            } else {
+                format!("{}: {}", UNAVAILABLE_PREFIX, error)
+            });
+            return status;
+        }
+    }
+
+    let probe = path.join(format!(".imgvault-write-test-{}", std::process::id()));
+    match fs::write(long_path::extended(&probe), b"") {
+        Ok(()) => {
+            status.writable = true;
+            let _ = fs::remove_file(long_path::extended(&probe));
+        }
+        Err(error) => status.error = Some(format!("{} is not writable: {}", path.display(), error)),
+    }
+    status.free_bytes = available_disk_space(path).ok();
+    status
+}
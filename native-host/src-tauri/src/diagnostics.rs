@@ -0,0 +1,774 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use crate::browser_profiles::{self, ProfileRegistration};
+use crate::db_backup;
+use crate::destination;
+use crate::dispatcher::{self, ThrottleDecision};
+use crate::extractors;
+use crate::folder_protection;
+use crate::downloader::find_yt_dlp;
+use crate::history::{DownloadStatus, History, NativeSession};
+use crate::jobs::JobRegistry;
+use crate::long_path;
+use crate::metrics;
+use crate::migration::{self, StepOutcome};
+use crate::power::{self, PreventSleep};
+use crate::profiles::Profile;
+use crate::resource_pressure::{self, Pressure};
+use crate::settings::{check_settings_file, Settings};
+use crate::{bandwidth, current_timestamp_millis, get_vault_directory, redact, EXTENSION_ID, NATIVE_HOST_NAME};
+
+const LOW_DISK_SPACE_BYTES: u64 = 1024 * 1024 * 1024;
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(5);
+const RECENT_ERROR_COUNT: usize = 3;
+const RECENT_SESSION_COUNT: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiagnosticCheck {
+    pub id: String,
+    pub status: CheckStatus,
+    pub message: String,
+    // What the user can do about a warning or failure
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+impl DiagnosticCheck {
+    fn pass(id: &str, message: impl Into<String>) -> Self {
+        DiagnosticCheck {
+            id: id.to_string(),
+            status: CheckStatus::Pass,
+            message: message.into(),
+            hint: None,
+        }
+    }
+
+    fn warn(id: &str, message: impl Into<String>, hint: impl Into<String>) -> Self {
+        DiagnosticCheck {
+            id: id.to_string(),
+            status: CheckStatus::Warn,
+            message: message.into(),
+            hint: Some(hint.into()),
+        }
+    }
+
+    fn fail(id: &str, message: impl Into<String>, hint: impl Into<String>) -> Self {
+        DiagnosticCheck {
+            id: id.to_string(),
+            status: CheckStatus::Fail,
+            message: message.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiagnosticsReport {
+    pub generated_at: i64,
+    pub host_version: String,
+    pub os: String,
+    pub checks: Vec<DiagnosticCheck>,
+    // Host processes the browser spawned lately and how long each lived
+    pub recent_sessions: Vec<NativeSession>,
+    // Counters of the process that made the report; absent from the CLI,
+    // which has nothing to count
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<Value>,
+    pub resource_pressure: ResourcePressureReport,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResourcePressureReport {
+    pub current: Pressure,
+    // Jobs the queue held back for memory or temp space, newest first
+    pub recent_decisions: Vec<ThrottleDecision>,
+}
+
+// Run every check and return the report as JSON with the user's home
+// directory and name redacted, ready to paste into a bug report.
+pub fn run_diagnostics(settings: &Settings, history: &History, jobs: Option<&JobRegistry>) -> Value {
+    let mut checks = Vec::new();
+    checks.extend(check_registration());
+    checks.push(check_manifest());
+    checks.push(check_profiles(settings));
+    checks.push(check_yt_dlp(settings));
+    checks.push(check_extractor_cache());
+    checks.push(check_ffmpeg());
+    checks.push(check_vault(settings));
+    checks.push(check_vault_location(settings));
+    checks.push(check_settings());
+    checks.push(check_migration());
+    checks.push(check_database(history));
+    checks.push(check_recent_errors(history));
+    checks.push(check_bandwidth(settings));
+    checks.push(check_resource_pressure(settings));
+    checks.push(check_power(settings));
+    checks.push(check_protocol());
+
+    let report = DiagnosticsReport {
+        generated_at: current_timestamp_millis(),
+        host_version: env!("CARGO_PKG_VERSION").to_string(),
+        os: format!("{} {}", env::consts::OS, env::consts::ARCH),
+        checks,
+        recent_sessions: list_sessions(RECENT_SESSION_COUNT).unwrap_or_default(),
+        metrics: jobs.map(metrics::snapshot),
+        resource_pressure: ResourcePressureReport {
+            current: resource_pressure::sample(settings),
+            recent_decisions: dispatcher::throttle_decisions().unwrap_or_default(),
+        },
+    };
+
+    let mut value = serde_json::to_value(report).unwrap_or(Value::Null);
+    redact_user(&mut value);
+    value
+}
+
+// Sessions are recorded in the default profile's history whatever profile
+// their messages name, since one host process serves them all
+pub fn list_sessions(limit: usize) -> Result<Vec<NativeSession>, String> {
+    History::open_default().sessions(limit)
+}
+
+#[cfg(target_os = "windows")]
+fn check_registration() -> Vec<DiagnosticCheck> {
+    use winreg::enums::HKEY_CURRENT_USER;
+    use winreg::RegKey;
+
+    let hkcu = RegKey::predef(HKEY_CURRENT_USER);
+    crate::registration::BROWSER_REGISTRY_PATHS
+        .iter()
+        .filter_map(|(browser, parent)| {
+            let id = format!("registration.{}", browser);
+            let registered = hkcu.open_subkey(format!(r"{}\{}", parent, NATIVE_HOST_NAME)).is_ok();
+            match (*browser, registered) {
+                (_, true) => Some(DiagnosticCheck::pass(&id, "Native host is registered")),
+                ("chrome", false) => Some(DiagnosticCheck::fail(
+                    &id,
+                    "Native host is not registered for Chrome",
+                    "Open ImgVault Native Host and click Register",
+                )),
+                // Only Chrome is registered automatically; other browsers are optional
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "windows"))]
+fn check_registration() -> Vec<DiagnosticCheck> {
+    vec![DiagnosticCheck::warn(
+        "registration.chrome",
+        "Automatic registration is only supported on Windows",
+        format!("Install the {} native messaging manifest for your browser manually", NATIVE_HOST_NAME),
+    )]
+}
+
+// Browsers the native host is registered for
+#[cfg(target_os = "windows")]
+pub(crate) fn registered_browsers() -> Vec<&'static str> {
+    use winreg::enums::HKEY_CURRENT_USER;
+    use winreg::RegKey;
+
+    let hkcu = RegKey::predef(HKEY_CURRENT_USER);
+    crate::registration::BROWSER_REGISTRY_PATHS
+        .iter()
+        .filter(|(_, parent)| hkcu.open_subkey(format!(r"{}\{}", parent, NATIVE_HOST_NAME)).is_ok())
+        .map(|(browser, _)| *browser)
+        .collect()
+}
+
+#[cfg(not(target_os = "windows"))]
+pub(crate) fn registered_browsers() -> Vec<&'static str> {
+    Vec::new()
+}
+
+#[cfg(target_os = "windows")]
+fn registered_manifest_path() -> Option<String> {
+    use winreg::enums::HKEY_CURRENT_USER;
+    use winreg::RegKey;
+
+    RegKey::predef(HKEY_CURRENT_USER)
+        .open_subkey(format!(r"Software\Google\Chrome\NativeMessagingHosts\{}", NATIVE_HOST_NAME))
+        .ok()?
+        .get_value::<String, _>("")
+        .ok()
+}
+
+#[cfg(not(target_os = "windows"))]
+fn registered_manifest_path() -> Option<String> {
+    None
+}
+
+fn check_manifest() -> DiagnosticCheck {
+    const ID: &str = "manifest";
+    let Some(manifest_path) = registered_manifest_path() else {
+        return DiagnosticCheck::warn(ID, "No registered manifest found", "Register the native host first");
+    };
+
+    let manifest = match fs::read_to_string(&manifest_path) {
+        Ok(contents) => contents,
+        Err(error) => {
+            return DiagnosticCheck::fail(
+                ID,
+                format!("Registered manifest {} cannot be read: {}", manifest_path, error),
+                "Register the native host again",
+            )
+        }
+    };
+    let manifest = match serde_json::from_str::<Value>(&manifest) {
+        Ok(manifest) => manifest,
+        Err(error) => {
+            return DiagnosticCheck::fail(
+                ID,
+                format!("Manifest {} is not valid JSON: {}", manifest_path, error),
+                "Register the native host again",
+            )
+        }
+    };
+
+    let host_path = manifest["path"].as_str().unwrap_or_default();
+    if !Path::new(host_path).is_file() {
+        return DiagnosticCheck::fail(
+            ID,
+            format!("Manifest points to a missing executable: {}", host_path),
+            "The host was moved after registering; register it again from its new location",
+        );
+    }
+
+    let current_exe = env::current_exe().ok();
+    if current_exe.as_deref() != Some(Path::new(host_path)) {
+        return DiagnosticCheck::warn(
+            ID,
+            format!("Manifest points to a different copy of the host: {}", host_path),
+            "Register again from the copy you want the browser to use",
+        );
+    }
+
+    let origin = format!("chrome-extension://{}/", EXTENSION_ID);
+    let allowed = manifest["allowed_origins"]
+        .as_array()
+        .is_some_and(|origins| origins.iter().any(|value| value.as_str() == Some(origin.as_str())));
+    if !allowed {
+        return DiagnosticCheck::fail(
+            ID,
+            "Manifest does not allow the ImgVault extension",
+            "Register the native host again",
+        );
+    }
+
+    DiagnosticCheck::pass(ID, format!("Manifest {} is valid", manifest_path))
+}
+
+// Finds the extension in each browser profile and whether the manifest that
+// browser reads lets it connect, which explains "registered but nothing happens"
+fn check_profiles(settings: &Settings) -> DiagnosticCheck {
+    const ID: &str = "registration.profiles";
+    let found = browser_profiles::scan(settings);
+    if found.is_empty() {
+        return DiagnosticCheck::warn(
+            ID,
+            "The ImgVault extension was not found in any browser profile",
+            "Install the extension, or add the user data folder of a portable browser in settings",
+        );
+    }
+
+    let mismatches = found
+        .iter()
+        .filter(|profile| profile.allowed == Some(false))
+        .map(|profile| {
+            format!(
+                "extension installed in {} with ID {} not in allowed_origins",
+                profile.describe(),
+                profile.extension_id
+            )
+        })
+        .collect::<Vec<_>>();
+    if !mismatches.is_empty() {
+        return DiagnosticCheck::fail(
+            ID,
+            mismatches.join("; "),
+            "Register the native host again with the extension ID shown for that profile",
+        );
+    }
+
+    let unregistered = found
+        .iter()
+        .filter(|profile| profile.allowed.is_none())
+        .map(ProfileRegistration::describe)
+        .collect::<Vec<_>>();
+    if !unregistered.is_empty() {
+        return DiagnosticCheck::warn(
+            ID,
+            format!("No native host manifest is registered for {}", unregistered.join(", ")),
+            "Register the native host for that browser",
+        );
+    }
+
+    DiagnosticCheck::pass(
+        ID,
+        format!(
+            "Extension found in {}, allowed by the registered manifest",
+            found.iter().map(ProfileRegistration::describe).collect::<Vec<_>>().join(", ")
+        ),
+    )
+}
+
+fn check_yt_dlp(settings: &Settings) -> DiagnosticCheck {
+    match find_yt_dlp(settings) {
+        Ok(version) => DiagnosticCheck::pass("yt_dlp", version),
+        Err(error) => DiagnosticCheck::fail(
+            "yt_dlp",
+            error,
+            "Install yt-dlp and add it to PATH, or set its location in settings",
+        ),
+    }
+}
+
+// Reads the cache file only; routing refreshes it when yt-dlp changes
+fn check_extractor_cache() -> DiagnosticCheck {
+    const ID: &str = "extractor_cache";
+    match extractors::read_cache() {
+        Ok(Some(cache)) => DiagnosticCheck::pass(
+            ID,
+            format!(
+                "{} extractors for yt-dlp {}, refreshed {} minutes ago",
+                cache.extractors.len(),
+                cache.yt_dlp_version,
+                (current_timestamp_millis() - cache.refreshed_at).max(0) / 60_000
+            ),
+        ),
+        Ok(None) => DiagnosticCheck::warn(
+            ID,
+            "No yt-dlp extractor cache yet",
+            "It is built the first time a link needs it, or with Refresh extractor cache",
+        ),
+        Err(error) => DiagnosticCheck::warn(ID, error, "It is rebuilt the next time a link needs it"),
+    }
+}
+
+pub(crate) fn check_ffmpeg() -> DiagnosticCheck {
+    let mut command = Command::new("ffmpeg");
+    command.arg("-version");
+    hide_console_window(&mut command);
+
+    match command.output() {
+        Ok(output) if output.status.success() => {
+            let version = String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .next()
+                .unwrap_or("ffmpeg is available")
+                .to_string();
+            DiagnosticCheck::pass("ffmpeg", version)
+        }
+        Ok(output) => DiagnosticCheck::warn(
+            "ffmpeg",
+            format!("ffmpeg returned exit code {:?}", output.status.code()),
+            "Reinstall ffmpeg; without it yt-dlp cannot merge video and audio",
+        ),
+        Err(error) => DiagnosticCheck::warn(
+            "ffmpeg",
+            format!("ffmpeg not found: {}", error),
+            "Install ffmpeg and add it to PATH; without it yt-dlp cannot merge video and audio",
+        ),
+    }
+}
+
+fn check_vault(settings: &Settings) -> DiagnosticCheck {
+    const ID: &str = "vault";
+    let directory = match get_vault_directory(settings) {
+        Ok(directory) => directory,
+        Err(error) => return DiagnosticCheck::fail(ID, error, "Set a vault folder in settings"),
+    };
+
+    match fs::metadata(long_path::extended(&directory)) {
+        Err(error) if destination::is_unavailable(&error) => {
+            return DiagnosticCheck::warn(
+                ID,
+                format!("Vault folder {} cannot be reached: {}", directory.display(), error),
+                "Check that the share or network drive is online; downloads wait until it is",
+            )
+        }
+        _ => {}
+    }
+    if !directory.is_dir() {
+        return DiagnosticCheck::fail(
+            ID,
+            format!("Vault folder {} does not exist", directory.display()),
+            "Create the folder or choose another vault folder in settings",
+        );
+    }
+
+    if let Err(error) = check_writable(&directory) {
+        return DiagnosticCheck::fail(ID, error, "Check the folder permissions or choose another vault folder");
+    }
+
+    match available_disk_space(&directory) {
+        Ok(free) if free < LOW_DISK_SPACE_BYTES => DiagnosticCheck::warn(
+            ID,
+            format!("Vault folder {} has only {} MiB free", directory.display(), free / (1024 * 1024)),
+            "Free up disk space or move the vault to another drive",
+        ),
+        Ok(free) => DiagnosticCheck::pass(
+            ID,
+            format!("Vault folder {} is writable, {} MiB free", directory.display(), free / (1024 * 1024)),
+        ),
+        Err(error) => DiagnosticCheck::warn(
+            ID,
+            format!("Vault folder {} is writable; free space unknown: {}", directory.display(), error),
+            "Make sure the drive has enough free space",
+        ),
+    }
+}
+
+// Writes and removes an empty file, which is the only sure test on shares
+// Controlled folder access refuses writes from apps it does not know, and
+// OneDrive swaps files it synced for placeholders that verify cannot read
+fn check_vault_location(settings: &Settings) -> DiagnosticCheck {
+    const ID: &str = "vault_location";
+    let directory = match get_vault_directory(settings) {
+        Ok(directory) => directory,
+        Err(error) => return DiagnosticCheck::warn(ID, error, "Set a vault folder in settings"),
+    };
+    let mut problems = Vec::new();
+    let mut hints = Vec::new();
+    if let Some(folder) = folder_protection::protected_folder(&directory) {
+        let certainty = match folder_protection::controlled_folder_access_enabled() {
+            Some(true) => "is",
+            _ => "may be",
+        };
+        problems.push(format!(
+            "Vault folder {} {} protected by Controlled folder access ({})",
+            directory.display(),
+            certainty,
+            folder.display()
+        ));
+        hints.push("Allow ImgVault in Windows Security > Ransomware protection > Allow an app through Controlled folder access");
+    }
+    if let Some(root) = folder_protection::cloud_root(&directory) {
+        problems.push(format!("Vault folder {} is synced by OneDrive ({})", directory.display(), root.display()));
+        hints.push("Choose \"Always keep on this device\" for the vault folder so its files are not replaced by placeholders");
+    }
+    if problems.is_empty() {
+        DiagnosticCheck::pass(ID, format!("Vault folder {} is not in a protected or synced folder", directory.display()))
+    } else {
+        DiagnosticCheck::warn(ID, problems.join("; "), hints.join("; "))
+    }
+}
+
+pub(crate) fn check_writable(directory: &Path) -> Result<(), String> {
+    let probe = directory.join(format!(".imgvault-write-test-{}", std::process::id()));
+    fs::write(long_path::extended(&probe), b"")
+        .map_err(|e| format!("Vault folder {} is not writable: {}", directory.display(), e))?;
+    let _ = fs::remove_file(long_path::extended(&probe));
+    Ok(())
+}
+
+fn check_migration() -> DiagnosticCheck {
+    const ID: &str = "migration";
+    let state = match migration::read_state() {
+        Ok(Some(state)) => state,
+        Ok(None) => return DiagnosticCheck::warn(ID, "The upgrade migration has not run", "Restart ImgVault"),
+        Err(error) => return DiagnosticCheck::warn(ID, error, "Delete migration.json so the migration runs again"),
+    };
+    let ran_at = state.ran_at.as_deref().unwrap_or("an unknown time");
+    let failed = state
+        .steps
+        .iter()
+        .filter(|step| step.outcome == StepOutcome::Failed)
+        .map(|step| format!("{}: {}", step.name, step.detail))
+        .collect::<Vec<_>>();
+    if !failed.is_empty() {
+        return DiagnosticCheck::warn(
+            ID,
+            format!("Upgrade migration at {} failed: {}", ran_at, failed.join("; ")),
+            "It runs again on the next start; earlier files are kept in migration-backup",
+        );
+    }
+    let migrated = state
+        .steps
+        .iter()
+        .filter(|step| step.outcome == StepOutcome::Migrated)
+        .map(|step| step.detail.as_str())
+        .collect::<Vec<_>>();
+    if migrated.is_empty() {
+        DiagnosticCheck::pass(ID, format!("Nothing from older versions to migrate (checked {})", ran_at))
+    } else {
+        DiagnosticCheck::pass(ID, format!("Migrated at {}: {}", ran_at, migrated.join("; ")))
+    }
+}
+
+fn check_settings() -> DiagnosticCheck {
+    match check_settings_file() {
+        Ok(None) => DiagnosticCheck::pass("settings", "No settings saved yet, using defaults"),
+        Ok(Some(settings)) => match settings.validate() {
+            Ok(()) => DiagnosticCheck::pass("settings", "Settings file is valid"),
+            Err(errors) => DiagnosticCheck::warn(
+                "settings",
+                errors
+                    .iter()
+                    .map(|error| format!("{}: {}", error.field, error.message))
+                    .collect::<Vec<_>>()
+                    .join("; "),
+                "Fix these settings in the settings window",
+            ),
+        },
+        Err(error) => DiagnosticCheck::fail(
+            "settings",
+            error,
+            "Settings are being ignored; save them again from the settings window",
+        ),
+    }
+}
+
+// What the GUI's startup check found, or a check now in other processes
+fn check_database(history: &History) -> DiagnosticCheck {
+    const ID: &str = "database";
+    let damage = match db_backup::startup_state(history) {
+        Ok(()) => return DiagnosticCheck::pass(ID, "History database is intact"),
+        Err(damage) => damage,
+    };
+    let newest = Profile::active()
+        .directory()
+        .ok()
+        .and_then(|directory| db_backup::newest_good(&directory));
+    match newest {
+        Some(backup) => DiagnosticCheck::fail(
+            ID,
+            damage,
+            format!("Restore backup {} from the backups list", backup.name),
+        ),
+        None => DiagnosticCheck::fail(ID, damage, "There is no intact backup to restore"),
+    }
+}
+
+fn check_recent_errors(history: &History) -> DiagnosticCheck {
+    match history.recent_with_status(DownloadStatus::Failed, RECENT_ERROR_COUNT) {
+        Ok(failures) if failures.is_empty() => DiagnosticCheck::pass("recent_errors", "No failed downloads"),
+        Ok(failures) => DiagnosticCheck::warn(
+            "recent_errors",
+            failures
+                .iter()
+                .map(|entry| {
+                    format!(
+                        "{}: {}",
+                        crate::logging::loggable_url(&entry.url),
+                        entry.message.as_deref().unwrap_or("no message")
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+            "Recent downloads failed; the messages above usually name the cause",
+        ),
+        Err(error) => DiagnosticCheck::warn("recent_errors", error, "History could not be read"),
+    }
+}
+
+// Informational: explains slow downloads during a limited window
+fn check_bandwidth(settings: &Settings) -> DiagnosticCheck {
+    let status = bandwidth::status(settings);
+    let limit = match status.limit_bytes_per_second {
+        Some(limit) => format!("New downloads limited to {} bytes/s", limit),
+        None => "New downloads run at full speed".to_string(),
+    };
+    let message = match (&status.next_change_at, status.next_limit_bytes_per_second) {
+        (Some(at), Some(next)) => format!("{}; {} bytes/s from {}", limit, next, at),
+        (Some(at), None) => format!("{}; full speed from {}", limit, at),
+        (None, _) => limit,
+    };
+    DiagnosticCheck::pass("bandwidth", message)
+}
+
+// Informational too: the queue slows down rather than failing
+fn check_resource_pressure(settings: &Settings) -> DiagnosticCheck {
+    let pressure = resource_pressure::sample(settings);
+    if pressure.is_under() {
+        DiagnosticCheck::warn(
+            "resource_pressure",
+            pressure.describe(),
+            "Free memory or temp space, or lower min_free_memory_mb and min_free_temp_mb",
+        )
+    } else {
+        DiagnosticCheck::pass("resource_pressure", pressure.describe())
+    }
+}
+
+// Power requests belong to a process, so this is about the one making the report
+fn check_power(settings: &Settings) -> DiagnosticCheck {
+    const ID: &str = "power";
+    match (settings.prevent_sleep, power::is_held()) {
+        (_, true) => DiagnosticCheck::pass(ID, "Holding off system sleep for the running downloads"),
+        (PreventSleep::WhileDownloading, false) => {
+            DiagnosticCheck::pass(ID, "No power request held; one is taken while downloads run")
+        }
+        (PreventSleep::Never, false) => DiagnosticCheck::warn(
+            ID,
+            "Downloads do not keep the machine awake",
+            "Set prevent_sleep to while_downloading if downloads break off when the machine sleeps",
+        ),
+    }
+}
+
+// Launch a second copy of the host in native mode and ping it over the real
+// length-prefixed protocol
+fn check_protocol() -> DiagnosticCheck {
+    match protocol_self_test() {
+        Ok(()) => DiagnosticCheck::pass("protocol", "Native messaging self-test succeeded"),
+        Err(error) => DiagnosticCheck::fail(
+            "protocol",
+            error,
+            "Something is writing to stdout or the host crashes on startup; attach the log to a bug report",
+        ),
+    }
+}
+
+pub(crate) fn protocol_self_test() -> Result<(), String> {
+    let exe = env::current_exe().map_err(|e| format!("Failed to get executable path: {}", e))?;
+    let mut command = Command::new(exe);
+    command
+        .arg("--native")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+    hide_console_window(&mut command);
+
+    let mut child = command
+        .spawn()
+        .map_err(|e| format!("Failed to start the host in native mode: {}", e))?;
+    let mut stdin = child.stdin.take().ok_or("Failed to open host stdin")?;
+    let mut stdout = child.stdout.take().ok_or("Failed to open host stdout")?;
+
+    let request = serde_json::json!({ "action": "ping", "request_id": "diagnostics-self-test" }).to_string();
+    let written = stdin
+        .write_all(&(request.len() as u32).to_ne_bytes())
+        .and_then(|_| stdin.write_all(request.as_bytes()))
+        .and_then(|_| stdin.flush());
+    if let Err(error) = written {
+        let _ = child.kill();
+        return Err(format!("Failed to send ping: {}", error));
+    }
+
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut length = [0u8; 4];
+        let result = stdout.read_exact(&mut length).and_then(|_| {
+            let mut body = vec![0u8; u32::from_ne_bytes(length) as usize];
+            stdout.read_exact(&mut body).map(|_| body)
+        });
+        let _ = tx.send(result);
+    });
+
+    let reply = rx.recv_timeout(SELF_TEST_TIMEOUT);
+    // Closing stdin ends the host's message loop
+    drop(stdin);
+    let _ = child.kill();
+    let _ = child.wait();
+
+    let body = match reply {
+        Ok(Ok(body)) => body,
+        Ok(Err(error)) => return Err(format!("Failed to read ping response: {}", error)),
+        Err(_) => return Err("Host did not answer the ping within 5 seconds".to_string()),
+    };
+    let response = serde_json::from_slice::<Value>(&body)
+        .map_err(|e| format!("Ping response is not valid JSON: {}", e))?;
+
+    if response["success"].as_bool() == Some(true) && response["requestId"] == "diagnostics-self-test" {
+        Ok(())
+    } else {
+        Err(format!("Unexpected ping response: {}", response))
+    }
+}
+
+pub(crate) fn hide_console_window(command: &mut Command) {
+    #[cfg(target_os = "windows")]
+    {
+        use std::os::windows::process::CommandExt;
+        const CREATE_NO_WINDOW: u32 = 0x08000000;
+        command.creation_flags(CREATE_NO_WINDOW);
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    let _ = command;
+}
+
+#[cfg(target_os = "windows")]
+pub fn available_disk_space(path: &Path) -> Result<u64, String> {
+    use winapi::shared::ntdef::ULARGE_INTEGER;
+    use winapi::um::fileapi::GetDiskFreeSpaceExW;
+
+    // A UNC root only works with its trailing backslash, so every folder gets one
+    let mut folder = crate::long_path::simplified(path).display().to_string();
+    if !folder.ends_with('\\') {
+        folder.push('\\');
+    }
+    let wide = crate::to_wide_null(&folder);
+    unsafe {
+        let mut available: ULARGE_INTEGER = std::mem::zeroed();
+        if GetDiskFreeSpaceExW(wide.as_ptr(), &mut available, std::ptr::null_mut(), std::ptr::null_mut()) == 0 {
+            return Err(format!("GetDiskFreeSpaceExW failed: {}", std::io::Error::last_os_error()));
+        }
+        Ok(*available.QuadPart())
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn available_disk_space(path: &Path) -> Result<u64, String> {
+    let output = Command::new("df")
+        .arg("-Pk")
+        .arg(path)
+        .output()
+        .map_err(|e| format!("Failed to execute df: {}", e))?;
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .nth(1)
+        .and_then(|line| line.split_whitespace().nth(3))
+        .and_then(|available| available.parse::<u64>().ok())
+        .map(|kib| kib * 1024)
+        .ok_or_else(|| "Unexpected df output".to_string())
+}
+
+// Replace secret values, the home directory and the user name in every string
+// so reports can be shared publicly
+pub(crate) fn redact_user(value: &mut Value) {
+    let home = env::var(if cfg!(target_os = "windows") { "USERPROFILE" } else { "HOME" }).ok();
+    let user = env::var(if cfg!(target_os = "windows") { "USERNAME" } else { "USER" }).ok();
+    redact_strings(value, home.as_deref(), user.as_deref());
+}
+
+fn redact_strings(value: &mut Value, home: Option<&str>, user: Option<&str>) {
+    match value {
+        Value::String(text) => {
+            if let Cow::Owned(scrubbed) = redact::redact(text) {
+                *text = scrubbed;
+            }
+            if let Some(home) = home.filter(|home| !home.is_empty()) {
+                *text = text.replace(home, "~");
+            }
+            if let Some(user) = user.filter(|user| user.len() > 2) {
+                *text = text.replace(user, "<user>");
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| redact_strings(item, home, user)),
+        Value::Object(map) => map.values_mut().for_each(|item| redact_strings(item, home, user)),
+        _ => {}
+    }
+}
@@ -0,0 +1,710 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+use crate::connectivity;
+use crate::destination::{self, DestinationError};
+use crate::downloader::DownloadContext;
+use crate::file_lock::{self, FileLock};
+use crate::jobs::{self, JobRegistry};
+use crate::resource_pressure::{self, Pressure};
+use crate::settings::Settings;
+use crate::site_login::host_in_domain;
+use crate::{current_timestamp_millis, get_app_data_directory, url_host};
+
+// Running and waiting jobs of every host process, so the limits hold across
+// the GUI and each native messaging port
+const STATE_FILE_NAME: &str = "download-slots.json";
+const LOCK_FILE_NAME: &str = "download-slots.lock";
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// Checking whether other host processes are still alive spawns a process on
+// Windows, so a waiting job only does it this often
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+pub const DEFAULT_DOMAIN_MAX_CONCURRENT: u32 = 2;
+pub const MAX_DOMAIN_MIN_DELAY_MS: u64 = 60_000;
+pub const MAX_DOMAIN_JITTER_MS: u64 = 60_000;
+// Throttling decisions kept for diagnostics
+const KEPT_THROTTLE_DECISIONS: usize = 10;
+
+// Overrides the per-domain defaults for a site and its subdomains
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DomainLimit {
+    pub domain: String,
+    pub max_concurrent: u32,
+    // Between one job's last request and the next job's first, and between
+    // yt-dlp's own requests
+    #[serde(alias = "delay_ms")]
+    pub min_delay_ms: u64,
+    // Up to this much is added to each delay at random, so requests to the
+    // site do not come at a fixed beat
+    pub jitter_ms: u64,
+}
+
+impl Default for DomainLimit {
+    fn default() -> Self {
+        DomainLimit {
+            domain: String::new(),
+            max_concurrent: DEFAULT_DOMAIN_MAX_CONCURRENT,
+            min_delay_ms: 0,
+            jitter_ms: 0,
+        }
+    }
+}
+
+// Waiting jobs start highest priority first, oldest first within a priority
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WaitReason {
+    GlobalLimit,
+    DomainLimit,
+    DomainDelay,
+    // The download folder is on a share that cannot be reached
+    Destination,
+    // The network is down; see connectivity
+    Offline,
+    // Memory or temp space is low, so one download runs at a time
+    LowResources,
+    // A merge-heavy job held back while memory or temp space is low
+    DeferredMerge,
+}
+
+impl WaitReason {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            WaitReason::GlobalLimit => "Waiting for a free download slot",
+            WaitReason::DomainLimit => "Waiting for other downloads from this site to finish",
+            WaitReason::DomainDelay => "Waiting between requests to this site",
+            WaitReason::Destination => "Waiting for the download folder's share to come back",
+            WaitReason::Offline => "Waiting for the network connection to come back",
+            WaitReason::LowResources => "Waiting while memory or temp space is low; one download runs at a time",
+            WaitReason::DeferredMerge => "Waiting for memory or temp space before a download that needs merging",
+        }
+    }
+
+    fn is_throttling(&self) -> bool {
+        matches!(self, WaitReason::LowResources | WaitReason::DeferredMerge)
+    }
+}
+
+// A job held back for memory or temp space, with what was measured
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThrottleDecision {
+    pub at: i64,
+    pub job_id: String,
+    pub reason: WaitReason,
+    pub pressure: Pressure,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SlotEntry {
+    pub job_id: String,
+    pub domain: String,
+    pub priority: Priority,
+    // The domain's limits when the job was queued, so every host process
+    // judges other processes' jobs the same way
+    pub max_concurrent: u32,
+    pub min_delay_ms: u64,
+    #[serde(default)]
+    pub jitter_ms: u64,
+    // Host process that owns the job; entries of processes that died are dropped
+    pub host_pid: u32,
+    pub running: bool,
+    pub waiting_reason: Option<WaitReason>,
+    pub since: i64,
+    // Held back first while memory or temp space is low
+    #[serde(default)]
+    pub merge_heavy: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DispatchState {
+    // Waiting jobs are kept in dispatch order; running ones stay where they were
+    slots: Vec<SlotEntry>,
+    // When the next job may start per domain: its delay, with jitter, after
+    // the last job started or finished. Kept in the file, so a restarted host
+    // waits it out too.
+    #[serde(default)]
+    next_allowed: HashMap<String, i64>,
+    // The latest first, from every host process
+    #[serde(default)]
+    throttle_decisions: VecDeque<ThrottleDecision>,
+}
+
+pub enum Admission {
+    Granted(DispatchSlot),
+    // The queue was paused while the job waited
+    Paused,
+    // The host began shutting down while the job waited
+    Interrupted,
+    // cancel_job removed the waiting job
+    Cancelled,
+}
+
+// A running job's share of the global and per-domain limits, freed when dropped
+pub struct DispatchSlot {
+    job_id: String,
+}
+
+impl Drop for DispatchSlot {
+    fn drop(&mut self) {
+        // A job's last request is at its end, so the delay starts over there
+        let released = update_state(|state| {
+            if let Some(position) = state.slots.iter().position(|slot| slot.job_id == self.job_id) {
+                let slot = state.slots.remove(position);
+                record_request(state, &slot, current_timestamp_millis());
+            }
+            Ok(())
+        });
+        if let Err(error) = released {
+            warn!(job_id = %self.job_id, "Failed to release download slot: {}", error);
+        }
+    }
+}
+
+struct Limits {
+    domain: String,
+    max_concurrent: u32,
+    min_delay_ms: u64,
+    jitter_ms: u64,
+}
+
+pub fn validate(limit: &DomainLimit, max_concurrent_downloads: u32) -> Result<(), String> {
+    if limit.domain.trim().is_empty() || limit.domain.contains(['/', ':', ' ']) {
+        return Err("Domain must be a host name such as imgur.com".to_string());
+    }
+    if !(1..=max_concurrent_downloads).contains(&limit.max_concurrent) {
+        return Err(format!("Concurrent downloads must be between 1 and {}", max_concurrent_downloads));
+    }
+    if limit.min_delay_ms > MAX_DOMAIN_MIN_DELAY_MS {
+        return Err(format!("Delay must be at most {} ms", MAX_DOMAIN_MIN_DELAY_MS));
+    }
+    if limit.jitter_ms > MAX_DOMAIN_JITTER_MS {
+        return Err(format!("Jitter must be at most {} ms", MAX_DOMAIN_JITTER_MS));
+    }
+    Ok(())
+}
+
+// Blocks until the job may start under the global and per-domain limits.
+// Jobs ahead in the queue that are only held back by their own domain do not
+// hold up the rest. `on_wait` hears each new reason for waiting. With a
+// `destination`, the folder is created first, and while its share cannot be
+// reached the job waits for it instead of failing. While memory or temp space
+// is low one job runs at a time, and merge-heavy jobs wait for the others.
+pub fn acquire(
+    jobs: &JobRegistry,
+    settings: &Settings,
+    context: &DownloadContext,
+    destination: Option<&Path>,
+    merge_heavy: bool,
+    on_wait: &mut dyn FnMut(WaitReason),
+) -> Result<Admission, String> {
+    let DownloadContext { job_id, url, priority, .. } = *context;
+    let limits = limits_for(settings, url);
+    let own_pid = std::process::id();
+    update_state(|state| {
+        state.slots.retain(|slot| slot.job_id != job_id);
+        let position = insert_position(&state.slots, priority);
+        state.slots.insert(position, SlotEntry {
+            job_id: job_id.to_string(),
+            domain: limits.domain.clone(),
+            priority,
+            max_concurrent: limits.max_concurrent,
+            min_delay_ms: limits.min_delay_ms,
+            jitter_ms: limits.jitter_ms,
+            host_pid: own_pid,
+            running: false,
+            waiting_reason: None,
+            since: current_timestamp_millis(),
+            merge_heavy,
+        });
+        Ok(())
+    })?;
+
+    let mut last_reason = None;
+    let mut last_prune: Option<Instant> = None;
+    // Tried before the limits, so a job whose share is offline takes no slot
+    let mut pending_destination = destination;
+    let mut last_destination_try: Option<Instant> = None;
+    loop {
+        if jobs.is_shutting_down() {
+            withdraw(job_id)?;
+            return Ok(Admission::Interrupted);
+        }
+        if jobs.is_paused() {
+            withdraw(job_id)?;
+            return Ok(Admission::Paused);
+        }
+
+        if connectivity::is_offline() {
+            if last_reason != Some(WaitReason::Offline) {
+                on_wait(WaitReason::Offline);
+                last_reason = Some(WaitReason::Offline);
+            }
+            if !mark_waiting(job_id, WaitReason::Offline)? {
+                return Ok(Admission::Cancelled);
+            }
+            std::thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+
+        if let Some(directory) = pending_destination {
+            if last_destination_try.is_none_or(|at| at.elapsed() >= destination::RETRY_INTERVAL) {
+                last_destination_try = Some(Instant::now());
+                match destination::ensure(directory) {
+                    Ok(()) => pending_destination = None,
+                    Err(DestinationError::Unavailable(error)) => {
+                        if last_reason != Some(WaitReason::Destination) {
+                            warn!(job_id, "{}; waiting for it", error);
+                            on_wait(WaitReason::Destination);
+                            last_reason = Some(WaitReason::Destination);
+                        }
+                    }
+                    Err(DestinationError::Failed(error)) => {
+                        withdraw(job_id)?;
+                        return Err(error);
+                    }
+                }
+            }
+            if pending_destination.is_some() {
+                // cancel_job withdraws the entry while the job waits here too
+                if !mark_waiting(job_id, WaitReason::Destination)? {
+                    return Ok(Admission::Cancelled);
+                }
+                std::thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+        }
+
+        let may_prune = last_prune.is_none_or(|at| at.elapsed() >= PRUNE_INTERVAL);
+        let pressure = resource_pressure::sample(settings);
+        resource_pressure::announce(jobs, &pressure);
+        // The outer None means the entry is gone: cancel_job withdrew it
+        let decision = update_state(|state| {
+            let now = current_timestamp_millis();
+            let Some(mut reason) = blocking_reason(state, settings, &pressure, job_id, now) else {
+                return Ok(None);
+            };
+            // Jobs of host processes that died never release their slots
+            if reason.is_some() && may_prune && prune_dead_hosts(state, own_pid) {
+                reason = blocking_reason(state, settings, &pressure, job_id, now).flatten();
+            }
+
+            let mut throttled = None;
+            if let Some(slot) = state.slots.iter_mut().find(|slot| slot.job_id == job_id) {
+                if reason.is_some_and(|reason| reason.is_throttling()) && slot.waiting_reason != reason {
+                    throttled = reason;
+                }
+                slot.waiting_reason = reason;
+                slot.running = reason.is_none();
+            }
+            if let Some(reason) = throttled {
+                record_throttle(state, job_id, reason, pressure, now);
+            }
+            if reason.is_none() {
+                let started = state.slots.iter().find(|slot| slot.job_id == job_id).cloned();
+                if let Some(slot) = started {
+                    record_request(state, &slot, now);
+                }
+            }
+            Ok(Some(reason))
+        })?;
+        if may_prune {
+            last_prune = Some(Instant::now());
+        }
+
+        match decision {
+            None => return Ok(Admission::Cancelled),
+            Some(None) => return Ok(Admission::Granted(DispatchSlot { job_id: job_id.to_string() })),
+            Some(Some(reason)) => {
+                if last_reason != Some(reason) {
+                    debug!(job_id, domain = %limits.domain, reason = ?reason, "Download waiting");
+                    on_wait(reason);
+                    last_reason = Some(reason);
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+// Records why a job waits outside the limits; false when its entry is gone
+fn mark_waiting(job_id: &str, reason: WaitReason) -> Result<bool, String> {
+    update_state(|state| {
+        let slot = state.slots.iter_mut().find(|slot| slot.job_id == job_id);
+        Ok(slot
+            .map(|slot| {
+                slot.waiting_reason = Some(reason);
+                slot.running = false;
+            })
+            .is_some())
+    })
+}
+
+// Removes a job that is still waiting; false when it is not waiting
+pub fn withdraw(job_id: &str) -> Result<bool, String> {
+    update_state(|state| {
+        let before = state.slots.len();
+        state.slots.retain(|slot| slot.running || slot.job_id != job_id);
+        Ok(state.slots.len() != before)
+    })
+}
+
+// Moves a waiting job to `new_index` among the waiting jobs; past the end
+// means last. A manual move wins over priority until the job starts.
+pub fn reorder(job_id: &str, new_index: usize) -> Result<SlotEntry, String> {
+    update_state(|state| {
+        let slot = take_waiting(state, job_id)?;
+        let position = state
+            .slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| !slot.running)
+            .nth(new_index)
+            .map_or(state.slots.len(), |(position, _)| position);
+        state.slots.insert(position, slot.clone());
+        Ok(slot)
+    })
+}
+
+// Changes a waiting job's priority and moves it behind the jobs that now go first
+pub fn set_priority(job_id: &str, priority: Priority) -> Result<SlotEntry, String> {
+    update_state(|state| {
+        let mut slot = take_waiting(state, job_id)?;
+        slot.priority = priority;
+        let position = insert_position(&state.slots, priority);
+        state.slots.insert(position, slot.clone());
+        Ok(slot)
+    })
+}
+
+// Newest first
+pub fn throttle_decisions() -> Result<Vec<ThrottleDecision>, String> {
+    let _lock = lock_state()?;
+    Ok(read_state()?.throttle_decisions.into_iter().collect())
+}
+
+fn record_throttle(state: &mut DispatchState, job_id: &str, reason: WaitReason, pressure: Pressure, now: i64) {
+    state.throttle_decisions.push_front(ThrottleDecision {
+        at: now,
+        job_id: job_id.to_string(),
+        reason,
+        pressure,
+    });
+    state.throttle_decisions.truncate(KEPT_THROTTLE_DECISIONS);
+}
+
+// When the next job may start, per domain still in its delay
+pub fn next_allowed() -> Result<HashMap<String, i64>, String> {
+    let _lock = lock_state()?;
+    let now = current_timestamp_millis();
+    Ok(read_state()?
+        .next_allowed
+        .into_iter()
+        .filter(|(_, at)| *at > now)
+        .collect())
+}
+
+// Jobs held back by a limit, in the order they will start
+pub fn waiting() -> Result<Vec<SlotEntry>, String> {
+    let _lock = lock_state()?;
+    Ok(read_state()?
+        .slots
+        .into_iter()
+        .filter(|slot| !slot.running)
+        .collect())
+}
+
+// yt-dlp makes several requests per download; keep the same distance between them
+pub fn add_sleep_requests_argument(command: &mut Command, settings: &Settings, url: &str) {
+    let limits = limits_for(settings, url);
+    if limits.min_delay_ms > 0 {
+        command
+            .arg("--sleep-requests")
+            .arg(format!("{:.3}", limits.min_delay_ms as f64 / 1000.0));
+    }
+}
+
+fn limits_for(settings: &Settings, url: &str) -> Limits {
+    let host = url_host(url).unwrap_or_default();
+    match settings
+        .domain_limits
+        .iter()
+        .find(|limit| host_in_domain(&host, &limit.domain))
+    {
+        Some(limit) => Limits {
+            domain: limit.domain.trim().trim_start_matches('.').to_ascii_lowercase(),
+            max_concurrent: limit.max_concurrent,
+            min_delay_ms: limit.min_delay_ms,
+            jitter_ms: limit.jitter_ms,
+        },
+        None => Limits {
+            domain: host.trim_start_matches("www.").to_string(),
+            max_concurrent: settings.domain_max_concurrent,
+            min_delay_ms: settings.domain_min_delay_ms,
+            jitter_ms: settings.domain_jitter_ms,
+        },
+    }
+}
+
+// Pushes the domain's next start to the slot's delay plus fresh jitter after
+// `now`, and forgets domains whose delay has passed
+fn record_request(state: &mut DispatchState, slot: &SlotEntry, now: i64) {
+    state.next_allowed.retain(|_, at| *at > now);
+    let delay = slot.min_delay_ms + random_jitter(slot.jitter_ms);
+    if delay > 0 {
+        let at = now + delay as i64;
+        let next = state.next_allowed.entry(slot.domain.clone()).or_insert(at);
+        *next = (*next).max(at);
+    }
+}
+
+// Evenly spread over 0..=jitter_ms; the whole of it when no randomness is at
+// hand, erring on the polite side
+fn random_jitter(jitter_ms: u64) -> u64 {
+    if jitter_ms == 0 {
+        return 0;
+    }
+    let mut bytes = [0u8; 8];
+    match getrandom::getrandom(&mut bytes) {
+        Ok(()) => u64::from_le_bytes(bytes) % (jitter_ms + 1),
+        Err(error) => {
+            debug!("No randomness for download jitter: {}", error);
+            jitter_ms
+        }
+    }
+}
+
+// None when the job may start now. The outer None means the job's entry is gone.
+// Walks the waiting jobs in order, letting every job ahead that could start take
+// its share of the free slots first.
+fn blocking_reason(
+    state: &DispatchState,
+    settings: &Settings,
+    pressure: &Pressure,
+    job_id: &str,
+    now: i64,
+) -> Option<Option<WaitReason>> {
+    if !state.slots.iter().any(|slot| slot.job_id == job_id) {
+        return None;
+    }
+    let running = state.slots.iter().filter(|slot| slot.running).collect::<Vec<_>>();
+    let max_concurrent = settings.max_concurrent_downloads as usize;
+    let limit = if pressure.is_under() { max_concurrent.min(1) } else { max_concurrent };
+    let mut free = limit.saturating_sub(running.len());
+    let mut starting = HashMap::<&str, usize>::new();
+
+    for slot in &state.slots {
+        // Jobs waiting for their share or the network neither start nor hold back others
+        if slot.job_id != job_id
+            && matches!(slot.waiting_reason, Some(WaitReason::Destination | WaitReason::Offline))
+        {
+            continue;
+        }
+        if slot.running {
+            if slot.job_id == job_id {
+                return Some(None);
+            }
+            continue;
+        }
+        let starting_here = starting.get(slot.domain.as_str()).copied().unwrap_or(0);
+        let running_here = running.iter().filter(|other| other.domain == slot.domain).count();
+        let delayed = state.next_allowed.get(&slot.domain).is_some_and(|at| now < *at);
+        let spaced = slot.min_delay_ms > 0 || slot.jitter_ms > 0;
+        // Alone a merge starts anyway; waiting would free nothing of ours
+        let reason = if slot.merge_heavy && pressure.is_under() && !running.is_empty() {
+            Some(WaitReason::DeferredMerge)
+        } else if running_here + starting_here >= slot.max_concurrent as usize {
+            Some(WaitReason::DomainLimit)
+        } else if delayed || (spaced && starting_here > 0) {
+            Some(WaitReason::DomainDelay)
+        } else if free == 0 && limit < max_concurrent && running.len() < max_concurrent {
+            Some(WaitReason::LowResources)
+        } else if free == 0 {
+            Some(WaitReason::GlobalLimit)
+        } else {
+            None
+        };
+
+        if slot.job_id == job_id {
+            return Some(reason);
+        }
+        if reason.is_none() {
+            free -= 1;
+            *starting.entry(slot.domain.as_str()).or_default() += 1;
+        }
+    }
+    Some(None)
+}
+
+// After every waiting job of the same or higher priority
+fn insert_position(slots: &[SlotEntry], priority: Priority) -> usize {
+    match slots.iter().rposition(|slot| !slot.running && slot.priority >= priority) {
+        Some(position) => position + 1,
+        None => slots.iter().position(|slot| !slot.running).unwrap_or(slots.len()),
+    }
+}
+
+fn take_waiting(state: &mut DispatchState, job_id: &str) -> Result<SlotEntry, String> {
+    let position = state
+        .slots
+        .iter()
+        .position(|slot| !slot.running && slot.job_id == job_id)
+        .ok_or_else(|| format!("Download {} is not waiting in the queue", job_id))?;
+    Ok(state.slots.remove(position))
+}
+
+fn prune_dead_hosts(state: &mut DispatchState, own_pid: u32) -> bool {
+    let dead = state
+        .slots
+        .iter()
+        .map(|slot| slot.host_pid)
+        .filter(|pid| *pid != own_pid)
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .filter(|pid| !jobs::process_exists(*pid))
+        .collect::<HashSet<_>>();
+    if dead.is_empty() {
+        return false;
+    }
+    debug!("Releasing download slots of {} exited host process(es)", dead.len());
+    state.slots.retain(|slot| !dead.contains(&slot.host_pid));
+    true
+}
+
+fn state_path() -> Result<PathBuf, String> {
+    Ok(get_app_data_directory()?.join(STATE_FILE_NAME))
+}
+
+fn read_state() -> Result<DispatchState, String> {
+    let path = state_path()?;
+    match fs::read_to_string(&path) {
+        // A damaged file only holds transient state; starting over is safe
+        Ok(contents) => Ok(serde_json::from_str(&contents).unwrap_or_default()),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(DispatchState::default()),
+        Err(error) => Err(format!("Failed to read download slots: {}", error)),
+    }
+}
+
+fn write_state(state: &DispatchState) -> Result<(), String> {
+    let path = state_path()?;
+    let contents = serde_json::to_string_pretty(state)
+        .map_err(|e| format!("Failed to serialize download slots: {}", e))?;
+    let temp_path = path.with_extension(format!("json.{}.tmp", std::process::id()));
+    fs::write(&temp_path, contents)
+        .map_err(|e| format!("Failed to write download slots: {}", e))?;
+    fs::rename(&temp_path, &path)
+        .map_err(|e| format!("Failed to save download slots: {}", e))
+}
+
+fn update_state<T>(change: impl FnOnce(&mut DispatchState) -> Result<T, String>) -> Result<T, String> {
+    let _lock = lock_state()?;
+    let mut state = read_state()?;
+    let result = change(&mut state)?;
+    write_state(&state)?;
+    Ok(result)
+}
+
+fn lock_state() -> Result<FileLock, String> {
+    file_lock::acquire(LOCK_FILE_NAME, "download slots")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slot(job_id: &str, domain: &str, priority: Priority, running: bool) -> SlotEntry {
+        SlotEntry {
+            job_id: job_id.to_string(),
+            domain: domain.to_string(),
+            priority,
+            max_concurrent: 1,
+            min_delay_ms: 0,
+            jitter_ms: 0,
+            host_pid: std::process::id(),
+            running,
+            waiting_reason: None,
+            since: 0,
+            merge_heavy: false,
+        }
+    }
+
+    // Queues the jobs as acquire does, in the order they arrive
+    fn queue(slots: Vec<SlotEntry>) -> DispatchState {
+        let mut state = DispatchState::default();
+        for slot in slots {
+            let position = insert_position(&state.slots, slot.priority);
+            state.slots.insert(position, slot);
+        }
+        state
+    }
+
+    fn reason(state: &DispatchState, settings: &Settings, job_id: &str) -> Option<WaitReason> {
+        blocking_reason(state, settings, &Pressure::default(), job_id, 0).expect("queued")
+    }
+
+    #[test]
+    fn waiting_jobs_go_by_priority_then_age() {
+        let state = queue(vec![
+            slot("running", "a.com", Priority::Low, true),
+            slot("low", "b.com", Priority::Low, false),
+            slot("normal", "c.com", Priority::Normal, false),
+            slot("high", "d.com", Priority::High, false),
+            slot("later normal", "e.com", Priority::Normal, false),
+        ]);
+        let order = state.slots.iter().map(|slot| slot.job_id.as_str()).collect::<Vec<_>>();
+        assert_eq!(order, ["running", "high", "normal", "later normal", "low"]);
+
+        // The one free slot goes to the front of the queue
+        let settings = Settings {
+            max_concurrent_downloads: 2,
+            ..Settings::default()
+        };
+        assert_eq!(reason(&state, &settings, "high"), None);
+        for job_id in ["normal", "later normal", "low"] {
+            assert_eq!(reason(&state, &settings, job_id), Some(WaitReason::GlobalLimit), "{}", job_id);
+        }
+        assert_eq!(reason(&state, &settings, "running"), None);
+        assert_eq!(blocking_reason(&state, &settings, &Pressure::default(), "gone", 0), None);
+    }
+
+    #[test]
+    fn a_domain_at_its_limit_does_not_hold_up_the_queue() {
+        let state = queue(vec![
+            slot("a running", "a.com", Priority::Normal, true),
+            slot("a waiting", "a.com", Priority::Normal, false),
+            slot("b first", "b.com", Priority::Normal, false),
+            slot("b second", "b.com", Priority::Normal, false),
+            slot("c", "c.com", Priority::Normal, false),
+            slot("d", "d.com", Priority::Normal, false),
+        ]);
+        let settings = Settings {
+            max_concurrent_downloads: 3,
+            ..Settings::default()
+        };
+        assert_eq!(reason(&state, &settings, "a waiting"), Some(WaitReason::DomainLimit));
+        assert_eq!(reason(&state, &settings, "b first"), None);
+        // b.com's one slot goes to the job ahead of it
+        assert_eq!(reason(&state, &settings, "b second"), Some(WaitReason::DomainLimit));
+        assert_eq!(reason(&state, &settings, "c"), None);
+        // The skipped jobs took no share of the global limit, but those that start do
+        assert_eq!(reason(&state, &settings, "d"), Some(WaitReason::GlobalLimit));
+    }
+}
@@ -0,0 +1,122 @@
+use serde::Serialize;
+use std::fmt;
+
+use crate::settings::Settings;
+use crate::site_login::host_in_domain;
+use crate::url_host;
+
+// What every message starts with; classify_download_error keys on it
+pub const BLOCKED_PREFIX: &str = "Domain blocked";
+
+// Why the policy refused a URL
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DomainBlocked {
+    pub host: String,
+    // The blocked_domains entry that matched; None when the host is missing
+    // from a non-empty allowed_domains
+    pub rule: Option<String>,
+}
+
+impl fmt::Display for DomainBlocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.rule {
+            Some(rule) => write!(f, "{}: {} matches {} in the blocked domains", BLOCKED_PREFIX, self.host, rule),
+            None => write!(f, "{}: {} is not in the allowed domains", BLOCKED_PREFIX, self.host),
+        }
+    }
+}
+
+// Whether the stored settings let the host download from `url`. The
+// blocklist wins over the allowlist, and an empty allowlist allows every
+// site. Nothing a message sends can lift the policy.
+pub fn check(settings: &Settings, url: &str) -> Result<(), DomainBlocked> {
+    let host = url_host(url).unwrap_or_default();
+    if let Some(rule) = settings.blocked_domains.iter().find(|rule| rule_matches(&host, rule)) {
+        return Err(DomainBlocked {
+            host,
+            rule: Some(rule.trim().to_string()),
+        });
+    }
+    if !settings.allowed_domains.is_empty() && !settings.allowed_domains.iter().any(|rule| rule_matches(&host, rule)) {
+        return Err(DomainBlocked { host, rule: None });
+    }
+    Ok(())
+}
+
+// "example.com" is that host only; "*.example.com" is it and every subdomain.
+// A trailing dot is ignored on both sides, and "[::1]" matches that IPv6 host.
+fn rule_matches(host: &str, rule: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    let rule = rule.trim().trim_end_matches('.').to_ascii_lowercase();
+    if host.is_empty() {
+        return false;
+    }
+    match rule.strip_prefix("*.") {
+        Some(domain) => host_in_domain(host, domain),
+        None => host == rule,
+    }
+}
+
+// None when `rule` is a host name, optionally behind "*.", or a bracketed
+// IPv6 address
+pub fn invalid_rule(rule: &str) -> Option<String> {
+    let trimmed = rule.trim();
+    let domain = trimmed.strip_prefix("*.").unwrap_or(trimmed);
+    let ipv6 = trimmed.len() > 2 && trimmed.starts_with('[') && trimmed.ends_with(']');
+    if domain.is_empty() || (!ipv6 && domain.contains(['/', ':', ' ', '*', '[', ']'])) {
+        Some(format!("\"{}\" is not a host name such as example.com or *.example.com", rule))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(blocked: &[&str], allowed: &[&str]) -> Settings {
+        Settings {
+            blocked_domains: blocked.iter().map(|rule| rule.to_string()).collect(),
+            allowed_domains: allowed.iter().map(|rule| rule.to_string()).collect(),
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn a_trailing_dot_does_not_get_past_the_blocklist() {
+        for rule in ["blocked.example", "*.blocked.example", "blocked.example."] {
+            let blocked = check(&settings(&[rule], &[]), "https://blocked.example./x").unwrap_err();
+            assert_eq!(blocked.host, "blocked.example");
+            assert_eq!(blocked.rule.as_deref(), Some(rule));
+        }
+        assert!(check(&settings(&["*.blocked.example"], &[]), "https://cdn.blocked.example./x").is_err());
+    }
+
+    #[test]
+    fn a_trailing_dot_still_matches_the_allowlist() {
+        let policy = settings(&[], &["blocked.example"]);
+        assert!(check(&policy, "https://blocked.example./x").is_ok());
+        assert!(check(&settings(&[], &["*.blocked.example"]), "https://cdn.blocked.example./x").is_ok());
+        assert!(check(&policy, "https://other.example./x").is_err());
+    }
+
+    #[test]
+    fn ipv6_hosts_keep_their_brackets() {
+        let blocked = check(&settings(&["[::1]"], &[]), "http://[::1]:8080/x").unwrap_err();
+        assert_eq!(blocked.host, "[::1]");
+        assert!(check(&settings(&["[::1]"], &[]), "http://[::2]/x").is_ok());
+        assert!(check(&settings(&[], &["[::1]"]), "http://[::1]/x").is_ok());
+    }
+
+    #[test]
+    fn rules_are_host_names_or_ipv6_literals() {
+        assert_eq!(invalid_rule("example.com"), None);
+        assert_eq!(invalid_rule("*.example.com"), None);
+        assert_eq!(invalid_rule("example.com."), None);
+        assert_eq!(invalid_rule("[::1]"), None);
+        assert!(invalid_rule("*.[::1]").is_some());
+        assert!(invalid_rule("example.com:8080").is_some());
+        assert!(invalid_rule("[::1").is_some());
+    }
+}
@@ -0,0 +1,251 @@
+use serde::Serialize;
+
+// Post-processors yt-dlp runs after the streams are in; each announces itself
+// with its name in brackets, e.g. "[ExtractAudio] Destination: ..."
+const POST_PROCESSOR_PREFIXES: &[&str] = &[
+    "[ExtractAudio]",
+    "[VideoConvertor]",
+    "[VideoRemuxer]",
+    "[Fixup",
+    "[FFmpeg",
+    "[EmbedThumbnail]",
+    "[EmbedSubtitle]",
+    "[Metadata]",
+    "[ModifyChapters]",
+    "[SponsorBlock]",
+    "[ThumbnailsConvertor]",
+    "[MoveFiles]",
+];
+pub(crate) const AUDIO_EXTENSIONS: &[&str] = &["m4a", "mp3", "opus", "ogg", "oga", "aac", "weba", "wav", "flac"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DownloadPhase {
+    Extracting,
+    Video,
+    Audio,
+    Merging,
+    PostProcessing,
+}
+
+impl DownloadPhase {
+    // Share of the whole job, in percent. The stream shares depend on how
+    // many streams yt-dlp fetches, so they are weighed in `weight`.
+    fn weight(self, streams: usize) -> f64 {
+        match (self, streams) {
+            (DownloadPhase::Extracting, _) => 5.0,
+            (DownloadPhase::Video, 2) => 60.0,
+            (DownloadPhase::Audio, 2) => 20.0,
+            (DownloadPhase::Video | DownloadPhase::Audio, _) => 80.0,
+            (DownloadPhase::Merging, _) => 10.0,
+            (DownloadPhase::PostProcessing, _) => 5.0,
+        }
+    }
+}
+
+// Attached to progress frames as their `data`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PhaseProgress {
+    pub phase: DownloadPhase,
+    // None for phases yt-dlp reports no percent for, such as merging
+    pub phase_percent: Option<f64>,
+    // Weighted over all phases; never goes backwards
+    pub percent: f64,
+}
+
+// Follows one yt-dlp run through its phases, so progress reads as one
+// climbing number instead of restarting at 0% for the audio stream
+pub struct PhaseTracker {
+    phase: DownloadPhase,
+    phase_percent: Option<f64>,
+    // "137+140" is two streams: video first, then audio
+    streams: usize,
+    // Streams whose destination yt-dlp has announced so far
+    destinations: usize,
+    // Sum of the weights of the phases already left behind
+    completed: f64,
+    percent: f64,
+}
+
+impl Default for PhaseTracker {
+    fn default() -> Self {
+        PhaseTracker {
+            phase: DownloadPhase::Extracting,
+            phase_percent: None,
+            streams: 1,
+            destinations: 0,
+            completed: 0.0,
+            percent: 0.0,
+        }
+    }
+}
+
+impl PhaseTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The progress after `line`, when it is a progress line or starts a phase
+    pub fn observe(&mut self, line: &str) -> Option<PhaseProgress> {
+        let line = line.trim();
+        if let Some(formats) = line.split_once("Downloading 1 format(s):").map(|(_, formats)| formats) {
+            self.streams = formats.trim().split('+').count().max(1);
+            return None;
+        }
+
+        if let Some(destination) = line.strip_prefix("[download] Destination:") {
+            let phase = if self.streams == 2 {
+                if self.destinations == 0 {
+                    DownloadPhase::Video
+                } else {
+                    DownloadPhase::Audio
+                }
+            } else if is_audio_file(destination.trim()) {
+                DownloadPhase::Audio
+            } else {
+                DownloadPhase::Video
+            };
+            self.destinations += 1;
+            self.enter(phase);
+        } else if line.ends_with("has already been downloaded") && line.starts_with("[download]") {
+            // The stream is in from an earlier run; it counts as done
+            self.destinations += 1;
+            self.enter(if self.destinations == 2 { DownloadPhase::Audio } else { DownloadPhase::Video });
+            self.phase_percent = Some(100.0);
+        } else if line.starts_with("[Merger]") {
+            self.enter(DownloadPhase::Merging);
+        } else if POST_PROCESSOR_PREFIXES.iter().any(|prefix| line.starts_with(prefix)) {
+            if self.phase == DownloadPhase::PostProcessing {
+                return None;
+            }
+            self.enter(DownloadPhase::PostProcessing);
+        } else if let Some(percent) = parse_download_percent(line) {
+            if !matches!(self.phase, DownloadPhase::Video | DownloadPhase::Audio) {
+                return None;
+            }
+            self.phase_percent = Some(percent.clamp(0.0, 100.0));
+        } else {
+            return None;
+        }
+
+        let phase_share = self.phase_percent.unwrap_or(0.0) / 100.0 * self.phase.weight(self.streams);
+        self.percent = self.percent.max((self.completed + phase_share).min(100.0));
+        Some(PhaseProgress {
+            phase: self.phase,
+            phase_percent: self.phase_percent,
+            percent: (self.percent * 10.0).round() / 10.0,
+        })
+    }
+
+    fn enter(&mut self, phase: DownloadPhase) {
+        if phase != self.phase {
+            self.completed += self.phase.weight(self.streams);
+            self.phase = phase;
+        }
+        self.phase_percent = None;
+    }
+}
+
+// "[download]  45.3% of ~10.00MiB at ..." -> 45.3
+fn parse_download_percent(line: &str) -> Option<f64> {
+    line.strip_prefix("[download]")?
+        .split_whitespace()
+        .next()?
+        .strip_suffix('%')?
+        .parse()
+        .ok()
+}
+
+fn is_audio_file(path: &str) -> bool {
+    path.rsplit_once('.')
+        .is_some_and(|(_, extension)| AUDIO_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const TRANSCRIPTS_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/yt-dlp");
+
+    // Every progress a transcript yields, line by line
+    fn replay(name: &str) -> Vec<PhaseProgress> {
+        let path = format!("{}/{}", TRANSCRIPTS_DIR, name);
+        let transcript = fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path, e));
+        let mut tracker = PhaseTracker::new();
+        transcript.lines().filter_map(|line| tracker.observe(line)).collect()
+    }
+
+    fn phases(progress: &[PhaseProgress]) -> Vec<DownloadPhase> {
+        let mut phases = progress.iter().map(|progress| progress.phase).collect::<Vec<_>>();
+        phases.dedup();
+        phases
+    }
+
+    #[test]
+    fn transcripts_climb_through_their_phases() {
+        use DownloadPhase::*;
+        let cases: &[(&str, &[DownloadPhase], f64)] = &[
+            ("merge.txt", &[Video, Audio, Merging], 85.0),
+            ("single.txt", &[Video, PostProcessing], 85.0),
+            ("audio_only.txt", &[Audio, PostProcessing], 85.0),
+            ("resumed.txt", &[Video, Audio, Merging], 85.0),
+        ];
+        for (name, expected_phases, expected_percent) in cases {
+            let progress = replay(name);
+            assert_eq!(phases(&progress), *expected_phases, "{}", name);
+            assert!(
+                progress.windows(2).all(|pair| pair[0].percent <= pair[1].percent),
+                "{} goes backwards: {:?}",
+                name,
+                progress
+            );
+            assert!(progress.iter().all(|progress| (0.0..=100.0).contains(&progress.percent)), "{}", name);
+            assert_eq!(progress.last().map(|progress| progress.percent), Some(*expected_percent), "{}", name);
+        }
+    }
+
+    #[test]
+    fn the_video_stream_of_two_ends_at_its_share() {
+        let progress = replay("merge.txt");
+        let video_done = progress
+            .iter()
+            .rfind(|progress| progress.phase == DownloadPhase::Video)
+            .expect("video progress");
+        assert_eq!(video_done.phase_percent, Some(100.0));
+        assert_eq!(video_done.percent, 65.0);
+        // The audio stream starts from there instead of from zero
+        let audio_start = progress
+            .iter()
+            .find(|progress| progress.phase == DownloadPhase::Audio)
+            .expect("audio progress");
+        assert_eq!(audio_start.phase_percent, None);
+        assert_eq!(audio_start.percent, 65.0);
+    }
+
+    #[test]
+    fn a_stream_already_on_disk_counts_as_done() {
+        let progress = replay("resumed.txt");
+        assert_eq!(progress[0].phase, DownloadPhase::Video);
+        assert_eq!(progress[0].phase_percent, Some(100.0));
+        assert_eq!(progress[0].percent, 65.0);
+        // yt-dlp's own percent dips after a resume; the overall one holds
+        let audio = progress
+            .iter()
+            .filter_map(|progress| progress.phase_percent.filter(|_| progress.phase == DownloadPhase::Audio))
+            .collect::<Vec<_>>();
+        assert_eq!(audio, vec![43.1, 40.2, 100.0]);
+    }
+
+    #[test]
+    fn fragment_progress_of_unknown_size_is_read() {
+        assert_eq!(
+            parse_download_percent("[download]  48.4% of ~   5.21MiB at    1.95MiB/s ETA 00:01 (frag 15/31)"),
+            Some(48.4)
+        );
+        assert_eq!(parse_download_percent("[download] 100% of    5.24MiB in 00:00:02 at 2.38MiB/s"), Some(100.0));
+        assert_eq!(parse_download_percent("[download] Resuming download at byte 4194304"), None);
+        assert_eq!(parse_download_percent("[hlsnative] Total fragments: 31"), None);
+    }
+}
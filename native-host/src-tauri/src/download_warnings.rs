@@ -0,0 +1,45 @@
+use std::collections::HashSet;
+use tracing::debug;
+
+use crate::source_page::truncate_at_char_boundary;
+
+// A playlist of throttled fragments can print hundreds of warnings
+const MAX_WARNINGS: usize = 20;
+const MAX_WARNING_BYTES: usize = 500;
+
+// The distinct "WARNING:" lines yt-dlp printed during one run, such as a
+// fallback to a lower quality or a missing ffmpeg feature
+#[derive(Default)]
+pub struct DownloadWarnings {
+    warnings: Vec<String>,
+    seen: HashSet<String>,
+    omitted: usize,
+}
+
+impl DownloadWarnings {
+    // The warning text when the line is a warning not seen before in this run
+    // and the list still has room; the caller reports it as a warning event
+    pub fn observe(&mut self, stream: &str, line: &str) -> Option<String> {
+        if stream != "stderr" {
+            return None;
+        }
+        let warning = line.trim().strip_prefix("WARNING:")?.trim();
+        let warning = truncate_at_char_boundary(warning, MAX_WARNING_BYTES);
+        if warning.is_empty() || !self.seen.insert(warning.to_string()) {
+            return None;
+        }
+        if self.warnings.len() == MAX_WARNINGS {
+            self.omitted += 1;
+            return None;
+        }
+        self.warnings.push(warning.to_string());
+        Some(warning.to_string())
+    }
+
+    pub fn into_vec(self) -> Vec<String> {
+        if self.omitted > 0 {
+            debug!(omitted = self.omitted, "More yt-dlp warnings than are reported");
+        }
+        self.warnings
+    }
+}
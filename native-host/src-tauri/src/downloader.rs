@@ -0,0 +1,2400 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+use crate::chapters::ChapterFile;
+use crate::coalesce::Claim;
+use crate::child_io::ChildEvent;
+use crate::dispatcher::{Admission, Priority};
+use crate::download_phase::PhaseTracker;
+use crate::download_warnings::DownloadWarnings;
+use crate::extension_origin::ExtensionOrigin;
+use crate::failure_details::FailureDetails;
+use crate::history::{DownloadStatus, History, HistoryEntry, NewHistoryEntry};
+use crate::instance::InstanceMessage;
+use crate::jobs::{JobEnd, JobHandle, JobRegistry};
+use crate::media_info::MediaInfo;
+use crate::native_stdout::FrameWriter;
+use crate::process_priority::ProcessPriority;
+use crate::protocol::{send_job_frame, send_native_response, BrowserCookie, NativeMessage, NativeResponse};
+use crate::queue::{announce_scheduled, generate_job_id};
+use crate::schedule::StopReason;
+use crate::settings::Settings;
+use crate::site_login::{self, host_in_domain, TempNetrc};
+use crate::source_page::SourcePage;
+use crate::speed_stats::{JobStats, SpeedSampler, TransferStats};
+use crate::stall::{Stall, StallDetector};
+use crate::supervisor::{ChildSupervisor, SupervisionReport};
+use crate::{
+    bandwidth, browser_fallback, chapters, child_io, coalesce, conflicts, connectivity, console_text, current_timestamp_millis,
+    dedupe, destination, dispatcher, domain_policy, extractors, failure_details, folder_protection, get_executable_directory,
+    i18n, instance, logging, long_path, media_info, media_policy, metrics, notifications, organize, origin_tag,
+    output_template, page_refresh, post_download, profiles, redact, resource_pressure, retry, s3, schedule, tool_integrity,
+    url_host, user_agent, validate_download_url, vault_events, webhooks,
+};
+
+pub const DEFAULT_GALLERY_DL_DOMAINS: &[&str] = &[
+    "pixiv.net",
+    "deviantart.com",
+    "artstation.com",
+    "danbooru.donmai.us",
+    "gelbooru.com",
+    "imgur.com",
+    "flickr.com",
+];
+// Direct links to these are fetched as they are, without an extractor
+pub(crate) const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif", "webp", "avif", "bmp", "tif", "tiff", "heic"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    YtDlp,
+    HttpImage,
+    GalleryDl,
+    // Development only; see fake_download
+    Fake,
+}
+
+impl Backend {
+    // Stored with the transfer stats of each history entry
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::YtDlp => "yt-dlp",
+            Backend::HttpImage => "http-image",
+            Backend::GalleryDl => "gallery-dl",
+            Backend::Fake => "fake",
+        }
+    }
+}
+
+// One download job as every step after its request sees it: the queue,
+// history and the resume queue
+#[derive(Clone, Copy)]
+pub struct DownloadContext<'a> {
+    pub job_id: &'a str,
+    pub url: &'a str,
+    // File or yt-dlp output template, before the source page is applied
+    pub output_path: &'a str,
+    pub upload: bool,
+    pub priority: Priority,
+    pub source_page: &'a SourcePage,
+    // The entry's source in history: "gui", "native", "http"...
+    pub source: &'a str,
+}
+
+// What a job asks of its backend; every backend runs as a child process, so
+// the job registry can pause, cancel and supervise any of them by pid
+pub struct DownloadOptions<'a> {
+    pub job_id: &'a str,
+    pub url: &'a str,
+    // File or yt-dlp output template
+    pub output_path: &'a str,
+    pub format_selector: &'a str,
+    pub settings: &'a Settings,
+    pub source_page: &'a SourcePage,
+    // Native downloads log verbosely and run in their output directory
+    pub verbose: bool,
+}
+
+impl DownloadOptions<'_> {
+    fn output_dir(&self) -> &Path {
+        Path::new(self.output_path)
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."))
+    }
+}
+
+// A way to download a URL. The child writes its progress to stdout and stderr
+// line by line, which the job loop reads for progress events, stall
+// detection and supervision, and prints the path of each finished file on a
+// plain stdout line.
+pub trait Downloader {
+    fn backend(&self) -> Backend;
+
+    // The child's name in log lines and error messages
+    fn program(&self) -> &str;
+
+    // Whether this backend should take the URL when none was asked for
+    fn probe(&self, url: &str, settings: &Settings) -> bool;
+
+    // The command to spawn, with piped output; cookies and site logins are
+    // added by the caller
+    fn command(&self, options: &DownloadOptions) -> Result<Command, String>;
+
+    // Keep the returned guard alive until the child exits
+    fn add_site_login(&self, _command: &mut Command, settings: &Settings, url: &str) -> Result<Option<TempNetrc>, String> {
+        if site_login::has_login(settings, url) {
+            debug!(program = self.program(), "Site logins are only passed to yt-dlp");
+        }
+        Ok(None)
+    }
+
+    // The last file the child reported, from its collected stdout
+    fn file_path(&self, stdout_text: &str) -> Option<String> {
+        last_plain_line(stdout_text)
+    }
+}
+
+// The backend for a download: the one asked for, or the first whose probe
+// takes the URL. yt-dlp takes everything the others do not, unless fake
+// downloads are on, when the fake backend takes it all.
+pub fn route(url: &str, settings: &Settings, requested: Option<Backend>) -> Box<dyn Downloader> {
+    if let Some(backend) = requested {
+        return for_backend(backend);
+    }
+    if settings.fake_downloads_enabled() {
+        debug!("Fake downloads are on; not running yt-dlp");
+        return Box::new(FakeDownloader);
+    }
+    let candidates: [Box<dyn Downloader>; 2] = [Box::new(HttpImageDownloader), Box::new(GalleryDlDownloader)];
+    let downloader = candidates
+        .into_iter()
+        .find(|downloader| downloader.probe(url, settings))
+        .unwrap_or_else(|| Box::new(YtDlpDownloader));
+    debug!(backend = ?downloader.backend(), "Routed download");
+    downloader
+}
+
+pub fn for_backend(backend: Backend) -> Box<dyn Downloader> {
+    match backend {
+        Backend::YtDlp => Box::new(YtDlpDownloader),
+        Backend::HttpImage => Box::new(HttpImageDownloader),
+        Backend::GalleryDl => Box::new(GalleryDlDownloader),
+        Backend::Fake => Box::new(FakeDownloader),
+    }
+}
+
+pub struct YtDlpDownloader;
+
+impl Downloader for YtDlpDownloader {
+    fn backend(&self) -> Backend {
+        Backend::YtDlp
+    }
+
+    fn program(&self) -> &str {
+        "yt-dlp"
+    }
+
+    fn probe(&self, _url: &str, _settings: &Settings) -> bool {
+        true
+    }
+
+    fn command(&self, options: &DownloadOptions) -> Result<Command, String> {
+        let settings = options.settings;
+        // Fails before spawning yt-dlp for a link no extractor could take
+        extractors::check(settings, options.url)?;
+        tool_integrity::verify(settings.yt_dlp_program())?;
+        let mut command = Command::new(settings.yt_dlp_program());
+        command.arg(options.url);
+        if options.verbose {
+            command.arg("--verbose").arg("--windows-filenames").current_dir(options.output_dir());
+        }
+        command
+            .arg("-f")
+            .arg(options.format_selector)
+            .arg("--merge-output-format")
+            .arg("mkv")
+            .arg("-o")
+            .arg(options.output_path)
+            .arg("--no-playlist")
+            .arg("--progress")
+            .arg("--newline")
+            .arg("--print")
+            .arg("after_move:filepath")
+            .arg("--continue")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        console_text::force_utf8(&mut command);
+        media_info::add_print_argument(&mut command);
+        media_policy::add_format_sort_argument(&mut command, settings);
+        organize::add_yt_dlp_argument(&mut command, settings);
+        long_path::add_yt_dlp_argument(&mut command, settings, options.output_path);
+        bandwidth::add_limit_rate_argument(&mut command, settings, options.job_id);
+        dispatcher::add_sleep_requests_argument(&mut command, settings, options.url);
+        options.source_page.add_referer_argument(&mut command);
+        user_agent::add_argument(&mut command, settings, options.url, options.source_page, options.job_id);
+        profiles::add_download_archive_argument(&mut command, settings);
+        chapters::add_yt_dlp_arguments(&mut command, settings, options.output_path);
+        Ok(command)
+    }
+
+    fn add_site_login(&self, command: &mut Command, settings: &Settings, url: &str) -> Result<Option<TempNetrc>, String> {
+        site_login::add_netrc_argument(command, settings, url)
+    }
+}
+
+// Direct image links, fetched by this executable in a child of its own so
+// pause, cancel and supervision work the same as for yt-dlp
+pub struct HttpImageDownloader;
+
+impl Downloader for HttpImageDownloader {
+    fn backend(&self) -> Backend {
+        Backend::HttpImage
+    }
+
+    fn program(&self) -> &str {
+        "image fetch"
+    }
+
+    fn probe(&self, url: &str, _settings: &Settings) -> bool {
+        let path = url.split(['?', '#']).next().unwrap_or(url);
+        let file_name = path.rsplit('/').next().unwrap_or("");
+        file_name
+            .rsplit_once('.')
+            .is_some_and(|(_, extension)| IMAGE_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()))
+    }
+
+    fn command(&self, options: &DownloadOptions) -> Result<Command, String> {
+        let exe_path = env::current_exe().map_err(|e| format!("Failed to get executable path: {}", e))?;
+        let mut command = Command::new(exe_path);
+        command
+            .arg(crate::image_fetch::SUBCOMMAND)
+            .arg(options.url)
+            .arg("--output")
+            .arg(options.output_path)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if !options.job_id.is_empty() {
+            command.arg("--job-id").arg(options.job_id);
+        }
+        media_policy::add_image_fetch_argument(&mut command, options.settings);
+        organize::add_image_fetch_argument(&mut command, options.settings);
+        bandwidth::add_image_fetch_argument(&mut command, options.settings);
+        options.source_page.add_referer_argument(&mut command);
+        user_agent::add_argument(&mut command, options.settings, options.url, options.source_page, options.job_id);
+        Ok(command)
+    }
+}
+
+// Image galleries and art sites yt-dlp has no extractor for
+pub struct GalleryDlDownloader;
+
+impl Downloader for GalleryDlDownloader {
+    fn backend(&self) -> Backend {
+        Backend::GalleryDl
+    }
+
+    fn program(&self) -> &str {
+        "gallery-dl"
+    }
+
+    // Only for the configured sites, and only when gallery-dl is installed
+    fn probe(&self, url: &str, settings: &Settings) -> bool {
+        let Some(host) = url_host(url) else {
+            return false;
+        };
+        if !settings.gallery_dl_domains.iter().any(|domain| host_in_domain(&host, domain)) {
+            return false;
+        }
+        // A tampered copy is not run even for --version; the download reports it
+        if tool_integrity::verify(settings.gallery_dl_program()).is_err() {
+            return true;
+        }
+        let available = Command::new(settings.gallery_dl_program())
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success());
+        if !available {
+            debug!(host = %host, "gallery-dl is not installed; using yt-dlp");
+        }
+        available
+    }
+
+    fn command(&self, options: &DownloadOptions) -> Result<Command, String> {
+        let settings = options.settings;
+        tool_integrity::verify(settings.gallery_dl_program())?;
+        let mut command = Command::new(settings.gallery_dl_program());
+        // gallery-dl names files itself; only the folder of the template is used
+        command
+            .arg(options.url)
+            .arg("--directory")
+            .arg(options.output_dir())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if options.verbose {
+            command.arg("--verbose");
+        }
+        console_text::force_utf8(&mut command);
+        bandwidth::add_limit_rate_argument(&mut command, settings, options.job_id);
+        organize::add_gallery_dl_argument(&mut command, settings);
+        user_agent::add_argument(&mut command, settings, options.url, options.source_page, options.job_id);
+        Ok(command)
+    }
+
+    // Files already on disk are listed with a leading "# "
+    fn file_path(&self, stdout_text: &str) -> Option<String> {
+        let line = last_plain_line(stdout_text)?;
+        Some(line.strip_prefix("# ").map(str::to_string).unwrap_or(line))
+    }
+}
+
+// Plays a scenario from the URL's query string instead of downloading, so the
+// queue, progress, history and responses can be exercised without yt-dlp. It
+// runs as a child of this executable like HttpImageDownloader.
+pub struct FakeDownloader;
+
+impl Downloader for FakeDownloader {
+    fn backend(&self) -> Backend {
+        Backend::Fake
+    }
+
+    fn program(&self) -> &str {
+        "fake download"
+    }
+
+    fn probe(&self, _url: &str, settings: &Settings) -> bool {
+        settings.fake_downloads_enabled()
+    }
+
+    fn command(&self, options: &DownloadOptions) -> Result<Command, String> {
+        let exe_path = env::current_exe().map_err(|e| format!("Failed to get executable path: {}", e))?;
+        let mut command = Command::new(exe_path);
+        command
+            .arg(crate::fake_download::SUBCOMMAND)
+            .arg(options.url)
+            .arg("--output")
+            .arg(options.output_path)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        Ok(command)
+    }
+}
+
+// The last stdout line that is neither a bracketed status line nor a warning
+pub fn last_plain_line(stdout_text: &str) -> Option<String> {
+    stdout_text
+        .lines()
+        .rev()
+        .find(|line| {
+            let trimmed = line.trim();
+            !trimmed.is_empty() &&
+                !trimmed.starts_with('[') &&
+                !trimmed.starts_with("WARNING:") &&
+                !trimmed.starts_with("ERROR:")
+        })
+        .map(|line| line.trim().to_string())
+}
+
+// How often a running job looks for a queue pause while yt-dlp prints nothing
+pub(crate) const PAUSE_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+pub struct DownloadOutcome {
+    pub(crate) message: String,
+    pub(crate) file_path: Option<String>,
+    pub(crate) stdout: String,
+    pub(crate) stderr: String,
+    pub(crate) cancelled: bool,
+    // Stopped by pausing the queue or by shutdown; resumed later from its partial files
+    pub(crate) stopped: Option<StopReason>,
+    // Set when yt-dlp ran and exited with an error
+    pub(crate) failure: Option<Box<FailureDetails>>,
+    // Distinct yt-dlp warnings of a successful run
+    pub(crate) warnings: Vec<String>,
+    // Timing and speed of a successful run
+    pub(crate) stats: Option<TransferStats>,
+    // What the child's supervisor saw, for the history entry
+    pub(crate) supervision: Option<SupervisionReport>,
+}
+
+// Both sides carry the yt-dlp output; the failure is boxed to keep Result small
+pub(crate) type DownloadResult = Result<DownloadOutcome, Box<DownloadOutcome>>;
+
+impl DownloadOutcome {
+    pub(crate) fn failure(message: String) -> Self {
+        DownloadOutcome {
+            message,
+            file_path: None,
+            stdout: String::new(),
+            stderr: String::new(),
+            cancelled: false,
+            stopped: None,
+            failure: None,
+            warnings: Vec::new(),
+            stats: None,
+            supervision: None,
+        }
+    }
+}
+
+pub(crate) fn get_cookies_path() -> Result<PathBuf, String> {
+    Ok(get_executable_directory()?.join("cookies.txt"))
+}
+
+fn get_output_directory(output_path: &str) -> Result<PathBuf, String> {
+    PathBuf::from(output_path)
+        .parent()
+        .map(|dir| dir.to_path_buf())
+        .ok_or_else(|| "Failed to determine download directory from output_path".to_string())
+}
+
+fn write_temp_cookies_file(cookies: &[BrowserCookie]) -> Result<PathBuf, String> {
+    let temp_dir = env::temp_dir();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| format!("Failed to create timestamp for temp cookie file: {}", e))?
+        .as_millis();
+    let path = temp_dir.join(format!("imgvault-yt-dlp-cookies-{}.txt", timestamp));
+
+    let mut contents = String::from("# Netscape HTTP Cookie File\n# Generated by ImgVault\n");
+    for cookie in cookies {
+        let include_subdomains = if cookie.host_only { "FALSE" } else { "TRUE" };
+        let secure = if cookie.secure { "TRUE" } else { "FALSE" };
+        contents.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            cookie.domain,
+            include_subdomains,
+            cookie.path,
+            secure,
+            cookie.expiration_date.max(0),
+            cookie.name,
+            cookie.value
+        ));
+    }
+
+    fs::write(&path, contents)
+        .map_err(|e| format!("Failed to write temporary cookies file: {}", e))?;
+
+    Ok(path)
+}
+
+fn add_cookies_argument(command: &mut Command, cookies_data: Option<&[BrowserCookie]>) -> Result<Option<PathBuf>, String> {
+    if let Some(cookies) = cookies_data {
+        if !cookies.is_empty() {
+            let temp_path = write_temp_cookies_file(cookies)?;
+            command.arg("--cookies").arg(&temp_path);
+            return Ok(Some(temp_path));
+        }
+    }
+
+    let cookies_path = get_cookies_path()?;
+    if cookies_path.exists() {
+        command.arg("--cookies").arg(&cookies_path);
+        return Ok(Some(cookies_path));
+    }
+
+    Ok(None)
+}
+
+fn cleanup_temp_cookies_file(path: &Option<PathBuf>) {
+    if let Some(path) = path {
+        if let Some(file_name) = path.file_name().and_then(|name| name.to_str()) {
+            if file_name.starts_with("imgvault-yt-dlp-cookies-") {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+pub(crate) fn run_gui_download(
+    jobs: &JobRegistry,
+    history: &History,
+    settings: &Settings,
+    context: &DownloadContext,
+    format_selector: &str,
+    hide_window: bool,
+    backend: Option<Backend>,
+) -> Result<serde_json::Value, String> {
+    let DownloadContext { job_id, url, output_path, upload, source_page, source, .. } = *context;
+    info!(
+        job_id,
+        url = logging::loggable_url(url),
+        output_path,
+        hide_window,
+        "Starting GUI download"
+    );
+    // Before anything is queued, so a blocked site never sees a request
+    domain_policy::check(settings, url).map_err(|blocked| {
+        warn!(job_id, "{}", blocked);
+        blocked.to_string()
+    })?;
+    jobs.events().track_job(job_id, url);
+    jobs.events().publish(&NativeResponse {
+        message: Some(logging::loggable_url(url).to_string()),
+        ..NativeResponse::job_event("queued", Some(job_id))
+    });
+
+    let _in_flight = jobs.track();
+    let started_at = current_timestamp_millis();
+    if jobs.is_paused() {
+        info!(job_id, "Queue is paused, keeping the download for resume");
+        return Err(stop_gui_download(jobs, context, started_at, "", "", StopReason::Paused));
+    }
+
+    let output_dir = get_output_directory(&source_page.apply_to_output_path(output_path))?;
+    let downloader = route(url, settings, backend);
+    let merge_heavy = resource_pressure::is_merge_heavy(downloader.backend(), format_selector, settings);
+    let admission = dispatcher::acquire(jobs, settings, context, Some(&output_dir), merge_heavy, &mut |reason| {
+        jobs.events().publish(&NativeResponse {
+            message: Some(reason.describe().to_string()),
+            data: Some(serde_json::json!({ "reason": reason })),
+            ..NativeResponse::job_event("waiting", Some(job_id))
+        });
+    })?;
+    let slot = match admission {
+        Admission::Granted(slot) => slot,
+        Admission::Paused => {
+            info!(job_id, "Queue paused while waiting, keeping the download for resume");
+            return Err(stop_gui_download(jobs, context, started_at, "", "", StopReason::Paused));
+        }
+        Admission::Interrupted => {
+            return Err(stop_gui_download(jobs, context, started_at, "", "", StopReason::Interrupted));
+        }
+        Admission::Cancelled => {
+            info!(job_id, "Download cancelled while waiting");
+            let message = "Download cancelled by user";
+            jobs.events().publish(&NativeResponse {
+                message: Some(message.to_string()),
+                error_code: Some("cancelled".to_string()),
+                ..NativeResponse::job_event("cancelled", Some(job_id))
+            }
+            .localized(&i18n::resolve(None)));
+            return Err(serde_json::json!({
+                "cancelled": true,
+                "jobId": job_id,
+                "message": message,
+                "stdout": "",
+                "stderr": ""
+            }).to_string());
+        }
+    };
+    metrics::download_started();
+    
+    media_policy::check_backend(settings, downloader.backend()).map_err(|rejected| {
+        warn!(job_id, "{}", rejected);
+        rejected.to_string()
+    })?;
+    let program = downloader.program();
+    let mut command = downloader.command(&DownloadOptions {
+        job_id,
+        url,
+        output_path: &source_page.apply_to_output_path(output_path),
+        format_selector,
+        settings,
+        source_page,
+        verbose: false,
+    })?;
+
+    let cookies_path = add_cookies_argument(&mut command, None)?;
+    match &cookies_path {
+        Some(path) => debug!(job_id, "Using cookies: {}", path.display()),
+        None => debug!(job_id, "cookies.txt not found next to native host executable"),
+    }
+    let netrc = downloader.add_site_login(&mut command, settings, url)?;
+    
+    // Hide CMD window on Windows if requested
+    #[cfg(target_os = "windows")]
+    if hide_window {
+        use std::os::windows::process::CommandExt;
+        const CREATE_NO_WINDOW: u32 = 0x08000000;
+        command.creation_flags(CREATE_NO_WINDOW);
+    }
+    
+    let mut child = command
+        .spawn()
+        .map_err(|e| format!("Failed to execute {}: {}. Make sure {} is in the same folder or in PATH", program, e, program))?;
+    let job = jobs.register(job_id, child.id());
+    job.watch_for_stalls(StallDetector::new(settings));
+    job.supervise(ChildSupervisor::new(program, child.id(), settings, Instant::now()));
+    if settings.background_priority {
+        if let Err(error) = job.set_process_priority(ProcessPriority::Background) {
+            warn!(job_id, "{}", error);
+        }
+    }
+
+    let (tx, rx) = mpsc::channel::<(String, String)>();
+    let stdout_handle = child
+        .stdout
+        .take()
+        .map(|pipe| spawn_output_reader(pipe, "stdout", tx.clone()));
+    let stderr_handle = child
+        .stderr
+        .take()
+        .map(|pipe| spawn_output_reader(pipe, "stderr", tx));
+
+    let mut warnings = DownloadWarnings::default();
+    let mut sampler = SpeedSampler::new(downloader.backend());
+    let mut phases = PhaseTracker::new();
+    while let Some(output) = next_output_line(&rx, jobs, Some(&job)) {
+        let (stream, line) = match output {
+            JobOutput::Line(stream, line) => (stream, line),
+            JobOutput::Stalled(stall) => {
+                warn!(job_id, "{}", stall.describe());
+                jobs.events().publish(&NativeResponse::stalled(Some(job_id), &stall));
+                continue;
+            }
+        };
+        job.observe_output_line(&line);
+        sampler.observe(&line, Instant::now());
+        debug!(job_id, stream = %stream, "{}", line);
+        let warning = warnings.observe(&stream, &line);
+        let phase = phases.observe(&line);
+        if jobs.events().has_subscribers() {
+            jobs.events().publish(&NativeResponse::progress(Some(job_id), stream, line, phase));
+            if let Some(warning) = warning {
+                jobs.events().publish(&NativeResponse::warning(Some(job_id), warning));
+            }
+        }
+    }
+
+    let status = child.wait();
+    let end = jobs.finish(&job, status.as_ref().is_ok_and(|status| status.success()));
+    // Uploads and post-download steps no longer touch the site
+    drop(slot);
+    cleanup_temp_cookies_file(&cookies_path);
+    drop(netrc);
+
+    let status = status.map_err(|e| format!("Failed while waiting for {}: {}", program, e))?;
+    // Returned to the caller, so scrubbed like every frame
+    let stdout_text = redact::redact(&join_output_reader(stdout_handle)).into_owned();
+    let stderr_text = redact::redact(&join_output_reader(stderr_handle)).into_owned();
+
+    let stopped = match end {
+        JobEnd::Paused => Some(StopReason::Paused),
+        JobEnd::Interrupted => Some(StopReason::Interrupted),
+        JobEnd::Exited if !status.success() && lost_network(settings, &stderr_text) => Some(StopReason::Offline),
+        JobEnd::Exited | JobEnd::Cancelled | JobEnd::Stalled(_) | JobEnd::Supervised(_) => None,
+    };
+    if let Some(reason) = stopped {
+        return Err(stop_gui_download(jobs, context, started_at, &stdout_text, &stderr_text, reason));
+    }
+
+    let mut file_path = downloader.file_path(&stdout_text).map(console_text::on_disk);
+    let (status_label, message) = if end == JobEnd::Cancelled {
+        (DownloadStatus::Cancelled, "Download cancelled by user".to_string())
+    } else if let JobEnd::Stalled(stall) = end {
+        (DownloadStatus::Failed, stall.describe())
+    } else if let JobEnd::Supervised(kill) = end {
+        (DownloadStatus::Failed, kill.describe(program))
+    } else if status.success() && file_path.is_none() && profiles::skipped_by_archive(&stdout_text) {
+        (DownloadStatus::Completed, profiles::ARCHIVE_SKIP_MESSAGE.to_string())
+    } else if status.success() {
+        match media_policy::check_download(settings, file_path.as_deref(), &stdout_text) {
+            Ok(()) => (DownloadStatus::Completed, "Download complete".to_string()),
+            Err(rejected) => {
+                file_path = None;
+                (DownloadStatus::Failed, rejected.to_string())
+            }
+        }
+    } else {
+        let message = match last_error_line(&stderr_text) {
+            Some(line) => format!("{} failed with exit code {:?}: {}", program, status.code(), line),
+            None => format!("{} failed with exit code: {:?}", program, status.code()),
+        };
+        (DownloadStatus::Failed, message)
+    };
+    let failure = match status_label {
+        DownloadStatus::Completed => {
+            failure_details::log_success(job_id, &command);
+            None
+        }
+        // The GUI has no browser to hand a download to
+        DownloadStatus::Failed | DownloadStatus::Delegated => {
+            let failure = FailureDetails::new(&command, status, &stderr_text, settings);
+            failure.log(job_id);
+            Some(failure)
+        }
+        DownloadStatus::Cancelled => None,
+    };
+
+    let mut after = if status_label == DownloadStatus::Completed {
+        let mut on_upload_progress = upload_progress_reporter(Some(job_id), |frame| {
+            if jobs.events().has_subscribers() {
+                jobs.events().publish(frame);
+            }
+        });
+        // Before the stages, which may remove the whole video after its upload
+        let mut pieces = AfterDownload::default();
+        pieces.collect_chapters(settings, &stdout_text, file_path.as_deref());
+        let mut after = AfterDownload {
+            media_info: media_info::parse(&stdout_text),
+            stats: Some(JobStats::new(sampler.finish(started_at))),
+            chapters: pieces.chapters,
+            ..run_after_download_stages(
+                settings,
+                job_id,
+                url,
+                source_page,
+                &mut file_path,
+                upload,
+                &mut on_upload_progress,
+            )
+        };
+        after.warnings.splice(0..0, warnings.into_vec().into_iter().chain(pieces.warnings));
+        after
+    } else {
+        AfterDownload::default()
+    };
+
+    let finished_at = current_timestamp_millis();
+    let supervision = job.supervision_report();
+    let entry = NewHistoryEntry {
+        job_id,
+        url,
+        file_path: file_path.as_deref(),
+        status: status_label,
+        message: Some(&message),
+        source,
+        started_at,
+        finished_at,
+        object_key: after.uploaded.as_ref().map(|object| object.key.as_str()),
+        etag: after.uploaded.as_ref().map(|object| object.etag.as_str()),
+        output_path: Some(output_path),
+        upload,
+        page_url: source_page.page_url.as_deref(),
+        page_title: source_page.page_title.as_deref(),
+        media_info: after.media_info.as_ref(),
+        transfer: after.stats.as_ref().map(|stats| &stats.transfer),
+        session_id: None,
+        supervision: supervision.as_ref(),
+    };
+    match history.record(&entry) {
+        Ok(entry_id) => {
+            after.record_retries(history, entry_id);
+            after.record_chapters(history, entry_id);
+            if let (DownloadStatus::Completed, Some(file_path)) = (status_label, file_path.as_deref()) {
+                dedupe::deduplicate(history, settings, entry_id, file_path);
+            }
+            // The toast thread is detached; the GUI process outlives the coalescing window
+            let _ = notifications::notify_download_finished(
+                settings,
+                history,
+                entry_id,
+                status_label,
+                finished_at - started_at,
+            );
+            if matches!(end, JobEnd::Stalled(_)) {
+                let restarted = retry::restart_stalled(history, settings, entry_id, context);
+                match restarted {
+                    Ok(Some(download)) => announce_scheduled(jobs, &download),
+                    Ok(None) => {}
+                    Err(error) => warn!(job_id, "Failed to restart stalled download: {}", error),
+                }
+            }
+        }
+        Err(error) => warn!(job_id, "{}", error),
+    }
+
+    let (event, error_code) = match status_label {
+        DownloadStatus::Completed => ("completed", None),
+        DownloadStatus::Cancelled => ("cancelled", Some("cancelled")),
+        DownloadStatus::Failed | DownloadStatus::Delegated => ("failed", Some(classify_download_error(&message))),
+    };
+    metrics::download_finished(error_code, finished_at - started_at);
+    jobs.events().publish(&NativeResponse {
+        success: status_label == DownloadStatus::Completed,
+        message: Some(message.clone()),
+        file_path: file_path.clone(),
+        error_code: error_code.map(|code| code.to_string()),
+        data: after
+            .response_data()
+            .or_else(|| failure.as_ref().and_then(|failure| serde_json::to_value(failure).ok())),
+        ..NativeResponse::job_event(event, Some(job_id))
+    }
+    .localized(&i18n::resolve(None)));
+    // Detached like the toast; the GUI process keeps running while it retries
+    let _ = webhooks::notify_job_finished(
+        settings,
+        webhooks::FinishedJob {
+            job_id,
+            url,
+            file_path: file_path.as_deref(),
+            status: status_label,
+            error_code,
+        },
+    );
+
+    match status_label {
+        DownloadStatus::Completed => {
+            info!(job_id, "Download completed");
+            // No local path once the file only lives in the bucket
+            let file_path = file_path.or_else(|| after.uploaded.is_none().then(|| output_path.to_string()));
+            Ok(serde_json::json!({
+                "success": true,
+                "jobId": job_id,
+                "filePath": file_path,
+                "stdout": stdout_text,
+                "stderr": stderr_text,
+                "warnings": after.warnings,
+                "upload": after.uploaded,
+                "mediaInfo": after.media_info,
+                "stats": after.stats
+            }))
+        }
+        DownloadStatus::Cancelled => {
+            info!(job_id, "Download cancelled by user");
+            Err(serde_json::json!({
+                "cancelled": true,
+                "jobId": job_id,
+                "message": message,
+                "stdout": stdout_text,
+                "stderr": stderr_text
+            }).to_string())
+        }
+        DownloadStatus::Failed | DownloadStatus::Delegated => {
+            warn!(job_id, "{}", message);
+            Err(serde_json::json!({
+                "cancelled": false,
+                "jobId": job_id,
+                "message": message,
+                "stdout": stdout_text,
+                "stderr": stderr_text,
+                "failure": failure
+            }).to_string())
+        }
+    }
+}
+
+// Keeps a GUI download stopped or held back by the paused queue or by shutdown
+// for resume and returns the error test_download reports for it
+fn stop_gui_download(
+    jobs: &JobRegistry,
+    context: &DownloadContext,
+    queued_at: i64,
+    stdout_text: &str,
+    stderr_text: &str,
+    reason: StopReason,
+) -> String {
+    let job_id = context.job_id;
+    let message = match schedule::add_stopped(context, queued_at, reason) {
+        Ok(()) => reason.describe().to_string(),
+        Err(error) => {
+            warn!(job_id, "{}", error);
+            format!("Download {}, but it could not be kept for resume: {}", reason.event(), error)
+        }
+    };
+    jobs.events().publish(&NativeResponse {
+        message: Some(message.clone()),
+        ..NativeResponse::job_event(reason.event(), Some(job_id))
+    });
+    serde_json::json!({
+        "cancelled": false,
+        "paused": reason == StopReason::Paused,
+        "interrupted": reason == StopReason::Interrupted,
+        "offline": reason == StopReason::Offline,
+        "jobId": job_id,
+        "message": message,
+        "stdout": stdout_text,
+        "stderr": stderr_text
+    })
+    .to_string()
+}
+
+// Native counterpart of stop_gui_download. The GUI restarts the job, so its
+// later events arrive over the event stream.
+pub(crate) fn stop_native_download(context: &DownloadContext, queued_at: i64, reason: StopReason) -> NativeResponse {
+    let job_id = context.job_id;
+    match schedule::add_stopped(context, queued_at, reason) {
+        Ok(()) => {
+            // A running GUI continues an interrupted job right away; otherwise
+            // it waits for the next launch
+            if reason == StopReason::Interrupted {
+                let _ = instance::send_to_running_instance(&InstanceMessage::ScheduleChanged);
+            }
+            NativeResponse {
+                message: Some(reason.describe().to_string()),
+                ..NativeResponse::job_event(reason.event(), Some(job_id))
+            }
+        }
+        Err(error) => {
+            warn!(job_id, "{}", error);
+            NativeResponse {
+                success: false,
+                message: Some(format!("Download {}, but it could not be kept for resume: {}", reason.event(), error)),
+                ..NativeResponse::job_event("complete", Some(job_id))
+            }
+        }
+    }
+}
+
+// Answers a request that coalesced into an identical job already in flight:
+// acknowledges it as coalesced, waits for that job, then completes with its
+// outcome under its job id
+pub(crate) fn follow_coalesced_native(
+    jobs: &JobRegistry,
+    history: &History,
+    stdout: &FrameWriter,
+    request_id: Option<String>,
+    job_id: &str,
+    since: i64,
+    locale: &str,
+) -> NativeResponse {
+    let data = serde_json::json!({ "jobId": job_id, "coalesced": true });
+    let acknowledgement = NativeResponse {
+        message: Some(i18n::text(locale, "coalesced", &[("job_id", job_id)])),
+        data: Some(data.clone()),
+        ..NativeResponse::job_event("queued", request_id.as_deref())
+    };
+    if let Err(error) = send_native_response(stdout, &acknowledgement) {
+        warn!(job_id, "Failed to acknowledge coalesced request: {}", error);
+    }
+
+    let (success, message, file_path, error_code) = match coalesce::wait_for(jobs, history, job_id, since) {
+        Ok(Some(entry)) => {
+            let message = entry.message.unwrap_or_default();
+            let error_code = match entry.status.as_str() {
+                "completed" => None,
+                "cancelled" => Some("cancelled"),
+                _ => Some(classify_download_error(&message)),
+            };
+            (error_code.is_none(), message, entry.file_path, error_code)
+        }
+        Ok(None) => (false, coalesced_without_result(job_id), None, None),
+        Err(error) => (false, error, None, None),
+    };
+    NativeResponse {
+        success,
+        message: Some(message),
+        file_path,
+        error_code: error_code.map(str::to_string),
+        data: Some(data),
+        ..NativeResponse::job_event("complete", request_id.as_deref())
+    }
+}
+
+// What a native messaging port hands each download it starts
+pub(crate) struct NativePort<'a> {
+    pub stdout: &'a FrameWriter,
+    pub jobs: &'a JobRegistry,
+    pub origin: &'a ExtensionOrigin,
+}
+
+// Answers a "download" message: schedules it, keeps it for resume while the
+// queue is paused, or runs it now and reports how it went. The notification
+// threads of recorded outcomes are added to `pending_notifications`.
+pub(crate) fn handle_download(
+    message: NativeMessage,
+    settings: Settings,
+    history: &History,
+    port: &NativePort,
+    locale: &str,
+    pending_notifications: &mut Vec<JoinHandle<()>>,
+) -> NativeResponse {
+    let NativePort { stdout, jobs, origin } = *port;
+    let NativeMessage {
+        url,
+        output_path,
+        output_template,
+        cookies_data,
+        request_id,
+        upload,
+        schedule_at,
+        priority,
+        page_url,
+        page_title,
+        referer,
+        backend,
+        ignore_policy,
+        hints,
+        force_new,
+        split_chapters,
+        tag_origin,
+        user_agent,
+        browser_user_agent,
+        ..
+    } = message;
+    let settings = media_policy::for_request(settings, ignore_policy.unwrap_or(false));
+    let settings = chapters::for_request(settings, split_chapters);
+    let settings = origin_tag::for_request(settings, tag_origin);
+    let priority = priority.unwrap_or_default();
+    let source_page = match SourcePage::new(page_url, page_title, referer).with_user_agents(user_agent, browser_user_agent) {
+        Ok(source_page) => source_page,
+        Err(error) => {
+            warn!(request_id = request_id.as_deref().unwrap_or(""), "Rejected user agent: {}", error);
+            return NativeResponse::failure(request_id.as_deref(), Some("invalid_user_agent"), error);
+        }
+    };
+    // Rejected here, before the job is queued or scheduled
+    let output_path = match output_template::resolve(output_path, output_template.as_deref(), &settings) {
+        Ok(output_path) => Some(output_path),
+        Err(error) => {
+            warn!(request_id = request_id.as_deref().unwrap_or(""), "Rejected output template: {}", error);
+            return NativeResponse::failure(request_id.as_deref(), Some("invalid_output_template"), error);
+        }
+    };
+    // What the rules decide is kept under the job id, so a request without
+    // one gets it here
+    let request_id = request_id.or_else(|| Some(generate_job_id("native")));
+    let job_id = request_id.as_deref().unwrap_or("");
+    let (settings, output_path) = match (url.as_deref(), output_path) {
+        (Some(url), Some(output_path)) => match organize::organize_job(history, settings, job_id, url, &hints, &output_path) {
+            // Nobody is asked about an existing file here
+            Ok((settings, output_path)) => {
+                let (settings, output_path) = conflicts::settle(settings, url, output_path, backend);
+                (settings, Some(output_path))
+            }
+            Err(message) => return NativeResponse::failure(request_id.as_deref(), Some("rule_skipped"), message),
+        },
+        (_, output_path) => (settings, output_path),
+    };
+    let (Some(url), Some(output_path)) = (url, output_path) else {
+        warn!("Download request is missing url");
+        return NativeResponse::failure(request_id.as_deref(), Some("missing_url"), "Missing url".to_string());
+    };
+    if let Err(blocked) = domain_policy::check(&settings, &url) {
+        warn!(request_id = job_id, "Rejected download: {}", blocked);
+        return NativeResponse {
+            data: serde_json::to_value(&blocked).ok(),
+            ..NativeResponse::failure(request_id.as_deref(), Some("domain_blocked"), blocked.to_string())
+        };
+    }
+    if let Err(error) = validate_download_url(&url) {
+        warn!(request_id = job_id, "Rejected download: {}", error);
+        return NativeResponse::failure(request_id.as_deref(), Some("invalid_url"), error);
+    }
+    // Its url is the link asked for, which a refresh below may replace
+    let mut context = DownloadContext {
+        job_id,
+        url: &url,
+        output_path: &output_path,
+        upload: upload.unwrap_or(true),
+        priority,
+        source_page: &source_page,
+        source: origin.history_source(),
+    };
+
+    if let Some(schedule_at) = schedule_at.as_deref() {
+        // The GUI's timer runs it; this port only gets the acknowledgement
+        if cookies_data.is_some() {
+            debug!(job_id, "Browser cookies are not kept for scheduled downloads");
+        }
+        return match schedule::add(&context, schedule_at) {
+            Ok(download) => {
+                schedule::notify_gui();
+                NativeResponse::scheduled(&download, locale)
+            }
+            Err(error) => {
+                warn!(job_id, "Failed to schedule download: {}", error);
+                NativeResponse::failure(request_id.as_deref(), None, error)
+            }
+        };
+    }
+    if jobs.is_paused() {
+        info!(request_id = job_id, "Queue is paused, keeping the download for resume");
+        return stop_native_download(&context, current_timestamp_millis(), StopReason::Paused);
+    }
+
+    info!(
+        request_id = job_id,
+        url = logging::loggable_url(&url),
+        output_path = %output_path,
+        "Processing download"
+    );
+    // Held until the outcome is recorded below
+    let _claim = match coalesce::claim(job_id, &url, &output_path, force_new.unwrap_or(false)) {
+        Ok(Claim::Owner(guard)) => Some(guard),
+        Ok(Claim::Attached { job_id: existing, since }) => {
+            return follow_coalesced_native(jobs, history, stdout, request_id.clone(), &existing, since, locale);
+        }
+        Err(error) => {
+            warn!(job_id, "Not checking for duplicate requests: {}", error);
+            None
+        }
+    };
+    // The port itself hears about the job from its progress frames
+    jobs.events().track_job(job_id, &url);
+    jobs.events().publish(&NativeResponse {
+        message: Some(logging::loggable_url(&url).to_string()),
+        ..NativeResponse::job_event("queued", Some(job_id))
+    });
+    let _in_flight = jobs.track();
+    let mut started_at = current_timestamp_millis();
+    let mut result = download_video_with_progress(&context, backend, cookies_data.as_deref(), jobs, &settings, stdout);
+    // A signed link that expired while the job waited: yt-dlp gets the page
+    // once to find a fresh one. The failure is recorded first, so the retry is
+    // the job's next attempt.
+    let mut recorded = false;
+    let mut refreshed_from_page = false;
+    let refresh_page = match &result {
+        Err(outcome) if !outcome.cancelled && outcome.stopped.is_none() => {
+            page_refresh::page_for(&settings, &url, &source_page, &outcome.message)
+        }
+        _ => None,
+    };
+    if let Some(page_url) = &refresh_page {
+        let (entry_id, pending) =
+            record_native_download(history, &settings, &context, &result, &mut AfterDownload::default(), started_at);
+        pending_notifications.extend(pending);
+        recorded = true;
+        match entry_id.map(|entry_id| page_refresh::claim(history, &settings, entry_id)) {
+            Some(Ok(true)) => {
+                info!(
+                    request_id = job_id,
+                    page_url = logging::loggable_url(page_url),
+                    "Download link looks expired; retrying from its page"
+                );
+                context.url = page_url;
+                started_at = current_timestamp_millis();
+                result = download_video_with_progress(
+                    &context,
+                    Some(Backend::YtDlp),
+                    cookies_data.as_deref(),
+                    jobs,
+                    &settings,
+                    stdout,
+                );
+                recorded = false;
+                refreshed_from_page = true;
+            }
+            Some(Err(error)) => warn!(request_id = job_id, "Not refreshing the expired link: {}", error),
+            _ => {}
+        }
+    }
+    // A link every extractor declines, e.g. DRM or a login only the browser
+    // has, is fetched directly once and then left to the extension to
+    // download itself
+    let declined = |result: &DownloadResult| {
+        result
+            .as_ref()
+            .is_err_and(|outcome| !outcome.cancelled && outcome.stopped.is_none() && browser_fallback::declined(&outcome.message))
+    };
+    if declined(&result) {
+        if let Some(next) = browser_fallback::next_backend(&url, &settings, backend) {
+            info!(request_id = job_id, backend = next.name(), "Download declined; trying the link directly");
+            context.url = &url;
+            result = download_video_with_progress(&context, Some(next), cookies_data.as_deref(), jobs, &settings, stdout);
+            recorded = false;
+        }
+    }
+    let mut delegated = None;
+    if declined(&result) {
+        if let Err(outcome) = &mut result {
+            outcome.message = format!("{}{}", browser_fallback::DELEGATED_PREFIX, outcome.message);
+            delegated = Some(browser_fallback::for_url(&url, &source_page));
+            // Its own entry after a failure the refresh recorded
+            recorded = false;
+        }
+    }
+    let mut after = match &mut result {
+        Ok(outcome) => {
+            let mut on_upload_progress = upload_progress_reporter(Some(job_id), |frame| {
+                let _ = send_job_frame(stdout, jobs, frame);
+            });
+            // Before the stages, which may remove the whole video after its upload
+            let mut pieces = AfterDownload::default();
+            pieces.collect_chapters(&settings, &outcome.stdout, outcome.file_path.as_deref());
+            let mut after = AfterDownload {
+                media_info: media_info::parse(&outcome.stdout),
+                stats: outcome.stats.take().map(JobStats::new),
+                chapters: pieces.chapters,
+                ..run_after_download_stages(
+                    &settings,
+                    job_id,
+                    context.url,
+                    &source_page,
+                    &mut outcome.file_path,
+                    context.upload,
+                    &mut on_upload_progress,
+                )
+            };
+            // yt-dlp's warnings come before those of the later stages
+            let warnings = std::mem::take(&mut outcome.warnings);
+            after.warnings.splice(0..0, warnings.into_iter().chain(pieces.warnings));
+            after
+        }
+        Err(_) => AfterDownload::default(),
+    };
+    // A paused or interrupted job is recorded when it finally finishes
+    if !recorded && !result.as_ref().is_err_and(|outcome| outcome.stopped.is_some()) {
+        let (_, pending) = record_native_download(history, &settings, &context, &result, &mut after, started_at);
+        pending_notifications.extend(pending);
+    }
+    let mut response = match result.map_err(|outcome| *outcome) {
+        Ok(outcome) => {
+            info!(
+                request_id = job_id,
+                file_path = outcome.file_path.as_deref().unwrap_or(""),
+                "Download successful"
+            );
+            NativeResponse {
+                message: Some(outcome.message),
+                file_path: outcome.file_path,
+                stdout: Some(outcome.stdout),
+                stderr: Some(outcome.stderr),
+                data: after.response_data(),
+                ..NativeResponse::job_event("complete", Some(job_id))
+            }
+        }
+        Err(DownloadOutcome { stopped: Some(reason), .. }) => stop_native_download(&context, started_at, reason),
+        Err(outcome) => {
+            warn!(request_id = job_id, "Download failed: {}", outcome.message);
+            let error_code = if outcome.cancelled { "cancelled" } else { classify_download_error(&outcome.message) };
+            NativeResponse {
+                stdout: Some(outcome.stdout),
+                stderr: Some(outcome.stderr),
+                data: outcome.failure.as_ref().and_then(|failure| serde_json::to_value(failure).ok()),
+                ..NativeResponse::failure(Some(job_id), Some(error_code), outcome.message)
+            }
+        }
+    };
+    if refreshed_from_page {
+        page_refresh::mark(&mut response.data);
+    }
+    if let Some(fallback) = &delegated {
+        browser_fallback::describe(fallback, &mut response.data);
+    }
+    jobs.events().publish(&response);
+    response
+}
+
+// The result test_download gives a request that coalesced into `job_id`,
+// shaped like the one the job's own request got
+pub(crate) fn coalesced_gui_result(job_id: &str, entry: Option<HistoryEntry>) -> Result<serde_json::Value, String> {
+    let Some(entry) = entry else {
+        return Err(serde_json::json!({
+            "cancelled": false,
+            "jobId": job_id,
+            "message": coalesced_without_result(job_id),
+            "coalesced": true
+        })
+        .to_string());
+    };
+    if entry.status == DownloadStatus::Completed.as_str() {
+        Ok(serde_json::json!({
+            "success": true,
+            "jobId": job_id,
+            "filePath": entry.file_path,
+            "mediaInfo": entry.media_info,
+            "coalesced": true
+        }))
+    } else {
+        Err(serde_json::json!({
+            "cancelled": entry.status == DownloadStatus::Cancelled.as_str(),
+            "jobId": job_id,
+            "message": entry.message,
+            "coalesced": true
+        })
+        .to_string())
+    }
+}
+
+fn coalesced_without_result(job_id: &str) -> String {
+    format!("Download {} stopped without a result; a paused download continues when the queue is resumed", job_id)
+}
+
+// What the stages after a successful yt-dlp run produced
+#[derive(Default)]
+pub struct AfterDownload {
+    pub(crate) uploaded: Option<s3::UploadedObject>,
+    // Reported in the response, yt-dlp's own first; none of the stages can
+    // fail the download
+    pub(crate) warnings: Vec<String>,
+    // What yt-dlp printed about the media, read from its stdout
+    pub(crate) media_info: Option<MediaInfo>,
+    pub(crate) stats: Option<JobStats>,
+    // The pieces of a chapter-split download, each recorded under the entry
+    // of the whole video
+    pub(crate) chapters: Vec<ChapterFile>,
+}
+
+impl AfterDownload {
+    pub(crate) fn response_data(&self) -> Option<serde_json::Value> {
+        if self.uploaded.is_none()
+            && self.warnings.is_empty()
+            && self.media_info.is_none()
+            && self.stats.is_none()
+            && self.chapters.is_empty()
+        {
+            return None;
+        }
+        Some(serde_json::json!({
+            "warnings": self.warnings,
+            "upload": self.uploaded,
+            "mediaInfo": self.media_info,
+            "stats": self.stats,
+            "chapters": self.chapters,
+            "filePaths": self.chapters.iter().map(|chapter| &chapter.file_path).collect::<Vec<_>>(),
+        }))
+    }
+
+    // Split pieces found after the download; a video without chapters stays
+    // a single file with a warning
+    pub(crate) fn collect_chapters(&mut self, settings: &Settings, stdout_text: &str, file_path: Option<&str>) {
+        match chapters::collect(settings, stdout_text, file_path) {
+            Ok(chapters) => self.chapters = chapters,
+            Err(warning) => self.warnings.push(warning),
+        }
+    }
+
+    pub(crate) fn record_chapters(&self, history: &History, entry_id: i64) {
+        if self.chapters.is_empty() {
+            return;
+        }
+        if let Err(error) = history.record_chapters(entry_id, &self.chapters) {
+            warn!("{}", error);
+        }
+    }
+
+    // History numbers the attempts of a job, so the retry count is only
+    // known once the entry is recorded
+    pub(crate) fn record_retries(&mut self, history: &History, entry_id: i64) {
+        if let Some(stats) = &mut self.stats {
+            match history.attempt(entry_id) {
+                Ok(attempt) => stats.retries = attempt,
+                Err(error) => warn!("{}", error),
+            }
+        }
+    }
+}
+
+// Mark the file with its origin when the settings ask for it, upload to S3
+// when configured and not turned off for this request, run the post-download
+// command, then drop the local copy if it is safely uploaded and the settings
+// ask for that. Clears `file_path` when the file is removed.
+pub(crate) fn run_after_download_stages(
+    settings: &Settings,
+    job_id: &str,
+    url: &str,
+    source_page: &SourcePage,
+    file_path: &mut Option<String>,
+    upload: bool,
+    on_upload_progress: &mut dyn FnMut(u64, u64),
+) -> AfterDownload {
+    let mut after = AfterDownload::default();
+
+    if let Some(path) = file_path.as_deref() {
+        let page_url = source_page.page_url.as_deref().or(source_page.referer.as_deref());
+        after.warnings.extend(origin_tag::tag(settings, job_id, path, url, page_url));
+    }
+
+    if let Some(config) = settings.s3_upload.as_ref().filter(|_| upload) {
+        match file_path.as_deref() {
+            Some(path) => match s3::upload_file(config, job_id, path, on_upload_progress) {
+                Ok(object) => after.uploaded = Some(object),
+                Err(error) => {
+                    warn!(job_id, "Upload failed: {}", error);
+                    after.warnings.push(format!("Upload failed: {}", error));
+                }
+            },
+            None => after
+                .warnings
+                .push("Upload skipped: the downloaded file path is unknown".to_string()),
+        }
+    }
+
+    after
+        .warnings
+        .extend(post_download::run_after_download(settings, job_id, url, file_path.as_deref()));
+
+    let delete_local = settings
+        .s3_upload
+        .as_ref()
+        .is_some_and(|config| config.delete_local_after_upload);
+    if let (true, Some(_), Some(path)) = (delete_local, &after.uploaded, file_path.clone()) {
+        match fs::remove_file(&path) {
+            Ok(()) => {
+                info!(job_id, "Removed local copy after upload");
+                vault_events::record(vault_events::VaultChange::Deleted { path });
+                *file_path = None;
+            }
+            Err(error) => {
+                warn!(job_id, "Failed to remove local copy after upload: {}", error);
+                after
+                    .warnings
+                    .push(format!("Uploaded, but the local copy could not be removed: {}", error));
+            }
+        }
+    }
+
+    after
+}
+
+// Upload progress as job frames, at most one per whole percent
+pub(crate) fn upload_progress_reporter<'a>(
+    request_id: Option<&'a str>,
+    mut emit: impl FnMut(&NativeResponse) + 'a,
+) -> impl FnMut(u64, u64) + 'a {
+    let mut last_percent = None;
+    move |uploaded_bytes, total_bytes| {
+        let percent = (uploaded_bytes * 100).checked_div(total_bytes).unwrap_or(100);
+        if last_percent != Some(percent) {
+            last_percent = Some(percent);
+            emit(&NativeResponse::upload_progress(request_id, uploaded_bytes, total_bytes));
+        }
+    }
+}
+
+pub(crate) fn last_error_line(stderr_text: &str) -> Option<&str> {
+    stderr_text
+        .lines()
+        .rev()
+        .map(str::trim)
+        .find(|line| line.starts_with("ERROR:"))
+}
+
+// Short, stable code for a failed download, shown in notifications
+pub(crate) fn classify_download_error(message: &str) -> &'static str {
+    if message.starts_with(browser_fallback::DELEGATED_PREFIX) {
+        return "fallback_to_browser";
+    }
+    if message.starts_with(domain_policy::BLOCKED_PREFIX) {
+        return "domain_blocked";
+    }
+    // The image fetch child reports it behind its own "failed with exit code"
+    if message.contains(media_policy::REJECTED_PREFIX) {
+        return "policy_rejected";
+    }
+    if message.starts_with(organize::SKIPPED_PREFIX) {
+        return "rule_skipped";
+    }
+    if message.starts_with(destination::UNAVAILABLE_PREFIX) {
+        return "destination_unavailable";
+    }
+    if message.starts_with(tool_integrity::TAMPERED_PREFIX) {
+        return "tampered_dependency";
+    }
+    if message.contains(folder_protection::BLOCKED_PREFIX) || folder_protection::mentions_blocked_write(message) {
+        return "controlled_folder_access";
+    }
+    let message = message.to_lowercase();
+    if message.contains("failed to execute yt-dlp") || message.contains("yt-dlp not found") {
+        "ytdlp_missing"
+    } else if message.contains("failed to execute gallery-dl") {
+        "gallery_dl_missing"
+    } else if message.contains("http error 403") {
+        "http_403"
+    } else if message.contains("http error 404") {
+        "http_404"
+    } else if message.contains("http error 429") {
+        "rate_limited"
+    } else if message.starts_with("download stalled") {
+        "stalled"
+    } else if message.starts_with("stopped by supervision") && message.contains("printed nothing") {
+        "unresponsive"
+    } else if message.starts_with("stopped by supervision") {
+        "memory_limit"
+    } else if message.contains("unsupported url") {
+        "unsupported_url"
+    } else if message.contains("sign in") || message.contains("login required") || message.contains("cookies") {
+        "auth_required"
+    } else if message.contains("network path was not found") || message.contains("network name is no longer available") {
+        // Windows' words for a share that went away while yt-dlp was writing
+        "destination_unavailable"
+    } else if message.contains("timed out")
+        || message.contains("connection")
+        || message.contains("getaddrinfo failed")
+        || message.contains("name or service not known")
+        || message.contains("temporary failure in name resolution")
+        || message.contains("nodename nor servname")
+    {
+        "network_error"
+    } else {
+        "download_failed"
+    }
+}
+
+// A download that could not connect while the network is down waits for it
+// to come back instead of failing
+fn lost_network(settings: &Settings, stderr_text: &str) -> bool {
+    classify_download_error(last_error_line(stderr_text).unwrap_or_default()) == "network_error"
+        && connectivity::offline_now(settings)
+}
+
+// Forward each line a child writes to the channel and return the full text once the pipe closes
+pub(crate) fn spawn_output_reader<R: Read + Send + 'static>(
+    pipe: R,
+    stream: &'static str,
+    tx: mpsc::Sender<(String, String)>,
+) -> JoinHandle<String> {
+    std::thread::spawn(move || {
+        let mut collected = Vec::new();
+        let mut reader = BufReader::new(pipe);
+        let mut buffer = Vec::new();
+
+        loop {
+            buffer.clear();
+            match reader.read_until(b'\n', &mut buffer) {
+                Ok(0) => break,
+                Ok(_) => {
+                    let line = console_text::decode(&buffer)
+                        .trim_end_matches(['\r', '\n'])
+                        .to_string();
+                    if !line.trim().is_empty() {
+                        let _ = tx.send((stream.to_string(), line.clone()));
+                    }
+                    collected.push(line);
+                }
+                Err(error) => {
+                    collected.push(format!("[ImgVault] Failed to read yt-dlp {}: {}", stream, error));
+                    break;
+                }
+            }
+        }
+
+        collected.join("\n")
+    })
+}
+
+enum JobOutput {
+    // Stream name and line
+    Line(String, String),
+    Stalled(Stall),
+}
+
+// Next line from a job's yt-dlp, checking for a queue pause and a stall
+// between lines and while yt-dlp is quiet. None once both pipes have closed.
+fn next_output_line(
+    rx: &mpsc::Receiver<(String, String)>,
+    jobs: &JobRegistry,
+    job: Option<&JobHandle>,
+) -> Option<JobOutput> {
+    loop {
+        if let Some(job) = job {
+            jobs.suspend_if_paused(job);
+            jobs.check_supervisor(job);
+            if let Some(stall) = jobs.check_stall(job) {
+                return Some(JobOutput::Stalled(stall));
+            }
+        }
+        match rx.recv_timeout(PAUSE_CHECK_INTERVAL) {
+            Ok((stream, line)) => return Some(JobOutput::Line(stream, line)),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+        }
+    }
+}
+
+fn join_output_reader(handle: Option<JoinHandle<String>>) -> String {
+    handle
+        .and_then(|handle| handle.join().ok())
+        .unwrap_or_default()
+}
+
+pub(crate) fn find_yt_dlp(settings: &Settings) -> Result<String, String> {
+    tool_integrity::verify(settings.yt_dlp_program())?;
+    let mut command = Command::new(settings.yt_dlp_program());
+    command.arg("--version");
+
+    #[cfg(target_os = "windows")]
+    {
+        use std::os::windows::process::CommandExt;
+        const CREATE_NO_WINDOW: u32 = 0x08000000;
+        command.creation_flags(CREATE_NO_WINDOW);
+    }
+
+    let output = command
+        .output()
+        .map_err(|e| format!("Failed to execute yt-dlp: {}", e))?;
+
+    if output.status.success() {
+        let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        Ok(if version.is_empty() {
+            "yt-dlp is available".to_string()
+        } else {
+            format!("yt-dlp version: {}", version)
+        })
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        Err(if stderr.is_empty() {
+            format!("yt-dlp returned exit code {:?}", output.status.code())
+        } else {
+            stderr
+        })
+    }
+}
+
+pub(crate) fn download_video_with_progress(
+    context: &DownloadContext,
+    backend: Option<Backend>,
+    cookies_data: Option<&[BrowserCookie]>,
+    jobs: &JobRegistry,
+    settings: &Settings,
+    stdout: &FrameWriter,
+) -> DownloadResult {
+    let downloader = route(context.url, settings, backend);
+    download_with(downloader.as_ref(), context, cookies_data, jobs, settings, stdout)
+}
+
+// The queue's part of a download, the same whichever backend runs it
+fn download_with(
+    downloader: &dyn Downloader,
+    context: &DownloadContext,
+    cookies_data: Option<&[BrowserCookie]>,
+    jobs: &JobRegistry,
+    settings: &Settings,
+    stdout: &FrameWriter,
+) -> DownloadResult {
+    let DownloadContext { job_id, url, output_path, source_page, .. } = *context;
+    domain_policy::check(settings, url).map_err(|blocked| DownloadOutcome::failure(blocked.to_string()))?;
+    let queued_at = current_timestamp_millis();
+    let output_path = &source_page.apply_to_output_path(output_path);
+    let output_dir = get_output_directory(output_path)
+        .map_err(DownloadOutcome::failure)?;
+
+    let merge_heavy =
+        resource_pressure::is_merge_heavy(downloader.backend(), settings.default_quality.format_selector(), settings);
+    let admission = dispatcher::acquire(jobs, settings, context, Some(&output_dir), merge_heavy, &mut |reason| {
+        let waiting = NativeResponse {
+            message: Some(reason.describe().to_string()),
+            data: Some(serde_json::json!({ "reason": reason })),
+            ..NativeResponse::job_event("waiting", Some(job_id))
+        };
+        if let Err(error) = send_job_frame(stdout, jobs, &waiting) {
+            warn!(job_id, "Failed to send waiting update: {}", error);
+        }
+    })
+    .map_err(DownloadOutcome::failure)?;
+    let slot = match admission {
+        Admission::Granted(slot) => slot,
+        Admission::Paused => {
+            return Err(Box::new(DownloadOutcome {
+                stopped: Some(StopReason::Paused),
+                ..DownloadOutcome::failure("Download paused".to_string())
+            }))
+        }
+        Admission::Interrupted => {
+            return Err(Box::new(DownloadOutcome {
+                stopped: Some(StopReason::Interrupted),
+                ..DownloadOutcome::failure("Download interrupted by shutdown".to_string())
+            }))
+        }
+        Admission::Cancelled => {
+            return Err(Box::new(DownloadOutcome {
+                cancelled: true,
+                ..DownloadOutcome::failure("Download cancelled by user".to_string())
+            }))
+        }
+    };
+    metrics::download_started();
+
+    media_policy::check_backend(settings, downloader.backend())
+        .map_err(|rejected| DownloadOutcome::failure(rejected.to_string()))?;
+    let program = downloader.program();
+    let mut command = downloader
+        .command(&DownloadOptions {
+            job_id,
+            url,
+            output_path,
+            format_selector: settings.default_quality.format_selector(),
+            settings,
+            source_page,
+            verbose: true,
+        })
+        .map_err(DownloadOutcome::failure)?;
+
+    let cookies_path = add_cookies_argument(&mut command, cookies_data)
+        .map_err(DownloadOutcome::failure)?;
+    let netrc = match downloader.add_site_login(&mut command, settings, url) {
+        Ok(netrc) => netrc,
+        Err(error) => {
+            cleanup_temp_cookies_file(&cookies_path);
+            return Err(Box::new(DownloadOutcome::failure(error)));
+        }
+    };
+
+    #[cfg(target_os = "windows")]
+    {
+        use std::os::windows::process::CommandExt;
+        const CREATE_NO_WINDOW: u32 = 0x08000000;
+        command.creation_flags(CREATE_NO_WINDOW);
+    }
+
+    // Spawned on the calling runtime, which reads its pipes concurrently
+    let runtime = child_io::runtime().map_err(DownloadOutcome::failure)?;
+    let mut command = tokio::process::Command::from(command);
+    let child = {
+        let _context = runtime.enter();
+        command.spawn()
+    };
+    let child = child.map_err(|e| DownloadOutcome {
+        message: match &cookies_path {
+            Some(path) => format!("Failed to execute {}: {} (cookies: {})", program, e, path.display()),
+            None => format!("Failed to execute {}: {}", program, e),
+        },
+        file_path: None,
+        stdout: String::new(),
+        stderr: String::new(),
+        cancelled: false,
+        stopped: None,
+        failure: None,
+        warnings: Vec::new(),
+        stats: None,
+        supervision: None,
+    })?;
+
+    // Only None once the child has been waited for
+    let pid = child.id().unwrap_or_default();
+    let job = jobs.register(job_id, pid);
+    job.watch_for_stalls(StallDetector::new(settings));
+    job.supervise(ChildSupervisor::new(program, pid, settings, Instant::now()));
+    if settings.background_priority {
+        if let Err(error) = job.set_process_priority(ProcessPriority::Background) {
+            warn!(job_id, "{}", error);
+        }
+    }
+
+    let (event_tx, mut event_rx) = tokio::sync::mpsc::channel(child_io::CHILD_EVENTS);
+    let exit = runtime.spawn(child_io::supervise(child, jobs.clone(), Some(job.clone()), event_tx));
+
+    let mut warnings = DownloadWarnings::default();
+    let mut sampler = SpeedSampler::new(downloader.backend());
+    let mut phases = PhaseTracker::new();
+    while let Some(event) = event_rx.blocking_recv() {
+        let (stream, line) = match event {
+            ChildEvent::Line(stream, line) => (stream.to_string(), line),
+            ChildEvent::Stalled(stall) => {
+                warn!(job_id, "{}", stall.describe());
+                if let Err(error) = send_job_frame(stdout, jobs, &NativeResponse::stalled(Some(job_id), &stall)) {
+                    warn!(job_id, "Failed to send stall update: {}", error);
+                }
+                continue;
+            }
+        };
+        job.observe_output_line(&line);
+        sampler.observe(&line, Instant::now());
+        debug!(job_id, stream = %stream, "{}", line);
+
+        let warning = warnings.observe(&stream, &line);
+        let phase = phases.observe(&line);
+        let progress_response = NativeResponse::progress(Some(job_id), stream, line, phase);
+        if let Err(error) = send_job_frame(stdout, jobs, &progress_response) {
+            warn!(job_id, "Failed to send progress update: {}", error);
+        }
+        if let Some(warning) = warning {
+            if let Err(error) = send_job_frame(stdout, jobs, &NativeResponse::warning(Some(job_id), warning)) {
+                warn!(job_id, "Failed to send warning: {}", error);
+            }
+        }
+    }
+
+    let exit = runtime
+        .block_on(exit)
+        .map_err(|e| DownloadOutcome::failure(format!("Failed while reading {} output: {}", program, e)))?;
+    let status = exit
+        .status
+        .map_err(|e| DownloadOutcome::failure(format!("Failed while waiting for {}: {}", program, e)))?;
+
+    let end = jobs.finish(&job, status.success());
+    let supervision = job.supervision_report();
+    // Also frees the slot before the retry below asks for a new one
+    drop(slot);
+
+    cleanup_temp_cookies_file(&cookies_path);
+    drop(netrc);
+
+    // Returned to the caller and kept in history, so scrubbed like every frame
+    let stdout_text = redact::redact(&exit.stdout).into_owned();
+    let stderr_text = redact::redact(&exit.stderr).into_owned();
+
+    let stopped = match end {
+        JobEnd::Paused => Some(StopReason::Paused),
+        JobEnd::Interrupted => Some(StopReason::Interrupted),
+        JobEnd::Exited if !status.success() && lost_network(settings, &stderr_text) => Some(StopReason::Offline),
+        JobEnd::Exited | JobEnd::Cancelled | JobEnd::Stalled(_) | JobEnd::Supervised(_) => None,
+    };
+    if let Some(reason) = stopped {
+        return Err(Box::new(DownloadOutcome {
+            message: reason.describe().to_string(),
+            file_path: None,
+            stdout: stdout_text,
+            stderr: stderr_text,
+            cancelled: false,
+            stopped: Some(reason),
+            failure: None,
+            warnings: Vec::new(),
+            stats: None,
+            supervision: supervision.clone(),
+        }));
+    }
+    if let JobEnd::Stalled(stall) = end {
+        return Err(Box::new(DownloadOutcome {
+            message: stall.describe(),
+            file_path: None,
+            stdout: stdout_text,
+            stderr: stderr_text,
+            cancelled: false,
+            stopped: None,
+            failure: None,
+            warnings: Vec::new(),
+            stats: None,
+            supervision: supervision.clone(),
+        }));
+    }
+    if let JobEnd::Supervised(kill) = end {
+        return Err(Box::new(DownloadOutcome {
+            supervision,
+            ..DownloadOutcome::failure(kill.describe(program))
+        }));
+    }
+    if end == JobEnd::Cancelled {
+        return Err(Box::new(DownloadOutcome {
+            message: "Download cancelled by user".to_string(),
+            file_path: None,
+            stdout: stdout_text,
+            stderr: stderr_text,
+            cancelled: true,
+            stopped: None,
+            failure: None,
+            warnings: Vec::new(),
+            stats: None,
+            supervision: supervision.clone(),
+        }));
+    }
+
+    let file_path = downloader.file_path(&stdout_text).map(console_text::on_disk);
+
+    if status.success() {
+        failure_details::log_success(job_id, command.as_std());
+        if let Err(rejected) = media_policy::check_download(settings, file_path.as_deref(), &stdout_text) {
+            return Err(Box::new(DownloadOutcome {
+                stdout: stdout_text,
+                stderr: stderr_text,
+                supervision,
+                ..DownloadOutcome::failure(rejected.to_string())
+            }));
+        }
+        if let Some(file_path) = file_path {
+            Ok(DownloadOutcome {
+                message: "Download complete".to_string(),
+                file_path: Some(file_path),
+                stdout: stdout_text,
+                stderr: stderr_text,
+                cancelled: false,
+                stopped: None,
+                failure: None,
+                warnings: warnings.into_vec(),
+                stats: Some(sampler.finish(queued_at)),
+                supervision: supervision.clone(),
+            })
+        } else {
+            let message = if profiles::skipped_by_archive(&stdout_text) {
+                profiles::ARCHIVE_SKIP_MESSAGE.to_string()
+            } else {
+                format!("{} did not return a file path", program)
+            };
+            Err(Box::new(DownloadOutcome {
+                message,
+                file_path: None,
+                stdout: stdout_text,
+                stderr: stderr_text,
+                cancelled: false,
+                stopped: None,
+                failure: None,
+                warnings: Vec::new(),
+                stats: None,
+                supervision: supervision.clone(),
+            }))
+        }
+    } else {
+        let combined = [stderr_text.trim().to_string(), stdout_text.trim().to_string()]
+            .into_iter()
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let lower_combined = combined.to_lowercase();
+        let should_retry_with_short_title =
+            downloader.backend() == Backend::YtDlp &&
+            output_path.contains("%(title)s") &&
+            !output_path.contains("%(title).180B") &&
+            (lower_combined.contains("unable to open for writing") ||
+                lower_combined.contains("no such file or directory"));
+
+        if should_retry_with_short_title {
+            let fallback_output_path = output_path.replace("%(title)s", "%(title).180B");
+            let notice = NativeResponse {
+                success: true,
+                event: Some("progress".to_string()),
+                request_id: Some(job_id.to_string()),
+                message: None,
+                line: Some(format!(
+                    "[ImgVault] Retrying with shortened title template: {}",
+                    fallback_output_path
+                )),
+                stream: Some("stderr".to_string()),
+                file_path: None,
+                stdout: None,
+                stderr: None,
+                error_code: None,
+                data: None,
+                detail: None,
+            };
+
+            if let Err(error) = send_job_frame(stdout, jobs, &notice) {
+                warn!(job_id, "Failed to send retry notice: {}", error);
+            }
+
+            let context = DownloadContext {
+                output_path: &fallback_output_path,
+                ..*context
+            };
+            return download_with(downloader, &context, cookies_data, jobs, settings, stdout);
+        }
+
+        let failure = FailureDetails::new(command.as_std(), status, &stderr_text, settings);
+        failure.log(job_id);
+        Err(Box::new(DownloadOutcome {
+            message: if combined.is_empty() {
+                format!("{} failed with exit code {:?}", program, status.code())
+            } else {
+                format!("{} failed:\n{}", program, combined)
+            },
+            file_path: None,
+            stdout: stdout_text,
+            stderr: stderr_text,
+            cancelled: false,
+            stopped: None,
+            failure: Some(Box::new(failure)),
+            warnings: Vec::new(),
+            stats: None,
+            supervision: supervision.clone(),
+        }))
+    }
+}
+
+pub(crate) fn record_native_download(
+    history: &History,
+    settings: &Settings,
+    context: &DownloadContext,
+    result: &DownloadResult,
+    after: &mut AfterDownload,
+    started_at: i64,
+) -> (Option<i64>, Vec<JoinHandle<()>>) {
+    let DownloadContext { job_id, url, output_path, upload, source_page, source, .. } = *context;
+    let (status, outcome) = match result.as_ref().map_err(|outcome| &**outcome) {
+        Ok(outcome) => (DownloadStatus::Completed, outcome),
+        Err(outcome) if outcome.cancelled => (DownloadStatus::Cancelled, outcome),
+        Err(outcome) if outcome.message.starts_with(browser_fallback::DELEGATED_PREFIX) => {
+            (DownloadStatus::Delegated, outcome)
+        }
+        Err(outcome) => (DownloadStatus::Failed, outcome),
+    };
+
+    let finished_at = current_timestamp_millis();
+    let entry = NewHistoryEntry {
+        job_id,
+        url,
+        file_path: outcome.file_path.as_deref(),
+        status,
+        message: Some(&outcome.message),
+        source,
+        started_at,
+        finished_at,
+        object_key: after.uploaded.as_ref().map(|object| object.key.as_str()),
+        etag: after.uploaded.as_ref().map(|object| object.etag.as_str()),
+        output_path: Some(output_path),
+        upload,
+        page_url: source_page.page_url.as_deref(),
+        page_title: source_page.page_title.as_deref(),
+        media_info: after.media_info.as_ref(),
+        transfer: after.stats.as_ref().map(|stats| &stats.transfer),
+        session_id: logging::session_id(),
+        supervision: outcome.supervision.as_ref(),
+    };
+    let error_code = match status {
+        DownloadStatus::Completed => None,
+        DownloadStatus::Cancelled => Some("cancelled"),
+        DownloadStatus::Failed | DownloadStatus::Delegated => Some(classify_download_error(&outcome.message)),
+    };
+    metrics::download_finished(error_code, finished_at - started_at);
+    let mut pending = Vec::new();
+    let mut recorded = None;
+    match history.record(&entry) {
+        Ok(entry_id) => {
+            recorded = Some(entry_id);
+            after.record_retries(history, entry_id);
+            after.record_chapters(history, entry_id);
+            if let (DownloadStatus::Completed, Some(file_path)) = (status, outcome.file_path.as_deref()) {
+                dedupe::deduplicate(history, settings, entry_id, file_path);
+            }
+            pending.extend(notifications::notify_download_finished(
+                settings,
+                history,
+                entry_id,
+                status,
+                finished_at - started_at,
+            ));
+            // Only stall detection set to restart stops a job as stalled
+            if error_code == Some("stalled") {
+                let restarted = retry::restart_stalled(history, settings, entry_id, context);
+                match restarted {
+                    Ok(Some(_)) => schedule::notify_gui(),
+                    Ok(None) => {}
+                    Err(error) => warn!(job_id, "Failed to restart stalled download: {}", error),
+                }
+            }
+        }
+        Err(error) => warn!(job_id, "{}", error),
+    }
+
+    pending.extend(webhooks::notify_job_finished(
+        settings,
+        webhooks::FinishedJob {
+            job_id,
+            url,
+            file_path: outcome.file_path.as_deref(),
+            status,
+            error_code,
+        },
+    ));
+    (recorded, pending)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media_policy::MediaType;
+    use crate::native_stdout;
+    use crate::logging::RotatingFile;
+    use crate::site_login::SiteLogin;
+    use crate::supervisor::SupervisorKill;
+    use crate::test_support::{self, AppData};
+    use std::cell::RefCell;
+    use std::io;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+    use tokio::io::AsyncWrite;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    const REFUSAL: &str = "mock backend refused";
+
+    // Stands in for a backend without spawning anything: it records what the
+    // queue asks of it and builds no command
+    struct MockDownloader {
+        backend: Backend,
+        asked: RefCell<Vec<(String, String, bool)>>,
+    }
+
+    impl MockDownloader {
+        fn new(backend: Backend) -> Self {
+            MockDownloader {
+                backend,
+                asked: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl Downloader for MockDownloader {
+        fn backend(&self) -> Backend {
+            self.backend
+        }
+
+        fn program(&self) -> &str {
+            "mock"
+        }
+
+        fn probe(&self, _url: &str, _settings: &Settings) -> bool {
+            true
+        }
+
+        fn command(&self, options: &DownloadOptions) -> Result<Command, String> {
+            self.asked
+                .borrow_mut()
+                .push((options.job_id.to_string(), options.output_path.to_string(), options.verbose));
+            Err(REFUSAL.to_string())
+        }
+    }
+
+    fn download(
+        downloader: &dyn Downloader,
+        url: &str,
+        jobs: &JobRegistry,
+        settings: &Settings,
+        app_data: &AppData,
+    ) -> DownloadResult {
+        download_over(Box::new(tokio::io::sink()), downloader, url, jobs, settings, app_data)
+    }
+
+    // The same with the frames the job writes to its port going to `port`
+    fn download_over(
+        port: Box<dyn AsyncWrite + Send + Unpin>,
+        downloader: &dyn Downloader,
+        url: &str,
+        jobs: &JobRegistry,
+        settings: &Settings,
+        app_data: &AppData,
+    ) -> DownloadResult {
+        let runtime = tokio::runtime::Runtime::new().expect("runtime");
+        let (stdout, writer) = {
+            let _context = runtime.enter();
+            native_stdout::over(port)
+        };
+        let output_path = app_data.directory.join("downloads").join("%(title)s.%(ext)s");
+        let output_path = output_path.to_string_lossy();
+        let context = DownloadContext {
+            job_id: "job-1",
+            url,
+            output_path: &output_path,
+            upload: false,
+            priority: Priority::Normal,
+            source_page: &SourcePage::default(),
+            source: "test",
+        };
+        let outcome = download_with(downloader, &context, None, jobs, settings, &stdout);
+        drop(stdout);
+        runtime.block_on(writer).expect("frame writer");
+        outcome
+    }
+
+    // A port that keeps every byte written to it
+    #[derive(Clone, Default)]
+    struct RecordedPort(Arc<Mutex<Vec<u8>>>);
+
+    impl AsyncWrite for RecordedPort {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, bytes: &[u8]) -> Poll<io::Result<usize>> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Poll::Ready(Ok(bytes.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    // Slots of every job the dispatcher still counts, running or waiting
+    fn slots(app_data: &AppData) -> usize {
+        fs::read_to_string(app_data.directory.join("download-slots.json"))
+            .ok()
+            .and_then(|state| serde_json::from_str::<serde_json::Value>(&state).ok())
+            .and_then(|state| state["slots"].as_array().map(Vec::len))
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn the_backend_gets_the_job_and_gives_its_slot_back() {
+        let app_data = test_support::app_data();
+        let mock = MockDownloader::new(Backend::YtDlp);
+        let settings = Settings {
+            max_concurrent_downloads: 1,
+            ..Settings::default()
+        };
+        let jobs = JobRegistry::new();
+
+        for _ in 0..2 {
+            let outcome = download(&mock, "https://videos.example/watch", &jobs, &settings, &app_data).err().expect("refused");
+            assert_eq!(outcome.message, REFUSAL);
+            assert!(!outcome.cancelled && outcome.stopped.is_none());
+            assert_eq!(slots(&app_data), 0);
+        }
+        let asked = mock.asked.borrow();
+        assert_eq!(asked.len(), 2, "the second job got the slot the first gave back");
+        let (job_id, output_path, verbose) = &asked[0];
+        assert_eq!(job_id, "job-1");
+        assert!(output_path.ends_with("%(title)s.%(ext)s"));
+        assert!(verbose);
+        assert!(jobs.list().is_empty());
+    }
+
+    #[test]
+    fn a_paused_queue_holds_the_job_before_its_backend_runs() {
+        let app_data = test_support::app_data();
+        let mock = MockDownloader::new(Backend::HttpImage);
+        let jobs = JobRegistry::new();
+        jobs.set_paused(true);
+
+        let outcome = download(&mock, "https://images.example/a.png", &jobs, &Settings::default(), &app_data)
+            .err()
+            .expect("paused");
+        assert_eq!(outcome.stopped, Some(StopReason::Paused));
+        assert!(mock.asked.borrow().is_empty());
+        assert_eq!(slots(&app_data), 0);
+
+        jobs.set_paused(false);
+        let outcome = download(&mock, "https://images.example/a.png", &jobs, &Settings::default(), &app_data)
+            .err()
+            .expect("refused");
+        assert_eq!(outcome.message, REFUSAL);
+        assert_eq!(mock.asked.borrow().len(), 1);
+    }
+
+    #[test]
+    fn policies_refuse_before_any_backend_runs() {
+        let app_data = test_support::app_data();
+        let jobs = JobRegistry::new();
+
+        let blocked = Settings {
+            blocked_domains: vec!["blocked.example".to_string()],
+            ..Settings::default()
+        };
+        let mock = MockDownloader::new(Backend::YtDlp);
+        let outcome = download(&mock, "https://blocked.example/x", &jobs, &blocked, &app_data).err().expect("blocked");
+        assert!(outcome.message.starts_with(domain_policy::BLOCKED_PREFIX));
+
+        let videos_only = Settings {
+            allowed_types: vec![MediaType::Video],
+            ..Settings::default()
+        };
+        let images = MockDownloader::new(Backend::HttpImage);
+        download(&images, "https://images.example/a.png", &jobs, &videos_only, &app_data)
+            .err()
+            .expect("images are not allowed");
+
+        assert!(mock.asked.borrow().is_empty());
+        assert!(images.asked.borrow().is_empty());
+        assert_eq!(slots(&app_data), 0);
+    }
+
+    #[test]
+    fn routing_honours_the_requested_backend_and_the_probes() {
+        let settings = Settings::default();
+        for backend in [Backend::YtDlp, Backend::HttpImage, Backend::GalleryDl, Backend::Fake] {
+            assert_eq!(route("https://videos.example/watch", &settings, Some(backend)).backend(), backend);
+        }
+        assert_eq!(route("https://images.example/a.PNG?size=large", &settings, None).backend(), Backend::HttpImage);
+        assert_eq!(route("https://videos.example/watch?v=1", &settings, None).backend(), Backend::YtDlp);
+    }
+
+    // An executable shell script in the test's app data directory
+    #[cfg(unix)]
+    fn stub_program(app_data: &AppData, name: &str, script: &str) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = app_data.directory.join(name);
+        fs::write(&path, script).expect("stub program");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).expect("stub program");
+        path
+    }
+
+    // Stands in for a careless extractor: it prints the netrc it was handed
+    // to stdout and stderr and fails with it in the error line
+    #[cfg(unix)]
+    const LEAKY_YT_DLP: &str = r#"#!/bin/sh
+netrc=""
+while [ $# -gt 0 ]; do
+    if [ "$1" = "--netrc-location" ]; then
+        shift
+        netrc="$1"
+    fi
+    shift
+done
+here=$(dirname "$0")
+echo "$netrc" > "$here/netrc-location"
+cat "$netrc" > "$here/netrc-seen"
+echo "[debug] Using $(cat "$netrc")"
+echo "[download]   1.0% of 10.00MiB at 1.00MiB/s ETA 00:09 $(cat "$netrc")"
+echo "ERROR: [example] Login failed with $(cat "$netrc")" >&2
+exit 1
+"#;
+
+    #[cfg(unix)]
+    #[test]
+    fn a_site_login_never_leaves_the_host() {
+        const PASSWORD: &str = "hunter2-c0rrect-h0rse";
+        let app_data = test_support::app_data();
+        test_support::memory_keychain();
+        crate::keychain::set_secret("example-login", PASSWORD).expect("secret");
+
+        let yt_dlp = stub_program(&app_data, "yt-dlp", LEAKY_YT_DLP);
+        let settings = Settings {
+            yt_dlp_path: Some(yt_dlp.to_string_lossy().into_owned()),
+            site_logins: vec![SiteLogin {
+                domain: "videos.example".to_string(),
+                machine: "example".to_string(),
+                username: "alice".to_string(),
+                password_secret: "example-login".to_string(),
+            }],
+            ..Settings::default()
+        };
+        let jobs = JobRegistry::new();
+        let events = jobs.events().subscribe();
+        let port = RecordedPort::default();
+        let log_path = app_data.directory.join("logs").join("imgvault.log");
+        let log = RotatingFile::open(log_path.clone()).expect("log file");
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_subscriber::fmt::layer().with_ansi(false).with_writer(Mutex::new(log)));
+
+        let outcome = tracing::subscriber::with_default(subscriber, || {
+            download_over(
+                Box::new(port.clone()),
+                &YtDlpDownloader,
+                "https://videos.example/watch?v=1",
+                &jobs,
+                &settings,
+                &app_data,
+            )
+        })
+        .err()
+        .expect("the stub fails");
+
+        // The login did reach yt-dlp, and its file went with the job
+        let seen = fs::read_to_string(app_data.directory.join("netrc-seen")).expect("netrc read by the stub");
+        assert_eq!(seen, format!("machine example login alice password {}\n", PASSWORD));
+        let netrc = fs::read_to_string(app_data.directory.join("netrc-location")).expect("netrc location");
+        assert!(!Path::new(netrc.trim()).exists(), "netrc file left behind");
+
+        let failure = outcome.failure.as_ref().map(|failure| serde_json::to_string(failure).expect("failure"));
+        let frames = String::from_utf8_lossy(&port.0.lock().unwrap()).into_owned();
+        let progress = events.drain().join("\n");
+        let log = fs::read_to_string(&log_path).expect("log");
+        assert!(progress.contains("Login failed with"), "no job events captured: {}", progress);
+        assert!(log.contains("Login failed with"), "no log lines captured: {}", log);
+        for (what, text) in [
+            ("message", outcome.message.as_str()),
+            ("stdout", &outcome.stdout),
+            ("stderr", &outcome.stderr),
+            ("failure", failure.as_deref().unwrap_or_default()),
+            ("frames", &frames),
+            ("events", &progress),
+            ("log", &log),
+        ] {
+            assert!(!text.contains(PASSWORD), "password in the {}: {}", what, text);
+        }
+    }
+
+    // Hangs in an extractor: never prints a byte and never exits by itself
+    #[cfg(unix)]
+    const SILENT_CHILD: &str = r#"#!/bin/sh
+echo $$ > "$(dirname "$0")/child-pid"
+exec sleep 600
+"#;
+
+    // The supervisor's first-output deadline, one second here, ends a child
+    // that stays silent, whichever backend spawned it
+    #[cfg(unix)]
+    fn assert_silent_child_is_killed(downloader: &dyn Downloader, url: &str) {
+        let app_data = test_support::app_data();
+        let program = stub_program(&app_data, "silent-child", SILENT_CHILD).to_string_lossy().into_owned();
+        let settings = Settings {
+            yt_dlp_path: Some(program.clone()),
+            gallery_dl_path: Some(program),
+            first_output_timeout_secs: 1,
+            ..Settings::default()
+        };
+        let jobs = JobRegistry::new();
+
+        let started = Instant::now();
+        let outcome = download(downloader, url, &jobs, &settings, &app_data).err().expect("killed");
+        let took = started.elapsed();
+
+        assert_eq!(
+            outcome.message,
+            SupervisorKill::NoOutput { window_secs: 1 }.describe(downloader.program())
+        );
+        assert!(took >= Duration::from_secs(1), "killed before the deadline, after {:?}", took);
+        assert!(took < Duration::from_secs(10), "killed long after the deadline, after {:?}", took);
+        let pid = fs::read_to_string(app_data.directory.join("child-pid")).expect("child pid");
+        let pid = pid.trim().parse::<u32>().expect("child pid");
+        assert!(!crate::jobs::process_exists(pid), "child {} outlived its job", pid);
+        let report = outcome.supervision.expect("supervision report");
+        assert_eq!(report.first_output_ms, None);
+        assert_eq!(report.events, vec![outcome.message.clone()]);
+        assert!(jobs.list().is_empty());
+        assert_eq!(slots(&app_data), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_silent_yt_dlp_is_killed_at_the_deadline() {
+        assert_silent_child_is_killed(&YtDlpDownloader, "https://videos.example/watch?v=1");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_silent_gallery_dl_is_killed_at_the_deadline() {
+        assert_silent_child_is_killed(&GalleryDlDownloader, "https://gallery.example/album/1");
+    }
+}
@@ -0,0 +1,340 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use tauri::{AppHandle, Manager};
+use tracing::{debug, info, warn};
+
+use crate::gui::start_forwarded_download;
+use crate::history::{DownloadStatus, History, NewHistoryEntry};
+use crate::long_path;
+use crate::notifications::{self, DesktopNotification};
+use crate::queue::generate_job_id;
+use crate::settings::{Settings, SettingsStore};
+use crate::vault_events::{self, VaultChange};
+use crate::{current_timestamp_millis, get_app_data_directory, get_vault_directory, logging, validate_download_url};
+
+pub(crate) const THUMBNAIL_DIRECTORY: &str = "thumbnails";
+const THUMBNAIL_WIDTH: u32 = 320;
+// What yt-dlp produces and what is usually saved next to it
+pub const MEDIA_EXTENSIONS: &[&str] = &[
+    "mp4", "mkv", "webm", "mov", "avi", "m4v", "flv", "mp3", "m4a", "opus", "ogg", "wav", "flac", "aac", "jpg",
+    "jpeg", "png", "gif", "webp",
+];
+// Skipped items named in the summary toast; the log has all of them
+const MAX_SKIPPED_IN_SUMMARY: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DropImportMode {
+    // The dropped file stays where it was
+    #[default]
+    Copy,
+    Move,
+}
+
+pub enum DroppedItem {
+    // A file or folder from the window's file-drop event
+    Path(PathBuf),
+    // Text dropped onto the page, such as a link dragged from a browser
+    Text(String),
+}
+
+enum DropAction {
+    Download(String),
+    Import(PathBuf),
+}
+
+#[derive(Default)]
+struct DropSummary {
+    queued: usize,
+    imported: usize,
+    // "name: reason" for everything that was not used
+    skipped: Vec<String>,
+}
+
+// Handles one drop as a batch on its own thread: links are queued as
+// downloads, media files are imported into the vault, and one toast sums up
+// what happened. `source` is recorded with imported files.
+pub fn handle_drop(app: AppHandle, items: Vec<DroppedItem>, source: &'static str) {
+    std::thread::spawn(move || {
+        let settings = app.state::<SettingsStore>().get();
+        let history = app.state::<History>().inner().clone();
+        let mut summary = DropSummary::default();
+        let mut seen_urls = HashSet::new();
+
+        for (label, action) in items.into_iter().flat_map(classify) {
+            match action {
+                Ok(DropAction::Download(url)) => {
+                    // A link dropped twice in one batch downloads once
+                    if seen_urls.insert(url.clone()) {
+                        info!(url = logging::loggable_url(&url), "Dropped link queued");
+                        start_forwarded_download(app.clone(), url, None);
+                        summary.queued += 1;
+                    }
+                }
+                Ok(DropAction::Import(path)) => match import_file(&history, &settings, &path, source) {
+                    Ok(destination) => {
+                        let _ = app.emit_all("log-event", format!("📁 Imported into the vault: {}", destination.display()));
+                        summary.imported += 1;
+                    }
+                    Err(error) => {
+                        warn!("Failed to import {}: {}", path.display(), error);
+                        summary.skipped.push(format!("{}: {}", label, error));
+                    }
+                },
+                Err(reason) => {
+                    info!("Ignored dropped {}: {}", label, reason);
+                    summary.skipped.push(format!("{}: {}", label, reason));
+                }
+            }
+        }
+
+        if let Some(notification) = summary_notification(&summary) {
+            notifications::show(&notification);
+        }
+    });
+}
+
+// Each dropped item as a display label and what to do with it. Text can hold
+// several links, one per line as in text/uri-list.
+fn classify(item: DroppedItem) -> Vec<(String, Result<DropAction, String>)> {
+    match item {
+        DroppedItem::Path(path) => {
+            let label = path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| path.display().to_string());
+            vec![(label, classify_path(path))]
+        }
+        DroppedItem::Text(text) => text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let action = validate_download_url(line)
+                    .map(|()| DropAction::Download(line.to_string()))
+                    .map_err(|_| "not an http or https link".to_string());
+                (logging::loggable_url(line).to_string(), action)
+            })
+            .collect(),
+    }
+}
+
+fn classify_path(path: PathBuf) -> Result<DropAction, String> {
+    if path.is_dir() {
+        return Err("folders cannot be imported, drop the files inside it".to_string());
+    }
+    // Windows saves a link dragged out of a browser as an internet shortcut
+    if lowercase_extension(&path) == "url" {
+        let url = read_internet_shortcut(&path)?;
+        validate_download_url(&url)?;
+        return Ok(DropAction::Download(url));
+    }
+    check_media_file(&path)?;
+    Ok(DropAction::Import(path))
+}
+
+// Only files of MEDIA_EXTENSIONS go into the vault
+pub fn check_media_file(path: &Path) -> Result<(), String> {
+    if !MEDIA_EXTENSIONS.contains(&lowercase_extension(path).as_str()) {
+        return Err("not a video, audio or image file".to_string());
+    }
+    Ok(())
+}
+
+fn lowercase_extension(path: &Path) -> String {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default()
+}
+
+// The URL= line of a .url file's [InternetShortcut] section
+fn read_internet_shortcut(path: &Path) -> Result<String, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read shortcut: {}", e))?;
+    contents
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("URL="))
+        .map(|url| url.trim().to_string())
+        .ok_or_else(|| "shortcut has no link".to_string())
+}
+
+// Copies or moves the file into the vault folder, records it in history and
+// makes a thumbnail. A file already in the vault folder is only recorded.
+// `source` is "drop" or "shell", for the history entry.
+pub fn import_file(history: &History, settings: &Settings, path: &Path, source: &str) -> Result<PathBuf, String> {
+    let started_at = current_timestamp_millis();
+    let vault = get_vault_directory(settings)?;
+    fs::create_dir_all(long_path::extended(&vault))
+        .map_err(|e| format!("Failed to create vault folder {}: {}", vault.display(), e))?;
+    let file_name = path.file_name().ok_or("file has no name")?;
+
+    let in_vault = path.parent().is_some_and(|parent| same_directory(parent, &vault));
+    let destination = if in_vault {
+        path.to_path_buf()
+    } else {
+        let destination = unique_destination(&vault, Path::new(file_name));
+        match settings.drop_import_mode {
+            DropImportMode::Copy => fs::copy(path, long_path::extended(&destination)).map(|_| ()),
+            DropImportMode::Move => move_file(path, &destination),
+        }
+        .map_err(|e| format!("Failed to {} into the vault: {}", import_verb(settings.drop_import_mode), e))?;
+        destination
+    };
+
+    let message = format!("Imported from {}", path.display());
+    let file_path = destination.display().to_string();
+    let entry = NewHistoryEntry {
+        job_id: &generate_job_id(source),
+        url: &format!("file://{}", path.display()),
+        file_path: Some(&file_path),
+        status: DownloadStatus::Completed,
+        message: Some(&message),
+        source,
+        started_at,
+        finished_at: current_timestamp_millis(),
+        object_key: None,
+        etag: None,
+        output_path: None,
+        upload: false,
+        page_url: None,
+        page_title: None,
+        media_info: None,
+        transfer: None,
+        session_id: None,
+        supervision: None,
+    };
+    let entry_id = history.record(&entry)?;
+    // The batch has its own summary toast
+    if let Err(error) = history.mark_notified(entry_id) {
+        warn!("{}", error);
+    }
+    if let Err(error) = generate_thumbnail(&destination, entry_id) {
+        debug!("No thumbnail for {}: {}", destination.display(), error);
+    }
+    Ok(destination)
+}
+
+fn import_verb(mode: DropImportMode) -> &'static str {
+    match mode {
+        DropImportMode::Copy => "copy",
+        DropImportMode::Move => "move",
+    }
+}
+
+fn same_directory(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+// "name.ext", then "name (1).ext" and so on, so an import never overwrites
+pub(crate) fn unique_destination(directory: &Path, file_name: &Path) -> PathBuf {
+    let candidate = directory.join(file_name);
+    if !candidate.exists() {
+        return candidate;
+    }
+    let stem = file_name.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    let extension = file_name.extension().map(|extension| extension.to_string_lossy().to_string());
+    (1..)
+        .map(|index| {
+            let name = match &extension {
+                Some(extension) => format!("{} ({}).{}", stem, index, extension),
+                None => format!("{} ({})", stem, index),
+            };
+            directory.join(name)
+        })
+        .find(|candidate| !candidate.exists())
+        .expect("some numbered name is free")
+}
+
+// A rename fails across drives, so fall back to copying and deleting
+pub(crate) fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    let (extended_from, extended_to) = (&long_path::extended(from), &long_path::extended(to));
+    if fs::rename(extended_from, extended_to).is_err() {
+        fs::copy(extended_from, extended_to)?;
+        fs::remove_file(extended_from)?;
+    }
+    vault_events::record(VaultChange::Moved {
+        from: from.display().to_string(),
+        path: to.display().to_string(),
+    });
+    Ok(())
+}
+
+// A frame of a video or a scaled copy of an image, saved as
+// thumbnails/<history id>.jpg in the app data folder. Audio without cover art
+// has none.
+pub(crate) fn generate_thumbnail(source: &Path, entry_id: i64) -> Result<PathBuf, String> {
+    let directory = get_app_data_directory()?.join(THUMBNAIL_DIRECTORY);
+    fs::create_dir_all(&directory)
+        .map_err(|e| format!("Failed to create thumbnail folder {}: {}", directory.display(), e))?;
+    let thumbnail = directory.join(format!("{}.jpg", entry_id));
+
+    let mut command = Command::new("ffmpeg");
+    command
+        .args(["-v", "error", "-y", "-i"])
+        .arg(long_path::simplified(source))
+        .args(["-frames:v", "1", "-vf"])
+        .arg(format!("scale={}:-2", THUMBNAIL_WIDTH))
+        .arg(&thumbnail)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+
+    #[cfg(target_os = "windows")]
+    {
+        use std::os::windows::process::CommandExt;
+        const CREATE_NO_WINDOW: u32 = 0x08000000;
+        command.creation_flags(CREATE_NO_WINDOW);
+    }
+
+    let output = command.output().map_err(|e| format!("Failed to run ffmpeg: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "ffmpeg failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(thumbnail)
+}
+
+fn summary_notification(summary: &DropSummary) -> Option<DesktopNotification> {
+    let mut parts = Vec::new();
+    if summary.queued > 0 {
+        parts.push(plural(summary.queued, "download queued", "downloads queued"));
+    }
+    if summary.imported > 0 {
+        parts.push(plural(summary.imported, "file imported", "files imported"));
+    }
+
+    let title = match (parts.is_empty(), summary.skipped.is_empty()) {
+        (true, true) => return None,
+        (true, false) => "Nothing to do with this drop",
+        (false, true) => "Drop added to ImgVault",
+        (false, false) => "Drop partly added to ImgVault",
+    };
+    let mut body = parts.join(", ");
+    if !summary.skipped.is_empty() {
+        if !body.is_empty() {
+            body.push('\n');
+        }
+        body.push_str("Skipped ");
+        body.push_str(&summary.skipped.iter().take(MAX_SKIPPED_IN_SUMMARY).cloned().collect::<Vec<_>>().join("; "));
+        if summary.skipped.len() > MAX_SKIPPED_IN_SUMMARY {
+            body.push_str(&format!(" and {} more", summary.skipped.len() - MAX_SKIPPED_IN_SUMMARY));
+        }
+    }
+
+    Some(DesktopNotification {
+        title: title.to_string(),
+        body,
+        reveal_path: None,
+    })
+}
+
+fn plural(count: usize, one: &str, many: &str) -> String {
+    format!("{} {}", count, if count == 1 { one } else { many })
+}
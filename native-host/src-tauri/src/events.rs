@@ -0,0 +1,219 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::native_stdout::FrameWriter;
+use crate::protocol::NativeResponse;
+use crate::site_login::host_in_domain;
+use crate::{frame_limit, redact, url_host};
+
+// Frames a subscriber may have waiting before progress frames start being dropped
+const MAX_QUEUED_FRAMES: usize = 256;
+// How often a subscribed native messaging port is sent what has queued up
+const FORWARD_INTERVAL: Duration = Duration::from_millis(100);
+// Events after which a job is gone from the queue, so its host is forgotten
+const FINAL_EVENTS: &[&str] = &["completed", "failed", "cancelled", "complete", "paused", "interrupted"];
+
+// Job lifecycle events of jobs running in this process, fanned out to
+// WebSocket clients and subscribed native messaging ports as the same
+// NativeResponse frames native messaging sends
+#[derive(Clone, Default)]
+pub struct JobEvents {
+    subscribers: Arc<Mutex<Vec<Weak<Subscription>>>>,
+    // Host of each job's URL, for subscriptions filtered by domain
+    hosts: Arc<Mutex<HashMap<String, String>>>,
+}
+
+// Which frames a subscription gets. Empty lists match every job; frames that
+// are not about one job, like offline and online, always go through.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EventFilter {
+    #[serde(default)]
+    pub job_ids: Vec<String>,
+    // A domain matches its subdomains too
+    #[serde(default)]
+    pub domains: Vec<String>,
+}
+
+impl EventFilter {
+    fn matches(&self, job_id: Option<&str>, host: Option<&str>) -> bool {
+        let Some(job_id) = job_id else {
+            return true;
+        };
+        if self.job_ids.is_empty() && self.domains.is_empty() {
+            return true;
+        }
+        self.job_ids.iter().any(|wanted| wanted == job_id)
+            || host.is_some_and(|host| self.domains.iter().any(|domain| host_in_domain(host, domain)))
+    }
+}
+
+struct QueuedFrame {
+    progress: bool,
+    seq: u64,
+    json: Arc<str>,
+}
+
+// Dropping the subscription unsubscribes
+pub struct Subscription {
+    queue: Mutex<VecDeque<QueuedFrame>>,
+    // None while a native messaging port is unsubscribed
+    filter: Mutex<Option<EventFilter>>,
+    // Counts every frame that matched, so a dropped progress frame shows up
+    // as a gap in what arrives
+    next_seq: AtomicU64,
+}
+
+impl JobEvents {
+    // Every frame, for WebSocket clients
+    pub fn subscribe(&self) -> Arc<Subscription> {
+        self.subscribe_filtered(Some(EventFilter::default()))
+    }
+
+    pub fn subscribe_filtered(&self, filter: Option<EventFilter>) -> Arc<Subscription> {
+        let subscription = Arc::new(Subscription {
+            queue: Mutex::new(VecDeque::new()),
+            filter: Mutex::new(filter),
+            next_seq: AtomicU64::new(1),
+        });
+        self.subscribers.lock().unwrap().push(Arc::downgrade(&subscription));
+        subscription
+    }
+
+    pub fn has_subscribers(&self) -> bool {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|subscriber| subscriber.strong_count() > 0)
+    }
+
+    // Remembers the job's host until its final event
+    pub fn track_job(&self, job_id: &str, url: &str) {
+        if let Some(host) = url_host(url) {
+            self.hosts.lock().unwrap().insert(job_id.to_string(), host);
+        }
+    }
+
+    pub fn publish(&self, frame: &NativeResponse) {
+        let job_id = frame.request_id.as_deref();
+        let host = {
+            let mut hosts = self.hosts.lock().unwrap();
+            match job_id {
+                Some(job_id) if frame.event.as_deref().is_some_and(|event| FINAL_EVENTS.contains(&event)) => {
+                    hosts.remove(job_id)
+                }
+                Some(job_id) => hosts.get(job_id).cloned(),
+                None => None,
+            }
+        };
+
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| subscriber.strong_count() > 0);
+        if subscribers.is_empty() {
+            return;
+        }
+
+        // Serialize once for every subscriber
+        let json: Arc<str> = match serde_json::to_string(frame) {
+            Ok(json) => redact::redact(&json).into(),
+            Err(error) => {
+                warn!("Failed to serialize job event: {}", error);
+                return;
+            }
+        };
+        let progress = frame.event.as_deref() == Some("progress");
+        for subscription in subscribers.iter().filter_map(Weak::upgrade) {
+            let wanted = subscription
+                .filter
+                .lock()
+                .unwrap()
+                .as_ref()
+                .is_some_and(|filter| filter.matches(job_id, host.as_deref()));
+            if wanted {
+                subscription.push(progress, Arc::clone(&json));
+            }
+        }
+    }
+}
+
+impl Subscription {
+    // A slow consumer loses intermediate progress frames, oldest first, but
+    // never a lifecycle frame: queued, completed, failed and cancelled always arrive
+    fn push(&self, progress: bool, json: Arc<str>) {
+        let seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= MAX_QUEUED_FRAMES {
+            if let Some(oldest_progress) = queue.iter().position(|queued| queued.progress) {
+                queue.remove(oldest_progress);
+            } else if progress {
+                return;
+            }
+        }
+        queue.push_back(QueuedFrame { progress, seq, json });
+    }
+
+    // None stops delivery and discards what is waiting; sequence numbers
+    // carry on when a filter is set again
+    pub fn set_filter(&self, filter: Option<EventFilter>) {
+        let stopped = filter.is_none();
+        *self.filter.lock().unwrap() = filter;
+        if stopped {
+            self.queue.lock().unwrap().clear();
+        }
+    }
+
+    pub fn drain(&self) -> Vec<Arc<str>> {
+        self.queue
+            .lock()
+            .unwrap()
+            .drain(..)
+            .map(|frame| frame.json)
+            .collect()
+    }
+
+    fn drain_numbered(&self) -> Vec<(u64, Arc<str>)> {
+        self.queue
+            .lock()
+            .unwrap()
+            .drain(..)
+            .map(|frame| (frame.seq, frame.json))
+            .collect()
+    }
+}
+
+// Sends a native messaging port the frames of its subscription, each with its
+// `seq` and within the frame limit, until the session drops the subscription
+// or the port closes
+pub fn forward_to_port(subscription: &Arc<Subscription>, stdout: FrameWriter) {
+    let subscription = Arc::downgrade(subscription);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(FORWARD_INTERVAL);
+        let Some(subscription) = subscription.upgrade() else {
+            return;
+        };
+        for (seq, json) in subscription.drain_numbered() {
+            let Ok(Value::Object(mut frame)) = serde_json::from_str::<Value>(&json) else {
+                continue;
+            };
+            frame.insert("seq".to_string(), Value::from(seq));
+            let frames = match frame_limit::frames(&frame) {
+                Ok(frames) => frames,
+                Err(error) => {
+                    warn!("{}", error);
+                    continue;
+                }
+            };
+            for frame in frames {
+                if stdout.write_frame(frame.as_bytes()).is_err() {
+                    debug!("Native messaging port closed, no longer forwarding job events");
+                    return;
+                }
+            }
+        }
+    });
+}
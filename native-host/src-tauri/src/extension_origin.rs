@@ -0,0 +1,106 @@
+use serde::Deserialize;
+use std::fs;
+use tracing::warn;
+
+use crate::EXTENSION_ID;
+use crate::registration::native_manifest_path;
+
+// Chrome passes the caller's origin as the first argument, "chrome-extension://<id>/"
+const ORIGIN_SCHEME: &str = "chrome-extension://";
+// History source for sessions whose caller is unknown
+const UNKNOWN_SOURCE: &str = "native";
+
+#[derive(Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    allowed_origins: Vec<String>,
+}
+
+// Who is on the other end of the native messaging port, decided once per session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtensionOrigin {
+    Allowed(String),
+    // Older Chrome or a manual run; served, with a warning at startup
+    Missing,
+    Refused(String),
+}
+
+impl ExtensionOrigin {
+    pub fn from_args(args: &[String]) -> Self {
+        let Some(origin) = args.iter().skip(1).find(|arg| arg.starts_with(ORIGIN_SCHEME)) else {
+            warn!("Chrome did not pass an extension origin; serving the session unverified");
+            return ExtensionOrigin::Missing;
+        };
+        let origin = normalize(origin);
+        let allowed = allowed_origins();
+        if allowed.contains(&origin) {
+            ExtensionOrigin::Allowed(origin)
+        } else {
+            warn!(origin = %origin, "Extension origin is not in the manifest's allowed_origins");
+            ExtensionOrigin::Refused(origin)
+        }
+    }
+
+    // Recorded as the history source of downloads this session starts
+    pub fn history_source(&self) -> &str {
+        match self {
+            ExtensionOrigin::Allowed(origin) => origin,
+            ExtensionOrigin::Missing | ExtensionOrigin::Refused(_) => UNKNOWN_SOURCE,
+        }
+    }
+
+    pub fn loggable(&self) -> &str {
+        match self {
+            ExtensionOrigin::Allowed(origin) | ExtensionOrigin::Refused(origin) => origin,
+            ExtensionOrigin::Missing => "unknown",
+        }
+    }
+
+    // The error sent back for every request of a refused session
+    pub fn refusal(&self) -> Option<String> {
+        match self {
+            ExtensionOrigin::Refused(origin) => Some(format!(
+                "{} is not allowed to use ImgVault; register the host again for this extension",
+                origin
+            )),
+            ExtensionOrigin::Allowed(_) | ExtensionOrigin::Missing => None,
+        }
+    }
+}
+
+// The allowlist recorded at registration; the built-in extension when the
+// manifest cannot be read, e.g. on platforms where registration is manual
+pub fn allowed_origins() -> Vec<String> {
+    match read_manifest_origins() {
+        Ok(origins) if !origins.is_empty() => origins,
+        Ok(_) => default_origins(),
+        Err(error) => {
+            warn!("{}; allowing only the built-in extension", error);
+            default_origins()
+        }
+    }
+}
+
+fn read_manifest_origins() -> Result<Vec<String>, String> {
+    // Written to app data by register_native_host
+    let manifest_path = native_manifest_path()?;
+    let content = fs::read_to_string(&manifest_path)
+        .map_err(|e| format!("Failed to read {}: {}", manifest_path.display(), e))?;
+    let manifest: Manifest = serde_json::from_str(&content)
+        .map_err(|e| format!("Failed to parse {}: {}", manifest_path.display(), e))?;
+    Ok(manifest.allowed_origins.iter().map(|origin| normalize(origin)).collect())
+}
+
+fn default_origins() -> Vec<String> {
+    vec![format!("{}{}/", ORIGIN_SCHEME, EXTENSION_ID)]
+}
+
+// Chrome always sends the trailing slash; hand-edited manifests may not
+fn normalize(origin: &str) -> String {
+    let origin = origin.trim();
+    if origin.ends_with('/') {
+        origin.to_string()
+    } else {
+        format!("{}/", origin)
+    }
+}
@@ -0,0 +1,852 @@
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Response, Server};
+
+use crate::capture::{self, Direction, Entry};
+use crate::frame_limit::MAX_FRAME_BYTES;
+use crate::i18n;
+use crate::jobs::process_exists;
+use crate::origin_tag;
+
+// Dev mode that plays the extension's side of native messaging against this
+// executable: `--simulate-extension <fixture.json>... [--yt-dlp <stub>] [--media <dir>]`
+pub const SIMULATE_FLAG: &str = "--simulate-extension";
+const YT_DLP_FLAG: &str = "--yt-dlp";
+const MEDIA_FLAG: &str = "--media";
+// `--replay <capture.jsonl>... [--yt-dlp <stub>] [--media <dir>]` sends the
+// inbound frames of captured sessions to fresh hosts and compares what they
+// answer with what was recorded
+pub const REPLAY_FLAG: &str = "--replay";
+// Next to the fixtures folder unless given
+const DEFAULT_STUB_YT_DLP: &str = "stub-yt-dlp.sh";
+const DEFAULT_MEDIA_DIR: &str = "media";
+// Captures can be anywhere, so replays use the ones of the source tree
+const SOURCE_SUPPORT_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/simulate");
+// The port steps use when they name none
+const DEFAULT_PORT: &str = "main";
+const DEFAULT_STEP_TIMEOUT: Duration = Duration::from_secs(10);
+// Time each host gets to exit once its stdin is closed
+const EXIT_TIMEOUT: Duration = Duration::from_secs(20);
+// Time the stub's children get to go away after their host has exited
+const ORPHAN_GRACE: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// Time each recorded answer gets to be sent again
+const REPLAY_FRAME_TIMEOUT: Duration = Duration::from_secs(15);
+// Events whose number and timing depend on the backend and the clock rather
+// than on the handlers; replays do not compare them
+const REPLAY_IGNORED_EVENTS: &[&str] = &["progress", "warning", "stalled", "waiting"];
+// The stub appends the pid of every run to this file
+const PIDS_ENV: &str = "IMGVAULT_SIM_PIDS";
+// Where get_app_data_directory puts the host's files below the data home
+#[cfg(target_os = "windows")]
+const APP_DATA_DIR_NAME: &str = "ImgVault";
+#[cfg(not(target_os = "windows"))]
+const APP_DATA_DIR_NAME: &str = "imgvault";
+// Fields of NativeResponse that are a string or null when present
+const STRING_FIELDS: &[&str] = &["event", "requestId", "message", "line", "stream", "filePath", "stdout", "stderr", "detail"];
+
+const EXIT_SUCCESS: i32 = 0;
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+
+// A scripted conversation. Steps run in order; strings in sent messages and
+// expectations may use {{server}} for the local media server, {{output}} for
+// the download folder and {{i}} for the index of a repeated send.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct Fixture {
+    name: String,
+    // Expected exit code per port; ports not listed must exit with 0
+    #[serde(default)]
+    exit_codes: HashMap<String, i32>,
+    // Laid over the sandbox's settings, e.g. to turn a feature on
+    #[serde(default)]
+    settings: Map<String, Value>,
+    steps: Vec<Step>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct Step {
+    // Each port is a host process of its own, as with chrome.runtime.connectNative
+    #[serde(default = "default_port")]
+    port: String,
+    send: Option<Value>,
+    #[serde(default = "default_times")]
+    repeat: usize,
+    send_raw: Option<RawFrame>,
+    // Fields the next matching frames must have; other frames are kept for
+    // later expectations
+    expect: Option<Map<String, Value>>,
+    #[serde(default = "default_times")]
+    count: usize,
+    timeout_ms: Option<u64>,
+    // Checked once the steps' frames are matched
+    expect_origin: Option<OriginExpectation>,
+    // Closes the port's stdin, as Chrome does when the extension disconnects
+    #[serde(default)]
+    close: bool,
+    sleep_ms: Option<u64>,
+}
+
+// A frame sent as given; a length other than the body's makes it malformed
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawFrame {
+    length: Option<u32>,
+    body: String,
+}
+
+// The origin mark of a downloaded file, as origin_tag::read has it; a null
+// tag expects the file to have none. zoneId is only checked on Windows.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct OriginExpectation {
+    file: String,
+    tag: Option<Map<String, Value>>,
+}
+
+fn default_port() -> String {
+    DEFAULT_PORT.to_string()
+}
+
+fn default_times() -> usize {
+    1
+}
+
+struct Options {
+    fixtures: Vec<PathBuf>,
+    yt_dlp: Option<PathBuf>,
+    media: Option<PathBuf>,
+}
+
+// Returns the process exit code: 0 when every fixture passed
+pub fn run(args: &[String]) -> i32 {
+    let options = match parse_args(args) {
+        Ok(options) => options,
+        Err(error) => {
+            report_error(&error);
+            report_error(&format!(
+                "Usage: {} <fixture.json>... [{} <stub>] [{} <dir>]",
+                SIMULATE_FLAG, YT_DLP_FLAG, MEDIA_FLAG
+            ));
+            return EXIT_USAGE;
+        }
+    };
+
+    let mut failed = 0;
+    for path in &options.fixtures {
+        let started = Instant::now();
+        match run_fixture(path, &options) {
+            Ok(name) => report(&format!("ok   {} ({:.1}s)", name, started.elapsed().as_secs_f64())),
+            Err(error) => {
+                failed += 1;
+                report(&format!("FAIL {}: {}", path.display(), error));
+            }
+        }
+    }
+    report(&format!("{} passed, {} failed", options.fixtures.len() - failed, failed));
+    if failed == 0 {
+        EXIT_SUCCESS
+    } else {
+        EXIT_FAILURE
+    }
+}
+
+// Returns the process exit code: 0 when every capture replayed without a
+// divergence
+pub fn replay(args: &[String]) -> i32 {
+    let options = match parse_args(args) {
+        Ok(options) => options,
+        Err(error) => {
+            report_error(&error);
+            report_error(&format!(
+                "Usage: {} <capture.jsonl>... [{} <stub>] [{} <dir>]",
+                REPLAY_FLAG, YT_DLP_FLAG, MEDIA_FLAG
+            ));
+            return EXIT_USAGE;
+        }
+    };
+
+    let mut diverged = 0;
+    for path in &options.fixtures {
+        let started = Instant::now();
+        match replay_capture(path, &options) {
+            Ok(frames) => report(&format!(
+                "ok   {} ({} frames, {:.1}s)",
+                path.display(),
+                frames,
+                started.elapsed().as_secs_f64()
+            )),
+            Err(error) => {
+                diverged += 1;
+                report(&format!("DIFF {}: {}", path.display(), error));
+            }
+        }
+    }
+    report(&format!("{} matched, {} diverged", options.fixtures.len() - diverged, diverged));
+    if diverged == 0 {
+        EXIT_SUCCESS
+    } else {
+        EXIT_FAILURE
+    }
+}
+
+// A dev mode run from a terminal, so results go straight to it
+#[allow(clippy::print_stdout)]
+fn report(line: &str) {
+    println!("{}", line);
+}
+
+#[allow(clippy::print_stderr)]
+fn report_error(line: &str) {
+    eprintln!("{}", line);
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        fixtures: Vec::new(),
+        yt_dlp: None,
+        media: None,
+    };
+    let mut arguments = args.iter().skip(1).filter(|arg| *arg != SIMULATE_FLAG && *arg != REPLAY_FLAG);
+    while let Some(arg) = arguments.next() {
+        match arg.as_str() {
+            YT_DLP_FLAG => options.yt_dlp = Some(arguments.next().ok_or("Missing path after --yt-dlp")?.into()),
+            MEDIA_FLAG => options.media = Some(arguments.next().ok_or("Missing folder after --media")?.into()),
+            _ => options.fixtures.push(PathBuf::from(arg)),
+        }
+    }
+    if options.fixtures.is_empty() {
+        return Err("No files given".to_string());
+    }
+    Ok(options)
+}
+
+fn run_fixture(path: &Path, options: &Options) -> Result<String, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("Failed to read fixture: {}", e))?;
+    let fixture: Fixture = serde_json::from_str(&content).map_err(|e| format!("Failed to parse fixture: {}", e))?;
+    // The stub and the media sit next to the fixtures folder
+    let support_dir = path
+        .parent()
+        .and_then(Path::parent)
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    let yt_dlp = options.yt_dlp.clone().unwrap_or_else(|| support_dir.join(DEFAULT_STUB_YT_DLP));
+    let media = options.media.clone().unwrap_or_else(|| support_dir.join(DEFAULT_MEDIA_DIR));
+
+    let sandbox = Sandbox::create(&fixture.name, &yt_dlp, &fixture.settings)?;
+    let server = MediaServer::start(media)?;
+    let result = Simulation::new(&sandbox, &server).play(&fixture);
+    server.stop();
+    match result {
+        Ok(()) => {
+            sandbox.remove();
+            Ok(fixture.name)
+        }
+        // Keep the host's logs and downloads for a look
+        Err(error) => Err(format!("{} ({} kept)", error, sandbox.root.display())),
+    }
+}
+
+// Returns how many answers were compared
+fn replay_capture(path: &Path, options: &Options) -> Result<usize, String> {
+    let entries = capture::read(path)?;
+    let support_dir = Path::new(SOURCE_SUPPORT_DIR);
+    let yt_dlp = options.yt_dlp.clone().unwrap_or_else(|| support_dir.join(DEFAULT_STUB_YT_DLP));
+    let media = options.media.clone().unwrap_or_else(|| support_dir.join(DEFAULT_MEDIA_DIR));
+    let name = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+
+    let sandbox = Sandbox::create(&format!("replay-{}", name), &yt_dlp, &Map::new())?;
+    let server = MediaServer::start(media)?;
+    let result = Simulation::new(&sandbox, &server).replay(&entries);
+    server.stop();
+    match result {
+        Ok(compared) => {
+            sandbox.remove();
+            Ok(compared)
+        }
+        Err(error) => Err(format!("{} ({} kept)", error, sandbox.root.display())),
+    }
+}
+
+// A data home, download folder and settings of its own per fixture, so the
+// hosts never touch the real vault, history or settings
+struct Sandbox {
+    root: PathBuf,
+    data_home: PathBuf,
+    output: PathBuf,
+    pids: PathBuf,
+}
+
+impl Sandbox {
+    fn create(name: &str, yt_dlp: &Path, overrides: &Map<String, Value>) -> Result<Self, String> {
+        let slug = name
+            .chars()
+            .map(|ch| if ch.is_ascii_alphanumeric() { ch.to_ascii_lowercase() } else { '-' })
+            .collect::<String>();
+        let root = env::temp_dir().join(format!("imgvault-sim-{}-{}", std::process::id(), slug));
+        let _ = fs::remove_dir_all(&root);
+        let data_home = root.join("data");
+        let output = root.join("downloads");
+        let app_data = data_home.join(APP_DATA_DIR_NAME);
+        for dir in [&app_data, &output] {
+            fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        }
+
+        let yt_dlp = fs::canonicalize(yt_dlp).map_err(|e| format!("Failed to find {}: {}", yt_dlp.display(), e))?;
+        let mut settings = json!({
+            "yt_dlp_path": yt_dlp,
+            "vault_root": output,
+            "notifications": "off",
+        });
+        if let Value::Object(fields) = &mut settings {
+            fields.extend(overrides.clone());
+        }
+        let settings_path = app_data.join("settings.json");
+        fs::write(&settings_path, settings.to_string())
+            .map_err(|e| format!("Failed to write {}: {}", settings_path.display(), e))?;
+
+        Ok(Sandbox {
+            pids: root.join("pids"),
+            root,
+            data_home,
+            output,
+        })
+    }
+
+    fn spawn_host(&self) -> Result<Child, String> {
+        let exe_path = env::current_exe().map_err(|e| format!("Failed to get executable path: {}", e))?;
+        Command::new(exe_path)
+            .arg("--native")
+            .env("XDG_DATA_HOME", &self.data_home)
+            .env("LOCALAPPDATA", &self.data_home)
+            .env(PIDS_ENV, &self.pids)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("Failed to start the host: {}", e))
+    }
+
+    // Children of the hosts still running after their host exited
+    fn orphans(&self) -> Vec<u32> {
+        let recorded = fs::read_to_string(&self.pids).unwrap_or_default();
+        let pids = recorded
+            .lines()
+            .filter_map(|line| line.trim().parse::<u32>().ok())
+            .collect::<Vec<_>>();
+        let deadline = Instant::now() + ORPHAN_GRACE;
+        loop {
+            let alive = pids.iter().copied().filter(|pid| process_exists(*pid)).collect::<Vec<_>>();
+            if alive.is_empty() || Instant::now() >= deadline {
+                return alive;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn remove(&self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+// Serves the media folder on a local port for the HTTP image backend
+struct MediaServer {
+    server: Arc<Server>,
+    url: String,
+}
+
+impl MediaServer {
+    fn start(media: PathBuf) -> Result<Self, String> {
+        let server = Server::http("127.0.0.1:0").map_err(|e| format!("Failed to start the media server: {}", e))?;
+        let address = server
+            .server_addr()
+            .to_ip()
+            .ok_or("Failed to get the media server's address")?;
+        let server = Arc::new(server);
+        let serving = server.clone();
+        thread::spawn(move || {
+            for request in serving.incoming_requests() {
+                let name = request.url().split(['?', '#']).next().unwrap_or("").trim_start_matches('/');
+                // Only plain file names; nothing outside the media folder
+                let file = (!name.is_empty() && !name.contains(['/', '\\']) && name != "..")
+                    .then(|| fs::read(media.join(name)).ok())
+                    .flatten();
+                let response = match file {
+                    Some(bytes) => {
+                        let content_type = content_type(name);
+                        let response = Response::from_data(bytes);
+                        match Header::from_bytes("Content-Type", content_type) {
+                            Ok(header) => response.with_header(header),
+                            Err(_) => response,
+                        }
+                    }
+                    None => Response::from_string("Not found").with_status_code(404),
+                };
+                let _ = request.respond(response);
+            }
+        });
+        Ok(MediaServer {
+            server,
+            url: format!("http://{}", address),
+        })
+    }
+
+    fn stop(&self) {
+        self.server.unblock();
+    }
+}
+
+fn content_type(name: &str) -> &'static str {
+    match name.rsplit_once('.').map(|(_, extension)| extension.to_ascii_lowercase()).as_deref() {
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+// One host process, as one chrome.runtime.connectNative port
+struct Port {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    // Checked frames, or why a frame broke the protocol or the schema
+    frames: Receiver<Result<Value, String>>,
+    // Frames read but not yet matched by an expectation
+    unmatched: Vec<Value>,
+}
+
+struct Simulation<'a> {
+    sandbox: &'a Sandbox,
+    server: &'a MediaServer,
+    ports: HashMap<String, Port>,
+}
+
+impl<'a> Simulation<'a> {
+    fn new(sandbox: &'a Sandbox, server: &'a MediaServer) -> Self {
+        Simulation {
+            sandbox,
+            server,
+            ports: HashMap::new(),
+        }
+    }
+
+    fn play(mut self, fixture: &Fixture) -> Result<(), String> {
+        let played = fixture
+            .steps
+            .iter()
+            .enumerate()
+            .try_for_each(|(index, step)| self.step(step).map_err(|error| format!("step {}: {}", index + 1, error)));
+        // Hosts are shut down and checked even when a step failed
+        let finished = self.finish(&fixture.exit_codes);
+        played.and(finished)
+    }
+
+    fn step(&mut self, step: &Step) -> Result<(), String> {
+        if let Some(message) = &step.send {
+            for index in 0..step.repeat {
+                let message = self.fill(message, index);
+                self.write(&step.port, message.to_string().as_bytes(), None)?;
+            }
+        }
+        if let Some(raw) = &step.send_raw {
+            self.write(&step.port, raw.body.as_bytes(), raw.length)?;
+        }
+        if step.close {
+            self.port(&step.port)?.stdin = None;
+        }
+        if let Some(sleep_ms) = step.sleep_ms {
+            thread::sleep(Duration::from_millis(sleep_ms));
+        }
+        if let Some(expected) = &step.expect {
+            let timeout = step.timeout_ms.map_or(DEFAULT_STEP_TIMEOUT, Duration::from_millis);
+            let expected = self.fill(&Value::Object(expected.clone()), 0);
+            self.expect(&step.port, expected.as_object().unwrap_or(&Map::new()), step.count, timeout)?;
+        }
+        if let Some(expected) = &step.expect_origin {
+            self.expect_origin(expected)?;
+        }
+        Ok(())
+    }
+
+    fn expect_origin(&self, expected: &OriginExpectation) -> Result<(), String> {
+        let file = PathBuf::from(self.fill_text(&expected.file, 0));
+        let found = match origin_tag::read(&file) {
+            Ok(found) => found,
+            // The temp folder's file system keeps no marks, so there is nothing to check
+            Err(error) if error.kind() == io::ErrorKind::Unsupported => {
+                report(&format!("skip origin check of {}: {}", file.display(), error));
+                return Ok(());
+            }
+            Err(error) => return Err(format!("Failed to read the origin of {}: {}", file.display(), error)),
+        };
+        let mut tag = expected
+            .tag
+            .as_ref()
+            .and_then(|tag| self.fill(&Value::Object(tag.clone()), 0).as_object().cloned());
+        if let Some(tag) = tag.as_mut().filter(|_| !cfg!(target_os = "windows")) {
+            tag.remove("zoneId");
+        }
+        let actual = found.as_ref().map(|found| json!(found));
+        let matched = match (&actual, &tag) {
+            (Some(actual), Some(tag)) => matches(actual, tag),
+            (None, None) => true,
+            _ => false,
+        };
+        if matched {
+            Ok(())
+        } else {
+            Err(format!(
+                "expected {} to be marked with {}, found {}",
+                file.display(),
+                tag.map_or(Value::Null, Value::Object),
+                actual.unwrap_or(Value::Null)
+            ))
+        }
+    }
+
+    // Inbound frames go out in order. Before each one, the answers recorded
+    // ahead of it must have come back, so it meets the state it met in the
+    // session; their order among themselves is not compared.
+    fn replay(mut self, entries: &[Entry]) -> Result<usize, String> {
+        let mut divergences = Vec::new();
+        let mut recorded = Vec::new();
+        let mut compared = 0;
+        for (index, entry) in entries.iter().enumerate() {
+            match entry.direction {
+                Direction::Out => recorded.extend(entry.frame.as_ref().and_then(outcome)),
+                Direction::In => {
+                    compared += recorded.len();
+                    divergences.extend(self.await_recorded(recorded.drain(..), index));
+                    match (&entry.frame, &entry.invalid) {
+                        (Some(Value::String(text)), _) => self.write(DEFAULT_PORT, text.as_bytes(), None)?,
+                        (Some(message), _) => {
+                            let message = self.for_replay(message);
+                            self.write(DEFAULT_PORT, message.to_string().as_bytes(), None)?;
+                        }
+                        // Bytes that were not UTF-8 are not in the capture
+                        (None, invalid) => report(&format!(
+                            "     line {}: skipped an unreadable frame ({})",
+                            index + 2,
+                            invalid.as_deref().unwrap_or("no reason recorded")
+                        )),
+                    }
+                }
+            }
+        }
+        compared += recorded.len();
+        divergences.extend(self.await_recorded(recorded.drain(..), entries.len()));
+        if let Some(port) = self.ports.get(DEFAULT_PORT) {
+            divergences.extend(
+                port.unmatched
+                    .iter()
+                    .filter(|frame| outcome(frame).is_some())
+                    .map(|frame| format!("not in the capture: {}", summarize(std::slice::from_ref(frame)))),
+            );
+        }
+        let finished = self.finish(&HashMap::new());
+        if divergences.is_empty() {
+            finished.map(|_| compared)
+        } else {
+            Err(divergences.into_iter().chain(finished.err()).collect::<Vec<_>>().join("; "))
+        }
+    }
+
+    // Each missing answer, as the line of the capture it was due before
+    fn await_recorded(&mut self, recorded: impl Iterator<Item = Map<String, Value>>, index: usize) -> Vec<String> {
+        recorded
+            .filter_map(|expected| {
+                self.expect(DEFAULT_PORT, &expected, 1, REPLAY_FRAME_TIMEOUT).err().map(|_| {
+                    format!(
+                        "missing before line {}: {}",
+                        index + 2,
+                        summarize(&[Value::Object(expected)])
+                    )
+                })
+            })
+            .collect()
+    }
+
+    // The recorded message pointed at the sandbox: downloads keep their file
+    // name but land in its folder, and run through the stub whatever backend
+    // the session picked, so nothing leaves the machine
+    fn for_replay(&self, message: &Value) -> Value {
+        let mut message = message.clone();
+        let Some(fields) = message.as_object_mut() else {
+            return message;
+        };
+        if fields.get("action").and_then(Value::as_str) != Some("download") {
+            return message;
+        }
+        if let Some(output_path) = fields.get("output_path").and_then(Value::as_str) {
+            let output_path = match Path::new(output_path).file_name() {
+                Some(name) => self.sandbox.output.join(name),
+                None => self.sandbox.output.clone(),
+            };
+            fields.insert("output_path".to_string(), Value::String(output_path.to_string_lossy().into_owned()));
+        }
+        if fields.get("backend").and_then(Value::as_str) != Some("fake") {
+            fields.insert("backend".to_string(), Value::String("yt_dlp".to_string()));
+        }
+        message
+    }
+
+    // {{server}}, {{output}} and {{i}} in every string of the message
+    fn fill(&self, message: &Value, index: usize) -> Value {
+        match message {
+            Value::String(text) => Value::String(self.fill_text(text, index)),
+            Value::Array(items) => Value::Array(items.iter().map(|item| self.fill(item, index)).collect()),
+            Value::Object(fields) => Value::Object(
+                fields
+                    .iter()
+                    .map(|(key, value)| (key.clone(), self.fill(value, index)))
+                    .collect(),
+            ),
+            other => other.clone(),
+        }
+    }
+
+    fn fill_text(&self, text: &str, index: usize) -> String {
+        text.replace("{{server}}", &self.server.url)
+            .replace("{{output}}", &self.sandbox.output.to_string_lossy())
+            .replace("{{i}}", &index.to_string())
+    }
+
+    fn port(&mut self, name: &str) -> Result<&mut Port, String> {
+        if !self.ports.contains_key(name) {
+            let mut child = self.sandbox.spawn_host()?;
+            let stdin = child.stdin.take();
+            let stdout = child.stdout.take().ok_or("Failed to capture the host's stdout")?;
+            let (frame_tx, frames) = mpsc::channel();
+            thread::spawn(move || read_frames(stdout, frame_tx));
+            self.ports.insert(
+                name.to_string(),
+                Port {
+                    child,
+                    stdin,
+                    frames,
+                    unmatched: Vec::new(),
+                },
+            );
+        }
+        Ok(self.ports.get_mut(name).expect("port was just opened"))
+    }
+
+    fn write(&mut self, port_name: &str, body: &[u8], length: Option<u32>) -> Result<(), String> {
+        let port = self.port(port_name)?;
+        let stdin = port
+            .stdin
+            .as_mut()
+            .ok_or_else(|| format!("port {} is already closed", port_name))?;
+        let length = length.unwrap_or(body.len() as u32);
+        stdin
+            .write_all(&length.to_le_bytes())
+            .and_then(|_| stdin.write_all(body))
+            .and_then(|_| stdin.flush())
+            .map_err(|e| format!("Failed to write to port {}: {}", port_name, e))
+    }
+
+    fn expect(&mut self, port_name: &str, expected: &Map<String, Value>, count: usize, timeout: Duration) -> Result<(), String> {
+        let port = self.port(port_name)?;
+        let mut matched = 0;
+        port.unmatched.retain(|frame| {
+            if matched < count && matches(frame, expected) {
+                matched += 1;
+                false
+            } else {
+                true
+            }
+        });
+
+        let deadline = Instant::now() + timeout;
+        while matched < count {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match port.frames.recv_timeout(remaining) {
+                Ok(Ok(frame)) if matches(&frame, expected) => matched += 1,
+                Ok(Ok(frame)) => port.unmatched.push(frame),
+                Ok(Err(error)) => return Err(format!("port {}: {}", port_name, error)),
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                    let ended = if Instant::now() < deadline { "the port closed" } else { "timed out" };
+                    return Err(format!(
+                        "expected {} frame(s) like {} on port {}, {} after {}; other frames: {}",
+                        count,
+                        Value::Object(expected.clone()),
+                        port_name,
+                        ended,
+                        matched,
+                        summarize(&port.unmatched)
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Closes every port, waits for the hosts to exit with the expected codes,
+    // checks the frames they sent last and looks for orphaned children
+    fn finish(&mut self, exit_codes: &HashMap<String, i32>) -> Result<(), String> {
+        let mut problems = Vec::new();
+        for port in self.ports.values_mut() {
+            port.stdin = None;
+        }
+        for (name, port) in &mut self.ports {
+            match wait_with_timeout(&mut port.child, EXIT_TIMEOUT) {
+                Some(status) => {
+                    let expected = exit_codes.get(name).copied().unwrap_or(EXIT_SUCCESS);
+                    if status.code() != Some(expected) {
+                        problems.push(format!("port {} exited with {} instead of {}", name, status, expected));
+                    }
+                }
+                None => {
+                    let _ = port.child.kill();
+                    let _ = port.child.wait();
+                    problems.push(format!("port {} did not exit within {}s of closing", name, EXIT_TIMEOUT.as_secs()));
+                }
+            }
+            // The reader ends at the host's exit, so this drains it
+            for frame in port.frames.iter() {
+                if let Err(error) = frame {
+                    problems.push(format!("port {}: {}", name, error));
+                }
+            }
+        }
+
+        let orphans = self.sandbox.orphans();
+        if !orphans.is_empty() {
+            problems.push(format!("children left running after the hosts exited: {:?}", orphans));
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("; "))
+        }
+    }
+}
+
+// Reads frames as Chrome does and checks each against the response schema
+fn read_frames(mut stdout: impl Read, frames: Sender<Result<Value, String>>) {
+    loop {
+        let mut length_bytes = [0u8; 4];
+        match stdout.read_exact(&mut length_bytes) {
+            Ok(()) => {}
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return,
+            Err(error) => {
+                let _ = frames.send(Err(format!("Failed to read a frame: {}", error)));
+                return;
+            }
+        }
+        let length = u32::from_le_bytes(length_bytes) as usize;
+        if length > MAX_FRAME_BYTES {
+            // Chrome would close the port here
+            let _ = frames.send(Err(format!("frame of {} bytes is over Chrome's limit", length)));
+            return;
+        }
+        let mut body = vec![0u8; length];
+        if let Err(error) = stdout.read_exact(&mut body) {
+            let _ = frames.send(Err(format!("frame cut off after its length: {}", error)));
+            return;
+        }
+        let checked = serde_json::from_slice::<Value>(&body)
+            .map_err(|e| format!("frame is not JSON: {}", e))
+            .and_then(|frame| check_schema(&frame).map(|_| frame));
+        if frames.send(checked).is_err() {
+            return;
+        }
+    }
+}
+
+// The shape of NativeResponse as frame_limit sends it
+fn check_schema(frame: &Value) -> Result<(), String> {
+    let Value::Object(fields) = frame else {
+        return Err(format!("frame is not a JSON object: {}", frame));
+    };
+    if !fields.get("success").is_some_and(Value::is_boolean) {
+        return Err(format!("frame has no boolean success: {}", frame));
+    }
+    for (field, value) in fields {
+        let valid = match field.as_str() {
+            "success" | "data" => true,
+            // Every code the host sends has an English catalog entry
+            "error_code" => value.as_str().is_some_and(|code| i18n::error_codes().any(|known| known == code)),
+            "truncated" | "more" => value.is_boolean(),
+            "stream" => value.is_null() || matches!(value.as_str(), Some("stdout" | "stderr")),
+            field if STRING_FIELDS.contains(&field) => value.is_string() || value.is_null(),
+            _ => return Err(format!("frame has an unknown field {}: {}", field, frame)),
+        };
+        if !valid {
+            return Err(format!("frame has an invalid {}: {}", field, frame));
+        }
+    }
+    Ok(())
+}
+
+// Whether every expected field is in the frame with the same value; nested
+// objects are compared the same way
+fn matches(frame: &Value, expected: &Map<String, Value>) -> bool {
+    expected.iter().all(|(field, value)| match (frame.get(field), value) {
+        (Some(actual @ Value::Object(_)), Value::Object(expected)) => matches(actual, expected),
+        (Some(actual), value) => actual == value,
+        (None, _) => false,
+    })
+}
+
+// What a replay compares of an answer: which event it is, for which request,
+// and whether and how it failed. None for frames it does not compare.
+fn outcome(frame: &Value) -> Option<Map<String, Value>> {
+    let event = frame.get("event").cloned().unwrap_or(Value::Null);
+    if event.as_str().is_some_and(|event| REPLAY_IGNORED_EVENTS.contains(&event))
+        || frame.get("more").and_then(Value::as_bool) == Some(true)
+    {
+        return None;
+    }
+    let mut outcome = Map::new();
+    outcome.insert("event".to_string(), event);
+    for field in ["requestId", "success", "error_code"] {
+        if let Some(value) = frame.get(field) {
+            outcome.insert(field.to_string(), value.clone());
+        }
+    }
+    Some(outcome)
+}
+
+// The unmatched frames for an error message, without their output tails
+fn summarize(frames: &[Value]) -> String {
+    if frames.is_empty() {
+        return "none".to_string();
+    }
+    frames
+        .iter()
+        .map(|frame| {
+            format!(
+                "{}/{}/{}",
+                frame.get("event").and_then(Value::as_str).unwrap_or("-"),
+                frame.get("requestId").and_then(Value::as_str).unwrap_or("-"),
+                frame.get("success").and_then(Value::as_bool).unwrap_or_default()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn wait_with_timeout(child: &mut Child, timeout: Duration) -> Option<ExitStatus> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Some(status),
+            Ok(None) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+            Ok(None) | Err(_) => return None,
+        }
+    }
+}
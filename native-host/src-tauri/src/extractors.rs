@@ -0,0 +1,195 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+use crate::diagnostics::hide_console_window;
+use crate::settings::Settings;
+use crate::{current_timestamp_millis, get_app_data_directory, tool_integrity, url_host};
+
+const CACHE_FILE_NAME: &str = "yt-dlp-extractors.json";
+// --list-extractors takes a second or two; a hung yt-dlp must not hold up routing
+const LIST_TIMEOUT: Duration = Duration::from_secs(30);
+// How long a process trusts the cache before asking yt-dlp for its version again
+const VERSION_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Direct links to these are never media, so on a site without an extractor
+// of its own yt-dlp's generic extractor has nothing to find
+const NON_MEDIA_EXTENSIONS: &[&str] = &[
+    "pdf", "zip", "rar", "7z", "tar", "gz", "exe", "msi", "dmg", "iso", "apk", "txt", "csv", "doc", "docx", "xls", "xlsx",
+    "ppt", "pptx",
+];
+
+// The extractors of one yt-dlp version, as --list-extractors printed them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtractorCache {
+    pub yt_dlp_version: String,
+    pub refreshed_at: i64,
+    pub extractors: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtractorCacheStatus {
+    pub yt_dlp_version: String,
+    pub refreshed_at: i64,
+    pub extractor_count: usize,
+}
+
+impl ExtractorCache {
+    pub fn status(&self) -> ExtractorCacheStatus {
+        ExtractorCacheStatus {
+            yt_dlp_version: self.yt_dlp_version.clone(),
+            refreshed_at: self.refreshed_at,
+            extractor_count: self.extractors.len(),
+        }
+    }
+
+    // Whether an extractor is named after one of the host's labels, e.g.
+    // "vimeo" or "vimeo:album" for player.vimeo.com. "generic" takes any
+    // URL, so it does not count.
+    fn has_site_extractor(&self, host: &str) -> bool {
+        let labels = host.split('.').filter(|label| *label != "www").collect::<Vec<_>>();
+        let site_labels = &labels[..labels.len().saturating_sub(1)];
+        self.extractors.iter().any(|extractor| {
+            let name = extractor.split(':').next().unwrap_or(extractor).to_ascii_lowercase();
+            name != "generic" && site_labels.contains(&name.as_str())
+        })
+    }
+}
+
+// What this process has loaded, and when the yt-dlp version was last compared
+static LOADED: Mutex<Option<(Arc<ExtractorCache>, Instant)>> = Mutex::new(None);
+
+fn cache_path() -> Result<PathBuf, String> {
+    Ok(get_app_data_directory()?.join(CACHE_FILE_NAME))
+}
+
+// The cache file as it is, without asking yt-dlp anything; for diagnostics
+pub fn read_cache() -> Result<Option<ExtractorCache>, String> {
+    let path = cache_path()?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    serde_json::from_str(&contents)
+        .map(Some)
+        .map_err(|e| format!("Extractor cache {} is corrupted: {}", path.display(), e))
+}
+
+// Asks yt-dlp for its version and extractors and rewrites the cache
+pub fn refresh(settings: &Settings) -> Result<ExtractorCache, String> {
+    let version = run_yt_dlp(settings, "--version")?.trim().to_string();
+    let extractors = run_yt_dlp(settings, "--list-extractors")?
+        .lines()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>();
+    if extractors.is_empty() {
+        return Err("yt-dlp listed no extractors".to_string());
+    }
+    let cache = ExtractorCache {
+        yt_dlp_version: version,
+        refreshed_at: current_timestamp_millis(),
+        extractors,
+    };
+
+    let path = cache_path()?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    let contents = serde_json::to_string(&cache).map_err(|e| format!("Failed to serialize extractor cache: {}", e))?;
+    fs::write(&path, contents).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    info!(version = %cache.yt_dlp_version, extractors = cache.extractors.len(), "Refreshed yt-dlp extractor cache");
+
+    *LOADED.lock().unwrap() = Some((Arc::new(cache.clone()), Instant::now()));
+    Ok(cache)
+}
+
+// The cache for the installed yt-dlp, refreshed when it is missing, corrupted
+// or from another version. None when yt-dlp cannot be asked.
+fn current(settings: &Settings) -> Option<Arc<ExtractorCache>> {
+    if let Some((cache, checked)) = LOADED.lock().unwrap().as_ref() {
+        if checked.elapsed() < VERSION_CHECK_INTERVAL {
+            return Some(Arc::clone(cache));
+        }
+    }
+
+    let stored = read_cache().unwrap_or_else(|error| {
+        warn!("{}; regenerating it", error);
+        None
+    });
+    let version = match run_yt_dlp(settings, "--version") {
+        Ok(version) => version.trim().to_string(),
+        Err(error) => {
+            debug!("Not checking the extractor cache: {}", error);
+            return None;
+        }
+    };
+    match stored {
+        Some(cache) if cache.yt_dlp_version == version => {
+            let cache = Arc::new(cache);
+            *LOADED.lock().unwrap() = Some((Arc::clone(&cache), Instant::now()));
+            Some(cache)
+        }
+        _ => match refresh(settings) {
+            Ok(cache) => Some(Arc::new(cache)),
+            Err(error) => {
+                warn!("Failed to refresh the yt-dlp extractor cache: {}", error);
+                None
+            }
+        },
+    }
+}
+
+// Turns away a direct link to a document or archive on a site yt-dlp has no
+// extractor for, before yt-dlp is spawned for it. Anything else is left to
+// yt-dlp, and so is everything while the cache is unavailable.
+pub fn check(settings: &Settings, url: &str) -> Result<(), String> {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let file_name = path.rsplit('/').next().unwrap_or("");
+    let non_media = file_name
+        .rsplit_once('.')
+        .is_some_and(|(_, extension)| NON_MEDIA_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()));
+    let Some(host) = url_host(url).filter(|_| non_media) else {
+        return Ok(());
+    };
+    match current(settings) {
+        Some(cache) if !cache.has_site_extractor(&host) => {
+            Err(format!("Unsupported URL: yt-dlp has no extractor for {} and {} is not media", host, file_name))
+        }
+        _ => Ok(()),
+    }
+}
+
+// stdout of `yt-dlp <argument>`, given up on after LIST_TIMEOUT
+fn run_yt_dlp(settings: &Settings, argument: &str) -> Result<String, String> {
+    tool_integrity::verify(settings.yt_dlp_program())?;
+    let mut command = Command::new(settings.yt_dlp_program());
+    command.arg(argument).stdout(Stdio::piped()).stderr(Stdio::null());
+    hide_console_window(&mut command);
+    let mut child = command.spawn().map_err(|e| format!("Failed to execute yt-dlp: {}", e))?;
+    let mut stdout = child.stdout.take().ok_or("Failed to open yt-dlp stdout")?;
+
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut output = String::new();
+        let _ = tx.send(stdout.read_to_string(&mut output).map(|_| output));
+    });
+    let output = rx.recv_timeout(LIST_TIMEOUT);
+    if output.is_err() {
+        let _ = child.kill();
+    }
+    let status = child.wait().map_err(|e| format!("Failed to wait for yt-dlp: {}", e))?;
+    match output {
+        Ok(Ok(output)) if status.success() => Ok(output),
+        Ok(Ok(_)) => Err(format!("yt-dlp {} returned exit code {:?}", argument, status.code())),
+        Ok(Err(error)) => Err(format!("Failed to read yt-dlp output: {}", error)),
+        Err(_) => Err(format!("yt-dlp {} did not finish within {} seconds", argument, LIST_TIMEOUT.as_secs())),
+    }
+}
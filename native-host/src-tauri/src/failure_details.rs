@@ -0,0 +1,117 @@
+use serde::Serialize;
+use std::process::{Command, ExitStatus};
+use tracing::{debug, warn};
+
+use crate::redact;
+use crate::settings::Settings;
+use crate::source_page::truncate_at_char_boundary;
+
+pub const MAX_STDERR_TAIL_LINES: usize = 200;
+// Options followed by a credentials file or a secret
+const REDACTED_VALUE_OPTIONS: &[&str] = &[
+    "--cookies",
+    "--netrc-location",
+    "--username",
+    "--password",
+    "--video-password",
+    "--proxy",
+];
+// Options followed by "Name:Value"; the name stays readable
+const HEADER_OPTIONS: &[&str] = &["--add-header", "--add-headers"];
+const REDACTED: &str = "<redacted>";
+// Verbose yt-dlp output can print whole HTTP headers on one line; with 200
+// lines of this the response stays far below Chrome's 1 MB message limit
+const MAX_LINE_BYTES: usize = 1000;
+
+// What the extension and the log get when yt-dlp exits with an error
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FailureDetails {
+    pub argv: Vec<String>,
+    pub exit_code: Option<i32>,
+    // Last failure_stderr_lines lines of stderr; empty when that is 0
+    pub stderr_tail: Vec<String>,
+}
+
+impl FailureDetails {
+    pub fn new(command: &Command, status: ExitStatus, stderr_text: &str, settings: &Settings) -> Self {
+        let (argv, redacted) = sanitize(command);
+        // --verbose echoes the command line, redacted values included
+        let mut stderr_tail = stderr_text
+            .lines()
+            .map(str::trim_end)
+            .filter(|line| !line.is_empty())
+            .rev()
+            .take(settings.failure_stderr_lines)
+            .map(|line| {
+                let line = redacted
+                    .iter()
+                    .fold(line.to_string(), |line, value| line.replace(value.as_str(), REDACTED));
+                truncate_line(&redact::redact(&line))
+            })
+            .collect::<Vec<_>>();
+        stderr_tail.reverse();
+        FailureDetails {
+            argv,
+            exit_code: status.code(),
+            stderr_tail,
+        }
+    }
+
+    pub fn log(&self, job_id: &str) {
+        warn!(
+            job_id,
+            exit_code = ?self.exit_code,
+            argv = %self.argv.join(" "),
+            "yt-dlp failed{}{}",
+            if self.stderr_tail.is_empty() { "" } else { ":\n" },
+            self.stderr_tail.join("\n")
+        );
+    }
+}
+
+pub fn log_success(job_id: &str, command: &Command) {
+    debug!(job_id, argv = %sanitize(command).0.join(" "), "yt-dlp succeeded");
+}
+
+// The program and arguments as they were run, with cookie and netrc paths,
+// credentials, header values and denylisted URL parameters replaced.
+// Also returns the replaced values.
+fn sanitize(command: &Command) -> (Vec<String>, Vec<String>) {
+    let mut argv = vec![command.get_program().to_string_lossy().into_owned()];
+    let mut redacted = Vec::new();
+    let mut previous: Option<String> = None;
+    for arg in command.get_args() {
+        let arg = arg.to_string_lossy();
+        let sanitized = match previous.as_deref() {
+            Some(option) if REDACTED_VALUE_OPTIONS.contains(&option) => {
+                redacted.push(arg.to_string());
+                REDACTED.to_string()
+            }
+            Some(option) if HEADER_OPTIONS.contains(&option) => match arg.split_once(':') {
+                Some((name, value)) => {
+                    redacted.push(value.trim().to_string());
+                    format!("{}:{}", name, REDACTED)
+                }
+                None => {
+                    redacted.push(arg.to_string());
+                    REDACTED.to_string()
+                }
+            },
+            _ => arg.to_string(),
+        };
+        argv.push(truncate_line(&redact::redact(&sanitized)));
+        previous = Some(arg.into_owned());
+    }
+    redacted.retain(|value| !value.is_empty());
+    (argv, redacted)
+}
+
+fn truncate_line(line: &str) -> String {
+    let truncated = truncate_at_char_boundary(line, MAX_LINE_BYTES);
+    if truncated.len() < line.len() {
+        format!("{}…", truncated)
+    } else {
+        truncated.to_string()
+    }
+}
@@ -0,0 +1,210 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::deep_link::percent_decode;
+use crate::image_fetch::{fill_template, format_size};
+
+// Hidden command line subcommand the fake backend runs as its child
+pub const SUBCOMMAND: &str = "fake-download";
+// Set to 1 to send every download to the fake backend, as the
+// fake_downloads setting does
+pub const ENV_VAR: &str = "IMGVAULT_FAKE_YTDLP";
+const DEFAULT_SECONDS: f64 = 2.0;
+const DEFAULT_SIZE: u64 = 1024 * 1024;
+const DEFAULT_FILES: usize = 3;
+const DEFAULT_LINES: usize = 20_000;
+const DEFAULT_STDERR: &str = "ERROR: [fake] Simulated failure";
+// Progress lines per file, spread over its share of the run
+const PROGRESS_STEPS: u64 = 20;
+// Long enough for any stall or first-output timeout to fire first
+const STALL_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+const HUGE_LINE_BYTES: usize = 200;
+const MAX_FILES: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scenario {
+    Success,
+    Fail,
+    Stall,
+    HugeOutput,
+    MultiFile,
+}
+
+// What to play, from the query string of a URL such as
+// https://fake.test/?scenario=multi_file&files=5&size=2097152&seconds=4
+//
+//   scenario  success (default), fail, stall, huge_output or multi_file
+//   seconds   how long the transfer takes; 2 by default
+//   size      bytes written per file; 1 MiB by default
+//   files     files of a multi_file run; 3 by default
+//   stderr    what a fail run prints, one line per %0A
+//   lines     stdout lines of a huge_output run; 20000 by default
+struct Plan {
+    scenario: Scenario,
+    seconds: f64,
+    size: u64,
+    files: usize,
+    stderr: String,
+    lines: usize,
+}
+
+pub fn enabled() -> bool {
+    env::var(ENV_VAR).is_ok_and(|value| value == "1")
+}
+
+// Plays the URL's scenario, printing what yt-dlp would with --newline and
+// the saved paths on plain stdout lines. Returns the process exit code.
+pub fn run(url: &str, output: &str, cookies: Option<&Path>) -> i32 {
+    let plan = match parse_plan(url) {
+        Ok(plan) => plan,
+        Err(error) => {
+            let _ = writeln!(io::stderr().lock(), "ERROR: [fake] {}", error);
+            return 2;
+        }
+    };
+    let mut stdout = io::stdout().lock();
+    let _ = writeln!(stdout, "[fake] Extracting URL: {}", url);
+    if let Some(cookies) = cookies {
+        let _ = writeln!(stdout, "[fake] Would use cookies from {}", cookies.display());
+    }
+
+    let result = match plan.scenario {
+        Scenario::Success => write_files(&plan, output, 1, &mut stdout),
+        Scenario::MultiFile => write_files(&plan, output, plan.files, &mut stdout),
+        Scenario::HugeOutput => print_huge_output(&plan, &mut stdout).and_then(|_| write_files(&plan, output, 1, &mut stdout)),
+        Scenario::Fail => {
+            let _ = writeln!(io::stderr().lock(), "{}", plan.stderr);
+            return 1;
+        }
+        Scenario::Stall => {
+            let _ = writeln!(stdout, "[info] fake: Downloading 1 format(s): 0");
+            let path = output_file(output, 1, 1);
+            let _ = writeln!(stdout, "[download] Destination: {}", path.display());
+            let _ = writeln!(stdout, "[download]   0.0% of {} at 0.00B/s ETA Unknown", format_size(plan.size));
+            let _ = stdout.flush();
+            thread::sleep(STALL_DURATION);
+            Ok(())
+        }
+    };
+    match result {
+        Ok(()) => 0,
+        Err(error) => {
+            let _ = writeln!(io::stderr().lock(), "ERROR: [fake] {}", error);
+            1
+        }
+    }
+}
+
+fn parse_plan(url: &str) -> Result<Plan, String> {
+    let mut plan = Plan {
+        scenario: Scenario::Success,
+        seconds: DEFAULT_SECONDS,
+        size: DEFAULT_SIZE,
+        files: DEFAULT_FILES,
+        stderr: DEFAULT_STDERR.to_string(),
+        lines: DEFAULT_LINES,
+    };
+    let query = url.split_once('?').map(|(_, query)| query).unwrap_or("");
+    let query = query.split('#').next().unwrap_or("");
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = percent_decode(value).map_err(|e| format!("Invalid {} parameter: {}", name, e))?;
+        match name {
+            "scenario" => {
+                plan.scenario = match value.as_str() {
+                    "success" => Scenario::Success,
+                    "fail" => Scenario::Fail,
+                    "stall" => Scenario::Stall,
+                    "huge_output" => Scenario::HugeOutput,
+                    "multi_file" => Scenario::MultiFile,
+                    other => return Err(format!("Unknown scenario {}", other)),
+                }
+            }
+            "seconds" => plan.seconds = parse_number(name, &value)?,
+            "size" => plan.size = parse_number(name, &value)?,
+            "files" => plan.files = parse_number::<usize>(name, &value)?.clamp(1, MAX_FILES),
+            "stderr" => plan.stderr = value,
+            "lines" => plan.lines = parse_number(name, &value)?,
+            // Extra parameters keep URLs distinct, e.g. for the download archive
+            _ => {}
+        }
+    }
+    Ok(plan)
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid {} parameter: {}", name, value))
+}
+
+// Writes each file over its share of the run, with the progress lines and
+// final paths yt-dlp would print
+fn write_files(plan: &Plan, output: &str, files: usize, stdout: &mut impl Write) -> Result<(), String> {
+    let _ = writeln!(stdout, "[info] fake: Downloading 1 format(s): 0");
+    let step_delay = Duration::from_secs_f64(plan.seconds.max(0.0) / files as f64 / PROGRESS_STEPS as f64);
+    let mut paths = Vec::with_capacity(files);
+    for index in 1..=files {
+        let path = output_file(output, index, files);
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+        }
+        let _ = writeln!(stdout, "[download] Destination: {}", path.display());
+        let mut file = File::create(&path).map_err(|e| format!("Unable to open for writing {}: {}", path.display(), e))?;
+        let chunk = vec![0u8; (plan.size / PROGRESS_STEPS + 1) as usize];
+        let mut written = 0u64;
+        for step in 1..=PROGRESS_STEPS {
+            let target = plan.size * step / PROGRESS_STEPS;
+            file.write_all(&chunk[..(target - written) as usize])
+                .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+            written = target;
+            thread::sleep(step_delay);
+            let speed = (plan.size as f64 / plan.seconds.max(0.001) * files as f64) as u64;
+            let eta_secs = (PROGRESS_STEPS - step) * step_delay.as_millis() as u64 / 1000;
+            let _ = writeln!(
+                stdout,
+                "[download] {:5.1}% of {} at {}/s ETA {:02}:{:02}",
+                step as f64 * 100.0 / PROGRESS_STEPS as f64,
+                format_size(plan.size),
+                format_size(speed),
+                eta_secs / 60,
+                eta_secs % 60
+            );
+            let _ = stdout.flush();
+        }
+        paths.push(path);
+    }
+    for path in &paths {
+        let _ = writeln!(stdout, "{}", path.display());
+    }
+    Ok(())
+}
+
+// Long stdout lines, for the frame size limits and output tails
+fn print_huge_output(plan: &Plan, stdout: &mut impl Write) -> Result<(), String> {
+    let filler = "x".repeat(HUGE_LINE_BYTES);
+    for index in 0..plan.lines {
+        writeln!(stdout, "[fake] Line {} {}", index, filler).map_err(|e| format!("Failed to print output: {}", e))?;
+    }
+    Ok(())
+}
+
+// File `index` of `files`, from the output template as yt-dlp would fill it
+fn output_file(output: &str, index: usize, files: usize) -> PathBuf {
+    if output.contains("%(") {
+        let title = if files == 1 { "fake".to_string() } else { format!("fake-{}", index) };
+        return PathBuf::from(fill_template(output, &title, &format!("fake{}", index), "mkv"));
+    }
+    let path = Path::new(output);
+    if index == 1 {
+        return path.to_path_buf();
+    }
+    // A fixed path names the first file; the others get a suffix
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    let extension = path.extension().map(|extension| format!(".{}", extension.to_string_lossy())).unwrap_or_default();
+    path.with_file_name(format!("{}-{}{}", stem, index, extension))
+}
@@ -0,0 +1,59 @@
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use crate::get_app_data_directory;
+
+const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+// Held while reading and rewriting a state file in the app data directory that
+// native host processes change as well as the GUI. Released when dropped. The
+// lock belongs to the operating system, so it goes with a holder that dies and
+// two processes can never both hold it. The file itself stays: deleting it
+// would let a waiter lock a file no one else can see any more.
+pub struct FileLock {
+    _file: File,
+}
+
+// `what` names the guarded state in errors, e.g. "scheduled downloads"
+pub fn acquire(lock_file_name: &str, what: &str) -> Result<FileLock, String> {
+    let file = open(lock_file_name, what)?;
+    let deadline = Instant::now() + LOCK_TIMEOUT;
+    loop {
+        match file.try_lock() {
+            Ok(()) => break,
+            Err(TryLockError::WouldBlock) if Instant::now() < deadline => std::thread::sleep(RETRY_INTERVAL),
+            Err(TryLockError::WouldBlock) => return Err(format!("Timed out waiting for the {} lock", what)),
+            Err(TryLockError::Error(error)) => return Err(format!("Failed to lock {}: {}", what, error)),
+        }
+    }
+    Ok(held(file))
+}
+
+// None right away when another process holds the lock
+pub fn try_acquire(lock_file_name: &str, what: &str) -> Result<Option<FileLock>, String> {
+    let file = open(lock_file_name, what)?;
+    match file.try_lock() {
+        Ok(()) => Ok(Some(held(file))),
+        Err(TryLockError::WouldBlock) => Ok(None),
+        Err(TryLockError::Error(error)) => Err(format!("Failed to lock {}: {}", what, error)),
+    }
+}
+
+fn open(lock_file_name: &str, what: &str) -> Result<File, String> {
+    let directory = get_app_data_directory()?;
+    fs::create_dir_all(&directory).map_err(|e| format!("Failed to create app data directory: {}", e))?;
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(directory.join(lock_file_name))
+        .map_err(|e| format!("Failed to lock {}: {}", what, e))
+}
+
+fn held(mut file: File) -> FileLock {
+    // Only for a look at who holds it; the lock works without
+    let _ = file.set_len(0).and_then(|()| write!(file, "{}", std::process::id()));
+    FileLock { _file: file }
+}
@@ -0,0 +1,308 @@
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tauri::{AppHandle, Manager};
+use tracing::{debug, info, warn};
+
+use crate::drop_import::{self, MEDIA_EXTENSIONS};
+use crate::history::{DownloadStatus, History, NewHistoryEntry};
+use crate::image_fetch::fill_template;
+use crate::long_path;
+use crate::queue::generate_job_id;
+use crate::settings::Settings;
+use crate::webhooks::hash_file;
+use crate::{current_timestamp_millis, get_vault_directory};
+
+pub const IMPORT_PROGRESS_EVENT: &str = "folder-import-progress";
+const SOURCE: &str = "import";
+// Enough for every signature below
+const MAGIC_BYTES: usize = 16;
+// Hash characters used as %(id)s when a moved file is named by the template
+const TEMPLATE_ID_LENGTH: usize = 12;
+
+// Counts for an import, sent after every file and returned at the end
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderImportSummary {
+    // Files found under the folder
+    pub total: usize,
+    pub processed: usize,
+    pub imported: usize,
+    // Same contents as a file imported before, in this run or an earlier one
+    pub skipped_duplicates: usize,
+    // Not a known media format
+    pub skipped_not_media: usize,
+    // Could not be read, hashed, moved or recorded; the log has the reason
+    pub unreadable: usize,
+    // The file being looked at, while the import runs
+    pub current: Option<String>,
+    pub cancelled: bool,
+}
+
+// One folder import at a time; cancel stops it between two files
+#[derive(Clone, Default)]
+pub struct FolderImport {
+    running: Arc<AtomicBool>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl FolderImport {
+    pub fn cancel(&self) -> bool {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.running.load(Ordering::SeqCst)
+    }
+
+    // Walks `path`, recording every media file in history with source
+    // "import". Files are identified by their first bytes, not their names.
+    // With `move_files` they are moved into the vault under the output
+    // template; otherwise they stay where they are and are only indexed.
+    // Files whose contents were imported before are skipped, so an
+    // interrupted import can simply be started again.
+    pub fn run(
+        &self,
+        app: &AppHandle,
+        history: &History,
+        settings: &Settings,
+        path: &Path,
+        move_files: bool,
+        recursive: bool,
+    ) -> Result<FolderImportSummary, String> {
+        if !path.is_dir() {
+            return Err(format!("{} is not a folder", path.display()));
+        }
+        if self.running.swap(true, Ordering::SeqCst) {
+            return Err("A folder import is already running".to_string());
+        }
+        self.cancelled.store(false, Ordering::SeqCst);
+        let result = self.import_files(app, history, settings, path, move_files, recursive);
+        self.running.store(false, Ordering::SeqCst);
+        result
+    }
+
+    fn import_files(
+        &self,
+        app: &AppHandle,
+        history: &History,
+        settings: &Settings,
+        path: &Path,
+        move_files: bool,
+        recursive: bool,
+    ) -> Result<FolderImportSummary, String> {
+        // Canonical, so files already in the vault are recognised
+        let path = long_path::canonical(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let vault = get_vault_directory(settings)?;
+        let vault = long_path::canonical(&vault).unwrap_or(vault);
+        let files = list_files(&path, recursive)?;
+        info!(folder = %path.display(), files = files.len(), move_files, "Importing folder");
+
+        let mut summary = FolderImportSummary {
+            total: files.len(),
+            ..FolderImportSummary::default()
+        };
+        for file in files {
+            if self.cancelled.load(Ordering::SeqCst) {
+                info!("Folder import cancelled after {} of {} files", summary.processed, summary.total);
+                summary.cancelled = true;
+                break;
+            }
+            summary.current = Some(file.display().to_string());
+            let _ = app.emit_all(IMPORT_PROGRESS_EVENT, &summary);
+
+            match import_one(history, settings, &vault, &file, move_files) {
+                Ok(Imported::Recorded) => summary.imported += 1,
+                Ok(Imported::Duplicate) => summary.skipped_duplicates += 1,
+                Ok(Imported::NotMedia) => summary.skipped_not_media += 1,
+                Err(error) => {
+                    warn!("Failed to import {}: {}", file.display(), error);
+                    summary.unreadable += 1;
+                }
+            }
+            summary.processed += 1;
+        }
+
+        summary.current = None;
+        let _ = app.emit_all(IMPORT_PROGRESS_EVENT, &summary);
+        info!(
+            imported = summary.imported,
+            duplicates = summary.skipped_duplicates,
+            not_media = summary.skipped_not_media,
+            unreadable = summary.unreadable,
+            "Folder import finished"
+        );
+        Ok(summary)
+    }
+}
+
+enum Imported {
+    Recorded,
+    Duplicate,
+    NotMedia,
+}
+
+fn import_one(history: &History, settings: &Settings, vault: &Path, file: &Path, move_files: bool) -> Result<Imported, String> {
+    let started_at = current_timestamp_millis();
+    let Some(sniffed) = sniff(file)? else {
+        debug!("Not a media file: {}", file.display());
+        return Ok(Imported::NotMedia);
+    };
+    let (_, sha256) = hash_file(&file.display().to_string()).map_err(|e| format!("Failed to read: {}", e))?;
+    // Checked before moving, so a duplicate stays where it is
+    if history.find_import(&sha256)?.is_some() {
+        return Ok(Imported::Duplicate);
+    }
+
+    let destination = if move_files && !file.starts_with(vault) {
+        let destination = template_destination(settings, vault, file, sniffed, &sha256);
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(long_path::extended(parent))
+                .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+        }
+        drop_import::move_file(file, &destination).map_err(|e| format!("Failed to move into the vault: {}", e))?;
+        destination
+    } else {
+        file.to_path_buf()
+    };
+
+    let message = format!("Imported from {}", file.display());
+    let file_path = destination.display().to_string();
+    let entry = NewHistoryEntry {
+        job_id: &generate_job_id(SOURCE),
+        url: &format!("file://{}", file.display()),
+        file_path: Some(&file_path),
+        status: DownloadStatus::Completed,
+        message: Some(&message),
+        source: SOURCE,
+        started_at,
+        finished_at: current_timestamp_millis(),
+        object_key: None,
+        etag: None,
+        output_path: None,
+        upload: false,
+        page_url: None,
+        page_title: None,
+        media_info: None,
+        transfer: None,
+        session_id: None,
+        supervision: None,
+    };
+    let Some(entry_id) = history.record_import(&entry, &sha256)? else {
+        return Ok(Imported::Duplicate);
+    };
+    // Imports report through their summary, never a toast per file
+    if let Err(error) = history.mark_notified(entry_id) {
+        warn!("{}", error);
+    }
+    if let Err(error) = drop_import::generate_thumbnail(&destination, entry_id) {
+        debug!("No thumbnail for {}: {}", destination.display(), error);
+    }
+    Ok(Imported::Recorded)
+}
+
+// Every file under `folder`, in name order. Symbolic links are not followed,
+// so a link back up the tree cannot loop.
+pub(crate) fn list_files(folder: &Path, recursive: bool) -> Result<Vec<PathBuf>, String> {
+    let mut files = Vec::new();
+    let mut folders = vec![folder.to_path_buf()];
+    while let Some(folder) = folders.pop() {
+        let entries = match fs::read_dir(&folder) {
+            Ok(entries) => entries,
+            Err(error) if folders.is_empty() && files.is_empty() => {
+                return Err(format!("Failed to read {}: {}", folder.display(), error));
+            }
+            Err(error) => {
+                warn!("Skipping unreadable folder {}: {}", folder.display(), error);
+                continue;
+            }
+        };
+        let mut paths = entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect::<Vec<_>>();
+        paths.sort();
+        for path in paths.into_iter().rev() {
+            match fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.is_dir() && recursive => folders.push(path),
+                Ok(metadata) if metadata.is_file() => files.push(path),
+                _ => {}
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// Where a moved file goes: the output template filled with the file's own
+// name, inside the vault, without overwriting anything
+fn template_destination(settings: &Settings, vault: &Path, file: &Path, sniffed: &str, sha256: &str) -> PathBuf {
+    let stem = file.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    let extension = file
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+        .filter(|extension| MEDIA_EXTENSIONS.contains(&extension.as_str()))
+        .unwrap_or_else(|| sniffed.to_string());
+    let relative = fill_template(&settings.output_template, &stem, &sha256[..TEMPLATE_ID_LENGTH], &extension);
+    let destination = vault.join(relative);
+    let directory = destination.parent().unwrap_or(vault);
+    drop_import::unique_destination(directory, Path::new(destination.file_name().unwrap_or_default()))
+}
+
+// The usual extension of the file's format, from its first bytes; None for
+// anything that is not an image, video or audio file
+fn sniff(path: &Path) -> Result<Option<&'static str>, String> {
+    let mut header = Vec::with_capacity(MAGIC_BYTES);
+    File::open(path)
+        .and_then(|file| file.take(MAGIC_BYTES as u64).read_to_end(&mut header))
+        .map_err(|e| format!("Failed to read: {}", e))?;
+    let bytes = header.as_slice();
+    let at = |offset: usize, magic: &[u8]| bytes.get(offset..offset + magic.len()) == Some(magic);
+
+    let extension = if at(0, b"\xff\xd8\xff") {
+        "jpg"
+    } else if at(0, b"\x89PNG\r\n\x1a\n") {
+        "png"
+    } else if at(0, b"GIF87a") || at(0, b"GIF89a") {
+        "gif"
+    } else if at(0, b"BM") && bytes.len() >= 14 {
+        "bmp"
+    } else if at(0, b"II*\0") || at(0, b"MM\0*") {
+        "tif"
+    } else if at(0, b"RIFF") && at(8, b"WEBP") {
+        "webp"
+    } else if at(0, b"RIFF") && at(8, b"AVI ") {
+        "avi"
+    } else if at(0, b"RIFF") && at(8, b"WAVE") {
+        "wav"
+    } else if at(4, b"ftyp") {
+        // ISO base media: the brand tells images, audio and video apart
+        match bytes.get(8..12).unwrap_or_default() {
+            b"avif" | b"avis" => "avif",
+            b"heic" | b"heix" | b"mif1" | b"msf1" => "heic",
+            b"M4A " | b"M4B " => "m4a",
+            b"qt  " => "mov",
+            _ => "mp4",
+        }
+    } else if at(0, b"\x1a\x45\xdf\xa3") {
+        // WebM is Matroska with another doctype; both play as mkv
+        "mkv"
+    } else if at(0, b"FLV") {
+        "flv"
+    } else if at(0, b"OggS") {
+        "ogg"
+    } else if at(0, b"fLaC") {
+        "flac"
+    } else if at(0, b"ID3") || frame_sync(bytes, 0xe6, 0xe2) {
+        "mp3"
+    } else if frame_sync(bytes, 0xf6, 0xf0) {
+        // ADTS
+        "aac"
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(extension))
+}
+
+// An MPEG audio frame header: eleven set sync bits, then the version and
+// layer bits picked by `mask`
+fn frame_sync(bytes: &[u8], mask: u8, value: u8) -> bool {
+    bytes.len() >= 2 && bytes[0] == 0xff && bytes[1] & mask == value
+}
@@ -0,0 +1,315 @@
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::long_path;
+
+// Starts messages about a write Controlled folder access refused, so
+// classify_download_error reads them as controlled_folder_access
+pub const BLOCKED_PREFIX: &str = "Blocked by Controlled folder access";
+// Hex file attributes, e.g. 400000, that every file is read as having, to
+// try the placeholder handling without OneDrive
+pub const SIMULATED_ATTRIBUTES_ENV: &str = "IMGVAULT_SIMULATE_FILE_ATTRIBUTES";
+
+const FILE_ATTRIBUTE_OFFLINE: u32 = 0x1000;
+const FILE_ATTRIBUTE_RECALL_ON_OPEN: u32 = 0x4_0000;
+const FILE_ATTRIBUTE_RECALL_ON_DATA_ACCESS: u32 = 0x40_0000;
+// Folders of the user profile Windows protects out of the box
+const DEFAULT_PROTECTED_FOLDERS: &[&str] = &["Documents", "Pictures", "Videos", "Music", "Desktop", "Favorites"];
+// Where OneDrive says its folders are; the last two are set per account type
+const CLOUD_ROOT_ENV_VARS: &[&str] = &["OneDrive", "OneDriveConsumer", "OneDriveCommercial"];
+// How Windows and Python word a refused write
+const ACCESS_DENIED_WORDS: &[&str] = &["access is denied", "permission denied", "errno 13", "winerror 5"];
+
+// A cloud placeholder whose contents are not on disk: reading it starts a
+// download, or fails offline, so its hash cannot be checked from here
+pub fn is_dehydrated(attributes: u32) -> bool {
+    attributes & (FILE_ATTRIBUTE_OFFLINE | FILE_ATTRIBUTE_RECALL_ON_OPEN | FILE_ATTRIBUTE_RECALL_ON_DATA_ACCESS) != 0
+}
+
+// Whether `path` is a placeholder OneDrive dehydrated; reading the
+// attributes does not hydrate it
+pub fn is_placeholder(path: &Path) -> bool {
+    file_attributes(path).is_some_and(is_dehydrated)
+}
+
+fn file_attributes(path: &Path) -> Option<u32> {
+    if let Ok(simulated) = env::var(SIMULATED_ATTRIBUTES_ENV) {
+        return u32::from_str_radix(simulated.trim().trim_start_matches("0x"), 16).ok();
+    }
+    read_attributes(path)
+}
+
+#[cfg(target_os = "windows")]
+fn read_attributes(path: &Path) -> Option<u32> {
+    use std::os::windows::fs::MetadataExt;
+    std::fs::symlink_metadata(long_path::extended(path))
+        .ok()
+        .map(|metadata| metadata.file_attributes())
+}
+
+#[cfg(not(target_os = "windows"))]
+fn read_attributes(_path: &Path) -> Option<u32> {
+    None
+}
+
+// The refused write to `path` as a Controlled folder access block, when
+// it was refused and the folder is one Windows protects
+pub fn access_denied(error: &io::Error, path: &Path) -> Option<String> {
+    const ERROR_ACCESS_DENIED: i32 = 5;
+    if !cfg!(target_os = "windows") || error.raw_os_error() != Some(ERROR_ACCESS_DENIED) {
+        return None;
+    }
+    protected_folder(path).map(|folder| {
+        format!(
+            "{}: {} is inside the protected folder {}: {}",
+            BLOCKED_PREFIX,
+            path.display(),
+            folder.display(),
+            error
+        )
+    })
+}
+
+// Whether a downloader's error is a refused write inside a protected folder;
+// yt-dlp names the file it could not open
+pub fn mentions_blocked_write(message: &str) -> bool {
+    let message = message.to_lowercase();
+    if controlled_folder_access_enabled() == Some(false) || !ACCESS_DENIED_WORDS.iter().any(|word| message.contains(word)) {
+        return false;
+    }
+    // Python doubles the backslashes of the path it prints
+    let message = message.replace(r"\\", r"\");
+    protected_folders().iter().any(|folder| {
+        let folder = folder.display().to_string().to_lowercase();
+        message.contains(&folder) || message.contains(&folder.replace('\\', "/"))
+    })
+}
+
+// The protected folder `path` is in, while Controlled folder access is not
+// known to be off
+pub fn protected_folder(path: &Path) -> Option<PathBuf> {
+    if controlled_folder_access_enabled() == Some(false) {
+        return None;
+    }
+    let path = long_path::simplified(path);
+    protected_folders().into_iter().find(|folder| starts_with_ignoring_case(&path, folder))
+}
+
+// The OneDrive folder `path` is synced by
+pub fn cloud_root(path: &Path) -> Option<PathBuf> {
+    let path = long_path::simplified(path);
+    CLOUD_ROOT_ENV_VARS
+        .iter()
+        .filter_map(env::var_os)
+        .map(PathBuf::from)
+        .filter(|root| !root.as_os_str().is_empty())
+        .find(|root| starts_with_ignoring_case(&path, root))
+}
+
+// Folders Windows protects by default, under the profile and under
+// OneDrive when it backs them up, and the ones the user added
+pub fn protected_folders() -> Vec<PathBuf> {
+    if !cfg!(target_os = "windows") {
+        return Vec::new();
+    }
+    let mut roots = env::var_os("USERPROFILE").map(PathBuf::from).into_iter().collect::<Vec<_>>();
+    roots.extend(CLOUD_ROOT_ENV_VARS.iter().filter_map(env::var_os).map(PathBuf::from));
+    let mut folders = roots
+        .iter()
+        .flat_map(|root| DEFAULT_PROTECTED_FOLDERS.iter().map(move |folder| root.join(folder)))
+        .collect::<Vec<_>>();
+    folders.extend(user_protected_folders());
+    folders
+}
+
+fn starts_with_ignoring_case(path: &Path, folder: &Path) -> bool {
+    let path = path.display().to_string().to_lowercase().replace('/', "\\");
+    let folder = folder.display().to_string().to_lowercase().replace('/', "\\");
+    let folder = folder.trim_end_matches('\\');
+    !folder.is_empty() && (path == folder || path.starts_with(&format!("{}\\", folder)))
+}
+
+// Some(true) when on or in audit mode, None when Defender's settings cannot
+// be read, which they often cannot without elevation
+#[cfg(target_os = "windows")]
+pub fn controlled_folder_access_enabled() -> Option<bool> {
+    use winreg::enums::HKEY_LOCAL_MACHINE;
+    use winreg::RegKey;
+
+    let mut unreadable = false;
+    for key_path in CFA_KEY_PATHS {
+        match RegKey::predef(HKEY_LOCAL_MACHINE)
+            .open_subkey(key_path)
+            .and_then(|key| key.get_value::<u32, _>("EnableControlledFolderAccess"))
+        {
+            Ok(value) => return Some(value != 0),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(_) => unreadable = true,
+        }
+    }
+    if unreadable {
+        None
+    } else {
+        Some(false)
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn controlled_folder_access_enabled() -> Option<bool> {
+    Some(false)
+}
+
+// Group policy wins over the Windows Security app's own setting
+#[cfg(target_os = "windows")]
+const CFA_KEY_PATHS: &[&str] = &[
+    r"SOFTWARE\Policies\Microsoft\Windows Defender\Windows Defender Exploit Guard\Controlled Folder Access",
+    r"SOFTWARE\Microsoft\Windows Defender\Windows Defender Exploit Guard\Controlled Folder Access",
+];
+
+#[cfg(target_os = "windows")]
+fn user_protected_folders() -> Vec<PathBuf> {
+    use winreg::enums::HKEY_LOCAL_MACHINE;
+    use winreg::RegKey;
+
+    CFA_KEY_PATHS
+        .iter()
+        .filter_map(|key_path| {
+            RegKey::predef(HKEY_LOCAL_MACHINE)
+                .open_subkey(format!(r"{}\ProtectedFolders", key_path))
+                .ok()
+        })
+        .flat_map(|key| key.enum_values().flatten().map(|(name, _)| PathBuf::from(name)).collect::<Vec<_>>())
+        .collect()
+}
+
+#[cfg(not(target_os = "windows"))]
+fn user_protected_folders() -> Vec<PathBuf> {
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use std::ffi::OsString;
+
+    const FILE_ATTRIBUTE_ARCHIVE: u32 = 0x20;
+    const FILE_ATTRIBUTE_REPARSE_POINT: u32 = 0x400;
+    const FILE_ATTRIBUTE_PINNED: u32 = 0x8_0000;
+    const FILE_ATTRIBUTE_UNPINNED: u32 = 0x10_0000;
+
+    // Sets environment variables for the length of a test and puts the old
+    // values back; the app data turn keeps other tests from seeing them
+    struct Environment(Vec<(&'static str, Option<OsString>)>);
+
+    impl Environment {
+        fn set(&mut self, name: &'static str, value: Option<&str>) {
+            if !self.0.iter().any(|(saved, _)| *saved == name) {
+                self.0.push((name, env::var_os(name)));
+            }
+            match value {
+                Some(value) => env::set_var(name, value),
+                None => env::remove_var(name),
+            }
+        }
+    }
+
+    impl Drop for Environment {
+        fn drop(&mut self) {
+            for (name, value) in self.0.drain(..) {
+                match value {
+                    Some(value) => env::set_var(name, value),
+                    None => env::remove_var(name),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn only_files_without_their_contents_are_placeholders() {
+        let cases = [
+            ("plain file", FILE_ATTRIBUTE_ARCHIVE, false),
+            ("no attributes", 0, false),
+            ("always kept on this device", FILE_ATTRIBUTE_ARCHIVE | FILE_ATTRIBUTE_REPARSE_POINT | FILE_ATTRIBUTE_PINNED, false),
+            ("downloaded, may be freed up", FILE_ATTRIBUTE_ARCHIVE | FILE_ATTRIBUTE_REPARSE_POINT, false),
+            (
+                "online-only",
+                FILE_ATTRIBUTE_ARCHIVE | FILE_ATTRIBUTE_REPARSE_POINT | FILE_ATTRIBUTE_UNPINNED | FILE_ATTRIBUTE_RECALL_ON_DATA_ACCESS,
+                true,
+            ),
+            ("recalled when opened", FILE_ATTRIBUTE_RECALL_ON_OPEN, true),
+            ("offline", FILE_ATTRIBUTE_ARCHIVE | FILE_ATTRIBUTE_OFFLINE, true),
+            // Freeing up space sets unpinned before the contents go
+            ("being freed up", FILE_ATTRIBUTE_ARCHIVE | FILE_ATTRIBUTE_REPARSE_POINT | FILE_ATTRIBUTE_UNPINNED, false),
+        ];
+        for (name, attributes, dehydrated) in cases {
+            assert_eq!(is_dehydrated(attributes), dehydrated, "{} ({:#x})", name, attributes);
+        }
+    }
+
+    #[test]
+    fn simulated_attributes_apply_to_every_file() {
+        let app_data = test_support::app_data();
+        let mut environment = Environment(Vec::new());
+        let file = app_data.directory.join("picture.png");
+        std::fs::write(&file, b"picture").expect("file");
+        let missing = app_data.directory.join("missing.png");
+
+        for (simulated, placeholder) in [("400000", true), ("0x1000", true), (" 40000 ", true), ("20", false), ("not hex", false)] {
+            environment.set(SIMULATED_ATTRIBUTES_ENV, Some(simulated));
+            assert_eq!(is_placeholder(&file), placeholder, "{:?}", simulated);
+            assert_eq!(is_placeholder(&missing), placeholder, "{:?}", simulated);
+        }
+
+        environment.set(SIMULATED_ATTRIBUTES_ENV, None);
+        assert!(!is_placeholder(&file));
+        assert!(!is_placeholder(&missing));
+    }
+
+    #[test]
+    fn a_vault_under_any_onedrive_folder_is_synced() {
+        let _app_data = test_support::app_data();
+        let mut environment = Environment(Vec::new());
+        environment.set("OneDrive", Some("/home/me/OneDrive"));
+        environment.set("OneDriveConsumer", Some(""));
+        environment.set("OneDriveCommercial", Some("/home/me/OneDrive - Contoso"));
+
+        let synced = [
+            ("/home/me/OneDrive", "/home/me/OneDrive"),
+            ("/home/me/OneDrive/Pictures/ImgVault", "/home/me/OneDrive"),
+            ("/HOME/ME/onedrive/ImgVault", "/home/me/OneDrive"),
+            ("/home/me/OneDrive - Contoso/Vault", "/home/me/OneDrive - Contoso"),
+        ];
+        for (vault, root) in synced {
+            assert_eq!(cloud_root(Path::new(vault)), Some(PathBuf::from(root)), "{}", vault);
+        }
+        // A set but empty variable is no root at all
+        for vault in ["/home/me/OneDriveBackup/Vault", "/home/me/Videos", "/"] {
+            assert_eq!(cloud_root(Path::new(vault)), None, "{}", vault);
+        }
+    }
+
+    // OneDrive sets the recall flags itself; offline is the one a test can
+    // set on a real file
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn a_file_marked_offline_is_a_placeholder() {
+        use std::os::windows::ffi::OsStrExt;
+        use winapi::um::fileapi::SetFileAttributesW;
+
+        let app_data = test_support::app_data();
+        let mut environment = Environment(Vec::new());
+        environment.set(SIMULATED_ATTRIBUTES_ENV, None);
+        let file = app_data.directory.join("picture.png");
+        std::fs::write(&file, b"picture").expect("file");
+        assert!(!is_placeholder(&file));
+
+        let wide = file.as_os_str().encode_wide().chain(Some(0)).collect::<Vec<_>>();
+        let set = |attributes| assert_ne!(unsafe { SetFileAttributesW(wide.as_ptr(), attributes) }, 0, "{}", io::Error::last_os_error());
+        set(FILE_ATTRIBUTE_ARCHIVE | FILE_ATTRIBUTE_OFFLINE);
+        assert!(is_placeholder(&file));
+        set(FILE_ATTRIBUTE_ARCHIVE);
+        assert!(!is_placeholder(&file));
+    }
+}
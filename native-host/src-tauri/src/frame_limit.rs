@@ -0,0 +1,313 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::redact;
+
+// Chrome closes the port when the host sends a message over 1 MB
+pub const MAX_FRAME_BYTES: usize = 1024 * 1024;
+// Cut beyond the overflow so escaping and the flags never tip a frame back over
+const HEADROOM_BYTES: usize = 1024;
+const TRUNCATED_MARKER: &str = "…";
+
+// What gives way when a frame is too big, first to last. Output tails keep
+// their end, where yt-dlp reports the error; messages keep their start.
+enum Cut {
+    Tail(&'static str),
+    Warnings,
+    MediaInfo,
+    Head(&'static str),
+    Data,
+}
+
+const CUTS: &[Cut] = &[
+    Cut::Tail("stderr"),
+    Cut::Tail("stdout"),
+    Cut::Warnings,
+    Cut::MediaInfo,
+    Cut::Head("detail"),
+    Cut::Head("message"),
+    Cut::Head("line"),
+    Cut::Data,
+];
+
+// The redacted frames to send for `response`, each within MAX_FRAME_BYTES.
+// Oversized fields are cut in the order of CUTS and the frame marked
+// `truncated: true`; a `data` array too big for one frame is split over
+// several, all but the last marked `more: true`.
+pub fn frames<T: Serialize>(response: &T) -> Result<Vec<String>, String> {
+    let frame = serde_json::to_string(response).map_err(|e| format!("Failed to serialize response: {}", e))?;
+    // yt-dlp output can echo a site login or a signed URL; neither leaves the host
+    let frame = redact::redact(&frame).into_owned();
+    if frame.len() <= MAX_FRAME_BYTES {
+        return Ok(vec![frame]);
+    }
+
+    let Value::Object(response) =
+        serde_json::to_value(response).map_err(|e| format!("Failed to serialize response: {}", e))?
+    else {
+        return Err(format!("Response of {} bytes is too large to send", frame.len()));
+    };
+    Ok(match response.get("data") {
+        Some(Value::Array(items)) if items.len() > 1 => split(&response, items),
+        _ => vec![shrink(response)],
+    })
+}
+
+fn encode(response: &Map<String, Value>) -> String {
+    redact::redact(&Value::Object(response.clone()).to_string()).into_owned()
+}
+
+// Packs the items into as few frames as fit, in order
+fn split(response: &Map<String, Value>, items: &[Value]) -> Vec<String> {
+    let mut empty = response.clone();
+    empty.insert("data".to_string(), Value::Array(Vec::new()));
+    empty.insert("more".to_string(), Value::Bool(true));
+    let budget = MAX_FRAME_BYTES.saturating_sub(encode(&empty).len() + HEADROOM_BYTES);
+
+    let mut chunks = vec![Vec::new()];
+    let mut used = 0;
+    for item in items {
+        let size = redact::redact(&item.to_string()).len() + 1;
+        let current = chunks.last_mut().unwrap();
+        if !current.is_empty() && used + size > budget {
+            chunks.push(Vec::new());
+            used = 0;
+        }
+        chunks.last_mut().unwrap().push(item.clone());
+        used += size;
+    }
+
+    let last = chunks.len() - 1;
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut frame = response.clone();
+            frame.insert("data".to_string(), Value::Array(chunk));
+            frame.insert("more".to_string(), Value::Bool(index < last));
+            // A single item can still be too big on its own
+            shrink(frame)
+        })
+        .collect()
+}
+
+fn shrink(mut response: Map<String, Value>) -> String {
+    let mut frame = encode(&response);
+    if frame.len() <= MAX_FRAME_BYTES {
+        return frame;
+    }
+    response.insert("truncated".to_string(), Value::Bool(true));
+
+    for cut in CUTS {
+        let overflow = frame.len().saturating_sub(MAX_FRAME_BYTES);
+        if overflow == 0 {
+            return frame;
+        }
+        apply(cut, &mut response, overflow + HEADROOM_BYTES);
+        frame = encode(&response);
+    }
+    if frame.len() <= MAX_FRAME_BYTES {
+        return frame;
+    }
+
+    // Only the identifying fields are left to send, and of a request id no
+    // extension would send, only what fits
+    response.retain(|key, _| matches!(key.as_str(), "success" | "event" | "requestId" | "more" | "truncated"));
+    response.insert("message".to_string(), Value::String("Response too large to send".to_string()));
+    let frame = encode(&response);
+    let overflow = frame.len().saturating_sub(MAX_FRAME_BYTES);
+    if overflow == 0 {
+        return frame;
+    }
+    apply(&Cut::Head("requestId"), &mut response, overflow + HEADROOM_BYTES);
+    encode(&response)
+}
+
+fn apply(cut: &Cut, response: &mut Map<String, Value>, excess: usize) {
+    match cut {
+        Cut::Tail(field) => {
+            if let Some(Value::String(text)) = response.get_mut(*field) {
+                *text = keep_tail(text, excess);
+            }
+        }
+        Cut::Head(field) => {
+            if let Some(Value::String(text)) = response.get_mut(*field) {
+                *text = keep_head(text, excess);
+            }
+        }
+        Cut::Warnings => {
+            if let Some(Value::Array(warnings)) = data_field(response, "warnings") {
+                let mut removed = 0;
+                while removed < excess {
+                    let Some(warning) = warnings.pop() else {
+                        break;
+                    };
+                    removed += warning.to_string().len() + 1;
+                }
+            }
+        }
+        Cut::MediaInfo => {
+            if let Some(media_info) = data_field(response, "mediaInfo") {
+                *media_info = Value::Null;
+            }
+        }
+        Cut::Data => {
+            response.insert("data".to_string(), Value::Null);
+        }
+    }
+}
+
+fn data_field<'a>(response: &'a mut Map<String, Value>, name: &str) -> Option<&'a mut Value> {
+    response.get_mut("data")?.as_object_mut()?.get_mut(name)
+}
+
+// `text` less at least `excess` bytes from its start
+fn keep_tail(text: &str, excess: usize) -> String {
+    if excess >= text.len() {
+        return TRUNCATED_MARKER.to_string();
+    }
+    let mut start = excess;
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    format!("{}{}", TRUNCATED_MARKER, &text[start..])
+}
+
+// `text` less at least `excess` bytes from its end
+fn keep_head(text: &str, excess: usize) -> String {
+    if excess >= text.len() {
+        return TRUNCATED_MARKER.to_string();
+    }
+    let mut end = text.len() - excess;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &text[..end], TRUNCATED_MARKER)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn sent(response: &Value) -> Vec<Value> {
+        let frames = frames(response).expect("frames");
+        assert!(!frames.is_empty());
+        frames
+            .iter()
+            .map(|frame| {
+                assert!(frame.len() <= MAX_FRAME_BYTES, "frame of {} bytes", frame.len());
+                serde_json::from_str(frame).expect("frame is JSON")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn small_responses_go_as_they_are() {
+        let response = json!({ "success": true, "event": "complete", "requestId": "r", "message": "Done" });
+        assert_eq!(sent(&response), [response]);
+    }
+
+    #[test]
+    fn huge_lists_are_split_in_order() {
+        let items = (0..60_000).map(|index| json!({ "id": index, "title": "x".repeat(80) })).collect::<Vec<_>>();
+        let response = json!({ "success": true, "event": "complete", "requestId": "r", "data": items });
+
+        let frames = sent(&response);
+        assert!(frames.len() > 1);
+        let (last, rest) = frames.split_last().unwrap();
+        assert!(rest.iter().all(|frame| frame["more"] == true));
+        assert_eq!(last["more"], false);
+        assert!(frames.iter().all(|frame| frame["requestId"] == "r" && frame.get("truncated").is_none()));
+        let received = frames.iter().flat_map(|frame| frame["data"].as_array().unwrap().clone()).collect::<Vec<_>>();
+        assert_eq!(received, items);
+    }
+
+    #[test]
+    fn one_item_too_big_for_a_frame_is_dropped_alone() {
+        let items = vec![json!("small"), json!("y".repeat(2 * MAX_FRAME_BYTES)), json!("after")];
+        let response = json!({ "success": true, "requestId": "r", "data": items });
+
+        let frames = sent(&response);
+        let received = frames.iter().filter_map(|frame| frame["data"].as_array()).flatten().cloned().collect::<Vec<_>>();
+        assert!(received.contains(&json!("small")) && received.contains(&json!("after")));
+        assert!(frames.iter().any(|frame| frame["truncated"] == true));
+    }
+
+    #[test]
+    fn long_output_keeps_its_end() {
+        let stderr = format!("{}ERROR: the real cause", "noise\n".repeat(600_000));
+        let response = json!({ "success": false, "requestId": "r", "stderr": stderr, "stdout": "s".repeat(1000), "message": "yt-dlp failed" });
+
+        let frames = sent(&response);
+        assert_eq!(frames.len(), 1);
+        let frame = &frames[0];
+        assert_eq!(frame["truncated"], true);
+        assert_eq!(frame["requestId"], "r");
+        assert_eq!(frame["message"], "yt-dlp failed");
+        let stderr = frame["stderr"].as_str().unwrap();
+        assert!(stderr.starts_with(TRUNCATED_MARKER) && stderr.ends_with("ERROR: the real cause"));
+    }
+
+    #[test]
+    fn a_long_error_message_keeps_its_start() {
+        let message = format!("yt-dlp failed: {}", "z".repeat(2 * MAX_FRAME_BYTES));
+        let response = json!({ "success": false, "requestId": "r", "stderr": "x".repeat(MAX_FRAME_BYTES), "message": message });
+
+        let frames = sent(&response);
+        let frame = &frames[0];
+        assert_eq!(frame["requestId"], "r");
+        assert_eq!(frame["stderr"], TRUNCATED_MARKER);
+        let message = frame["message"].as_str().unwrap();
+        assert!(message.starts_with("yt-dlp failed: ") && message.ends_with(TRUNCATED_MARKER));
+    }
+
+    #[test]
+    fn multi_byte_text_is_cut_on_character_boundaries() {
+        // Every offset of the cut against two, three and four byte characters
+        for text in ["é", "日", "🎬"] {
+            let line = format!("ab{}", text.repeat(8));
+            for excess in 0..line.len() {
+                let head = keep_head(&line, excess);
+                let tail = keep_tail(&line, excess);
+                assert!(head.len() <= line.len() - excess + TRUNCATED_MARKER.len());
+                assert!(tail.len() <= line.len() - excess + TRUNCATED_MARKER.len());
+                assert!(line.starts_with(head.trim_end_matches(TRUNCATED_MARKER)));
+                assert!(line.ends_with(tail.trim_start_matches(TRUNCATED_MARKER)));
+            }
+        }
+
+        let response = json!({ "success": false, "requestId": "r", "line": format!("a{}", "🎬".repeat(MAX_FRAME_BYTES / 4 + 7)) });
+        let frames = sent(&response);
+        let line = frames[0]["line"].as_str().unwrap();
+        assert!(line.ends_with(TRUNCATED_MARKER));
+        assert!(line.trim_end_matches(TRUNCATED_MARKER).trim_start_matches('a').chars().all(|ch| ch == '🎬'));
+    }
+
+    #[test]
+    fn text_that_grows_when_escaped_still_fits() {
+        // Each control character takes six bytes once escaped
+        let message = format!("{}{}", "\u{1}".repeat(170_000), "plain ".repeat(20_000));
+        let detail = "\"\\\n".repeat(MAX_FRAME_BYTES / 2);
+        let response = json!({ "success": false, "requestId": "r", "message": message, "detail": detail });
+
+        let frames = sent(&response);
+        assert_eq!(frames[0]["truncated"], true);
+    }
+
+    #[test]
+    fn a_response_made_only_of_pathological_fields_still_fits() {
+        let response = json!({
+            "success": false,
+            "event": "complete",
+            "requestId": "r".repeat(2 * MAX_FRAME_BYTES),
+            "filePath": "p".repeat(2 * MAX_FRAME_BYTES),
+            "data": { "warnings": vec!["w".repeat(1000); 3000], "mediaInfo": { "title": "t".repeat(MAX_FRAME_BYTES) } },
+        });
+
+        let frames = sent(&response);
+        assert_eq!(frames[0]["truncated"], true);
+        assert_eq!(frames[0]["success"], false);
+        assert!(frames[0]["requestId"].as_str().unwrap().starts_with("rrrr"));
+    }
+}
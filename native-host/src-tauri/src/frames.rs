@@ -0,0 +1,247 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use tracing::{debug, info, warn};
+
+use crate::clips::{probe_duration, seconds_label};
+use crate::diagnostics::hide_console_window;
+use crate::drop_import::{generate_thumbnail, unique_destination};
+use crate::history::History;
+use crate::{current_timestamp_millis, long_path};
+
+// One call writes at most this many files; a short interval on a long video
+// would otherwise fill a folder with thousands of them
+pub const MAX_FRAMES: usize = 500;
+// The last decodable frame sits a little before the reported duration
+const END_MARGIN_SECS: f64 = 0.1;
+const MIN_INDEX_DIGITS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FrameFormat {
+    Png,
+    Jpeg,
+}
+
+impl FrameFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            FrameFormat::Png => "png",
+            FrameFormat::Jpeg => "jpg",
+        }
+    }
+}
+
+// Either the moments to take, or an interval to take one every
+pub enum FrameSelection {
+    Timestamps(Vec<f64>),
+    EveryNSeconds(f64),
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtractedFrames {
+    pub paths: Vec<String>,
+    // The history entries of the frames, in the same order
+    pub entry_ids: Vec<i64>,
+    // Timestamps that were moved into the video, and frames without a thumbnail
+    pub warnings: Vec<String>,
+}
+
+// Saves frames of the file of history entry `file_id` into "<name> frames/"
+// next to it, each recorded in history under the video's entry
+pub fn extract_frames(
+    history: &History,
+    file_id: i64,
+    selection: FrameSelection,
+    format: FrameFormat,
+    quality: Option<u8>,
+) -> Result<ExtractedFrames, String> {
+    let entry = history
+        .get(file_id)?
+        .ok_or_else(|| format!("History entry {} not found", file_id))?;
+    let source = entry
+        .file_path
+        .map(PathBuf::from)
+        .filter(|path| path.is_file())
+        .ok_or_else(|| format!("The file of history entry {} is not on disk", file_id))?;
+    if quality.is_some_and(|quality| !(1..=100).contains(&quality)) {
+        return Err("quality must be between 1 and 100".to_string());
+    }
+
+    let duration = probe_duration(&source)?;
+    let mut warnings = Vec::new();
+    let timestamps = plan_timestamps(selection, duration, &mut warnings)?;
+
+    let stem = source.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    let directory = source
+        .parent()
+        .ok_or("The source file has no folder")?
+        .join(format!("{} frames", stem));
+    fs::create_dir_all(long_path::extended(&directory))
+        .map_err(|e| format!("Failed to create frame folder {}: {}", directory.display(), e))?;
+    let digits = timestamps.len().to_string().len().max(MIN_INDEX_DIGITS);
+
+    let mut extracted = ExtractedFrames {
+        paths: Vec::with_capacity(timestamps.len()),
+        entry_ids: Vec::with_capacity(timestamps.len()),
+        warnings,
+    };
+    for (index, timestamp) in timestamps.iter().enumerate() {
+        let file_name = format!("{:0width$}.{}", index + 1, format.extension(), width = digits);
+        let output = unique_destination(&directory, Path::new(&file_name));
+        extract_frame(&source, &output, *timestamp, format, quality)?;
+
+        let path = output.display().to_string();
+        let message = format!("Frame at {}s", seconds_label(*timestamp));
+        let entry_id = history.record_derived(file_id, &path, &message, "frame", current_timestamp_millis())?;
+        if let Err(error) = generate_thumbnail(&output, entry_id) {
+            debug!("No thumbnail for {}: {}", path, error);
+            extracted.warnings.push(format!("No thumbnail for {}: {}", path, error));
+        }
+        extracted.paths.push(path);
+        extracted.entry_ids.push(entry_id);
+    }
+    info!(file_id, frames = extracted.paths.len(), "Extracted frames into {}", directory.display());
+    Ok(extracted)
+}
+
+// The moments to take, inside the video and within MAX_FRAMES
+fn plan_timestamps(selection: FrameSelection, duration: f64, warnings: &mut Vec<String>) -> Result<Vec<f64>, String> {
+    let last = (duration - END_MARGIN_SECS).max(0.0);
+    let timestamps = match selection {
+        FrameSelection::Timestamps(timestamps) => {
+            if timestamps.iter().any(|timestamp| !timestamp.is_finite()) {
+                return Err("Timestamps must be seconds from the beginning".to_string());
+            }
+            timestamps
+                .into_iter()
+                .map(|timestamp| {
+                    let clamped = timestamp.clamp(0.0, last);
+                    if clamped != timestamp {
+                        warn!("Frame timestamp {}s is outside the video, using {}s", timestamp, clamped);
+                        warnings.push(format!(
+                            "{}s is outside the {}s video; took the frame at {}s",
+                            seconds_label(timestamp),
+                            seconds_label(duration),
+                            seconds_label(clamped)
+                        ));
+                    }
+                    clamped
+                })
+                .collect::<Vec<_>>()
+        }
+        FrameSelection::EveryNSeconds(interval) => {
+            if !interval.is_finite() || interval <= 0.0 {
+                return Err("every_n_seconds must be more than 0".to_string());
+            }
+            let count = (last / interval).floor() as usize + 1;
+            if count > MAX_FRAMES {
+                return Err(format!(
+                    "One frame every {}s is {} frames; at most {} can be extracted at once",
+                    seconds_label(interval),
+                    count,
+                    MAX_FRAMES
+                ));
+            }
+            (0..count).map(|index| index as f64 * interval).collect()
+        }
+    };
+    if timestamps.is_empty() {
+        return Err("No timestamps given".to_string());
+    }
+    if timestamps.len() > MAX_FRAMES {
+        return Err(format!("{} frames asked for; at most {} can be extracted at once", timestamps.len(), MAX_FRAMES));
+    }
+    Ok(timestamps)
+}
+
+// JPEG quality 1-100 maps onto ffmpeg's -q:v scale, where 2 is best and 31
+// worst; PNG is lossless and ignores it
+fn extract_frame(source: &Path, output: &Path, timestamp: f64, format: FrameFormat, quality: Option<u8>) -> Result<(), String> {
+    let mut command = Command::new("ffmpeg");
+    command
+        .args(["-v", "error", "-y", "-ss"])
+        .arg(format!("{:.3}", timestamp))
+        .arg("-i")
+        .arg(long_path::simplified(source))
+        .args(["-frames:v", "1"]);
+    if let (FrameFormat::Jpeg, Some(quality)) = (format, quality) {
+        let scale = 31 - (u32::from(quality) - 1) * 29 / 99;
+        command.arg("-q:v").arg(scale.to_string());
+    }
+    command
+        .arg(long_path::simplified(output))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    hide_console_window(&mut command);
+
+    let finished = command.output().map_err(|e| format!("Failed to run ffmpeg: {}", e))?;
+    if !finished.status.success() {
+        return Err(format!(
+            "ffmpeg failed at {}s: {}",
+            seconds_label(timestamp),
+            String::from_utf8_lossy(&finished.stderr).trim()
+        ));
+    }
+    if !output.exists() {
+        return Err(format!("ffmpeg wrote no frame at {}s", seconds_label(timestamp)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(selection: FrameSelection, duration: f64) -> Result<(Vec<f64>, Vec<String>), String> {
+        let mut warnings = Vec::new();
+        plan_timestamps(selection, duration, &mut warnings).map(|timestamps| (timestamps, warnings))
+    }
+
+    #[test]
+    fn timestamps_outside_the_video_are_moved_into_it() {
+        let (timestamps, warnings) = plan(FrameSelection::Timestamps(vec![-3.0, 4.5, 10.0, 60.0]), 10.0).unwrap();
+        assert_eq!(timestamps, [0.0, 4.5, 9.9, 9.9]);
+        assert_eq!(
+            warnings,
+            [
+                "-3s is outside the 10s video; took the frame at 0s",
+                "10s is outside the 10s video; took the frame at 9.9s",
+                "60s is outside the 10s video; took the frame at 9.9s",
+            ]
+        );
+
+        // Too short for the margin: the first frame is all there is
+        let (timestamps, _) = plan(FrameSelection::Timestamps(vec![0.5]), 0.05).unwrap();
+        assert_eq!(timestamps, [0.0]);
+
+        assert!(plan(FrameSelection::Timestamps(vec![f64::NAN]), 10.0).is_err());
+        assert!(plan(FrameSelection::Timestamps(Vec::new()), 10.0).is_err());
+    }
+
+    #[test]
+    fn no_more_than_max_frames_are_extracted() {
+        let (timestamps, warnings) = plan(FrameSelection::EveryNSeconds(2.5), 10.0).unwrap();
+        assert_eq!(timestamps, [0.0, 2.5, 5.0, 7.5]);
+        assert!(warnings.is_empty());
+
+        // One every second of a 500s video ends at 499s, the 500th frame
+        let (timestamps, _) = plan(FrameSelection::EveryNSeconds(1.0), MAX_FRAMES as f64).unwrap();
+        assert_eq!(timestamps.len(), MAX_FRAMES);
+        assert_eq!(timestamps.last(), Some(&499.0));
+        let error = plan(FrameSelection::EveryNSeconds(1.0), MAX_FRAMES as f64 + 1.0).expect_err("501 frames");
+        assert!(error.contains("is 501 frames; at most 500"), "{}", error);
+
+        let (timestamps, _) = plan(FrameSelection::Timestamps(vec![1.0; MAX_FRAMES]), 10.0).unwrap();
+        assert_eq!(timestamps.len(), MAX_FRAMES);
+        let error = plan(FrameSelection::Timestamps(vec![1.0; MAX_FRAMES + 1]), 10.0).expect_err("501 frames");
+        assert!(error.contains("501 frames asked for; at most 500"), "{}", error);
+
+        for interval in [0.0, -1.0, f64::INFINITY] {
+            assert!(plan(FrameSelection::EveryNSeconds(interval), 10.0).is_err(), "{}", interval);
+        }
+    }
+}
@@ -0,0 +1,365 @@
+use std::path::PathBuf;
+use tauri::{AppHandle, FileDropEvent, Manager, RunEvent, WindowEvent};
+use tracing::{info, warn};
+
+use crate::clipboard_watch::ClipboardPrompt;
+use crate::conflicts::ConflictPrompts;
+use crate::dispatcher::Priority;
+use crate::downloader::{run_gui_download, DownloadContext};
+use crate::drop_import::DroppedItem;
+use crate::folder_import::FolderImport;
+use crate::history::History;
+use crate::history_backfill::HistoryBackfill;
+use crate::http_api::HttpApi;
+use crate::instance::{InstanceListener, InstanceMessage};
+use crate::jobs::JobRegistry;
+use crate::log_viewer::LogFollower;
+use crate::organize::MediaHints;
+use crate::profiles::Profile;
+use crate::queue::generate_job_id;
+use crate::registration::RegistrationReport;
+use crate::settings::SettingsStore;
+use crate::source_page::SourcePage;
+use crate::vault_export::VaultExporter;
+use crate::vault_verify::VaultVerifier;
+use crate::{
+    autostart, clipboard_watch, coalesce, commands, conflicts, connectivity, db_backup, deep_link, drop_import, get_vault_directory, logging,
+    media_policy, native_proxy, notifications, organize, retry, schedule, show_message_box, shutdown, tray, EXTENSION_ID,
+};
+
+pub(crate) fn focus_main_window(app: &AppHandle) {
+    if let Some(window) = app.get_window("main") {
+        let _ = window.unminimize();
+        let _ = window.show();
+        let _ = window.set_focus();
+    }
+}
+
+// Act on launch arguments, whether from our own command line or forwarded by a second launch
+fn handle_launch_args(app: &AppHandle, args: &[String]) {
+    for arg in args.iter().skip(1) {
+        if arg.starts_with("http://") || arg.starts_with("https://") {
+            start_forwarded_download(app.clone(), arg.clone(), None);
+        } else if deep_link::is_deep_link(arg) {
+            handle_deep_link(app, arg);
+        }
+    }
+}
+
+fn handle_deep_link(app: &AppHandle, link: &str) {
+    match deep_link::parse_deep_link(link) {
+        Ok(download) => {
+            info!(url = logging::loggable_url(&download.url), "Received download link");
+            focus_main_window(app);
+            notifications::show(&notifications::DesktopNotification {
+                title: "Download queued".to_string(),
+                body: logging::loggable_url(&download.url).to_string(),
+                reveal_path: None,
+            });
+            start_forwarded_download(app.clone(), download.url, download.file_name);
+        }
+        Err(error) => {
+            warn!("Rejected {}:// link: {}", deep_link::SCHEME, error);
+            let message = format!("This ImgVault link cannot be opened.\n\n{}", error);
+            // The message box blocks until dismissed; keep the instance listener free
+            std::thread::spawn(move || show_message_box("ImgVault Native Host", &message, true));
+        }
+    }
+}
+
+pub(crate) fn start_forwarded_download(app: AppHandle, url: String, file_name: Option<String>) {
+    let jobs = app.state::<JobRegistry>().inner().clone();
+    let history = app.state::<History>().inner().clone();
+    let settings = app.state::<SettingsStore>().get();
+
+    std::thread::spawn(move || {
+        let template = match &file_name {
+            Some(file_name) => format!("{}.%(ext)s", file_name),
+            None => "%(title)s [%(id)s].%(ext)s".to_string(),
+        };
+        let output_path = match get_vault_directory(&settings) {
+            Ok(directory) => directory.join(template).display().to_string(),
+            Err(error) => {
+                let _ = app.emit_all("log-event", format!("❌ Download failed: {}", error));
+                return;
+            }
+        };
+        let job_id = generate_job_id("gui");
+        let (settings, output_path) =
+            match organize::organize_job(&history, settings, &job_id, &url, &MediaHints::default(), &output_path) {
+                Ok(organized) => organized,
+                Err(message) => {
+                    let _ = app.emit_all("log-event", format!("⏭️ {}: {}", message, url));
+                    return;
+                }
+            };
+        let (settings, output_path) = conflicts::settle_interactive(&app, settings, &job_id, &url, output_path, None);
+
+        let _ = app.emit_all("log-event", format!("📥 Starting download: {}", url));
+        let format_selector = settings.default_quality.format_selector();
+        let context = DownloadContext {
+            job_id: &job_id,
+            url: &url,
+            output_path: &output_path,
+            upload: true,
+            priority: Priority::Normal,
+            source_page: &SourcePage::default(),
+            source: "gui",
+        };
+        let result = run_gui_download(&jobs, &history, &settings, &context, format_selector, true, None);
+        let _ = app.emit_all("log-event", download_result_message(&url, result));
+    });
+}
+
+// Runs a job whose scheduled time has come, with the settings of this moment
+fn start_scheduled_download(app: AppHandle, download: schedule::ScheduledDownload) {
+    let jobs = app.state::<JobRegistry>().inner().clone();
+    let history = app.state::<History>().inner().clone();
+    let settings = organize::resume_job(&history, app.state::<SettingsStore>().get(), &download.job_id);
+    let settings = media_policy::for_request(settings, download.options.ignore_policy);
+
+    std::thread::spawn(move || {
+        let _ = app.emit_all("log-event", format!("📥 Starting scheduled download: {}", download.url));
+        let format_selector = settings.default_quality.format_selector();
+        let context = DownloadContext {
+            job_id: &download.job_id,
+            url: &download.url,
+            output_path: &download.output_path,
+            upload: download.upload,
+            priority: download.priority,
+            source_page: &download.source_page,
+            source: "gui",
+        };
+        let result = run_gui_download(&jobs, &history, &settings, &context, format_selector, true, download.options.backend);
+        coalesce::release_queued(&download.job_id);
+        let _ = app.emit_all("log-event", download_result_message(&download.url, result));
+    });
+}
+
+fn download_result_message(url: &str, result: Result<serde_json::Value, String>) -> String {
+    match result {
+        Ok(_) => format!("✅ Download successful: {}", url),
+        Err(error) => {
+            let reason = serde_json::from_str::<serde_json::Value>(&error)
+                .ok()
+                .and_then(|value| value["message"].as_str().map(|message| message.to_string()))
+                .unwrap_or(error);
+            format!("❌ Download failed: {}", reason)
+        }
+    }
+}
+
+pub fn run_gui(listener: Option<InstanceListener>, launch_args: Vec<String>) -> Result<(), String> {
+    let profile = Profile::active();
+    let history = profile.open_history();
+    db_backup::check_at_startup(history.clone());
+    let jobs = JobRegistry::new();
+    let settings = SettingsStore::load();
+    settings.set_profile(profile);
+    let http_api = HttpApi::new(jobs.clone(), history.clone(), settings.clone());
+    let tray = tray::build_tray(&history, &settings);
+
+    let app = tauri::Builder::default()
+        .manage(jobs)
+        .manage(LogFollower::default())
+        .manage(FolderImport::default())
+        .manage(VaultVerifier::default())
+        .manage(HistoryBackfill::default())
+        .manage(VaultExporter::default())
+        .manage(history.clone())
+        .manage(settings)
+        .manage(http_api)
+        .manage(ClipboardPrompt::default())
+        .manage(ConflictPrompts::default())
+        .system_tray(tray)
+        .on_system_tray_event(tray::handle_tray_event)
+        .on_window_event(move |event| match event.event() {
+            WindowEvent::CloseRequested { api, .. }
+                if event.window().state::<SettingsStore>().get().minimize_to_tray =>
+            {
+                api.prevent_close();
+                let _ = event.window().hide();
+            }
+            WindowEvent::FileDrop(FileDropEvent::Dropped(paths)) => drop_import::handle_drop(
+                event.window().app_handle(),
+                paths.iter().cloned().map(DroppedItem::Path).collect(),
+                "drop",
+            ),
+            _ => {}
+        })
+        .setup(move |app| {
+            // Keep the old launch-once-to-register behavior for first runs
+            if !commands::check_registration().unwrap_or(false) {
+                if let Err(error) =
+                    commands::register_host(EXTENSION_ID.to_string()).and_then(RegistrationReport::into_result)
+                {
+                    warn!("Failed to register native host: {}", error);
+                }
+            }
+
+            let handle = app.handle();
+            // The window is created hidden so a login launch goes straight to the tray
+            if !launch_args.iter().any(|arg| arg == autostart::MINIMIZED_FLAG) {
+                focus_main_window(&handle);
+            }
+            if let Some(listener) = listener {
+                let instance_handle = handle.clone();
+                listener.serve(move |message| match message {
+                    InstanceMessage::Activate { args } => {
+                        // A login launch while already running must not pop the window up
+                        if !args.iter().any(|arg| arg == autostart::MINIMIZED_FLAG) {
+                            focus_main_window(&instance_handle);
+                        }
+                        handle_launch_args(&instance_handle, &args);
+                    }
+                    InstanceMessage::Notify { notification } => notifications::show(&notification),
+                    InstanceMessage::ScheduleChanged => instance_handle.state::<JobRegistry>().scheduler().wake(),
+                    InstanceMessage::Import { paths } => drop_import::handle_drop(
+                        instance_handle.clone(),
+                        paths.into_iter().map(|path| DroppedItem::Path(PathBuf::from(path))).collect(),
+                        "shell",
+                    ),
+                    // The listener hands these to the closure below
+                    InstanceMessage::AttachNative { .. } => {}
+                }, {
+                    let attach_handle = handle.clone();
+                    move |stream, args| {
+                        native_proxy::host(
+                            stream,
+                            args,
+                            attach_handle.state::<JobRegistry>().inner().clone(),
+                            attach_handle.state::<History>().inner().clone(),
+                            attach_handle.state::<SettingsStore>().inner().clone(),
+                        )
+                    }
+                });
+            }
+            // Also runs whatever came due while the app was closed
+            let scheduler_handle = handle.clone();
+            app.state::<JobRegistry>()
+                .scheduler()
+                .start(move |download| start_scheduled_download(scheduler_handle.clone(), download));
+            retry::start(
+                app.state::<History>().inner().clone(),
+                app.state::<SettingsStore>().inner().clone(),
+                app.state::<JobRegistry>().inner().clone(),
+            );
+            connectivity::start(
+                app.state::<SettingsStore>().inner().clone(),
+                app.state::<JobRegistry>().inner().clone(),
+            );
+            handle_launch_args(&handle, &launch_args);
+            // A port conflict is reported without keeping the GUI from starting
+            if let Err(error) = app.state::<HttpApi>().apply() {
+                notifications::show(&notifications::DesktopNotification {
+                    title: "ImgVault HTTP API not started".to_string(),
+                    body: error,
+                    reveal_path: None,
+                });
+            }
+            clipboard_watch::spawn_clipboard_watcher(handle.clone());
+            tray::spawn_tray_updater(handle);
+
+            Ok(())
+        })
+        .invoke_handler(tauri::generate_handler![
+            commands::check_registration,
+            commands::check_browser_profiles,
+            commands::register_host,
+            commands::unregister_host,
+            commands::reload_path,
+            commands::check_cookies,
+            commands::test_download,
+            commands::cancel_download,
+            commands::get_settings,
+            commands::save_settings,
+            commands::export_settings,
+            commands::import_settings,
+            commands::get_log_path,
+            commands::read_log_tail,
+            commands::follow_log,
+            commands::get_crash_reports,
+            commands::run_diagnostics,
+            commands::list_sessions,
+            commands::check_for_updates,
+            commands::download_update,
+            commands::get_autostart,
+            commands::set_autostart,
+            commands::get_http_api_token,
+            commands::get_http_api_status,
+            commands::test_webhook,
+            commands::get_webhook_secret,
+            commands::set_secret,
+            commands::delete_secret,
+            commands::has_secret,
+            commands::get_queue,
+            commands::set_process_priority,
+            commands::pause_queue,
+            commands::resume_queue,
+            commands::schedule_download,
+            commands::list_scheduled_downloads,
+            commands::reschedule_download,
+            commands::run_scheduled_download_now,
+            commands::reorder_job,
+            commands::set_job_priority,
+            commands::set_auto_retry,
+            commands::resolve_conflict,
+            commands::get_pending_conflicts,
+            commands::get_speed_stats,
+            commands::confirm_clipboard_download,
+            commands::dismiss_clipboard_download,
+            commands::drop_text,
+            commands::check_shell_integration,
+            commands::install_shell_integration,
+            commands::remove_shell_integration,
+            commands::list_profiles,
+            commands::create_profile,
+            commands::delete_profile,
+            commands::set_active_profile,
+            commands::get_history_encryption,
+            commands::encrypt_history,
+            commands::preview_output_template,
+            commands::import_directory,
+            commands::cancel_import_directory,
+            commands::verify_vault,
+            commands::cancel_verify_vault,
+            commands::backfill_history,
+            commands::cancel_backfill_history,
+            commands::export_items,
+            commands::cancel_export_items,
+            commands::get_shared_content,
+            commands::get_vault_stats,
+            commands::get_events,
+            commands::get_metrics,
+            commands::check_destination,
+            commands::get_onboarding_state,
+            commands::complete_onboarding_step,
+            commands::preview_cleanup,
+            commands::perform_cleanup,
+            commands::list_database_backups,
+            commands::restore_database,
+            commands::trust_managed_tool,
+            commands::reset_metrics,
+            commands::refresh_extractor_cache,
+            commands::test_rules,
+            commands::check_subscriptions,
+            commands::make_clip,
+            commands::extract_frames,
+            commands::diagnose_url,
+        ])
+        .build(tauri::generate_context!())
+        .map_err(|e| format!("Failed to build GUI: {}", e))?;
+
+    app.run(|app, event| {
+        // Closing the last window and quitting from the tray both end here
+        if let RunEvent::Exit = event {
+            let jobs = app.state::<JobRegistry>();
+            shutdown::shut_down(&jobs, &app.state::<History>(), &app.state::<SettingsStore>().get(), |frame| {
+                jobs.events().publish(frame)
+            });
+            app.state::<HttpApi>().shutdown();
+        }
+    });
+
+    Ok(())
+}
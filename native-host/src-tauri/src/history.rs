@@ -0,0 +1,1649 @@
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{error, warn};
+
+use crate::chapters::ChapterFile;
+use crate::db_backup::{self, DatabaseBackup, SnapshotReason};
+use crate::get_app_data_directory;
+use crate::history_crypto::{self, FieldCipher, ENCRYPTED_PLACEHOLDER};
+use crate::media_info::{self, MediaInfo};
+use crate::organize::CollisionMode;
+use crate::speed_stats::TransferStats;
+use crate::supervisor::{self, SupervisionReport};
+use crate::vault_events::{self, VaultChange};
+
+pub(crate) const HISTORY_FILE_NAME: &str = "history.db";
+const SCHEMA_VERSION: i64 = 17;
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+// Search and filters can only use what stays readable in the database
+const ENCRYPTION_WARNING: &str =
+    "URLs, page titles and tags are encrypted in the history database, so searching it only matches file names and sites";
+
+// Download history shared by the GUI and every native host process. Falls back
+// to an in-memory database when the file cannot be opened so a broken app data
+// directory never blocks downloads.
+#[derive(Clone)]
+pub struct History {
+    conn: Arc<Mutex<Connection>>,
+    // For url, page_url and page_title once the database is encrypted
+    cipher: FieldCipher,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadStatus {
+    Completed,
+    Failed,
+    Cancelled,
+    // Handed back to the extension to download in the browser
+    Delegated,
+}
+
+impl DownloadStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DownloadStatus::Completed => "completed",
+            DownloadStatus::Failed => "failed",
+            DownloadStatus::Cancelled => "cancelled",
+            DownloadStatus::Delegated => "delegated",
+        }
+    }
+}
+
+pub struct NewHistoryEntry<'a> {
+    pub job_id: &'a str,
+    pub url: &'a str,
+    pub file_path: Option<&'a str>,
+    pub status: DownloadStatus,
+    pub message: Option<&'a str>,
+    pub source: &'a str,
+    pub started_at: i64,
+    pub finished_at: i64,
+    // Where the upload stage put the file, if it ran
+    pub object_key: Option<&'a str>,
+    pub etag: Option<&'a str>,
+    // What an automatic retry needs to run the job again
+    pub output_path: Option<&'a str>,
+    pub upload: bool,
+    // The page the download was started from, as the extension reported it
+    pub page_url: Option<&'a str>,
+    pub page_title: Option<&'a str>,
+    pub media_info: Option<&'a MediaInfo>,
+    // None when yt-dlp never started, e.g. a job cancelled while queued
+    pub transfer: Option<&'a TransferStats>,
+    // The native messaging session that ran the job; None for the GUI
+    pub session_id: Option<&'a str>,
+    // None for jobs without a child process, such as imports
+    pub supervision: Option<&'a SupervisionReport>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEntry {
+    pub id: i64,
+    pub job_id: String,
+    pub url: String,
+    pub file_path: Option<String>,
+    pub status: String,
+    pub message: Option<String>,
+    pub source: String,
+    pub started_at: i64,
+    pub finished_at: i64,
+    // Missing from exports made before uploads existed
+    #[serde(default)]
+    pub object_key: Option<String>,
+    #[serde(default)]
+    pub etag: Option<String>,
+    // The first attempt of the job when this entry is an automatic retry
+    #[serde(default)]
+    pub retry_of: Option<i64>,
+    // 0 for the first attempt, then 1 for the first retry and so on
+    #[serde(default)]
+    pub attempt: u32,
+    // false once the user excluded the job from automatic retries
+    #[serde(default)]
+    pub auto_retry: bool,
+    #[serde(default)]
+    pub page_url: Option<String>,
+    #[serde(default)]
+    pub page_title: Option<String>,
+    // Trimmed yt-dlp info for completed downloads
+    #[serde(default)]
+    pub media_info: Option<MediaInfo>,
+    #[serde(default)]
+    pub transfer: Option<TransferStats>,
+    #[serde(default)]
+    pub session_id: Option<String>,
+    #[serde(default)]
+    pub supervision: Option<SupervisionReport>,
+    // The organization rules the job matched, in settings order
+    #[serde(default)]
+    pub rule_ids: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // The entry this one was made from: the whole video of a chapter split
+    // from it, or the source of a clip
+    #[serde(default)]
+    pub parent_id: Option<i64>,
+    // Of the file when it was recorded or backfilled
+    #[serde(default)]
+    pub size_bytes: Option<u64>,
+}
+
+// A failed attempt nobody has retried yet
+#[derive(Debug, Clone)]
+pub struct RetryCandidate {
+    pub id: i64,
+    pub job_id: String,
+    pub url: String,
+    pub message: Option<String>,
+    pub output_path: Option<String>,
+    pub upload: bool,
+    pub attempt: u32,
+    pub finished_at: i64,
+    pub page_url: Option<String>,
+    pub page_title: Option<String>,
+}
+
+// Whether the sensitive columns are encrypted, for the settings page
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEncryption {
+    pub enabled: bool,
+    // false shows entries as "[encrypted]" until the key is restored
+    pub key_available: bool,
+    pub warning: Option<String>,
+    // Rows encrypted by the migration that returned this
+    pub encrypted_rows: usize,
+}
+
+// One native host process, from Chrome opening the port to it closing
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NativeSession {
+    pub id: String,
+    pub pid: u32,
+    pub origin: String,
+    pub started_at: i64,
+    // None while the host runs, or when it died without closing the session
+    pub ended_at: Option<i64>,
+    pub duration_ms: Option<i64>,
+}
+
+// A saved file as vault verification sees it
+#[derive(Debug, Clone)]
+pub struct SavedFile {
+    pub id: i64,
+    pub file_path: String,
+    // None until an import or a verification hashed it
+    pub sha256: Option<String>,
+}
+
+// A saved file as backfill_history sees it
+#[derive(Debug, Clone)]
+pub struct BackfillRow {
+    pub id: i64,
+    pub url: String,
+    pub file_path: String,
+    pub sha256: Option<String>,
+    pub size_bytes: Option<u64>,
+    pub media_info: Option<MediaInfo>,
+}
+
+// Disk use of the vault as get_vault_stats reports it
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VaultStats {
+    pub files: u64,
+    pub hashed_files: u64,
+    // Entries whose file is a link to another entry's
+    pub shared_files: u64,
+    pub bytes_saved: u64,
+}
+
+// A channel or user archive_channel keeps up to date
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelSubscription {
+    pub url: String,
+    // Where its items are saved; an output template like a download's
+    pub output_path: String,
+    // The newest this many items are looked at; all of them when None
+    pub max_items: Option<u32>,
+    // Items uploaded before this day (YYYYMMDD) are left out
+    pub date_after: Option<String>,
+    pub created_at: i64,
+    // When archive_channel last queued its new items
+    pub last_checked_at: Option<i64>,
+}
+
+// One finished download as speed_stats sees it
+#[derive(Debug, Clone)]
+pub struct TransferRow {
+    pub url: String,
+    pub status: String,
+    pub active_ms: Option<i64>,
+    pub average_bytes_per_second: Option<u64>,
+}
+
+impl History {
+    pub fn open_default() -> Self {
+        Self::open_in_directory(get_app_data_directory())
+    }
+
+    // The history of a profile, kept in its own directory
+    pub fn open_in_directory(directory: Result<PathBuf, String>) -> Self {
+        let opened = directory.and_then(|directory| open_connection_in(&directory));
+
+        match opened {
+            Ok(conn) => History {
+                conn: Arc::new(Mutex::new(conn)),
+                cipher: FieldCipher::default(),
+            },
+            Err(error) => {
+                warn!("{}; keeping history in memory for this session", error);
+                db_backup::note_open_failure(&error);
+                let conn = Connection::open_in_memory().expect("in-memory SQLite is always available");
+                if let Err(error) = Self::migrate(&conn) {
+                    error!("{}", error);
+                }
+                History {
+                    conn: Arc::new(Mutex::new(conn)),
+                    cipher: FieldCipher::default(),
+                }
+            }
+        }
+    }
+
+    // Points this history and every clone of it at the database in
+    // `directory`, e.g. when the GUI switches profiles
+    pub fn switch_directory(&self, directory: &Path) -> Result<(), String> {
+        let conn = open_connection_in(directory)?;
+        *self.conn.lock().unwrap() = conn;
+        Ok(())
+    }
+
+    // Lets go of the database file so a cleanup can delete it; the rest of
+    // the session is kept in memory
+    pub fn close(&self) -> Result<(), String> {
+        let conn = Connection::open_in_memory().map_err(|e| format!("Failed to open in-memory history: {}", e))?;
+        Self::migrate(&conn)?;
+        *self.conn.lock().unwrap() = conn;
+        Ok(())
+    }
+
+    // Empty when the database is fine; run when the GUI starts, since a
+    // full check reads every page
+    pub fn integrity_check(&self) -> Result<(), String> {
+        let conn = self.conn.lock().unwrap();
+        if !on_disk(&conn) {
+            return Err(db_backup::open_failure()
+                .unwrap_or_else(|| "The history database could not be opened".to_string()));
+        }
+        db_backup::integrity_check(&conn)
+    }
+
+    // Replaces the database in `directory`, which this history uses, with
+    // one of its backups after backing up what is there now. A database
+    // that could not be opened is moved aside instead.
+    pub fn restore_backup(&self, directory: &Path, name: &str, token: &str) -> Result<DatabaseBackup, String> {
+        let (backup, path) = db_backup::find(directory, name, token)?;
+        let mut conn = self.conn.lock().unwrap();
+        if on_disk(&conn) {
+            // A damaged database may not read far enough to be backed up
+            if let Err(error) = db_backup::snapshot(&conn, directory, SnapshotReason::BeforeRestore) {
+                warn!("Restoring without a backup of the current database: {}", error);
+            }
+            db_backup::copy_into(&path, &mut conn)?;
+            // A backup from before a schema upgrade gets it again
+            migrate_locked(&conn)?;
+        } else {
+            db_backup::set_aside(directory, HISTORY_FILE_NAME)?;
+            let mut restored = Connection::open(directory.join(HISTORY_FILE_NAME))
+                .map_err(|e| format!("Failed to create history database: {}", e))?;
+            db_backup::copy_into(&path, &mut restored)?;
+            drop(restored);
+            *conn = open_connection_in(directory)?;
+        }
+        db_backup::clear_startup_state();
+        Ok(backup)
+    }
+
+    fn migrate(conn: &Connection) -> Result<(), String> {
+        let version: i64 = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .map_err(|e| format!("Failed to read history schema version: {}", e))?;
+
+        if version < 1 {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS downloads (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    job_id TEXT NOT NULL,
+                    url TEXT NOT NULL,
+                    file_path TEXT,
+                    status TEXT NOT NULL,
+                    message TEXT,
+                    source TEXT NOT NULL,
+                    started_at INTEGER NOT NULL,
+                    finished_at INTEGER NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS downloads_finished_at ON downloads (finished_at);",
+            )
+            .map_err(|e| format!("Failed to create history schema: {}", e))?;
+        }
+
+        if version < 2 {
+            // Existing rows predate notifications and must never produce a toast
+            conn.execute_batch("ALTER TABLE downloads ADD COLUMN notified INTEGER NOT NULL DEFAULT 1;")
+                .map_err(|e| format!("Failed to migrate history schema: {}", e))?;
+        }
+
+        if version < 3 {
+            conn.execute_batch(
+                "ALTER TABLE downloads ADD COLUMN object_key TEXT;
+                 ALTER TABLE downloads ADD COLUMN etag TEXT;",
+            )
+            .map_err(|e| format!("Failed to migrate history schema: {}", e))?;
+        }
+
+        if version < 4 {
+            // retry_state is NULL until auto-retry queues the attempt again,
+            // 'queued' while the retry waits, and 'retried' once it recorded
+            conn.execute_batch(
+                "ALTER TABLE downloads ADD COLUMN output_path TEXT;
+                 ALTER TABLE downloads ADD COLUMN upload INTEGER NOT NULL DEFAULT 1;
+                 ALTER TABLE downloads ADD COLUMN retry_of INTEGER;
+                 ALTER TABLE downloads ADD COLUMN attempt INTEGER NOT NULL DEFAULT 0;
+                 ALTER TABLE downloads ADD COLUMN auto_retry INTEGER NOT NULL DEFAULT 1;
+                 ALTER TABLE downloads ADD COLUMN retry_state TEXT;
+                 CREATE INDEX IF NOT EXISTS downloads_job_id ON downloads (job_id);",
+            )
+            .map_err(|e| format!("Failed to migrate history schema: {}", e))?;
+        }
+
+        if version < 5 {
+            conn.execute_batch(
+                "ALTER TABLE downloads ADD COLUMN page_url TEXT;
+                 ALTER TABLE downloads ADD COLUMN page_title TEXT;",
+            )
+            .map_err(|e| format!("Failed to migrate history schema: {}", e))?;
+        }
+
+        if version < 6 {
+            // JSON text, see media_info::MediaInfo
+            conn.execute_batch("ALTER TABLE downloads ADD COLUMN media_info TEXT;")
+                .map_err(|e| format!("Failed to migrate history schema: {}", e))?;
+        }
+
+        if version < 7 {
+            conn.execute_batch(
+                "ALTER TABLE downloads ADD COLUMN queue_wait_ms INTEGER;
+                 ALTER TABLE downloads ADD COLUMN active_ms INTEGER;
+                 ALTER TABLE downloads ADD COLUMN average_bytes_per_second INTEGER;
+                 ALTER TABLE downloads ADD COLUMN peak_bytes_per_second INTEGER;
+                 ALTER TABLE downloads ADD COLUMN backend TEXT;",
+            )
+            .map_err(|e| format!("Failed to migrate history schema: {}", e))?;
+        }
+
+        if version < 8 {
+            // name/value pairs describing the database itself, such as 'encrypted'
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS history_meta (name TEXT PRIMARY KEY, value TEXT NOT NULL);",
+            )
+            .map_err(|e| format!("Failed to migrate history schema: {}", e))?;
+        }
+
+        if version < 9 {
+            // ended_at stays NULL while the host runs, or when it died without closing
+            conn.execute_batch(
+                "ALTER TABLE downloads ADD COLUMN session_id TEXT;
+                 CREATE TABLE IF NOT EXISTS native_sessions (
+                     id TEXT PRIMARY KEY,
+                     pid INTEGER NOT NULL,
+                     origin TEXT NOT NULL,
+                     started_at INTEGER NOT NULL,
+                     ended_at INTEGER
+                 );
+                 CREATE INDEX IF NOT EXISTS native_sessions_started_at ON native_sessions (started_at);",
+            )
+            .map_err(|e| format!("Failed to migrate history schema: {}", e))?;
+        }
+
+        if version < 10 {
+            // JSON text, see supervisor::SupervisionReport
+            conn.execute_batch("ALTER TABLE downloads ADD COLUMN supervision TEXT;")
+                .map_err(|e| format!("Failed to migrate history schema: {}", e))?;
+        }
+
+        if version < 11 {
+            // SHA-256 of each file a folder import recorded, so running it again
+            // skips what is already indexed
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS content_hashes (sha256 TEXT PRIMARY KEY, entry_id INTEGER NOT NULL);",
+            )
+            .map_err(|e| format!("Failed to migrate history schema: {}", e))?;
+        }
+
+        if version < 12 {
+            // SHA-256 of the saved file, set by imports and by the first vault
+            // verification that reads it
+            conn.execute_batch("ALTER TABLE downloads ADD COLUMN sha256 TEXT;")
+                .map_err(|e| format!("Failed to migrate history schema: {}", e))?;
+        }
+
+        if version < 13 {
+            // Entries whose file dedupe_mode turned into a link to an earlier
+            // entry's file; original_id is always the first holder of the content
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS shared_files (
+                     entry_id INTEGER PRIMARY KEY,
+                     original_id INTEGER NOT NULL,
+                     kind TEXT NOT NULL,
+                     bytes INTEGER NOT NULL
+                 );
+                 CREATE INDEX IF NOT EXISTS shared_files_original_id ON shared_files (original_id);
+                 CREATE INDEX IF NOT EXISTS downloads_sha256 ON downloads (sha256);",
+            )
+            .map_err(|e| format!("Failed to migrate history schema: {}", e))?;
+        }
+
+        if version < 14 {
+            // Organization rules run before a job is queued, long before its
+            // entry exists; what they decided waits in organized_jobs and is
+            // copied into every entry of the job. Both list columns hold JSON
+            // arrays.
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS organized_jobs (
+                     job_id TEXT PRIMARY KEY,
+                     rule_ids TEXT NOT NULL,
+                     tags TEXT NOT NULL,
+                     collision_mode TEXT
+                 );
+                 ALTER TABLE downloads ADD COLUMN rule_ids TEXT;
+                 ALTER TABLE downloads ADD COLUMN tags TEXT;",
+            )
+            .map_err(|e| format!("Failed to migrate history schema: {}", e))?;
+        }
+
+        if version < 15 {
+            // Chapters split from a video point at the entry of the whole video
+            conn.execute_batch("ALTER TABLE downloads ADD COLUMN parent_id INTEGER;")
+                .map_err(|e| format!("Failed to migrate history schema: {}", e))?;
+        }
+
+        if version < 16 {
+            // Channels archive_channel was run on, so check_subscriptions and
+            // the next run only look at what is new. date_after is YYYYMMDD.
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS subscriptions (
+                     url TEXT PRIMARY KEY,
+                     output_path TEXT NOT NULL,
+                     max_items INTEGER,
+                     date_after TEXT,
+                     created_at INTEGER NOT NULL,
+                     last_checked_at INTEGER
+                 );",
+            )
+            .map_err(|e| format!("Failed to migrate history schema: {}", e))?;
+        }
+
+        if version < 17 {
+            // Older entries have none until backfill_history reads their files
+            conn.execute_batch("ALTER TABLE downloads ADD COLUMN size_bytes INTEGER;")
+                .map_err(|e| format!("Failed to migrate history schema: {}", e))?;
+        }
+
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION)
+            .map_err(|e| format!("Failed to record history schema version: {}", e))
+    }
+
+    // An attempt started by auto-retry keeps the job id, which links the new
+    // entry to the one it retries
+    pub fn record(&self, entry: &NewHistoryEntry) -> Result<i64, String> {
+        let (id, tags) = {
+            let mut conn = self.conn.lock().unwrap();
+            db_backup::snapshot_daily(&conn);
+            let tx = conn
+                .transaction()
+                .map_err(|e| format!("Failed to record download history: {}", e))?;
+            let id = self.insert_entry(&tx, entry)?;
+            let tags = self.entry_tags(&tx, id)?;
+            tx.commit()
+                .map_err(|e| format!("Failed to record download history: {}", e))?;
+            (id, tags)
+        };
+        if let (DownloadStatus::Completed, Some(file_path)) = (entry.status, entry.file_path) {
+            announce_saved(id, file_path, entry.source, tags);
+        }
+        Ok(id)
+    }
+
+    // One entry per chapter file, copied from the entry of the whole video.
+    // They are never notified about or retried on their own.
+    pub fn record_chapters(&self, parent_id: i64, chapters: &[ChapterFile]) -> Result<Vec<i64>, String> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn
+            .transaction()
+            .map_err(|e| format!("Failed to record chapter history: {}", e))?;
+        let mut ids = Vec::with_capacity(chapters.len());
+        let mut saved = Vec::with_capacity(chapters.len());
+        for chapter in chapters {
+            let id = Self::insert_derived(&tx, parent_id, &chapter.file_path, &chapter.title, None, None)?;
+            ids.push(id);
+            saved.push((id, &chapter.file_path, self.entry_tags(&tx, id)?));
+        }
+        tx.commit()
+            .map_err(|e| format!("Failed to record chapter history: {}", e))?;
+        drop(conn);
+        for (id, file_path, tags) in saved {
+            announce_saved(id, file_path, "chapter", tags);
+        }
+        Ok(ids)
+    }
+
+    // A file made from entry `parent_id`'s, such as a clip or a still frame;
+    // `source` says which
+    pub fn record_derived(
+        &self,
+        parent_id: i64,
+        file_path: &str,
+        message: &str,
+        source: &str,
+        finished_at: i64,
+    ) -> Result<i64, String> {
+        let (id, tags) = {
+            let conn = self.conn.lock().unwrap();
+            let id = Self::insert_derived(&conn, parent_id, file_path, message, Some(source), Some(finished_at))?;
+            (id, self.entry_tags(&conn, id)?)
+        };
+        announce_saved(id, file_path, source, tags);
+        Ok(id)
+    }
+
+    // An entry for a file made from entry `parent_id`'s, which it copies
+    // everything else from; `source` and `finished_at` replace the parent's
+    fn insert_derived(
+        conn: &Connection,
+        parent_id: i64,
+        file_path: &str,
+        message: &str,
+        source: Option<&str>,
+        finished_at: Option<i64>,
+    ) -> Result<i64, String> {
+        let inserted = conn
+            .execute(
+                "INSERT INTO downloads (job_id, url, file_path, status, message, source, started_at, finished_at, notified,
+                                        output_path, upload, attempt, auto_retry, page_url, page_title, backend, session_id,
+                                        rule_ids, tags, parent_id, size_bytes)
+                 SELECT job_id, url, ?2, status, ?3, COALESCE(?4, source), started_at, COALESCE(?5, finished_at), 1,
+                        output_path, upload, attempt, 0, page_url, page_title, backend, session_id,
+                        rule_ids, tags, id, ?6
+                 FROM downloads WHERE id = ?1",
+                params![parent_id, file_path, message, source, finished_at, file_size(Some(file_path))],
+            )
+            .map_err(|e| format!("Failed to record derived history entry: {}", e))?;
+        if inserted == 0 {
+            return Err(format!("History entry {} not found", parent_id));
+        }
+        Ok(conn.last_insert_rowid())
+    }
+
+    // Records an imported file together with its hash. None, with nothing
+    // recorded, when a file with the same contents was imported before.
+    pub fn record_import(&self, entry: &NewHistoryEntry, sha256: &str) -> Result<Option<i64>, String> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn
+            .transaction()
+            .map_err(|e| format!("Failed to record download history: {}", e))?;
+        if Self::imported_entry(&tx, sha256)?.is_some() {
+            return Ok(None);
+        }
+        let id = self.insert_entry(&tx, entry)?;
+        tx.execute(
+            "INSERT INTO content_hashes (sha256, entry_id) VALUES (?1, ?2)",
+            params![sha256, id],
+        )
+        .map_err(|e| format!("Failed to record download history: {}", e))?;
+        tx.execute("UPDATE downloads SET sha256 = ?1 WHERE id = ?2", params![sha256, id])
+            .map_err(|e| format!("Failed to record download history: {}", e))?;
+        let tags = self.entry_tags(&tx, id)?;
+        tx.commit()
+            .map_err(|e| format!("Failed to record download history: {}", e))?;
+        drop(conn);
+        if let Some(file_path) = entry.file_path {
+            announce_saved(id, file_path, entry.source, tags);
+        }
+        Ok(Some(id))
+    }
+
+    // The entry a file with these contents was imported as
+    pub fn find_import(&self, sha256: &str) -> Result<Option<i64>, String> {
+        let conn = self.conn.lock().unwrap();
+        Self::imported_entry(&conn, sha256)
+    }
+
+    fn imported_entry(conn: &Connection, sha256: &str) -> Result<Option<i64>, String> {
+        conn.query_row(
+            "SELECT entry_id FROM content_hashes WHERE sha256 = ?1",
+            params![sha256],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(|e| format!("Failed to query download history: {}", e))
+    }
+
+    fn insert_entry(&self, tx: &Connection, entry: &NewHistoryEntry) -> Result<i64, String> {
+        let retried = tx
+            .query_row(
+                "SELECT id, retry_of, attempt, auto_retry FROM downloads
+                 WHERE job_id = ?1 AND retry_state = 'queued' ORDER BY id DESC LIMIT 1",
+                params![entry.job_id],
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Option<i64>>(1)?, row.get::<_, u32>(2)?, row.get::<_, bool>(3)?)),
+            )
+            .optional()
+            .map_err(|e| format!("Failed to record download history: {}", e))?;
+        let (retry_of, attempt, auto_retry) = match retried {
+            Some((id, retry_of, attempt, auto_retry)) => {
+                tx.execute("UPDATE downloads SET retry_state = 'retried' WHERE id = ?1", params![id])
+                    .map_err(|e| format!("Failed to record download history: {}", e))?;
+                (Some(retry_of.unwrap_or(id)), attempt + 1, auto_retry)
+            }
+            None => (None, 0, true),
+        };
+        // Read on every write, since another process may have encrypted the database
+        let encrypted = encryption_enabled(tx)?;
+        let url = self.seal(encrypted, Some(entry.url))?;
+        let page_url = self.seal(encrypted, entry.page_url)?;
+        let page_title = self.seal(encrypted, entry.page_title)?;
+        // Sealed here too, in case the job was organized before encryption was on
+        let tags = tx
+            .query_row("SELECT tags FROM organized_jobs WHERE job_id = ?1", params![entry.job_id], |row| row.get(0))
+            .optional()
+            .map_err(|e| format!("Failed to record download history: {}", e))?
+            .map(list_from_column)
+            .unwrap_or_default();
+        let tags = self.seal_list(encrypted, &tags)?;
+
+        tx.execute(
+            "INSERT INTO downloads (job_id, url, file_path, status, message, source, started_at, finished_at, notified, object_key, etag,
+                                    output_path, upload, retry_of, attempt, auto_retry, page_url, page_title, media_info,
+                                    queue_wait_ms, active_ms, average_bytes_per_second, peak_bytes_per_second, backend, session_id,
+                                    supervision, rule_ids, tags, size_bytes)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, 0, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25,
+                     (SELECT rule_ids FROM organized_jobs WHERE job_id = ?1), ?26, ?27)",
+            params![
+                entry.job_id,
+                url,
+                entry.file_path,
+                entry.status.as_str(),
+                entry.message,
+                entry.source,
+                entry.started_at,
+                entry.finished_at,
+                entry.object_key,
+                entry.etag,
+                entry.output_path,
+                entry.upload,
+                retry_of,
+                attempt,
+                auto_retry,
+                page_url,
+                page_title,
+                media_info::to_column(entry.media_info),
+                entry.transfer.map(|transfer| transfer.queue_wait_ms),
+                entry.transfer.map(|transfer| transfer.active_ms),
+                entry.transfer.map(|transfer| transfer.average_bytes_per_second as i64),
+                entry.transfer.map(|transfer| transfer.peak_bytes_per_second as i64),
+                entry.transfer.map(|transfer| transfer.backend.as_str()),
+                entry.session_id,
+                supervisor::to_column(entry.supervision),
+                tags,
+                file_size(entry.file_path),
+            ],
+        )
+        .map_err(|e| format!("Failed to record download history: {}", e))?;
+        Ok(tx.last_insert_rowid())
+    }
+
+    pub fn recent(&self, limit: usize) -> Result<Vec<HistoryEntry>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare(
+                "SELECT id, job_id, url, file_path, status, message, source, started_at, finished_at, object_key, etag, retry_of, attempt, auto_retry,
+                        page_url, page_title, media_info,
+                        queue_wait_ms, active_ms, average_bytes_per_second, peak_bytes_per_second, backend, session_id, supervision, rule_ids, tags, parent_id, size_bytes
+                 FROM downloads ORDER BY id DESC LIMIT ?1",
+            )
+            .map_err(|e| format!("Failed to query download history: {}", e))?;
+
+        let rows = statement
+            .query_map(params![limit as i64], map_history_row)
+            .map_err(|e| format!("Failed to query download history: {}", e))?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to read download history: {}", e))
+            .map(|entries| self.reveal(entries))
+    }
+
+    pub fn recent_with_status(&self, status: DownloadStatus, limit: usize) -> Result<Vec<HistoryEntry>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare(
+                "SELECT id, job_id, url, file_path, status, message, source, started_at, finished_at, object_key, etag, retry_of, attempt, auto_retry,
+                        page_url, page_title, media_info,
+                        queue_wait_ms, active_ms, average_bytes_per_second, peak_bytes_per_second, backend, session_id, supervision, rule_ids, tags, parent_id, size_bytes
+                 FROM downloads WHERE status = ?1 ORDER BY id DESC LIMIT ?2",
+            )
+            .map_err(|e| format!("Failed to query download history: {}", e))?;
+
+        let rows = statement
+            .query_map(params![status.as_str(), limit as i64], map_history_row)
+            .map_err(|e| format!("Failed to query download history: {}", e))?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to read download history: {}", e))
+            .map(|entries| self.reveal(entries))
+    }
+
+    pub fn all(&self) -> Result<Vec<HistoryEntry>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare(
+                "SELECT id, job_id, url, file_path, status, message, source, started_at, finished_at, object_key, etag, retry_of, attempt, auto_retry,
+                        page_url, page_title, media_info,
+                        queue_wait_ms, active_ms, average_bytes_per_second, peak_bytes_per_second, backend, session_id, supervision, rule_ids, tags, parent_id, size_bytes
+                 FROM downloads ORDER BY id",
+            )
+            .map_err(|e| format!("Failed to query download history: {}", e))?;
+
+        let rows = statement
+            .query_map([], map_history_row)
+            .map_err(|e| format!("Failed to query download history: {}", e))?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to read download history: {}", e))
+            .map(|entries| self.reveal(entries))
+    }
+
+    pub fn get(&self, id: i64) -> Result<Option<HistoryEntry>, String> {
+        let conn = self.conn.lock().unwrap();
+        let entry = conn
+            .query_row(
+                "SELECT id, job_id, url, file_path, status, message, source, started_at, finished_at, object_key, etag, retry_of, attempt, auto_retry,
+                        page_url, page_title, media_info,
+                        queue_wait_ms, active_ms, average_bytes_per_second, peak_bytes_per_second, backend, session_id, supervision, rule_ids, tags, parent_id, size_bytes
+                 FROM downloads WHERE id = ?1",
+                params![id],
+                map_history_row,
+            )
+            .optional()
+            .map_err(|e| format!("Failed to query download history: {}", e))?;
+        Ok(entry.and_then(|entry| self.reveal(vec![entry]).pop()))
+    }
+
+    // The newest entry of a job that finished at or after `since`
+    pub fn latest_for_job(&self, job_id: &str, since: i64) -> Result<Option<HistoryEntry>, String> {
+        let conn = self.conn.lock().unwrap();
+        let entry = conn
+            .query_row(
+                "SELECT id, job_id, url, file_path, status, message, source, started_at, finished_at, object_key, etag, retry_of, attempt, auto_retry,
+                        page_url, page_title, media_info,
+                        queue_wait_ms, active_ms, average_bytes_per_second, peak_bytes_per_second, backend, session_id, supervision, rule_ids, tags, parent_id, size_bytes
+                 FROM downloads WHERE job_id = ?1 AND finished_at >= ?2 AND parent_id IS NULL ORDER BY id DESC LIMIT 1",
+                params![job_id, since],
+                map_history_row,
+            )
+            .optional()
+            .map_err(|e| format!("Failed to query download history: {}", e))?;
+        Ok(entry.and_then(|entry| self.reveal(vec![entry]).pop()))
+    }
+
+    // Insert entries from another machine, skipping ones already present.
+    // Imported rows are marked notified so they never produce a toast, and
+    // excluded from auto-retry since their output paths belong elsewhere.
+    pub fn import(&self, entries: &[HistoryEntry]) -> Result<usize, String> {
+        let conn = self.conn.lock().unwrap();
+        let encrypted = encryption_enabled(&conn)?;
+        let mut imported = 0;
+
+        for entry in entries {
+            let url = self.seal(encrypted, Some(entry.url.as_str()))?;
+            let page_url = self.seal(encrypted, entry.page_url.as_deref())?;
+            let page_title = self.seal(encrypted, entry.page_title.as_deref())?;
+            let tags = self.seal_list(encrypted, &entry.tags)?;
+            imported += conn
+                .execute(
+                    "INSERT INTO downloads (job_id, url, file_path, status, message, source, started_at, finished_at, notified, object_key, etag, auto_retry,
+                                            page_url, page_title, media_info,
+                                            queue_wait_ms, active_ms, average_bytes_per_second, peak_bytes_per_second, backend, session_id,
+                                            supervision, rule_ids, tags, size_bytes)
+                     SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, 1, ?9, ?10, 0, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23
+                     WHERE NOT EXISTS (
+                         SELECT 1 FROM downloads WHERE job_id = ?1 AND finished_at = ?8
+                           AND (url = ?2 OR url LIKE 'enc:%')
+                     )",
+                    params![
+                        entry.job_id,
+                        url,
+                        entry.file_path,
+                        entry.status,
+                        entry.message,
+                        entry.source,
+                        entry.started_at,
+                        entry.finished_at,
+                        entry.object_key,
+                        entry.etag,
+                        page_url,
+                        page_title,
+                        media_info::to_column(entry.media_info.as_ref()),
+                        entry.transfer.as_ref().map(|transfer| transfer.queue_wait_ms),
+                        entry.transfer.as_ref().map(|transfer| transfer.active_ms),
+                        entry.transfer.as_ref().map(|transfer| transfer.average_bytes_per_second as i64),
+                        entry.transfer.as_ref().map(|transfer| transfer.peak_bytes_per_second as i64),
+                        entry.transfer.as_ref().map(|transfer| transfer.backend.as_str()),
+                        entry.session_id,
+                        supervisor::to_column(entry.supervision.as_ref()),
+                        list_to_column(&entry.rule_ids),
+                        tags,
+                        entry.size_bytes.map(|size| size as i64),
+                    ],
+                )
+                .map_err(|e| format!("Failed to import download history: {}", e))?;
+        }
+
+        Ok(imported)
+    }
+
+    pub fn mark_notified(&self, id: i64) -> Result<(), String> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("UPDATE downloads SET notified = 1 WHERE id = ?1", params![id])
+            .map(|_| ())
+            .map_err(|e| format!("Failed to update download history: {}", e))
+    }
+
+    // Atomically takes ownership of every finished download with `status` that no
+    // process has notified about yet, so concurrent host processes never double-toast
+    pub fn claim_unnotified(&self, status: DownloadStatus, since: i64) -> Result<Vec<HistoryEntry>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare(
+                "UPDATE downloads SET notified = 1
+                 WHERE notified = 0 AND status = ?1 AND finished_at >= ?2
+                 RETURNING id, job_id, url, file_path, status, message, source, started_at, finished_at, object_key, etag, retry_of, attempt, auto_retry,
+                           page_url, page_title, media_info,
+                           queue_wait_ms, active_ms, average_bytes_per_second, peak_bytes_per_second, backend, session_id, supervision, rule_ids, tags, parent_id, size_bytes",
+            )
+            .map_err(|e| format!("Failed to claim download notifications: {}", e))?;
+
+        let rows = statement
+            .query_map(params![status.as_str(), since], map_history_row)
+            .map_err(|e| format!("Failed to claim download notifications: {}", e))?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to claim download notifications: {}", e))
+            .map(|entries| self.reveal(entries))
+    }
+
+    // Failed attempts not yet retried whose job first failed at or after `since`
+    pub fn retry_candidates(&self, since: i64) -> Result<Vec<RetryCandidate>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare(
+                "SELECT failed.id, failed.job_id, failed.url, failed.message, failed.output_path, failed.upload,
+                        failed.attempt, failed.finished_at, failed.page_url, failed.page_title
+                 FROM downloads failed LEFT JOIN downloads first ON first.id = failed.retry_of
+                 WHERE failed.status = 'failed' AND failed.retry_state IS NULL AND failed.auto_retry = 1
+                   AND COALESCE(first.finished_at, failed.finished_at) >= ?1
+                 ORDER BY failed.id",
+            )
+            .map_err(|e| format!("Failed to query retryable downloads: {}", e))?;
+
+        let rows = statement
+            .query_map(params![since], |row| {
+                Ok(RetryCandidate {
+                    id: row.get(0)?,
+                    job_id: row.get(1)?,
+                    url: row.get(2)?,
+                    message: row.get(3)?,
+                    output_path: row.get(4)?,
+                    upload: row.get(5)?,
+                    attempt: row.get(6)?,
+                    finished_at: row.get(7)?,
+                    page_url: row.get(8)?,
+                    page_title: row.get(9)?,
+                })
+            })
+            .map_err(|e| format!("Failed to query retryable downloads: {}", e))?;
+
+        let candidates = rows
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to read retryable downloads: {}", e))?;
+        // Without the key there is no URL to download again
+        Ok(candidates
+            .into_iter()
+            .map(|candidate| RetryCandidate {
+                url: self.cipher.decrypt(candidate.url),
+                page_url: candidate.page_url.map(|value| self.cipher.decrypt(value)),
+                page_title: candidate.page_title.map(|value| self.cipher.decrypt(value)),
+                ..candidate
+            })
+            .filter(|candidate| candidate.url != ENCRYPTED_PLACEHOLDER)
+            .collect())
+    }
+
+    // Url, status and transfer numbers of every download finished at or after
+    // `since`, for speed_stats
+    pub fn transfer_rows(&self, since: i64) -> Result<Vec<TransferRow>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare(
+                "SELECT url, status, active_ms, average_bytes_per_second FROM downloads
+                 WHERE finished_at >= ?1 AND parent_id IS NULL ORDER BY id",
+            )
+            .map_err(|e| format!("Failed to query download history: {}", e))?;
+
+        let rows = statement
+            .query_map(params![since], |row| {
+                Ok(TransferRow {
+                    url: self.cipher.decrypt(row.get(0)?),
+                    status: row.get(1)?,
+                    active_ms: row.get(2)?,
+                    average_bytes_per_second: row.get::<_, Option<i64>>(3)?.map(|speed| speed.max(0) as u64),
+                })
+            })
+            .map_err(|e| format!("Failed to query download history: {}", e))?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to read download history: {}", e))
+    }
+
+    // Completed entries with a file, finished within the range when one is
+    // given, with the hash recorded for each
+    pub fn saved_files(&self, from: Option<i64>, to: Option<i64>) -> Result<Vec<SavedFile>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare(
+                "SELECT id, file_path, sha256 FROM downloads
+                 WHERE status = 'completed' AND file_path IS NOT NULL
+                   AND finished_at >= ?1 AND finished_at <= ?2
+                 ORDER BY id",
+            )
+            .map_err(|e| format!("Failed to query download history: {}", e))?;
+
+        let rows = statement
+            .query_map(params![from.unwrap_or(i64::MIN), to.unwrap_or(i64::MAX)], |row| {
+                Ok(SavedFile {
+                    id: row.get(0)?,
+                    file_path: row.get(1)?,
+                    sha256: row.get(2)?,
+                })
+            })
+            .map_err(|e| format!("Failed to query download history: {}", e))?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to read download history: {}", e))
+    }
+
+    pub fn set_sha256(&self, id: i64, sha256: &str) -> Result<(), String> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("UPDATE downloads SET sha256 = ?1 WHERE id = ?2", params![sha256, id])
+            .map_err(|e| format!("Failed to update download history: {}", e))?;
+        Ok(())
+    }
+
+    // Saved files with what backfill_history may fill in, oldest first
+    pub fn backfill_rows(&self, from: Option<i64>, to: Option<i64>) -> Result<Vec<BackfillRow>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare(
+                "SELECT id, url, file_path, sha256, size_bytes, media_info FROM downloads
+                 WHERE status = 'completed' AND file_path IS NOT NULL
+                   AND finished_at >= ?1 AND finished_at <= ?2
+                 ORDER BY id",
+            )
+            .map_err(|e| format!("Failed to query download history: {}", e))?;
+
+        let rows = statement
+            .query_map(params![from.unwrap_or(i64::MIN), to.unwrap_or(i64::MAX)], |row| {
+                Ok(BackfillRow {
+                    id: row.get(0)?,
+                    url: row.get(1)?,
+                    file_path: row.get(2)?,
+                    sha256: row.get(3)?,
+                    size_bytes: row.get::<_, Option<i64>>(4)?.map(|size| size as u64),
+                    media_info: media_info::from_column(row.get(5)?),
+                })
+            })
+            .map_err(|e| format!("Failed to query download history: {}", e))?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to read download history: {}", e))
+            .map(|rows| {
+                rows.into_iter()
+                    .map(|row| BackfillRow {
+                        url: self.cipher.decrypt(row.url),
+                        ..row
+                    })
+                    .collect()
+            })
+    }
+
+    pub fn set_size_bytes(&self, id: i64, size_bytes: u64) -> Result<(), String> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("UPDATE downloads SET size_bytes = ?1 WHERE id = ?2", params![size_bytes as i64, id])
+            .map_err(|e| format!("Failed to update download history: {}", e))?;
+        Ok(())
+    }
+
+    pub fn set_media_info(&self, id: i64, info: &MediaInfo) -> Result<(), String> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE downloads SET media_info = ?1 WHERE id = ?2",
+            params![media_info::to_column(Some(info)), id],
+        )
+        .map_err(|e| format!("Failed to update download history: {}", e))?;
+        Ok(())
+    }
+
+    // Kept for the job until its entries are recorded; nothing is kept when
+    // no rule matched and the request had no tags
+    pub fn record_organized_job(
+        &self,
+        job_id: &str,
+        rule_ids: &[String],
+        tags: &[String],
+        collision_mode: Option<CollisionMode>,
+    ) -> Result<(), String> {
+        if rule_ids.is_empty() && tags.is_empty() {
+            return Ok(());
+        }
+        let conn = self.conn.lock().unwrap();
+        let tags = self.seal_list(encryption_enabled(&conn)?, tags)?.unwrap_or_else(|| "[]".to_string());
+        conn.execute(
+            "INSERT OR REPLACE INTO organized_jobs (job_id, rule_ids, tags, collision_mode) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                job_id,
+                serde_json::to_string(rule_ids).unwrap_or_default(),
+                tags,
+                collision_mode.map(CollisionMode::as_str),
+            ],
+        )
+        .map_err(|e| format!("Failed to update download history: {}", e))?;
+        Ok(())
+    }
+
+    pub fn organized_collision_mode(&self, job_id: &str) -> Result<Option<CollisionMode>, String> {
+        let conn = self.conn.lock().unwrap();
+        let collision_mode = conn
+            .query_row(
+                "SELECT collision_mode FROM organized_jobs WHERE job_id = ?1",
+                params![job_id],
+                |row| row.get::<_, Option<String>>(0),
+            )
+            .optional()
+            .map_err(|e| format!("Failed to query download history: {}", e))?;
+        Ok(collision_mode.flatten().as_deref().and_then(CollisionMode::parse))
+    }
+
+    // Completed entries whose file had this hash when it was read, oldest first
+    pub fn files_with_sha256(&self, sha256: &str) -> Result<Vec<SavedFile>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare(
+                "SELECT id, file_path, sha256 FROM downloads
+                 WHERE sha256 = ?1 AND status = 'completed' AND file_path IS NOT NULL
+                 ORDER BY id",
+            )
+            .map_err(|e| format!("Failed to query download history: {}", e))?;
+
+        let rows = statement
+            .query_map(params![sha256], |row| {
+                Ok(SavedFile {
+                    id: row.get(0)?,
+                    file_path: row.get(1)?,
+                    sha256: row.get(2)?,
+                })
+            })
+            .map_err(|e| format!("Failed to query download history: {}", e))?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to read download history: {}", e))
+    }
+
+    // Links are always recorded against the first holder of the content, so
+    // a link to a link points at its original instead
+    pub fn record_shared_content(&self, entry_id: i64, linked_to: i64, kind: &str, bytes: u64) -> Result<(), String> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO shared_files (entry_id, original_id, kind, bytes)
+             VALUES (?1, COALESCE((SELECT original_id FROM shared_files WHERE entry_id = ?2), ?2), ?3, ?4)",
+            params![entry_id, linked_to, kind, bytes as i64],
+        )
+        .map_err(|e| format!("Failed to update download history: {}", e))?;
+        Ok(())
+    }
+
+    // The other entries whose files are the same content on disk as this
+    // entry's, for a warning before either is deleted
+    pub fn shared_with(&self, entry_id: i64) -> Result<Vec<SavedFile>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare(
+                "WITH root AS (
+                     SELECT COALESCE((SELECT original_id FROM shared_files WHERE entry_id = ?1), ?1) AS id
+                 )
+                 SELECT d.id, d.file_path, d.sha256 FROM downloads d, root
+                 WHERE d.id != ?1 AND d.file_path IS NOT NULL
+                   AND (d.id = root.id OR d.id IN (SELECT entry_id FROM shared_files WHERE original_id = root.id))
+                 ORDER BY d.id",
+            )
+            .map_err(|e| format!("Failed to query download history: {}", e))?;
+
+        let rows = statement
+            .query_map(params![entry_id], |row| {
+                Ok(SavedFile {
+                    id: row.get(0)?,
+                    file_path: row.get(1)?,
+                    sha256: row.get(2)?,
+                })
+            })
+            .map_err(|e| format!("Failed to query download history: {}", e))?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to read download history: {}", e))
+    }
+
+    pub fn vault_stats(&self) -> Result<VaultStats, String> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT
+                 (SELECT COUNT(*) FROM downloads WHERE status = 'completed' AND file_path IS NOT NULL),
+                 (SELECT COUNT(*) FROM downloads WHERE status = 'completed' AND file_path IS NOT NULL AND sha256 IS NOT NULL),
+                 (SELECT COUNT(*) FROM shared_files),
+                 (SELECT COALESCE(SUM(bytes), 0) FROM shared_files)",
+            [],
+            |row| {
+                Ok(VaultStats {
+                    files: row.get::<_, i64>(0)? as u64,
+                    hashed_files: row.get::<_, i64>(1)? as u64,
+                    shared_files: row.get::<_, i64>(2)? as u64,
+                    bytes_saved: row.get::<_, i64>(3)? as u64,
+                })
+            },
+        )
+        .map_err(|e| format!("Failed to query download history: {}", e))
+    }
+
+    // 0 for the first attempt of a job, 1 for its first retry and so on
+    pub fn attempt(&self, id: i64) -> Result<u32, String> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("SELECT attempt FROM downloads WHERE id = ?1", params![id], |row| row.get(0))
+            .map_err(|e| format!("Failed to query download history: {}", e))
+    }
+
+    // Marks a failed attempt as queued for retry; false when another process got there first
+    pub fn claim_retry(&self, id: i64) -> Result<bool, String> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE downloads SET retry_state = 'queued' WHERE id = ?1 AND retry_state IS NULL",
+            params![id],
+        )
+        .map(|changed| changed == 1)
+        .map_err(|e| format!("Failed to update download history: {}", e))
+    }
+
+    // Undoes claim_retry when the retry could not be queued
+    pub fn release_retry(&self, id: i64) -> Result<(), String> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE downloads SET retry_state = NULL WHERE id = ?1 AND retry_state = 'queued'",
+            params![id],
+        )
+        .map(|_| ())
+        .map_err(|e| format!("Failed to update download history: {}", e))
+    }
+
+    // Includes or excludes every attempt of the entry's job from auto-retry
+    pub fn set_auto_retry(&self, id: i64, enabled: bool) -> Result<(), String> {
+        let conn = self.conn.lock().unwrap();
+        let changed = conn
+            .execute(
+                "UPDATE downloads SET auto_retry = ?2
+                 WHERE COALESCE(retry_of, id) = (SELECT COALESCE(retry_of, id) FROM downloads WHERE id = ?1)",
+                params![id, enabled],
+            )
+            .map_err(|e| format!("Failed to update download history: {}", e))?;
+        if changed == 0 {
+            return Err(format!("No download history entry {}", id));
+        }
+        Ok(())
+    }
+
+    // Waits for any write in progress and pushes cached pages to disk, the
+    // last step before the process exits
+    pub fn flush(&self) -> Result<(), String> {
+        let conn = self.conn.lock().unwrap();
+        conn.cache_flush()
+            .map_err(|e| format!("Failed to flush download history: {}", e))?;
+        // Fold the WAL back into the database so nothing depends on it after exit
+        conn.query_row("PRAGMA wal_checkpoint(PASSIVE)", [], |_| Ok(()))
+            .map_err(|e| format!("Failed to checkpoint download history: {}", e))
+    }
+
+    pub fn latest_id(&self) -> Result<Option<i64>, String> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("SELECT MAX(id) FROM downloads", [], |row| row.get(0))
+            .map_err(|e| format!("Failed to query download history: {}", e))
+    }
+
+    // Output path of the newest download that named one, e.g. the folder an
+    // older build was told to save into
+    pub fn latest_output_path(&self) -> Result<Option<String>, String> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT output_path FROM downloads WHERE output_path IS NOT NULL ORDER BY id DESC LIMIT 1",
+            [],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(|e| format!("Failed to query download history: {}", e))
+    }
+
+    // URLs of every completed download, for archive_channel to leave out
+    pub fn completed_urls(&self) -> Result<HashSet<String>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare("SELECT url FROM downloads WHERE status = 'completed' AND parent_id IS NULL")
+            .map_err(|e| format!("Failed to query download history: {}", e))?;
+        let rows = statement
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(|e| format!("Failed to query download history: {}", e))?;
+        rows.map(|url| url.map(|url| self.cipher.decrypt(url)))
+            .collect::<Result<HashSet<_>, _>>()
+            .map_err(|e| format!("Failed to read download history: {}", e))
+    }
+
+    // Adds the subscription, or updates the one for the same URL
+    pub fn save_subscription(&self, subscription: &ChannelSubscription) -> Result<(), String> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO subscriptions (url, output_path, max_items, date_after, created_at, last_checked_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT (url) DO UPDATE SET output_path = excluded.output_path, max_items = excluded.max_items,
+                 date_after = excluded.date_after, last_checked_at = excluded.last_checked_at",
+            params![
+                subscription.url,
+                subscription.output_path,
+                subscription.max_items,
+                subscription.date_after,
+                subscription.created_at,
+                subscription.last_checked_at,
+            ],
+        )
+        .map(|_| ())
+        .map_err(|e| format!("Failed to save subscription: {}", e))
+    }
+
+    pub fn subscription(&self, url: &str) -> Result<Option<ChannelSubscription>, String> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT url, output_path, max_items, date_after, created_at, last_checked_at FROM subscriptions WHERE url = ?1",
+            params![url],
+            map_subscription_row,
+        )
+        .optional()
+        .map_err(|e| format!("Failed to query subscriptions: {}", e))
+    }
+
+    // Oldest first
+    pub fn subscriptions(&self) -> Result<Vec<ChannelSubscription>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare(
+                "SELECT url, output_path, max_items, date_after, created_at, last_checked_at FROM subscriptions
+                 ORDER BY created_at",
+            )
+            .map_err(|e| format!("Failed to query subscriptions: {}", e))?;
+        let rows = statement
+            .query_map([], map_subscription_row)
+            .map_err(|e| format!("Failed to query subscriptions: {}", e))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to read subscriptions: {}", e))
+    }
+
+    pub fn start_session(&self, id: &str, origin: &str, started_at: i64) -> Result<(), String> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO native_sessions (id, pid, origin, started_at, ended_at) VALUES (?1, ?2, ?3, ?4, NULL)",
+            params![id, std::process::id(), origin, started_at],
+        )
+        .map(|_| ())
+        .map_err(|e| format!("Failed to record native session: {}", e))
+    }
+
+    pub fn end_session(&self, id: &str, ended_at: i64) -> Result<(), String> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("UPDATE native_sessions SET ended_at = ?2 WHERE id = ?1", params![id, ended_at])
+            .map(|_| ())
+            .map_err(|e| format!("Failed to record native session: {}", e))
+    }
+
+    // Most recent first
+    pub fn sessions(&self, limit: usize) -> Result<Vec<NativeSession>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare(
+                "SELECT id, pid, origin, started_at, ended_at FROM native_sessions
+                 ORDER BY started_at DESC LIMIT ?1",
+            )
+            .map_err(|e| format!("Failed to query native sessions: {}", e))?;
+
+        let rows = statement
+            .query_map(params![limit as i64], |row| {
+                let started_at: i64 = row.get(3)?;
+                let ended_at: Option<i64> = row.get(4)?;
+                Ok(NativeSession {
+                    id: row.get(0)?,
+                    pid: row.get(1)?,
+                    origin: row.get(2)?,
+                    started_at,
+                    ended_at,
+                    duration_ms: ended_at.map(|ended_at| ended_at - started_at),
+                })
+            })
+            .map_err(|e| format!("Failed to query native sessions: {}", e))?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to read native sessions: {}", e))
+    }
+
+    pub fn encryption(&self) -> Result<HistoryEncryption, String> {
+        let conn = self.conn.lock().unwrap();
+        let enabled = encryption_enabled(&conn)?;
+        Ok(HistoryEncryption {
+            enabled,
+            key_available: enabled && self.cipher.has_key(),
+            warning: enabled.then(|| ENCRYPTION_WARNING.to_string()),
+            encrypted_rows: 0,
+        })
+    }
+
+    // Turns on encryption for this database: creates the key in the keychain
+    // if needed, then encrypts every plaintext url, page_url, page_title and tag.
+    // Running it again encrypts nothing twice.
+    pub fn encrypt_existing(&self) -> Result<HistoryEncryption, String> {
+        self.cipher.create_key()?;
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn
+            .transaction()
+            .map_err(|e| format!("Failed to encrypt download history: {}", e))?;
+
+        let rows = {
+            let mut statement = tx
+                .prepare("SELECT id, url, page_url, page_title, tags FROM downloads ORDER BY id")
+                .map_err(|e| format!("Failed to encrypt download history: {}", e))?;
+            let rows = statement
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, Option<String>>(2)?,
+                        row.get::<_, Option<String>>(3)?,
+                        list_from_column(row.get(4)?),
+                    ))
+                })
+                .map_err(|e| format!("Failed to encrypt download history: {}", e))?;
+            rows.collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("Failed to encrypt download history: {}", e))?
+        };
+
+        let mut encrypted_rows = 0;
+        for (id, url, page_url, page_title, tags) in rows {
+            let plaintext = |value: &Option<String>| value.as_deref().is_some_and(|value| !history_crypto::is_encrypted(value));
+            if history_crypto::is_encrypted(&url)
+                && !plaintext(&page_url)
+                && !plaintext(&page_title)
+                && tags.iter().all(|tag| history_crypto::is_encrypted(tag))
+            {
+                continue;
+            }
+            let url = self.seal(true, Some(&url))?;
+            let page_url = self.seal(true, page_url.as_deref())?;
+            let page_title = self.seal(true, page_title.as_deref())?;
+            let tags = self.seal_list(true, &tags)?;
+            tx.execute(
+                "UPDATE downloads SET url = ?2, page_url = ?3, page_title = ?4, tags = ?5 WHERE id = ?1",
+                params![id, url, page_url, page_title, tags],
+            )
+            .map_err(|e| format!("Failed to encrypt download history: {}", e))?;
+            encrypted_rows += 1;
+        }
+
+        // Tags of jobs not recorded yet are copied into their entries later
+        let organized = {
+            let mut statement = tx
+                .prepare("SELECT job_id, tags FROM organized_jobs")
+                .map_err(|e| format!("Failed to encrypt download history: {}", e))?;
+            let rows = statement
+                .query_map([], |row| Ok((row.get::<_, String>(0)?, list_from_column(row.get(1)?))))
+                .map_err(|e| format!("Failed to encrypt download history: {}", e))?;
+            rows.collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("Failed to encrypt download history: {}", e))?
+        };
+        for (job_id, tags) in organized {
+            tx.execute(
+                "UPDATE organized_jobs SET tags = ?2 WHERE job_id = ?1",
+                params![job_id, self.seal_list(true, &tags)?.unwrap_or_else(|| "[]".to_string())],
+            )
+            .map_err(|e| format!("Failed to encrypt download history: {}", e))?;
+        }
+
+        tx.execute(
+            "INSERT INTO history_meta (name, value) VALUES ('encrypted', '1')
+             ON CONFLICT (name) DO UPDATE SET value = excluded.value",
+            [],
+        )
+        .map_err(|e| format!("Failed to encrypt download history: {}", e))?;
+        tx.commit()
+            .map_err(|e| format!("Failed to encrypt download history: {}", e))?;
+        // Freed pages may still hold the plaintext
+        if let Err(error) = conn.execute_batch("VACUUM;") {
+            warn!("Failed to compact download history after encrypting it: {}", error);
+        }
+
+        Ok(HistoryEncryption {
+            enabled: true,
+            key_available: true,
+            warning: Some(ENCRYPTION_WARNING.to_string()),
+            encrypted_rows,
+        })
+    }
+
+    // A column value as stored: encrypted once the database is, already
+    // encrypted values as they are
+    fn seal(&self, encrypted: bool, value: Option<&str>) -> Result<Option<String>, String> {
+        match value {
+            Some(value) if encrypted && !history_crypto::is_encrypted(value) => self.cipher.encrypt(value).map(Some),
+            value => Ok(value.map(str::to_string)),
+        }
+    }
+
+    // Each tag is sealed on its own, so the column stays a JSON array
+    fn seal_list(&self, encrypted: bool, values: &[String]) -> Result<Option<String>, String> {
+        let sealed = values
+            .iter()
+            .map(|value| self.seal(encrypted, Some(value)).map(Option::unwrap_or_default))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(list_to_column(&sealed))
+    }
+
+    fn entry_tags(&self, conn: &Connection, id: i64) -> Result<Vec<String>, String> {
+        conn.query_row("SELECT tags FROM downloads WHERE id = ?1", params![id], |row| row.get(0))
+            .map(|tags| list_from_column(tags).into_iter().map(|tag| self.cipher.decrypt(tag)).collect())
+            .map_err(|e| format!("Failed to query download history: {}", e))
+    }
+
+    fn reveal(&self, entries: Vec<HistoryEntry>) -> Vec<HistoryEntry> {
+        entries
+            .into_iter()
+            .map(|entry| HistoryEntry {
+                url: self.cipher.decrypt(entry.url),
+                page_url: entry.page_url.map(|value| self.cipher.decrypt(value)),
+                page_title: entry.page_title.map(|value| self.cipher.decrypt(value)),
+                tags: entry.tags.into_iter().map(|tag| self.cipher.decrypt(tag)).collect(),
+                ..entry
+            })
+            .collect()
+    }
+}
+
+fn map_subscription_row(row: &Row) -> rusqlite::Result<ChannelSubscription> {
+    Ok(ChannelSubscription {
+        url: row.get(0)?,
+        output_path: row.get(1)?,
+        max_items: row.get(2)?,
+        date_after: row.get(3)?,
+        created_at: row.get(4)?,
+        last_checked_at: row.get(5)?,
+    })
+}
+
+fn encryption_enabled(conn: &Connection) -> Result<bool, String> {
+    conn.query_row("SELECT value FROM history_meta WHERE name = 'encrypted'", [], |row| row.get::<_, String>(0))
+        .optional()
+        .map(|value| value.as_deref() == Some("1"))
+        .map_err(|e| format!("Failed to read history settings: {}", e))
+}
+
+fn open_connection_in(directory: &Path) -> Result<Connection, String> {
+    fs::create_dir_all(directory)
+        .map_err(|e| format!("Failed to create history directory {}: {}", directory.display(), e))?;
+    open_connection(&directory.join(HISTORY_FILE_NAME))
+}
+
+fn open_connection(path: &Path) -> Result<Connection, String> {
+    let conn = Connection::open(path)
+        .map_err(|e| format!("Failed to open history database {}: {}", path.display(), e))?;
+    // Several native host processes may write at once. WAL lets them read while
+    // one writes; it stays off on file systems without shared memory.
+    conn.busy_timeout(BUSY_TIMEOUT)
+        .map_err(|e| format!("Failed to configure history database: {}", e))?;
+    match conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0)) {
+        Ok(mode) if mode.eq_ignore_ascii_case("wal") => {
+            conn.pragma_update(None, "synchronous", "NORMAL")
+                .map_err(|e| format!("Failed to configure history database: {}", e))?;
+        }
+        Ok(mode) => warn!("History database stays in {} journal mode", mode),
+        Err(error) => warn!("Failed to switch the history database to WAL: {}", error),
+    }
+
+    let version: i64 = conn
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(|e| format!("Failed to read history schema version: {}", e))?;
+    if (1..SCHEMA_VERSION).contains(&version) {
+        if let Some(directory) = path.parent() {
+            db_backup::snapshot(&conn, directory, SnapshotReason::Migration)
+                .map_err(|e| format!("Not upgrading the history schema without a backup: {}", e))?;
+        }
+    }
+    migrate_locked(&conn)?;
+    Ok(conn)
+}
+
+// False for the in-memory fallback
+fn on_disk(conn: &Connection) -> bool {
+    conn.path().is_some_and(|path| !path.is_empty())
+}
+
+// Processes starting together must not both run the same migration
+fn migrate_locked(conn: &Connection) -> Result<(), String> {
+    conn.execute_batch("BEGIN IMMEDIATE")
+        .map_err(|e| format!("Failed to migrate history schema: {}", e))?;
+    let migrated = History::migrate(conn);
+    conn.execute_batch(if migrated.is_ok() { "COMMIT" } else { "ROLLBACK" })
+        .map_err(|e| format!("Failed to migrate history schema: {}", e))?;
+    migrated
+}
+
+fn map_history_row(row: &Row) -> rusqlite::Result<HistoryEntry> {
+    Ok(HistoryEntry {
+        id: row.get(0)?,
+        job_id: row.get(1)?,
+        url: row.get(2)?,
+        file_path: row.get(3)?,
+        status: row.get(4)?,
+        message: row.get(5)?,
+        source: row.get(6)?,
+        started_at: row.get(7)?,
+        finished_at: row.get(8)?,
+        object_key: row.get(9)?,
+        etag: row.get(10)?,
+        retry_of: row.get(11)?,
+        attempt: row.get(12)?,
+        auto_retry: row.get(13)?,
+        page_url: row.get(14)?,
+        page_title: row.get(15)?,
+        media_info: media_info::from_column(row.get(16)?),
+        transfer: map_transfer(row, 17)?,
+        session_id: row.get(22)?,
+        supervision: supervisor::from_column(row.get(23)?),
+        rule_ids: list_from_column(row.get(24)?),
+        tags: list_from_column(row.get(25)?),
+        parent_id: row.get(26)?,
+        size_bytes: row.get::<_, Option<i64>>(27)?.map(|size| size as u64),
+    })
+}
+
+// Size of a recorded file, when it is on disk
+fn file_size(file_path: Option<&str>) -> Option<i64> {
+    file_path
+        .and_then(|path| fs::metadata(path).ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len() as i64)
+}
+
+// Written to the vault event log once the entry is committed
+fn announce_saved(entry_id: i64, file_path: &str, source: &str, tags: Vec<String>) {
+    vault_events::record(VaultChange::Added {
+        entry_id,
+        path: file_path.to_string(),
+        source: source.to_string(),
+    });
+    if !tags.is_empty() {
+        vault_events::record(VaultChange::Tagged { entry_id, tags });
+    }
+}
+
+// JSON array text; NULL when empty
+fn list_to_column(values: &[String]) -> Option<String> {
+    if values.is_empty() {
+        return None;
+    }
+    serde_json::to_string(values).ok()
+}
+
+fn list_from_column(value: Option<String>) -> Vec<String> {
+    value
+        .and_then(|value| serde_json::from_str(&value).ok())
+        .unwrap_or_default()
+}
+
+// The transfer columns starting at `first`; rows from before they existed
+// have no backend
+fn map_transfer(row: &Row, first: usize) -> rusqlite::Result<Option<TransferStats>> {
+    let Some(backend) = row.get::<_, Option<String>>(first + 4)? else {
+        return Ok(None);
+    };
+    Ok(Some(TransferStats {
+        queue_wait_ms: row.get::<_, Option<i64>>(first)?.unwrap_or(0),
+        active_ms: row.get::<_, Option<i64>>(first + 1)?.unwrap_or(0),
+        average_bytes_per_second: row.get::<_, Option<i64>>(first + 2)?.unwrap_or(0).max(0) as u64,
+        peak_bytes_per_second: row.get::<_, Option<i64>>(first + 3)?.unwrap_or(0).max(0) as u64,
+        backend,
+    }))
+}
@@ -0,0 +1,314 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tauri::{AppHandle, Manager};
+use tracing::{debug, info, warn};
+
+use crate::diagnostics::hide_console_window;
+use crate::downloader::IMAGE_EXTENSIONS;
+use crate::history::{BackfillRow, History};
+use crate::media_info;
+use crate::settings::Settings;
+use crate::timestamps::parse_rfc3339;
+use crate::{image_size, long_path, webhooks};
+
+pub const BACKFILL_PROGRESS_EVENT: &str = "history-backfill-progress";
+
+// Which history entries to fill in
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum BackfillScope {
+    All,
+    // RFC 3339 bounds on when the download finished; either may be missing
+    DateRange { from: Option<String>, to: Option<String> },
+    Entries { ids: Vec<i64> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackfillField {
+    Size,
+    Sha256,
+    // Read from the file: the image header, or ffprobe for video
+    Dimensions,
+    Duration,
+    // Only from the source URL, so only with refetch_remote
+    Title,
+    Uploader,
+}
+
+const ALL_FIELDS: &[BackfillField] = &[
+    BackfillField::Size,
+    BackfillField::Sha256,
+    BackfillField::Dimensions,
+    BackfillField::Duration,
+    BackfillField::Title,
+    BackfillField::Uploader,
+];
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackfillProgress {
+    pub total: usize,
+    pub done: usize,
+    pub updated: usize,
+    pub skipped: usize,
+    pub current: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackfillFailure {
+    pub entry_id: i64,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackfillSummary {
+    pub total: usize,
+    // Entries that got at least one field
+    pub updated: usize,
+    // Entries with nothing missing, or nothing that could be found
+    pub skipped: usize,
+    // Files that could not be read and source URLs that are gone; those
+    // entries count as updated or skipped by what else they got
+    pub failures: Vec<BackfillFailure>,
+    pub cancelled: bool,
+}
+
+// One backfill at a time; cancel stops it between entries
+#[derive(Clone, Default)]
+pub struct HistoryBackfill {
+    running: Arc<AtomicBool>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl HistoryBackfill {
+    pub fn cancel(&self) -> bool {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.running.load(Ordering::SeqCst)
+    }
+
+    // Fills in the missing `fields` of the entries in `scope`, all of them
+    // when none are named. Values already recorded are never replaced, so a
+    // second run only does what the first could not.
+    pub fn run(
+        &self,
+        app: &AppHandle,
+        history: &History,
+        settings: &Settings,
+        scope: BackfillScope,
+        fields: Option<Vec<BackfillField>>,
+        refetch_remote: bool,
+    ) -> Result<BackfillSummary, String> {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return Err("A history backfill is already running".to_string());
+        }
+        self.cancelled.store(false, Ordering::SeqCst);
+        let fields = fields.filter(|fields| !fields.is_empty()).unwrap_or_else(|| ALL_FIELDS.to_vec());
+        let result = self.backfill(app, history, settings, &scope, &fields, refetch_remote);
+        self.running.store(false, Ordering::SeqCst);
+        result
+    }
+
+    fn backfill(
+        &self,
+        app: &AppHandle,
+        history: &History,
+        settings: &Settings,
+        scope: &BackfillScope,
+        fields: &[BackfillField],
+        refetch_remote: bool,
+    ) -> Result<BackfillSummary, String> {
+        let rows = select_rows(history, scope)?;
+        info!(entries = rows.len(), scope = ?scope, fields = ?fields, refetch_remote, "Backfilling history");
+        let mut summary = BackfillSummary {
+            total: rows.len(),
+            ..BackfillSummary::default()
+        };
+        let mut progress = BackfillProgress {
+            total: rows.len(),
+            ..BackfillProgress::default()
+        };
+
+        for row in rows {
+            if self.cancelled.load(Ordering::SeqCst) {
+                summary.cancelled = true;
+                break;
+            }
+            progress.current = Some(row.file_path.clone());
+            let _ = app.emit_all(BACKFILL_PROGRESS_EVENT, &progress);
+
+            let mut failures = Vec::new();
+            let updated = fill_row(history, settings, &row, fields, refetch_remote, &mut failures)?;
+            for error in failures {
+                warn!(entry_id = row.id, "Backfill: {}", error);
+                summary.failures.push(BackfillFailure { entry_id: row.id, error });
+            }
+            if updated {
+                summary.updated += 1;
+                progress.updated += 1;
+            } else {
+                summary.skipped += 1;
+                progress.skipped += 1;
+            }
+            progress.done += 1;
+        }
+
+        progress.current = None;
+        let _ = app.emit_all(BACKFILL_PROGRESS_EVENT, &progress);
+        info!(
+            updated = summary.updated,
+            skipped = summary.skipped,
+            failures = summary.failures.len(),
+            cancelled = summary.cancelled,
+            "History backfill finished"
+        );
+        Ok(summary)
+    }
+}
+
+fn select_rows(history: &History, scope: &BackfillScope) -> Result<Vec<BackfillRow>, String> {
+    match scope {
+        BackfillScope::All => history.backfill_rows(None, None),
+        BackfillScope::DateRange { from, to } => {
+            let from = from.as_deref().map(parse_rfc3339).transpose()?;
+            let to = to.as_deref().map(parse_rfc3339).transpose()?;
+            history.backfill_rows(from, to)
+        }
+        BackfillScope::Entries { ids } => Ok(history
+            .backfill_rows(None, None)?
+            .into_iter()
+            .filter(|row| ids.contains(&row.id))
+            .collect()),
+    }
+}
+
+// Whether anything was written. Errors reading the file or the source are
+// collected in `failures`; only history errors stop the run.
+fn fill_row(
+    history: &History,
+    settings: &Settings,
+    row: &BackfillRow,
+    fields: &[BackfillField],
+    refetch_remote: bool,
+    failures: &mut Vec<String>,
+) -> Result<bool, String> {
+    let wants = |field: BackfillField| fields.contains(&field);
+    let path = Path::new(&row.file_path);
+    let on_disk = long_path::extended(path).is_file();
+    let mut updated = false;
+
+    if wants(BackfillField::Size) && row.size_bytes.is_none() && on_disk {
+        match fs::metadata(long_path::extended(path)) {
+            Ok(metadata) => {
+                history.set_size_bytes(row.id, metadata.len())?;
+                updated = true;
+            }
+            Err(error) => failures.push(format!("Failed to read the size of {}: {}", row.file_path, error)),
+        }
+    }
+    if wants(BackfillField::Sha256) && row.sha256.is_none() && on_disk {
+        match webhooks::hash_file(&long_path::extended(path).to_string_lossy()) {
+            Ok((_, sha256)) => {
+                history.set_sha256(row.id, &sha256)?;
+                updated = true;
+            }
+            Err(error) => failures.push(format!("Failed to hash {}: {}", row.file_path, error)),
+        }
+    }
+
+    let mut info = row.media_info.clone().unwrap_or_default();
+    let needs_dimensions = wants(BackfillField::Dimensions) && info.resolution.is_none();
+    let needs_duration = wants(BackfillField::Duration) && info.duration.is_none() && !is_image(path);
+    if (needs_dimensions || needs_duration) && on_disk {
+        match probe_file(path) {
+            Ok((dimensions, duration)) => {
+                if needs_dimensions {
+                    info.resolution = dimensions.map(|(width, height)| format!("{}x{}", width, height));
+                }
+                if needs_duration {
+                    info.duration = duration;
+                }
+            }
+            Err(error) => failures.push(error),
+        }
+    }
+
+    let needs_title = wants(BackfillField::Title) && info.title.is_none();
+    let needs_uploader = wants(BackfillField::Uploader) && info.uploader.is_none();
+    if refetch_remote && (needs_title || needs_uploader) {
+        match media_info::probe(settings, &row.url) {
+            Ok(remote) => {
+                if needs_title {
+                    info.title = remote.title;
+                }
+                if needs_uploader {
+                    info.uploader = remote.uploader;
+                }
+                // Harmless to take along while they are missing too
+                info.upload_date = info.upload_date.or(remote.upload_date);
+                info.webpage_url = info.webpage_url.or(remote.webpage_url);
+            }
+            // Deleted and private videos end up here; the rest still runs
+            Err(error) => failures.push(format!("Source is unavailable: {}", error)),
+        }
+    }
+
+    if info != row.media_info.clone().unwrap_or_default() {
+        info.ext = info
+            .ext
+            .or_else(|| path.extension().map(|extension| extension.to_string_lossy().to_ascii_lowercase()));
+        history.set_media_info(row.id, &info)?;
+        updated = true;
+    }
+    Ok(updated)
+}
+
+fn is_image(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| IMAGE_EXTENSIONS.contains(&extension.to_string_lossy().to_ascii_lowercase().as_str()))
+}
+
+// Width and height, and the duration of anything that plays
+type Measured = (Option<(u32, u32)>, Option<f64>);
+
+// Images are read from their header, the rest by ffprobe
+fn probe_file(path: &Path) -> Result<Measured, String> {
+    if is_image(path) {
+        return Ok((image_size::read(path), None));
+    }
+    let mut command = Command::new("ffprobe");
+    command
+        .args(["-v", "error", "-select_streams", "v:0", "-show_entries", "stream=width,height:format=duration", "-of", "json"])
+        .arg(long_path::simplified(path));
+    hide_console_window(&mut command);
+
+    let output = command.output().map_err(|e| format!("Failed to run ffprobe: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "ffprobe failed on {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let probed: Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| format!("Failed to parse ffprobe output for {}: {}", path.display(), e))?;
+    let stream = probed.get("streams").and_then(|streams| streams.get(0));
+    let dimension = |name: &str| stream.and_then(|stream| stream.get(name)).and_then(Value::as_u64).map(|value| value as u32);
+    let dimensions = dimension("width").zip(dimension("height"));
+    // Audio files have no video stream, and still a duration
+    let duration = probed
+        .get("format")
+        .and_then(|format| format.get("duration"))
+        .and_then(Value::as_str)
+        .and_then(|duration| duration.parse::<f64>().ok())
+        .filter(|duration| duration.is_finite() && *duration > 0.0);
+    debug!(path = %path.display(), ?dimensions, ?duration, "Probed file for backfill");
+    Ok((dimensions, duration))
+}
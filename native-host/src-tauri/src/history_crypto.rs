@@ -0,0 +1,267 @@
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+use crate::keychain;
+
+// Shown instead of a value whose key is gone, so history still lists
+pub const ENCRYPTED_PLACEHOLDER: &str = "[encrypted]";
+// One key for every profile's history, created by the first migration
+const KEY_SECRET_NAME: &str = "history_encryption_key";
+const KEY_BYTES: usize = 32;
+const NONCE_BYTES: usize = 12;
+// Stored values are "enc:v1:" and the hex of nonce and ciphertext
+const ENCRYPTED_PREFIX: &str = "enc:v1:";
+
+// Encrypts and decrypts the sensitive history columns. The key is read from
+// the keychain the first time it is needed and kept for the process.
+#[derive(Clone, Default)]
+pub struct FieldCipher {
+    // None until loaded; Some(None) when the keychain has no usable key
+    key: Arc<Mutex<Option<Option<Aes256Gcm>>>>,
+}
+
+impl FieldCipher {
+    pub fn encrypt(&self, value: &str) -> Result<String, String> {
+        let cipher = self
+            .cipher()
+            .ok_or("History is encrypted but its key is missing from the keychain")?;
+        let mut nonce = [0u8; NONCE_BYTES];
+        getrandom::getrandom(&mut nonce).map_err(|e| format!("Failed to encrypt history: {}", e))?;
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), value.as_bytes())
+            .map_err(|_| "Failed to encrypt history".to_string())?;
+        Ok(format!("{}{}{}", ENCRYPTED_PREFIX, to_hex(&nonce), to_hex(&ciphertext)))
+    }
+
+    // Plaintext passes through; anything that cannot be decrypted becomes
+    // ENCRYPTED_PLACEHOLDER
+    pub fn decrypt(&self, value: String) -> String {
+        let Some(encoded) = value.strip_prefix(ENCRYPTED_PREFIX) else {
+            return value;
+        };
+        let Some(cipher) = self.cipher() else {
+            return ENCRYPTED_PLACEHOLDER.to_string();
+        };
+        let decrypted = from_hex(encoded)
+            .filter(|bytes| bytes.len() > NONCE_BYTES)
+            .and_then(|bytes| {
+                let (nonce, ciphertext) = bytes.split_at(NONCE_BYTES);
+                cipher.decrypt(Nonce::from_slice(nonce), ciphertext).ok()
+            })
+            .and_then(|plaintext| String::from_utf8(plaintext).ok());
+        match decrypted {
+            Some(plaintext) => plaintext,
+            None => {
+                warn!("Failed to decrypt a history value; the key may have changed");
+                ENCRYPTED_PLACEHOLDER.to_string()
+            }
+        }
+    }
+
+    pub fn has_key(&self) -> bool {
+        self.cipher().is_some()
+    }
+
+    // Keeps an existing key; a new one needs a working keychain, since a key
+    // saved next to the database would protect nothing
+    pub fn create_key(&self) -> Result<(), String> {
+        if self.has_key() {
+            return Ok(());
+        }
+        if keychain::has_secret(KEY_SECRET_NAME) {
+            return Err(format!("{} in the keychain is not a valid key", KEY_SECRET_NAME));
+        }
+        let mut key = [0u8; KEY_BYTES];
+        getrandom::getrandom(&mut key).map_err(|e| format!("Failed to generate history key: {}", e))?;
+        keychain::set_secret(KEY_SECRET_NAME, &to_hex(&key))?;
+        info!("Generated the history encryption key");
+        *self.key.lock().unwrap() = Some(Aes256Gcm::new_from_slice(&key).ok());
+        Ok(())
+    }
+
+    fn cipher(&self) -> Option<Aes256Gcm> {
+        let mut key = self.key.lock().unwrap();
+        key.get_or_insert_with(load_key).clone()
+    }
+}
+
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(ENCRYPTED_PREFIX)
+}
+
+fn load_key() -> Option<Aes256Gcm> {
+    match keychain::get_secret(KEY_SECRET_NAME) {
+        Ok(Some(key)) => {
+            let cipher = from_hex(&key).and_then(|key| Aes256Gcm::new_from_slice(&key).ok());
+            if cipher.is_none() {
+                warn!("{} in the keychain is not a valid key", KEY_SECRET_NAME);
+            }
+            cipher
+        }
+        Ok(None) => None,
+        Err(error) => {
+            warn!("{}", error);
+            None
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::{DownloadStatus, History, NewHistoryEntry, HISTORY_FILE_NAME};
+    use crate::test_support;
+    use rusqlite::Connection;
+
+    const URL: &str = "https://example.com/private/picture.png";
+
+    // Every test starts with no key; the memory keychain outlives app_data
+    fn without_key() -> FieldCipher {
+        test_support::memory_keychain();
+        keychain::delete_secret(KEY_SECRET_NAME).expect("delete key");
+        FieldCipher::default()
+    }
+
+    fn entry(job_id: &str) -> NewHistoryEntry<'_> {
+        NewHistoryEntry {
+            job_id,
+            url: URL,
+            file_path: None,
+            status: DownloadStatus::Failed,
+            message: None,
+            source: "test",
+            started_at: 0,
+            finished_at: 0,
+            object_key: None,
+            etag: None,
+            output_path: None,
+            upload: false,
+            page_url: Some("https://example.com/private"),
+            page_title: Some("A private page"),
+            media_info: None,
+            transfer: None,
+            session_id: None,
+            supervision: None,
+        }
+    }
+
+    #[test]
+    fn a_value_round_trips() {
+        let _app_data = test_support::app_data();
+        let cipher = without_key();
+        cipher.create_key().expect("key");
+
+        let sealed = cipher.encrypt(URL).expect("encrypt");
+        assert!(is_encrypted(&sealed), "{}", sealed);
+        assert!(!sealed.contains("example.com"), "{}", sealed);
+        // A fresh nonce every time
+        assert_ne!(cipher.encrypt(URL).expect("encrypt"), sealed);
+        assert_eq!(cipher.decrypt(sealed.clone()), URL);
+        // Loaded from the keychain by another process
+        assert_eq!(FieldCipher::default().decrypt(sealed), URL);
+    }
+
+    #[test]
+    fn only_prefixed_values_count_as_encrypted() {
+        let _app_data = test_support::app_data();
+        let cipher = without_key();
+        for (value, encrypted) in [
+            ("enc:v1:00", true),
+            ("enc:v1:", true),
+            ("enc:v2:00", false),
+            ("https://example.com/enc:v1:00", false),
+            ("", false),
+        ] {
+            assert_eq!(is_encrypted(value), encrypted, "{}", value);
+        }
+        // Plaintext is read as it is, key or not
+        assert_eq!(cipher.decrypt(URL.to_string()), URL);
+    }
+
+    #[test]
+    fn a_missing_or_wrong_key_reads_as_the_placeholder() {
+        let _app_data = test_support::app_data();
+        let cipher = without_key();
+        assert!(cipher.encrypt(URL).is_err());
+        cipher.create_key().expect("key");
+        let sealed = cipher.encrypt(URL).expect("encrypt");
+
+        let missing = without_key();
+        assert!(!missing.has_key());
+        assert_eq!(missing.decrypt(sealed.clone()), ENCRYPTED_PLACEHOLDER);
+
+        let wrong = without_key();
+        wrong.create_key().expect("key");
+        assert_eq!(wrong.decrypt(sealed), ENCRYPTED_PLACEHOLDER);
+        for corrupt in ["enc:v1:", "enc:v1:zz", "enc:v1:0", "enc:v1:00ff"] {
+            assert_eq!(wrong.decrypt(corrupt.to_string()), ENCRYPTED_PLACEHOLDER, "{}", corrupt);
+        }
+    }
+
+    #[test]
+    fn encrypting_existing_history_twice_encrypts_nothing_twice() {
+        let _app_data = test_support::app_data();
+        without_key();
+        let history = History::open_default();
+        history.record(&entry("before")).expect("record");
+
+        let first = history.encrypt_existing().expect("encrypt");
+        assert!(first.enabled && first.key_available);
+        assert_eq!(first.encrypted_rows, 1);
+        // Recorded after encryption was turned on, so already sealed
+        history.record(&entry("after")).expect("record");
+        assert_eq!(history.encrypt_existing().expect("encrypt").encrypted_rows, 0);
+
+        for entry in history.all().expect("history") {
+            assert_eq!(entry.url, URL);
+            assert_eq!(entry.page_title.as_deref(), Some("A private page"));
+        }
+    }
+
+    #[test]
+    fn tags_are_sealed_wherever_they_are_stored() {
+        let app_data = test_support::app_data();
+        without_key();
+        let history = History::open_default();
+        let tags = ["holiday-secret".to_string()];
+        history.record_organized_job("before", &[], &tags, None).expect("organize");
+        history.record(&entry("before")).expect("record");
+        history.record_organized_job("pending", &[], &tags, None).expect("organize");
+        history.encrypt_existing().expect("encrypt");
+        history.record_organized_job("after", &[], &tags, None).expect("organize");
+        history.record(&entry("after")).expect("record");
+        history.record(&entry("pending")).expect("record");
+
+        let entries = history.all().expect("history");
+        assert_eq!(entries.len(), 3);
+        assert!(entries.iter().all(|entry| entry.tags == tags), "{:?}", entries);
+        history.flush().expect("flush");
+        let conn = Connection::open(app_data.directory.join(HISTORY_FILE_NAME)).expect("database");
+        for query in ["SELECT tags FROM downloads", "SELECT tags FROM organized_jobs"] {
+            let mut statement = conn.prepare(query).expect("query");
+            let stored = statement
+                .query_map([], |row| row.get::<_, String>(0))
+                .expect("query")
+                .collect::<Result<Vec<_>, _>>()
+                .expect("rows");
+            assert_eq!(stored.len(), 3);
+            assert!(stored.iter().all(|tags| !tags.contains("holiday")), "{:?}", stored);
+        }
+    }
+}
@@ -0,0 +1,561 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::{self, Cursor, Read};
+use std::sync::{Arc, Mutex};
+use tiny_http::{Header, Method, Request, Response, Server};
+use tracing::{debug, error, info, warn};
+
+use crate::dispatcher::Priority;
+use crate::domain_policy;
+use crate::downloader::{Backend, DownloadContext};
+use crate::coalesce::{self, Claim};
+use crate::history::History;
+use crate::i18n;
+use crate::jobs::JobRegistry;
+use crate::media_policy;
+use crate::metrics;
+use crate::organize::{self, MediaHints};
+use crate::output_template;
+use crate::preview;
+use crate::queue::{announce_scheduled, cancel_job, generate_job_id, pause_queue_jobs, queue_snapshot, resume_queue_jobs};
+use crate::schedule::RunOptions;
+use crate::settings::SettingsStore;
+use crate::source_page::SourcePage;
+use crate::websocket::EventServer;
+use crate::{current_timestamp_millis, logging, redact, schedule, secrets, timestamps, updates, validate_download_url};
+
+const TOKEN_FILE_NAME: &str = "http-api-token";
+const MAX_BODY_BYTES: u64 = 64 * 1024;
+const PREFLIGHT_MAX_AGE_SECS: u32 = 600;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HttpApiStatus {
+    pub enabled: bool,
+    pub running: bool,
+    pub port: u16,
+    pub websocket_port: u16,
+    // Why the server is not running although it is enabled, e.g. a port conflict
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DownloadRequest {
+    url: String,
+    output_path: Option<String>,
+    // Template inside the vault; wins over output_path
+    output_template: Option<String>,
+    job_id: Option<String>,
+    // `false` skips the configured upload stage
+    upload: Option<bool>,
+    // RFC 3339 start time; the job waits on the schedule until then
+    schedule_at: Option<String>,
+    #[serde(default)]
+    priority: Priority,
+    // Same as the native message fields
+    page_url: Option<String>,
+    page_title: Option<String>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    browser_user_agent: Option<String>,
+    backend: Option<Backend>,
+    ignore_policy: Option<bool>,
+    #[serde(flatten)]
+    hints: MediaHints,
+    // Runs even when an identical download is already in flight
+    #[serde(alias = "force_new")]
+    force_new: Option<bool>,
+}
+
+struct RunningServer {
+    server: Arc<Server>,
+    port: u16,
+    events: EventServer,
+    events_port: u16,
+}
+
+// Everything a request handler needs, cloned into the server thread
+#[derive(Clone)]
+struct ApiContext {
+    jobs: JobRegistry,
+    history: History,
+    settings: SettingsStore,
+    token: String,
+    port: u16,
+}
+
+// Localhost REST API over the same job registry and download pipeline as the
+// GUI, for browsers where native messaging is unavailable. Lives in the GUI
+// process, which stays running in the tray.
+#[derive(Clone)]
+pub struct HttpApi {
+    jobs: JobRegistry,
+    history: History,
+    settings: SettingsStore,
+    running: Arc<Mutex<Option<RunningServer>>>,
+    last_error: Arc<Mutex<Option<String>>>,
+}
+
+impl HttpApi {
+    pub fn new(jobs: JobRegistry, history: History, settings: SettingsStore) -> Self {
+        HttpApi {
+            jobs,
+            history,
+            settings,
+            running: Arc::new(Mutex::new(None)),
+            last_error: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    // Start, stop or move the server so it matches the current settings
+    pub fn apply(&self) -> Result<(), String> {
+        let settings = self.settings.get();
+        let mut running = self.running.lock().unwrap();
+
+        let wanted_ports = settings
+            .http_api_enabled
+            .then_some((settings.http_api_port, settings.websocket_port));
+        if running.as_ref().map(|server| (server.port, server.events_port)) == wanted_ports {
+            return Ok(());
+        }
+
+        if let Some(previous) = running.take() {
+            previous.stop();
+        }
+        *self.last_error.lock().unwrap() = None;
+
+        let Some((port, events_port)) = wanted_ports else {
+            return Ok(());
+        };
+        match self.start(port, events_port) {
+            Ok(server) => {
+                *running = Some(server);
+                Ok(())
+            }
+            Err(error) => {
+                error!("{}", error);
+                *self.last_error.lock().unwrap() = Some(error.clone());
+                Err(error)
+            }
+        }
+    }
+
+    pub fn status(&self) -> HttpApiStatus {
+        let settings = self.settings.get();
+        let running = self.running.lock().unwrap();
+        HttpApiStatus {
+            enabled: settings.http_api_enabled,
+            running: running.is_some(),
+            port: running.as_ref().map_or(settings.http_api_port, |server| server.port),
+            websocket_port: running.as_ref().map_or(settings.websocket_port, |server| server.events_port),
+            error: self.last_error.lock().unwrap().clone(),
+        }
+    }
+
+    // Called on exit so WebSocket clients get a close frame instead of a reset
+    pub fn shutdown(&self) {
+        if let Some(running) = self.running.lock().unwrap().take() {
+            running.stop();
+        }
+    }
+
+    fn start(&self, port: u16, events_port: u16) -> Result<RunningServer, String> {
+        let token = get_or_create_token()?;
+        // Never fall back to another port: the extension is configured with this one
+        let server = Server::http(("127.0.0.1", port)).map_err(|e| {
+            match e.downcast_ref::<io::Error>().map(io::Error::kind) {
+                Some(io::ErrorKind::AddrInUse) => format!(
+                    "HTTP API port {} is already in use by another program; choose a different port",
+                    port
+                ),
+                _ => format!("Failed to start HTTP API on port {}: {}", port, e),
+            }
+        })?;
+        let server = Arc::new(server);
+        let events = match EventServer::start(self.jobs.clone(), self.settings.clone(), token.clone(), events_port) {
+            Ok(events) => events,
+            Err(error) => {
+                server.unblock();
+                return Err(error);
+            }
+        };
+
+        let context = ApiContext {
+            jobs: self.jobs.clone(),
+            history: self.history.clone(),
+            settings: self.settings.clone(),
+            token,
+            port,
+        };
+        let listener = Arc::clone(&server);
+        std::thread::spawn(move || {
+            for request in listener.incoming_requests() {
+                handle_request(&context, request);
+            }
+        });
+
+        info!("HTTP API listening on 127.0.0.1:{}", port);
+        Ok(RunningServer {
+            server,
+            port,
+            events,
+            events_port,
+        })
+    }
+}
+
+impl RunningServer {
+    fn stop(self) {
+        self.server.unblock();
+        self.events.shutdown();
+        info!("HTTP API on port {} stopped", self.port);
+    }
+}
+
+// Per-install bearer token, generated on first use
+pub fn get_or_create_token() -> Result<String, String> {
+    secrets::get_or_create_secret(TOKEN_FILE_NAME)
+}
+
+fn header<'a>(request: &'a Request, name: &str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.as_str().as_str().eq_ignore_ascii_case(name))
+        .map(|header| header.value.as_str())
+}
+
+fn handle_request(context: &ApiContext, mut request: Request) {
+    let method = request.method().clone();
+    let path = request.url().split('?').next().unwrap_or("").to_string();
+    debug!(method = ?method, path = %path, "HTTP API request");
+
+    // A Host other than our own address means DNS rebinding from a web page
+    let expected_hosts = [format!("127.0.0.1:{}", context.port), format!("localhost:{}", context.port)];
+    if !header(&request, "Host").is_some_and(|host| expected_hosts.iter().any(|expected| host.eq_ignore_ascii_case(expected))) {
+        warn!(path = %path, "Rejected HTTP API request with unexpected Host header");
+        respond(request, 403, json!({ "error": "Forbidden host" }), None);
+        return;
+    }
+
+    // Browsers always send Origin on cross-origin requests; scripts send none
+    let origin = header(&request, "Origin").map(|origin| origin.trim_end_matches('/').to_string());
+    if let Some(origin) = &origin {
+        let allowed = context
+            .settings
+            .get()
+            .http_api_allowed_origins
+            .iter()
+            .any(|allowed| allowed.trim_end_matches('/') == origin);
+        if !allowed {
+            warn!(origin = %origin, "Rejected HTTP API request from an origin that is not allowed");
+            respond(request, 403, json!({ "error": "Origin not allowed" }), None);
+            return;
+        }
+    }
+    let origin = origin.as_deref();
+
+    if method == Method::Options {
+        respond_preflight(request, origin);
+        return;
+    }
+
+    let authorized = header(&request, "Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| constant_time_eq(token.trim().as_bytes(), context.token.as_bytes()));
+    if !authorized {
+        warn!(path = %path, "Rejected HTTP API request without a valid token");
+        respond(request, 401, json!({ "error": "Missing or invalid bearer token" }), origin);
+        return;
+    }
+
+    // Prometheus scrapes plain text, with the same bearer token
+    if method == Method::Get && path == "/metrics" {
+        respond_text(request, 200, metrics::prometheus(&context.jobs), origin);
+        return;
+    }
+
+    // Images rather than JSON, made on a thread of their own so that a slow
+    // decode holds up no other request
+    if method == Method::Get {
+        if let Some(file_id) = path.strip_prefix("/preview/") {
+            serve_preview(context, request, file_id, origin);
+            return;
+        }
+    }
+
+    // Error bodies get a `message` in the browser's language next to errorCode
+    let locale = i18n::resolve(header(&request, "Accept-Language").and_then(i18n::from_accept_language).as_deref());
+    let (status, mut body) = match (&method, path.as_str()) {
+        (Method::Get, "/health") => (200, health(context)),
+        (Method::Get, "/jobs") => (200, queue_snapshot(&context.jobs, &context.settings.get())),
+        (Method::Post, "/queue/pause") => {
+            let message = pause_queue_jobs(&context.jobs);
+            (200, json!({ "success": true, "message": message }))
+        }
+        (Method::Post, "/queue/resume") => match resume_queue_jobs(&context.jobs) {
+            Ok(message) => (200, json!({ "success": true, "message": message })),
+            Err(error) => (500, json!({ "success": false, "error": error })),
+        },
+        (Method::Post, "/download") => match read_body(&mut request) {
+            Ok(body) => start_download(context, &body),
+            Err(error) => (400, json!({ "error": error })),
+        },
+        (Method::Post, path) => match path
+            .strip_prefix("/jobs/")
+            .and_then(|rest| rest.strip_suffix("/cancel"))
+            .filter(|job_id| !job_id.is_empty() && !job_id.contains('/'))
+        {
+            Some(job_id) => match cancel_job(&context.jobs, job_id) {
+                Ok(message) => (200, json!({ "success": true, "message": message })),
+                Err(error) => (500, json!({ "success": false, "error": error })),
+            },
+            None => (404, json!({ "error": "Not found" })),
+        },
+        (_, "/health" | "/jobs" | "/metrics" | "/download" | "/queue/pause" | "/queue/resume") => (405, json!({ "error": "Method not allowed" })),
+        _ => (404, json!({ "error": "Not found" })),
+    };
+    i18n::localize_json(&mut body, &locale);
+    respond(request, status, body, origin);
+}
+
+// Same payload as the native protocol's ping
+fn health(context: &ApiContext) -> Value {
+    json!({
+        "ok": true,
+        "version": env!("CARGO_PKG_VERSION"),
+        "updateAvailable": updates::cached_update_available(&context.settings.get()),
+        "queuePaused": context.jobs.is_paused(),
+    })
+}
+
+// Queue a download and return its job id right away; progress is available
+// from GET /jobs
+fn start_download(context: &ApiContext, body: &str) -> (u16, Value) {
+    let request = match serde_json::from_str::<DownloadRequest>(body) {
+        Ok(request) => request,
+        Err(error) => return (400, json!({ "error": format!("Invalid download request: {}", error) })),
+    };
+    if let Err(error) = validate_download_url(&request.url) {
+        return (400, json!({ "error": error, "errorCode": "invalid_url" }));
+    }
+
+    let settings = media_policy::for_request(context.settings.get(), request.ignore_policy.unwrap_or(false));
+    if let Err(blocked) = domain_policy::check(&settings, &request.url) {
+        warn!("Rejected download over HTTP API: {}", blocked);
+        return (403, json!({ "error": blocked.to_string(), "errorCode": "domain_blocked", "rule": blocked.rule }));
+    }
+    let output_path = match output_template::resolve(request.output_path, request.output_template.as_deref(), &settings) {
+        Ok(output_path) => output_path,
+        Err(error) => return (400, json!({ "error": error, "errorCode": "invalid_output_template" })),
+    };
+    let job_id = request.job_id.unwrap_or_else(|| generate_job_id("http"));
+    // The timer's run picks up the collision mode the rules chose, see organize::resume_job
+    let output_path =
+        match organize::organize_job(&context.history, settings, &job_id, &request.url, &request.hints, &output_path) {
+            Ok((_, output_path)) => output_path,
+            Err(message) => return (403, json!({ "error": message, "errorCode": "rule_skipped" })),
+        };
+    let source_page = match SourcePage::new(request.page_url, request.page_title, request.referer)
+        .with_user_agents(request.user_agent, request.browser_user_agent)
+    {
+        Ok(source_page) => source_page,
+        Err(error) => return (400, json!({ "error": error, "errorCode": "invalid_user_agent" })),
+    };
+    info!(
+        job_id = %job_id,
+        url = logging::loggable_url(&request.url),
+        "Download requested over HTTP API"
+    );
+
+    let job = DownloadContext {
+        job_id: &job_id,
+        url: &request.url,
+        output_path: &output_path,
+        upload: request.upload.unwrap_or(true),
+        priority: request.priority,
+        source_page: &source_page,
+        source: "http",
+    };
+    let options = RunOptions {
+        backend: request.backend,
+        ignore_policy: request.ignore_policy.unwrap_or(false),
+    };
+    if let Some(schedule_at) = request.schedule_at.as_deref() {
+        return match schedule::add_with(&job, schedule_at, options) {
+            Ok(download) => {
+                announce_scheduled(&context.jobs, &download);
+                (202, json!({ "success": true, "jobId": job_id, "scheduledAt": download.scheduled_at }))
+            }
+            Err(error) => (400, json!({ "error": error })),
+        };
+    }
+
+    // Held while the job waits in the queue, then by the timer until it ran
+    let claim = match coalesce::claim(&job_id, &request.url, &output_path, request.force_new.unwrap_or(false)) {
+        Ok(Claim::Owner(guard)) => Some(guard),
+        Ok(Claim::Attached { job_id: existing, .. }) => {
+            return (202, json!({ "success": true, "jobId": existing, "coalesced": true }));
+        }
+        Err(error) => {
+            warn!(job_id = %job_id, "Not checking for duplicate requests: {}", error);
+            None
+        }
+    };
+    // Due now, so the scheduler's timer starts it under the dispatcher's limits
+    // like any queued job, instead of a thread per request
+    let now = timestamps::format_rfc3339(current_timestamp_millis());
+    if let Err(error) = schedule::add_with(&job, &now, options) {
+        return (400, json!({ "error": error }));
+    }
+    if let Some(claim) = claim {
+        coalesce::hold_while_queued(claim);
+    }
+    context.jobs.scheduler().wake();
+
+    (202, json!({ "success": true, "jobId": job_id, "coalesced": false }))
+}
+
+// GET /preview/{history entry id}?max=256: a JPEG of the entry's file that
+// fits in max x max, with an ETag so the popup can keep it. Files ffmpeg
+// cannot read get a placeholder instead of an error.
+fn serve_preview(context: &ApiContext, request: Request, file_id: &str, origin: Option<&str>) {
+    let Ok(entry_id) = file_id.parse::<i64>() else {
+        respond(request, 404, json!({ "error": "Not found" }), origin);
+        return;
+    };
+    let size = request.url().split_once('?').and_then(|(_, query)| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("max="))
+            .and_then(|max| max.parse::<u32>().ok())
+    });
+    let size = preview::clamp_size(size);
+    let if_none_match = header(&request, "If-None-Match").map(str::to_string);
+    let history = context.history.clone();
+    let origin = origin.map(str::to_string);
+
+    std::thread::spawn(move || {
+        let origin = origin.as_deref();
+        let source = match preview::resolve(&history, entry_id, size) {
+            Ok(source) => source,
+            Err(error) => {
+                debug!(entry_id, "No preview: {}", error);
+                respond(request, 404, json!({ "error": "Not found" }), origin);
+                return;
+            }
+        };
+        let etag = format!("\"{}\"", source.etag);
+        let unchanged = if_none_match.as_deref().is_some_and(|tags| {
+            tags.split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == etag || tag == "*")
+        });
+        if unchanged {
+            respond_bytes(request, 304, None, Vec::new(), Some(&etag), origin);
+            return;
+        }
+        match preview::render(&source) {
+            Ok(bytes) => respond_bytes(request, 200, Some("image/jpeg"), bytes, Some(&etag), origin),
+            Err(error) => {
+                debug!(entry_id, "Serving the placeholder preview: {}", error);
+                let placeholder = preview::PLACEHOLDER_SVG.as_bytes().to_vec();
+                respond_bytes(request, 200, Some("image/svg+xml"), placeholder, None, origin);
+            }
+        }
+    });
+}
+
+fn read_body(request: &mut Request) -> Result<String, String> {
+    let mut body = String::new();
+    request
+        .as_reader()
+        .take(MAX_BODY_BYTES + 1)
+        .read_to_string(&mut body)
+        .map_err(|e| format!("Failed to read request body: {}", e))?;
+    if body.len() as u64 > MAX_BODY_BYTES {
+        return Err(format!("Request body is larger than {} bytes", MAX_BODY_BYTES));
+    }
+    Ok(body)
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn with_cors(mut response: Response<Cursor<Vec<u8>>>, origin: Option<&str>) -> Response<Cursor<Vec<u8>>> {
+    if let Some(origin) = origin {
+        if let Ok(header) = Header::from_bytes("Access-Control-Allow-Origin", origin) {
+            response.add_header(header);
+        }
+        if let Ok(header) = Header::from_bytes("Vary", "Origin") {
+            response.add_header(header);
+        }
+    }
+    response
+}
+
+fn respond(request: Request, status: u16, body: Value, origin: Option<&str>) {
+    let body = redact::redact(&body.to_string()).into_owned();
+    let mut response = Response::from_string(body).with_status_code(status);
+    if let Ok(header) = Header::from_bytes("Content-Type", "application/json") {
+        response.add_header(header);
+    }
+    if let Err(error) = request.respond(with_cors(response, origin)) {
+        debug!("Failed to send HTTP API response: {}", error);
+    }
+}
+
+fn respond_text(request: Request, status: u16, body: String, origin: Option<&str>) {
+    let mut response = Response::from_string(body).with_status_code(status);
+    if let Ok(header) = Header::from_bytes("Content-Type", "text/plain; version=0.0.4") {
+        response.add_header(header);
+    }
+    if let Err(error) = request.respond(with_cors(response, origin)) {
+        debug!("Failed to send HTTP API response: {}", error);
+    }
+}
+
+// Previews with an ETag may be kept for a day and then revalidated; the
+// placeholder is not kept, so a preview replaces it once ffmpeg can make one
+fn respond_bytes(
+    request: Request,
+    status: u16,
+    content_type: Option<&str>,
+    body: Vec<u8>,
+    etag: Option<&str>,
+    origin: Option<&str>,
+) {
+    let mut response = Response::from_data(body).with_status_code(status);
+    let cache_control = if etag.is_some() { "private, max-age=86400" } else { "no-store" };
+    let headers = [
+        ("Content-Type", content_type),
+        ("ETag", etag),
+        ("Cache-Control", Some(cache_control)),
+    ];
+    for (name, value) in headers {
+        if let Some(header) = value.and_then(|value| Header::from_bytes(name, value).ok()) {
+            response.add_header(header);
+        }
+    }
+    if let Err(error) = request.respond(with_cors(response, origin)) {
+        debug!("Failed to send HTTP API response: {}", error);
+    }
+}
+
+fn respond_preflight(request: Request, origin: Option<&str>) {
+    let mut response = Response::from_string(String::new()).with_status_code(204);
+    for (name, value) in [
+        ("Access-Control-Allow-Methods", "GET, POST, OPTIONS".to_string()),
+        ("Access-Control-Allow-Headers", "Authorization, Content-Type".to_string()),
+        ("Access-Control-Max-Age", PREFLIGHT_MAX_AGE_SECS.to_string()),
+    ] {
+        if let Ok(header) = Header::from_bytes(name, value) {
+            response.add_header(header);
+        }
+    }
+    if let Err(error) = request.respond(with_cors(response, origin)) {
+        debug!("Failed to send HTTP API response: {}", error);
+    }
+}
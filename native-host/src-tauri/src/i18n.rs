@@ -0,0 +1,335 @@
+use serde_json::Value;
+use std::sync::RwLock;
+
+// Every response that is not in another language falls back to this one
+pub const DEFAULT_LOCALE: &str = "en";
+// Languages with a catalog; a locale such as de-AT uses its language's
+pub const LOCALES: &[&str] = &["en", "de"];
+
+// Replaced by the locale setting once it is loaded
+static LOCALE: RwLock<Option<String>> = RwLock::new(None);
+
+type Catalog = &'static [(&'static str, &'static str)];
+
+// One entry per error_code the host sends; a new code needs one here, which
+// is what makes it part of the protocol. Other languages may lag behind and
+// fall back to these.
+const ERRORS_EN: Catalog = &[
+    ("protocol_error", "The connection to the browser broke down."),
+    ("invalid_message", "The extension sent a message the host could not read."),
+    ("invalid_schema", "The extension sent a message that does not match the protocol."),
+    ("origin_not_allowed", "This extension is not allowed to use ImgVault."),
+    ("invalid_profile", "The requested profile does not exist."),
+    ("invalid_output_template", "The output template is not valid."),
+    ("invalid_user_agent", "The user agent is not valid."),
+    ("rule_skipped", "An organization rule skipped this download."),
+    ("domain_blocked", "Downloads from this site are blocked by your settings."),
+    ("invalid_url", "The link is not a valid web address."),
+    ("missing_url", "The download request has no link."),
+    ("missing_file_path", "The request does not say where the file was saved."),
+    ("invalid_file_path", "The reported path is not a file on this computer."),
+    ("unknown_action", "The host does not know this action."),
+    ("internal_error", "Something went wrong inside ImgVault."),
+    ("cancelled", "The download was cancelled."),
+    ("policy_rejected", "The file does not meet your media policy."),
+    ("ytdlp_missing", "yt-dlp is not installed or could not be started."),
+    ("gallery_dl_missing", "gallery-dl is not installed or could not be started."),
+    ("http_403", "The site refused access to this file."),
+    ("http_404", "The file no longer exists on the site."),
+    ("rate_limited", "The site is limiting requests; try again later."),
+    ("stalled", "The download stalled."),
+    ("unresponsive", "The downloader stopped responding."),
+    ("memory_limit", "The downloader used too much memory and was stopped."),
+    ("unsupported_url", "This site is not supported."),
+    ("auth_required", "The site requires you to sign in."),
+    ("network_error", "The network connection failed."),
+    ("destination_unavailable", "The download folder's share or network drive cannot be reached."),
+    ("tampered_dependency", "A downloader installed by ImgVault was modified and was not run."),
+    (
+        "controlled_folder_access",
+        "Windows blocked writing to a protected folder. Allow ImgVault in Controlled folder access (Windows Security, Ransomware protection) or choose a vault outside it.",
+    ),
+    ("fallback_to_browser", "ImgVault cannot download this; the browser will download it instead."),
+    ("download_failed", "The download failed."),
+];
+
+pub const ERROR_CODE_COUNT: usize = ERRORS_EN.len();
+
+// Fixed status lines; `{name}` is filled in by format
+const STATUS_EN: Catalog = &[
+    ("host_reachable", "Native host reachable"),
+    ("path_reloaded", "PATH reloaded"),
+    ("diagnostics_complete", "Diagnostics complete"),
+    ("url_diagnosed", "URL diagnosis complete"),
+    ("settings_unchanged", "Settings reloaded, nothing changed"),
+    ("settings_changed", "Settings reloaded, changed: {fields}"),
+    ("queue_updated", "Queue updated"),
+    ("scheduled", "Download scheduled for {time}"),
+    ("coalesced", "Following download {job_id}, which is already in progress"),
+    ("subscribed", "Subscribed to job events"),
+    ("unsubscribed", "Unsubscribed from job events"),
+];
+
+const ERRORS_DE: Catalog = &[
+    ("protocol_error", "Die Verbindung zum Browser ist abgebrochen."),
+    ("invalid_message", "Die Erweiterung hat eine unlesbare Nachricht gesendet."),
+    ("invalid_schema", "Die Erweiterung hat eine Nachricht gesendet, die nicht zum Protokoll passt."),
+    ("origin_not_allowed", "Diese Erweiterung darf ImgVault nicht verwenden."),
+    ("invalid_profile", "Das angeforderte Profil existiert nicht."),
+    ("invalid_output_template", "Die Ausgabevorlage ist ungültig."),
+    ("invalid_user_agent", "Der User-Agent ist ungültig."),
+    ("rule_skipped", "Eine Organisationsregel hat diesen Download übersprungen."),
+    ("domain_blocked", "Downloads von dieser Seite sind in deinen Einstellungen gesperrt."),
+    ("invalid_url", "Der Link ist keine gültige Webadresse."),
+    ("missing_url", "Die Download-Anfrage enthält keinen Link."),
+    ("missing_file_path", "Die Anfrage gibt nicht an, wo die Datei gespeichert wurde."),
+    ("invalid_file_path", "Der gemeldete Pfad ist keine Datei auf diesem Computer."),
+    ("unknown_action", "Der Host kennt diese Aktion nicht."),
+    ("internal_error", "In ImgVault ist ein Fehler aufgetreten."),
+    ("cancelled", "Der Download wurde abgebrochen."),
+    ("policy_rejected", "Die Datei entspricht nicht deiner Medienrichtlinie."),
+    ("ytdlp_missing", "yt-dlp ist nicht installiert oder konnte nicht gestartet werden."),
+    ("gallery_dl_missing", "gallery-dl ist nicht installiert oder konnte nicht gestartet werden."),
+    ("http_403", "Die Seite hat den Zugriff auf diese Datei verweigert."),
+    ("http_404", "Die Datei existiert auf der Seite nicht mehr."),
+    ("rate_limited", "Die Seite begrenzt Anfragen; versuche es später erneut."),
+    ("stalled", "Der Download ist ins Stocken geraten."),
+    ("unresponsive", "Das Download-Programm reagiert nicht mehr."),
+    ("memory_limit", "Das Download-Programm hat zu viel Speicher belegt und wurde beendet."),
+    ("unsupported_url", "Diese Seite wird nicht unterstützt."),
+    ("auth_required", "Die Seite verlangt eine Anmeldung."),
+    ("network_error", "Die Netzwerkverbindung ist fehlgeschlagen."),
+    ("destination_unavailable", "Die Freigabe oder das Netzlaufwerk des Download-Ordners ist nicht erreichbar."),
+    ("tampered_dependency", "Ein von ImgVault installiertes Download-Programm wurde verändert und nicht ausgeführt."),
+    (
+        "controlled_folder_access",
+        "Windows hat das Schreiben in einen geschützten Ordner blockiert. Erlaube ImgVault im überwachten Ordnerzugriff (Windows-Sicherheit, Ransomware-Schutz) oder wähle einen Tresor außerhalb davon.",
+    ),
+    ("fallback_to_browser", "ImgVault kann dies nicht herunterladen; stattdessen lädt es der Browser herunter."),
+    ("download_failed", "Der Download ist fehlgeschlagen."),
+];
+
+const STATUS_DE: Catalog = &[
+    ("host_reachable", "Nativer Host erreichbar"),
+    ("path_reloaded", "PATH neu geladen"),
+    ("diagnostics_complete", "Diagnose abgeschlossen"),
+    ("url_diagnosed", "URL-Diagnose abgeschlossen"),
+    ("settings_unchanged", "Einstellungen neu geladen, nichts geändert"),
+    ("settings_changed", "Einstellungen neu geladen, geändert: {fields}"),
+    ("queue_updated", "Warteschlange aktualisiert"),
+    ("scheduled", "Download geplant für {time}"),
+    ("coalesced", "Folge Download {job_id}, der bereits läuft"),
+    ("subscribed", "Auftragsereignisse abonniert"),
+    ("unsubscribed", "Auftragsereignisse abbestellt"),
+];
+
+// Catalogs of a language, errors first
+fn catalogs(language: &str) -> Option<[Catalog; 2]> {
+    match language {
+        "en" => Some([ERRORS_EN, STATUS_EN]),
+        "de" => Some([ERRORS_DE, STATUS_DE]),
+        _ => None,
+    }
+}
+
+// "de-AT", "de_at" and "DE" all become "de"
+fn language(locale: &str) -> String {
+    locale
+        .trim()
+        .split(['-', '_'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+// Whether responses can be written in `locale`, at least through its language
+pub fn supported(locale: &str) -> bool {
+    catalogs(&language(locale)).is_some()
+}
+
+// Every error code the host sends, for whoever builds the other side
+pub fn error_codes() -> impl Iterator<Item = &'static str> {
+    ERRORS_EN.iter().map(|(code, _)| *code)
+}
+
+// Apply the locale setting, like redact::apply_parameters
+pub fn apply_locale(locale: &str) {
+    *LOCALE.write().unwrap() = Some(language(locale));
+}
+
+// The language a response is written in: the one the message asked for, else
+// the locale setting, else English
+pub fn resolve(requested: Option<&str>) -> String {
+    let configured = LOCALE.read().unwrap().clone();
+    requested
+        .map(language)
+        .into_iter()
+        .chain(configured)
+        .find(|language| catalogs(language).is_some())
+        .unwrap_or_else(|| DEFAULT_LOCALE.to_string())
+}
+
+// `key` in `locale`, falling back to English; None for a key no catalog has
+pub fn lookup(locale: &str, key: &str) -> Option<&'static str> {
+    [language(locale).as_str(), DEFAULT_LOCALE]
+        .into_iter()
+        .filter_map(catalogs)
+        .flatten()
+        .flat_map(|catalog| catalog.iter())
+        .find(|(name, _)| *name == key)
+        .map(|(_, text)| *text)
+}
+
+// `key` in `locale` with each `{name}` replaced by its argument
+pub fn text(locale: &str, key: &str, args: &[(&str, &str)]) -> String {
+    let text = lookup(locale, key).unwrap_or(key);
+    args.iter()
+        .fold(text.to_string(), |text, (name, value)| text.replace(&format!("{{{}}}", name), value))
+}
+
+// Gives an HTTP API error body a `message` in `locale` next to its errorCode;
+// `error` keeps the host's own wording
+pub fn localize_json(body: &mut Value, locale: &str) {
+    let message = body
+        .get("errorCode")
+        .and_then(Value::as_str)
+        .and_then(|code| lookup(locale, code));
+    if let (Some(message), Value::Object(fields)) = (message, body) {
+        fields.insert("message".to_string(), Value::String(message.to_string()));
+    }
+}
+
+// The first language in an Accept-Language header that has a catalog
+pub fn from_accept_language(header: &str) -> Option<String> {
+    header
+        .split(',')
+        .map(|part| language(part.split(';').next().unwrap_or_default()))
+        .find(|language| catalogs(language).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const SOURCE_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src");
+
+    // Every `"code"` literal in `text` that is the value of an error_code,
+    // directly or through an if, or an argument of NativeResponse::failure,
+    // as the host writes them
+    fn error_code_literals(text: &str) -> Vec<String> {
+        let mut codes = Vec::new();
+        let mut rest = text;
+        while let Some(found) = rest.find("error_code: Some(") {
+            rest = &rest[found + "error_code: Some(".len()..];
+            let value = &rest[..rest.find(')').unwrap_or(rest.len())];
+            codes.extend(quoted(value));
+        }
+        // The search string here is quoted; calls are not
+        for (found, call) in text.match_indices("NativeResponse::failure(").filter(|(found, _)| !text[..*found].ends_with('"')) {
+            let rest = &text[found + call.len()..];
+            let mut depth = 1;
+            let end = rest
+                .char_indices()
+                .find(|&(_, c)| {
+                    depth += match c {
+                        '(' => 1,
+                        ')' => -1,
+                        _ => 0,
+                    };
+                    depth == 0
+                })
+                .map_or(rest.len(), |(index, _)| index);
+            codes.extend(quoted(&rest[..end]));
+        }
+        codes
+    }
+
+    // What classify_download_error can return: the literals it returns, one
+    // per line
+    fn classified_codes(text: &str) -> Vec<String> {
+        let start = text.find("fn classify_download_error").expect("classify_download_error");
+        let body = &text[start..start + text[start..].find("\n}\n").expect("end of function")];
+        body.lines()
+            .map(|line| line.trim().trim_start_matches("return ").trim_end_matches(';'))
+            .filter(|line| line.starts_with('"') && line.ends_with('"'))
+            .flat_map(quoted)
+            .collect()
+    }
+
+    // Quoted words shaped like an error code; this file's own searches are not
+    fn quoted(text: &str) -> Vec<String> {
+        text.split('"')
+            .skip(1)
+            .step_by(2)
+            .filter(|word| !word.is_empty() && word.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_'))
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn every_error_code_the_host_sends_has_an_english_entry() {
+        let mut sent = Vec::new();
+        for entry in fs::read_dir(SOURCE_DIR).expect("source folder") {
+            let path = entry.expect("source file").path();
+            if path.extension().is_some_and(|extension| extension == "rs") {
+                let text = fs::read_to_string(&path).expect("source file");
+                sent.extend(error_code_literals(&text));
+                if text.contains("fn classify_download_error") {
+                    sent.extend(classified_codes(&text));
+                }
+            }
+        }
+        // Guards the scan itself as much as the catalog
+        assert!(sent.iter().any(|code| code == "invalid_schema"), "{:?}", sent);
+        assert!(sent.iter().any(|code| code == "http_404"), "{:?}", sent);
+        for code in &sent {
+            assert!(error_codes().any(|known| known == code), "{} has no English entry", code);
+        }
+    }
+
+    #[test]
+    fn catalogs_hold_no_key_twice_and_no_key_english_lacks() {
+        for locale in LOCALES {
+            for catalog in catalogs(locale).expect("catalog") {
+                for (index, (key, text)) in catalog.iter().enumerate() {
+                    assert!(!text.is_empty(), "{}: {} is empty", locale, key);
+                    assert!(!catalog[..index].iter().any(|(earlier, _)| earlier == key), "{}: {} twice", locale, key);
+                    assert!(lookup(DEFAULT_LOCALE, key).is_some(), "{}: {} is not in English", locale, key);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn other_languages_fall_back_to_english() {
+        for code in error_codes() {
+            let english = lookup("en", code);
+            assert!(english.is_some());
+            // A language without a catalog, and a region of one with a catalog
+            assert_eq!(lookup("fr-CA", code), english, "{}", code);
+            assert_eq!(lookup("de-AT", code), lookup("de", code), "{}", code);
+            assert!(lookup("de", code).is_some(), "{}", code);
+        }
+        assert_eq!(lookup("de", "no_such_key"), None);
+        assert_eq!(text("de", "no_such_key", &[]), "no_such_key");
+        assert_eq!(
+            text("fr", "coalesced", &[("job_id", "job-1")]),
+            "Following download job-1, which is already in progress"
+        );
+        assert_eq!(text("de_DE", "coalesced", &[("job_id", "job-1")]), "Folge Download job-1, der bereits läuft");
+    }
+
+    #[test]
+    fn the_request_wins_over_the_setting_and_english_comes_last() {
+        let configured = LOCALE.read().unwrap().clone();
+        apply_locale("de-AT");
+        assert_eq!(resolve(None), "de");
+        assert_eq!(resolve(Some("en-GB")), "en");
+        assert_eq!(resolve(Some("fr")), "de", "an unknown request falls back to the setting");
+        apply_locale("pt-BR");
+        assert_eq!(resolve(Some("fr")), DEFAULT_LOCALE);
+        assert_eq!(resolve(Some("DE")), "de");
+        *LOCALE.write().unwrap() = configured;
+    }
+}
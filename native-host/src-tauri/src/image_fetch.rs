@@ -0,0 +1,673 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::bandwidth::{self, BandwidthWindow};
+use crate::jobs;
+use crate::long_path;
+use crate::media_policy::{self, ImageDimensions};
+use crate::site_login::host_in_domain;
+use crate::url_host;
+
+// Hidden command line subcommand the HTTP image backend runs as its child
+pub const SUBCOMMAND: &str = "fetch-image";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+pub(crate) const USER_AGENT: &str = concat!("ImgVault/", env!("CARGO_PKG_VERSION"));
+// Often enough for stall detection and the GUI, rarely enough not to matter
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+// How often the copy loop looks at the bandwidth schedule, the queue pause
+// and cancel requests
+const CONTROL_INTERVAL: Duration = Duration::from_millis(500);
+const BUFFER_SIZE: usize = 64 * 1024;
+// Smallest read under a bandwidth limit; larger limits read a quarter second
+// of data at a time
+const MIN_THROTTLED_READ: usize = 1024;
+const DEFAULT_FILE_NAME: &str = "image";
+// Used when neither the URL nor the Content-Type names a known type
+const DEFAULT_EXTENSION: &str = "jpg";
+const MAX_FILE_NAME_BYTES: usize = 180;
+
+// One image to fetch, as the subcommand's arguments give it
+pub struct ImageRequest<'a> {
+    pub url: &'a str,
+    pub output: &'a str,
+    pub referer: Option<&'a str>,
+    // Without one ImgVault sends its own
+    pub user_agent: Option<&'a str>,
+    pub cookies: Option<&'a Path>,
+    // Smaller images are deleted and fail the fetch
+    pub min_dimensions: Option<ImageDimensions>,
+    // Keeps an existing file and reports it as the result
+    pub no_overwrites: bool,
+}
+
+// What the copy loop asks between reads
+pub(crate) trait TransferControl {
+    // Bytes per second allowed right now; None for full speed
+    fn limit(&mut self) -> Option<u64>;
+
+    // While the queue is paused the loop reads nothing. The host stops this
+    // process soon after, and the .part file is resumed from later.
+    fn paused(&mut self) -> bool;
+
+    fn cancelled(&mut self) -> bool;
+}
+
+// The queue as this child sees it: the bandwidth schedule it was given, the
+// pause state every host shares and the job's pid file, which a cancel from
+// any host removes
+struct QueueControl {
+    schedule: Vec<BandwidthWindow>,
+    job_id: Option<String>,
+    // The host registers the job only after spawning this process
+    registered: bool,
+    checked: Option<Instant>,
+    limit: Option<u64>,
+    paused: bool,
+    cancelled: bool,
+}
+
+impl QueueControl {
+    fn new(schedule: Vec<BandwidthWindow>, job_id: Option<&str>) -> Self {
+        QueueControl {
+            schedule,
+            job_id: job_id.filter(|job_id| !job_id.is_empty()).map(str::to_string),
+            registered: false,
+            checked: None,
+            limit: None,
+            paused: false,
+            cancelled: false,
+        }
+    }
+
+    fn refresh(&mut self) {
+        if self.checked.is_some_and(|at| at.elapsed() < CONTROL_INTERVAL) {
+            return;
+        }
+        self.checked = Some(Instant::now());
+        self.limit = bandwidth::limit_now(&self.schedule);
+        self.paused = jobs::is_queue_paused() == Some(true);
+        if let Some(job_id) = &self.job_id {
+            let registered = jobs::is_registered(job_id);
+            self.cancelled |= self.registered && !registered;
+            self.registered |= registered;
+        }
+    }
+}
+
+impl TransferControl for QueueControl {
+    fn limit(&mut self) -> Option<u64> {
+        self.refresh();
+        self.limit
+    }
+
+    fn paused(&mut self) -> bool {
+        self.refresh();
+        self.paused
+    }
+
+    fn cancelled(&mut self) -> bool {
+        self.refresh();
+        self.cancelled
+    }
+}
+
+// Paces reads to the limit in force. A new limit starts a new measure, so a
+// running transfer speeds up or slows down as soon as the window changes.
+struct Throttle {
+    limit: Option<u64>,
+    since: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    fn new(now: Instant) -> Self {
+        Throttle {
+            limit: None,
+            since: now,
+            bytes: 0,
+        }
+    }
+
+    // How long to hold off before the next read
+    fn delay(&mut self, limit: Option<u64>, now: Instant) -> Option<Duration> {
+        if limit != self.limit {
+            self.limit = limit;
+            self.since = now;
+            self.bytes = 0;
+        }
+        let limit = limit?;
+        let due = self.since + Duration::from_secs_f64(self.bytes as f64 / limit as f64);
+        due.checked_duration_since(now).filter(|delay| !delay.is_zero())
+    }
+
+    fn record(&mut self, bytes: u64) {
+        self.bytes += bytes;
+    }
+}
+
+// Downloads one image, printing progress the way yt-dlp does with
+// --newline so the job loop reads it like any other download, and the saved
+// path on the last stdout line. Errors go to stderr as "ERROR: ..." lines.
+// The transfer follows `bandwidth_schedule`, a JSON list of windows, holds
+// while the queue is paused and stops when `job_id` is cancelled. Returns the
+// process exit code.
+pub fn run(request: &ImageRequest, job_id: Option<&str>, bandwidth_schedule: Option<&str>) -> i32 {
+    let mut stdout = io::stdout().lock();
+    let schedule = match bandwidth_schedule.map(serde_json::from_str::<Vec<BandwidthWindow>>).transpose() {
+        Ok(schedule) => schedule.unwrap_or_default(),
+        Err(error) => {
+            let _ = writeln!(io::stderr().lock(), "ERROR: Invalid bandwidth schedule: {}", error);
+            return 1;
+        }
+    };
+    let mut control = QueueControl::new(schedule, job_id);
+    match fetch(request, &mut control, &mut stdout) {
+        Ok(path) => {
+            let _ = writeln!(stdout, "{}", path.display());
+            0
+        }
+        Err(error) => {
+            let _ = writeln!(io::stderr().lock(), "ERROR: {}", error);
+            1
+        }
+    }
+}
+
+fn fetch(
+    request: &ImageRequest,
+    control: &mut impl TransferControl,
+    progress: &mut impl Write,
+) -> Result<PathBuf, String> {
+    let ImageRequest { url, output, referer, user_agent, cookies, min_dimensions, no_overwrites } = *request;
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout_read(READ_TIMEOUT)
+        .user_agent(user_agent.unwrap_or(USER_AGENT))
+        .build();
+    let mut request = agent.get(url);
+    if let Some(referer) = referer {
+        request = request.set("Referer", referer);
+    }
+    if let Some(cookie) = cookies.and_then(|path| cookie_header(path, url)) {
+        request = request.set("Cookie", &cookie);
+    }
+    let response = match request.clone().call() {
+        Ok(response) => response,
+        // Worded like yt-dlp so classify_download_error reads it the same way
+        Err(ureq::Error::Status(status, _)) => return Err(format!("HTTP Error {}", status)),
+        Err(ureq::Error::Transport(error)) => return Err(format!("Connection failed: {}", error)),
+    };
+
+    let content_type = response
+        .header("Content-Type")
+        .map(|value| value.split(';').next().unwrap_or("").trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    if !content_type.is_empty() && !content_type.starts_with("image/") && content_type != "application/octet-stream" {
+        return Err(format!("Unsupported URL: the server sent {} instead of an image", content_type));
+    }
+
+    let path = output_file(output, url, &content_type);
+    if no_overwrites && long_path::extended(&path).is_file() {
+        // Worded like yt-dlp's message for the same case
+        let _ = writeln!(progress, "[download] {} has already been downloaded", path.display());
+        return Ok(path);
+    }
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(long_path::extended(parent))
+            .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    let _ = writeln!(progress, "[download] Destination: {}", path.display());
+
+    // A pause left the start of the file behind; the rest is asked for when
+    // the server takes ranges, the way yt-dlp's --continue does
+    let part_path = PathBuf::from(format!("{}.part", path.display()));
+    let partial = fs::metadata(long_path::extended(&part_path)).map_or(0, |metadata| metadata.len());
+    let ranged = (partial > 0 && response.header("Accept-Ranges") == Some("bytes"))
+        .then(|| request.set("Range", &format!("bytes={}-", partial)).call().ok())
+        .flatten()
+        .filter(|ranged| ranged.status() == 206);
+    let (response, resume_from) = match ranged {
+        Some(ranged) => {
+            let _ = writeln!(progress, "[download] Resuming download at byte {}", partial);
+            (ranged, partial)
+        }
+        None => (response, 0),
+    };
+    let total_bytes = response
+        .header("Content-Length")
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(|length| resume_from + length);
+
+    let result = copy_with_progress(&mut response.into_reader(), &part_path, resume_from, total_bytes, control, progress)
+        .and_then(|()| {
+            // Checked on the .part file, so a rejected image never lands in the vault
+            match min_dimensions {
+                Some(min) => media_policy::check_image(&part_path, min).map_err(|rejected| rejected.to_string()),
+                None => Ok(()),
+            }
+        });
+    match result {
+        Ok(()) => fs::rename(long_path::extended(&part_path), long_path::extended(&path))
+            .map_err(|e| format!("Failed to move {} into place: {}", path.display(), e))?,
+        Err(error) => {
+            let _ = fs::remove_file(&part_path);
+            return Err(error);
+        }
+    }
+    Ok(path)
+}
+
+// Appends to the .part file when resuming from its first `resume_from` bytes
+fn copy_with_progress(
+    body: &mut impl Read,
+    part_path: &Path,
+    resume_from: u64,
+    total_bytes: Option<u64>,
+    control: &mut impl TransferControl,
+    progress: &mut impl Write,
+) -> Result<(), String> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(resume_from > 0)
+        .truncate(resume_from == 0)
+        .open(long_path::extended(part_path))
+        .map_err(|e| format!("Unable to open for writing {}: {}", part_path.display(), e))?;
+    let write_failed = |e: io::Error| format!("Failed to write {}: {}", part_path.display(), e);
+    let started = Instant::now();
+    let mut last_report = started;
+    let mut throttle = Throttle::new(started);
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut written = resume_from;
+    loop {
+        // Waits out the limit and a pause, still answering a cancel
+        let limit = loop {
+            if control.cancelled() {
+                return Err("Download cancelled by user".to_string());
+            }
+            let now = Instant::now();
+            let limit = control.limit();
+            let delay = if control.paused() {
+                // No catching up on the time spent paused
+                throttle = Throttle::new(now);
+                Some(CONTROL_INTERVAL)
+            } else {
+                throttle.delay(limit, now)
+            };
+            match delay {
+                Some(delay) => thread::sleep(delay.min(CONTROL_INTERVAL)),
+                None => break limit,
+            }
+        };
+        let chunk = limit.map_or(BUFFER_SIZE, |limit| {
+            usize::try_from(limit / 4).unwrap_or(BUFFER_SIZE).clamp(MIN_THROTTLED_READ, BUFFER_SIZE)
+        });
+        let read = match body.read(&mut buffer[..chunk]) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(format!("Connection failed while downloading: {}", error)),
+        };
+        file.write_all(&buffer[..read]).map_err(write_failed)?;
+        written += read as u64;
+        throttle.record(read as u64);
+
+        let now = Instant::now();
+        if now.duration_since(last_report) >= PROGRESS_INTERVAL {
+            last_report = now;
+            let speed = speed(written - resume_from, now.duration_since(started));
+            let _ = writeln!(progress, "{}", progress_line(written, total_bytes, speed));
+        }
+    }
+    file.flush().map_err(write_failed)?;
+
+    if total_bytes.is_some_and(|total| written < total) {
+        return Err(format!(
+            "Connection closed after {} of {} bytes",
+            written,
+            total_bytes.unwrap_or_default()
+        ));
+    }
+    let _ = writeln!(
+        progress,
+        "[download] 100% of {} in {:.1}s",
+        format_size(written),
+        started.elapsed().as_secs_f64()
+    );
+    Ok(())
+}
+
+// Bytes per second of this run, not counting what an earlier one fetched
+fn speed(fetched: u64, elapsed: Duration) -> u64 {
+    (fetched as f64 / elapsed.as_secs_f64().max(0.001)) as u64
+}
+
+// "[download]  45.3% of 1.20MiB at 2.50MiB/s ETA 00:01", or without the
+// percent when the server sent no length
+fn progress_line(written: u64, total_bytes: Option<u64>, speed: u64) -> String {
+    match total_bytes.filter(|total| *total > 0) {
+        Some(total) => {
+            let eta_secs = total.saturating_sub(written).checked_div(speed).unwrap_or(0);
+            format!(
+                "[download] {:5.1}% of {} at {}/s ETA {:02}:{:02}",
+                written as f64 * 100.0 / total as f64,
+                format_size(total),
+                format_size(speed),
+                eta_secs / 60,
+                eta_secs % 60
+            )
+        }
+        None => format!("[download] {} at {}/s", format_size(written), format_size(speed)),
+    }
+}
+
+// In the units yt-dlp prints, which parse_byte_size reads back
+pub(crate) fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2}{}", value, UNITS[unit])
+}
+
+// The file to write: the output path with the yt-dlp template fields it can
+// fill, or the URL's file name inside it when it names a folder
+fn output_file(output: &str, url: &str, content_type: &str) -> PathBuf {
+    let (stem, extension) = url_file_name(url, content_type);
+    if output.contains("%(") {
+        return PathBuf::from(fill_template(output, &stem, &stem, &extension));
+    }
+    let path = PathBuf::from(output);
+    if output.ends_with(['/', '\\']) || path.is_dir() {
+        path.join(format!("{}.{}", stem, extension))
+    } else {
+        path
+    }
+}
+
+pub(crate) fn url_file_name(url: &str, content_type: &str) -> (String, String) {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let file_name = path.rsplit('/').next().unwrap_or("");
+    let (stem, extension) = match file_name.rsplit_once('.') {
+        Some((stem, extension)) if !extension.is_empty() => (stem, Some(extension.to_ascii_lowercase())),
+        _ => (file_name, None),
+    };
+    let extension = extension
+        .or_else(|| content_type.strip_prefix("image/").map(|subtype| subtype.replace("jpeg", "jpg")))
+        .unwrap_or_else(|| DEFAULT_EXTENSION.to_string());
+    let stem = sanitize(stem);
+    let stem = if stem.is_empty() { DEFAULT_FILE_NAME.to_string() } else { stem };
+    (stem, sanitize(&extension))
+}
+
+// Fills %(title)s, %(id)s and %(ext)s, with or without a length such as
+// %(title).180B; yt-dlp writes NA for fields it does not know, and so do we
+pub(crate) fn fill_template(template: &str, title: &str, id: &str, extension: &str) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("%(") {
+        filled.push_str(&rest[..start]);
+        let field = &rest[start + 2..];
+        let Some(end) = field.find(')') else {
+            filled.push_str(&rest[start..]);
+            return filled;
+        };
+        let after = &field[end + 1..];
+        // The conversion ends at its type letter, e.g. "s" or ".180B"
+        let conversion_len = after.find(|ch: char| ch.is_ascii_alphabetic()).map_or(0, |index| index + 1);
+        filled.push_str(match &field[..end] {
+            "title" | "fulltitle" => title,
+            "id" => id,
+            "ext" => extension,
+            _ => "NA",
+        });
+        rest = &after[conversion_len..];
+    }
+    filled.push_str(rest);
+    filled
+}
+
+pub(crate) fn sanitize(name: &str) -> String {
+    let mut name = name
+        .chars()
+        .map(|ch| if ch.is_control() || "<>:\"/\\|?*%".contains(ch) { '_' } else { ch })
+        .collect::<String>();
+    while name.len() > MAX_FILE_NAME_BYTES {
+        name.pop();
+    }
+    name.trim().trim_end_matches('.').to_string()
+}
+
+// Cookies for `url` from a Netscape cookies file, as the extension writes them
+fn cookie_header(path: &Path, url: &str) -> Option<String> {
+    let host = url_host(url)?;
+    let secure = url.starts_with("https://");
+    let request_path = url
+        .split_once("://")
+        .and_then(|(_, rest)| rest.find('/').map(|index| &rest[index..]))
+        .unwrap_or("/");
+    let contents = fs::read_to_string(path).ok()?;
+    let cookies = contents
+        .lines()
+        .map(|line| line.strip_prefix("#HttpOnly_").unwrap_or(line))
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let fields = line.split('\t').collect::<Vec<_>>();
+            let [domain, include_subdomains, cookie_path, cookie_secure, _expires, name, value] = fields[..] else {
+                return None;
+            };
+            let domain_matches = if include_subdomains == "TRUE" {
+                host_in_domain(&host, domain)
+            } else {
+                host == domain.trim_start_matches('.').to_ascii_lowercase()
+            };
+            (domain_matches && request_path.starts_with(cookie_path) && (secure || cookie_secure != "TRUE"))
+                .then(|| format!("{}={}", name, value))
+        })
+        .collect::<Vec<_>>();
+    (!cookies.is_empty()).then(|| cookies.join("; "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::env;
+    use std::rc::Rc;
+    use std::sync::mpsc;
+    use tiny_http::{Header, Response, Server};
+
+    // A body that counts what the copy loop has read of it
+    struct Body {
+        data: io::Cursor<Vec<u8>>,
+        read: Rc<Cell<u64>>,
+        largest_read: usize,
+    }
+
+    impl Read for Body {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            self.largest_read = self.largest_read.max(buffer.len());
+            let read = self.data.read(buffer)?;
+            self.read.set(self.read.get() + read as u64);
+            Ok(read)
+        }
+    }
+
+    // The queue's side, played from a script keyed on how much has been read
+    #[derive(Default)]
+    struct ScriptedControl {
+        read: Rc<Cell<u64>>,
+        limit: Option<u64>,
+        lift_limit_at: Option<u64>,
+        paused_checks: usize,
+        read_while_paused: u64,
+        cancel_at: Option<u64>,
+    }
+
+    impl TransferControl for ScriptedControl {
+        fn limit(&mut self) -> Option<u64> {
+            self.limit.filter(|_| self.lift_limit_at.is_none_or(|at| self.read.get() < at))
+        }
+
+        fn paused(&mut self) -> bool {
+            if self.paused_checks == 0 {
+                return false;
+            }
+            self.paused_checks -= 1;
+            self.read_while_paused += self.read.get();
+            true
+        }
+
+        fn cancelled(&mut self) -> bool {
+            self.cancel_at.is_some_and(|at| self.read.get() >= at)
+        }
+    }
+
+    fn body(size: usize, control: &ScriptedControl) -> Body {
+        Body {
+            data: io::Cursor::new((0..size).map(|index| index as u8).collect()),
+            read: Rc::clone(&control.read),
+            largest_read: 0,
+        }
+    }
+
+    fn part_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("imgvault-image-fetch-{}-{}.part", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn copy(body: &mut Body, control: &mut ScriptedControl, part: &Path) -> (Result<(), String>, Duration) {
+        let started = Instant::now();
+        let result = copy_with_progress(body, part, 0, Some(body.data.get_ref().len() as u64), control, &mut Vec::new());
+        (result, started.elapsed())
+    }
+
+    #[test]
+    fn reads_at_the_limit_in_force() {
+        let mut control = ScriptedControl {
+            limit: Some(64 * 1024),
+            ..ScriptedControl::default()
+        };
+        let mut body = body(32 * 1024, &control);
+        let part = part_path("limit");
+
+        let (result, elapsed) = copy(&mut body, &mut control, &part);
+        assert_eq!(result, Ok(()));
+        assert!(elapsed >= Duration::from_millis(400), "32 KiB at 64 KiB/s took {:?}", elapsed);
+        assert_eq!(body.largest_read, 16 * 1024);
+        assert_eq!(fs::metadata(&part).map(|metadata| metadata.len()).ok(), Some(32 * 1024));
+        let _ = fs::remove_file(&part);
+    }
+
+    #[test]
+    fn a_new_window_changes_the_speed_of_a_running_transfer() {
+        let mut control = ScriptedControl {
+            limit: Some(16 * 1024),
+            lift_limit_at: Some(8 * 1024),
+            ..ScriptedControl::default()
+        };
+        let mut body = body(1024 * 1024, &control);
+        let part = part_path("window");
+
+        let (result, elapsed) = copy(&mut body, &mut control, &part);
+        assert_eq!(result, Ok(()));
+        // A whole MiB at the first window's limit would take over a minute
+        assert!(elapsed < Duration::from_secs(5), "took {:?}", elapsed);
+        assert_eq!(body.largest_read, BUFFER_SIZE);
+        let _ = fs::remove_file(&part);
+    }
+
+    #[test]
+    fn reads_nothing_while_the_queue_is_paused() {
+        let mut control = ScriptedControl {
+            paused_checks: 2,
+            ..ScriptedControl::default()
+        };
+        let mut body = body(256 * 1024, &control);
+        let part = part_path("paused");
+
+        let (result, elapsed) = copy(&mut body, &mut control, &part);
+        assert_eq!(result, Ok(()));
+        assert_eq!(control.read_while_paused, 0);
+        assert!(elapsed >= CONTROL_INTERVAL, "held for {:?}", elapsed);
+        assert_eq!(fs::metadata(&part).map(|metadata| metadata.len()).ok(), Some(256 * 1024));
+        let _ = fs::remove_file(&part);
+    }
+
+    #[test]
+    fn a_cancel_stops_the_transfer() {
+        let mut control = ScriptedControl {
+            cancel_at: Some(2 * BUFFER_SIZE as u64),
+            ..ScriptedControl::default()
+        };
+        let mut body = body(1024 * 1024, &control);
+        let part = part_path("cancel");
+
+        let (result, _) = copy(&mut body, &mut control, &part);
+        assert_eq!(result, Err("Download cancelled by user".to_string()));
+        assert_eq!(control.read.get(), 2 * BUFFER_SIZE as u64);
+        let _ = fs::remove_file(&part);
+    }
+
+    #[test]
+    fn a_paused_fetch_resumes_from_its_part_file() {
+        let image = (0..100 * 1024).map(|index| (index % 251) as u8).collect::<Vec<_>>();
+        let kept = 40 * 1024;
+        let server = Server::http("127.0.0.1:0").expect("server");
+        let address = server.server_addr().to_ip().expect("address");
+        let (ranges, asked) = mpsc::channel();
+        let served = image.clone();
+        let serving = std::thread::spawn(move || {
+            for request in server.incoming_requests().take(2) {
+                let range = request
+                    .headers()
+                    .iter()
+                    .find(|header| header.field.equiv("Range"))
+                    .map(|header| header.value.to_string());
+                let _ = ranges.send(range.clone());
+                let from = range
+                    .as_deref()
+                    .and_then(|range| range.strip_prefix("bytes="))
+                    .and_then(|range| range.trim_end_matches('-').parse::<usize>().ok());
+                let response = Response::from_data(served[from.unwrap_or(0)..].to_vec())
+                    .with_status_code(if from.is_some() { 206 } else { 200 })
+                    .with_header(Header::from_bytes("Content-Type", "image/png").expect("header"))
+                    .with_header(Header::from_bytes("Accept-Ranges", "bytes").expect("header"));
+                let _ = request.respond(response);
+            }
+        });
+        let folder = env::temp_dir().join(format!("imgvault-image-resume-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).expect("folder");
+        fs::write(folder.join("photo.png.part"), &image[..kept]).expect("part file");
+
+        let mut progress = Vec::new();
+        let request = ImageRequest {
+            url: &format!("http://{}/photo.png", address),
+            output: &format!("{}/", folder.display()),
+            referer: None,
+            user_agent: None,
+            cookies: None,
+            min_dimensions: None,
+            no_overwrites: false,
+        };
+        let path = fetch(&request, &mut ScriptedControl::default(), &mut progress).expect("resumed");
+        serving.join().expect("server");
+
+        assert_eq!(fs::read(&path).expect("image"), image);
+        assert!(!folder.join("photo.png.part").exists());
+        assert_eq!(asked.try_iter().collect::<Vec<_>>(), [None, Some(format!("bytes={}-", kept))]);
+        let progress = String::from_utf8(progress).expect("progress");
+        assert!(progress.contains(&format!("Resuming download at byte {}", kept)), "{}", progress);
+        assert!(progress.contains("100% of 100.00KiB"), "{}", progress);
+        let _ = fs::remove_dir_all(&folder);
+    }
+}
@@ -0,0 +1,97 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+// Every format below keeps its size near the start; JPEG's frame header may
+// follow large EXIF blocks, so it gets the most
+const HEADER_BYTES: u64 = 256 * 1024;
+
+// Width and height of a PNG, JPEG, GIF, WebP or BMP file, read from its
+// header. None for other formats and damaged files.
+pub fn read(path: &Path) -> Option<(u32, u32)> {
+    let mut header = Vec::new();
+    File::open(path).ok()?.take(HEADER_BYTES).read_to_end(&mut header).ok()?;
+    parse(&header)
+}
+
+fn parse(bytes: &[u8]) -> Option<(u32, u32)> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        // IHDR is always the first chunk
+        return Some((be32(bytes, 16)?, be32(bytes, 20)?));
+    }
+    if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        return Some((le16(bytes, 6)? as u32, le16(bytes, 8)? as u32));
+    }
+    if bytes.starts_with(b"BM") {
+        let width = le32(bytes, 18)? as i32;
+        let height = le32(bytes, 22)? as i32;
+        // Negative heights are top-down bitmaps
+        return Some((width.unsigned_abs(), height.unsigned_abs()));
+    }
+    if bytes.starts_with(b"\xff\xd8") {
+        return jpeg(bytes);
+    }
+    if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
+        return webp(bytes);
+    }
+    None
+}
+
+// Walks the segments to the first start-of-frame marker
+fn jpeg(bytes: &[u8]) -> Option<(u32, u32)> {
+    let mut offset = 2;
+    loop {
+        while *bytes.get(offset)? != 0xff {
+            offset += 1;
+        }
+        while *bytes.get(offset)? == 0xff {
+            offset += 1;
+        }
+        let marker = *bytes.get(offset)?;
+        offset += 1;
+        match marker {
+            // Markers without a length
+            0xd0..=0xd9 | 0x01 => continue,
+            // Start of frame, except DHT, JPG and DAC which share the range
+            0xc0..=0xcf if !matches!(marker, 0xc4 | 0xc8 | 0xcc) => {
+                let height = be16(bytes, offset + 3)?;
+                let width = be16(bytes, offset + 5)?;
+                return Some((width as u32, height as u32));
+            }
+            _ => offset += be16(bytes, offset)? as usize,
+        }
+    }
+}
+
+fn webp(bytes: &[u8]) -> Option<(u32, u32)> {
+    match bytes.get(12..16)? {
+        b"VP8 " => Some(((le16(bytes, 26)? & 0x3fff) as u32, (le16(bytes, 28)? & 0x3fff) as u32)),
+        b"VP8L" => {
+            let bits = le32(bytes, 21)?;
+            Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+        }
+        b"VP8X" => Some((le24(bytes, 24)? + 1, le24(bytes, 27)? + 1)),
+        _ => None,
+    }
+}
+
+fn be16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(bytes.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn be32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn le16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn le24(bytes: &[u8], offset: usize) -> Option<u32> {
+    let part = bytes.get(offset..offset + 3)?;
+    Some(part[0] as u32 | (part[1] as u32) << 8 | (part[2] as u32) << 16)
+}
+
+fn le32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
@@ -0,0 +1,376 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::file_lock::{self, FileLock};
+use crate::get_app_data_directory;
+use crate::notifications::DesktopNotification;
+
+// Held by the primary GUI instance for as long as it runs. The lock belongs to
+// the operating system and goes with a process that dies, so no stale lock is
+// left behind after a crash.
+const LOCK_FILE_NAME: &str = "instance.lock";
+// Where the primary listens and the token it was started with. Any local
+// process can reach a loopback port, so messages without the token are turned
+// away; only the user can read the file.
+const ENDPOINT_FILE_NAME: &str = "instance.json";
+const TOKEN_BYTES: usize = 32;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(3);
+const FORWARD_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum InstanceMessage {
+    Activate { args: Vec<String> },
+    Notify { notification: DesktopNotification },
+    // A native host changed the scheduled downloads file
+    ScheduleChanged,
+    // Files sent from Explorer's "Send to ImgVault" menu, as absolute paths
+    Import { paths: Vec<String> },
+    // A native host process Chrome started with `args`; after the reply the
+    // connection carries its messaging port's frames both ways
+    AttachNative { args: Vec<String> },
+}
+
+// What a later launch sends; the token of the running session first
+#[derive(Debug, Serialize, Deserialize)]
+struct InstanceRequest<M> {
+    token: String,
+    #[serde(flatten)]
+    message: M,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Endpoint {
+    port: u16,
+    token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct InstanceReply {
+    ok: bool,
+    message: Option<String>,
+}
+
+pub enum InstanceRole {
+    Primary(InstanceListener),
+    Forwarded,
+    Standalone(String),
+}
+
+pub struct InstanceListener {
+    listener: TcpListener,
+    token: String,
+    _lock: FileLock,
+}
+
+// Becomes the primary instance, or hands `args` to the running one
+pub fn acquire_or_forward(args: &[String]) -> InstanceRole {
+    match listen() {
+        Ok(Some(listener)) => InstanceRole::Primary(listener),
+        Ok(None) => match forward(&InstanceMessage::Activate { args: args.to_vec() }) {
+            Ok(()) => InstanceRole::Forwarded,
+            Err(error) => InstanceRole::Standalone(format!(
+                "Another instance holds the single-instance lock and hand-off failed: {}",
+                error
+            )),
+        },
+        Err(error) => InstanceRole::Standalone(format!("Failed to become the primary instance: {}", error)),
+    }
+}
+
+// None when another instance is primary
+fn listen() -> Result<Option<InstanceListener>, String> {
+    let Some(lock) = file_lock::try_acquire(LOCK_FILE_NAME, "single instance")? else {
+        return Ok(None);
+    };
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .map_err(|e| format!("Failed to listen for other instances: {}", e))?;
+    let port = listener
+        .local_addr()
+        .map_err(|e| format!("Failed to listen for other instances: {}", e))?
+        .port();
+    let mut random = [0u8; TOKEN_BYTES];
+    getrandom::getrandom(&mut random).map_err(|e| format!("Failed to create instance token: {}", e))?;
+    let token = random.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+    write_endpoint(&Endpoint { port, token: token.clone() })?;
+    Ok(Some(InstanceListener {
+        listener,
+        token,
+        _lock: lock,
+    }))
+}
+
+// Written aside and renamed, so a reader never sees half of it. On Windows
+// the app data directory is the user's own already.
+fn write_endpoint(endpoint: &Endpoint) -> Result<(), String> {
+    let path = endpoint_path()?;
+    let partial = path.with_extension("json.partial");
+    let payload = serde_json::to_string(endpoint).map_err(|e| format!("Failed to serialize instance endpoint: {}", e))?;
+    let _ = fs::remove_file(&partial);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(&partial)
+        .and_then(|mut file| file.write_all(payload.as_bytes()))
+        .and_then(|()| fs::rename(&partial, &path))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+fn read_endpoint() -> Result<Endpoint, String> {
+    let path = endpoint_path()?;
+    let payload = fs::read_to_string(&path).map_err(|e| format!("No running instance ({}): {}", path.display(), e))?;
+    serde_json::from_str(&payload).map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+}
+
+fn endpoint_path() -> Result<PathBuf, String> {
+    get_app_data_directory().map(|directory| directory.join(ENDPOINT_FILE_NAME))
+}
+
+// The primary may hold the lock but not have written its endpoint yet
+fn forward(message: &InstanceMessage) -> Result<(), String> {
+    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+    loop {
+        match send_to_running_instance(message) {
+            Ok(()) => return Ok(()),
+            Err(_) if Instant::now() < deadline => std::thread::sleep(FORWARD_RETRY_INTERVAL),
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+// Deliver a message to the running GUI, if there is one
+pub fn send_to_running_instance(message: &InstanceMessage) -> Result<(), String> {
+    handshake(message).map(|_| ())
+}
+
+// Hands a native messaging port to the running GUI and returns the
+// connection its frames travel over. Fails right away when no GUI runs.
+pub fn attach_native(args: &[String]) -> Result<TcpStream, String> {
+    let stream = handshake(&InstanceMessage::AttachNative { args: args.to_vec() })?;
+    stream
+        .set_read_timeout(None)
+        .map_err(|e| format!("Failed to configure instance socket: {}", e))?;
+    Ok(stream)
+}
+
+fn handshake(message: &InstanceMessage) -> Result<TcpStream, String> {
+    let endpoint = read_endpoint()?;
+    let mut stream = TcpStream::connect_timeout(&(Ipv4Addr::LOCALHOST, endpoint.port).into(), HANDSHAKE_TIMEOUT)
+        .map_err(|e| format!("Failed to connect to running instance: {}", e))?;
+    stream
+        .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+        .map_err(|e| format!("Failed to configure instance socket: {}", e))?;
+
+    let request = InstanceRequest {
+        token: endpoint.token,
+        message,
+    };
+    let payload = serde_json::to_string(&request)
+        .map_err(|e| format!("Failed to serialize instance message: {}", e))?;
+    writeln!(stream, "{}", payload)
+        .map_err(|e| format!("Failed to send instance message: {}", e))?;
+
+    let mut reply_line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut reply_line)
+        .map_err(|e| format!("Failed to read instance reply: {}", e))?;
+    let reply = serde_json::from_str::<InstanceReply>(&reply_line)
+        .map_err(|e| format!("Unexpected reply from instance port: {}", e))?;
+
+    if reply.ok {
+        Ok(stream)
+    } else {
+        Err(reply.message.unwrap_or_else(|| "Running instance rejected the message".to_string()))
+    }
+}
+
+impl InstanceListener {
+    // Accept hand-offs from later launches on a background thread. Attached
+    // native ports go to `attach`, which must not block the listener.
+    pub fn serve<F, A>(self, handler: F, attach: A)
+    where
+        F: Fn(InstanceMessage) + Send + 'static,
+        A: Fn(TcpStream, Vec<String>) + Send + 'static,
+    {
+        std::thread::spawn(move || {
+            for stream in self.listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(error) = handle_connection(stream, &self.token, &handler, &attach) {
+                            warn!("{}", error);
+                        }
+                    }
+                    Err(error) => warn!("Failed to accept connection: {}", error),
+                }
+            }
+        });
+    }
+}
+
+fn handle_connection<F, A>(mut stream: TcpStream, token: &str, handler: &F, attach: &A) -> Result<(), String>
+where
+    F: Fn(InstanceMessage),
+    A: Fn(TcpStream, Vec<String>),
+{
+    stream
+        .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+        .map_err(|e| format!("Failed to configure instance socket: {}", e))?;
+
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut line)
+        .map_err(|e| format!("Failed to read instance message: {}", e))?;
+
+    // Nothing of a message is looked at before its token
+    let request = match serde_json::from_str::<InstanceRequest<serde_json::Value>>(&line) {
+        Ok(request) if same_token(&request.token, token) => request,
+        Ok(_) => {
+            let reply = InstanceReply {
+                ok: false,
+                message: Some("Wrong instance token".to_string()),
+            };
+            write_reply(&mut stream, &reply).map_err(|e| format!("Failed to send instance reply: {}", e))?;
+            return Err("Turned away an instance message with the wrong token".to_string());
+        }
+        Err(e) => {
+            let reply = InstanceReply {
+                ok: false,
+                message: Some(format!("Invalid instance message: {}", e)),
+            };
+            write_reply(&mut stream, &reply).map_err(|e| format!("Failed to send instance reply: {}", e))?;
+            return Err(format!("Turned away an invalid instance message: {}", e));
+        }
+    };
+
+    let reply = match serde_json::from_value::<InstanceMessage>(request.message) {
+        Ok(InstanceMessage::AttachNative { args }) => {
+            write_reply(&mut stream, &InstanceReply { ok: true, message: None })
+                .and_then(|_| stream.set_read_timeout(None))
+                .map_err(|e| format!("Failed to accept native port: {}", e))?;
+            attach(stream, args);
+            return Ok(());
+        }
+        Ok(message) => {
+            handler(message);
+            InstanceReply { ok: true, message: None }
+        }
+        Err(e) => InstanceReply {
+            ok: false,
+            message: Some(format!("Invalid instance message: {}", e)),
+        },
+    };
+
+    write_reply(&mut stream, &reply)
+        .map_err(|e| format!("Failed to send instance reply: {}", e))
+}
+
+// Takes as long whichever byte differs
+fn same_token(sent: &str, token: &str) -> bool {
+    sent.len() == token.len() && sent.bytes().zip(token.bytes()).fold(0, |differ, (a, b)| differ | (a ^ b)) == 0
+}
+
+fn write_reply(stream: &mut TcpStream, reply: &InstanceReply) -> io::Result<()> {
+    let payload = serde_json::to_string(reply).map_err(io::Error::other)?;
+    writeln!(stream, "{}", payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use std::sync::mpsc::{self, Receiver};
+
+    // A primary instance serving on a thread, and what it was handed
+    fn primary() -> Receiver<InstanceMessage> {
+        let listener = listen().expect("listen").expect("no other primary");
+        let (sender, received) = mpsc::channel();
+        let sender = std::sync::Mutex::new(sender);
+        listener.serve(move |message| sender.lock().unwrap().send(message).unwrap(), |_, _| {});
+        received
+    }
+
+    // One request line as any local process could send it, and the reply
+    fn send_raw(line: &str) -> InstanceReply {
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, read_endpoint().expect("endpoint").port)).expect("connect");
+        writeln!(stream, "{}", line).expect("send");
+        let mut reply = String::new();
+        BufReader::new(&stream).read_line(&mut reply).expect("reply");
+        serde_json::from_str(&reply).expect("reply JSON")
+    }
+
+    #[test]
+    fn only_messages_with_the_session_token_are_taken() {
+        let _app_data = test_support::app_data();
+        let received = primary();
+        let token = read_endpoint().expect("endpoint").token;
+        assert_eq!(token.len(), TOKEN_BYTES * 2);
+
+        let turned_away = [
+            r#"{"kind":"activate","args":["imgvault","--download","https://example.com/a.png"]}"#.to_string(),
+            r#"{"token":"","kind":"schedule_changed"}"#.to_string(),
+            format!(r#"{{"token":"{}","kind":"schedule_changed"}}"#, "0".repeat(token.len())),
+            format!(r#"{{"token":"{}","kind":"schedule_changed"}}"#, &token[1..]),
+            "not json".to_string(),
+        ];
+        for line in &turned_away {
+            let reply = send_raw(line);
+            assert!(!reply.ok, "{}", line);
+        }
+        assert!(received.recv_timeout(Duration::from_millis(200)).is_err());
+
+        let reply = send_raw(&format!(r#"{{"token":"{}","kind":"schedule_changed"}}"#, token));
+        assert!(reply.ok, "{:?}", reply.message);
+        assert!(matches!(received.recv_timeout(HANDSHAKE_TIMEOUT), Ok(InstanceMessage::ScheduleChanged)));
+    }
+
+    #[test]
+    fn a_later_launch_forwards_to_the_primary() {
+        let _app_data = test_support::app_data();
+        let received = primary();
+
+        let args = vec!["imgvault".to_string(), "--minimized".to_string()];
+        // The lock of this process would be this process's again, so the
+        // hand-off acquire_or_forward makes is made directly
+        forward(&InstanceMessage::Activate { args: args.clone() }).expect("forwarded");
+        match received.recv_timeout(HANDSHAKE_TIMEOUT) {
+            Ok(InstanceMessage::Activate { args: forwarded }) => assert_eq!(forwarded, args),
+            other => panic!("{:?}", other),
+        }
+        assert!(send_to_running_instance(&InstanceMessage::ScheduleChanged).is_ok());
+    }
+
+    #[test]
+    fn without_a_primary_nothing_is_sent() {
+        let app_data = test_support::app_data();
+        assert!(send_to_running_instance(&InstanceMessage::ScheduleChanged).is_err());
+        assert!(attach_native(&[]).is_err());
+
+        // An endpoint left by a primary that crashed leads nowhere
+        let stale = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).expect("port");
+        let port = stale.local_addr().expect("address").port();
+        drop(stale);
+        write_endpoint(&Endpoint { port, token: "0".repeat(TOKEN_BYTES * 2) }).expect("endpoint");
+        assert!(send_to_running_instance(&InstanceMessage::ScheduleChanged).is_err());
+        assert!(app_data.directory.join(ENDPOINT_FILE_NAME).exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_the_user_can_read_the_endpoint() {
+        use std::os::unix::fs::PermissionsExt;
+        let app_data = test_support::app_data();
+        let _received = primary();
+        let mode = fs::metadata(app_data.directory.join(ENDPOINT_FILE_NAME)).expect("endpoint").permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
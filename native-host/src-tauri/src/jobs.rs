@@ -0,0 +1,594 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::{info, warn};
+
+use crate::events::JobEvents;
+use crate::get_app_data_directory;
+use crate::power;
+use crate::process_priority::{self, ProcessPriority};
+use crate::schedule::Scheduler;
+use crate::stall::{Stall, StallAction, StallDetector};
+use crate::supervisor::{ChildSupervisor, SupervisionReport, SupervisorKill};
+
+// Present while the queue is paused, so the state survives restarts and every
+// host process sees it
+const PAUSED_FILE_NAME: &str = "queue-paused";
+
+// Registry of running yt-dlp children, shared by the GUI commands and the
+// native messaging cancel action. Every job is also mirrored to a pid file in
+// the temp directory because Chrome opens a separate host process per port,
+// so a cancel request usually arrives in a different process than the download.
+#[derive(Clone, Default)]
+pub struct JobRegistry {
+    jobs: Arc<Mutex<HashMap<String, Arc<JobHandle>>>>,
+    paused: Arc<AtomicBool>,
+    // Set once by the shutdown sequence; no job starts after that
+    shutting_down: Arc<AtomicBool>,
+    in_flight: Arc<AtomicUsize>,
+    events: JobEvents,
+    scheduler: Scheduler,
+}
+
+// Counts a job from its start until its outcome is recorded, so shutdown can
+// wait for queue entries and history to be written
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub struct JobHandle {
+    job_id: String,
+    pid: u32,
+    pid_file_written: bool,
+    cancelled: AtomicBool,
+    // Stopped because the queue was paused; its partial files are kept for --continue
+    paused: AtomicBool,
+    // Stopped by shutdown once the grace period ran out; also kept for --continue
+    interrupted: AtomicBool,
+    speed_bytes_per_second: AtomicU64,
+    destinations: Mutex<Vec<PathBuf>>,
+    stall_detector: Mutex<Option<StallDetector>>,
+    // Set when the job was stopped for a restart after stalling
+    stalled: Mutex<Option<Stall>>,
+    supervisor: Mutex<Option<ChildSupervisor>>,
+    // Set when the supervisor stopped the child
+    supervisor_kill: Mutex<Option<SupervisorKill>>,
+    // What the child tree runs at; a boost from the tray sets it back to normal
+    process_priority: Mutex<ProcessPriority>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobSummary {
+    pub job_id: String,
+    pub pid: u32,
+    pub speed_bytes_per_second: u64,
+    pub process_priority: ProcessPriority,
+}
+
+pub enum CancelOutcome {
+    Cancelled,
+    NotRunning,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobEnd {
+    // yt-dlp exited on its own, successfully or not
+    Exited,
+    Cancelled,
+    Paused,
+    Interrupted,
+    // Stopped by stall detection, to be queued again
+    Stalled(Stall),
+    // Stopped by its supervisor: silent too long or over the memory limit
+    Supervised(SupervisorKill),
+}
+
+impl JobRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&self, job_id: &str, pid: u32) -> Arc<JobHandle> {
+        let pid_file_written = match write_request_pid(job_id, pid) {
+            Ok(()) => true,
+            Err(error) => {
+                warn!(job_id, "Failed to persist pid: {}", error);
+                false
+            }
+        };
+
+        let handle = Arc::new(JobHandle {
+            job_id: job_id.to_string(),
+            pid,
+            pid_file_written,
+            cancelled: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            interrupted: AtomicBool::new(false),
+            speed_bytes_per_second: AtomicU64::new(0),
+            destinations: Mutex::new(Vec::new()),
+            stall_detector: Mutex::new(None),
+            stalled: Mutex::new(None),
+            supervisor: Mutex::new(None),
+            supervisor_kill: Mutex::new(None),
+            process_priority: Mutex::new(ProcessPriority::Normal),
+        });
+
+        let active = {
+            let mut jobs = self.jobs.lock().unwrap();
+            jobs.insert(job_id.to_string(), Arc::clone(&handle));
+            jobs.len()
+        };
+        power::set_active_jobs(active);
+
+        handle
+    }
+
+    // Removes the job once its child has exited and reports whether it ended
+    // because of a cancel request, either from this process or another one, or
+    // because the queue was paused or the host shut down.
+    pub fn finish(&self, handle: &JobHandle, succeeded: bool) -> JobEnd {
+        let active = {
+            let mut jobs = self.jobs.lock().unwrap();
+            jobs.remove(&handle.job_id);
+            jobs.len()
+        };
+        // The last job out lets the machine sleep again
+        power::set_active_jobs(active);
+
+        let cancelled_elsewhere =
+            handle.pid_file_written && !get_request_pid_path(&handle.job_id).exists();
+        remove_request_pid(&handle.job_id);
+
+        if handle.is_cancelled() || cancelled_elsewhere {
+            handle.cleanup_partial_files();
+            JobEnd::Cancelled
+        } else if handle.paused.load(Ordering::SeqCst) && !succeeded {
+            JobEnd::Paused
+        } else if handle.interrupted.load(Ordering::SeqCst) && !succeeded {
+            JobEnd::Interrupted
+        } else if let (Some(kill), false) = (*handle.supervisor_kill.lock().unwrap(), succeeded) {
+            JobEnd::Supervised(kill)
+        } else {
+            match *handle.stalled.lock().unwrap() {
+                Some(stall) if !succeeded => JobEnd::Stalled(stall),
+                _ => JobEnd::Exited,
+            }
+        }
+    }
+
+    pub fn track(&self) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(Arc::clone(&self.in_flight))
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    pub fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        self.scheduler.stop();
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    // Kills every child this process still runs at the end of the shutdown
+    // grace period, keeping partial files. Returns how many there were.
+    pub fn interrupt_all(&self) -> usize {
+        let running = self.jobs.lock().unwrap().values().cloned().collect::<Vec<_>>();
+        running
+            .iter()
+            .filter(|handle| {
+                handle.interrupted.store(true, Ordering::SeqCst);
+                match kill_process_tree(handle.pid) {
+                    Ok(()) => true,
+                    Err(error) => {
+                        warn!(job_id = %handle.job_id, "Failed to stop job during shutdown: {}", error);
+                        false
+                    }
+                }
+            })
+            .count()
+    }
+
+    // Number of running jobs and their combined download speed in bytes per second
+    pub fn activity(&self) -> (usize, u64) {
+        let jobs = self.jobs.lock().unwrap();
+        let speed = jobs
+            .values()
+            .map(|job| job.speed_bytes_per_second.load(Ordering::Relaxed))
+            .sum();
+        (jobs.len(), speed)
+    }
+
+    // Jobs running in this process, sorted by id
+    pub fn list(&self) -> Vec<JobSummary> {
+        let mut jobs = self
+            .jobs
+            .lock()
+            .unwrap()
+            .values()
+            .map(|job| JobSummary {
+                job_id: job.job_id.clone(),
+                pid: job.pid,
+                speed_bytes_per_second: job.speed_bytes_per_second.load(Ordering::Relaxed),
+                process_priority: *job.process_priority.lock().unwrap(),
+            })
+            .collect::<Vec<_>>();
+        jobs.sort_by(|a, b| a.job_id.cmp(&b.job_id));
+        jobs
+    }
+
+    pub fn events(&self) -> &JobEvents {
+        &self.events
+    }
+
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    pub fn is_paused(&self) -> bool {
+        is_queue_paused().unwrap_or_else(|| self.paused.load(Ordering::SeqCst))
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+        if let Err(error) = persist_paused(paused) {
+            warn!("{}; the queue state will not survive a restart", error);
+        }
+    }
+
+    // Pauses the queue and stops this process's running jobs right away; jobs
+    // of other host processes stop themselves once they see the paused state.
+    // Returns how many jobs were stopped here.
+    pub fn pause(&self) -> usize {
+        self.set_paused(true);
+        let running = self.jobs.lock().unwrap().values().cloned().collect::<Vec<_>>();
+        running.iter().filter(|handle| self.suspend(handle)).count()
+    }
+
+    // Called by a running job's output loop so a pause from anywhere reaches it
+    pub fn suspend_if_paused(&self, handle: &JobHandle) {
+        if !handle.paused.load(Ordering::SeqCst) && self.is_paused() {
+            self.suspend(handle);
+        }
+    }
+
+    // Called by a running job's output loop; stops the job when it stalled and
+    // the settings ask for a restart
+    pub fn check_stall(&self, handle: &JobHandle) -> Option<Stall> {
+        let stall = handle.stall_detector.lock().unwrap().as_mut()?.check(Instant::now())?;
+        if stall.action == StallAction::Restart {
+            *handle.stalled.lock().unwrap() = Some(stall);
+            if let Err(error) = kill_process_tree(handle.pid) {
+                warn!(job_id = %handle.job_id, "Failed to stop stalled job: {}", error);
+            }
+        }
+        Some(stall)
+    }
+
+    // Called by a running job's output loop alongside check_stall; stops the
+    // child when its supervisor says so
+    pub fn check_supervisor(&self, handle: &JobHandle) {
+        let kill = handle.supervisor.lock().unwrap().as_mut().and_then(|supervisor| supervisor.check(Instant::now()));
+        let Some(kill) = kill else {
+            return;
+        };
+        *handle.supervisor_kill.lock().unwrap() = Some(kill);
+        if let Err(error) = kill_process_tree(handle.pid) {
+            warn!(job_id = %handle.job_id, "Failed to stop supervised job: {}", error);
+        }
+    }
+
+    fn suspend(&self, handle: &JobHandle) -> bool {
+        if handle.paused.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        match kill_process_tree(handle.pid) {
+            Ok(()) => true,
+            Err(error) => {
+                warn!(job_id = %handle.job_id, "Failed to suspend job: {}", error);
+                false
+            }
+        }
+    }
+
+    // Changes the priority of one job running in this process
+    pub fn set_process_priority(&self, job_id: &str, priority: ProcessPriority) -> Result<(), String> {
+        let handle = self.jobs.lock().unwrap().get(job_id).cloned();
+        match handle {
+            Some(handle) => handle.set_process_priority(priority),
+            None => Err(format!("Download {} is not running in this process", job_id)),
+        }
+    }
+
+    // Puts every job of this process at `priority`, e.g. when the
+    // background_priority setting changes or the tray boosts the downloads.
+    // Returns how many jobs changed.
+    pub fn set_all_process_priorities(&self, priority: ProcessPriority) -> usize {
+        let running = self.jobs.lock().unwrap().values().cloned().collect::<Vec<_>>();
+        running
+            .iter()
+            .filter(|handle| *handle.process_priority.lock().unwrap() != priority)
+            .filter(|handle| match handle.set_process_priority(priority) {
+                Ok(()) => true,
+                Err(error) => {
+                    warn!(job_id = %handle.job_id, "{}", error);
+                    false
+                }
+            })
+            .count()
+    }
+
+    pub fn cancel(&self, job_id: &str) -> Result<CancelOutcome, String> {
+        let local = self.jobs.lock().unwrap().get(job_id).cloned();
+
+        if let Some(handle) = local {
+            handle.cancelled.store(true, Ordering::SeqCst);
+            remove_request_pid(job_id);
+            kill_process_tree(handle.pid)?;
+            return Ok(CancelOutcome::Cancelled);
+        }
+
+        let pid_path = get_request_pid_path(job_id);
+        if !pid_path.exists() {
+            return Ok(CancelOutcome::NotRunning);
+        }
+
+        let pid_text = fs::read_to_string(&pid_path)
+            .map_err(|e| format!("Failed to read request pid file: {}", e))?;
+        let pid = pid_text
+            .trim()
+            .parse::<u32>()
+            .map_err(|e| format!("Failed to parse request pid: {}", e))?;
+
+        // The owning process notices the missing pid file once its child exits
+        // and cleans up the partial files itself.
+        remove_request_pid(job_id);
+        match kill_process_tree(pid) {
+            Ok(()) => Ok(CancelOutcome::Cancelled),
+            Err(_) if !process_exists(pid) => Ok(CancelOutcome::NotRunning),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+impl JobHandle {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn watch_for_stalls(&self, detector: Option<StallDetector>) {
+        *self.stall_detector.lock().unwrap() = detector;
+    }
+
+    pub fn supervise(&self, supervisor: ChildSupervisor) {
+        *self.supervisor.lock().unwrap() = Some(supervisor);
+    }
+
+    // Applies `priority` to the child and everything it started, and notes
+    // the change in the supervision report
+    pub fn set_process_priority(&self, priority: ProcessPriority) -> Result<(), String> {
+        process_priority::apply(self.pid, priority)?;
+        *self.process_priority.lock().unwrap() = priority;
+        info!(job_id = %self.job_id, priority = priority.name(), "Download priority set");
+        if let Some(supervisor) = self.supervisor.lock().unwrap().as_mut() {
+            supervisor.note(format!("Running at {} priority", priority.name()));
+        }
+        Ok(())
+    }
+
+    pub fn supervision_report(&self) -> Option<SupervisionReport> {
+        self.supervisor.lock().unwrap().as_ref().map(ChildSupervisor::report)
+    }
+
+    // Remembers every file yt-dlp announces so a cancelled job can remove its
+    // partial downloads and unmerged format streams, and tracks the current
+    // speed and whether the download stalled.
+    pub fn observe_output_line(&self, line: &str) {
+        if let Some(speed) = parse_progress_speed(line) {
+            self.speed_bytes_per_second.store(speed, Ordering::Relaxed);
+        }
+        if let Some(detector) = self.stall_detector.lock().unwrap().as_mut() {
+            detector.observe(line, Instant::now());
+        }
+        if let Some(supervisor) = self.supervisor.lock().unwrap().as_mut() {
+            supervisor.observe_output(Instant::now());
+        }
+
+        let trimmed = line.trim();
+        let destination = trimmed
+            .strip_prefix("[download] Destination:")
+            .or_else(|| trimmed.strip_prefix("[Merger] Merging formats into"))
+            .map(|rest| rest.trim().trim_matches('"'));
+
+        if let Some(destination) = destination.filter(|value| !value.is_empty()) {
+            self.destinations
+                .lock()
+                .unwrap()
+                .push(PathBuf::from(destination));
+        }
+    }
+
+    fn cleanup_partial_files(&self) {
+        for destination in self.destinations.lock().unwrap().iter() {
+            remove_partial_files(destination);
+        }
+    }
+}
+
+// Reads the speed from a yt-dlp progress line such as
+// "[download]  45.3% of  100.00MiB at    2.50MiB/s ETA 00:22"
+pub fn parse_progress_speed(line: &str) -> Option<u64> {
+    let rest = line.trim().strip_prefix("[download]")?;
+    let speed = rest.split(" at ").nth(1)?.split_whitespace().next()?;
+    parse_byte_size(speed.strip_suffix("/s")?)
+}
+
+// Reads a yt-dlp size such as "100.00MiB"
+pub fn parse_byte_size(size: &str) -> Option<u64> {
+    let split_at = size.find(|ch: char| !(ch.is_ascii_digit() || ch == '.'))?;
+    let (value, unit) = size.split_at(split_at);
+    let value = value.parse::<f64>().ok()?;
+    let multiplier = match unit {
+        "B" => 1.0,
+        "KiB" => 1024.0,
+        "MiB" => 1024.0 * 1024.0,
+        "GiB" => 1024.0 * 1024.0 * 1024.0,
+        "KB" => 1000.0,
+        "MB" => 1000.0 * 1000.0,
+        "GB" => 1000.0 * 1000.0 * 1000.0,
+        _ => return None,
+    };
+
+    Some((value * multiplier) as u64)
+}
+
+fn remove_partial_files(destination: &Path) {
+    let Some(file_name) = destination.file_name().and_then(|name| name.to_str()) else {
+        return;
+    };
+
+    let _ = fs::remove_file(destination);
+    let _ = fs::remove_file(destination.with_file_name(format!("{}.part", file_name)));
+    let _ = fs::remove_file(destination.with_file_name(format!("{}.ytdl", file_name)));
+
+    let fragment_prefix = format!("{}.part-Frag", file_name);
+    if let Some(Ok(entries)) = destination.parent().map(fs::read_dir) {
+        for entry in entries.flatten() {
+            if entry.file_name().to_string_lossy().starts_with(&fragment_prefix) {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+// None when the app data directory is unavailable
+pub fn is_queue_paused() -> Option<bool> {
+    Some(get_app_data_directory().ok()?.join(PAUSED_FILE_NAME).exists())
+}
+
+// Whether a host still lists `job_id` as running. A cancel from any process
+// removes it, which is how the image fetch child notices one.
+pub fn is_registered(job_id: &str) -> bool {
+    get_request_pid_path(job_id).exists()
+}
+
+fn persist_paused(paused: bool) -> Result<(), String> {
+    let directory = get_app_data_directory()?;
+    let path = directory.join(PAUSED_FILE_NAME);
+    if paused {
+        fs::create_dir_all(&directory)
+            .and_then(|_| fs::write(&path, b""))
+            .map_err(|e| format!("Failed to save paused queue state: {}", e))
+    } else {
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(format!("Failed to clear paused queue state: {}", error)),
+        }
+    }
+}
+
+fn sanitize_request_id(request_id: &str) -> String {
+    request_id
+        .chars()
+        .map(|ch| {
+            if ch.is_ascii_alphanumeric() || ch == '-' || ch == '_' {
+                ch
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn get_request_pid_path(request_id: &str) -> PathBuf {
+    env::temp_dir().join(format!(
+        "imgvault-native-download-{}.pid",
+        sanitize_request_id(request_id)
+    ))
+}
+
+fn write_request_pid(request_id: &str, pid: u32) -> Result<(), String> {
+    fs::write(get_request_pid_path(request_id), pid.to_string())
+        .map_err(|e| format!("Failed to write request pid file: {}", e))
+}
+
+fn remove_request_pid(request_id: &str) {
+    let _ = fs::remove_file(get_request_pid_path(request_id));
+}
+
+#[cfg(target_os = "windows")]
+pub fn kill_process_tree(pid: u32) -> Result<(), String> {
+    use std::os::windows::process::CommandExt;
+    const CREATE_NO_WINDOW: u32 = 0x08000000;
+
+    let status = Command::new("taskkill")
+        .arg("/PID")
+        .arg(pid.to_string())
+        .arg("/T")
+        .arg("/F")
+        .creation_flags(CREATE_NO_WINDOW)
+        .status()
+        .map_err(|e| format!("Failed to execute taskkill: {}", e))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("taskkill failed with exit code {:?}", status.code()))
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn kill_process_tree(pid: u32) -> Result<(), String> {
+    let status = Command::new("kill")
+        .arg("-9")
+        .arg(pid.to_string())
+        .status()
+        .map_err(|e| format!("Failed to execute kill: {}", e))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("kill failed with exit code {:?}", status.code()))
+    }
+}
+
+#[cfg(target_os = "windows")]
+pub fn process_exists(pid: u32) -> bool {
+    use std::os::windows::process::CommandExt;
+    const CREATE_NO_WINDOW: u32 = 0x08000000;
+
+    Command::new("tasklist")
+        .arg("/FI")
+        .arg(format!("PID eq {}", pid))
+        .arg("/NH")
+        .creation_flags(CREATE_NO_WINDOW)
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).contains(&pid.to_string()))
+        .unwrap_or(false)
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn process_exists(pid: u32) -> bool {
+    Command::new("kill")
+        .arg("-0")
+        .arg(pid.to_string())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
@@ -0,0 +1,116 @@
+use keyring::Entry;
+use std::borrow::Cow;
+use std::sync::Mutex;
+
+// Credential Manager on Windows, Keychain on macOS, Secret Service on Linux
+const SERVICE: &str = "ImgVault";
+// Settings strings may embed {secret:<name>}; it is replaced only when the
+// value is used, so the settings file never holds the secret itself
+const REFERENCE_PREFIX: &str = "{secret:";
+const MAX_NAME_LENGTH: usize = 64;
+// Shorter values would scrub ordinary words out of the log
+const MIN_SCRUB_LENGTH: usize = 4;
+const SCRUBBED: &str = "<secret>";
+
+// Every secret value this process has read, scrubbed from log lines and diagnostics
+static SENSITIVE_VALUES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+fn entry(name: &str) -> Result<Entry, String> {
+    Entry::new(SERVICE, name).map_err(|e| format!("Failed to open keychain entry {}: {}", name, e))
+}
+
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err(format!("Secret name must be between 1 and {} characters", MAX_NAME_LENGTH));
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        return Err("Secret name may only contain letters, digits, '_', '-' and '.'".to_string());
+    }
+    Ok(())
+}
+
+pub fn get_secret(name: &str) -> Result<Option<String>, String> {
+    match entry(name)?.get_password() {
+        Ok(value) => {
+            mark_sensitive(&value);
+            Ok(Some(value))
+        }
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(error) => Err(format!("Failed to read {} from the keychain: {}", name, error)),
+    }
+}
+
+pub fn has_secret(name: &str) -> bool {
+    matches!(entry(name).map(|entry| entry.get_password()), Ok(Ok(_)))
+}
+
+pub fn set_secret(name: &str, value: &str) -> Result<(), String> {
+    validate_name(name)?;
+    if value.is_empty() {
+        return Err(format!("Secret {} must not be empty", name));
+    }
+    entry(name)?
+        .set_password(value)
+        .map_err(|e| format!("Failed to save {} to the keychain: {}", name, e))?;
+    mark_sensitive(value);
+    Ok(())
+}
+
+pub fn delete_secret(name: &str) -> Result<(), String> {
+    match entry(name)?.delete_password() {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(error) => Err(format!("Failed to remove {} from the keychain: {}", name, error)),
+    }
+}
+
+pub fn reference(name: &str) -> String {
+    format!("{}{}}}", REFERENCE_PREFIX, name)
+}
+
+pub fn has_references(value: &str) -> bool {
+    value.contains(REFERENCE_PREFIX)
+}
+
+// Replace every {secret:<name>} in a settings value with the stored secret
+pub fn resolve_references(value: &str) -> Result<String, String> {
+    let mut resolved = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find(REFERENCE_PREFIX) {
+        resolved.push_str(&rest[..start]);
+        let after = &rest[start + REFERENCE_PREFIX.len()..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| "Unterminated {secret:...} reference".to_string())?;
+        let name = &after[..end];
+        validate_name(name)?;
+        let secret = get_secret(name)?.ok_or_else(|| format!("Secret {} is not set", name))?;
+        resolved.push_str(&secret);
+        rest = &after[end + 1..];
+    }
+    resolved.push_str(rest);
+    Ok(resolved)
+}
+
+pub fn mark_sensitive(value: &str) {
+    if value.len() < MIN_SCRUB_LENGTH {
+        return;
+    }
+    let mut values = SENSITIVE_VALUES.lock().unwrap();
+    if !values.iter().any(|known| known == value) {
+        values.push(value.to_string());
+    }
+}
+
+pub fn scrub(text: &str) -> Cow<'_, str> {
+    let values = SENSITIVE_VALUES.lock().unwrap();
+    let mut scrubbed = Cow::Borrowed(text);
+    for value in values.iter() {
+        if scrubbed.contains(value.as_str()) {
+            scrubbed = Cow::Owned(scrubbed.replace(value.as_str(), SCRUBBED));
+        }
+    }
+    scrubbed
+}
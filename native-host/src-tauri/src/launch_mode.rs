@@ -0,0 +1,181 @@
+use tracing::debug;
+
+use crate::{autostart, cli, deep_link, extension_sim};
+
+// Our own flag, used by the diagnostics self-test and older registrations
+const NATIVE_FLAG: &str = "--native";
+// Chromium browsers pass the caller's origin first, "chrome-extension://<id>/"
+const CHROMIUM_ORIGIN_SCHEME: &str = "chrome-extension://";
+// Firefox passes the path of the host manifest first, then the add-on ID and
+// nothing else
+const MANIFEST_EXTENSION: &str = ".json";
+// Extra arguments Chrome adds on Windows; they say nothing about the mode
+const IGNORED_BROWSER_PREFIXES: &[&str] = &["--parent-window="];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LaunchMode {
+    // Spawned by a browser for one native messaging port
+    Native,
+    Cli,
+    Gui,
+    // Plays fixture conversations against native hosts of its own
+    SimulateExtension,
+    // Plays captured sessions the same way and compares the answers
+    Replay,
+}
+
+// How this process was started. Arguments decide when they can; a launch
+// without any falls back to checking whether a browser piped stdin.
+pub fn detect(args: &[String]) -> LaunchMode {
+    let mode = classify(args).unwrap_or_else(|| {
+        if stdin_is_pipe() {
+            LaunchMode::Native
+        } else {
+            LaunchMode::Gui
+        }
+    });
+    debug!(mode = ?mode, arguments = args.len().saturating_sub(1), "Detected launch mode");
+    mode
+}
+
+// None when the arguments are ambiguous
+fn classify(args: &[String]) -> Option<LaunchMode> {
+    if args.iter().skip(1).any(|arg| arg == extension_sim::SIMULATE_FLAG) {
+        return Some(LaunchMode::SimulateExtension);
+    }
+    if args.iter().skip(1).any(|arg| arg == extension_sim::REPLAY_FLAG) {
+        return Some(LaunchMode::Replay);
+    }
+    if args.iter().skip(1).any(|arg| arg == NATIVE_FLAG) {
+        return Some(LaunchMode::Native);
+    }
+    // Scripts often run subcommands with stdin redirected
+    if cli::is_cli_invocation(args) {
+        return Some(LaunchMode::Cli);
+    }
+
+    let mut arguments = args
+        .iter()
+        .skip(1)
+        .filter(|arg| !IGNORED_BROWSER_PREFIXES.iter().any(|prefix| arg.starts_with(prefix)));
+    let first = arguments.next()?;
+    if first.starts_with(CHROMIUM_ORIGIN_SCHEME) {
+        return Some(LaunchMode::Native);
+    }
+    if is_firefox_launch(first, arguments.collect::<Vec<_>>().as_slice()) {
+        return Some(LaunchMode::Native);
+    }
+    if is_gui_argument(first) {
+        return Some(LaunchMode::Gui);
+    }
+    // Something we do not know; let the pipe decide
+    None
+}
+
+// A lone path ending in .json is as likely a file someone opened with us
+fn is_firefox_launch(manifest: &str, rest: &[&String]) -> bool {
+    manifest.to_ascii_lowercase().ends_with(MANIFEST_EXTENSION) && matches!(rest, [addon_id] if is_addon_id(addon_id))
+}
+
+// "name@example.com" or a "{GUID}", the two forms Firefox allows
+fn is_addon_id(arg: &str) -> bool {
+    if let Some(guid) = arg.strip_prefix('{').and_then(|rest| rest.strip_suffix('}')) {
+        return !guid.is_empty() && guid.chars().all(|c| c.is_ascii_hexdigit() || c == '-');
+    }
+    match arg.split_once('@') {
+        Some((name, domain)) => {
+            !name.is_empty()
+                && !domain.is_empty()
+                && !domain.contains('@')
+                && !arg.contains(|c: char| c.is_whitespace() || matches!(c, '/' | '\\'))
+        }
+        None => false,
+    }
+}
+
+fn is_gui_argument(arg: &str) -> bool {
+    arg == autostart::MINIMIZED_FLAG
+        || arg.starts_with("http://")
+        || arg.starts_with("https://")
+        || deep_link::is_deep_link(arg)
+}
+
+// Browsers always connect stdin to a pipe, but so do scripts; only used when
+// the arguments say nothing
+#[cfg(target_os = "windows")]
+fn stdin_is_pipe() -> bool {
+    use std::os::windows::io::AsRawHandle;
+    use winapi::um::fileapi::GetFileType;
+    use winapi::um::winbase::FILE_TYPE_PIPE;
+
+    let handle = std::io::stdin().as_raw_handle();
+    unsafe { GetFileType(handle as _) == FILE_TYPE_PIPE }
+}
+
+// Outside Windows the browsers always pass the origin or manifest path
+#[cfg(not(target_os = "windows"))]
+fn stdin_is_pipe() -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn launched_with(arguments: &[&str]) -> Option<LaunchMode> {
+        let args = std::iter::once("imgvault-native-host")
+            .chain(arguments.iter().copied())
+            .map(String::from)
+            .collect::<Vec<_>>();
+        classify(&args)
+    }
+
+    #[test]
+    fn chromium_browsers_pass_the_origin() {
+        // Chrome and Edge on Linux and macOS, then on Windows with the window handle
+        let chrome = "chrome-extension://knldjmfmopnpolahpmmgbagdohdnhkik/";
+        let edge = "chrome-extension://jlhmfgmfgeifomenelglieieghnjghma/";
+        for origin in [chrome, edge] {
+            assert_eq!(launched_with(&[origin]), Some(LaunchMode::Native));
+            assert_eq!(launched_with(&[origin, "--parent-window=0"]), Some(LaunchMode::Native));
+        }
+        assert_eq!(launched_with(&["--parent-window=1312"]), None);
+    }
+
+    #[test]
+    fn firefox_passes_the_manifest_and_the_addon_id() {
+        let cases = [
+            ["/home/ana/.mozilla/native-messaging-hosts/com.imgvault.native.json", "imgvault@fahadbinhussain.dev"],
+            [r"C:\Program Files\ImgVault\com.imgvault.native.json", "{8b1c3a52-9f0e-4d7b-a2c6-51e0f4d9b7a3}"],
+            ["/Library/Application Support/Mozilla/NativeMessagingHosts/COM.IMGVAULT.NATIVE.JSON", "imgvault@example"],
+        ];
+        for arguments in cases {
+            assert_eq!(launched_with(&arguments), Some(LaunchMode::Native), "{:?}", arguments);
+        }
+    }
+
+    #[test]
+    fn a_json_file_alone_is_not_a_browser() {
+        let cases: &[&[&str]] = &[
+            &["export.json"],
+            &["/home/ana/Downloads/settings.json"],
+            &["a.json", "b.json"],
+            &["manifest.json", "not an id"],
+            &["manifest.json", "imgvault@example", "extra"],
+            &["manifest.json", "{}"],
+            &["manifest.json", "../evil@example"],
+        ];
+        for arguments in cases {
+            assert_eq!(launched_with(arguments), None, "{:?}", arguments);
+        }
+    }
+
+    #[test]
+    fn gui_arguments_start_the_gui() {
+        for argument in ["--minimized", "https://images.example/a.png", "http://images.example/a.png"] {
+            assert_eq!(launched_with(&[argument]), Some(LaunchMode::Gui), "{}", argument);
+        }
+        assert_eq!(launched_with(&[]), None);
+        assert_eq!(launched_with(&["--native"]), Some(LaunchMode::Native));
+    }
+}
@@ -0,0 +1,126 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tauri::{AppHandle, Manager};
+use tracing::warn;
+
+use crate::logging::get_log_path;
+
+// Hard caps so a request for a million lines cannot exhaust memory
+const MAX_TAIL_LINES: usize = 5000;
+const MAX_TAIL_BYTES: u64 = 4 * 1024 * 1024;
+const TAIL_CHUNK_BYTES: u64 = 64 * 1024;
+// Largest backlog sent in one follow tick; older output is skipped
+const MAX_FOLLOW_BYTES: u64 = 256 * 1024;
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+pub const LOG_LINES_EVENT: &str = "log-lines";
+
+// Last `lines` lines of the log file, oldest first
+pub fn read_log_tail(path: &Path, lines: usize) -> io::Result<Vec<String>> {
+    let lines = lines.min(MAX_TAIL_LINES);
+    if lines == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error),
+    };
+    let len = file.metadata()?.len();
+
+    // Walk backwards in chunks until enough newlines have been seen
+    let mut start = len;
+    let mut buffer = Vec::new();
+    while start > 0 && len - start < MAX_TAIL_BYTES {
+        let chunk = TAIL_CHUNK_BYTES.min(start);
+        start -= chunk;
+        let mut block = vec![0u8; chunk as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut block)?;
+        block.extend_from_slice(&buffer);
+        buffer = block;
+
+        if buffer.iter().filter(|byte| **byte == b'\n').count() > lines {
+            break;
+        }
+    }
+
+    let text = String::from_utf8_lossy(&buffer);
+    let all_lines = text.lines().collect::<Vec<_>>();
+    let skip = all_lines.len().saturating_sub(lines);
+    Ok(all_lines[skip..].iter().map(|line| line.to_string()).collect())
+}
+
+// Streams lines appended to the log to the frontend. Each enable starts a new
+// generation so toggling quickly never leaves two followers running.
+#[derive(Clone, Default)]
+pub struct LogFollower {
+    generation: Arc<AtomicU64>,
+}
+
+impl LogFollower {
+    pub fn set_enabled(&self, app: AppHandle, enabled: bool) -> Result<(), String> {
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        if !enabled {
+            return Ok(());
+        }
+
+        let path = get_log_path()?;
+        let current = Arc::clone(&self.generation);
+        std::thread::spawn(move || {
+            let mut offset = fs::metadata(&path).map(|metadata| metadata.len()).unwrap_or(0);
+            while current.load(Ordering::SeqCst) == generation {
+                match read_new_lines(&path, &mut offset) {
+                    Ok(lines) if !lines.is_empty() => {
+                        let _ = app.emit_all(LOG_LINES_EVENT, lines);
+                    }
+                    Ok(_) => {}
+                    Err(error) => warn!("Failed to follow log: {}", error),
+                }
+                std::thread::sleep(FOLLOW_INTERVAL);
+            }
+        });
+
+        Ok(())
+    }
+}
+
+// Complete lines written since `offset`. A file shorter than the offset means
+// it was rotated, so reading restarts at the top of the new file.
+fn read_new_lines(path: &Path, offset: &mut u64) -> io::Result<Vec<String>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error),
+    };
+    let len = file.metadata()?.len();
+
+    if len < *offset {
+        *offset = 0;
+    }
+    if len == *offset {
+        return Ok(Vec::new());
+    }
+    if len - *offset > MAX_FOLLOW_BYTES {
+        *offset = len - MAX_FOLLOW_BYTES;
+    }
+
+    let mut buffer = Vec::new();
+    file.seek(SeekFrom::Start(*offset))?;
+    file.take(len - *offset).read_to_end(&mut buffer)?;
+
+    // Leave a partially written last line for the next tick
+    let Some(last_newline) = buffer.iter().rposition(|byte| *byte == b'\n') else {
+        return Ok(Vec::new());
+    };
+    *offset += last_newline as u64 + 1;
+
+    Ok(String::from_utf8_lossy(&buffer[..last_newline])
+        .lines()
+        .map(|line| line.to_string())
+        .collect())
+}
@@ -0,0 +1,203 @@
+use std::borrow::Cow;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use tracing::warn;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+use crate::{get_app_data_directory, redact};
+
+const LOG_DIRECTORY_NAME: &str = "logs";
+const LOG_FILE_STEM: &str = "imgvault";
+const LOG_ENV_VAR: &str = "IMGVAULT_LOG";
+const MAX_LOG_FILE_BYTES: u64 = 5 * 1024 * 1024;
+// Current file plus rotated imgvault.1.log .. imgvault.4.log
+const KEEP_LOG_FILES: usize = 5;
+
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+pub const LOG_LEVELS: &[&str] = &["error", "warn", "info", "debug", "trace"];
+
+// Set only when IMGVAULT_LOG is absent, so the environment always wins over settings
+static FILTER_HANDLE: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+// Native host processes share the log file; each tags its lines with its session
+static SESSION_ID: OnceLock<String> = OnceLock::new();
+// The file the subscriber writes to until close_file takes it away
+static LOG_FILE: Mutex<Option<RotatingFile>> = Mutex::new(None);
+
+// Short random id for this process's native messaging session. Every line
+// logged from here on starts with it.
+pub fn start_session() -> String {
+    let mut bytes = [0u8; 4];
+    let id = match getrandom::getrandom(&mut bytes) {
+        Ok(()) => bytes.iter().map(|byte| format!("{:02x}", byte)).collect(),
+        Err(_) => format!("{:08x}", std::process::id()),
+    };
+    SESSION_ID.get_or_init(|| id).clone()
+}
+
+pub fn session_id() -> Option<&'static str> {
+    SESSION_ID.get().map(String::as_str)
+}
+
+pub fn get_log_directory() -> Result<PathBuf, String> {
+    Ok(get_app_data_directory()?.join(LOG_DIRECTORY_NAME))
+}
+
+pub fn get_log_path() -> Result<PathBuf, String> {
+    Ok(get_log_directory()?.join(format!("{}.log", LOG_FILE_STEM)))
+}
+
+// Install the global subscriber. Output goes to the rotating log file and, in
+// debug builds, to stderr. Nothing may ever write to stdout: in native mode it
+// carries the length-prefixed messaging protocol.
+pub fn init() {
+    let env_filter = EnvFilter::try_from_env(LOG_ENV_VAR).ok();
+    let env_override = env_filter.is_some();
+    let (filter, handle) =
+        reload::Layer::new(env_filter.unwrap_or_else(|| EnvFilter::new(DEFAULT_LOG_LEVEL)));
+
+    let (file_layer, open_error) =
+        match get_log_path().and_then(|path| RotatingFile::open(path).map_err(|e| e.to_string())) {
+            Ok(file) => {
+                *LOG_FILE.lock().unwrap() = Some(file);
+                (Some(fmt::layer().with_ansi(false).with_writer(|| LogFileWriter)), None)
+            }
+            Err(error) => (None, Some(error)),
+        };
+    // Without a file, release builds log to stderr too rather than not at all
+    let stderr_layer =
+        (cfg!(debug_assertions) || open_error.is_some()).then(|| fmt::layer().with_writer(io::stderr));
+
+    let installed = tracing_subscriber::registry()
+        .with(filter)
+        .with(file_layer)
+        .with(stderr_layer)
+        .try_init()
+        .is_ok();
+
+    if installed && !env_override {
+        let _ = FILTER_HANDLE.set(handle);
+    }
+    if let Some(error) = open_error {
+        warn!("Failed to open log file, logging to stderr only: {}", error);
+    }
+}
+
+// Apply the level from settings; a no-op when IMGVAULT_LOG is set
+pub fn apply_level(level: &str) {
+    if let Some(handle) = FILTER_HANDLE.get() {
+        if let Err(error) = handle.reload(EnvFilter::new(level)) {
+            warn!("Failed to change log level to {}: {}", level, error);
+        }
+    }
+}
+
+// Flushes and closes the log file so its folder can be deleted, which Windows
+// refuses while it is open. Later lines are not written anywhere.
+pub fn close_file() {
+    if let Some(mut file) = LOG_FILE.lock().unwrap().take() {
+        let _ = file.flush();
+    }
+}
+
+// URL without its query string or fragment, which often carry tokens
+pub fn loggable_url(url: &str) -> &str {
+    url.split(['?', '#']).next().unwrap_or(url)
+}
+
+// Size-based rotation shared by the GUI and every native host process, so the
+// current log always lives at the same path users are asked to send.
+pub(crate) struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    written: u64,
+}
+
+impl RotatingFile {
+    pub(crate) fn open(path: PathBuf) -> io::Result<Self> {
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(RotatingFile { path, file, written })
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        let reopened = RotatingFile::open(self.path.clone())?;
+        *self = reopened;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        // Another process may have rotated already; then just follow the new file
+        let current_len = fs::metadata(&self.path).map(|metadata| metadata.len()).unwrap_or(0);
+        if current_len < MAX_LOG_FILE_BYTES {
+            return self.reopen();
+        }
+
+        for index in (1..KEEP_LOG_FILES - 1).rev() {
+            let from = rotated_log_path(&self.path, index);
+            if from.exists() {
+                fs::rename(&from, rotated_log_path(&self.path, index + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated_log_path(&self.path, 1))?;
+        self.reopen()
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Secrets and URL tokens never reach the file
+        let text = String::from_utf8_lossy(buf);
+        let scrubbed = match SESSION_ID.get() {
+            Some(session_id) => Cow::Owned(format!("[{}] {}", session_id, redact::redact(&text))),
+            None => redact::redact(&text),
+        };
+        let bytes = scrubbed.as_bytes();
+
+        if self.written > 0 && self.written + bytes.len() as u64 > MAX_LOG_FILE_BYTES {
+            // Logging from inside the subscriber would deadlock, so the
+            // failure goes into the file it failed to rotate
+            if let Err(error) = self.rotate() {
+                let _ = writeln!(self.file, "Failed to rotate log file: {}", error);
+                self.written = 0;
+            }
+        }
+
+        self.file.write_all(bytes)?;
+        self.written += bytes.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// What the subscriber writes each line through; nothing once the file is closed
+struct LogFileWriter;
+
+impl Write for LogFileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match LOG_FILE.lock().unwrap().as_mut() {
+            Some(file) => file.write(buf),
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match LOG_FILE.lock().unwrap().as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+fn rotated_log_path(path: &Path, index: usize) -> PathBuf {
+    path.with_file_name(format!("{}.{}.log", LOG_FILE_STEM, index))
+}
@@ -0,0 +1,194 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::settings::Settings;
+
+// Fits under Windows' MAX_PATH of 260 with room for yt-dlp's .part and
+// .f137 suffixes
+pub const DEFAULT_MAX_PATH_LENGTH: u32 = 240;
+pub const MIN_MAX_PATH_LENGTH: u32 = 80;
+// Extended-length paths go up to 32767 UTF-16 units
+pub const MAX_MAX_PATH_LENGTH: u32 = 32767;
+// yt-dlp keeps at least this much of a file name, even in a deep folder
+const MIN_FILE_NAME_LENGTH: usize = 32;
+
+// The path to hand to std::fs for a file in the vault. On Windows an absolute
+// path gets the \\?\ prefix, which lifts MAX_PATH for the calls we make
+// ourselves; elsewhere, and for relative paths, it is returned as it is.
+#[cfg(target_os = "windows")]
+pub fn extended(path: &Path) -> PathBuf {
+    let text = path.as_os_str().to_string_lossy();
+    if text.starts_with(r"\\?\") || !path.is_absolute() {
+        return path.to_path_buf();
+    }
+    // The prefix turns off separator normalization, so only backslashes work
+    let text = text.replace('/', r"\");
+    match text.strip_prefix(r"\\") {
+        Some(share) => PathBuf::from(format!(r"\\?\UNC\{}", share)),
+        None => PathBuf::from(format!(r"\\?\{}", text)),
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn extended(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+// The other way round: \\?\UNC\server\share becomes \\server\share and
+// \\?\C:\ becomes C:\, for paths shown to the user or handed to ffmpeg,
+// which does not understand the prefix
+#[cfg(target_os = "windows")]
+pub fn simplified(path: &Path) -> PathBuf {
+    let text = path.as_os_str().to_string_lossy();
+    if let Some(share) = text.strip_prefix(r"\\?\UNC\") {
+        return PathBuf::from(format!(r"\\{}", share));
+    }
+    match text.strip_prefix(r"\\?\") {
+        Some(local) if local.get(1..2) == Some(":") => PathBuf::from(local),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn simplified(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+// fs::canonicalize without the prefix it adds on Windows. A mapped drive
+// resolves to the share behind it, so a vault on Z:\ and one on
+// \\nas\archive compare equal when Z: is \\nas\archive.
+pub fn canonical(path: &Path) -> io::Result<PathBuf> {
+    fs::canonicalize(path).map(|canonical| simplified(&canonical))
+}
+
+// yt-dlp opens its files without the prefix, so on Windows the names it
+// expands from a template are trimmed to what is left of max_path_length
+// after the fixed folder part of `output_path`
+#[cfg(target_os = "windows")]
+pub fn add_yt_dlp_argument(command: &mut Command, settings: &Settings, output_path: &str) {
+    command
+        .arg("--trim-filenames")
+        .arg(file_name_budget(settings, output_path).to_string());
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn add_yt_dlp_argument(_command: &mut Command, _settings: &Settings, _output_path: &str) {}
+
+// Characters left for the template-expanded part of `output_path`. Folders
+// in the template share the budget with the file name, so a template such
+// as %(uploader)s/%(title)s.%(ext)s leaves the title less room.
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn file_name_budget(settings: &Settings, output_path: &str) -> usize {
+    let expanded_from = output_path.find("%(").unwrap_or(output_path.len());
+    let fixed = output_path[..expanded_from].rfind(['/', '\\']).map_or(0, |separator| separator + 1);
+    let template_folders = output_path[fixed..].matches(['/', '\\']).count();
+    (settings.max_path_length as usize)
+        .saturating_sub(output_path[..fixed].chars().count())
+        .saturating_sub(template_folders * MIN_FILE_NAME_LENGTH)
+        .max(MIN_FILE_NAME_LENGTH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drop_import;
+    use crate::history::History;
+    use crate::test_support;
+
+    // A folder whose path is well past MAX_PATH, with no segment longer
+    // than a file name may be
+    fn deep_folder(root: &Path) -> PathBuf {
+        let mut folder = root.to_path_buf();
+        let mut depth = 0;
+        while folder.as_os_str().len() < 320 {
+            folder.push(format!("{:02}-a-folder-name-of-forty-characters-long", depth));
+            depth += 1;
+        }
+        folder
+    }
+
+    #[test]
+    fn files_past_max_path_are_created_moved_and_imported() {
+        let app_data = test_support::app_data();
+        let vault = deep_folder(&app_data.directory.join("vault"));
+        fs::create_dir_all(extended(&vault)).expect("deep vault folder");
+
+        let created = vault.join("created.png");
+        fs::write(extended(&created), b"created").expect("file past MAX_PATH");
+        assert!(created.as_os_str().len() > 300, "{}", created.display());
+
+        let moved = vault.join("moved.png");
+        drop_import::move_file(&created, &moved).expect("move within the deep folder");
+        assert!(!extended(&created).exists());
+        assert_eq!(fs::read(extended(&moved)).expect("moved file"), b"created");
+
+        // A drop from a short path into a vault past MAX_PATH
+        let dropped = app_data.directory.join("dropped.png");
+        fs::write(&dropped, b"dropped").expect("dropped file");
+        let settings = Settings {
+            vault_root: Some(vault.display().to_string()),
+            ..Settings::default()
+        };
+        let imported = drop_import::import_file(&History::open_default(), &settings, &dropped, "drop").expect("import");
+        assert_eq!(imported, vault.join("dropped.png"));
+        assert_eq!(fs::read(extended(&imported)).expect("imported file"), b"dropped");
+
+        // And back out again
+        let back = app_data.directory.join("back.png");
+        drop_import::move_file(&imported, &back).expect("move out of the deep folder");
+        assert_eq!(fs::read(&back).expect("file moved back"), b"dropped");
+    }
+
+    #[test]
+    fn yt_dlp_names_get_what_the_folder_leaves() {
+        let settings = Settings {
+            max_path_length: 240,
+            ..Settings::default()
+        };
+        let folder = format!("C:\\{}\\", "v".repeat(99));
+        assert_eq!(file_name_budget(&settings, &format!("{}%(title)s.%(ext)s", folder)), 240 - 103);
+        // Each template folder keeps room for a name of its own
+        assert_eq!(
+            file_name_budget(&settings, &format!("{}%(uploader)s/%(title)s.%(ext)s", folder)),
+            240 - 103 - MIN_FILE_NAME_LENGTH
+        );
+        // A folder that is already too deep still leaves a short name
+        let too_deep = format!("C:\\{}\\%(title)s.%(ext)s", "v".repeat(300));
+        assert_eq!(file_name_budget(&settings, &too_deep), MIN_FILE_NAME_LENGTH);
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn the_prefix_goes_on_and_comes_off() {
+        let cases = [
+            (r"C:\Users\me\Videos\a.mp4", r"\\?\C:\Users\me\Videos\a.mp4"),
+            ("C:/Users/me/Videos/a.mp4", r"\\?\C:\Users\me\Videos\a.mp4"),
+            (r"\\nas\archive\a.mp4", r"\\?\UNC\nas\archive\a.mp4"),
+            (r"\\?\C:\already.mp4", r"\\?\C:\already.mp4"),
+            (r"relative\a.mp4", r"relative\a.mp4"),
+        ];
+        for (path, prefixed) in cases {
+            assert_eq!(extended(Path::new(path)), PathBuf::from(prefixed), "{}", path);
+        }
+        assert_eq!(simplified(Path::new(r"\\?\C:\Users\a.mp4")), PathBuf::from(r"C:\Users\a.mp4"));
+        assert_eq!(simplified(Path::new(r"\\?\UNC\nas\archive\a.mp4")), PathBuf::from(r"\\nas\archive\a.mp4"));
+        // A volume GUID path has no shorter form
+        let volume = r"\\?\Volume{0b1e5f3a-0000-0000-0000-100000000000}\a.mp4";
+        assert_eq!(simplified(Path::new(volume)), PathBuf::from(volume));
+    }
+
+    // What the user is shown for a file past MAX_PATH has no prefix
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn canonical_paths_past_max_path_lose_the_prefix() {
+        let app_data = test_support::app_data();
+        let vault = deep_folder(&app_data.directory.join("vault"));
+        fs::create_dir_all(extended(&vault)).expect("deep vault folder");
+        let file = vault.join("a.png");
+        fs::write(extended(&file), b"a").expect("write");
+        assert_eq!(canonical(&file).expect("canonical"), canonical(&extended(&file)).expect("canonical"));
+        assert!(!canonical(&file).expect("canonical").display().to_string().starts_with(r"\\?\"));
+    }
+}
@@ -0,0 +1,99 @@
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+use tracing::debug;
+
+use crate::diagnostics::hide_console_window;
+use crate::downloader::last_error_line;
+use crate::settings::Settings;
+use crate::{console_text, tool_integrity};
+
+// Starts with '[' so downloader::last_plain_line skips the line like yt-dlp's own
+const MEDIA_INFO_PREFIX: &str = "[imgvault:media_info] ";
+// yt-dlp builds the object itself, so `formats` and the rest of the info dict
+// never reach stdout or the progress frames
+const MEDIA_INFO_TEMPLATE: &str = "%(.{title,uploader,upload_date,duration,webpage_url,resolution,ext})j";
+
+// The fields of yt-dlp's info dict the extension shows on its cards, under
+// yt-dlp's names. Anything missing or null stays None.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MediaInfo {
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub uploader: Option<String>,
+    // YYYYMMDD
+    #[serde(default)]
+    pub upload_date: Option<String>,
+    // Seconds, fractional for some extractors
+    #[serde(default)]
+    pub duration: Option<f64>,
+    #[serde(default)]
+    pub webpage_url: Option<String>,
+    // e.g. 1920x1080, or "audio only"
+    #[serde(default)]
+    pub resolution: Option<String>,
+    #[serde(default)]
+    pub ext: Option<String>,
+}
+
+// Prints the trimmed info dict once the file is in place, after the file path
+pub fn add_print_argument(command: &mut Command) {
+    command
+        .arg("--print")
+        .arg(format!("after_move:{}{}", MEDIA_INFO_PREFIX, MEDIA_INFO_TEMPLATE));
+}
+
+// Asks yt-dlp for the info of `url` again without downloading, e.g. for
+// entries recorded before media info was kept
+pub fn probe(settings: &Settings, url: &str) -> Result<MediaInfo, String> {
+    tool_integrity::verify(settings.yt_dlp_program())?;
+    let mut command = Command::new(settings.yt_dlp_program());
+    command
+        .arg(url)
+        .arg("--simulate")
+        .arg("--no-playlist")
+        .arg("--no-warnings")
+        .arg("--print")
+        .arg(MEDIA_INFO_TEMPLATE);
+    console_text::force_utf8(&mut command);
+    hide_console_window(&mut command);
+
+    let output = command
+        .output()
+        .map_err(|e| format!("Failed to execute yt-dlp: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(last_error_line(&stderr)
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("yt-dlp returned exit code {:?}", output.status.code())));
+    }
+    let stdout = console_text::decode(&output.stdout);
+    let line = stdout.lines().rev().find(|line| !line.trim().is_empty()).unwrap_or_default();
+    serde_json::from_str(line.trim()).map_err(|e| format!("Failed to parse yt-dlp's media info: {}", e))
+}
+
+// None when yt-dlp printed nothing usable, e.g. an older version without the
+// template syntax; the download itself is unaffected
+pub fn parse(stdout_text: &str) -> Option<MediaInfo> {
+    let line = stdout_text
+        .lines()
+        .rev()
+        .find_map(|line| line.trim().strip_prefix(MEDIA_INFO_PREFIX.trim_end()))?;
+    match serde_json::from_str::<MediaInfo>(line.trim()) {
+        Ok(info) if info != MediaInfo::default() => Some(info),
+        Ok(_) => None,
+        Err(error) => {
+            debug!("Ignoring media info printed by yt-dlp: {}", error);
+            None
+        }
+    }
+}
+
+// Stored in history as JSON text; unreadable values are dropped
+pub fn from_column(value: Option<String>) -> Option<MediaInfo> {
+    value.and_then(|value| serde_json::from_str(&value).ok())
+}
+
+pub fn to_column(info: Option<&MediaInfo>) -> Option<String> {
+    info.and_then(|info| serde_json::to_string(info).ok())
+}
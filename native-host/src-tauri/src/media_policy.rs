@@ -0,0 +1,245 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+use tracing::{debug, warn};
+
+use crate::download_phase::AUDIO_EXTENSIONS;
+use crate::downloader::{Backend, IMAGE_EXTENSIONS};
+use crate::image_size;
+use crate::media_info::{self, MediaInfo};
+use crate::settings::Settings;
+
+// What every message starts with; classify_download_error keys on it
+pub const REJECTED_PREFIX: &str = "Policy rejected";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaType {
+    Image,
+    Video,
+    Audio,
+}
+
+impl MediaType {
+    fn name(self) -> &'static str {
+        match self {
+            MediaType::Image => "image",
+            MediaType::Video => "video",
+            MediaType::Audio => "audio",
+        }
+    }
+
+    // yt-dlp reports "audio only" for audio; otherwise the extension decides,
+    // and whatever is neither an image nor audio counts as video
+    fn of_file(path: &Path, info: Option<&MediaInfo>) -> MediaType {
+        if info.and_then(|info| info.resolution.as_deref()) == Some("audio only") {
+            return MediaType::Audio;
+        }
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+            .or_else(|| info.and_then(|info| info.ext.clone()))
+            .unwrap_or_default();
+        if IMAGE_EXTENSIONS.contains(&extension.as_str()) {
+            MediaType::Image
+        } else if AUDIO_EXTENSIONS.contains(&extension.as_str()) {
+            MediaType::Audio
+        } else {
+            MediaType::Video
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageDimensions {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl fmt::Display for ImageDimensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+// "800x600", as the image fetch child takes it on its command line
+impl FromStr for ImageDimensions {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (width, height) = value
+            .split_once('x')
+            .ok_or_else(|| format!("Invalid dimensions {}, expected WIDTHxHEIGHT", value))?;
+        let parse = |part: &str| {
+            part.trim()
+                .parse::<u32>()
+                .map_err(|_| format!("Invalid dimensions {}, expected WIDTHxHEIGHT", value))
+        };
+        Ok(ImageDimensions {
+            width: parse(width)?,
+            height: parse(height)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyRule {
+    MinImageDimensions,
+    MaxVideoHeight,
+    AllowedTypes,
+}
+
+// Why the policy refused a file, named after the setting it broke
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PolicyRejected {
+    pub rule: PolicyRule,
+    // The setting's value, e.g. "800x600", "1080p" or "image, video"
+    pub limit: String,
+    // What the download turned out to be, in the same terms
+    pub measured: String,
+}
+
+impl fmt::Display for PolicyRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.rule {
+            PolicyRule::MinImageDimensions => write!(
+                f,
+                "{}: image is {}, smaller than min_image_dimensions {}",
+                REJECTED_PREFIX, self.measured, self.limit
+            ),
+            PolicyRule::MaxVideoHeight => write!(
+                f,
+                "{}: video is {}, taller than max_video_height {}",
+                REJECTED_PREFIX, self.measured, self.limit
+            ),
+            PolicyRule::AllowedTypes => write!(
+                f,
+                "{}: {} is not in allowed_types {}",
+                REJECTED_PREFIX, self.measured, self.limit
+            ),
+        }
+    }
+}
+
+// The settings one request runs with: without the media policy when it sent
+// ignore_policy and the stored settings allow overrides
+pub fn for_request(mut settings: Settings, ignore_policy: bool) -> Settings {
+    if !ignore_policy {
+        return settings;
+    }
+    if !settings.allow_policy_override {
+        warn!("Ignoring ignore_policy; allow_policy_override is off");
+        return settings;
+    }
+    debug!("Media policy skipped for this request");
+    settings.min_image_dimensions = None;
+    settings.max_video_height = None;
+    settings.allowed_types.clear();
+    settings
+}
+
+fn type_allowed(settings: &Settings, media_type: MediaType) -> Result<(), PolicyRejected> {
+    if settings.allowed_types.is_empty() || settings.allowed_types.contains(&media_type) {
+        return Ok(());
+    }
+    Err(PolicyRejected {
+        rule: PolicyRule::AllowedTypes,
+        limit: settings
+            .allowed_types
+            .iter()
+            .map(|media_type| media_type.name())
+            .collect::<Vec<_>>()
+            .join(", "),
+        measured: media_type.name().to_string(),
+    })
+}
+
+// Before anything is spawned: the HTTP image backend only ever fetches images
+pub fn check_backend(settings: &Settings, backend: Backend) -> Result<(), PolicyRejected> {
+    match backend {
+        Backend::HttpImage => type_allowed(settings, MediaType::Image),
+        _ => Ok(()),
+    }
+}
+
+// Has yt-dlp pick a format no taller than the limit when the site offers
+// one; videos only available taller are refused after the download
+pub fn add_format_sort_argument(command: &mut Command, settings: &Settings) {
+    if let Some(max_height) = settings.max_video_height {
+        command.arg("-S").arg(format!("res:{}", max_height));
+    }
+}
+
+// The image fetch child checks the size itself, before moving the file into
+// place
+pub fn add_image_fetch_argument(command: &mut Command, settings: &Settings) {
+    if let Some(min) = settings.min_image_dimensions {
+        command.arg("--min-dimensions").arg(min.to_string());
+    }
+}
+
+// Images whose header cannot be read pass; so does everything else the
+// policy has no measure for
+pub fn check_image(path: &Path, min: ImageDimensions) -> Result<(), PolicyRejected> {
+    let Some((width, height)) = image_size::read(path) else {
+        debug!(path = %path.display(), "Image size unknown; not checking min_image_dimensions");
+        return Ok(());
+    };
+    if width >= min.width && height >= min.height {
+        return Ok(());
+    }
+    Err(PolicyRejected {
+        rule: PolicyRule::MinImageDimensions,
+        limit: min.to_string(),
+        measured: ImageDimensions { width, height }.to_string(),
+    })
+}
+
+// "1920x1080" -> 1080
+fn video_height(info: &MediaInfo) -> Option<u32> {
+    info.resolution.as_deref()?.split_once('x')?.1.parse().ok()
+}
+
+fn check_file(settings: &Settings, path: &Path, info: Option<&MediaInfo>) -> Result<(), PolicyRejected> {
+    let media_type = MediaType::of_file(path, info);
+    type_allowed(settings, media_type)?;
+    match media_type {
+        MediaType::Image => match settings.min_image_dimensions {
+            Some(min) => check_image(path, min),
+            None => Ok(()),
+        },
+        MediaType::Video => match (settings.max_video_height, info.and_then(video_height)) {
+            (Some(max_height), Some(height)) if height > max_height => Err(PolicyRejected {
+                rule: PolicyRule::MaxVideoHeight,
+                limit: format!("{}p", max_height),
+                measured: format!("{}p", height),
+            }),
+            _ => Ok(()),
+        },
+        MediaType::Audio => Ok(()),
+    }
+}
+
+// After a successful download: the finished file against the policy. A
+// rejected file is deleted so nothing that breaks the policy stays in the
+// vault.
+pub fn check_download(settings: &Settings, file_path: Option<&str>, stdout_text: &str) -> Result<(), PolicyRejected> {
+    let Some(file_path) = file_path else {
+        return Ok(());
+    };
+    let path = Path::new(file_path);
+    let info = media_info::parse(stdout_text);
+    let result = check_file(settings, path, info.as_ref());
+    if let Err(rejected) = &result {
+        warn!(path = %path.display(), "{}", rejected);
+        if let Err(error) = fs::remove_file(path) {
+            warn!(path = %path.display(), "Failed to delete rejected file: {}", error);
+        }
+    }
+    result
+}
@@ -0,0 +1,239 @@
+use serde_json::{json, Value};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::jobs::JobRegistry;
+use crate::{current_timestamp_millis, dispatcher, i18n};
+
+// Upper bounds of the histogram buckets; a last, unbounded one catches the rest
+const DOWNLOAD_DURATION_BOUNDS_MS: &[u64] = &[1_000, 5_000, 15_000, 60_000, 300_000, 900_000, 3_600_000];
+const SERIALIZATION_BOUNDS_US: &[u64] = &[50, 100, 500, 1_000, 5_000, 25_000, 100_000];
+const MAX_BUCKETS: usize = 8;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+// Counters of this process since it started or was last reset. The GUI and
+// every native messaging port are separate processes, each with its own.
+static METRICS: Metrics = Metrics {
+    reset_at: AtomicI64::new(0),
+    messages_received: ZERO,
+    downloads_started: ZERO,
+    downloads_succeeded: ZERO,
+    downloads_cancelled: ZERO,
+    downloads_failed: [ZERO; i18n::ERROR_CODE_COUNT],
+    download_duration: Histogram::new(DOWNLOAD_DURATION_BOUNDS_MS, 1_000),
+    serialization: Histogram::new(SERIALIZATION_BOUNDS_US, 1_000_000),
+};
+
+struct Metrics {
+    // Zero until the first reset
+    reset_at: AtomicI64,
+    messages_received: AtomicU64,
+    downloads_started: AtomicU64,
+    downloads_succeeded: AtomicU64,
+    downloads_cancelled: AtomicU64,
+    // One per error code, in the order of i18n::error_codes
+    downloads_failed: [AtomicU64; i18n::ERROR_CODE_COUNT],
+    download_duration: Histogram,
+    serialization: Histogram,
+}
+
+// Fixed buckets, so observing a value is a few atomic additions
+struct Histogram {
+    bounds: &'static [u64],
+    // Observed values per second, for the Prometheus text
+    units_per_second: u64,
+    buckets: [AtomicU64; MAX_BUCKETS],
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    const fn new(bounds: &'static [u64], units_per_second: u64) -> Self {
+        Histogram {
+            bounds,
+            units_per_second,
+            buckets: [ZERO; MAX_BUCKETS],
+            count: ZERO,
+            sum: ZERO,
+        }
+    }
+
+    fn observe(&self, value: u64) {
+        let bucket = self.bounds.iter().position(|bound| value <= *bound).unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.count.store(0, Ordering::Relaxed);
+        self.sum.store(0, Ordering::Relaxed);
+    }
+
+    // Cumulative counts per upper bound, the unbounded bucket last as None
+    fn cumulative(&self) -> Vec<(Option<u64>, u64)> {
+        let mut total = 0;
+        (0..=self.bounds.len())
+            .map(|index| {
+                total += self.buckets[index].load(Ordering::Relaxed);
+                (self.bounds.get(index).copied(), total)
+            })
+            .collect()
+    }
+
+    fn to_json(&self) -> Value {
+        let buckets = self
+            .cumulative()
+            .into_iter()
+            .map(|(bound, count)| json!({ "le": bound, "count": count }))
+            .collect::<Vec<_>>();
+        json!({
+            "count": self.count.load(Ordering::Relaxed),
+            "sum": self.sum.load(Ordering::Relaxed),
+            "buckets": buckets,
+        })
+    }
+
+    fn write_prometheus(&self, out: &mut String, name: &str, help: &str) {
+        let seconds = |value: u64| value as f64 / self.units_per_second as f64;
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+        for (bound, count) in self.cumulative() {
+            let le = bound.map_or_else(|| "+Inf".to_string(), |bound| seconds(bound).to_string());
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, count);
+        }
+        let _ = writeln!(out, "{}_sum {}", name, seconds(self.sum.load(Ordering::Relaxed)));
+        let _ = writeln!(out, "{}_count {}", name, self.count.load(Ordering::Relaxed));
+    }
+}
+
+pub fn message_received() {
+    METRICS.messages_received.fetch_add(1, Ordering::Relaxed);
+}
+
+// Once a download has its slot and its backend is about to run
+pub fn download_started() {
+    METRICS.downloads_started.fetch_add(1, Ordering::Relaxed);
+}
+
+// `error_code` is None for a completed download and "cancelled" for one the
+// user stopped; anything else counts as a failure under that code
+pub fn download_finished(error_code: Option<&str>, duration_ms: i64) {
+    match error_code {
+        None => &METRICS.downloads_succeeded,
+        Some("cancelled") => &METRICS.downloads_cancelled,
+        Some(code) => {
+            // Codes outside the catalog count as a plain download failure
+            let index = i18n::error_codes()
+                .position(|known| known == code)
+                .or_else(|| i18n::error_codes().position(|known| known == "download_failed"))
+                .unwrap_or_default();
+            &METRICS.downloads_failed[index]
+        }
+    }
+    .fetch_add(1, Ordering::Relaxed);
+    METRICS.download_duration.observe(duration_ms.max(0) as u64);
+}
+
+pub fn response_serialized(elapsed: Duration) {
+    METRICS.serialization.observe(elapsed.as_micros() as u64);
+}
+
+// Zeroes every counter and histogram; the gauges are read live
+pub fn reset() {
+    let metrics = &METRICS;
+    for counter in [
+        &metrics.messages_received,
+        &metrics.downloads_started,
+        &metrics.downloads_succeeded,
+        &metrics.downloads_cancelled,
+    ]
+    .into_iter()
+    .chain(&metrics.downloads_failed)
+    {
+        counter.store(0, Ordering::Relaxed);
+    }
+    metrics.download_duration.reset();
+    metrics.serialization.reset();
+    metrics.reset_at.store(current_timestamp_millis(), Ordering::Relaxed);
+}
+
+// Failures per error code, leaving out the codes that never happened
+fn failures() -> Vec<(&'static str, u64)> {
+    i18n::error_codes()
+        .zip(&METRICS.downloads_failed)
+        .map(|(code, counter)| (code, counter.load(Ordering::Relaxed)))
+        .filter(|(_, count)| *count > 0)
+        .collect()
+}
+
+// Jobs running in this process and jobs held back by a limit across all of them
+fn gauges(jobs: &JobRegistry) -> (usize, usize) {
+    let (active, _) = jobs.activity();
+    (active, dispatcher::waiting().map(|waiting| waiting.len()).unwrap_or_default())
+}
+
+// Everything above as JSON, for get_metrics and the diagnostics report
+pub fn snapshot(jobs: &JobRegistry) -> Value {
+    let metrics = &METRICS;
+    let (active_jobs, queue_depth) = gauges(jobs);
+    let reset_at = metrics.reset_at.load(Ordering::Relaxed);
+    json!({
+        "generatedAt": current_timestamp_millis(),
+        // null when counting since the process started
+        "resetAt": (reset_at > 0).then_some(reset_at),
+        "messagesReceived": metrics.messages_received.load(Ordering::Relaxed),
+        "downloadsStarted": metrics.downloads_started.load(Ordering::Relaxed),
+        "downloadsSucceeded": metrics.downloads_succeeded.load(Ordering::Relaxed),
+        "downloadsCancelled": metrics.downloads_cancelled.load(Ordering::Relaxed),
+        "downloadsFailed": failures()
+            .into_iter()
+            .map(|(code, count)| (code.to_string(), Value::from(count)))
+            .collect::<serde_json::Map<_, _>>(),
+        "activeJobs": active_jobs,
+        "queueDepth": queue_depth,
+        "downloadDurationMs": metrics.download_duration.to_json(),
+        "responseSerializationUs": metrics.serialization.to_json(),
+    })
+}
+
+// The same numbers in the Prometheus text format, for GET /metrics
+pub fn prometheus(jobs: &JobRegistry) -> String {
+    let metrics = &METRICS;
+    let (active_jobs, queue_depth) = gauges(jobs);
+    let mut out = String::new();
+    for (name, help, counter) in [
+        ("imgvault_messages_received_total", "Native messages received.", &metrics.messages_received),
+        ("imgvault_downloads_started_total", "Downloads started.", &metrics.downloads_started),
+        ("imgvault_downloads_succeeded_total", "Downloads completed.", &metrics.downloads_succeeded),
+        ("imgvault_downloads_cancelled_total", "Downloads cancelled by the user.", &metrics.downloads_cancelled),
+    ] {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+        let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+    }
+    let _ = writeln!(
+        out,
+        "# HELP imgvault_downloads_failed_total Downloads failed, by error code.\n# TYPE imgvault_downloads_failed_total counter"
+    );
+    for (code, count) in failures() {
+        let _ = writeln!(out, "imgvault_downloads_failed_total{{error_code=\"{}\"}} {}", code, count);
+    }
+    for (name, help, value) in [
+        ("imgvault_active_jobs", "Downloads running in this process.", active_jobs),
+        ("imgvault_queue_depth", "Downloads waiting on a limit.", queue_depth),
+    ] {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value);
+    }
+    metrics
+        .download_duration
+        .write_prometheus(&mut out, "imgvault_download_duration_seconds", "Time from start to outcome of a download.");
+    metrics
+        .serialization
+        .write_prometheus(&mut out, "imgvault_response_serialization_seconds", "Time to serialize a native response.");
+    out
+}
@@ -0,0 +1,290 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::file_lock;
+use crate::history::History;
+use crate::registration::native_manifest_path;
+use crate::settings::{self, Settings};
+use crate::{current_timestamp_millis, get_app_data_directory, timestamps};
+
+const STATE_FILE_NAME: &str = "migration.json";
+const LOCK_FILE_NAME: &str = "migration.lock";
+// Everything the migration changes is copied here first
+#[cfg(target_os = "windows")]
+const BACKUP_DIRECTORY_NAME: &str = "migration-backup";
+// Raised when a step is added; a recorded version never runs its steps again
+const MIGRATION_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepOutcome {
+    Migrated,
+    // Nothing of the old layout was found
+    Skipped,
+    // Tried again on the next start
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrationStep {
+    pub name: String,
+    pub outcome: StepOutcome,
+    pub detail: String,
+}
+
+// What the last migration did, kept for diagnostics
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrationState {
+    pub version: u32,
+    // RFC 3339, of the last run
+    pub ran_at: Option<String>,
+    pub steps: Vec<MigrationStep>,
+}
+
+fn state_path() -> Result<PathBuf, String> {
+    Ok(get_app_data_directory()?.join(STATE_FILE_NAME))
+}
+
+// Ok(None) before the first run
+pub fn read_state() -> Result<Option<MigrationState>, String> {
+    let path = state_path()?;
+    match fs::read_to_string(&path) {
+        Ok(text) => serde_json::from_str(&text)
+            .map(Some)
+            .map_err(|e| format!("Failed to parse {}: {}", path.display(), e)),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(format!("Failed to read {}: {}", path.display(), error)),
+    }
+}
+
+fn write_state(state: &MigrationState) -> Result<(), String> {
+    let path = state_path()?;
+    let text = serde_json::to_string_pretty(state).map_err(|e| format!("Failed to serialize migration state: {}", e))?;
+    fs::write(&path, text).map_err(|e| format!("Failed to save {}: {}", path.display(), e))
+}
+
+fn is_current() -> bool {
+    read_state().is_ok_and(|state| state.is_some_and(|state| state.version >= MIGRATION_VERSION))
+}
+
+// Brings the layout of older builds up to date, once. Runs at every start
+// before settings are loaded; after a successful run it only reads the state
+// file. Every step is safe to repeat, so a failed one is simply tried again.
+pub fn run() {
+    if is_current() {
+        return;
+    }
+    // The GUI and a native host may start together after an upgrade
+    let _lock = match file_lock::acquire(LOCK_FILE_NAME, "migration") {
+        Ok(lock) => lock,
+        Err(error) => {
+            warn!("Skipping the migration for now: {}", error);
+            return;
+        }
+    };
+    if is_current() {
+        return;
+    }
+
+    let steps = vec![relocate_manifest(), synthesize_settings()];
+    let failed = steps.iter().any(|step| step.outcome == StepOutcome::Failed);
+    for step in &steps {
+        match step.outcome {
+            StepOutcome::Failed => warn!(step = %step.name, "Migration step failed: {}", step.detail),
+            _ => info!(step = %step.name, outcome = ?step.outcome, "{}", step.detail),
+        }
+    }
+    let previous = read_state().ok().flatten().map_or(0, |state| state.version);
+    let state = MigrationState {
+        version: if failed { previous } else { MIGRATION_VERSION },
+        ran_at: Some(timestamps::format_rfc3339(current_timestamp_millis())),
+        steps,
+    };
+    if let Err(error) = write_state(&state) {
+        warn!("{}", error);
+    }
+}
+
+fn step(name: &str, result: Result<Option<String>, String>) -> MigrationStep {
+    let (outcome, detail) = match result {
+        Ok(Some(detail)) => (StepOutcome::Migrated, detail),
+        Ok(None) => (StepOutcome::Skipped, "Nothing to migrate".to_string()),
+        Err(error) => (StepOutcome::Failed, error),
+    };
+    MigrationStep {
+        name: name.to_string(),
+        outcome,
+        detail,
+    }
+}
+
+// Copies `contents` into the backup folder, next to earlier copies of the same name
+#[cfg(target_os = "windows")]
+fn back_up(name: &str, contents: &[u8]) -> Result<PathBuf, String> {
+    let directory = get_app_data_directory()?.join(BACKUP_DIRECTORY_NAME);
+    fs::create_dir_all(&directory).map_err(|e| format!("Failed to create {}: {}", directory.display(), e))?;
+    let path = (0..)
+        .map(|copy| match copy {
+            0 => directory.join(name),
+            copy => directory.join(format!("{}.{}", name, copy)),
+        })
+        .find(|path| !path.exists())
+        .expect("an unused backup name always exists");
+    fs::write(&path, contents).map_err(|e| format!("Failed to back up {}: {}", name, e))?;
+    Ok(path)
+}
+
+// Older builds wrote manifest.json beside the exe, where an upgrade or a
+// read-only install folder gets in the way. Each browser's registry key is
+// pointed at the manifest in app data, with the old origins kept.
+#[cfg(target_os = "windows")]
+fn relocate_manifest() -> MigrationStep {
+    step("manifest", relocate_windows_manifest())
+}
+
+#[cfg(target_os = "windows")]
+fn relocate_windows_manifest() -> Result<Option<String>, String> {
+    use winreg::enums::{HKEY_CURRENT_USER, KEY_READ, KEY_WRITE};
+    use winreg::RegKey;
+
+    let target = native_manifest_path()?;
+    let hkcu = RegKey::predef(HKEY_CURRENT_USER);
+    let mut moved = Vec::new();
+    let mut old_manifests = Vec::new();
+    for (browser, parent) in crate::registration::BROWSER_REGISTRY_PATHS {
+        let Ok(key) = hkcu.open_subkey_with_flags(format!(r"{}\{}", parent, crate::NATIVE_HOST_NAME), KEY_READ | KEY_WRITE)
+        else {
+            continue;
+        };
+        let Ok(old_path) = key.get_value::<String, _>("") else {
+            continue;
+        };
+        let old_path = PathBuf::from(old_path);
+        if old_path == target {
+            continue;
+        }
+        let contents = fs::read(&old_path)
+            .map_err(|e| format!("Failed to read the {} manifest {}: {}", browser, old_path.display(), e))?;
+        let old_manifest = serde_json::from_slice::<Value>(&contents)
+            .map_err(|e| format!("The {} manifest {} is not valid JSON: {}", browser, old_path.display(), e))?;
+
+        back_up(&format!("{}-manifest.json", browser), &contents)?;
+        back_up(&format!("{}-registry.txt", browser), old_path.display().to_string().as_bytes())?;
+        write_merged_manifest(&target, &old_manifest)?;
+        key.set_value("", &target.display().to_string())
+            .map_err(|e| format!("Failed to point {} at {}: {}", browser, target.display(), e))?;
+        moved.push(*browser);
+        old_manifests.push(old_path);
+    }
+    if moved.is_empty() {
+        return Ok(None);
+    }
+
+    // Backed up above; one a browser still uses would have been moved too
+    for old_path in old_manifests {
+        if let Err(error) = fs::remove_file(&old_path) {
+            warn!("Left the old manifest {} in place: {}", old_path.display(), error);
+        }
+    }
+    Ok(Some(format!("Moved the manifest for {} to {}", moved.join(", "), target.display())))
+}
+
+// The old manifest with this exe's path, and the origins of any manifest
+// already at the target, so browsers moved one after another all stay allowed
+#[cfg(target_os = "windows")]
+fn write_merged_manifest(target: &Path, old_manifest: &Value) -> Result<(), String> {
+    let mut manifest = old_manifest.clone();
+    let exe_path = std::env::current_exe().map_err(|e| format!("Failed to get executable path: {}", e))?;
+    manifest["path"] = Value::from(exe_path.display().to_string());
+
+    let mut origins = allowed_origins(old_manifest);
+    if let Some(existing) = fs::read(target).ok().and_then(|contents| serde_json::from_slice::<Value>(&contents).ok()) {
+        for origin in allowed_origins(&existing) {
+            if !origins.contains(&origin) {
+                origins.push(origin);
+            }
+        }
+    }
+    manifest["allowed_origins"] = Value::from(origins);
+
+    if let Some(directory) = target.parent() {
+        fs::create_dir_all(directory).map_err(|e| format!("Failed to create {}: {}", directory.display(), e))?;
+    }
+    let text = serde_json::to_string_pretty(&manifest).map_err(|e| format!("Failed to serialize manifest: {}", e))?;
+    fs::write(target, text).map_err(|e| format!("Failed to write {}: {}", target.display(), e))
+}
+
+#[cfg(not(target_os = "windows"))]
+fn relocate_manifest() -> MigrationStep {
+    step("manifest", Ok(None))
+}
+
+fn allowed_origins(manifest: &Value) -> Vec<String> {
+    manifest["allowed_origins"]
+        .as_array()
+        .map(|origins| origins.iter().filter_map(|origin| origin.as_str().map(str::to_string)).collect())
+        .unwrap_or_default()
+}
+
+// Older builds had no settings file; the extension sent the folder with every
+// download. A first settings file is written from what can still be found.
+fn synthesize_settings() -> MigrationStep {
+    step("settings", synthesize_settings_file())
+}
+
+fn synthesize_settings_file() -> Result<Option<String>, String> {
+    let path = settings::get_settings_path()?;
+    if path.exists() {
+        return Ok(None);
+    }
+
+    let mut settings = Settings::default();
+    let mut sources = Vec::new();
+
+    // The HTTP API takes origins without the manifest's trailing slash
+    let manifest = fs::read(native_manifest_path()?)
+        .ok()
+        .and_then(|contents| serde_json::from_slice::<Value>(&contents).ok());
+    for origin in manifest.as_ref().map(allowed_origins).unwrap_or_default() {
+        let origin = origin.trim_end_matches('/').to_string();
+        if !settings.http_api_allowed_origins.contains(&origin) {
+            settings.http_api_allowed_origins.push(origin);
+            sources.push("the manifest's allowed origins");
+        }
+    }
+
+    let output_directory = History::open_default()
+        .latest_output_path()?
+        .map(|output_path| fixed_prefix(Path::new(&output_path)))
+        .filter(|directory| directory.is_absolute() && directory.is_dir());
+    if let Some(directory) = output_directory {
+        settings.vault_root = Some(directory.display().to_string());
+        sources.push("the last download folder");
+    }
+
+    settings::save_settings(&settings)?;
+    sources.dedup();
+    Ok(Some(if sources.is_empty() {
+        format!("Created {} with the defaults", path.display())
+    } else {
+        format!("Created {} from {}", path.display(), sources.join(" and "))
+    }))
+}
+
+// The folder part of an output path before any template field, e.g. D:\Videos
+// for D:\Videos\%(uploader)s\%(title)s.%(ext)s
+fn fixed_prefix(output_path: &Path) -> PathBuf {
+    let parent = output_path.parent().unwrap_or(output_path);
+    parent
+        .components()
+        .take_while(|component| match component {
+            Component::Normal(part) => !part.to_string_lossy().contains("%("),
+            _ => true,
+        })
+        .collect()
+}
@@ -0,0 +1,815 @@
+use std::env;
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use tokio::time;
+use tracing::{debug, error, info, warn};
+
+use crate::capture::Capture;
+use crate::commands::{check_cookies, reload_path};
+use crate::downloader::{find_yt_dlp, handle_download, NativePort};
+use crate::events::Subscription;
+use crate::extension_origin::ExtensionOrigin;
+use crate::history::History;
+use crate::jobs::JobRegistry;
+use crate::native_stdout::FrameWriter;
+use crate::profiles::Profile;
+use crate::protocol::{
+    internal_error_response, invalid_message_response, read_native_messages, send_job_frame, send_native_response,
+    strict_schema_response, INCOMING_FRAMES, IncomingFrame, NativeMessage, NativeResponse, PortEnd,
+    WRITER_DRAIN_TIMEOUT,
+};
+use crate::queue::{
+    cancel_job, generate_job_id, pause_queue_jobs, queue_snapshot, reorder_queued_job, resume_queue_jobs,
+    set_queued_job_priority,
+};
+use crate::settings::{Settings, SettingsStore};
+use crate::source_page::SourcePage;
+use crate::{
+    browser_fallback, channel_archive, current_timestamp_millis, diagnostics, dispatcher, events, frame_limit,
+    get_vault_directory, i18n, logging, metrics, native_proxy, native_stdout, output_template, schedule, shutdown, updates,
+    url_diagnosis, vault_events,
+};
+#[cfg(target_os = "windows")]
+use crate::reload_windows_path_environment;
+
+// Exit codes of a native messaging session; Chrome only logs them
+const EXIT_SUCCESS: i32 = 0;
+const EXIT_PROTOCOL_ERROR: i32 = 3;
+const EXIT_RUNTIME_ERROR: i32 = 4;
+// Downloads run on blocking threads; the workers only move bytes and timers
+const NATIVE_WORKER_THREADS: usize = 2;
+
+// Handle native messaging (stdin/stdout communication)
+pub fn handle_native_messaging(args: &[String]) -> i32 {
+    #[cfg(target_os = "windows")]
+    {
+        if let Err(e) = reload_windows_path_environment() {
+            warn!("Failed to reload PATH on native startup: {}", e);
+        } else {
+            debug!("Reloaded PATH on native startup");
+        }
+    }
+
+    let runtime = match tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(NATIVE_WORKER_THREADS)
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(error) => {
+            error!("Failed to start the native messaging runtime: {}", error);
+            return EXIT_RUNTIME_ERROR;
+        }
+    };
+    let exit_code = runtime.block_on(run_native_session(args.to_vec()));
+    // stdin's blocking read outlives a session that ended on a write error
+    runtime.shutdown_background();
+    exit_code
+}
+
+async fn run_native_session(args: Vec<String>) -> i32 {
+    let session_id = logging::start_session();
+    let settings_store = SettingsStore::load();
+    // Before stdout is claimed, so the capture has every frame
+    let capture = Capture::start(&args, &settings_store.get(), &session_id);
+    let (stdout, writer) = native_stdout::claim(capture.clone());
+    let origin = ExtensionOrigin::from_args(&args);
+    let started_at = current_timestamp_millis();
+    info!(origin = origin.loggable(), pid = std::process::id(), started_at, "Native messaging session started");
+    let jobs = JobRegistry::new();
+    let history = History::open_default();
+    if let Err(error) = history.start_session(&session_id, origin.loggable(), started_at) {
+        warn!("{}", error);
+    }
+
+    // Messages are read by their own task so Chrome closing the port is
+    // noticed while a download runs in the message loop
+    let (message_tx, mut message_rx) = tokio::sync::mpsc::channel::<IncomingFrame>(INCOMING_FRAMES);
+    let reader = {
+        let jobs = jobs.clone();
+        let history = history.clone();
+        let settings_store = settings_store.clone();
+        let stdout = stdout.clone();
+        tokio::spawn(async move {
+            let end = read_native_messages(&mut tokio::io::stdin(), &message_tx, capture.as_ref()).await;
+            match &end {
+                PortEnd::Closed => info!("Native messaging port closed"),
+                PortEnd::Truncated(error) => {
+                    error!("Native messaging protocol error: {}", error);
+                    // Chrome may still be listening; tell it why the host is leaving
+                    let response = NativeResponse {
+                        success: false,
+                        message: Some(format!("Native messaging protocol error: {}", error)),
+                        error_code: Some("protocol_error".to_string()),
+                        ..NativeResponse::job_event("error", None)
+                    }
+                    .localized(&i18n::resolve(None));
+                    if let Ok(frames) = frame_limit::frames(&response) {
+                        for frame in frames {
+                            let _ = stdout.send_frame(frame.as_bytes()).await;
+                        }
+                    }
+                }
+            }
+            // Waits out the grace period of the running jobs
+            let _ = tokio::task::spawn_blocking(move || {
+                settings_store.reload_if_changed();
+                shutdown::shut_down(&jobs, &history, &settings_store.get(), |frame| {
+                    // The port is usually gone already; the event is best effort
+                    let _ = send_native_response(&stdout, frame);
+                });
+            })
+            .await;
+            end
+        })
+    };
+
+    // With the GUI running, its job engine answers the messages and this
+    // process only relays frames, so there is one queue and one history writer.
+    // Should the GUI exit, the rest of the port is served here.
+    let mut handed_back = Vec::new();
+    let mut relayed = false;
+    if let Some(connection) = native_proxy::attach(&args).await {
+        info!("Relaying the native messaging port to the running GUI");
+        match native_proxy::relay(connection, &mut message_rx, &stdout).await {
+            native_proxy::RelayEnd::PortClosed => relayed = true,
+            native_proxy::RelayEnd::GuiGone(frames) => {
+                warn!("The GUI stopped serving the native messaging port; serving it standalone");
+                handed_back = frames;
+            }
+        }
+    }
+
+    // Downloads block the loop for their whole run, so it has a thread of its own
+    if relayed {
+        // Nothing more to write; lets the writer below finish
+        drop(stdout);
+    } else {
+        let message_loop = {
+            let jobs = jobs.clone();
+            let history = history.clone();
+            tokio::task::spawn_blocking(move || {
+                serve_native_messages(handed_back, message_rx, stdout, origin, jobs, history, settings_store)
+            })
+        };
+        if let Err(error) = message_loop.await {
+            error!("Native message loop failed: {}", error);
+        }
+    }
+
+    // Let the shutdown sequence finish checkpointing; without it the port is
+    // still open and only the response could not be sent
+    let port_end = if jobs.is_shutting_down() {
+        reader.await.unwrap_or(PortEnd::Closed)
+    } else {
+        PortEnd::Closed
+    };
+    // Frames still queued are written before the session ends
+    if time::timeout(WRITER_DRAIN_TIMEOUT, writer).await.is_err() {
+        warn!("Gave up writing the last responses to the native messaging port");
+    }
+
+    let ended_at = current_timestamp_millis();
+    if let Err(error) = history.end_session(&session_id, ended_at) {
+        warn!("{}", error);
+    }
+    info!(ended_at, duration_ms = ended_at - started_at, "Native messaging session ended");
+
+    match port_end {
+        PortEnd::Closed => EXIT_SUCCESS,
+        PortEnd::Truncated(_) => EXIT_PROTOCOL_ERROR,
+    }
+}
+
+// Answers messages in the order they arrive until the port closes or a
+// response cannot be sent
+pub(crate) fn serve_native_messages(
+    handed_back: Vec<IncomingFrame>,
+    mut message_rx: tokio::sync::mpsc::Receiver<IncomingFrame>,
+    stdout: FrameWriter,
+    origin: ExtensionOrigin,
+    jobs: JobRegistry,
+    history: History,
+    settings_store: SettingsStore,
+) {
+    let mut pending_notifications = Vec::new();
+    let port = NativePort { stdout: &stdout, jobs: &jobs, origin: &origin };
+    // Histories of the non-default profiles messages have named so far
+    let mut profile_histories = HashMap::new();
+    // Job events of the whole engine, once the port subscribes. Kept for the
+    // session so its sequence numbers carry on across unsubscribe.
+    let mut subscription: Option<Arc<Subscription>> = None;
+    // Set by a ping with `strict`; a message's own `strict` wins over it
+    let mut strict_session = false;
+
+    let mut handed_back = handed_back.into_iter();
+    while let Some(frame) = handed_back.next().or_else(|| message_rx.blocking_recv()) {
+        // Messages that arrived just before the port closed are dropped
+        if jobs.is_shutting_down() {
+            break;
+        }
+
+        metrics::message_received();
+        let msg = match frame {
+            IncomingFrame::Message(msg) => msg,
+            IncomingFrame::Invalid(error) => {
+                warn!("{}", error);
+                if let Err(error) = send_native_response(&stdout, &invalid_message_response(error)) {
+                    error!("{}", error);
+                    break;
+                }
+                continue;
+            }
+        };
+
+        // The raw message may carry cookies, so only its size is logged here
+        debug!("Received {} byte message", msg.len());
+
+        // Chrome keeps this process alive for hours, so pick up settings saved by
+        // the GUI since the last message. Each job runs on this snapshot.
+        settings_store.reload_if_changed();
+
+        if let Some(response) = strict_schema_response(&msg, strict_session) {
+            if let Err(error) = send_native_response(&stdout, &response) {
+                error!("{}", error);
+                break;
+            }
+            continue;
+        }
+        
+        // Set once the message parses; errors are localized after handling,
+        // so a panic's internal error is too
+        let mut requested_locale = None;
+        // Parse the message. A panic while servicing one request is reported
+        // back as an internal error and the loop carries on with the next one.
+        let handled = panic::catch_unwind(AssertUnwindSafe(|| match serde_json::from_str::<NativeMessage>(&msg) {
+            Ok(native_msg) => {
+                requested_locale = native_msg.locale.clone();
+                let locale = i18n::resolve(native_msg.locale.as_deref());
+                info!(
+                    action = %native_msg.action,
+                    request_id = native_msg.request_id.as_deref().unwrap_or(""),
+                    origin = origin.loggable(),
+                    "Handling native message"
+                );
+                // An unknown extension can still tell the host is installed, nothing more
+                if let Some(error) = origin.refusal().filter(|_| native_msg.action != "ping") {
+                    return NativeResponse::failure(native_msg.request_id.as_deref(), Some("origin_not_allowed"), error);
+                }
+                let (settings, history) =
+                    match resolve_native_profile(native_msg.profile.as_deref(), &settings_store, &history, &mut profile_histories) {
+                        Ok(resolved) => resolved,
+                        Err(error) => {
+                            warn!(request_id = native_msg.request_id.as_deref().unwrap_or(""), "{}", error);
+                            return NativeResponse::failure(native_msg.request_id.as_deref(), Some("invalid_profile"), error);
+                        }
+                    };
+                match native_msg.action.as_str() {
+                    "download" => handle_download(native_msg, settings, &history, &port, &locale, &mut pending_notifications),
+                    "reload_path" => {
+                        match reload_path() {
+                            Ok(_) => NativeResponse {
+                                success: true,
+                                event: Some("complete".to_string()),
+                                request_id: native_msg.request_id.clone(),
+                                message: Some(i18n::text(&locale, "path_reloaded", &[])),
+                                line: None,
+                                stream: None,
+                                file_path: None,
+                                stdout: None,
+                                stderr: None,
+                                error_code: None,
+                                data: None,
+                                detail: None,
+                            },
+                            Err(e) => NativeResponse {
+                                success: false,
+                                event: Some("complete".to_string()),
+                                request_id: native_msg.request_id.clone(),
+                                message: Some(e),
+                                line: None,
+                                stream: None,
+                                file_path: None,
+                                stdout: None,
+                                stderr: None,
+                                error_code: None,
+                                data: None,
+                                detail: None,
+                            },
+                        }
+                    }
+                    "diagnostics" => NativeResponse {
+                        success: true,
+                        event: Some("complete".to_string()),
+                        request_id: native_msg.request_id.clone(),
+                        message: Some(i18n::text(&locale, "diagnostics_complete", &[])),
+                        line: None,
+                        stream: None,
+                        file_path: None,
+                        stdout: None,
+                        stderr: None,
+                        error_code: None,
+                        data: Some(diagnostics::run_diagnostics(&settings, &history, Some(&jobs))),
+                        detail: None,
+                    },
+                    "diagnose_url" => match native_msg.url.as_deref() {
+                        Some(url) => NativeResponse {
+                            success: true,
+                            message: Some(i18n::text(&locale, "url_diagnosed", &[])),
+                            data: Some(url_diagnosis::diagnose(&settings, url, native_msg.referer.as_deref())),
+                            ..NativeResponse::job_event("complete", native_msg.request_id.as_deref())
+                        },
+                        None => NativeResponse {
+                            success: false,
+                            message: Some("Missing url".to_string()),
+                            error_code: Some("missing_url".to_string()),
+                            ..NativeResponse::job_event("complete", native_msg.request_id.as_deref())
+                        },
+                    },
+                    "get_events" => match vault_events::since(native_msg.since_seq.unwrap_or(0), native_msg.limit) {
+                        Ok(events) => NativeResponse {
+                            success: true,
+                            data: serde_json::to_value(events).ok(),
+                            ..NativeResponse::job_event("complete", native_msg.request_id.as_deref())
+                        },
+                        Err(error) => NativeResponse {
+                            success: false,
+                            message: Some(error),
+                            error_code: Some("internal_error".to_string()),
+                            ..NativeResponse::job_event("complete", native_msg.request_id.as_deref())
+                        },
+                    },
+                    "register_external_file" => {
+                        let NativeMessage {
+                            url,
+                            file_path,
+                            request_id,
+                            page_url,
+                            page_title,
+                            referer,
+                            ..
+                        } = native_msg;
+                        match (url, file_path) {
+                            (Some(url), Some(file_path)) => {
+                                if let Err(error) = browser_fallback::check_file(&file_path) {
+                                    warn!(request_id = request_id.as_deref().unwrap_or(""), "Not registering the browser's download: {}", error);
+                                    return NativeResponse {
+                                        success: false,
+                                        message: Some(error),
+                                        error_code: Some("invalid_file_path".to_string()),
+                                        ..NativeResponse::job_event("complete", request_id.as_deref())
+                                    };
+                                }
+                                let job_id = request_id.clone().unwrap_or_else(|| generate_job_id(browser_fallback::BROWSER_SOURCE));
+                                let source_page = SourcePage::new(page_url, page_title, referer);
+                                match browser_fallback::register(&history, &job_id, &url, &file_path, &source_page) {
+                                    Ok(entry_id) => {
+                                        info!(job_id = %job_id, entry_id, "Registered a file the browser downloaded");
+                                        NativeResponse {
+                                            success: true,
+                                            file_path: Some(file_path),
+                                            data: Some(serde_json::json!({ "entryId": entry_id })),
+                                            ..NativeResponse::job_event("complete", request_id.as_deref())
+                                        }
+                                    }
+                                    Err(error) => NativeResponse {
+                                        success: false,
+                                        message: Some(error),
+                                        error_code: Some("internal_error".to_string()),
+                                        ..NativeResponse::job_event("complete", request_id.as_deref())
+                                    },
+                                }
+                            }
+                            (None, _) => NativeResponse {
+                                success: false,
+                                message: Some("Missing url".to_string()),
+                                error_code: Some("missing_url".to_string()),
+                                ..NativeResponse::job_event("complete", request_id.as_deref())
+                            },
+                            (_, None) => NativeResponse {
+                                success: false,
+                                message: Some("Missing file_path".to_string()),
+                                error_code: Some("missing_file_path".to_string()),
+                                ..NativeResponse::job_event("complete", request_id.as_deref())
+                            },
+                        }
+                    }
+                    "get_metrics" | "reset_metrics" => {
+                        if native_msg.action == "reset_metrics" {
+                            metrics::reset();
+                        }
+                        NativeResponse {
+                            data: Some(metrics::snapshot(&jobs)),
+                            ..NativeResponse::job_event("complete", native_msg.request_id.as_deref())
+                        }
+                    }
+                    "reload_settings" => {
+                        let changed = settings_store.reload();
+                        NativeResponse {
+                            success: true,
+                            event: Some("complete".to_string()),
+                            request_id: native_msg.request_id.clone(),
+                            message: Some(if changed.is_empty() {
+                                i18n::text(&locale, "settings_unchanged", &[])
+                            } else {
+                                i18n::text(&locale, "settings_changed", &[("fields", &changed.join(", "))])
+                            }),
+                            line: None,
+                            stream: None,
+                            file_path: None,
+                            stdout: None,
+                            stderr: None,
+                            error_code: None,
+                            data: None,
+                            detail: None,
+                        }
+                    }
+                    "ping" => {
+                        // The handshake: strict checking for the rest of the session
+                        if let Some(strict) = native_msg.strict {
+                            strict_session = strict;
+                        }
+                        NativeResponse {
+                            success: true,
+                            event: Some("complete".to_string()),
+                            request_id: native_msg.request_id.clone(),
+                            message: Some(i18n::text(&locale, "host_reachable", &[])),
+                            line: None,
+                            stream: None,
+                            file_path: None,
+                            stdout: None,
+                            stderr: None,
+                            error_code: None,
+                            // null until the first update check has completed
+                            data: Some(serde_json::json!({
+                                "version": env!("CARGO_PKG_VERSION"),
+                                "updateAvailable": updates::cached_update_available(&settings),
+                                "queuePaused": jobs.is_paused(),
+                                "strict": strict_session,
+                            })),
+                            detail: None,
+                        }
+                    }
+                    "check_yt_dlp" => {
+                        match find_yt_dlp(&settings) {
+                            Ok(message) => NativeResponse {
+                                success: true,
+                                event: Some("complete".to_string()),
+                                request_id: native_msg.request_id.clone(),
+                                message: Some(message),
+                                line: None,
+                                stream: None,
+                                file_path: None,
+                                stdout: None,
+                                stderr: None,
+                                error_code: None,
+                                data: None,
+                                detail: None,
+                            },
+                            Err(e) => NativeResponse {
+                                success: false,
+                                event: Some("complete".to_string()),
+                                request_id: native_msg.request_id.clone(),
+                                message: Some(e),
+                                line: None,
+                                stream: None,
+                                file_path: None,
+                                stdout: None,
+                                stderr: None,
+                                error_code: None,
+                                data: None,
+                                detail: None,
+                            },
+                        }
+                    }
+                    "check_cookies" => {
+                        match check_cookies() {
+                            Ok(message) => NativeResponse {
+                                success: true,
+                                event: Some("complete".to_string()),
+                                request_id: native_msg.request_id.clone(),
+                                message: Some(message),
+                                line: None,
+                                stream: None,
+                                file_path: None,
+                                stdout: None,
+                                stderr: None,
+                                error_code: None,
+                                data: None,
+                                detail: None,
+                            },
+                            Err(e) => NativeResponse {
+                                success: false,
+                                event: Some("complete".to_string()),
+                                request_id: native_msg.request_id.clone(),
+                                message: Some(e),
+                                line: None,
+                                stream: None,
+                                file_path: None,
+                                stdout: None,
+                                stderr: None,
+                                error_code: None,
+                                data: None,
+                                detail: None,
+                            },
+                        }
+                    }
+                    "cancel_download" => {
+                        match native_msg.request_id.as_deref() {
+                            Some(request_id) => match cancel_job(&jobs, request_id) {
+                                Ok(message) => NativeResponse {
+                                    success: true,
+                                    event: Some("complete".to_string()),
+                                    request_id: Some(request_id.to_string()),
+                                    message: Some(message),
+                                    line: None,
+                                    stream: None,
+                                    file_path: None,
+                                    stdout: None,
+                                    stderr: None,
+                                    error_code: None,
+                                    data: None,
+                                    detail: None,
+                                },
+                                Err(e) => NativeResponse {
+                                    success: false,
+                                    event: Some("complete".to_string()),
+                                    request_id: Some(request_id.to_string()),
+                                    message: Some(e),
+                                    line: None,
+                                    stream: None,
+                                    file_path: None,
+                                    stdout: None,
+                                    stderr: None,
+                                    error_code: None,
+                                    data: None,
+                                    detail: None,
+                                },
+                            },
+                            None => NativeResponse {
+                                success: false,
+                                event: Some("complete".to_string()),
+                                request_id: None,
+                                message: Some("Missing request_id for cancel_download".to_string()),
+                                line: None,
+                                stream: None,
+                                file_path: None,
+                                stdout: None,
+                                stderr: None,
+                                error_code: None,
+                                data: None,
+                                detail: None,
+                            },
+                        }
+                    }
+                    "pause_queue" => NativeResponse {
+                        message: Some(pause_queue_jobs(&jobs)),
+                        ..NativeResponse::job_event("complete", native_msg.request_id.as_deref())
+                    },
+                    "resume_queue" => match resume_queue_jobs(&jobs) {
+                        Ok(message) => {
+                            // Paused jobs restart in the GUI, which may need starting
+                            schedule::notify_gui();
+                            NativeResponse {
+                                message: Some(message),
+                                ..NativeResponse::job_event("complete", native_msg.request_id.as_deref())
+                            }
+                        }
+                        Err(error) => NativeResponse {
+                            success: false,
+                            message: Some(error),
+                            ..NativeResponse::job_event("complete", native_msg.request_id.as_deref())
+                        },
+                    },
+                    "reschedule" | "run_now" => {
+                        let result = match (native_msg.request_id.as_deref(), native_msg.schedule_at.as_deref()) {
+                            (None, _) => Err(format!("Missing request_id for {}", native_msg.action)),
+                            (Some(job_id), _) if native_msg.action == "run_now" => schedule::run_now(job_id),
+                            (Some(job_id), Some(schedule_at)) => schedule::reschedule(job_id, schedule_at),
+                            (Some(_), None) => Err("Missing schedule_at for reschedule".to_string()),
+                        };
+                        match result {
+                            Ok(download) => {
+                                schedule::notify_gui();
+                                NativeResponse {
+                                    event: Some("complete".to_string()),
+                                    ..NativeResponse::scheduled(&download, &locale)
+                                }
+                            }
+                            Err(error) => NativeResponse {
+                                success: false,
+                                message: Some(error),
+                                ..NativeResponse::job_event("complete", native_msg.request_id.as_deref())
+                            },
+                        }
+                    }
+                    "reorder" | "set_priority" => {
+                        let result = match (native_msg.request_id.as_deref(), native_msg.action.as_str()) {
+                            (None, _) => Err(format!("Missing request_id for {}", native_msg.action)),
+                            (Some(job_id), "reorder") => match native_msg.new_index {
+                                Some(new_index) => reorder_queued_job(&jobs, job_id, new_index),
+                                None => Err("Missing new_index for reorder".to_string()),
+                            },
+                            (Some(job_id), _) => match native_msg.priority {
+                                Some(priority) => set_queued_job_priority(&jobs, job_id, priority),
+                                None => Err("Missing priority for set_priority".to_string()),
+                            },
+                        };
+                        NativeResponse {
+                            success: result.is_ok(),
+                            message: Some(result.map_or_else(|error| error, |()| i18n::text(&locale, "queue_updated", &[]))),
+                            data: dispatcher::waiting().ok().and_then(|waiting| serde_json::to_value(waiting).ok()),
+                            ..NativeResponse::job_event("complete", native_msg.request_id.as_deref())
+                        }
+                    }
+                    // Every job this process runs, whoever started it; a port
+                    // handed to the GUI sees the GUI's whole engine
+                    "subscribe" => {
+                        let filter = native_msg.filter.clone().unwrap_or_default();
+                        match &subscription {
+                            Some(existing) => existing.set_filter(Some(filter)),
+                            None => {
+                                let subscribed = jobs.events().subscribe_filtered(Some(filter));
+                                events::forward_to_port(&subscribed, stdout.clone());
+                                subscription = Some(subscribed);
+                            }
+                        }
+                        NativeResponse {
+                            message: Some(i18n::text(&locale, "subscribed", &[])),
+                            data: Some(queue_snapshot(&jobs, &settings)),
+                            ..NativeResponse::job_event("complete", native_msg.request_id.as_deref())
+                        }
+                    }
+                    "unsubscribe" => {
+                        if let Some(subscription) = &subscription {
+                            subscription.set_filter(None);
+                        }
+                        NativeResponse {
+                            message: Some(i18n::text(&locale, "unsubscribed", &[])),
+                            ..NativeResponse::job_event("complete", native_msg.request_id.as_deref())
+                        }
+                    }
+                    // Queues the new items of a channel or user as one batch; each
+                    // job is announced with its own scheduled event first
+                    "archive_channel" => {
+                        let output_path = output_template::resolve(
+                            native_msg.output_path.clone(),
+                            native_msg.output_template.as_deref(),
+                            &settings,
+                        );
+                        let result = match (native_msg.url.as_deref(), output_path) {
+                            (None, _) => Err("Missing url for archive_channel".to_string()),
+                            (_, Err(error)) => Err(error),
+                            (Some(url), Ok(output_path)) => channel_archive::archive(
+                                &history,
+                                &settings,
+                                &channel_archive::ChannelRequest {
+                                    url,
+                                    output_path,
+                                    max_items: native_msg.max_items,
+                                    date_after: native_msg.date_after.as_deref(),
+                                    source: origin.history_source(),
+                                    priority: native_msg.priority.unwrap_or_default(),
+                                },
+                            ),
+                        };
+                        match result {
+                            Ok(archive) => {
+                                for download in &archive.queued {
+                                    let _ = send_job_frame(&stdout, &jobs, &NativeResponse::scheduled(download, &locale));
+                                }
+                                if !archive.queued.is_empty() {
+                                    schedule::notify_gui();
+                                }
+                                NativeResponse {
+                                    message: Some(format!(
+                                        "Queued {} new of {} items",
+                                        archive.queued.len(),
+                                        archive.found
+                                    )),
+                                    data: serde_json::to_value(&archive).ok(),
+                                    ..NativeResponse::job_event("complete", native_msg.request_id.as_deref())
+                                }
+                            }
+                            Err(error) => {
+                                warn!("Failed to archive channel: {}", error);
+                                NativeResponse {
+                                    success: false,
+                                    message: Some(error),
+                                    ..NativeResponse::job_event("complete", native_msg.request_id.as_deref())
+                                }
+                            }
+                        }
+                    }
+                    "get_default_video_directory" => {
+                        match get_vault_directory(&settings) {
+                            Ok(path) => NativeResponse {
+                                success: true,
+                                event: Some("complete".to_string()),
+                                request_id: native_msg.request_id.clone(),
+                                message: Some(path.display().to_string()),
+                                line: None,
+                                stream: None,
+                                file_path: Some(path.display().to_string()),
+                                stdout: None,
+                                stderr: None,
+                                error_code: None,
+                                data: None,
+                                detail: None,
+                            },
+                            Err(e) => NativeResponse {
+                                success: false,
+                                event: Some("complete".to_string()),
+                                request_id: native_msg.request_id.clone(),
+                                message: Some(e),
+                                line: None,
+                                stream: None,
+                                file_path: None,
+                                stdout: None,
+                                stderr: None,
+                                error_code: None,
+                                data: None,
+                                detail: None,
+                            },
+                        }
+                    }
+                    _ => {
+                        warn!(action = %native_msg.action, "Unknown action");
+                        NativeResponse {
+                            success: false,
+                            event: Some("complete".to_string()),
+                            request_id: native_msg.request_id.clone(),
+                            message: Some("Unknown action".to_string()),
+                            line: None,
+                            stream: None,
+                            file_path: None,
+                            stdout: None,
+                            stderr: None,
+                            error_code: Some("unknown_action".to_string()),
+                            data: None,
+                            detail: None,
+                        }
+                    },
+                }
+            }
+            Err(e) => {
+                warn!("Failed to parse message: {}", e);
+                NativeResponse {
+                    success: false,
+                    event: Some("complete".to_string()),
+                    request_id: None,
+                    message: Some(format!("Failed to parse message: {}", e)),
+                    line: None,
+                    stream: None,
+                    file_path: None,
+                    stdout: None,
+                    stderr: None,
+                    error_code: Some("invalid_message".to_string()),
+                    data: None,
+                    detail: None,
+                }
+            }
+        }));
+        let response = handled
+            .unwrap_or_else(|payload| internal_error_response(&msg, payload.as_ref()))
+            .localized(&i18n::resolve(requested_locale.as_deref()));
+        
+        debug!(
+            event = response.event.as_deref().unwrap_or(""),
+            success = response.success,
+            "Sending response"
+        );
+
+        if let Err(error) = send_native_response(&stdout, &response) {
+            error!("{}", error);
+            break;
+        }
+    }
+
+    // Chrome closes the port right after the last response; stay alive long
+    // enough to deliver any notification still in its coalescing window
+    for handle in pending_notifications {
+        let _ = handle.join();
+    }
+}
+
+// The settings and history a native message works on. Chrome keeps the port
+// open for hours, so histories of other profiles stay open in `histories`.
+fn resolve_native_profile(
+    name: Option<&str>,
+    settings_store: &SettingsStore,
+    default_history: &History,
+    histories: &mut HashMap<String, History>,
+) -> Result<(Settings, History), String> {
+    let profile = Profile::load(name)?;
+    let settings = profile.apply(settings_store.base());
+    if profile.is_default() {
+        return Ok((settings, default_history.clone()));
+    }
+    let history = histories
+        .entry(profile.name.clone())
+        .or_insert_with(|| profile.open_history())
+        .clone();
+    Ok((settings, history))
+}
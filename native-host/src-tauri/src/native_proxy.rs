@@ -0,0 +1,197 @@
+use std::collections::HashSet;
+use std::net::TcpStream as StdTcpStream;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, Receiver};
+use tokio::time;
+use tracing::{debug, error, info, warn};
+
+use crate::extension_origin::ExtensionOrigin;
+use crate::history::History;
+use crate::jobs::JobRegistry;
+use crate::native::serve_native_messages;
+use crate::native_stdout::{self, FrameWriter};
+use crate::protocol::{
+    invalid_message_response, read_native_messages, read_until_eof, IncomingFrame, INCOMING_FRAMES, WRITER_DRAIN_TIMEOUT,
+};
+use crate::settings::SettingsStore;
+use crate::{frame_limit, instance};
+
+// Events the GUI sends about a message before its answer
+const INTERIM_EVENTS: &[&str] = &["progress", "queued", "waiting", "stalled", "warning"];
+
+// How a relayed port ended
+pub enum RelayEnd {
+    // Chrome closed the port; downloads it started carry on in the GUI
+    PortClosed,
+    // The GUI exited or dropped the connection. The messages it took but
+    // never answered, e.g. a download it was running, and the one it could not
+    // be handed are served again by the standalone loop, in the order sent.
+    GuiGone(Vec<IncomingFrame>),
+}
+
+// The running GUI's connection for this port, or None to work standalone
+pub async fn attach(args: &[String]) -> Option<TcpStream> {
+    let args = args.to_vec();
+    let attached = tokio::task::spawn_blocking(move || instance::attach_native(&args)).await.ok()?;
+    let stream = match attached {
+        Ok(stream) => stream,
+        Err(error) => {
+            debug!("Serving the native messaging port standalone: {}", error);
+            return None;
+        }
+    };
+    match stream.set_nonblocking(true).and_then(|()| TcpStream::from_std(stream)) {
+        Ok(stream) => Some(stream),
+        Err(error) => {
+            warn!("Failed to use the GUI's connection, serving the port standalone: {}", error);
+            None
+        }
+    }
+}
+
+// Passes Chrome's messages to the GUI and its responses back, frame by frame.
+// Messages are taken from the session's reader, so the standalone loop can
+// carry on from the next one should the GUI go away.
+pub async fn relay(connection: TcpStream, messages: &mut Receiver<IncomingFrame>, stdout: &FrameWriter) -> RelayEnd {
+    let (mut from_gui, mut to_gui) = connection.into_split();
+    // Request ids the GUI has sent the answer to
+    let answered = Arc::new(Mutex::new(HashSet::new()));
+    let mut responses = {
+        let stdout = stdout.clone();
+        let answered = answered.clone();
+        tokio::spawn(async move {
+            loop {
+                let mut length = [0u8; 4];
+                if !matches!(read_until_eof(&mut from_gui, &mut length).await, Ok(4)) {
+                    return;
+                }
+                let mut frame = vec![0u8; u32::from_ne_bytes(length) as usize];
+                match read_until_eof(&mut from_gui, &mut frame).await {
+                    Ok(read) if read == frame.len() => {}
+                    _ => return,
+                }
+                if let Some(request_id) = answer_to(&frame) {
+                    answered.lock().unwrap().insert(request_id);
+                }
+                // Chrome has gone; its messages stop arriving too
+                if stdout.send_frame(&frame).await.is_err() {
+                    return;
+                }
+            }
+        })
+    };
+    // Messages the GUI took, with their request ids, until it answers them
+    let mut unanswered: Vec<(Option<String>, String)> = Vec::new();
+
+    loop {
+        tokio::select! {
+            frame = messages.recv() => match frame {
+                None => {
+                    // The GUI sees the port close and lets go once its jobs for it finish
+                    let _ = to_gui.shutdown().await;
+                    responses.abort();
+                    return RelayEnd::PortClosed;
+                }
+                Some(IncomingFrame::Message(message)) => {
+                    if let Err(error) = write_frame(&mut to_gui, message.as_bytes()).await {
+                        warn!("Failed to pass a message to the GUI: {}", error);
+                        // Whatever the GUI answered before it went still reaches Chrome
+                        let _ = time::timeout(WRITER_DRAIN_TIMEOUT, &mut responses).await;
+                        responses.abort();
+                        let mut handed_back = still_unanswered(unanswered, &answered);
+                        handed_back.push(IncomingFrame::Message(message));
+                        return RelayEnd::GuiGone(handed_back);
+                    }
+                    // The answer may already be in, so the message is listed first
+                    unanswered.push((request_id_of(&message), message));
+                    let answered = std::mem::take(&mut *answered.lock().unwrap());
+                    unanswered.retain(|(request_id, _)| !request_id.as_ref().is_some_and(|id| answered.contains(id)));
+                }
+                // Nothing worth passing on; answered here as the loop would
+                Some(IncomingFrame::Invalid(error)) => {
+                    warn!("{}", error);
+                    if let Ok(frames) = frame_limit::frames(&invalid_message_response(error)) {
+                        for frame in frames {
+                            let _ = stdout.send_frame(frame.as_bytes()).await;
+                        }
+                    }
+                }
+            },
+            _ = &mut responses => return RelayEnd::GuiGone(still_unanswered(unanswered, &answered)),
+        }
+    }
+}
+
+// Messages without a request id cannot be told apart from their answers; a
+// response to them could be any frame, so they are not served twice
+fn still_unanswered(unanswered: Vec<(Option<String>, String)>, answered: &Mutex<HashSet<String>>) -> Vec<IncomingFrame> {
+    let answered = answered.lock().unwrap();
+    unanswered
+        .into_iter()
+        .filter_map(|(request_id, message)| match request_id {
+            Some(request_id) if answered.contains(&request_id) => None,
+            Some(request_id) => {
+                info!(request_id = %request_id, "Serving a message again that the GUI never answered");
+                Some(IncomingFrame::Message(message))
+            }
+            None => None,
+        })
+        .collect()
+}
+
+fn request_id_of(message: &str) -> Option<String> {
+    let message = serde_json::from_str::<serde_json::Value>(message).ok()?;
+    message.get("request_id")?.as_str().map(str::to_string)
+}
+
+// The request id `frame` is the last answer to; split answers say `more`
+// on all but their last frame
+fn answer_to(frame: &[u8]) -> Option<String> {
+    let frame = serde_json::from_slice::<serde_json::Value>(frame).ok()?;
+    let event = frame.get("event").and_then(serde_json::Value::as_str);
+    if event.is_some_and(|event| INTERIM_EVENTS.contains(&event)) || frame.get("more") == Some(&serde_json::Value::Bool(true)) {
+        return None;
+    }
+    frame.get("requestId")?.as_str().map(str::to_string)
+}
+
+async fn write_frame(out: &mut (impl AsyncWrite + Unpin), frame: &[u8]) -> std::io::Result<()> {
+    out.write_all(&(frame.len() as u32).to_ne_bytes()).await?;
+    out.write_all(frame).await?;
+    out.flush().await
+}
+
+// In the GUI: serves the port of a host process that attached, with the GUI's
+// own job engine, history and settings, the way that process would have.
+// Chrome closing the port does not stop its downloads; they finish here.
+pub fn host(stream: StdTcpStream, args: Vec<String>, jobs: JobRegistry, history: History, settings: SettingsStore) {
+    tauri::async_runtime::spawn(async move {
+        let stream = match stream.set_nonblocking(true).and_then(|()| TcpStream::from_std(stream)) {
+            Ok(stream) => stream,
+            Err(error) => {
+                warn!("Failed to serve an attached native messaging port: {}", error);
+                return;
+            }
+        };
+        let (mut input, output) = stream.into_split();
+        let (stdout, writer) = native_stdout::over(Box::new(output));
+        let origin = ExtensionOrigin::from_args(&args);
+        info!(origin = origin.loggable(), "Serving a native messaging port handed over by a host process");
+
+        let (message_tx, message_rx) = mpsc::channel::<IncomingFrame>(INCOMING_FRAMES);
+        let reader = tokio::spawn(async move { read_native_messages(&mut input, &message_tx, None).await });
+        let message_loop = tokio::task::spawn_blocking(move || {
+            serve_native_messages(Vec::new(), message_rx, stdout, origin, jobs, history, settings)
+        });
+        if let Err(error) = message_loop.await {
+            error!("Attached native message loop failed: {}", error);
+        }
+        reader.abort();
+        if time::timeout(WRITER_DRAIN_TIMEOUT, writer).await.is_err() {
+            warn!("Gave up writing the last responses to an attached native messaging port");
+        }
+        info!("Attached native messaging port closed");
+    });
+}
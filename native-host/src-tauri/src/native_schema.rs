@@ -0,0 +1,253 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::fmt;
+
+use crate::protocol::NativeMessage;
+
+// Every action serve_native_messages handles
+pub const ACTIONS: &[&str] = &[
+    "download",
+    "reload_path",
+    "diagnostics",
+    "diagnose_url",
+    "get_events",
+    "register_external_file",
+    "get_metrics",
+    "reset_metrics",
+    "reload_settings",
+    "ping",
+    "check_yt_dlp",
+    "check_cookies",
+    "cancel_download",
+    "pause_queue",
+    "resume_queue",
+    "reschedule",
+    "run_now",
+    "reorder",
+    "set_priority",
+    "subscribe",
+    "unsubscribe",
+    "archive_channel",
+    "get_default_video_directory",
+];
+
+// The keys each object of a message may have, by path; `[]` stands for any
+// item of an array. Serde ignores the rest, and with the flattened media
+// hints it cannot be told to refuse them, hence this list.
+const OBJECT_KEYS: &[(&str, &[&str])] = &[
+    (
+        "",
+        &[
+            "action",
+            "url",
+            "output_path",
+            "output_template",
+            "cookies_data",
+            "request_id",
+            "upload",
+            "schedule_at",
+            "priority",
+            "new_index",
+            "page_url",
+            "page_title",
+            "referer",
+            "profile",
+            "backend",
+            "ignore_policy",
+            "tags",
+            "mime_type",
+            "mimeType",
+            "dimensions",
+            "uploader",
+            "force_new",
+            "locale",
+            "filter",
+            "split_chapters",
+            "tag_origin",
+            "max_items",
+            "date_after",
+            "user_agent",
+            "browser_user_agent",
+            "since_seq",
+            "limit",
+            "file_path",
+            "strict",
+        ],
+    ),
+    (
+        "cookies_data[]",
+        &["domain", "host_only", "path", "secure", "expiration_date", "name", "value"],
+    ),
+    ("filter", &["job_ids", "domains"]),
+    ("dimensions", &["width", "height"]),
+];
+
+// One way a message departs from the schema, e.g.
+// `max_items: expected integer, got string`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SchemaError {
+    pub path: String,
+    pub error: String,
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.error)
+    }
+}
+
+// Whether `raw` is checked strictly: its own `strict` when it has one, else
+// what the session's handshake asked for
+pub fn is_strict(raw: &Value, session: bool) -> bool {
+    raw.get("strict").and_then(Value::as_bool).unwrap_or(session)
+}
+
+// Every problem with `raw`: the action, then wrong types, then unknown keys;
+// empty when it matches. Types are left to serde, one field at a time so
+// that each gets its own error instead of only the first.
+pub fn validate(raw: &Value) -> Vec<SchemaError> {
+    let Value::Object(fields) = raw else {
+        return vec![SchemaError {
+            path: "message".to_string(),
+            error: format!("expected object, got {}", kind(raw)),
+        }];
+    };
+    let mut errors = Vec::new();
+    match fields.get("action") {
+        None => errors.push(SchemaError {
+            path: "action".to_string(),
+            error: "missing field".to_string(),
+        }),
+        Some(Value::String(action)) if !ACTIONS.contains(&action.as_str()) => errors.push(SchemaError {
+            path: "action".to_string(),
+            error: format!("unknown action \"{}\"", action),
+        }),
+        _ => {}
+    }
+
+    for (key, value) in fields {
+        if !known_key(key) {
+            continue;
+        }
+        match value {
+            // Items one by one, so the error names the one that is wrong
+            Value::Array(items) if key == "cookies_data" => {
+                for (index, item) in items.iter().enumerate() {
+                    check_field(key, &Value::Array(vec![item.clone()]), &format!("{}[{}]", key, index), &mut errors);
+                }
+            }
+            _ => check_field(key, value, key, &mut errors),
+        }
+    }
+    collect_unknown_keys(raw, "", &mut errors);
+    errors
+}
+
+// Deserializes a message with nothing but `key` set to `value`
+fn check_field(key: &str, value: &Value, path: &str, errors: &mut Vec<SchemaError>) {
+    let mut isolated = Map::new();
+    if key != "action" {
+        isolated.insert("action".to_string(), Value::String("ping".to_string()));
+    }
+    isolated.insert(key.to_string(), value.clone());
+    if let Err(error) = serde_json::from_value::<NativeMessage>(Value::Object(isolated)) {
+        errors.push(SchemaError {
+            path: path.to_string(),
+            error: describe(&error.to_string()),
+        });
+    }
+}
+
+fn collect_unknown_keys(value: &Value, path: &str, errors: &mut Vec<SchemaError>) {
+    match value {
+        Value::Object(fields) => {
+            let schema = OBJECT_KEYS.iter().find(|(pattern, _)| *pattern == pattern_of(path));
+            for (key, value) in fields {
+                let child = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+                match schema {
+                    Some((_, keys)) if !keys.contains(&key.as_str()) => errors.push(SchemaError {
+                        path: child,
+                        error: "unknown field".to_string(),
+                    }),
+                    _ => collect_unknown_keys(value, &child, errors),
+                }
+            }
+        }
+        Value::Array(items) => {
+            for (index, item) in items.iter().enumerate() {
+                collect_unknown_keys(item, &format!("{}[{}]", path, index), errors);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn known_key(key: &str) -> bool {
+    OBJECT_KEYS
+        .iter()
+        .any(|(pattern, keys)| pattern.is_empty() && keys.contains(&key))
+}
+
+// cookies_data[3] becomes cookies_data[]
+fn pattern_of(path: &str) -> String {
+    let mut pattern = String::with_capacity(path.len());
+    let mut in_index = false;
+    for c in path.chars() {
+        match c {
+            '[' => {
+                in_index = true;
+                pattern.push(c);
+            }
+            ']' => {
+                in_index = false;
+                pattern.push(c);
+            }
+            _ if in_index => {}
+            _ => pattern.push(c),
+        }
+    }
+    pattern
+}
+
+// serde's "invalid type: string \"720\", expected u32" as "expected integer,
+// got string". The value is left out, since it may be a cookie.
+fn describe(error: &str) -> String {
+    let Some((got, expected)) = error
+        .strip_prefix("invalid type: ")
+        .and_then(|rest| rest.rsplit_once(", expected "))
+    else {
+        return error.to_string();
+    };
+    let got = match got.split_whitespace().next().unwrap_or_default() {
+        "integer" => "integer",
+        "floating" => "number",
+        "boolean" => "boolean",
+        "string" | "character" => "string",
+        "sequence" => "array",
+        "map" => "object",
+        "null" | "unit" => "null",
+        other => other,
+    };
+    let expected = match expected.trim() {
+        "u8" | "u16" | "u32" | "u64" | "usize" | "i8" | "i16" | "i32" | "i64" | "isize" => "integer",
+        "f32" | "f64" => "number",
+        "a string" => "string",
+        "a boolean" => "boolean",
+        "a sequence" => "array",
+        other if other.starts_with("struct ") || other == "a map" => "object",
+        other => other,
+    };
+    format!("expected {}, got {}", expected, got)
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(number) if number.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
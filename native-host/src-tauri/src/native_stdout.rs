@@ -0,0 +1,167 @@
+use std::fs::File;
+use std::io::{self, PipeReader, PipeWriter, Read, Write};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{error, warn};
+
+use crate::capture::Capture;
+
+// Frames waiting for the writer; a port Chrome stops reading holds the
+// senders back instead of piling up output in memory
+const OUTGOING_FRAMES: usize = 64;
+
+// In native mode stdout carries the length-prefixed messaging protocol, so one
+// stray println! from us or a dependency would corrupt it. claim() keeps the
+// real stdout for protocol frames only and points the process's stdout at a
+// pipe. In debug builds nothing reads that pipe and a stray write panics with a
+// broken pipe; in release builds the bytes are logged and dropped.
+//
+// Frames are handed to one writer task over a bounded channel.
+#[derive(Clone)]
+pub struct FrameWriter {
+    frames: mpsc::Sender<Vec<u8>>,
+}
+
+impl FrameWriter {
+    // From blocking code, e.g. a download's job loop; waits while the writer
+    // is behind. Fails once the writer has stopped.
+    pub fn write_frame(&self, frame: &[u8]) -> io::Result<()> {
+        self.frames.blocking_send(encode(frame)).map_err(|_| writer_stopped())
+    }
+
+    // The same from async code, where blocking_send would panic
+    pub async fn send_frame(&self, frame: &[u8]) -> io::Result<()> {
+        self.frames.send(encode(frame)).await.map_err(|_| writer_stopped())
+    }
+}
+
+// The native-endian length prefix and the frame in one buffer, so frames from
+// different senders never interleave
+fn encode(frame: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(frame.len() + 4);
+    encoded.extend_from_slice(&(frame.len() as u32).to_ne_bytes());
+    encoded.extend_from_slice(frame);
+    encoded
+}
+
+fn writer_stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the native messaging port is closed")
+}
+
+// Must be called inside the runtime. The writer task ends once every
+// FrameWriter is dropped and the frames sent so far are written; each one
+// written goes to the capture too.
+pub fn claim(capture: Option<Capture>) -> (FrameWriter, JoinHandle<()>) {
+    // Anything print! buffered so far would otherwise land on the pipe
+    let _ = io::stdout().flush();
+
+    let out: Box<dyn AsyncWrite + Send + Unpin> = match io::pipe().and_then(|(reader, writer)| {
+        let original = redirect_stdout(writer)?;
+        if cfg!(debug_assertions) {
+            drop(reader);
+        } else {
+            drain_stray_output(reader);
+        }
+        Ok(original)
+    }) {
+        Ok(original) => Box::new(tokio::fs::File::from_std(original)),
+        Err(e) => {
+            error!("Failed to guard stdout, frames share it with the rest of the process: {}", e);
+            Box::new(tokio::io::stdout())
+        }
+    };
+    spawn_writer(out, capture)
+}
+
+// Frames for any other stream, such as the connection of a native host
+// process that hands its port to the GUI. Must be called inside the runtime.
+pub fn over(out: Box<dyn AsyncWrite + Send + Unpin>) -> (FrameWriter, JoinHandle<()>) {
+    spawn_writer(out, None)
+}
+
+fn spawn_writer(out: Box<dyn AsyncWrite + Send + Unpin>, capture: Option<Capture>) -> (FrameWriter, JoinHandle<()>) {
+    let (frames, queued) = mpsc::channel(OUTGOING_FRAMES);
+    (FrameWriter { frames }, tokio::spawn(write_frames(out, queued, capture)))
+}
+
+async fn write_frames(
+    mut out: Box<dyn AsyncWrite + Send + Unpin>,
+    mut queued: mpsc::Receiver<Vec<u8>>,
+    capture: Option<Capture>,
+) {
+    while let Some(frame) = queued.recv().await {
+        let written = match out.write_all(&frame).await {
+            Ok(()) => out.flush().await,
+            Err(error) => Err(error),
+        };
+        // Chrome has gone; the senders notice once the channel closes
+        if let Err(error) = written {
+            error!("Failed to write response: {}", error);
+            return;
+        }
+        if let Some(capture) = &capture {
+            capture.record_out(&frame[4..]);
+        }
+    }
+}
+
+fn drain_stray_output(mut reader: PipeReader) {
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; 4096];
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(0) | Err(_) => return,
+                Ok(length) => warn!(
+                    "Dropped {} bytes written to stdout outside the messaging protocol: {}",
+                    length,
+                    String::from_utf8_lossy(&buffer[..length]).trim_end()
+                ),
+            }
+        }
+    });
+}
+
+// Point stdout at the pipe and return a handle to the original. The duplicate is
+// close-on-exec so yt-dlp and other children never inherit the protocol stream.
+#[cfg(unix)]
+fn redirect_stdout(pipe: PipeWriter) -> io::Result<File> {
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    unsafe {
+        let original = libc::fcntl(libc::STDOUT_FILENO, libc::F_DUPFD_CLOEXEC, 0);
+        if original < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::dup2(pipe.as_raw_fd(), libc::STDOUT_FILENO) < 0 {
+            let error = io::Error::last_os_error();
+            libc::close(original);
+            return Err(error);
+        }
+        Ok(File::from_raw_fd(original))
+    }
+}
+
+// Rust's stdout looks the handle up on every write, so swapping the standard
+// handle is enough. The pipe handle is owned by the process from here on.
+#[cfg(target_os = "windows")]
+fn redirect_stdout(pipe: PipeWriter) -> io::Result<File> {
+    use std::os::windows::io::{FromRawHandle, IntoRawHandle};
+    use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+    use winapi::um::processenv::{GetStdHandle, SetStdHandle};
+    use winapi::um::winbase::STD_OUTPUT_HANDLE;
+
+    unsafe {
+        let original = GetStdHandle(STD_OUTPUT_HANDLE);
+        if original.is_null() || original == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        let pipe = pipe.into_raw_handle();
+        if SetStdHandle(STD_OUTPUT_HANDLE, pipe as _) == 0 {
+            let error = io::Error::last_os_error();
+            drop(PipeWriter::from_raw_handle(pipe));
+            return Err(error);
+        }
+        Ok(File::from_raw_handle(original as _))
+    }
+}
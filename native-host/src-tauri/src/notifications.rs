@@ -0,0 +1,169 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::history::{DownloadStatus, History, HistoryEntry};
+use crate::instance::{self, InstanceMessage};
+use crate::settings::Settings;
+
+// Downloads finishing within this window of each other share one toast
+const COALESCE_WINDOW: Duration = Duration::from_secs(3);
+#[cfg(not(target_os = "windows"))]
+const APP_IDENTIFIER: &str = "com.imgvault.nativehost";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationMode {
+    All,
+    FailuresOnly,
+    Off,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DesktopNotification {
+    pub title: String,
+    pub body: String,
+    // File revealed in the file manager when the toast is clicked
+    pub reveal_path: Option<String>,
+}
+
+// Queue a notification for a finished download. The returned thread waits for
+// the coalescing window, then claims every unnotified download with the same
+// status from the shared history, so a batch started from the extension (one
+// host process per download) still produces a single toast.
+pub fn notify_download_finished(
+    settings: &Settings,
+    history: &History,
+    entry_id: i64,
+    status: DownloadStatus,
+    duration_millis: i64,
+) -> Option<JoinHandle<()>> {
+    let wanted = match (settings.notifications, status) {
+        // The extension reports those, and their browser download has its own
+        (_, DownloadStatus::Cancelled | DownloadStatus::Delegated) => false,
+        (NotificationMode::Off, _) => false,
+        (NotificationMode::FailuresOnly, DownloadStatus::Completed) => false,
+        _ => duration_millis >= (settings.notification_min_duration_secs * 1000) as i64,
+    };
+
+    if !wanted {
+        if let Err(error) = history.mark_notified(entry_id) {
+            warn!("{}", error);
+        }
+        return None;
+    }
+
+    let history = history.clone();
+    Some(std::thread::spawn(move || {
+        std::thread::sleep(COALESCE_WINDOW);
+        let since = crate::current_timestamp_millis() - 2 * COALESCE_WINDOW.as_millis() as i64;
+        let entries = match history.claim_unnotified(status, since) {
+            Ok(entries) => entries,
+            Err(error) => {
+                warn!("{}", error);
+                return;
+            }
+        };
+
+        if let Some(notification) = build_notification(status, &entries) {
+            dispatch(notification);
+        }
+    }))
+}
+
+fn build_notification(status: DownloadStatus, entries: &[HistoryEntry]) -> Option<DesktopNotification> {
+    let first = entries.first()?;
+
+    let notification = match (status, entries.len()) {
+        (DownloadStatus::Completed, 1) => DesktopNotification {
+            title: "Download complete".to_string(),
+            body: display_name(first),
+            reveal_path: first.file_path.clone(),
+        },
+        (DownloadStatus::Completed, count) => DesktopNotification {
+            title: "Downloads complete".to_string(),
+            body: format!("{} files saved", count),
+            reveal_path: first.file_path.clone(),
+        },
+        (_, 1) => DesktopNotification {
+            title: "Download failed".to_string(),
+            body: format!(
+                "{} ({})",
+                display_name(first),
+                crate::downloader::classify_download_error(first.message.as_deref().unwrap_or(""))
+            ),
+            reveal_path: None,
+        },
+        (_, count) => DesktopNotification {
+            title: "Downloads failed".to_string(),
+            body: format!(
+                "{} downloads failed, last error: {}",
+                count,
+                crate::downloader::classify_download_error(first.message.as_deref().unwrap_or(""))
+            ),
+            reveal_path: None,
+        },
+    };
+
+    Some(notification)
+}
+
+fn display_name(entry: &HistoryEntry) -> String {
+    entry
+        .file_path
+        .as_deref()
+        .and_then(|path| Path::new(path).file_name())
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| entry.url.clone())
+}
+
+// Prefer the running GUI so clicks land in a live process, otherwise show it here
+fn dispatch(notification: DesktopNotification) {
+    let message = InstanceMessage::Notify { notification };
+    if instance::send_to_running_instance(&message).is_ok() {
+        return;
+    }
+
+    if let InstanceMessage::Notify { notification } = message {
+        show(&notification);
+    }
+}
+
+// Windows toasts come from winrt directly so a click can reveal the file;
+// the portable exe has no registered AUMID, so borrow PowerShell's.
+#[cfg(target_os = "windows")]
+pub fn show(notification: &DesktopNotification) {
+    use tauri_winrt_notification::Toast;
+
+    let reveal_path = notification.reveal_path.clone();
+    let result = Toast::new(Toast::POWERSHELL_APP_ID)
+        .title(&notification.title)
+        .text1(&notification.body)
+        .on_activated(move || {
+            if let Some(path) = &reveal_path {
+                if let Err(error) = crate::reveal_in_file_manager(Path::new(path)) {
+                    warn!("Failed to reveal {}: {}", path, error);
+                }
+            }
+            Ok(())
+        })
+        .show();
+
+    if let Err(error) = result {
+        warn!("Failed to show notification: {}", error);
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn show(notification: &DesktopNotification) {
+    let result = tauri::api::notification::Notification::new(APP_IDENTIFIER)
+        .title(&notification.title)
+        .body(&notification.body)
+        .show();
+
+    if let Err(error) = result {
+        warn!("Failed to show notification: {}", error);
+    }
+}
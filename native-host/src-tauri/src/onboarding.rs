@@ -0,0 +1,177 @@
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::db_backup;
+use crate::destination::{self, DestinationError};
+use crate::diagnostics::{check_writable, protocol_self_test, registered_browsers};
+use crate::downloader::find_yt_dlp;
+use crate::history::History;
+use crate::profiles::Profile;
+use crate::registration::{register_native_host, RegistrationReport};
+use crate::settings::Settings;
+use crate::{get_vault_directory, EXTENSION_ID};
+
+// First-run setup, in the order the wizard walks through it. Every step is
+// judged by the real check each time, never by a stored flag, so a step that
+// breaks later (yt-dlp deleted, vault drive gone) shows up as incomplete again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnboardingStep {
+    // Only ever incomplete when the history database is damaged
+    Database,
+    Vault,
+    YtDlp,
+    Browser,
+    SelfTest,
+}
+
+const STEPS: [OnboardingStep; 5] = [
+    OnboardingStep::Database,
+    OnboardingStep::Vault,
+    OnboardingStep::YtDlp,
+    OnboardingStep::Browser,
+    OnboardingStep::SelfTest,
+];
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StepState {
+    pub step: OnboardingStep,
+    pub complete: bool,
+    pub message: String,
+    // What complete_step does, or what the user has to do, when incomplete
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OnboardingState {
+    pub steps: Vec<StepState>,
+    pub complete: bool,
+    // The first incomplete step, which the wizard shows and the health banner names
+    pub next_step: Option<OnboardingStep>,
+}
+
+// Runs every check, including the protocol self-test, which starts a second
+// copy of the host; call it off the main thread
+pub fn state(settings: &Settings, history: &History) -> OnboardingState {
+    let steps = STEPS.iter().map(|&step| check(step, settings, history)).collect::<Vec<_>>();
+    let next_step = steps.iter().find(|state| !state.complete).map(|state| state.step);
+    OnboardingState {
+        complete: next_step.is_none(),
+        next_step,
+        steps,
+    }
+}
+
+fn check(step: OnboardingStep, settings: &Settings, history: &History) -> StepState {
+    let result = match step {
+        OnboardingStep::Database => check_database(history),
+        OnboardingStep::Vault => check_vault(settings),
+        OnboardingStep::YtDlp => find_yt_dlp(settings),
+        OnboardingStep::Browser => check_browser(),
+        OnboardingStep::SelfTest => {
+            protocol_self_test().map(|()| "The browser extension can talk to the host".to_string())
+        }
+    };
+    match result {
+        Ok(message) => StepState {
+            step,
+            complete: true,
+            message,
+            hint: None,
+        },
+        Err(message) => StepState {
+            step,
+            complete: false,
+            message,
+            hint: Some(hint(step)),
+        },
+    }
+}
+
+fn hint(step: OnboardingStep) -> String {
+    match step {
+        OnboardingStep::Database => database_hint(),
+        OnboardingStep::Vault => "Create the default vault folder or choose another one in settings".to_string(),
+        OnboardingStep::YtDlp => "Install yt-dlp and add it to PATH, or set its location in settings".to_string(),
+        OnboardingStep::Browser => "Register the host with your browser".to_string(),
+        OnboardingStep::SelfTest => {
+            "Something is writing to stdout or the host crashes on startup; attach the log to a bug report".to_string()
+        }
+    }
+}
+
+fn check_database(history: &History) -> Result<String, String> {
+    db_backup::startup_state(history).map(|()| "History database is intact".to_string())
+}
+
+// Names the backup to restore, which restore_database needs confirmed
+fn database_hint() -> String {
+    let newest = Profile::active()
+        .directory()
+        .ok()
+        .and_then(|directory| db_backup::newest_good(&directory));
+    match newest {
+        Some(backup) => format!(
+            "Restore backup {} from {}; the damaged database is kept in the backups folder",
+            backup.name,
+            crate::timestamps::format_rfc3339(backup.created_at)
+        ),
+        None => "There is no intact backup to restore; move history.db out of the app data folder to start a new one"
+            .to_string(),
+    }
+}
+
+fn check_vault(settings: &Settings) -> Result<String, String> {
+    let directory = get_vault_directory(settings)?;
+    if !directory.is_dir() {
+        return Err(format!("Vault folder {} does not exist", directory.display()));
+    }
+    check_writable(&directory)?;
+    Ok(format!("Vault folder {} is writable", directory.display()))
+}
+
+fn check_browser() -> Result<String, String> {
+    let browsers = registered_browsers();
+    if browsers.is_empty() {
+        Err("The host is not registered with any browser".to_string())
+    } else {
+        Ok(format!("Registered with {}", browsers.join(", ")))
+    }
+}
+
+// Does what can be done for a step from here, then reports whether it is now
+// complete. Registration uses the published extension's id unless given one.
+pub fn complete_step(
+    step: OnboardingStep,
+    settings: &Settings,
+    history: &History,
+    extension_id: Option<&str>,
+) -> Result<StepState, String> {
+    match step {
+        // Replacing the history takes an explicit restore_database
+        OnboardingStep::Database => {}
+        OnboardingStep::Vault => {
+            let directory = get_vault_directory(settings)?;
+            destination::ensure(&directory).map_err(|error| match error {
+                DestinationError::Unavailable(error) | DestinationError::Failed(error) => error,
+            })?;
+            info!(directory = %directory.display(), "Vault folder created during setup");
+        }
+        // Nothing to install from here; a yt-dlp installed since the host
+        // started is only found once PATH is read again
+        OnboardingStep::YtDlp => {
+            #[cfg(target_os = "windows")]
+            crate::reload_windows_path_environment()?;
+        }
+        OnboardingStep::Browser => {
+            register_native_host(extension_id.unwrap_or(EXTENSION_ID), "chrome").and_then(RegistrationReport::into_result)?;
+            info!("Host registered during setup");
+        }
+        // Running the check is all there is to it
+        OnboardingStep::SelfTest => {}
+    }
+    Ok(check(step, settings, history))
+}
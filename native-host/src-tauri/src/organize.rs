@@ -0,0 +1,400 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use tracing::{debug, info, warn};
+
+use crate::conflicts::ConflictDecision;
+use crate::download_phase::AUDIO_EXTENSIONS;
+use crate::downloader::IMAGE_EXTENSIONS;
+use crate::history::History;
+use crate::media_policy::ImageDimensions;
+use crate::settings::Settings;
+use crate::{get_vault_directory, url_host};
+
+// What every message starts with; classify_download_error keys on it
+pub const SKIPPED_PREFIX: &str = "Skipped by rule";
+
+// What to do when the file a download would write already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionMode {
+    // Keep the existing file and download nothing
+    Skip,
+    Overwrite,
+    // The window asks, see conflicts; other downloads use conflict_default
+    Ask,
+}
+
+impl CollisionMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CollisionMode::Skip => "skip",
+            CollisionMode::Overwrite => "overwrite",
+            CollisionMode::Ask => "ask",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "skip" => Some(CollisionMode::Skip),
+            "overwrite" => Some(CollisionMode::Overwrite),
+            "ask" => Some(CollisionMode::Ask),
+            _ => None,
+        }
+    }
+}
+
+// One entry of settings.organize_rules
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OrganizeRule {
+    // Shown by test_rules and kept in history with the entries it matched
+    pub id: String,
+    pub conditions: RuleConditions,
+    pub actions: RuleActions,
+    // Later rules are still tried after this one matches
+    #[serde(rename = "continue")]
+    pub continue_matching: bool,
+}
+
+// Every condition given must hold; a rule without any matches every job
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RuleConditions {
+    // Glob on the host, e.g. "*.twitter.com", which also matches twitter.com
+    pub domain: Option<String>,
+    // e.g. "image/*" or "video/mp4"
+    pub mime_type: Option<String>,
+    // With or without the leading "#"
+    pub tag: Option<String>,
+    // Only jobs whose request gave dimensions can match
+    pub min_dimensions: Option<ImageDimensions>,
+    // Glob on the uploader the request named
+    pub uploader: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RuleActions {
+    // Relative folder inside the vault, e.g. "Wallpapers"
+    pub subfolder: Option<String>,
+    pub add_tags: Vec<String>,
+    pub collision_mode: Option<CollisionMode>,
+    // Refuse the job
+    pub skip: bool,
+}
+
+// What a request already knew about the media before anything was
+// downloaded; rules match against these and the URL
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MediaHints {
+    pub tags: Vec<String>,
+    // Guessed from the URL's extension when missing. The HTTP API spells it
+    // mimeType like its other fields.
+    #[serde(alias = "mimeType")]
+    pub mime_type: Option<String>,
+    pub dimensions: Option<ImageDimensions>,
+    pub uploader: Option<String>,
+}
+
+// A hypothetical request for test_rules
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RuleSample {
+    pub url: String,
+    #[serde(flatten)]
+    pub hints: MediaHints,
+}
+
+// The combined actions of the rules a job matched
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RuleMatch {
+    // In settings order; empty when no rule matched
+    pub rule_ids: Vec<String>,
+    // The request's tags followed by those the rules added
+    pub tags: Vec<String>,
+    // Joined in rule order, so a later rule nests inside an earlier one's folder
+    pub subfolder: Option<String>,
+    // The last matching rule that set one wins
+    pub collision_mode: Option<CollisionMode>,
+    // The rule that refused the job
+    pub skipped_by: Option<String>,
+}
+
+impl RuleMatch {
+    pub fn skipped_message(&self) -> Option<String> {
+        self.skipped_by
+            .as_ref()
+            .map(|id| format!("{}: organization rule \"{}\" skips this download", SKIPPED_PREFIX, id))
+    }
+
+    // The output path with the subfolder inserted right under the vault, or
+    // before the file name when the path is outside it
+    fn apply_to_output_path(&self, output_path: &str, settings: &Settings) -> String {
+        let Some(subfolder) = &self.subfolder else {
+            return output_path.to_string();
+        };
+        let path = Path::new(output_path);
+        let vault = get_vault_directory(settings).ok();
+        let organized = match vault.as_deref().and_then(|vault| Some((vault, path.strip_prefix(vault).ok()?))) {
+            Some((vault, relative)) => vault.join(subfolder).join(relative),
+            None => {
+                let parent = path.parent().unwrap_or(Path::new(""));
+                parent.join(subfolder).join(path.file_name().unwrap_or_default())
+            }
+        };
+        organized.display().to_string()
+    }
+}
+
+// Runs the rules for a job arriving at the host, before it is queued or
+// scheduled, and keeps what they decided for its history entries. Returns
+// the settings and output path the job runs with, or why a rule skipped it.
+// Jobs that come back later, such as retries, resumes and scheduled runs,
+// already carry the output path and go through resume_job instead.
+pub fn organize_job(
+    history: &History,
+    settings: Settings,
+    job_id: &str,
+    url: &str,
+    hints: &MediaHints,
+    output_path: &str,
+) -> Result<(Settings, String), String> {
+    let matched = evaluate(&settings.organize_rules, url, hints);
+    if let Some(message) = matched.skipped_message() {
+        info!(job_id, "{}", message);
+        return Err(message);
+    }
+    if !matched.rule_ids.is_empty() {
+        info!(job_id, rules = %matched.rule_ids.join(", "), "Applied organization rules");
+    }
+    if let Err(error) = history.record_organized_job(job_id, &matched.rule_ids, &matched.tags, matched.collision_mode) {
+        warn!(job_id, "{}", error);
+    }
+    let output_path = matched.apply_to_output_path(output_path, &settings);
+    Ok((with_collision_mode(settings, matched.collision_mode), output_path))
+}
+
+// The settings a job that comes back runs with: the collision mode its rules
+// set when it first arrived
+pub fn resume_job(history: &History, settings: Settings, job_id: &str) -> Settings {
+    match history.organized_collision_mode(job_id) {
+        Ok(collision_mode) => with_collision_mode(settings, collision_mode),
+        Err(error) => {
+            warn!(job_id, "{}", error);
+            settings
+        }
+    }
+}
+
+fn with_collision_mode(mut settings: Settings, collision_mode: Option<CollisionMode>) -> Settings {
+    if collision_mode.is_some() {
+        settings.collision_mode = collision_mode;
+    }
+    settings
+}
+
+// Walks the rules in order; the first that matches ends the walk unless it is
+// marked continue
+pub fn evaluate(rules: &[OrganizeRule], url: &str, hints: &MediaHints) -> RuleMatch {
+    let mut matched = RuleMatch {
+        tags: hints.tags.iter().map(|tag| normalize_tag(tag)).filter(|tag| !tag.is_empty()).collect(),
+        ..RuleMatch::default()
+    };
+    let mime_type = hints.mime_type.clone().or_else(|| guess_mime_type(url));
+    let mut subfolder = PathBuf::new();
+    for rule in rules {
+        if !conditions_hold(&rule.conditions, url, hints, mime_type.as_deref()) {
+            continue;
+        }
+        debug!(rule = %rule.id, "Organization rule matched");
+        matched.rule_ids.push(rule.id.clone());
+        let actions = &rule.actions;
+        for tag in &actions.add_tags {
+            let tag = normalize_tag(tag);
+            if !matched.tags.contains(&tag) {
+                matched.tags.push(tag);
+            }
+        }
+        if let Some(folder) = &actions.subfolder {
+            subfolder.push(folder.trim());
+        }
+        if actions.collision_mode.is_some() {
+            matched.collision_mode = actions.collision_mode;
+        }
+        if actions.skip {
+            matched.skipped_by = Some(rule.id.clone());
+            break;
+        }
+        if !rule.continue_matching {
+            break;
+        }
+    }
+    if !subfolder.as_os_str().is_empty() {
+        matched.subfolder = Some(subfolder.display().to_string());
+    }
+    matched
+}
+
+fn conditions_hold(conditions: &RuleConditions, url: &str, hints: &MediaHints, mime_type: Option<&str>) -> bool {
+    if let Some(pattern) = &conditions.domain {
+        let host = url_host(url).unwrap_or_default();
+        let pattern = pattern.trim().to_ascii_lowercase();
+        let bare = pattern.strip_prefix("*.").is_some_and(|domain| domain == host);
+        if host.is_empty() || !(bare || glob_matches(&pattern, &host)) {
+            return false;
+        }
+    }
+    if let Some(pattern) = &conditions.mime_type {
+        if !mime_type.is_some_and(|mime_type| glob_matches(&pattern.trim().to_ascii_lowercase(), &mime_type.to_ascii_lowercase())) {
+            return false;
+        }
+    }
+    if let Some(tag) = &conditions.tag {
+        let tag = normalize_tag(tag);
+        if !hints.tags.iter().any(|candidate| normalize_tag(candidate) == tag) {
+            return false;
+        }
+    }
+    if let Some(min) = conditions.min_dimensions {
+        if !hints.dimensions.is_some_and(|size| size.width >= min.width && size.height >= min.height) {
+            return false;
+        }
+    }
+    if let Some(pattern) = &conditions.uploader {
+        let pattern = pattern.trim().to_lowercase();
+        if !hints.uploader.as_deref().is_some_and(|uploader| glob_matches(&pattern, &uploader.trim().to_lowercase())) {
+            return false;
+        }
+    }
+    true
+}
+
+// "#Wallpaper" and "wallpaper" are the same tag
+fn normalize_tag(tag: &str) -> String {
+    tag.trim().trim_start_matches('#').to_lowercase()
+}
+
+// `*` matches any run of characters, `?` any one
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was, and the text position it has swallowed up to
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// From the extension of the URL's last path segment
+fn guess_mime_type(url: &str) -> Option<String> {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let (_, extension) = path.rsplit('/').next()?.rsplit_once('.')?;
+    let extension = extension.to_ascii_lowercase();
+    let mime_type = match extension.as_str() {
+        "jpg" | "jpeg" => "image/jpeg".to_string(),
+        "svg" => "image/svg+xml".to_string(),
+        "mp3" => "audio/mpeg".to_string(),
+        "m4a" => "audio/mp4".to_string(),
+        "mkv" => "video/x-matroska".to_string(),
+        "mov" => "video/quicktime".to_string(),
+        "mp4" | "webm" | "avi" | "flv" => format!("video/{}", extension),
+        extension if IMAGE_EXTENSIONS.contains(&extension) => format!("image/{}", extension),
+        extension if AUDIO_EXTENSIONS.contains(&extension) => format!("audio/{}", extension),
+        _ => return None,
+    };
+    Some(mime_type)
+}
+
+pub fn validate(rules: &[OrganizeRule]) -> Result<(), String> {
+    let mut ids = HashSet::new();
+    for rule in rules {
+        if rule.id.trim().is_empty() {
+            return Err("Every rule needs an id".to_string());
+        }
+        if !ids.insert(rule.id.as_str()) {
+            return Err(format!("{}: id is used by another rule", rule.id));
+        }
+        if let Some(domain) = &rule.conditions.domain {
+            if domain.trim().is_empty() || domain.contains(['/', ':', ' ']) {
+                return Err(format!("{}: \"{}\" is not a host name glob such as *.example.com", rule.id, domain));
+            }
+        }
+        if let Some(mime_type) = &rule.conditions.mime_type {
+            if !mime_type.contains('/') {
+                return Err(format!("{}: \"{}\" is not a MIME type such as image/*", rule.id, mime_type));
+            }
+        }
+        if rule.conditions.min_dimensions.is_some_and(|min| min.width == 0 || min.height == 0) {
+            return Err(format!("{}: width and height must be at least 1", rule.id));
+        }
+        if let Some(subfolder) = &rule.actions.subfolder {
+            let path = Path::new(subfolder.trim());
+            if subfolder.trim().is_empty() || !path.components().all(|component| matches!(component, Component::Normal(_))) {
+                return Err(format!("{}: subfolder must be a relative folder inside the vault", rule.id));
+            }
+        }
+        if rule.actions.add_tags.iter().any(|tag| normalize_tag(tag).is_empty()) {
+            return Err(format!("{}: tags must not be empty", rule.id));
+        }
+    }
+    Ok(())
+}
+
+// yt-dlp skips a finished file by itself but still overwrites what it
+// post-processes; both modes are made explicit
+pub fn add_yt_dlp_argument(command: &mut Command, settings: &Settings) {
+    match backend_mode(settings) {
+        Some(CollisionMode::Skip) => {
+            command.arg("--no-overwrites");
+        }
+        Some(CollisionMode::Overwrite) => {
+            command.arg("--force-overwrites");
+        }
+        Some(CollisionMode::Ask) | None => {}
+    }
+}
+
+// gallery-dl skips files it already has unless told otherwise
+pub fn add_gallery_dl_argument(command: &mut Command, settings: &Settings) {
+    if backend_mode(settings) == Some(CollisionMode::Overwrite) {
+        command.arg("--no-skip");
+    }
+}
+
+// The image fetch child overwrites unless told otherwise
+pub fn add_image_fetch_argument(command: &mut Command, settings: &Settings) {
+    if backend_mode(settings) == Some(CollisionMode::Skip) {
+        command.arg("--no-overwrites");
+    }
+}
+
+// An ask still open when the backend starts, i.e. nobody was asked, follows
+// conflict_default. Renaming needs the file name up front, which only
+// conflicts::settle works out, so here it keeps the existing file.
+fn backend_mode(settings: &Settings) -> Option<CollisionMode> {
+    match settings.collision_mode {
+        Some(CollisionMode::Ask) => Some(match settings.conflict_default {
+            ConflictDecision::Overwrite => CollisionMode::Overwrite,
+            ConflictDecision::Skip | ConflictDecision::Rename => CollisionMode::Skip,
+        }),
+        mode => mode,
+    }
+}
@@ -0,0 +1,195 @@
+use serde::Serialize;
+use std::io;
+use std::path::Path;
+use tracing::{debug, info, warn};
+
+use crate::logging;
+use crate::settings::Settings;
+
+// The Internet zone, as browsers mark what they download
+#[cfg(target_os = "windows")]
+const INTERNET_ZONE_ID: u32 = 3;
+#[cfg(target_os = "windows")]
+const ZONE_STREAM: &str = "Zone.Identifier";
+// freedesktop.org's common extended attributes, which file managers and
+// browsers on Linux read and write
+#[cfg(target_os = "linux")]
+const ORIGIN_URL_ATTRIBUTE: &str = "user.xdg.origin.url";
+#[cfg(target_os = "linux")]
+const REFERRER_URL_ATTRIBUTE: &str = "user.xdg.referrer.url";
+
+// Where a file says it came from, read back from its stream or attributes
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OriginTag {
+    // Only Windows has zones
+    pub zone_id: Option<u32>,
+    pub host_url: Option<String>,
+    pub referrer_url: Option<String>,
+}
+
+// The settings with a request's tag_origin applied, like chapters::for_request
+pub fn for_request(mut settings: Settings, tag_origin: Option<bool>) -> Settings {
+    if let Some(tag_origin) = tag_origin {
+        settings.tag_download_origin = tag_origin;
+    }
+    settings
+}
+
+// The Zone.Identifier stream Chrome and Edge write, CRLF and all; a download
+// without a page has no ReferrerUrl
+#[cfg(target_os = "windows")]
+pub fn zone_identifier(url: &str, page_url: Option<&str>) -> String {
+    let mut lines = vec!["[ZoneTransfer]".to_string(), format!("ZoneId={}", INTERNET_ZONE_ID)];
+    if let Some(page_url) = page_url {
+        lines.push(format!("ReferrerUrl={}", page_url));
+    }
+    lines.push(format!("HostUrl={}", url));
+    lines.iter().map(|line| format!("{}\r\n", line)).collect()
+}
+
+#[cfg(target_os = "windows")]
+fn parse_zone_identifier(text: &str) -> OriginTag {
+    let mut tag = OriginTag::default();
+    for line in text.lines() {
+        match line.trim().split_once('=') {
+            Some(("ZoneId", value)) => tag.zone_id = value.trim().parse().ok(),
+            Some(("HostUrl", value)) => tag.host_url = Some(value.to_string()),
+            Some(("ReferrerUrl", value)) => tag.referrer_url = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    tag
+}
+
+// Marks the file of a finished download with `url` and the page it was on,
+// when the settings ask for it. Returns a warning for the response when the
+// mark could not be written; the download stands either way.
+pub fn tag(settings: &Settings, job_id: &str, file_path: &str, url: &str, page_url: Option<&str>) -> Option<String> {
+    if !settings.tag_download_origin {
+        return None;
+    }
+    match write(Path::new(file_path), url, page_url) {
+        Ok(()) => {
+            debug!(job_id, url = logging::loggable_url(url), "Marked the download with its origin");
+            None
+        }
+        // FAT drives, network shares without streams and platforms without
+        // an attribute of this kind
+        Err(error) if error.kind() == io::ErrorKind::Unsupported => {
+            info!(job_id, "Not marking the download with its origin: {}", error);
+            None
+        }
+        Err(error) => {
+            warn!(job_id, "Failed to mark the download with its origin: {}", error);
+            Some(format!("The file was not marked with where it came from: {}", error))
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn write(path: &Path, url: &str, page_url: Option<&str>) -> io::Result<()> {
+    // What FAT and exFAT say of a name with a stream in it
+    const ERROR_INVALID_NAME: i32 = 123;
+    std::fs::write(stream_path(path), zone_identifier(url, page_url)).map_err(|error| {
+        if error.raw_os_error() == Some(ERROR_INVALID_NAME) {
+            io::Error::new(io::ErrorKind::Unsupported, "the drive cannot hold alternate data streams")
+        } else {
+            error
+        }
+    })
+}
+
+// None when the file has no mark
+#[cfg(target_os = "windows")]
+pub fn read(path: &Path) -> io::Result<Option<OriginTag>> {
+    match std::fs::read_to_string(stream_path(path)) {
+        Ok(text) => Ok(Some(parse_zone_identifier(&text))),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+// file.mp4:Zone.Identifier, which only NTFS and ReFS can hold
+#[cfg(target_os = "windows")]
+fn stream_path(path: &Path) -> std::path::PathBuf {
+    let mut stream = crate::long_path::extended(path).into_os_string();
+    stream.push(":");
+    stream.push(ZONE_STREAM);
+    stream.into()
+}
+
+#[cfg(target_os = "linux")]
+fn write(path: &Path, url: &str, page_url: Option<&str>) -> io::Result<()> {
+    set_attribute(path, ORIGIN_URL_ATTRIBUTE, url)?;
+    match page_url {
+        Some(page_url) => set_attribute(path, REFERRER_URL_ATTRIBUTE, page_url),
+        None => Ok(()),
+    }
+}
+
+// None when the file has no mark
+#[cfg(target_os = "linux")]
+pub fn read(path: &Path) -> io::Result<Option<OriginTag>> {
+    let Some(host_url) = get_attribute(path, ORIGIN_URL_ATTRIBUTE)? else {
+        return Ok(None);
+    };
+    Ok(Some(OriginTag {
+        zone_id: None,
+        host_url: Some(host_url),
+        referrer_url: get_attribute(path, REFERRER_URL_ATTRIBUTE)?,
+    }))
+}
+
+#[cfg(target_os = "linux")]
+fn c_path(path: &Path) -> io::Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+#[cfg(target_os = "linux")]
+fn set_attribute(path: &Path, name: &str, value: &str) -> io::Result<()> {
+    let (path, name) = (c_path(path)?, c_path(Path::new(name))?);
+    if unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), value.as_ptr().cast(), value.len(), 0) } < 0 {
+        return Err(attribute_error());
+    }
+    Ok(())
+}
+
+// Without user attributes, e.g. FAT or an older tmpfs, is not a failure
+#[cfg(target_os = "linux")]
+fn attribute_error() -> io::Error {
+    let error = io::Error::last_os_error();
+    if error.raw_os_error() == Some(libc::ENOTSUP) {
+        io::Error::new(io::ErrorKind::Unsupported, "the file system does not keep extended attributes")
+    } else {
+        error
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn get_attribute(path: &Path, name: &str) -> io::Result<Option<String>> {
+    let (path, name) = (c_path(path)?, c_path(Path::new(name))?);
+    // Long enough for any URL a browser would keep
+    let mut value = vec![0u8; 64 * 1024];
+    let length = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), value.as_mut_ptr().cast(), value.len()) };
+    if length < 0 {
+        let error = attribute_error();
+        return match error.raw_os_error() {
+            Some(libc::ENODATA) => Ok(None),
+            _ => Err(error),
+        };
+    }
+    value.truncate(length as usize);
+    Ok(Some(String::from_utf8_lossy(&value).into_owned()))
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
+fn write(_path: &Path, _url: &str, _page_url: Option<&str>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "origin marks are not supported here"))
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
+pub fn read(_path: &Path) -> io::Result<Option<OriginTag>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "origin marks are not supported here"))
+}
@@ -0,0 +1,303 @@
+use serde::Serialize;
+use std::process::Command;
+
+use crate::{console_text, get_vault_directory, tool_integrity, user_agent};
+use crate::settings::Settings;
+use crate::source_page::SourcePage;
+
+pub const DEFAULT_OUTPUT_TEMPLATE: &str = "%(title)s [%(id)s].%(ext)s";
+const MAX_TEMPLATE_LENGTH: usize = 512;
+// yt-dlp fields a template may use; the rest either leak more than a file
+// name needs, such as URLs, or make no sense in one
+const ALLOWED_FIELDS: &[&str] = &[
+    "title",
+    "fulltitle",
+    "alt_title",
+    "id",
+    "display_id",
+    "ext",
+    "uploader",
+    "uploader_id",
+    "channel",
+    "channel_id",
+    "creator",
+    "upload_date",
+    "release_date",
+    "timestamp",
+    "epoch",
+    "duration",
+    "duration_string",
+    "resolution",
+    "width",
+    "height",
+    "fps",
+    "format_id",
+    "format_note",
+    "vcodec",
+    "acodec",
+    "playlist",
+    "playlist_title",
+    "playlist_id",
+    "playlist_index",
+    "playlist_count",
+    "autonumber",
+    "extractor",
+    "extractor_key",
+    "webpage_url_domain",
+    "series",
+    "season_number",
+    "episode_number",
+    "track",
+    "artist",
+    "album",
+];
+// printf conversion types yt-dlp accepts after a field
+const CONVERSION_TYPES: &str = "diouxXeEfFgGcrsaBljqDSU";
+
+// Checks a template before any download runs it: every %(...) group is
+// closed and has a conversion, names only allowed fields, and the template
+// stays inside the folder it is rooted in. Errors name the offending token.
+pub fn validate(template: &str) -> Result<(), String> {
+    if template.trim().is_empty() {
+        return Err("Output template is empty".to_string());
+    }
+    if template.len() > MAX_TEMPLATE_LENGTH {
+        return Err(format!("Output template is longer than {} characters", MAX_TEMPLATE_LENGTH));
+    }
+    if let Some(invalid) = template.chars().find(|c| c.is_control()) {
+        return Err(format!("Output template contains a control character: {:?}", invalid));
+    }
+
+    // The template with every field replaced, for the path checks
+    let mut skeleton = String::with_capacity(template.len());
+    let mut has_ext = false;
+    let mut rest = template;
+    while let Some(start) = rest.find('%') {
+        skeleton.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        if let Some(literal) = after.strip_prefix('%') {
+            skeleton.push('%');
+            rest = literal;
+            continue;
+        }
+        let Some(group) = after.strip_prefix('(') else {
+            let token: String = rest[start..].chars().take(2).collect();
+            return Err(format!("Stray {} in output template; write %% for a percent sign", token));
+        };
+        let Some(end) = group.find(')') else {
+            return Err(format!("Unclosed %({} in output template", group));
+        };
+        let expression = &group[..end];
+        let conversion_len = conversion_length(&group[end + 1..]);
+        let token = &rest[start..start + 2 + end + 1 + conversion_len];
+        if conversion_len == 0 {
+            return Err(format!("Missing conversion type after {}, e.g. {}s", token, token));
+        }
+        if !token.ends_with(|c| CONVERSION_TYPES.contains(c)) {
+            return Err(format!("Unknown conversion type in {}", token));
+        }
+        for alternative in expression.split(',') {
+            let name = field_name(alternative);
+            if name.is_empty() {
+                return Err(format!("Empty field name in {}", token));
+            }
+            if !ALLOWED_FIELDS.contains(&name) {
+                return Err(format!("Field {} in {} is not allowed in output templates", name, token));
+            }
+            has_ext |= name == "ext";
+        }
+        skeleton.push('x');
+        rest = &group[end + 1 + conversion_len..];
+    }
+    skeleton.push_str(rest);
+
+    if skeleton.starts_with(['/', '\\', '~']) || skeleton.chars().nth(1) == Some(':') {
+        return Err(format!("Output template {} must be relative to the vault", template));
+    }
+    if let Some(component) = skeleton.split(['/', '\\']).find(|component| component.trim() == "..") {
+        return Err(format!("Output template must not leave the vault with {}", component));
+    }
+    if !has_ext {
+        return Err("Output template has no %(ext)s, so files would lack an extension".to_string());
+    }
+    Ok(())
+}
+
+// Length of the flags, width, precision and type after a field's ")"
+fn conversion_length(after: &str) -> usize {
+    let spec = after
+        .find(|c: char| !(c.is_ascii_digit() || "#-+ .".contains(c)))
+        .unwrap_or(after.len());
+    match after[spec..].chars().next() {
+        Some(c) if c.is_ascii_alphabetic() => spec + 1,
+        _ => 0,
+    }
+}
+
+// "upload_date>%Y-%m-%d" -> "upload_date"; formatting, defaults and
+// replacements follow the name
+fn field_name(alternative: &str) -> &str {
+    let alternative = alternative.trim();
+    let end = alternative
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(alternative.len());
+    let (name, suffix) = alternative.split_at(end);
+    if suffix.is_empty() || suffix.starts_with(['.', '>', '|', '&']) {
+        name
+    } else {
+        // Arithmetic and other expressions are not offered
+        alternative
+    }
+}
+
+// The output path for `template` inside the vault, once it is valid
+pub fn in_vault(template: &str, settings: &Settings) -> Result<String, String> {
+    validate(template)?;
+    Ok(get_vault_directory(settings)?.join(template).display().to_string())
+}
+
+// Where a download goes: its own template in the vault, else the path it was
+// sent with, else the configured template in the vault
+pub fn resolve(output_path: Option<String>, template: Option<&str>, settings: &Settings) -> Result<String, String> {
+    match (template, output_path) {
+        (Some(template), _) => in_vault(template, settings),
+        (None, Some(output_path)) => Ok(output_path),
+        (None, None) => in_vault(&settings.output_template, settings),
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TemplatePreview {
+    pub file_name: String,
+    // What the download would send; None when yt-dlp keeps its own
+    pub user_agent: Option<String>,
+}
+
+// The file name yt-dlp would pick for `url` with `template`, without
+// downloading anything, asking the site with the user agent the download
+// would use
+pub fn preview(template: &str, url: &str, settings: &Settings, source_page: &SourcePage) -> Result<TemplatePreview, String> {
+    let output_path = in_vault(template, settings)?;
+    tool_integrity::verify(settings.yt_dlp_program())?;
+    let user_agent = user_agent::effective(settings, url, source_page);
+    let mut command = Command::new(settings.yt_dlp_program());
+    command
+        .arg(url)
+        .arg("-o")
+        .arg(&output_path)
+        .arg("--print")
+        .arg("filename")
+        .arg("--simulate")
+        .arg("--no-playlist")
+        .arg("--no-warnings");
+    if let Some(user_agent) = user_agent.as_deref() {
+        command.arg("--user-agent").arg(user_agent);
+    }
+    console_text::force_utf8(&mut command);
+
+    #[cfg(target_os = "windows")]
+    {
+        use std::os::windows::process::CommandExt;
+        const CREATE_NO_WINDOW: u32 = 0x08000000;
+        command.creation_flags(CREATE_NO_WINDOW);
+    }
+
+    let output = command
+        .output()
+        .map_err(|e| format!("Failed to execute yt-dlp: {}", e))?;
+    let stdout = console_text::decode(&output.stdout);
+    match stdout.lines().rev().find(|line| !line.trim().is_empty()) {
+        Some(file_name) if output.status.success() => Ok(TemplatePreview {
+            file_name: file_name.trim().to_string(),
+            user_agent,
+        }),
+        _ => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(stderr
+                .lines()
+                .rev()
+                .find(|line| line.starts_with("ERROR:"))
+                .map(|line| line.trim().to_string())
+                .unwrap_or_else(|| format!("yt-dlp returned exit code {:?}", output.status.code())))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn rejected(template: &str) -> String {
+        validate(template).expect_err(template)
+    }
+
+    #[test]
+    fn templates_may_not_climb_out_of_the_vault() {
+        for template in [
+            "../%(title)s.%(ext)s",
+            "Videos/../../%(id)s.%(ext)s",
+            r"Videos\..\%(id)s.%(ext)s",
+            "%(title)s/ .. /%(id)s.%(ext)s",
+        ] {
+            assert!(rejected(template).contains("must not leave the vault"), "{}", template);
+        }
+        // A field is never a path component of its own
+        validate("%(title)s..%(id)s.%(ext)s").expect("dots inside a name");
+    }
+
+    #[test]
+    fn absolute_templates_are_refused() {
+        for template in [
+            "/etc/%(id)s.%(ext)s",
+            r"\\server\share\%(id)s.%(ext)s",
+            r"C:\Videos\%(id)s.%(ext)s",
+            "~/%(id)s.%(ext)s",
+        ] {
+            assert!(rejected(template).contains("must be relative to the vault"), "{}", template);
+        }
+    }
+
+    #[test]
+    fn unknown_fields_and_broken_groups_name_the_token() {
+        let cases = [
+            ("%(webpage_url)s.%(ext)s", "Field webpage_url in %(webpage_url)s is not allowed"),
+            ("%(title,url)s.%(ext)s", "Field url in %(title,url)s is not allowed"),
+            ("%(playlist_index+1)d %(title)s.%(ext)s", "Field playlist_index+1"),
+            ("%()s.%(ext)s", "Empty field name in %()s"),
+            ("%(ext)s %(title", "Unclosed %(title"),
+            ("%(title)s.%(ext)", "Missing conversion type after %(ext)"),
+            ("%(title)k.%(ext)s", "Unknown conversion type in %(title)k"),
+            ("100% %(title)s.%(ext)s", "Stray % "),
+            ("%(title)s", "has no %(ext)s"),
+        ];
+        for (template, expected) in cases {
+            let error = rejected(template);
+            assert!(error.contains(expected), "{}: {}", template, error);
+        }
+    }
+
+    #[test]
+    fn a_valid_template_renders_inside_the_vault() {
+        let template = "%(uploader)s/%(upload_date>%Y-%m-%d)s - %(title)s [%(id)s] 100%%.%(ext)s";
+        validate(template).expect("valid");
+        let vault = env::temp_dir().join("imgvault-template-vault");
+        let settings = Settings {
+            vault_root: Some(vault.display().to_string()),
+            ..Settings::default()
+        };
+        let rendered = vault.join(template).display().to_string();
+        assert_eq!(in_vault(template, &settings).expect("in vault"), rendered);
+
+        // The request's template wins over its path, which wins over the setting
+        let sent_path = "/elsewhere/x.mp4";
+        assert_eq!(resolve(Some(sent_path.to_string()), Some(template), &settings).expect("resolve"), rendered);
+        assert_eq!(resolve(Some(sent_path.to_string()), None, &settings).expect("resolve"), sent_path);
+        assert_eq!(
+            resolve(None, None, &settings).expect("resolve"),
+            vault.join(DEFAULT_OUTPUT_TEMPLATE).display().to_string()
+        );
+        resolve(None, Some("../%(id)s.%(ext)s"), &settings).expect_err("invalid template");
+    }
+}
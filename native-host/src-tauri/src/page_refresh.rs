@@ -0,0 +1,81 @@
+use serde_json::Value;
+use tracing::{debug, info};
+
+use crate::downloader::classify_download_error;
+use crate::history::History;
+use crate::settings::Settings;
+use crate::source_page::SourcePage;
+use crate::{logging, url_host};
+
+// Public suffixes of two labels, under which a site has three, e.g.
+// example.co.uk; there is no public suffix list to ask
+const TWO_LABEL_SUFFIXES: &[&str] = &[
+    "co.uk", "org.uk", "ac.uk", "gov.uk", "co.jp", "ne.jp", "or.jp", "com.au", "net.au", "org.au", "co.nz", "co.kr",
+    "co.in", "co.za", "com.br", "com.cn", "com.hk", "com.mx", "com.tr", "com.tw",
+];
+// What yt-dlp and the direct fetch say when a signed link ran out
+const EXPIRED_WORDS: &[&str] = &["http error 401", "http error 410", "expired", "signature"];
+
+// The page to download from instead of `url`, which failed with `message`:
+// only for an error that looks like an expired or refused link, when the
+// setting allows it and the page is on the same site as the media
+pub fn page_for(settings: &Settings, url: &str, source_page: &SourcePage, message: &str) -> Option<String> {
+    if !settings.refresh_expired_from_page || !looks_expired(message) {
+        return None;
+    }
+    let page_url = source_page.page_url.as_deref().filter(|page_url| *page_url != url)?;
+    let same_site = url_host(url)
+        .zip(url_host(page_url))
+        .is_some_and(|(media, page)| site(&media) == site(&page));
+    if !same_site {
+        debug!(
+            page_url = logging::loggable_url(page_url),
+            "Not refreshing the link from a page on another site"
+        );
+        return None;
+    }
+    Some(page_url.to_string())
+}
+
+// Claims the failed attempt `entry_id` for the refresh, which is one of
+// the job's auto_retry_max_attempts like any retry; false once they are
+// used up or another retry got there first
+pub fn claim(history: &History, settings: &Settings, entry_id: i64) -> Result<bool, String> {
+    let attempt = history.attempt(entry_id)?;
+    if attempt >= settings.auto_retry_max_attempts {
+        info!(attempt, "Expired download used up its retries");
+        return Ok(false);
+    }
+    history.claim_retry(entry_id)
+}
+
+// Adds refreshedFromPage to a response's data
+pub fn mark(data: &mut Option<Value>) {
+    match data {
+        Some(Value::Object(fields)) => {
+            fields.insert("refreshedFromPage".to_string(), Value::Bool(true));
+        }
+        _ => *data = Some(serde_json::json!({ "refreshedFromPage": true })),
+    }
+}
+
+fn looks_expired(message: &str) -> bool {
+    if matches!(classify_download_error(message), "http_403" | "auth_required") {
+        return true;
+    }
+    let message = message.to_lowercase();
+    EXPIRED_WORDS.iter().any(|word| message.contains(word))
+}
+
+// The registrable part of a host: cdn.example.com and example.com are both
+// example.com
+fn site(host: &str) -> String {
+    let labels = host.split('.').collect::<Vec<_>>();
+    let suffix_labels = if labels.len() >= 3 && TWO_LABEL_SUFFIXES.contains(&labels[labels.len() - 2..].join(".").as_str()) {
+        2
+    } else {
+        1
+    };
+    let keep = (suffix_labels + 1).min(labels.len());
+    labels[labels.len() - keep..].join(".")
+}
@@ -0,0 +1,206 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::downloader::spawn_output_reader;
+use crate::settings::Settings;
+use crate::{get_vault_directory, keychain, logging, webhooks};
+
+pub const DEFAULT_TIMEOUT_SECS: u64 = 60;
+pub const MAX_TIMEOUT_SECS: u64 = 3600;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Local program run after each successful download. Only ever read from the
+// settings file; native messages cannot supply or change it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PostDownloadCommand {
+    pub program: String,
+    // Each argument may contain {path}, {url}, {sha256} and {secret:<name>}; no shell is involved
+    pub args: Vec<String>,
+    pub timeout_secs: u64,
+}
+
+impl Default for PostDownloadCommand {
+    fn default() -> Self {
+        PostDownloadCommand {
+            program: String::new(),
+            args: vec!["{path}".to_string()],
+            timeout_secs: DEFAULT_TIMEOUT_SECS,
+        }
+    }
+}
+
+// Runs the configured command, if any, and returns a warning for the response
+// when it could not run or did not succeed. The download itself stays successful.
+pub fn run_after_download(settings: &Settings, job_id: &str, url: &str, file_path: Option<&str>) -> Option<String> {
+    let hook = settings.post_download_command.as_ref()?;
+    let Some(file_path) = file_path else {
+        warn!(job_id, "Post-download command skipped: the downloaded file path is unknown");
+        return Some("Post-download command skipped: the downloaded file path is unknown".to_string());
+    };
+
+    match run(hook, settings, job_id, url, file_path) {
+        Ok(()) => None,
+        Err(error) => {
+            warn!(job_id, "{}", error);
+            Some(error)
+        }
+    }
+}
+
+// One pass over the argument as configured: what a placeholder expands to is
+// never read again, so a URL from a web page cannot smuggle in a
+// {secret:...} reference, nor a file name a {url} of its own
+fn expand(arg: &str, file_path: &str, url: &str, sha256: &str) -> Result<String, String> {
+    let mut expanded = String::with_capacity(arg.len());
+    let mut rest = arg;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let placeholder = [("{path}", file_path), ("{url}", url), ("{sha256}", sha256)]
+            .into_iter()
+            .find(|(placeholder, _)| rest.starts_with(placeholder));
+        if let Some((placeholder, value)) = placeholder {
+            expanded.push_str(value);
+            rest = &rest[placeholder.len()..];
+        } else if rest.starts_with("{secret:") {
+            let end = rest.find('}').ok_or_else(|| "Unterminated {secret:...} reference".to_string())?;
+            expanded.push_str(&keychain::resolve_references(&rest[..=end])?);
+            rest = &rest[end + 1..];
+        } else {
+            expanded.push('{');
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn run(hook: &PostDownloadCommand, settings: &Settings, job_id: &str, url: &str, file_path: &str) -> Result<(), String> {
+    let sha256 = if hook.args.iter().any(|arg| arg.contains("{sha256}")) {
+        webhooks::hash_file(file_path)
+            .map(|(_, hash)| hash)
+            .map_err(|e| format!("Post-download command skipped: failed to hash {}: {}", file_path, e))?
+    } else {
+        String::new()
+    };
+    let args = hook
+        .args
+        .iter()
+        .map(|arg| expand(arg, file_path, url, &sha256))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Post-download command skipped: {}", e))?;
+
+    let working_directory = get_vault_directory(settings)?;
+    let mut command = Command::new(&hook.program);
+    command
+        .args(&args)
+        .current_dir(&working_directory)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    #[cfg(target_os = "windows")]
+    {
+        use std::os::windows::process::CommandExt;
+        const CREATE_NO_WINDOW: u32 = 0x08000000;
+        command.creation_flags(CREATE_NO_WINDOW);
+    }
+
+    info!(
+        job_id,
+        program = %hook.program,
+        url = logging::loggable_url(url),
+        "Running post-download command"
+    );
+    let mut child = command
+        .spawn()
+        .map_err(|e| format!("Failed to run post-download command {}: {}", hook.program, e))?;
+
+    let (tx, rx) = mpsc::channel::<(String, String)>();
+    if let Some(pipe) = child.stdout.take() {
+        spawn_output_reader(pipe, "stdout", tx.clone());
+    }
+    if let Some(pipe) = child.stderr.take() {
+        spawn_output_reader(pipe, "stderr", tx);
+    }
+
+    let program = Path::new(&hook.program)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| hook.program.clone());
+    let deadline = Instant::now() + Duration::from_secs(hook.timeout_secs);
+    let status = loop {
+        if let Ok((stream, line)) = rx.recv_timeout(POLL_INTERVAL) {
+            info!(job_id, stream = %stream, "[{}] {}", program, line);
+        }
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() >= deadline => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!(
+                    "Post-download command {} timed out after {} seconds and was stopped",
+                    program, hook.timeout_secs
+                ));
+            }
+            Ok(None) => {}
+            Err(e) => return Err(format!("Failed while waiting for post-download command: {}", e)),
+        }
+    };
+    // Output written just before exit
+    while let Ok((stream, line)) = rx.recv_timeout(POLL_INTERVAL) {
+        info!(job_id, stream = %stream, "[{}] {}", program, line);
+    }
+
+    if status.success() {
+        info!(job_id, "Post-download command finished");
+        Ok(())
+    } else {
+        Err(format!("Post-download command {} failed with exit code: {:?}", program, status.code()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    const SHA256: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    #[test]
+    fn each_placeholder_is_expanded_once() {
+        let cases = [
+            ("{path}", "/vault/a {url} b.png", "https://example.com/x", "/vault/a {url} b.png"),
+            (
+                "--file={path} --from={url}",
+                "/vault/{sha256}.png",
+                "https://example.com/{path}",
+                "--file=/vault/{sha256}.png --from=https://example.com/{path}",
+            ),
+            ("{sha256}.sum", "/vault/a.png", "https://example.com/x", "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08.sum"),
+            ("{url}", "/vault/a.png", "https://example.com/?q={secret:webhook}", "https://example.com/?q={secret:webhook}"),
+            ("{unknown} {path", "/vault/a.png", "https://example.com/x", "{unknown} {path"),
+            ("{{path}}", "/vault/a.png", "https://example.com/x", "{/vault/a.png}"),
+        ];
+        for (arg, file_path, url, expected) in cases {
+            assert_eq!(expand(arg, file_path, url, SHA256).as_deref(), Ok(expected), "{}", arg);
+        }
+    }
+
+    #[test]
+    fn a_secret_is_not_expanded_again() {
+        let _app_data = test_support::app_data();
+        test_support::memory_keychain();
+        keychain::set_secret("post-hook-token", "t0ken-{url}-{path}").expect("secret");
+
+        let expanded = expand("--token={secret:post-hook-token} {path}", "/vault/a.png", "https://example.com/x", SHA256);
+        assert_eq!(expanded.as_deref(), Ok("--token=t0ken-{url}-{path} /vault/a.png"));
+        assert!(expand("{secret:post-hook-token", "/vault/a.png", "https://example.com/x", SHA256).is_err());
+        assert!(expand("{secret:never-set}", "/vault/a.png", "https://example.com/x", SHA256).is_err());
+    }
+}
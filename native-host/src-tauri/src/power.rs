@@ -0,0 +1,168 @@
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+// Shown by `powercfg /requests` and its counterparts
+const REASON: &str = "ImgVault is downloading";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PreventSleep {
+    // Only system sleep is held off; the display still turns off
+    #[default]
+    WhileDownloading,
+    Never,
+}
+
+struct PowerState {
+    mode: PreventSleep,
+    active_jobs: usize,
+    request: Option<PowerRequest>,
+}
+
+// Replaced by the prevent_sleep setting once it is loaded; the jobs running in
+// this process decide whether a request is held
+static STATE: Mutex<PowerState> = Mutex::new(PowerState {
+    mode: PreventSleep::WhileDownloading,
+    active_jobs: 0,
+    request: None,
+});
+
+// Apply the prevent_sleep setting, like redact::apply_parameters
+pub fn apply_mode(mode: PreventSleep) {
+    let mut state = STATE.lock().unwrap();
+    state.mode = mode;
+    sync(&mut state);
+}
+
+// Called by the job registry whenever a job starts or ends
+pub fn set_active_jobs(count: usize) {
+    let mut state = STATE.lock().unwrap();
+    state.active_jobs = count;
+    sync(&mut state);
+}
+
+// Whether this process holds off system sleep right now, for diagnostics
+pub fn is_held() -> bool {
+    STATE.lock().unwrap().request.is_some()
+}
+
+fn sync(state: &mut PowerState) {
+    let wanted = state.mode == PreventSleep::WhileDownloading && state.active_jobs > 0;
+    match (wanted, state.request.is_some()) {
+        (true, false) => match PowerRequest::acquire() {
+            Ok(request) => {
+                info!(active_jobs = state.active_jobs, "Holding off system sleep while downloading");
+                state.request = Some(request);
+            }
+            Err(error) => warn!("Failed to hold off system sleep: {}", error),
+        },
+        (false, true) => {
+            state.request = None;
+            info!("Allowing system sleep again");
+        }
+        _ => {}
+    }
+}
+
+// A system-required power request, cleared when dropped
+#[cfg(target_os = "windows")]
+struct PowerRequest(usize);
+
+#[cfg(target_os = "windows")]
+impl PowerRequest {
+    fn acquire() -> Result<Self, String> {
+        use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+        use winapi::um::minwinbase::REASON_CONTEXT;
+        use winapi::um::winbase::{PowerCreateRequest, PowerSetRequest};
+        use winapi::um::winnt::{
+            PowerRequestExecutionRequired, PowerRequestSystemRequired, POWER_REQUEST_CONTEXT_SIMPLE_STRING,
+            POWER_REQUEST_CONTEXT_VERSION,
+        };
+
+        // PowerCreateRequest keeps a pointer to the string, so it lives as long as the process
+        let reason = Box::leak(crate::to_wide_null(REASON).into_boxed_slice());
+        unsafe {
+            let mut context: REASON_CONTEXT = std::mem::zeroed();
+            context.Version = POWER_REQUEST_CONTEXT_VERSION;
+            context.Flags = POWER_REQUEST_CONTEXT_SIMPLE_STRING;
+            *context.Reason.SimpleReasonString_mut() = reason.as_mut_ptr();
+            let handle = PowerCreateRequest(&mut context);
+            if handle == INVALID_HANDLE_VALUE {
+                return Err(format!("PowerCreateRequest failed: {}", std::io::Error::last_os_error()));
+            }
+            // Execution-required keeps a process running through modern standby;
+            // system-required keeps older machines from sleeping. Neither keeps the display on.
+            for request_type in [PowerRequestExecutionRequired, PowerRequestSystemRequired] {
+                if PowerSetRequest(handle, request_type) == 0 {
+                    let error = std::io::Error::last_os_error();
+                    CloseHandle(handle);
+                    return Err(format!("PowerSetRequest failed: {}", error));
+                }
+            }
+            Ok(PowerRequest(handle as usize))
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl Drop for PowerRequest {
+    fn drop(&mut self) {
+        use winapi::um::handleapi::CloseHandle;
+        use winapi::um::winbase::PowerClearRequest;
+        use winapi::um::winnt::{PowerRequestExecutionRequired, PowerRequestSystemRequired, HANDLE};
+
+        let handle = self.0 as HANDLE;
+        unsafe {
+            PowerClearRequest(handle, PowerRequestExecutionRequired);
+            PowerClearRequest(handle, PowerRequestSystemRequired);
+            CloseHandle(handle);
+        }
+    }
+}
+
+// caffeinate on macOS and systemd-inhibit on Linux hold off idle sleep for as
+// long as they run
+#[cfg(not(target_os = "windows"))]
+struct PowerRequest(std::process::Child);
+
+#[cfg(not(target_os = "windows"))]
+impl PowerRequest {
+    fn acquire() -> Result<Self, String> {
+        use std::process::{Command, Stdio};
+
+        let mut command;
+        #[cfg(target_os = "macos")]
+        {
+            command = Command::new("caffeinate");
+            command.arg("-i").arg("-w").arg(std::process::id().to_string());
+        }
+        #[cfg(not(target_os = "macos"))]
+        {
+            command = Command::new("systemd-inhibit");
+            command
+                .arg("--what=sleep")
+                .arg("--who=ImgVault")
+                .arg(format!("--why={}", REASON))
+                .arg("--mode=block")
+                // Ends with this process, should it never drop the request
+                .args(["tail", "-f", "/dev/null"])
+                .arg(format!("--pid={}", std::process::id()));
+        }
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map(PowerRequest)
+            .map_err(|e| format!("Failed to start {:?}: {}", command.get_program(), e))
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+impl Drop for PowerRequest {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
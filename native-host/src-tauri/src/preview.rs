@@ -0,0 +1,169 @@
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Condvar, Mutex};
+use std::time::UNIX_EPOCH;
+use tracing::debug;
+
+use crate::diagnostics::hide_console_window;
+use crate::downloader::IMAGE_EXTENSIONS;
+use crate::drop_import::THUMBNAIL_DIRECTORY;
+use crate::history::History;
+use crate::{get_app_data_directory, long_path};
+
+pub const DEFAULT_PREVIEW_SIZE: u32 = 256;
+const MIN_PREVIEW_SIZE: u32 = 16;
+const MAX_PREVIEW_SIZE: u32 = 1024;
+// Decoding a large image or a video frame takes a lot of memory, so only
+// this many run at once; the rest wait their turn
+const MAX_CONCURRENT_DECODES: usize = 2;
+const PREVIEW_DIRECTORY: &str = "previews";
+
+// Served for files ffmpeg cannot make a preview of
+pub const PLACEHOLDER_SVG: &str = concat!(
+    r##"<svg xmlns="http://www.w3.org/2000/svg" width="256" height="256" viewBox="0 0 256 256">"##,
+    r##"<rect width="256" height="256" fill="#e5e7eb"/>"##,
+    r##"<rect x="64" y="72" width="128" height="112" rx="8" fill="none" stroke="#9ca3af" stroke-width="8"/>"##,
+    r##"<circle cx="100" cy="108" r="12" fill="#9ca3af"/>"##,
+    r##"<path d="M72 176l40-40 28 28 20-20 32 32z" fill="#9ca3af"/>"##,
+    "</svg>"
+);
+
+static DECODES: Mutex<usize> = Mutex::new(0);
+static DECODE_FREED: Condvar = Condvar::new();
+
+// A decode slot, given back when dropped
+struct DecodeSlot;
+
+impl DecodeSlot {
+    fn acquire() -> Self {
+        let mut running = DECODES.lock().unwrap();
+        while *running >= MAX_CONCURRENT_DECODES {
+            running = DECODE_FREED.wait(running).unwrap();
+        }
+        *running += 1;
+        DecodeSlot
+    }
+}
+
+impl Drop for DecodeSlot {
+    fn drop(&mut self) {
+        *DECODES.lock().unwrap() -= 1;
+        DECODE_FREED.notify_one();
+    }
+}
+
+// The file a preview is made from and its ETag
+pub struct PreviewSource {
+    pub entry_id: i64,
+    pub path: PathBuf,
+    pub size: u32,
+    pub etag: String,
+}
+
+pub fn clamp_size(size: Option<u32>) -> u32 {
+    size.unwrap_or(DEFAULT_PREVIEW_SIZE).clamp(MIN_PREVIEW_SIZE, MAX_PREVIEW_SIZE)
+}
+
+// Only ever a path history recorded for `entry_id`; Err when there is no
+// such entry or its file is gone
+pub fn resolve(history: &History, entry_id: i64, size: u32) -> Result<PreviewSource, String> {
+    let file_path = history
+        .get(entry_id)?
+        .and_then(|entry| entry.file_path)
+        .ok_or_else(|| format!("History entry {} has no file", entry_id))?;
+    let path = PathBuf::from(file_path);
+    let metadata = fs::metadata(long_path::extended(&path))
+        .ok()
+        .filter(|metadata| metadata.is_file())
+        .ok_or_else(|| format!("The file of history entry {} is gone", entry_id))?;
+    // A changed file gets a new tag and so a new preview
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|modified| modified.as_nanos())
+        .unwrap_or_default();
+    let digest = Sha256::digest(format!("{}:{}:{}:{}", entry_id, size, metadata.len(), modified).as_bytes());
+    let etag = digest[..8].iter().map(|byte| format!("{:02x}", byte)).collect();
+    Ok(PreviewSource {
+        entry_id,
+        path,
+        size,
+        etag,
+    })
+}
+
+// A JPEG that fits in size x size, from the cache when it was made before
+pub fn render(source: &PreviewSource) -> Result<Vec<u8>, String> {
+    let directory = get_app_data_directory()?.join(PREVIEW_DIRECTORY);
+    let cached = directory.join(format!("{}-{}-{}.jpg", source.entry_id, source.size, source.etag));
+    if let Ok(bytes) = fs::read(&cached) {
+        return Ok(bytes);
+    }
+    fs::create_dir_all(&directory)
+        .map_err(|e| format!("Failed to create preview folder {}: {}", directory.display(), e))?;
+
+    let _slot = DecodeSlot::acquire();
+    let input = frame_source(source)?;
+    let partial = cached.with_extension("part.jpg");
+    let mut command = Command::new("ffmpeg");
+    command
+        .args(["-v", "error", "-y", "-i"])
+        .arg(long_path::simplified(&input))
+        .args(["-frames:v", "1", "-q:v", "5", "-vf"])
+        .arg(format!(
+            "scale='min({size},iw)':'min({size},ih)':force_original_aspect_ratio=decrease",
+            size = source.size
+        ))
+        .arg(&partial)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    hide_console_window(&mut command);
+
+    let output = command.output().map_err(|e| format!("Failed to run ffmpeg: {}", e))?;
+    if !output.status.success() {
+        let _ = fs::remove_file(&partial);
+        return Err(format!(
+            "ffmpeg failed on {}: {}",
+            input.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    fs::rename(&partial, &cached).map_err(|e| format!("Failed to save preview {}: {}", cached.display(), e))?;
+    remove_stale(&directory, source);
+    debug!(entry_id = source.entry_id, size = source.size, "Made preview");
+    fs::read(&cached).map_err(|e| format!("Failed to read preview {}: {}", cached.display(), e))
+}
+
+// Videos start from the frame an import saved, when there is one
+fn frame_source(source: &PreviewSource) -> Result<PathBuf, String> {
+    let is_image = source
+        .path
+        .extension()
+        .is_some_and(|extension| IMAGE_EXTENSIONS.contains(&extension.to_string_lossy().to_ascii_lowercase().as_str()));
+    if is_image {
+        return Ok(source.path.clone());
+    }
+    let thumbnail = get_app_data_directory()?
+        .join(THUMBNAIL_DIRECTORY)
+        .join(format!("{}.jpg", source.entry_id));
+    Ok(if thumbnail.is_file() { thumbnail } else { source.path.clone() })
+}
+
+// Previews of the same entry and size made from an older version of the file
+fn remove_stale(directory: &Path, source: &PreviewSource) {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return;
+    };
+    let prefix = format!("{}-{}-", source.entry_id, source.size);
+    let current = format!("{}{}.jpg", prefix, source.etag);
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with(&prefix) && name != current {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
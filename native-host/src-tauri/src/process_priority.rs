@@ -0,0 +1,139 @@
+use serde::{Deserialize, Serialize};
+use sysinfo::{Pid, System};
+
+// CPU and I/O priority of a download's child processes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProcessPriority {
+    #[default]
+    Normal,
+    // Below-normal CPU and low I/O priority, so a merge does not slow down
+    // whatever the user is working on
+    Background,
+}
+
+impl ProcessPriority {
+    pub fn from_setting(background_priority: bool) -> Self {
+        if background_priority {
+            ProcessPriority::Background
+        } else {
+            ProcessPriority::Normal
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ProcessPriority::Normal => "normal",
+            ProcessPriority::Background => "background",
+        }
+    }
+}
+
+// Sets the priority of `pid` and every process below it, e.g. yt-dlp and
+// the ffmpeg it merges with. Children started later inherit it on every
+// platform, so applying it right after spawning covers them too.
+pub fn apply(pid: u32, priority: ProcessPriority) -> Result<(), String> {
+    // Every process is tried; the first failure is the one reported
+    let applied = process_tree(pid)
+        .into_iter()
+        .map(|pid| set_process_priority(pid, priority))
+        .collect::<Vec<_>>();
+    applied.into_iter().collect()
+}
+
+fn process_tree(pid: u32) -> Vec<u32> {
+    let mut system = System::new();
+    system.refresh_processes();
+    let mut tree = vec![Pid::from_u32(pid)];
+    let mut index = 0;
+    while index < tree.len() {
+        let parent = tree[index];
+        let children = system
+            .processes()
+            .iter()
+            .filter(|(child, process)| process.parent() == Some(parent) && !tree.contains(*child))
+            .map(|(child, _)| *child)
+            .collect::<Vec<_>>();
+        tree.extend(children);
+        index += 1;
+    }
+    tree.into_iter().map(|pid| pid.as_u32()).collect()
+}
+
+#[cfg(target_os = "windows")]
+fn set_process_priority(pid: u32, priority: ProcessPriority) -> Result<(), String> {
+    use winapi::shared::ntdef::{HANDLE, NTSTATUS};
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::processthreadsapi::{OpenProcess, SetPriorityClass};
+    use winapi::um::winbase::{BELOW_NORMAL_PRIORITY_CLASS, NORMAL_PRIORITY_CLASS};
+    use winapi::um::winnt::PROCESS_SET_INFORMATION;
+
+    // PROCESS_INFORMATION_CLASS ProcessIoPriority and its IO_PRIORITY_HINT values
+    const PROCESS_IO_PRIORITY: u32 = 33;
+    const IO_PRIORITY_VERY_LOW: u32 = 0;
+    const IO_PRIORITY_NORMAL: u32 = 2;
+
+    #[link(name = "ntdll")]
+    extern "system" {
+        fn NtSetInformationProcess(process: HANDLE, class: u32, information: *const u32, length: u32) -> NTSTATUS;
+    }
+
+    let (class, io_priority) = match priority {
+        ProcessPriority::Normal => (NORMAL_PRIORITY_CLASS, IO_PRIORITY_NORMAL),
+        ProcessPriority::Background => (BELOW_NORMAL_PRIORITY_CLASS, IO_PRIORITY_VERY_LOW),
+    };
+    unsafe {
+        let process = OpenProcess(PROCESS_SET_INFORMATION, 0, pid);
+        if process.is_null() {
+            return Err(format!("Failed to open process {}: {}", pid, std::io::Error::last_os_error()));
+        }
+        let result = if SetPriorityClass(process, class) == 0 {
+            Err(format!("SetPriorityClass failed for {}: {}", pid, std::io::Error::last_os_error()))
+        } else {
+            let status = NtSetInformationProcess(process, PROCESS_IO_PRIORITY, &io_priority, 4);
+            if status < 0 {
+                Err(format!("Setting the I/O priority of {} failed with status {:#x}", pid, status))
+            } else {
+                Ok(())
+            }
+        };
+        CloseHandle(process);
+        result
+    }
+}
+
+// nice 10 and, on Linux, the lowest best-effort I/O level. Going back to
+// normal lowers the nice value, which most systems only allow root.
+#[cfg(unix)]
+fn set_process_priority(pid: u32, priority: ProcessPriority) -> Result<(), String> {
+    let nice = match priority {
+        ProcessPriority::Normal => 0,
+        ProcessPriority::Background => 10,
+    };
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS as _, pid as libc::id_t, nice) } != 0 {
+        return Err(format!("Failed to renice {}: {}", pid, std::io::Error::last_os_error()));
+    }
+    set_io_priority(pid, priority)
+}
+
+#[cfg(target_os = "linux")]
+fn set_io_priority(pid: u32, priority: ProcessPriority) -> Result<(), String> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_BE: libc::c_int = 2;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    let level = match priority {
+        ProcessPriority::Normal => 4,
+        ProcessPriority::Background => 7,
+    };
+    let value = (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | level;
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, pid as libc::c_int, value) } != 0 {
+        return Err(format!("Failed to set the I/O priority of {}: {}", pid, std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+// macOS only lets a process lower its own I/O policy
+#[cfg(all(unix, not(target_os = "linux")))]
+fn set_io_priority(_pid: u32, _priority: ProcessPriority) -> Result<(), String> {
+    Ok(())
+}
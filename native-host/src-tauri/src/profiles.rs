@@ -0,0 +1,488 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::{info, warn};
+
+use crate::history::{DownloadStatus, History};
+use crate::settings::Settings;
+use crate::{get_app_data_directory, get_vault_directory};
+
+// Keeps the app data locations every install had before profiles existed
+pub const DEFAULT_PROFILE: &str = "default";
+pub(crate) const PROFILES_DIRECTORY: &str = "profiles";
+const ACTIVE_PROFILE_FILE_NAME: &str = "profiles.json";
+const OVERRIDES_FILE_NAME: &str = "settings.json";
+const DOWNLOAD_ARCHIVE_FILE_NAME: &str = "download-archive.txt";
+const MAX_PROFILE_NAME_LENGTH: usize = 40;
+// What yt-dlp prints instead of downloading a video already in the archive
+const ARCHIVE_SKIP_MARKER: &str = "has already been recorded in the archive";
+pub const ARCHIVE_SKIP_MESSAGE: &str = "Already downloaded in this profile";
+
+// Settings a profile may set for itself; everything else is shared
+pub const OVERRIDABLE_FIELDS: &[&str] = &[
+    "vault_root",
+    "output_template",
+    "default_quality",
+    "notifications",
+    "post_download_command",
+    "s3_upload",
+    "site_logins",
+    "download_archive",
+    "split_chapters",
+    "user_agent",
+    "site_user_agents",
+];
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ActiveProfileFile {
+    active: Option<String>,
+}
+
+// A named vault with its own history database, download archive and
+// settings overrides
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub name: String,
+    overrides: Map<String, Value>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileInfo {
+    pub name: String,
+    pub active: bool,
+    // Where the profile's downloads go, with its overrides applied
+    pub vault_directory: Option<String>,
+    pub overrides: Map<String, Value>,
+}
+
+// What delete_profile removed
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeletedProfile {
+    pub name: String,
+    pub deleted_media_files: usize,
+}
+
+impl Profile {
+    pub fn default_profile() -> Self {
+        Profile {
+            name: DEFAULT_PROFILE.to_string(),
+            overrides: Map::new(),
+        }
+    }
+
+    // `name` None or "default" is the default profile
+    pub fn load(name: Option<&str>) -> Result<Self, String> {
+        let name = match name.map(str::trim) {
+            None | Some("") | Some(DEFAULT_PROFILE) => return Ok(Self::default_profile()),
+            Some(name) => name,
+        };
+        validate_name(name)?;
+        let directory = profile_directory(name)?;
+        if !directory.is_dir() {
+            return Err(format!("Profile \"{}\" does not exist", name));
+        }
+
+        let path = directory.join(OVERRIDES_FILE_NAME);
+        let overrides = match fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str::<Value>(&contents) {
+                Ok(Value::Object(overrides)) => overrides,
+                Ok(_) | Err(_) => {
+                    warn!("Ignoring invalid {}", path.display());
+                    Map::new()
+                }
+            },
+            Err(_) => Map::new(),
+        };
+        Ok(Profile {
+            name: name.to_string(),
+            overrides,
+        })
+    }
+
+    // The profile the GUI was last switched to; a missing or deleted one
+    // falls back to the default profile
+    pub fn active() -> Self {
+        let name = read_active_file().active;
+        match Self::load(name.as_deref()) {
+            Ok(profile) => profile,
+            Err(error) => {
+                warn!("{}; using the default profile", error);
+                Self::default_profile()
+            }
+        }
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.name == DEFAULT_PROFILE
+    }
+
+    // Holds the history database and download archive
+    pub fn directory(&self) -> Result<PathBuf, String> {
+        if self.is_default() {
+            get_app_data_directory()
+        } else {
+            profile_directory(&self.name)
+        }
+    }
+
+    pub fn open_history(&self) -> History {
+        History::open_in_directory(self.directory())
+    }
+
+    pub fn download_archive_path(&self) -> Result<PathBuf, String> {
+        Ok(self.directory()?.join(DOWNLOAD_ARCHIVE_FILE_NAME))
+    }
+
+    // The shared settings with this profile's overrides on top. Without its
+    // own vault_root a profile saves into a subfolder of the shared vault.
+    pub fn apply(&self, base: Settings) -> Settings {
+        if self.is_default() {
+            return base;
+        }
+
+        let mut settings = match serde_json::to_value(&base) {
+            Ok(Value::Object(mut merged)) => {
+                for (field, value) in &self.overrides {
+                    if OVERRIDABLE_FIELDS.contains(&field.as_str()) {
+                        merged.insert(field.clone(), value.clone());
+                    }
+                }
+                match serde_json::from_value::<Settings>(Value::Object(merged)) {
+                    Ok(settings) => settings,
+                    Err(error) => {
+                        warn!("Ignoring invalid overrides of profile \"{}\": {}", self.name, error);
+                        base.clone()
+                    }
+                }
+            }
+            _ => base.clone(),
+        };
+        if !self.overrides.contains_key("vault_root") {
+            settings.vault_root = get_vault_directory(&base)
+                .ok()
+                .map(|vault| vault.join(&self.name).display().to_string());
+        }
+        settings.profile = self.name.clone();
+        settings
+    }
+
+    fn info(&self, base: &Settings, active: &str) -> ProfileInfo {
+        let settings = self.apply(base.clone());
+        ProfileInfo {
+            name: self.name.clone(),
+            active: self.name == active,
+            vault_directory: get_vault_directory(&settings).ok().map(|vault| vault.display().to_string()),
+            overrides: self.overrides.clone(),
+        }
+    }
+}
+
+// The default profile first, then the others by name
+pub fn list(base: &Settings) -> Result<Vec<ProfileInfo>, String> {
+    let active = Profile::active().name;
+    let mut names = Vec::new();
+    if let Ok(entries) = fs::read_dir(get_app_data_directory()?.join(PROFILES_DIRECTORY)) {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if entry.path().is_dir() && validate_name(&name).is_ok() {
+                names.push(name);
+            }
+        }
+    }
+    names.sort();
+
+    let mut profiles = vec![Profile::default_profile().info(base, &active)];
+    for name in names {
+        match Profile::load(Some(&name)) {
+            Ok(profile) => profiles.push(profile.info(base, &active)),
+            Err(error) => warn!("{}", error),
+        }
+    }
+    Ok(profiles)
+}
+
+// `overrides` may only hold OVERRIDABLE_FIELDS and must leave valid settings
+pub fn create(base: &Settings, name: &str, overrides: Option<Value>) -> Result<ProfileInfo, String> {
+    let name = name.trim();
+    validate_name(name)?;
+    if name == DEFAULT_PROFILE {
+        return Err("The default profile always exists".to_string());
+    }
+    let overrides = match overrides {
+        None | Some(Value::Null) => Map::new(),
+        Some(Value::Object(overrides)) => overrides,
+        Some(_) => return Err("Profile overrides must be a JSON object".to_string()),
+    };
+    if let Some(field) = overrides.keys().find(|field| !OVERRIDABLE_FIELDS.contains(&field.as_str())) {
+        return Err(format!(
+            "{} cannot differ between profiles; allowed are {}",
+            field,
+            OVERRIDABLE_FIELDS.join(", ")
+        ));
+    }
+
+    let profile = Profile {
+        name: name.to_string(),
+        overrides,
+    };
+    let mut merged = serde_json::to_value(base).map_err(|e| format!("Failed to serialize settings: {}", e))?;
+    for (field, value) in &profile.overrides {
+        merged[field.as_str()] = value.clone();
+    }
+    let settings = serde_json::from_value::<Settings>(merged).map_err(|e| format!("Invalid overrides: {}", e))?;
+    if let Err(errors) = settings.validate() {
+        let messages = errors
+            .iter()
+            .filter(|error| profile.overrides.contains_key(&error.field))
+            .map(|error| format!("{}: {}", error.field, error.message))
+            .collect::<Vec<_>>();
+        if !messages.is_empty() {
+            return Err(format!("Invalid overrides: {}", messages.join("; ")));
+        }
+    }
+
+    let directory = profile_directory(name)?;
+    if directory.exists() {
+        return Err(format!("Profile \"{}\" already exists", name));
+    }
+    fs::create_dir_all(&directory)
+        .map_err(|e| format!("Failed to create profile folder {}: {}", directory.display(), e))?;
+    let contents = serde_json::to_string_pretty(&profile.overrides)
+        .map_err(|e| format!("Failed to serialize profile overrides: {}", e))?;
+    fs::write(directory.join(OVERRIDES_FILE_NAME), contents)
+        .map_err(|e| format!("Failed to save profile overrides: {}", e))?;
+    info!("Created profile \"{}\"", name);
+
+    Ok(profile.info(base, &Profile::active().name))
+}
+
+// Removes the profile's history, archive and overrides. Its downloaded files
+// stay unless `delete_media` is set, and then only the files its history
+// recorded inside its vault are deleted. `confirm_name` must repeat the
+// profile's name.
+pub fn delete(base: &Settings, name: &str, confirm_name: &str, delete_media: bool) -> Result<DeletedProfile, String> {
+    let profile = Profile::load(Some(name))?;
+    if profile.is_default() {
+        return Err("The default profile cannot be deleted".to_string());
+    }
+    if confirm_name.trim() != profile.name {
+        return Err(format!("Type \"{}\" to confirm deleting the profile", profile.name));
+    }
+    if Profile::active().name == profile.name {
+        return Err("Switch to another profile before deleting this one".to_string());
+    }
+
+    let mut deleted_media_files = 0;
+    if delete_media {
+        // Resolved, so neither "..", a symlink nor a moved vault reaches outside it
+        let vault = get_vault_directory(&profile.apply(base.clone()))?;
+        let vault = fs::canonicalize(&vault).unwrap_or(vault);
+        let history = profile.open_history();
+        let completed = history
+            .all()?
+            .into_iter()
+            .filter(|entry| entry.status == DownloadStatus::Completed.as_str())
+            .filter_map(|entry| entry.file_path);
+        for path in completed {
+            let Ok(path) = fs::canonicalize(Path::new(&path)) else {
+                continue;
+            };
+            if !path.is_file() {
+                continue;
+            }
+            if !path.starts_with(&vault) {
+                warn!("Keeping {}, which is outside the vault {}", path.display(), vault.display());
+                continue;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => deleted_media_files += 1,
+                Err(error) => warn!("Failed to delete {}: {}", path.display(), error),
+            }
+        }
+    }
+
+    let directory = profile.directory()?;
+    fs::remove_dir_all(&directory)
+        .map_err(|e| format!("Failed to delete profile folder {}: {}", directory.display(), e))?;
+    info!(deleted_media_files, "Deleted profile \"{}\"", profile.name);
+
+    Ok(DeletedProfile {
+        name: profile.name,
+        deleted_media_files,
+    })
+}
+
+// Lets yt-dlp skip videos the settings' profile downloaded before, when
+// download_archive is on
+pub fn add_download_archive_argument(command: &mut Command, settings: &Settings) {
+    if !settings.download_archive {
+        return;
+    }
+    let path = match Profile::load(Some(&settings.profile)).and_then(|profile| profile.download_archive_path()) {
+        Ok(path) => path,
+        Err(error) => {
+            warn!("Downloading without an archive: {}", error);
+            return;
+        }
+    };
+    command.arg("--download-archive").arg(path);
+}
+
+// "<extractor> <id>" lines of the settings' profile's download archive, as
+// yt-dlp writes them; empty when download_archive is off or nothing is in it
+pub fn archived_ids(settings: &Settings) -> HashSet<String> {
+    if !settings.download_archive {
+        return HashSet::new();
+    }
+    Profile::load(Some(&settings.profile))
+        .and_then(|profile| profile.download_archive_path())
+        .ok()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .map(|contents| contents.lines().map(|line| line.trim().to_string()).filter(|line| !line.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+pub fn skipped_by_archive(stdout: &str) -> bool {
+    stdout.contains(ARCHIVE_SKIP_MARKER)
+}
+
+// Records the GUI's profile; native messages and the CLI name their own
+pub fn set_active(profile: &Profile) -> Result<(), String> {
+    let path = get_app_data_directory()?.join(ACTIVE_PROFILE_FILE_NAME);
+    let file = ActiveProfileFile {
+        active: Some(profile.name.clone()),
+    };
+    let contents = serde_json::to_string_pretty(&file).map_err(|e| format!("Failed to serialize active profile: {}", e))?;
+    fs::write(&path, contents).map_err(|e| format!("Failed to save {}: {}", path.display(), e))?;
+    info!("Switched to profile \"{}\"", profile.name);
+    Ok(())
+}
+
+fn read_active_file() -> ActiveProfileFile {
+    let Ok(path) = get_app_data_directory().map(|directory| directory.join(ACTIVE_PROFILE_FILE_NAME)) else {
+        return ActiveProfileFile::default();
+    };
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+fn profile_directory(name: &str) -> Result<PathBuf, String> {
+    Ok(get_app_data_directory()?.join(PROFILES_DIRECTORY).join(name))
+}
+
+// Names are folder names, so only letters, digits, '-' and '_'
+fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_PROFILE_NAME_LENGTH {
+        return Err(format!("Profile names must be 1 to {} characters", MAX_PROFILE_NAME_LENGTH));
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err("Profile names may only contain letters, digits, '-' and '_'".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::NewHistoryEntry;
+    use crate::test_support;
+
+    fn base(vault: &Path) -> Settings {
+        Settings {
+            vault_root: Some(vault.display().to_string()),
+            ..Settings::default()
+        }
+    }
+
+    // Saves a file at `path` and records it in the profile's history
+    fn download(profile: &Profile, path: &Path) {
+        fs::create_dir_all(path.parent().expect("parent")).expect("folder");
+        fs::write(path, b"a picture").expect("download");
+        let file_path = path.display().to_string();
+        profile
+            .open_history()
+            .record(&NewHistoryEntry {
+                job_id: &file_path,
+                url: "https://example.com/picture.png",
+                file_path: Some(&file_path),
+                status: DownloadStatus::Completed,
+                message: None,
+                source: "test",
+                started_at: 0,
+                finished_at: 0,
+                object_key: None,
+                etag: None,
+                output_path: None,
+                upload: false,
+                page_url: None,
+                page_title: None,
+                media_info: None,
+                transfer: None,
+                session_id: None,
+                supervision: None,
+            })
+            .expect("history entry");
+    }
+
+    #[test]
+    fn deleting_needs_the_name_repeated_and_another_profile_active() {
+        let app_data = test_support::app_data();
+        let base = base(&app_data.directory.join("vault"));
+        create(&base, "travel", None).expect("create");
+        let directory = profile_directory("travel").expect("directory");
+
+        for confirm_name in ["", "Travel", "work"] {
+            let error = delete(&base, "travel", confirm_name, false).expect_err(confirm_name);
+            assert!(error.contains("Type \"travel\""), "{}", error);
+        }
+        set_active(&Profile::load(Some("travel")).expect("load")).expect("switch");
+        let error = delete(&base, "travel", "travel", false).expect_err("active");
+        assert!(error.contains("Switch to another profile"), "{}", error);
+        assert!(directory.is_dir());
+
+        set_active(&Profile::default_profile()).expect("switch");
+        delete(&base, "travel", " travel ", false).expect("delete");
+        assert!(!directory.exists());
+        assert!(delete(&base, DEFAULT_PROFILE, DEFAULT_PROFILE, false).is_err());
+    }
+
+    #[test]
+    fn media_is_kept_unless_asked_for_and_never_outside_the_vault() {
+        let app_data = test_support::app_data();
+        let vault = app_data.directory.join("vault");
+        let base = base(&vault);
+        let kept = vault.join("keep").join("kept.png");
+        let saved = vault.join("purge").join("album").join("saved.png");
+        let outside = [
+            app_data.directory.join("elsewhere.png"),
+            vault.join("keep").join("neighbour.png"),
+        ];
+
+        create(&base, "keep", None).expect("create");
+        download(&Profile::load(Some("keep")).expect("load"), &kept);
+        let deleted = delete(&base, "keep", "keep", false).expect("delete");
+        assert_eq!(deleted.deleted_media_files, 0);
+        assert!(kept.is_file());
+
+        create(&base, "purge", None).expect("create");
+        let purge = Profile::load(Some("purge")).expect("load");
+        download(&purge, &saved);
+        download(&purge, &outside[0]);
+        // Recorded through the profile's own vault, but resolving into another's
+        download(&purge, &vault.join("purge").join("..").join("keep").join("neighbour.png"));
+        let deleted = delete(&base, "purge", "purge", true).expect("delete");
+        assert_eq!(deleted.deleted_media_files, 1);
+        assert!(!saved.exists());
+        assert!(kept.is_file());
+        for path in &outside {
+            assert!(path.is_file(), "{}", path.display());
+        }
+    }
+}
@@ -0,0 +1,378 @@
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::io;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::warn;
+
+use crate::capture::Capture;
+use crate::dispatcher::Priority;
+use crate::download_phase::PhaseProgress;
+use crate::downloader::Backend;
+use crate::events::EventFilter;
+use crate::jobs::JobRegistry;
+use crate::native_stdout::FrameWriter;
+use crate::organize::MediaHints;
+use crate::stall::Stall;
+use crate::{crash, frame_limit, i18n, metrics, native_schema, schedule};
+
+// Messages read ahead of the message loop
+pub(crate) const INCOMING_FRAMES: usize = 16;
+// How long queued responses get to reach Chrome once the session is over
+pub(crate) const WRITER_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NativeMessage {
+    pub action: String,
+    pub url: Option<String>,
+    pub output_path: Option<String>,
+    // yt-dlp template inside the vault; wins over output_path, and the
+    // configured one is used when neither is sent
+    pub output_template: Option<String>,
+    pub cookies_data: Option<Vec<BrowserCookie>>,
+    pub request_id: Option<String>,
+    // `false` skips the configured upload stage for this download
+    pub upload: Option<bool>,
+    // RFC 3339 start time for download, or the new one for reschedule
+    pub schedule_at: Option<String>,
+    // low, normal or high; the new one for set_priority
+    pub priority: Option<Priority>,
+    // Zero-based position among waiting jobs for reorder
+    pub new_index: Option<usize>,
+    // The tab the download was started from; page_url doubles as the referer
+    // unless one is given
+    pub page_url: Option<String>,
+    pub page_title: Option<String>,
+    pub referer: Option<String>,
+    // Profile whose vault, history and settings the message uses; the
+    // default profile when missing
+    pub profile: Option<String>,
+    // yt_dlp, http_image, gallery_dl or fake; picked from the URL when missing
+    pub backend: Option<Backend>,
+    // Skips the media policy for this download, only when the settings allow
+    // overrides
+    pub ignore_policy: Option<bool>,
+    // tags, mime_type, dimensions and uploader, for the organization rules
+    #[serde(flatten)]
+    pub hints: MediaHints,
+    // Runs the download even when an identical one is already in flight,
+    // instead of following that one
+    pub force_new: Option<bool>,
+    // Language of the response messages, e.g. "de" or "de-AT"; the locale
+    // setting when missing or without a catalog
+    pub locale: Option<String>,
+    // Job ids and domains whose events subscribe delivers; every job when missing
+    pub filter: Option<EventFilter>,
+    // Overrides the split_chapters setting for this download
+    pub split_chapters: Option<bool>,
+    // false skips marking this download with its origin, whatever the settings say
+    pub tag_origin: Option<bool>,
+    // For archive_channel: how many of the newest items to look at, and the
+    // upload day (YYYY-MM-DD) older items are left out before
+    pub max_items: Option<u32>,
+    pub date_after: Option<String>,
+    // User agent for this download, ahead of the per-site and default ones;
+    // "browser" sends browser_user_agent, the extension's navigator.userAgent
+    pub user_agent: Option<String>,
+    pub browser_user_agent: Option<String>,
+    // For get_events: the last sequence number the caller has seen, and how
+    // many events to return at most
+    pub since_seq: Option<u64>,
+    pub limit: Option<usize>,
+    // For register_external_file: where the browser saved a download the
+    // host left to it, under the request_id of that download
+    pub file_path: Option<String>,
+    // Answers a message that breaks the schema with every problem in it,
+    // instead of ignoring unknown keys; sent with ping, it holds for the session
+    pub strict: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NativeResponse {
+    pub success: bool,
+    pub event: Option<String>,
+    #[serde(rename = "requestId")]
+    pub request_id: Option<String>,
+    pub message: Option<String>,
+    pub line: Option<String>,
+    pub stream: Option<String>,
+    #[serde(rename = "filePath")]
+    pub file_path: Option<String>,
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+    // Structured payload for actions whose result is more than a message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+    // The host's own English wording once `message` has been localized
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl NativeResponse {
+    // Frame about one job; the WebSocket stream sends the same frames as native messaging
+    pub(crate) fn job_event(event: &str, request_id: Option<&str>) -> Self {
+        NativeResponse {
+            success: true,
+            event: Some(event.to_string()),
+            request_id: request_id.map(|value| value.to_string()),
+            message: None,
+            line: None,
+            stream: None,
+            file_path: None,
+            stdout: None,
+            stderr: None,
+            error_code: None,
+            data: None,
+            detail: None,
+        }
+    }
+
+    // The complete event of a request that failed; error_code when the cause is known
+    pub(crate) fn failure(request_id: Option<&str>, error_code: Option<&str>, message: String) -> Self {
+        NativeResponse {
+            success: false,
+            message: Some(message),
+            error_code: error_code.map(str::to_string),
+            ..NativeResponse::job_event("complete", request_id)
+        }
+    }
+
+    // Upload phase of a job, reported after yt-dlp has finished
+    pub(crate) fn upload_progress(request_id: Option<&str>, uploaded_bytes: u64, total_bytes: u64) -> Self {
+        let percent = (uploaded_bytes * 100).checked_div(total_bytes).unwrap_or(100);
+        NativeResponse {
+            line: Some(format!("[upload] {}% of {} bytes", percent, total_bytes)),
+            stream: Some("upload".to_string()),
+            data: Some(serde_json::json!({
+                "phase": "upload",
+                "uploadedBytes": uploaded_bytes,
+                "totalBytes": total_bytes,
+            })),
+            ..NativeResponse::job_event("progress", request_id)
+        }
+    }
+
+    // Acknowledges a job waiting on the schedule; its lifecycle events follow once it runs
+    pub(crate) fn scheduled(download: &schedule::ScheduledDownload, locale: &str) -> Self {
+        NativeResponse {
+            message: Some(i18n::text(locale, "scheduled", &[("time", &download.scheduled_at)])),
+            data: serde_json::to_value(download).ok(),
+            ..NativeResponse::job_event("scheduled", Some(&download.job_id))
+        }
+    }
+
+    // `phase` is set for lines that move the download along or start a new phase
+    pub(crate) fn progress(request_id: Option<&str>, stream: String, line: String, phase: Option<PhaseProgress>) -> Self {
+        NativeResponse {
+            data: phase.and_then(|phase| serde_json::to_value(phase).ok()),
+            line: Some(line),
+            stream: Some(stream),
+            ..NativeResponse::job_event("progress", request_id)
+        }
+    }
+
+    pub(crate) fn stalled(request_id: Option<&str>, stall: &Stall) -> Self {
+        NativeResponse {
+            message: Some(stall.describe()),
+            data: serde_json::to_value(stall).ok(),
+            ..NativeResponse::job_event("stalled", request_id)
+        }
+    }
+
+    // Follows the progress frame of the first line with this yt-dlp warning
+    pub(crate) fn warning(request_id: Option<&str>, warning: String) -> Self {
+        NativeResponse {
+            message: Some(warning),
+            stream: Some("stderr".to_string()),
+            ..NativeResponse::job_event("warning", request_id)
+        }
+    }
+
+    // Replaces the message of an error with its catalog text in `locale`;
+    // the original moves to `detail`, and error_code stays as it is
+    pub(crate) fn localized(mut self, locale: &str) -> Self {
+        if let Some(text) = self.error_code.as_deref().and_then(|code| i18n::lookup(locale, code)) {
+            self.detail = self.message.replace(text.to_string());
+        }
+        self
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BrowserCookie {
+    pub domain: String,
+    pub host_only: bool,
+    pub path: String,
+    pub secure: bool,
+    pub expiration_date: i64,
+    pub name: String,
+    pub value: String,
+}
+
+pub(crate) fn internal_error_response(msg: &str, payload: &(dyn Any + Send)) -> NativeResponse {
+    // Best effort: the panic may have come from a message that parsed fine
+    let request = serde_json::from_str::<serde_json::Value>(msg).ok();
+    let field = |name: &str| {
+        request
+            .as_ref()
+            .and_then(|value| value[name].as_str())
+            .map(|value| value.to_string())
+    };
+
+    NativeResponse {
+        success: false,
+        event: Some("complete".to_string()),
+        request_id: field("request_id"),
+        message: Some(format!(
+            "Internal error while handling {}: {}",
+            field("action").unwrap_or_else(|| "message".to_string()),
+            crash::panic_message(payload)
+        )),
+        line: None,
+        stream: None,
+        file_path: None,
+        stdout: None,
+        stderr: None,
+        error_code: Some("internal_error".to_string()),
+        data: None,
+        detail: None,
+    }
+}
+
+// Large responses may go out as several frames; see frame_limit
+pub(crate) fn send_native_response(stdout: &FrameWriter, response: &NativeResponse) -> Result<(), String> {
+    let serializing = Instant::now();
+    let frames = frame_limit::frames(response)?;
+    metrics::response_serialized(serializing.elapsed());
+    for frame in frames {
+        stdout
+            .write_frame(frame.as_bytes())
+            .map_err(|e| format!("Failed to write response: {}", e))?;
+    }
+    Ok(())
+}
+
+// A frame about a job this port started, also fanned out to WebSocket clients
+// and subscribed ports
+pub(crate) fn send_job_frame(stdout: &FrameWriter, jobs: &JobRegistry, response: &NativeResponse) -> Result<(), String> {
+    jobs.events().publish(response);
+    send_native_response(stdout, response)
+}
+
+// What the reader task hands the message loop
+pub enum IncomingFrame {
+    Message(String),
+    // A frame that arrived whole but cannot be a message, e.g. zero-length;
+    // answered with an error and the session carries on
+    Invalid(String),
+}
+
+// Why the reader task stopped
+#[derive(Debug)]
+pub enum PortEnd {
+    // EOF at a frame boundary: Chrome closed the port
+    Closed,
+    // The stream ended inside a frame, so nothing after it can be trusted
+    Truncated(String),
+}
+
+// The answer to a strict message that parses but breaks the schema; None
+// for a lenient one, a valid one, or one that is not JSON at all
+pub fn strict_schema_response(msg: &str, strict_session: bool) -> Option<NativeResponse> {
+    let raw = serde_json::from_str::<serde_json::Value>(msg).ok()?;
+    if !native_schema::is_strict(&raw, strict_session) {
+        return None;
+    }
+    let errors = native_schema::validate(&raw);
+    if errors.is_empty() {
+        return None;
+    }
+    let request_id = raw.get("request_id").and_then(serde_json::Value::as_str);
+    warn!(
+        request_id = request_id.unwrap_or(""),
+        errors = errors.len(),
+        "Message does not match the schema"
+    );
+    let message = errors.iter().map(ToString::to_string).collect::<Vec<_>>().join("; ");
+    let locale = i18n::resolve(raw.get("locale").and_then(serde_json::Value::as_str));
+    Some(
+        NativeResponse {
+            data: Some(serde_json::json!({ "errors": errors })),
+            ..NativeResponse::failure(request_id, Some("invalid_schema"), format!("Message does not match the schema: {}", message))
+        }
+        .localized(&locale),
+    )
+}
+
+// The answer to a frame that is not a message
+pub fn invalid_message_response(error: String) -> NativeResponse {
+    NativeResponse::failure(None, Some("invalid_message"), error).localized(&i18n::resolve(None))
+}
+
+// Forwards each length-prefixed message from stdin until the stream ends
+pub async fn read_native_messages(
+    input: &mut (impl AsyncRead + Unpin),
+    messages: &tokio::sync::mpsc::Sender<IncomingFrame>,
+    capture: Option<&Capture>,
+) -> PortEnd {
+    loop {
+        // Read message length (4 bytes, native byte order)
+        let mut length_bytes = [0u8; 4];
+        match read_until_eof(input, &mut length_bytes).await {
+            Ok(0) => return PortEnd::Closed,
+            Ok(4) => {}
+            Ok(read) => return PortEnd::Truncated(format!("Frame header cut off after {} of 4 bytes", read)),
+            Err(error) => {
+                warn!("Failed to read from the native messaging port: {}", error);
+                return PortEnd::Closed;
+            }
+        }
+        let message_length = u32::from_ne_bytes(length_bytes) as usize;
+
+        // Read message content
+        let mut message_buffer = vec![0u8; message_length];
+        match read_until_eof(input, &mut message_buffer).await {
+            Ok(read) if read == message_length => {}
+            Ok(read) => {
+                return PortEnd::Truncated(format!("Frame body cut off after {} of {} bytes", read, message_length))
+            }
+            Err(error) => {
+                warn!("Failed to read from the native messaging port: {}", error);
+                return PortEnd::Closed;
+            }
+        }
+
+        let frame = if message_length == 0 {
+            IncomingFrame::Invalid("Received an empty message".to_string())
+        } else {
+            match String::from_utf8(message_buffer) {
+                Ok(msg) => IncomingFrame::Message(msg),
+                Err(_) => IncomingFrame::Invalid("Received a message that is not UTF-8".to_string()),
+            }
+        };
+        if let Some(capture) = capture {
+            capture.record_in(&frame);
+        }
+        // Waits while the message loop is busy, so Chrome is held back
+        // rather than its messages piling up here
+        if messages.send(frame).await.is_err() {
+            return PortEnd::Closed;
+        }
+    }
+}
+
+// Fills `buffer` unless the stream ends first; returns how many bytes arrived
+pub async fn read_until_eof(input: &mut (impl AsyncRead + Unpin), buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match input.read(&mut buffer[filled..]).await {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(filled)
+}
@@ -0,0 +1,105 @@
+use tracing::{info, warn};
+
+use crate::dispatcher::Priority;
+use crate::jobs::{CancelOutcome, JobRegistry};
+use crate::protocol::NativeResponse;
+use crate::settings::Settings;
+use crate::{bandwidth, coalesce, connectivity, current_timestamp_millis, dispatcher, i18n, schedule};
+
+pub fn cancel_job(jobs: &JobRegistry, job_id: &str) -> Result<String, String> {
+    // A job still waiting for its start time only needs to leave the schedule
+    match schedule::remove(job_id) {
+        Ok(true) => {
+            coalesce::release_queued(job_id);
+            info!(job_id, "Scheduled download removed");
+            return Ok(format!("Scheduled download {} removed", job_id));
+        }
+        Ok(false) => {}
+        Err(error) => warn!(job_id, "{}", error),
+    }
+    // Held back by a concurrency or domain limit; its host process notices
+    // the missing slot entry and reports the cancellation
+    match dispatcher::withdraw(job_id) {
+        Ok(true) => {
+            info!(job_id, "Waiting download cancelled");
+            return Ok(format!("Waiting download {} cancelled", job_id));
+        }
+        Ok(false) => {}
+        Err(error) => warn!(job_id, "{}", error),
+    }
+    match jobs.cancel(job_id)? {
+        CancelOutcome::Cancelled => Ok(format!("Stop signal sent for request {}", job_id)),
+        CancelOutcome::NotRunning => Ok(format!("No active download for request {}", job_id)),
+    }
+}
+
+// Tell event stream clients about a scheduled job and let the timer re-plan
+pub fn announce_scheduled(jobs: &JobRegistry, download: &schedule::ScheduledDownload) {
+    jobs.events().publish(&NativeResponse::scheduled(download, &i18n::resolve(None)));
+    jobs.scheduler().wake();
+}
+
+// Running, waiting and scheduled jobs plus the bandwidth limit, shared by
+// get_queue and GET /jobs. Waiting jobs carry the limit that holds them back.
+pub fn queue_snapshot(jobs: &JobRegistry, settings: &Settings) -> serde_json::Value {
+    serde_json::json!({
+        "paused": jobs.is_paused(),
+        "offline": connectivity::is_offline(),
+        "jobs": jobs.list(),
+        "waiting": dispatcher::waiting().unwrap_or_default(),
+        // Epoch millis per domain before which no job for it starts
+        "nextAllowed": dispatcher::next_allowed().unwrap_or_default(),
+        "scheduled": schedule::list().unwrap_or_default(),
+        "bandwidth": bandwidth::status(settings),
+    })
+}
+
+// Stop dispatching and stop running downloads, keeping their partial files
+pub fn pause_queue_jobs(jobs: &JobRegistry) -> String {
+    let stopped = jobs.pause();
+    info!(stopped, "Queue paused");
+    format!("Queue paused; {} running download(s) stopped", stopped)
+}
+
+// Restart paused downloads in the order they were first queued
+pub fn resume_queue_jobs(jobs: &JobRegistry) -> Result<String, String> {
+    jobs.set_paused(false);
+    let resumed = schedule::resume_paused()?;
+    jobs.scheduler().wake();
+    info!(resumed, "Queue resumed");
+    Ok(format!("Queue resumed; {} paused download(s) restarting", resumed))
+}
+
+// Tell queue views that a waiting job moved
+fn announce_reordered(jobs: &JobRegistry, slot: &dispatcher::SlotEntry) {
+    jobs.events().publish(&NativeResponse {
+        data: serde_json::to_value(slot).ok(),
+        ..NativeResponse::job_event("reordered", Some(&slot.job_id))
+    });
+}
+
+// Waiting processes re-read the queue file on their next poll, so the move
+// takes effect on the next dispatch decision
+pub fn reorder_queued_job(jobs: &JobRegistry, job_id: &str, new_index: usize) -> Result<(), String> {
+    let slot = dispatcher::reorder(job_id, new_index)?;
+    info!(job_id, new_index, "Waiting download moved");
+    announce_reordered(jobs, &slot);
+    Ok(())
+}
+
+pub fn set_queued_job_priority(jobs: &JobRegistry, job_id: &str, priority: Priority) -> Result<(), String> {
+    match dispatcher::set_priority(job_id, priority) {
+        Ok(slot) => announce_reordered(jobs, &slot),
+        // Not due yet; the priority applies once the timer starts it
+        Err(error) => match schedule::set_priority(job_id, priority)? {
+            Some(download) => announce_scheduled(jobs, &download),
+            None => return Err(error),
+        },
+    }
+    info!(job_id, priority = ?priority, "Download priority changed");
+    Ok(())
+}
+
+pub fn generate_job_id(prefix: &str) -> String {
+    format!("{}-{}", prefix, current_timestamp_millis())
+}
@@ -0,0 +1,371 @@
+use serde::Serialize;
+#[cfg(target_os = "windows")]
+use std::fs;
+#[cfg(target_os = "windows")]
+use std::path::Path;
+use std::path::PathBuf;
+#[cfg(target_os = "windows")]
+use tracing::warn;
+
+use crate::get_app_data_directory;
+#[cfg(target_os = "windows")]
+use crate::{deep_link, NATIVE_HOST_NAME};
+
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+// Per-user registry parents of the Chromium browsers the host can register with
+#[cfg(target_os = "windows")]
+pub(crate) const BROWSER_REGISTRY_PATHS: &[(&str, &str)] = &[
+    ("chrome", r"Software\Google\Chrome\NativeMessagingHosts"),
+    ("edge", r"Software\Microsoft\Edge\NativeMessagingHosts"),
+    ("brave", r"Software\BraveSoftware\Brave-Browser\NativeMessagingHosts"),
+    ("chromium", r"Software\Chromium\NativeMessagingHosts"),
+];
+
+// Where registration writes the native messaging manifest that every browser's
+// registry key points to. Builds before the migration kept it beside the exe.
+pub fn native_manifest_path() -> Result<PathBuf, String> {
+    Ok(get_app_data_directory()?.join(MANIFEST_FILE_NAME))
+}
+
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArtifactKind {
+    // Shared by every browser
+    Manifest,
+    // One per browser, pointing at the manifest
+    RegistryKey,
+    // imgvault:// links; registration works without it
+    UrlScheme,
+    // What the cleanup lists besides registration: our Run value
+    Autostart,
+    // The Send to ImgVault menu
+    ShellIntegration,
+    Settings,
+    // History, its backups and the profiles holding their own
+    Database,
+    Logs,
+    // yt-dlp and the other tools the host downloaded for itself
+    ManagedTool,
+    // Anything else in the app data folder, e.g. caches and reports
+    AppData,
+    // Only the files history recorded, never the folder itself
+    Vault,
+}
+
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArtifactStatus {
+    Created,
+    Removed,
+    // Left in place because another browser still uses it
+    Kept,
+    // Not there to remove
+    Missing,
+    // Created, then undone because a later artifact failed
+    RolledBack,
+    Failed,
+    // Not attempted after an earlier failure
+    Skipped,
+    // Listed by a cleanup preview; nothing was done
+    Planned,
+}
+
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Artifact {
+    pub kind: ArtifactKind,
+    // None for the artifacts all browsers share
+    pub browser: Option<String>,
+    // File path or HKCU registry path
+    pub location: String,
+    // A failed optional artifact does not fail the registration
+    pub required: bool,
+    pub status: ArtifactStatus,
+    pub error: Option<String>,
+}
+
+// Everything register_host or unregister_host touched, in the order they were
+// done, so the GUI can show what exists and what needs attention
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationReport {
+    pub success: bool,
+    pub artifacts: Vec<Artifact>,
+}
+
+impl RegistrationReport {
+    // The errors of the artifacts that failed, for callers that only need
+    // to know whether it worked
+    pub fn into_result(self) -> Result<Self, String> {
+        if self.success {
+            return Ok(self);
+        }
+        let errors = self
+            .artifacts
+            .iter()
+            .filter_map(|artifact| artifact.error.as_deref())
+            .collect::<Vec<_>>();
+        Err(errors.join("; "))
+    }
+}
+
+// What a registration for `browser` consists of, in the order it is created.
+// Unregistering removes the same artifacts in reverse.
+#[cfg(target_os = "windows")]
+fn artifacts(browser: &str) -> Result<Vec<Artifact>, String> {
+    let parent_path = browser_registry_path(browser)?;
+    let planned = |kind, browser: Option<&str>, location: String, required| Artifact {
+        kind,
+        browser: browser.map(str::to_string),
+        location,
+        required,
+        status: ArtifactStatus::Skipped,
+        error: None,
+    };
+    Ok(vec![
+        planned(ArtifactKind::Manifest, None, native_manifest_path()?.display().to_string(), true),
+        planned(
+            ArtifactKind::RegistryKey,
+            Some(browser),
+            format!(r"HKCU\{}\{}", parent_path, NATIVE_HOST_NAME),
+            true,
+        ),
+        planned(ArtifactKind::UrlScheme, None, format!(r"HKCU\{}", deep_link::SCHEME_REGISTRY_PATH), false),
+    ])
+}
+
+// Everything a registration with any browser may have created, each shared
+// artifact once, so a cleanup finds what registrations left behind
+pub fn host_artifacts() -> Result<Vec<Artifact>, String> {
+    #[cfg(target_os = "windows")]
+    {
+        let mut all: Vec<Artifact> = Vec::new();
+        for (browser, _) in BROWSER_REGISTRY_PATHS {
+            for artifact in artifacts(browser)? {
+                if !all.iter().any(|listed| listed.location == artifact.location) {
+                    all.push(artifact);
+                }
+            }
+        }
+        Ok(all)
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    Ok(Vec::new())
+}
+
+#[cfg(target_os = "windows")]
+fn browser_registry_path(browser: &str) -> Result<&'static str, String> {
+    BROWSER_REGISTRY_PATHS
+        .iter()
+        .find(|(name, _)| *name == browser)
+        .map(|(_, path)| *path)
+        .ok_or_else(|| format!("Unsupported browser: {}", browser))
+}
+
+// Err only when nothing could be attempted; a failed artifact is reported in
+// the result, after the ones created before it are rolled back
+pub fn register_native_host(extension_id: &str, browser: &str) -> Result<RegistrationReport, String> {
+    #[cfg(target_os = "windows")]
+    {
+        use winreg::enums::HKEY_CURRENT_USER;
+        use winreg::RegKey;
+
+        let exe_path = std::env::current_exe()
+            .map_err(|e| format!("Failed to get executable path: {}", e))?;
+        let mut artifacts = artifacts(browser)?;
+        let manifest_path = native_manifest_path()?;
+        let key_path = format!(r"{}\{}", browser_registry_path(browser)?, NATIVE_HOST_NAME);
+        let hkcu = RegKey::predef(HKEY_CURRENT_USER);
+
+        // What was there before, restored on rollback
+        let previous_manifest = fs::read(&manifest_path).ok();
+        let previous_key = hkcu.open_subkey(&key_path).ok().map(|key| key.get_value::<String, _>("").ok());
+
+        for index in 0..artifacts.len() {
+            let result = match artifacts[index].kind {
+                ArtifactKind::Manifest => write_manifest(&manifest_path, &exe_path, extension_id),
+                ArtifactKind::RegistryKey => write_registry_key(&hkcu, &key_path, &manifest_path, previous_key.is_some()),
+                ArtifactKind::UrlScheme => deep_link::register_scheme(),
+                kind => Err(format!("{:?} is not part of a registration", kind)),
+            };
+            let artifact = &mut artifacts[index];
+            match result {
+                Ok(()) => artifact.status = ArtifactStatus::Created,
+                Err(error) => {
+                    artifact.status = ArtifactStatus::Failed;
+                    artifact.error = Some(error);
+                }
+            }
+            if artifact.status == ArtifactStatus::Failed && artifact.required {
+                break;
+            }
+            // Links still work without the scheme
+            if artifact.status == ArtifactStatus::Failed {
+                warn!("{}", artifact.error.as_deref().unwrap_or_default());
+            }
+        }
+
+        let success = !artifacts
+            .iter()
+            .any(|artifact| artifact.required && artifact.status == ArtifactStatus::Failed);
+        if !success {
+            for artifact in artifacts.iter_mut().rev().filter(|artifact| artifact.status == ArtifactStatus::Created) {
+                let undone = match artifact.kind {
+                    ArtifactKind::Manifest => restore_manifest(&manifest_path, previous_manifest.as_deref()),
+                    ArtifactKind::RegistryKey => restore_registry_key(&hkcu, &key_path, previous_key.as_ref()),
+                    ArtifactKind::UrlScheme => deep_link::unregister_scheme(),
+                    kind => Err(format!("{:?} is not part of a registration", kind)),
+                };
+                match undone {
+                    Ok(()) => artifact.status = ArtifactStatus::RolledBack,
+                    Err(error) => {
+                        warn!("Failed to roll back {}: {}", artifact.location, error);
+                        artifact.error = Some(format!("Could not be rolled back: {}", error));
+                    }
+                }
+            }
+        }
+        Ok(RegistrationReport { success, artifacts })
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    {
+        let _ = (extension_id, browser);
+        Err("Registration only supported on Windows".to_string())
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn write_manifest(manifest_path: &Path, exe_path: &Path, extension_id: &str) -> Result<(), String> {
+    if let Some(directory) = manifest_path.parent() {
+        fs::create_dir_all(directory).map_err(|e| format!("Failed to create app data directory: {}", e))?;
+    }
+    let allowed_origin = format!("chrome-extension://{}/", extension_id);
+
+    let manifest_content = serde_json::json!({
+        "name": NATIVE_HOST_NAME,
+        "description": "ImgVault Native Messaging Host",
+        "path": exe_path.display().to_string(),
+        "type": "stdio",
+        "allowed_origins": [
+            allowed_origin
+        ]
+    });
+
+    let text = serde_json::to_string_pretty(&manifest_content).map_err(|e| format!("Failed to serialize manifest: {}", e))?;
+    fs::write(manifest_path, text).map_err(|e| format!("Failed to write manifest: {}", e))
+}
+
+#[cfg(target_os = "windows")]
+fn restore_manifest(manifest_path: &Path, previous: Option<&[u8]>) -> Result<(), String> {
+    match previous {
+        Some(contents) => fs::write(manifest_path, contents).map_err(|e| format!("Failed to restore manifest: {}", e)),
+        None => fs::remove_file(manifest_path).map_err(|e| format!("Failed to delete manifest: {}", e)),
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn write_registry_key(hkcu: &winreg::RegKey, key_path: &str, manifest_path: &Path, existed: bool) -> Result<(), String> {
+    let (key, _) = hkcu
+        .create_subkey(key_path)
+        .map_err(|e| format!("Failed to create registry key: {}", e))?;
+    key.set_value("", &manifest_path.display().to_string()).map_err(|error| {
+        // Leave no empty key behind for the browser to trip over
+        if !existed {
+            let _ = hkcu.delete_subkey(key_path);
+        }
+        format!("Failed to set registry value: {}", error)
+    })
+}
+
+// `previous` is None when the key did not exist, Some(None) when it had no value
+#[cfg(target_os = "windows")]
+fn restore_registry_key(hkcu: &winreg::RegKey, key_path: &str, previous: Option<&Option<String>>) -> Result<(), String> {
+    match previous {
+        None => hkcu
+            .delete_subkey(key_path)
+            .map_err(|e| format!("Failed to delete registry key: {}", e)),
+        Some(value) => {
+            let (key, _) = hkcu
+                .create_subkey(key_path)
+                .map_err(|e| format!("Failed to open registry key: {}", e))?;
+            match value {
+                Some(value) => key.set_value("", value),
+                None => key.delete_value(""),
+            }
+            .map_err(|e| format!("Failed to restore registry value: {}", e))
+        }
+    }
+}
+
+// Removes the artifacts register_native_host creates, last first. The manifest
+// and the URL scheme stay while another browser is still registered.
+pub fn unregister_native_host(browser: &str) -> Result<RegistrationReport, String> {
+    #[cfg(target_os = "windows")]
+    {
+        use winreg::enums::{HKEY_CURRENT_USER, KEY_WRITE};
+        use winreg::RegKey;
+
+        let mut artifacts = artifacts(browser)?;
+        let manifest_path = native_manifest_path()?;
+        let parent_path = browser_registry_path(browser)?;
+        let hkcu = RegKey::predef(HKEY_CURRENT_USER);
+        let other_registered = BROWSER_REGISTRY_PATHS.iter().any(|(name, parent)| {
+            *name != browser && hkcu.open_subkey(format!(r"{}\{}", parent, NATIVE_HOST_NAME)).is_ok()
+        });
+
+        for artifact in artifacts.iter_mut().rev() {
+            let shared = artifact.browser.is_none();
+            let result = if shared && other_registered {
+                Ok(ArtifactStatus::Kept)
+            } else {
+                match artifact.kind {
+                    ArtifactKind::Manifest => match fs::remove_file(&manifest_path) {
+                        Ok(()) => Ok(ArtifactStatus::Removed),
+                        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(ArtifactStatus::Missing),
+                        Err(error) => Err(format!("Failed to delete manifest: {}", error)),
+                    },
+                    ArtifactKind::RegistryKey => match hkcu.open_subkey_with_flags(parent_path, KEY_WRITE) {
+                        Ok(parent_key) => match parent_key.delete_subkey(NATIVE_HOST_NAME) {
+                            Ok(()) => Ok(ArtifactStatus::Removed),
+                            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(ArtifactStatus::Missing),
+                            Err(error) => Err(format!("Failed to delete registry key: {}", error)),
+                        },
+                        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(ArtifactStatus::Missing),
+                        Err(error) => Err(format!("Failed to open parent registry key: {}", error)),
+                    },
+                    ArtifactKind::UrlScheme => deep_link::unregister_scheme().map(|()| ArtifactStatus::Removed),
+                    kind => Err(format!("{:?} is not part of a registration", kind)),
+                }
+            };
+            match result {
+                Ok(status) => artifact.status = status,
+                Err(error) => {
+                    artifact.status = ArtifactStatus::Failed;
+                    artifact.error = Some(error);
+                }
+            }
+            // The manifest stays while a browser may still point at it
+            if artifact.status == ArtifactStatus::Failed && artifact.required {
+                break;
+            }
+        }
+
+        let success = !artifacts
+            .iter()
+            .any(|artifact| artifact.required && artifact.status == ArtifactStatus::Failed);
+        Ok(RegistrationReport { success, artifacts })
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    {
+        let _ = browser;
+        Err("Unregistration only supported on Windows".to_string())
+    }
+}
@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use sysinfo::{Disks, System};
+use tracing::info;
+
+use crate::downloader::Backend;
+use crate::jobs::JobRegistry;
+use crate::protocol::NativeResponse;
+use crate::settings::Settings;
+
+pub const MAX_MIN_FREE_MEMORY_MB: u64 = 64 * 1024;
+pub const MAX_MIN_FREE_TEMP_MB: u64 = 1024 * 1024;
+const BYTES_PER_MB: u64 = 1024 * 1024;
+// Waiting jobs look every half second; memory and disks need not be read that often
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+// Free memory and temp space against min_free_memory_mb and min_free_temp_mb
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Pressure {
+    // None when it could not be read, which never counts as low
+    pub available_memory_mb: Option<u64>,
+    pub free_temp_mb: Option<u64>,
+    pub low_memory: bool,
+    pub low_disk: bool,
+}
+
+impl Pressure {
+    pub fn is_under(&self) -> bool {
+        self.low_memory || self.low_disk
+    }
+
+    pub fn describe(&self) -> String {
+        let mut low = Vec::new();
+        if self.low_memory {
+            low.push(format!("{} MB of memory", self.available_memory_mb.unwrap_or_default()));
+        }
+        if self.low_disk {
+            low.push(format!("{} MB of temp space", self.free_temp_mb.unwrap_or_default()));
+        }
+        if low.is_empty() {
+            return "Enough memory and temp space; downloads run at the usual concurrency".to_string();
+        }
+        format!(
+            "Only {} free; running one download at a time and holding back merges",
+            low.join(" and ")
+        )
+    }
+}
+
+struct Sample {
+    at: Instant,
+    available_memory_mb: Option<u64>,
+    free_temp_mb: Option<u64>,
+}
+
+static LAST_SAMPLE: Mutex<Option<Sample>> = Mutex::new(None);
+// Whether this process last announced pressure, so resource_pressure events
+// only mark changes
+static ANNOUNCED: Mutex<Option<bool>> = Mutex::new(None);
+
+// The thresholds are read on every call; the measurements are a few seconds old at most
+pub fn sample(settings: &Settings) -> Pressure {
+    let (available_memory_mb, free_temp_mb) = {
+        let mut last = LAST_SAMPLE.lock().unwrap();
+        match &*last {
+            Some(sample) if sample.at.elapsed() < SAMPLE_INTERVAL => (sample.available_memory_mb, sample.free_temp_mb),
+            _ => {
+                let sample = Sample {
+                    at: Instant::now(),
+                    available_memory_mb: available_memory_mb(),
+                    free_temp_mb: free_temp_mb(),
+                };
+                let measured = (sample.available_memory_mb, sample.free_temp_mb);
+                *last = Some(sample);
+                measured
+            }
+        }
+    };
+    Pressure {
+        available_memory_mb,
+        free_temp_mb,
+        low_memory: below(available_memory_mb, settings.min_free_memory_mb),
+        low_disk: below(free_temp_mb, settings.min_free_temp_mb),
+    }
+}
+
+// A threshold of 0 is off
+fn below(free_mb: Option<u64>, threshold_mb: u64) -> bool {
+    threshold_mb > 0 && free_mb.is_some_and(|free_mb| free_mb < threshold_mb)
+}
+
+fn available_memory_mb() -> Option<u64> {
+    let mut system = System::new();
+    system.refresh_memory();
+    // 0 where the platform does not report it
+    Some(system.available_memory() / BYTES_PER_MB).filter(|available| *available > 0)
+}
+
+// The disk holding the temp folder is the one with the longest mount point above it
+fn free_temp_mb() -> Option<u64> {
+    let temp = env::temp_dir();
+    let temp = temp.canonicalize().unwrap_or(temp);
+    let disks = Disks::new_with_refreshed_list();
+    disks
+        .list()
+        .iter()
+        .filter(|disk| temp.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+        .map(|disk| disk.available_space() / BYTES_PER_MB)
+}
+
+// Publishes a resource_pressure event when the state changed since the last
+// one from this process
+pub fn announce(jobs: &JobRegistry, pressure: &Pressure) {
+    let under = pressure.is_under();
+    {
+        let mut announced = ANNOUNCED.lock().unwrap();
+        // Nothing to say about a machine that was never short
+        if *announced == Some(under) || (announced.is_none() && !under) {
+            return;
+        }
+        *announced = Some(under);
+    }
+    let message = pressure.describe();
+    info!(
+        available_memory_mb = pressure.available_memory_mb,
+        free_temp_mb = pressure.free_temp_mb,
+        "{}",
+        message
+    );
+    jobs.events().publish(&NativeResponse {
+        message: Some(message),
+        data: serde_json::to_value(pressure).ok(),
+        ..NativeResponse::job_event("resource_pressure", None)
+    });
+}
+
+// yt-dlp merging separate video and audio, or cutting chapters, runs ffmpeg
+// over the whole file next to the download
+pub fn is_merge_heavy(backend: Backend, format_selector: &str, settings: &Settings) -> bool {
+    backend == Backend::YtDlp && (format_selector.contains('+') || settings.split_chapters)
+}
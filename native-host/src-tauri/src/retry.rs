@@ -0,0 +1,134 @@
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::connectivity;
+use crate::dispatcher::Priority;
+use crate::downloader::{classify_download_error, DownloadContext};
+use crate::history::{History, RetryCandidate};
+use crate::jobs::JobRegistry;
+use crate::queue::announce_scheduled;
+use crate::schedule::ScheduledDownload;
+use crate::settings::{Settings, SettingsStore};
+use crate::source_page::SourcePage;
+use crate::{current_timestamp_millis, get_vault_directory, schedule, timestamps};
+
+// Failures that tend to go away on their own, such as a connection dropped
+// while the laptop slept or a NAS that rebooted. Missing files, logins and
+// yt-dlp itself do not.
+const RETRYABLE_ERROR_CODES: &[&str] = &["network_error", "rate_limited", "stalled", "destination_unavailable"];
+// The first retry waits this long after the failure, each later one twice as long
+const BASE_BACKOFF_MS: i64 = 5 * 60 * 1000;
+const MAX_BACKOFF_DOUBLINGS: u32 = 16;
+
+// Looks for failed downloads to retry right away and then every
+// auto_retry_interval_minutes, for the life of the GUI process. Retries go
+// through the schedule queue, so the scheduler's timer starts them.
+pub fn start(history: History, settings: SettingsStore, jobs: JobRegistry) {
+    std::thread::spawn(move || loop {
+        let current = settings.get();
+        if current.auto_retry_failed {
+            match queue_due_retries(&history, &current, &jobs) {
+                Ok(0) => {}
+                Ok(queued) => info!(queued, "Queued failed downloads for retry"),
+                Err(error) => warn!("Failed to check failed downloads for retry: {}", error),
+            }
+        }
+        std::thread::sleep(Duration::from_secs(current.auto_retry_interval_minutes.max(1) as u64 * 60));
+    });
+}
+
+fn queue_due_retries(history: &History, settings: &Settings, jobs: &JobRegistry) -> Result<usize, String> {
+    // Retrying now would only fail again; they come due once the network is back
+    if connectivity::is_offline() {
+        return Ok(0);
+    }
+    let now = current_timestamp_millis();
+    let window_ms = settings.auto_retry_window_hours as i64 * 60 * 60 * 1000;
+    let mut queued = 0;
+
+    for candidate in history.retry_candidates(now - window_ms)? {
+        let error_code = classify_download_error(candidate.message.as_deref().unwrap_or(""));
+        if !RETRYABLE_ERROR_CODES.contains(&error_code)
+            || candidate.attempt >= settings.auto_retry_max_attempts
+            || now < next_retry_at(&candidate)
+        {
+            continue;
+        }
+        // Another GUI instance may be looking at the same history
+        if !history.claim_retry(candidate.id)? {
+            continue;
+        }
+
+        let output_path = match &candidate.output_path {
+            Some(output_path) => output_path.clone(),
+            // Recorded before output paths were kept
+            None => get_vault_directory(settings)?.join("%(title)s [%(id)s].%(ext)s").display().to_string(),
+        };
+        // The page is referer again; an explicit referer was not kept
+        let source_page = SourcePage::new(candidate.page_url.clone(), candidate.page_title.clone(), None);
+        let context = DownloadContext {
+            job_id: &candidate.job_id,
+            url: &candidate.url,
+            output_path: &output_path,
+            upload: candidate.upload,
+            // Retries yield to anything the user starts meanwhile
+            priority: Priority::Low,
+            source_page: &source_page,
+            source: "retry",
+        };
+        let added = schedule::add(&context, &timestamps::format_rfc3339(now));
+        match added {
+            Ok(download) => {
+                info!(
+                    job_id = %candidate.job_id,
+                    attempt = candidate.attempt + 1,
+                    error_code,
+                    "Retrying failed download"
+                );
+                announce_scheduled(jobs, &download);
+                queued += 1;
+            }
+            Err(error) => {
+                debug!(job_id = %candidate.job_id, "Retry not queued: {}", error);
+                history.release_retry(candidate.id)?;
+            }
+        }
+    }
+    Ok(queued)
+}
+
+// Queues a job stopped by stall detection again right away, as the next
+// attempt of the history entry just recorded for it. None once the job used
+// up auto_retry_max_attempts.
+pub fn restart_stalled(
+    history: &History,
+    settings: &Settings,
+    entry_id: i64,
+    context: &DownloadContext,
+) -> Result<Option<ScheduledDownload>, String> {
+    let job_id = context.job_id;
+    let attempt = history.attempt(entry_id)?;
+    if attempt >= settings.auto_retry_max_attempts {
+        info!(job_id, attempt, "Stalled download used up its retries");
+        return Ok(None);
+    }
+    if !history.claim_retry(entry_id)? {
+        return Ok(None);
+    }
+    let now = timestamps::format_rfc3339(current_timestamp_millis());
+    let context = DownloadContext { source: "retry", ..*context };
+    match schedule::add(&context, &now) {
+        Ok(download) => {
+            info!(job_id, attempt = attempt + 1, "Restarting stalled download");
+            Ok(Some(download))
+        }
+        Err(error) => {
+            history.release_retry(entry_id)?;
+            Err(error)
+        }
+    }
+}
+
+fn next_retry_at(candidate: &RetryCandidate) -> i64 {
+    candidate.finished_at + BASE_BACKOFF_MS * (1_i64 << candidate.attempt.min(MAX_BACKOFF_DOUBLINGS))
+}
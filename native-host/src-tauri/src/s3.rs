@@ -0,0 +1,477 @@
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+use crate::{keychain, timestamps};
+
+// Default keychain entries for the credentials; never written to settings.json
+const ACCESS_KEY_ID_SECRET: &str = "s3_access_key_id";
+const SECRET_ACCESS_KEY_SECRET: &str = "s3_secret_access_key";
+// Files larger than one part go up as a multipart upload so a failure only
+// repeats that part; every request body is buffered so it can be signed and retried
+const PART_SIZE: usize = 16 * 1024 * 1024;
+const MAX_RETRIES: u32 = 3;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(120);
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct S3UploadSettings {
+    // Service URL, e.g. https://minio.example.com:9000
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    // Folder inside the bucket; {year}, {month}, {day} and {job_id} are filled in
+    pub prefix_template: String,
+    // Bucket in the path instead of the host name, which MinIO expects by default
+    pub path_style: bool,
+    pub delete_local_after_upload: bool,
+    // Names of the keychain secrets holding the credentials
+    pub access_key_id_secret: String,
+    pub secret_access_key_secret: String,
+}
+
+impl Default for S3UploadSettings {
+    fn default() -> Self {
+        S3UploadSettings {
+            endpoint: String::new(),
+            region: "us-east-1".to_string(),
+            bucket: String::new(),
+            prefix_template: "imgvault/{year}/{month}/".to_string(),
+            path_style: true,
+            delete_local_after_upload: false,
+            access_key_id_secret: ACCESS_KEY_ID_SECRET.to_string(),
+            secret_access_key_secret: SECRET_ACCESS_KEY_SECRET.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadedObject {
+    pub bucket: String,
+    pub key: String,
+    pub etag: String,
+}
+
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+}
+
+// Read from the keychain for each upload, so changed keys apply to the next job
+fn load_credentials(config: &S3UploadSettings) -> Result<Credentials, String> {
+    match (
+        keychain::get_secret(&config.access_key_id_secret)?,
+        keychain::get_secret(&config.secret_access_key_secret)?,
+    ) {
+        (Some(access_key_id), Some(secret_access_key)) => Ok(Credentials {
+            access_key_id,
+            secret_access_key,
+        }),
+        _ => Err(format!(
+            "S3 credentials are not set; store them as the {} and {} secrets",
+            config.access_key_id_secret, config.secret_access_key_secret
+        )),
+    }
+}
+
+// Bucket naming rules shared by AWS and MinIO
+pub fn validate_bucket_name(bucket: &str) -> Result<(), String> {
+    if !(3..=63).contains(&bucket.len()) {
+        return Err("Must be between 3 and 63 characters".to_string());
+    }
+    if !bucket
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '-')
+    {
+        return Err("May only contain lowercase letters, digits, dots and hyphens".to_string());
+    }
+    if !bucket.starts_with(|c: char| c.is_ascii_alphanumeric()) || !bucket.ends_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Err("Must start and end with a letter or digit".to_string());
+    }
+    Ok(())
+}
+
+// Streams the file to the bucket and returns where it ended up. `on_progress`
+// receives the bytes sent so far and the file size.
+pub fn upload_file(
+    config: &S3UploadSettings,
+    job_id: &str,
+    file_path: &str,
+    on_progress: &mut dyn FnMut(u64, u64),
+) -> Result<UploadedObject, String> {
+    let path = Path::new(file_path);
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .ok_or_else(|| format!("Cannot upload {}: not a file", file_path))?;
+    let size = fs::metadata(path)
+        .map_err(|e| format!("Failed to read {}: {}", file_path, e))?
+        .len();
+    let key = object_key(config, job_id, &file_name);
+    let client = Client::new(config, load_credentials(config)?)?;
+
+    info!(job_id, bucket = %config.bucket, key = %key, size, "Uploading to S3");
+    let mut file = File::open(path).map_err(|e| format!("Failed to open {}: {}", file_path, e))?;
+    let etag = if size > PART_SIZE as u64 {
+        client.multipart_upload(&key, &mut file, size, on_progress)?
+    } else {
+        let mut body = Vec::with_capacity(size as usize);
+        file.read_to_end(&mut body)
+            .map_err(|e| format!("Failed to read {}: {}", file_path, e))?;
+        let response = client.send(&Request::new("PUT", &key, &[], "Upload"), &body, 0, size, on_progress)?;
+        response_etag(&response)?
+    };
+    info!(job_id, key = %key, etag = %etag, "Upload to S3 complete");
+
+    Ok(UploadedObject {
+        bucket: config.bucket.clone(),
+        key,
+        etag,
+    })
+}
+
+fn object_key(config: &S3UploadSettings, job_id: &str, file_name: &str) -> String {
+    let (year, month, day) = timestamps::civil_from_days(unix_seconds() as i64 / 86_400);
+    let mut prefix = config
+        .prefix_template
+        .replace("{year}", &format!("{:04}", year))
+        .replace("{month}", &format!("{:02}", month))
+        .replace("{day}", &format!("{:02}", day))
+        .replace("{job_id}", job_id)
+        .trim_start_matches('/')
+        .to_string();
+    if !prefix.is_empty() && !prefix.ends_with('/') {
+        prefix.push('/');
+    }
+    prefix + file_name
+}
+
+struct Client<'a> {
+    config: &'a S3UploadSettings,
+    credentials: Credentials,
+    agent: ureq::Agent,
+    scheme: String,
+    // host[:port] exactly as ureq sends it in the Host header, which is signed
+    authority: String,
+}
+
+impl<'a> Client<'a> {
+    fn new(config: &'a S3UploadSettings, credentials: Credentials) -> Result<Self, String> {
+        let (scheme, rest) = config
+            .endpoint
+            .split_once("://")
+            .ok_or_else(|| format!("Invalid S3 endpoint {}", config.endpoint))?;
+        let scheme = scheme.to_ascii_lowercase();
+        let authority = rest.split('/').next().unwrap_or(rest);
+        let authority = match scheme.as_str() {
+            "https" => authority.strip_suffix(":443").unwrap_or(authority),
+            _ => authority.strip_suffix(":80").unwrap_or(authority),
+        };
+        let authority = if config.path_style {
+            authority.to_string()
+        } else {
+            format!("{}.{}", config.bucket, authority)
+        };
+
+        Ok(Client {
+            config,
+            credentials,
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(CONNECT_TIMEOUT)
+                .timeout_read(READ_TIMEOUT)
+                .user_agent(&format!("ImgVault/{}", env!("CARGO_PKG_VERSION")))
+                .build(),
+            scheme,
+            authority,
+        })
+    }
+
+    fn multipart_upload(
+        &self,
+        key: &str,
+        file: &mut File,
+        size: u64,
+        on_progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<String, String> {
+        let response = self.send(&Request::new("POST", key, &[("uploads", "")], "Starting multipart upload"), &[], 0, size, &mut |_, _| {})?;
+        let body = response
+            .into_string()
+            .map_err(|e| format!("Failed to read S3 response: {}", e))?;
+        let upload_id = xml_value(&body, "UploadId")
+            .ok_or("S3 did not return a multipart upload id")?
+            .to_string();
+
+        let result = self.upload_parts(key, &upload_id, file, size, on_progress);
+        if result.is_err() {
+            // Otherwise the bucket keeps the orphaned parts around
+            if let Err(error) = self.send(&Request::new("DELETE", key, &[("uploadId", &upload_id)], "Aborting multipart upload"), &[], 0, 0, &mut |_, _| {}) {
+                warn!(key, "{}", error);
+            }
+        }
+        result
+    }
+
+    fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        file: &mut File,
+        size: u64,
+        on_progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<String, String> {
+        let mut buffer = vec![0u8; PART_SIZE];
+        let mut parts = Vec::new();
+        let mut uploaded = 0u64;
+
+        loop {
+            let read = read_full(file, &mut buffer).map_err(|e| format!("Failed to read file for upload: {}", e))?;
+            if read == 0 {
+                break;
+            }
+            let part_number = (parts.len() + 1).to_string();
+            debug!(key, part = %part_number, "Uploading part");
+            let query = [("partNumber", part_number.as_str()), ("uploadId", upload_id)];
+            let response = self.send(&Request::new("PUT", key, &query, "Uploading part"), &buffer[..read], uploaded, size, on_progress)?;
+            parts.push((part_number, response_etag(&response)?));
+            uploaded += read as u64;
+        }
+
+        let mut manifest = String::from("<CompleteMultipartUpload>");
+        for (part_number, etag) in &parts {
+            manifest.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>\"{}\"</ETag></Part>",
+                part_number, etag
+            ));
+        }
+        manifest.push_str("</CompleteMultipartUpload>");
+
+        let query = [("uploadId", upload_id)];
+        let request = Request::new("POST", key, &query, "Completing multipart upload");
+        let response = self.send(&request, manifest.as_bytes(), size, size, &mut |_, _| {})?;
+        let body = response
+            .into_string()
+            .map_err(|e| format!("Failed to read S3 response: {}", e))?;
+        // S3 may answer 200 and still report a failure in the body
+        if let Some(code) = xml_value(&body, "Code") {
+            return Err(format!("S3 rejected the multipart upload: {}", code));
+        }
+        xml_value(&body, "ETag")
+            .map(|etag| etag.replace("&quot;", "").trim_matches('"').to_string())
+            .ok_or_else(|| "S3 did not return an ETag for the upload".to_string())
+    }
+
+    // One signed request, retried with backoff on network errors, 408, 429 and 5xx
+    fn send(
+        &self,
+        request: &Request,
+        body: &[u8],
+        sent_before: u64,
+        total: u64,
+        on_progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<ureq::Response, String> {
+        let Request { method, key, query, action } = *request;
+        let payload_hash = format!("{:x}", Sha256::digest(body));
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 0;
+        loop {
+            let reader = ProgressReader {
+                remaining: body,
+                sent: sent_before,
+                total,
+                on_progress: &mut *on_progress,
+            };
+            let result = self
+                .signed_request(method, key, query, &payload_hash)
+                .set("Content-Length", &body.len().to_string())
+                .send(reader);
+
+            let error = match result {
+                Ok(response) => return Ok(response),
+                Err(ureq::Error::Status(status, response)) => {
+                    let detail = response
+                        .into_string()
+                        .ok()
+                        .and_then(|body| xml_value(&body, "Message").or(xml_value(&body, "Code")).map(|value| value.to_string()))
+                        .unwrap_or_default();
+                    let message = format!("{} failed with HTTP {} {}", action, status, detail);
+                    if !(status == 408 || status == 429 || status >= 500) {
+                        return Err(message.trim_end().to_string());
+                    }
+                    message
+                }
+                Err(ureq::Error::Transport(error)) => format!("{} failed: {}", action, error),
+            };
+
+            attempt += 1;
+            if attempt > MAX_RETRIES {
+                return Err(error.trim_end().to_string());
+            }
+            debug!(key, attempt, "{}; retrying", error.trim_end());
+            std::thread::sleep(backoff);
+            backoff *= 2;
+        }
+    }
+
+    // AWS Signature Version 4
+    fn signed_request(&self, method: &str, key: &str, query: &[(&str, &str)], payload_hash: &str) -> ureq::Request {
+        let canonical_uri = if self.config.path_style {
+            format!("/{}/{}", self.config.bucket, uri_encode(key, false))
+        } else {
+            format!("/{}", uri_encode(key, false))
+        };
+        let mut query = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", uri_encode(name, true), uri_encode(value, true)))
+            .collect::<Vec<_>>();
+        query.sort();
+        let canonical_query = query.join("&");
+
+        let seconds = unix_seconds();
+        let (year, month, day) = timestamps::civil_from_days(seconds as i64 / 86_400);
+        let date = format!("{:04}{:02}{:02}", year, month, day);
+        let time_of_day = seconds % 86_400;
+        let amz_date = format!(
+            "{}T{:02}{:02}{:02}Z",
+            date,
+            time_of_day / 3600,
+            time_of_day % 3600 / 60,
+            time_of_day % 60
+        );
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, canonical_uri, canonical_query, self.authority, payload_hash, amz_date, SIGNED_HEADERS, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+            amz_date,
+            scope,
+            Sha256::digest(canonical_request.as_bytes())
+        );
+
+        let signing_key = [date.as_str(), self.config.region.as_str(), "s3", "aws4_request"]
+            .iter()
+            .fold(format!("AWS4{}", self.credentials.secret_access_key).into_bytes(), |key, part| {
+                hmac_sha256(&key, part.as_bytes())
+            });
+        let signature = hmac_sha256(&signing_key, string_to_sign.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+
+        let mut url = format!("{}://{}{}", self.scheme, self.authority, canonical_uri);
+        if !canonical_query.is_empty() {
+            url.push('?');
+            url.push_str(&canonical_query);
+        }
+        self.agent
+            .request(method, &url)
+            .set("x-amz-date", &amz_date)
+            .set("x-amz-content-sha256", payload_hash)
+            .set(
+                "Authorization",
+                &format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    self.credentials.access_key_id, scope, SIGNED_HEADERS, signature
+                ),
+            )
+    }
+}
+
+// Request body that reports how much of the file has been handed to the socket
+// What one S3 call does; action names it in errors
+#[derive(Clone, Copy)]
+struct Request<'a> {
+    method: &'a str,
+    key: &'a str,
+    query: &'a [(&'a str, &'a str)],
+    action: &'a str,
+}
+
+impl<'a> Request<'a> {
+    fn new(method: &'a str, key: &'a str, query: &'a [(&'a str, &'a str)], action: &'a str) -> Self {
+        Request { method, key, query, action }
+    }
+}
+
+struct ProgressReader<'a> {
+    remaining: &'a [u8],
+    sent: u64,
+    total: u64,
+    on_progress: &'a mut dyn FnMut(u64, u64),
+}
+
+impl Read for ProgressReader<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = self.remaining.read(buffer)?;
+        if read > 0 {
+            self.sent += read as u64;
+            (self.on_progress)(self.sent, self.total);
+        }
+        Ok(read)
+    }
+}
+
+fn read_full(file: &mut File, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(filled)
+}
+
+fn response_etag(response: &ureq::Response) -> Result<String, String> {
+    response
+        .header("ETag")
+        .map(|etag| etag.trim_matches('"').to_string())
+        .ok_or_else(|| "S3 did not return an ETag".to_string())
+}
+
+// The responses we read are small and flat, so a tag search is enough
+fn xml_value<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("<{}>", tag);
+    let start = xml.find(&open)? + open.len();
+    let end = xml[start..].find(&format!("</{}>", tag))? + start;
+    Some(&xml[start..end])
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// RFC 3986 encoding as SigV4 expects; '/' separates key segments in the path
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn unix_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
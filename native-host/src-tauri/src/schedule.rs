@@ -0,0 +1,520 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+use crate::connectivity;
+use crate::dispatcher::Priority;
+use crate::downloader::{Backend, DownloadContext};
+use crate::file_lock::{self, FileLock};
+use crate::instance::{self, InstanceMessage};
+use crate::jobs;
+use crate::source_page::SourcePage;
+use crate::{autostart, current_timestamp_millis, get_app_data_directory, logging, timestamps};
+
+const QUEUE_FILE_NAME: &str = "scheduled-downloads.json";
+const LOCK_FILE_NAME: &str = "scheduled-downloads.lock";
+// The timer re-reads the queue file at least this often, which also picks up
+// changes from native host processes that could not reach the GUI
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+// Wall clock running this far ahead of the wait means the machine was asleep
+const SLEEP_DETECTION_SLACK: Duration = Duration::from_secs(60);
+
+// A download waiting in the queue file for its start time, or stopped by
+// pausing the queue or by shutdown. Browser cookies are never persisted, so
+// these jobs only use cookies.txt and site logins.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduledDownload {
+    pub job_id: String,
+    pub url: String,
+    pub output_path: String,
+    pub upload: bool,
+    // gui, native or http
+    pub source: String,
+    pub run_at: i64,
+    // run_at as RFC 3339 in UTC, for display
+    pub scheduled_at: String,
+    pub created_at: i64,
+    // Waits for resume_queue instead of a time; yt-dlp continues its .part files
+    #[serde(default)]
+    pub paused: bool,
+    #[serde(default)]
+    pub priority: Priority,
+    // Cut off by shutdown; due right away, so the next timer pass continues it
+    #[serde(default)]
+    pub interrupted: bool,
+    // pageUrl, pageTitle and referer, kept so the job runs as it would have right away
+    #[serde(default, flatten)]
+    pub source_page: SourcePage,
+    #[serde(default, flatten)]
+    pub options: RunOptions,
+}
+
+// What an HTTP API request chose beyond its download context
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunOptions {
+    // None routes by URL
+    #[serde(default)]
+    pub backend: Option<Backend>,
+    // See media_policy::for_request
+    #[serde(default)]
+    pub ignore_policy: bool,
+}
+
+impl ScheduledDownload {
+    // Due at `run_at`, as first queued then
+    fn new(context: &DownloadContext, run_at: i64) -> Self {
+        ScheduledDownload {
+            job_id: context.job_id.to_string(),
+            url: context.url.to_string(),
+            output_path: context.output_path.to_string(),
+            upload: context.upload,
+            source: context.source.to_string(),
+            run_at,
+            scheduled_at: timestamps::format_rfc3339(run_at),
+            created_at: run_at,
+            paused: false,
+            priority: context.priority,
+            interrupted: false,
+            source_page: context.source_page.clone(),
+            options: RunOptions::default(),
+        }
+    }
+}
+
+// Why a started job went back to the queue file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Paused,
+    Interrupted,
+    // Failed to connect while the network was down; due again once it is back
+    Offline,
+}
+
+impl StopReason {
+    // Job event name
+    pub fn event(&self) -> &'static str {
+        match self {
+            StopReason::Paused => "paused",
+            StopReason::Interrupted => "interrupted",
+            // To the extension this is a pause it does not have to undo
+            StopReason::Offline => "paused",
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            StopReason::Paused => "Download paused; it continues when the queue is resumed",
+            StopReason::Interrupted => "Download interrupted by shutdown; it continues when ImgVault runs again",
+            StopReason::Offline => "Network connection lost; the download continues when it is back",
+        }
+    }
+}
+
+// Wakes the GUI's timer thread early, after a job was added or moved
+#[derive(Clone, Default)]
+pub struct Scheduler {
+    wake: Arc<(Mutex<bool>, Condvar)>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Scheduler {
+    pub fn wake(&self) {
+        let (woken, condvar) = &*self.wake;
+        *woken.lock().unwrap() = true;
+        condvar.notify_all();
+    }
+
+    // Ends the timer thread for shutdown; jobs still due stay in the queue file
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.wake();
+    }
+
+    // Runs due jobs on a background thread for the life of the process. The
+    // first pass happens right away, so jobs that came due while the app was
+    // closed start on launch.
+    pub fn start<F>(&self, run: F)
+    where
+        F: Fn(ScheduledDownload) + Send + 'static,
+    {
+        let wake = Arc::clone(&self.wake);
+        let stopped = Arc::clone(&self.stopped);
+        std::thread::spawn(move || loop {
+            if stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            // Nothing is dispatched while the queue is paused or the network is
+            // down; resuming or coming back online wakes the timer
+            let due = if jobs::is_queue_paused() == Some(true) || connectivity::is_offline() {
+                Ok((Vec::new(), None))
+            } else {
+                take_due(current_timestamp_millis())
+            };
+            let next_run_at = match due {
+                Ok((due, next_run_at)) => {
+                    for download in due {
+                        info!(
+                            job_id = %download.job_id,
+                            scheduled_at = %download.scheduled_at,
+                            "Starting scheduled download"
+                        );
+                        run(download);
+                    }
+                    next_run_at
+                }
+                Err(error) => {
+                    warn!("Failed to check scheduled downloads: {}", error);
+                    None
+                }
+            };
+
+            // Monotonic timeouts may stand still while the machine sleeps, so
+            // the wait is capped and every pass compares against the wall clock
+            let wait = next_run_at
+                .map(|run_at| Duration::from_millis((run_at - current_timestamp_millis()).max(0) as u64))
+                .map_or(POLL_INTERVAL, |until_next| until_next.min(POLL_INTERVAL));
+            let wall_clock_before = SystemTime::now();
+            let (woken, condvar) = &*wake;
+            let mut woken = woken.lock().unwrap();
+            if !*woken {
+                woken = condvar.wait_timeout(woken, wait).unwrap().0;
+            }
+            *woken = false;
+            drop(woken);
+
+            if SystemTime::now()
+                .duration_since(wall_clock_before)
+                .is_ok_and(|elapsed| elapsed > wait + SLEEP_DETECTION_SLACK)
+            {
+                info!("Resumed from sleep, checking for overdue scheduled downloads");
+            }
+        });
+    }
+}
+
+pub fn parse_schedule_at(value: &str) -> Result<i64, String> {
+    timestamps::parse_rfc3339(value).map_err(|_| {
+        format!(
+            "schedule_at must be an RFC 3339 timestamp such as 2024-05-01T22:30:00+02:00, not {}",
+            value
+        )
+    })
+}
+
+// Adds a job to the queue file. A time in the past runs on the timer's next pass.
+pub fn add(context: &DownloadContext, schedule_at: &str) -> Result<ScheduledDownload, String> {
+    add_with(context, schedule_at, RunOptions::default())
+}
+
+pub fn add_with(context: &DownloadContext, schedule_at: &str, options: RunOptions) -> Result<ScheduledDownload, String> {
+    let run_at = parse_schedule_at(schedule_at)?;
+    let download = ScheduledDownload {
+        created_at: current_timestamp_millis(),
+        options,
+        ..ScheduledDownload::new(context, run_at)
+    };
+    let job_id = context.job_id;
+    update_queue(|queue| {
+        if queue.iter().any(|queued| queued.job_id == job_id) {
+            return Err(format!("Download {} is already scheduled", job_id));
+        }
+        queue.push(download.clone());
+        Ok(())
+    })?;
+    info!(
+        job_id,
+        url = logging::loggable_url(context.url),
+        scheduled_at = %download.scheduled_at,
+        "Download scheduled"
+    );
+    Ok(download)
+}
+
+// Keeps a job stopped by pausing the queue, or started while it was paused,
+// until resume, and one cut off by shutdown until the timer's next pass.
+// `queued_at` orders the restart.
+pub fn add_stopped(context: &DownloadContext, queued_at: i64, reason: StopReason) -> Result<(), String> {
+    let download = ScheduledDownload {
+        paused: reason == StopReason::Paused,
+        interrupted: reason == StopReason::Interrupted,
+        ..ScheduledDownload::new(context, queued_at)
+    };
+    let job_id = context.job_id;
+    update_queue(|queue| {
+        queue.retain(|queued| queued.job_id != job_id);
+        queue.push(download);
+        Ok(())
+    })?;
+    info!(job_id, reason = ?reason, "Download kept for resume");
+    Ok(())
+}
+
+// Makes every paused job due now, keeping the order they were first queued in.
+// Returns how many there were.
+pub fn resume_paused() -> Result<usize, String> {
+    let now = current_timestamp_millis();
+    update_queue(|queue| {
+        let mut paused = queue
+            .iter_mut()
+            .filter(|download| download.paused)
+            .collect::<Vec<_>>();
+        paused.sort_by_key(|download| download.created_at);
+        // One millisecond apart so the timer starts them in this order
+        for (index, download) in paused.iter_mut().enumerate() {
+            download.paused = false;
+            download.run_at = now + index as i64;
+            download.scheduled_at = timestamps::format_rfc3339(download.run_at);
+        }
+        Ok(paused.len())
+    })
+}
+
+// Waiting jobs, soonest first
+pub fn list() -> Result<Vec<ScheduledDownload>, String> {
+    let _lock = lock_queue()?;
+    let mut queue = read_queue()?;
+    queue.sort_by_key(|download| download.run_at);
+    Ok(queue)
+}
+
+pub fn reschedule(job_id: &str, schedule_at: &str) -> Result<ScheduledDownload, String> {
+    let run_at = parse_schedule_at(schedule_at)?;
+    let download = move_job(job_id, run_at)?;
+    info!(job_id, scheduled_at = %download.scheduled_at, "Scheduled download moved");
+    Ok(download)
+}
+
+pub fn run_now(job_id: &str) -> Result<ScheduledDownload, String> {
+    let download = move_job(job_id, current_timestamp_millis())?;
+    info!(job_id, "Scheduled download moved to now");
+    Ok(download)
+}
+
+// The priority a scheduled or paused job gets in the queue once it is due;
+// None when no job with that id is scheduled
+pub fn set_priority(job_id: &str, priority: Priority) -> Result<Option<ScheduledDownload>, String> {
+    update_queue(|queue| {
+        Ok(queue.iter_mut().find(|download| download.job_id == job_id).map(|download| {
+            download.priority = priority;
+            download.clone()
+        }))
+    })
+}
+
+// Drops a waiting job; false when no job with that id is scheduled
+pub fn remove(job_id: &str) -> Result<bool, String> {
+    update_queue(|queue| {
+        let before = queue.len();
+        queue.retain(|download| download.job_id != job_id);
+        Ok(queue.len() != before)
+    })
+}
+
+// The timer lives in the GUI process. Tell it about a change made in this
+// process, or start it in the tray so the job still runs on time.
+pub fn notify_gui() {
+    if instance::send_to_running_instance(&InstanceMessage::ScheduleChanged).is_ok() {
+        return;
+    }
+
+    let exe = match env::current_exe() {
+        Ok(exe) => exe,
+        Err(error) => {
+            warn!("Failed to start the GUI for scheduled downloads: {}", error);
+            return;
+        }
+    };
+    let mut command = Command::new(exe);
+    command.arg(autostart::MINIMIZED_FLAG);
+    // Chrome puts native hosts in a job object that is closed with the port
+    #[cfg(target_os = "windows")]
+    {
+        use std::os::windows::process::CommandExt;
+        const DETACHED_PROCESS: u32 = 0x00000008;
+        const CREATE_BREAKAWAY_FROM_JOB: u32 = 0x01000000;
+        command.creation_flags(DETACHED_PROCESS | CREATE_BREAKAWAY_FROM_JOB);
+    }
+    match command.spawn() {
+        Ok(_) => info!("Started the GUI in the tray to run scheduled downloads"),
+        Err(error) => warn!("Failed to start the GUI for scheduled downloads: {}", error),
+    }
+}
+
+fn move_job(job_id: &str, run_at: i64) -> Result<ScheduledDownload, String> {
+    update_queue(|queue| {
+        let download = queue
+            .iter_mut()
+            .find(|download| download.job_id == job_id)
+            .ok_or_else(|| format!("No scheduled download {}", job_id))?;
+        download.run_at = run_at;
+        download.scheduled_at = timestamps::format_rfc3339(run_at);
+        download.paused = false;
+        Ok(download.clone())
+    })
+}
+
+// Removes and returns the jobs due at `now`, plus when the next one is due
+fn take_due(now: i64) -> Result<(Vec<ScheduledDownload>, Option<i64>), String> {
+    update_queue(|queue| {
+        let (mut due, waiting): (Vec<_>, Vec<_>) = queue
+            .drain(..)
+            .partition(|download| !download.paused && download.run_at <= now);
+        *queue = waiting;
+        due.sort_by_key(|download| download.run_at);
+        let next_run_at = queue
+            .iter()
+            .filter(|download| !download.paused)
+            .map(|download| download.run_at)
+            .min();
+        Ok((due, next_run_at))
+    })
+}
+
+fn queue_path() -> Result<PathBuf, String> {
+    Ok(get_app_data_directory()?.join(QUEUE_FILE_NAME))
+}
+
+fn read_queue() -> Result<Vec<ScheduledDownload>, String> {
+    let path = queue_path()?;
+    match fs::read_to_string(&path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .map_err(|e| format!("Failed to parse {}: {}", path.display(), e)),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(error) => Err(format!("Failed to read scheduled downloads: {}", error)),
+    }
+}
+
+fn write_queue(queue: &[ScheduledDownload]) -> Result<(), String> {
+    let path = queue_path()?;
+    let contents = serde_json::to_string_pretty(queue)
+        .map_err(|e| format!("Failed to serialize scheduled downloads: {}", e))?;
+    // A crash mid-write must not lose every other scheduled job
+    let temp_path = path.with_extension(format!("json.{}.tmp", std::process::id()));
+    fs::write(&temp_path, contents)
+        .map_err(|e| format!("Failed to write scheduled downloads: {}", e))?;
+    fs::rename(&temp_path, &path)
+        .map_err(|e| format!("Failed to save scheduled downloads: {}", e))
+}
+
+fn update_queue<T>(change: impl FnOnce(&mut Vec<ScheduledDownload>) -> Result<T, String>) -> Result<T, String> {
+    let _lock = lock_queue()?;
+    let mut queue = read_queue()?;
+    let original = queue.clone();
+    let result = change(&mut queue)?;
+    // Nothing due is the common case on every timer pass; skip the rewrite
+    if queue != original {
+        write_queue(&queue)?;
+    }
+    Ok(result)
+}
+
+fn lock_queue() -> Result<FileLock, String> {
+    file_lock::acquire(LOCK_FILE_NAME, "scheduled downloads")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    fn context<'a>(job_id: &'a str, source_page: &'a SourcePage) -> DownloadContext<'a> {
+        DownloadContext {
+            job_id,
+            url: "https://example.com/watch?v=1",
+            output_path: "/vault/%(title)s.%(ext)s",
+            upload: false,
+            priority: Priority::Normal,
+            source_page,
+            source: "gui",
+        }
+    }
+
+    fn schedule(job_id: &str, schedule_at: &str) -> Result<ScheduledDownload, String> {
+        add(&context(job_id, &SourcePage::default()), schedule_at)
+    }
+
+    fn job_ids(queue: &[ScheduledDownload]) -> Vec<&str> {
+        queue.iter().map(|download| download.job_id.as_str()).collect()
+    }
+
+    #[test]
+    fn jobs_are_saved_listed_soonest_first_and_removed() {
+        let app_data = test_support::app_data();
+        let later = schedule("later", "2030-01-02T00:00:00Z").expect("add");
+        schedule("sooner", "2030-01-01T22:30:00+02:00").expect("add");
+        assert_eq!(later.run_at, parse_schedule_at("2030-01-02T00:00:00Z").unwrap());
+        assert_eq!(later.scheduled_at, timestamps::format_rfc3339(later.run_at));
+        assert!(app_data.directory.join(QUEUE_FILE_NAME).is_file());
+        assert_eq!(job_ids(&list().unwrap()), ["sooner", "later"]);
+
+        let error = schedule("later", "2030-01-03T00:00:00Z").expect_err("duplicate job");
+        assert!(error.contains("already scheduled"), "{}", error);
+        let error = schedule("other", "tomorrow").expect_err("not a timestamp");
+        assert!(error.contains("RFC 3339"), "{}", error);
+
+        assert!(remove("sooner").unwrap());
+        assert!(!remove("sooner").unwrap());
+        assert_eq!(list().unwrap(), [later]);
+    }
+
+    #[test]
+    fn due_jobs_are_taken_in_order_with_the_next_due_time() {
+        let _app_data = test_support::app_data();
+        let first = schedule("first", "2030-01-01T00:00:00Z").expect("add").run_at;
+        let last = schedule("last", "2030-01-03T00:00:00Z").expect("add").run_at;
+        let second = schedule("second", "2030-01-02T00:00:00Z").expect("add").run_at;
+        // Paused jobs wait for resume however early they were queued
+        add_stopped(&context("paused", &SourcePage::default()), 0, StopReason::Paused).expect("add stopped");
+
+        let (due, next_run_at) = take_due(second).unwrap();
+        assert_eq!(job_ids(&due), ["first", "second"]);
+        assert_eq!(due[0].run_at, first);
+        assert_eq!(next_run_at, Some(last));
+        assert_eq!(job_ids(&list().unwrap()), ["paused", "last"]);
+
+        let (due, next_run_at) = take_due(second).unwrap();
+        assert!(due.is_empty());
+        assert_eq!(next_run_at, Some(last));
+
+        assert_eq!(resume_paused().unwrap(), 1);
+        let (due, next_run_at) = take_due(current_timestamp_millis()).unwrap();
+        assert_eq!(job_ids(&due), ["paused"]);
+        assert_eq!(next_run_at, Some(last));
+    }
+
+    #[test]
+    fn run_now_and_reschedule_move_a_job() {
+        let _app_data = test_support::app_data();
+        schedule("job", "2030-01-01T00:00:00Z").expect("add");
+
+        let moved = reschedule("job", "2031-06-01T12:00:00Z").expect("reschedule");
+        assert_eq!(moved.run_at, parse_schedule_at("2031-06-01T12:00:00Z").unwrap());
+        assert_eq!(moved.scheduled_at, timestamps::format_rfc3339(moved.run_at));
+        assert_eq!(list().unwrap(), [moved]);
+        reschedule("job", "soon").expect_err("not a timestamp");
+
+        let before = current_timestamp_millis();
+        let moved = run_now("job").expect("run now");
+        assert!(moved.run_at >= before && moved.run_at <= current_timestamp_millis());
+        let (due, next_run_at) = take_due(current_timestamp_millis()).unwrap();
+        assert_eq!(due, [moved]);
+        assert_eq!(next_run_at, None);
+
+        let error = run_now("job").expect_err("no longer scheduled");
+        assert!(error.contains("No scheduled download job"), "{}", error);
+        reschedule("missing", "2030-01-01T00:00:00Z").expect_err("never scheduled");
+
+        // Moving a paused job also releases it from the pause
+        add_stopped(&context("paused", &SourcePage::default()), 0, StopReason::Paused).expect("add stopped");
+        assert!(!run_now("paused").expect("run now").paused);
+    }
+}
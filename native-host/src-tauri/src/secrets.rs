@@ -0,0 +1,56 @@
+use std::fs;
+use tracing::{info, warn};
+
+use crate::{get_app_data_directory, keychain};
+
+const SECRET_BYTES: usize = 32;
+
+// Random per-install secret kept in the OS keychain under `name`, generated
+// the first time it is needed. Older builds kept it in a file of the same name
+// in the app data directory; that file is moved into the keychain and deleted.
+// Without a usable keychain the file stays, so the feature keeps working.
+// Returned as lowercase hex.
+pub fn get_or_create_secret(name: &str) -> Result<String, String> {
+    // After a read error the entry may still exist, so it is never overwritten
+    let stored = match keychain::get_secret(name) {
+        Ok(Some(secret)) => return Ok(secret),
+        Ok(None) => Ok(()),
+        Err(error) => Err(error),
+    };
+
+    let path = get_app_data_directory()?.join(name);
+    let existing = fs::read_to_string(&path)
+        .ok()
+        .map(|secret| secret.trim().to_string())
+        .filter(|secret| !secret.is_empty());
+    let secret = match &existing {
+        Some(secret) => secret.clone(),
+        None => {
+            let mut bytes = [0u8; SECRET_BYTES];
+            getrandom::getrandom(&mut bytes).map_err(|e| format!("Failed to generate {}: {}", name, e))?;
+            bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()
+        }
+    };
+    keychain::mark_sensitive(&secret);
+
+    match stored.and_then(|()| keychain::set_secret(name, &secret)) {
+        Ok(()) if existing.is_some() => {
+            if let Err(error) = fs::remove_file(&path) {
+                warn!("Moved {} into the keychain but could not delete {}: {}", name, path.display(), error);
+            } else {
+                info!("Moved {} into the keychain", name);
+            }
+        }
+        Ok(()) => info!("Generated {}", name),
+        Err(error) if existing.is_none() => {
+            warn!("{}; keeping {} in the app data directory", error, name);
+            if let Some(directory) = path.parent() {
+                fs::create_dir_all(directory)
+                    .map_err(|e| format!("Failed to create app data directory: {}", e))?;
+            }
+            fs::write(&path, &secret).map_err(|e| format!("Failed to save {}: {}", name, e))?;
+        }
+        Err(error) => warn!("{}; keeping {} in the app data directory", error, name),
+    }
+    Ok(secret)
+}
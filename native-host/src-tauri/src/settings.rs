@@ -0,0 +1,1081 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+use crate::bandwidth::{self, BandwidthWindow};
+use crate::clipboard_watch::DEFAULT_CLIPBOARD_DOMAINS;
+use crate::conflicts::{ConflictDecision, MAX_CONFLICT_TIMEOUT_MINUTES, MIN_CONFLICT_TIMEOUT_MINUTES};
+use crate::connectivity::{
+    DEFAULT_CHECK_INTERVAL_SECS, DEFAULT_PROBE_URL, MAX_CHECK_INTERVAL_SECS, MIN_CHECK_INTERVAL_SECS,
+};
+use crate::dedupe::DedupeMode;
+use crate::destination;
+use crate::downloader::DEFAULT_GALLERY_DL_DOMAINS;
+use crate::dispatcher::{self, DomainLimit, DEFAULT_DOMAIN_MAX_CONCURRENT, MAX_DOMAIN_JITTER_MS, MAX_DOMAIN_MIN_DELAY_MS};
+use crate::domain_policy;
+use crate::drop_import::DropImportMode;
+use crate::fake_download;
+use crate::i18n;
+use crate::failure_details::MAX_STDERR_TAIL_LINES;
+use crate::{get_app_data_directory, keychain};
+use crate::logging::{self, DEFAULT_LOG_LEVEL, LOG_LEVELS};
+use crate::long_path::{self, DEFAULT_MAX_PATH_LENGTH, MAX_MAX_PATH_LENGTH, MIN_MAX_PATH_LENGTH};
+use crate::media_policy::{ImageDimensions, MediaType};
+use crate::notifications::NotificationMode;
+use crate::organize::{self, CollisionMode, OrganizeRule};
+use crate::output_template::{self, DEFAULT_OUTPUT_TEMPLATE};
+use crate::power::{self, PreventSleep};
+use crate::post_download::{PostDownloadCommand, MAX_TIMEOUT_SECS};
+use crate::profiles::{Profile, DEFAULT_PROFILE};
+use crate::redact::{self, DEFAULT_REDACTED_PARAMETERS};
+use crate::resource_pressure::{MAX_MIN_FREE_MEMORY_MB, MAX_MIN_FREE_TEMP_MB};
+use crate::s3::{self, S3UploadSettings};
+use crate::site_login::{self, SiteLogin};
+use crate::stall::{StallAction, MAX_STALL_WINDOW_SECS, MIN_STALL_WINDOW_SECS};
+use crate::supervisor::{MAX_FIRST_OUTPUT_WINDOW_SECS, MIN_FIRST_OUTPUT_WINDOW_SECS};
+use crate::updates::UpdateChannel;
+use crate::user_agent::{self, SiteUserAgent};
+
+pub(crate) const SETTINGS_FILE_NAME: &str = "settings.json";
+const SCHEMA_VERSION: u32 = 1;
+const MAX_CONCURRENT_DOWNLOADS: u32 = 8;
+const MAX_NOTIFICATION_MIN_DURATION_SECS: u64 = 600;
+const MAX_AUTO_RETRY_INTERVAL_MINUTES: u32 = 24 * 60;
+const MAX_AUTO_RETRY_ATTEMPTS: u32 = 10;
+const MAX_AUTO_RETRY_WINDOW_HOURS: u32 = 7 * 24;
+const MAX_SHUTDOWN_GRACE_PERIOD_SECS: u64 = 300;
+const DEFAULT_HTTP_API_PORT: u16 = 47380;
+const DEFAULT_WEBSOCKET_PORT: u16 = 47381;
+// Browser extension origins; web pages must never be able to call the HTTP API
+const EXTENSION_ORIGIN_PREFIXES: &[&str] = &["chrome-extension://", "moz-extension://"];
+
+// Machine-specific fields, only carried over by an import when asked to
+pub const PATH_FIELDS: &[&str] = &["vault_root", "yt_dlp_path", "gallery_dl_path", "post_download_command"];
+// Fields holding credentials, left out of exports unless explicitly requested
+// Webhook URLs often embed an access token
+pub const SECRET_FIELDS: &[&str] = &["webhook_urls"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VideoQuality {
+    Best,
+    #[serde(rename = "1080p")]
+    Hd1080,
+    #[serde(rename = "720p")]
+    Hd720,
+    #[serde(rename = "480p")]
+    Sd480,
+    AudioOnly,
+}
+
+impl VideoQuality {
+    // yt-dlp `-f` selector for this quality
+    pub fn format_selector(&self) -> &'static str {
+        match self {
+            VideoQuality::Best => "bestvideo+bestaudio/best",
+            VideoQuality::Hd1080 => "bestvideo[height<=1080]+bestaudio/best[height<=1080]",
+            VideoQuality::Hd720 => "bestvideo[height<=720]+bestaudio/best[height<=720]",
+            VideoQuality::Sd480 => "bestvideo[height<=480]+bestaudio/best[height<=480]",
+            VideoQuality::AudioOnly => "bestaudio/best",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub schema_version: u32,
+    // Where downloads without an explicit output path are saved; None uses the Videos folder
+    pub vault_root: Option<String>,
+    // yt-dlp output template, inside the vault, for downloads sent without a path
+    pub output_template: String,
+    pub max_concurrent_downloads: u32,
+    // Below this much available memory or free temp space, in MB, the queue
+    // runs one download at a time and holds back merges; 0 turns either off
+    pub min_free_memory_mb: u64,
+    pub min_free_temp_mb: u64,
+    pub default_quality: VideoQuality,
+    // Explicit yt-dlp executable; None resolves it from PATH
+    pub yt_dlp_path: Option<String>,
+    // Explicit gallery-dl executable; None resolves it from PATH
+    pub gallery_dl_path: Option<String>,
+    // Links on these sites or their subdomains go to gallery-dl when it is installed
+    pub gallery_dl_domains: Vec<String>,
+    // Only these sites may be downloaded from when not empty; "*.example.com"
+    // covers its subdomains
+    pub allowed_domains: Vec<String>,
+    // Never downloaded from, even when allowed above
+    pub blocked_domains: Vec<String>,
+    // Images smaller than this in either direction are deleted and the
+    // download fails; None keeps any size
+    pub min_image_dimensions: Option<ImageDimensions>,
+    // yt-dlp prefers formats up to this height, and videos only available
+    // taller fail; None keeps any height
+    pub max_video_height: Option<u32>,
+    // Kinds of media kept; empty keeps all
+    pub allowed_types: Vec<MediaType>,
+    // Whether a request may skip the three above with ignore_policy
+    pub allow_policy_override: bool,
+    // Development: downloads play a fake scenario instead of running yt-dlp;
+    // IMGVAULT_FAKE_YTDLP=1 does the same
+    pub fake_downloads: bool,
+    // Each native messaging session writes its frames, redacted, to captures/
+    // in the data folder for bug reports and --replay
+    pub capture_sessions: bool,
+    pub minimize_to_tray: bool,
+    pub notifications: NotificationMode,
+    // Jobs that finish faster than this never produce a notification
+    pub notification_min_duration_secs: u64,
+    // Overridden by the IMGVAULT_LOG environment variable
+    pub log_level: String,
+    pub update_channel: UpdateChannel,
+    // Opt-in localhost REST API for browsers where native messaging is unavailable
+    pub http_api_enabled: bool,
+    pub http_api_port: u16,
+    // Origins allowed to call the HTTP API from a browser, e.g. chrome-extension://<id>
+    pub http_api_allowed_origins: Vec<String>,
+    // Job event stream, served while the HTTP API is enabled
+    pub websocket_port: u16,
+    // POSTed a signed JSON summary whenever a job finishes
+    pub webhook_urls: Vec<String>,
+    // Local program run after each successful download; settable only from the GUI or this file
+    pub post_download_command: Option<PostDownloadCommand>,
+    // Upload finished downloads to an S3-compatible bucket; keys live in the OS keychain
+    pub s3_upload: Option<S3UploadSettings>,
+    // Credentials yt-dlp gets through a temporary netrc file for matching URLs
+    pub site_logins: Vec<SiteLogin>,
+    // Rate limits by time of day for newly started downloads; empty means full speed
+    pub bandwidth_schedule: Vec<BandwidthWindow>,
+    // Politeness limits per site, on top of max_concurrent_downloads
+    pub domain_max_concurrent: u32,
+    // Minimum time between one job for a site ending or starting and the next starting
+    pub domain_min_delay_ms: u64,
+    // Up to this much random extra delay on top of domain_min_delay_ms
+    pub domain_jitter_ms: u64,
+    // Per-site overrides of the limits above; a domain covers its subdomains
+    pub domain_limits: Vec<DomainLimit>,
+    // Re-queue downloads that failed with a transient error, e.g. while the laptop slept
+    pub auto_retry_failed: bool,
+    // How often the GUI looks for failed downloads to retry
+    pub auto_retry_interval_minutes: u32,
+    // Retries per job, not counting the first attempt
+    pub auto_retry_max_attempts: u32,
+    // No retries once this long has passed since the job first failed
+    pub auto_retry_window_hours: u32,
+    // Retry a download whose signed link failed like an expired one from the
+    // page it was found on, when that is on the same site; one of the retries above
+    pub refresh_expired_from_page: bool,
+    // How long running downloads get to finish on quit before they are stopped and kept for resume
+    pub shutdown_grace_period_secs: u64,
+    // Lines of yt-dlp's stderr returned and logged when a download fails; 0 keeps only the command line
+    pub failure_stderr_lines: usize,
+    // A download slower than this over stall_window_secs counts as stalled; 0 turns detection off
+    pub stall_min_bytes_per_second: u64,
+    pub stall_window_secs: u64,
+    // Report a stalled download, or restart it as one of its auto-retry attempts
+    pub stall_action: StallAction,
+    // A download whose yt-dlp prints nothing this long after starting is stopped; 0 turns it off
+    pub first_output_timeout_secs: u64,
+    // A download whose yt-dlp grows past this much memory is stopped; 0 turns it off
+    pub child_memory_limit_mb: u64,
+    // Offer to download links copied in other apps while the GUI runs
+    pub clipboard_watch: bool,
+    // Only links on these sites or their subdomains are offered
+    pub clipboard_domains: Vec<String>,
+    // Download matching links without asking first
+    pub clipboard_auto_download: bool,
+    // Whether media files dropped onto the window are copied or moved into the vault
+    pub drop_import_mode: DropImportMode,
+    // Whether a download identical to a file already in the vault is replaced
+    // by a link to it
+    pub dedupe_mode: DedupeMode,
+    // Tried in order against every incoming job before it is queued
+    pub organize_rules: Vec<OrganizeRule>,
+    // What a download does when its file already exists; None leaves it to
+    // the backend. Organization rules can set it per job.
+    pub collision_mode: Option<CollisionMode>,
+    // With collision_mode ask: what a conflict the window leaves unanswered
+    // for conflict_timeout_minutes gets, and what every download not started
+    // from the window gets without asking
+    pub conflict_default: ConflictDecision,
+    pub conflict_timeout_minutes: u64,
+    // Skip videos this profile downloaded before, through yt-dlp's --download-archive
+    pub download_archive: bool,
+    // Split videos with chapters into one file per chapter, in a folder next
+    // to the whole video; a request can turn it on or off with split_chapters
+    pub split_chapters: bool,
+    // User agent for downloads that ask for none; "browser" sends the one of
+    // the browser the request came from. None keeps each backend's own.
+    pub user_agent: Option<String>,
+    // Per-site user agents, ahead of user_agent; a domain covers its subdomains
+    pub site_user_agents: Vec<SiteUserAgent>,
+    // URL query parameters whose values are masked in logs, errors and diagnostics
+    pub redact_query_parameters: Vec<String>,
+    // Language of the messages sent to the extension when a message names
+    // none, e.g. "de"; error codes stay the same in every language
+    pub locale: String,
+    // Longest path, in characters, that yt-dlp may write on Windows; file
+    // names from the output template are trimmed to fit
+    pub max_path_length: u32,
+    // Run yt-dlp and ffmpeg at below-normal CPU and low I/O priority; the
+    // tray can boost a running download back to normal
+    pub background_priority: bool,
+    // Whether running downloads keep the machine from going to sleep
+    pub prevent_sleep: PreventSleep,
+    // Checked to tell when the network drops, so the queue waits for it
+    // instead of failing; empty turns the check off
+    pub connectivity_probe_url: String,
+    // Seconds between connectivity checks while the network is up
+    pub connectivity_check_secs: u64,
+    // User data folders of portable or custom browser installs, checked for
+    // the extension along with the usual Chrome and Edge profiles
+    pub browser_user_data_dirs: Vec<String>,
+    // Mark finished downloads with where they came from, as browsers do: the
+    // Zone.Identifier stream on Windows, user.xdg.origin.url elsewhere. Off
+    // by default since Windows then warns before opening them; a request can
+    // skip it with tag_origin.
+    pub tag_download_origin: bool,
+    // The profile these settings were resolved for; never saved
+    #[serde(skip)]
+    pub profile: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            schema_version: SCHEMA_VERSION,
+            vault_root: None,
+            output_template: DEFAULT_OUTPUT_TEMPLATE.to_string(),
+            max_concurrent_downloads: 2,
+            min_free_memory_mb: 1024,
+            min_free_temp_mb: 2048,
+            default_quality: VideoQuality::Best,
+            yt_dlp_path: None,
+            gallery_dl_path: None,
+            gallery_dl_domains: DEFAULT_GALLERY_DL_DOMAINS.iter().map(|domain| domain.to_string()).collect(),
+            allowed_domains: Vec::new(),
+            blocked_domains: Vec::new(),
+            min_image_dimensions: None,
+            max_video_height: None,
+            allowed_types: Vec::new(),
+            allow_policy_override: false,
+            fake_downloads: false,
+            capture_sessions: false,
+            minimize_to_tray: false,
+            notifications: NotificationMode::All,
+            notification_min_duration_secs: 5,
+            log_level: DEFAULT_LOG_LEVEL.to_string(),
+            update_channel: UpdateChannel::Stable,
+            http_api_enabled: false,
+            http_api_port: DEFAULT_HTTP_API_PORT,
+            http_api_allowed_origins: vec![format!("chrome-extension://{}", crate::EXTENSION_ID)],
+            websocket_port: DEFAULT_WEBSOCKET_PORT,
+            webhook_urls: Vec::new(),
+            post_download_command: None,
+            s3_upload: None,
+            site_logins: Vec::new(),
+            bandwidth_schedule: Vec::new(),
+            domain_max_concurrent: DEFAULT_DOMAIN_MAX_CONCURRENT,
+            domain_min_delay_ms: 0,
+            domain_jitter_ms: 0,
+            domain_limits: Vec::new(),
+            auto_retry_failed: false,
+            auto_retry_interval_minutes: 30,
+            auto_retry_max_attempts: 3,
+            auto_retry_window_hours: 24,
+            refresh_expired_from_page: true,
+            shutdown_grace_period_secs: 10,
+            failure_stderr_lines: 30,
+            stall_min_bytes_per_second: 10_000,
+            stall_window_secs: 120,
+            stall_action: StallAction::Notify,
+            first_output_timeout_secs: 60,
+            child_memory_limit_mb: 4096,
+            clipboard_watch: false,
+            clipboard_domains: DEFAULT_CLIPBOARD_DOMAINS.iter().map(|domain| domain.to_string()).collect(),
+            clipboard_auto_download: false,
+            drop_import_mode: DropImportMode::Copy,
+            dedupe_mode: DedupeMode::Off,
+            organize_rules: Vec::new(),
+            collision_mode: None,
+            conflict_default: ConflictDecision::Rename,
+            conflict_timeout_minutes: 10,
+            download_archive: false,
+            split_chapters: false,
+            user_agent: None,
+            site_user_agents: Vec::new(),
+            redact_query_parameters: DEFAULT_REDACTED_PARAMETERS.iter().map(|name| name.to_string()).collect(),
+            locale: i18n::DEFAULT_LOCALE.to_string(),
+            max_path_length: DEFAULT_MAX_PATH_LENGTH,
+            background_priority: false,
+            prevent_sleep: PreventSleep::WhileDownloading,
+            connectivity_probe_url: DEFAULT_PROBE_URL.to_string(),
+            connectivity_check_secs: DEFAULT_CHECK_INTERVAL_SECS,
+            browser_user_data_dirs: Vec::new(),
+            tag_download_origin: false,
+            profile: DEFAULT_PROFILE.to_string(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn yt_dlp_program(&self) -> &str {
+        self.yt_dlp_path.as_deref().unwrap_or("yt-dlp")
+    }
+
+    pub fn gallery_dl_program(&self) -> &str {
+        self.gallery_dl_path.as_deref().unwrap_or("gallery-dl")
+    }
+
+    pub fn fake_downloads_enabled(&self) -> bool {
+        self.fake_downloads || fake_download::enabled()
+    }
+
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+
+        if let Some(vault_root) = &self.vault_root {
+            let path = Path::new(vault_root);
+            if !path.is_absolute() {
+                errors.push(FieldError::new(
+                    "vault_root",
+                    r"Must be a full path such as D:\Vault or \\server\share\Vault",
+                ));
+            } else {
+                match fs::metadata(long_path::extended(path)) {
+                    Ok(metadata) if metadata.is_dir() => {}
+                    // A share that is offline right now is kept; downloads wait for it
+                    Err(error) if destination::is_unavailable(&error) => {}
+                    _ => errors.push(FieldError::new("vault_root", "Folder does not exist")),
+                }
+            }
+        }
+
+        if let Err(error) = output_template::validate(&self.output_template) {
+            errors.push(FieldError::new("output_template", error));
+        }
+
+        if !(1..=MAX_CONCURRENT_DOWNLOADS).contains(&self.max_concurrent_downloads) {
+            errors.push(FieldError::new(
+                "max_concurrent_downloads",
+                format!("Must be between 1 and {}", MAX_CONCURRENT_DOWNLOADS),
+            ));
+        }
+
+        if let Some(yt_dlp_path) = &self.yt_dlp_path {
+            if !Path::new(yt_dlp_path).is_file() {
+                errors.push(FieldError::new("yt_dlp_path", "File does not exist"));
+            }
+        }
+
+        if let Some(gallery_dl_path) = &self.gallery_dl_path {
+            if !Path::new(gallery_dl_path).is_file() {
+                errors.push(FieldError::new("gallery_dl_path", "File does not exist"));
+            }
+        }
+
+        if let Some(domain) = self
+            .gallery_dl_domains
+            .iter()
+            .find(|domain| domain.trim().is_empty() || domain.contains(['/', ':', ' ']))
+        {
+            errors.push(FieldError::new(
+                "gallery_dl_domains",
+                format!("\"{}\" is not a host name such as pixiv.net", domain),
+            ));
+        }
+
+        for (field, rules) in [("allowed_domains", &self.allowed_domains), ("blocked_domains", &self.blocked_domains)] {
+            if let Some(error) = rules.iter().find_map(|rule| domain_policy::invalid_rule(rule)) {
+                errors.push(FieldError::new(field, error));
+            }
+        }
+
+        if self
+            .min_image_dimensions
+            .is_some_and(|min| min.width == 0 || min.height == 0)
+        {
+            errors.push(FieldError::new("min_image_dimensions", "Width and height must be at least 1"));
+        }
+
+        if self.max_video_height == Some(0) {
+            errors.push(FieldError::new("max_video_height", "Must be at least 1"));
+        }
+
+        if let Err(error) = organize::validate(&self.organize_rules) {
+            errors.push(FieldError::new("organize_rules", error));
+        }
+
+        if self.notification_min_duration_secs > MAX_NOTIFICATION_MIN_DURATION_SECS {
+            errors.push(FieldError::new(
+                "notification_min_duration_secs",
+                format!("Must be at most {} seconds", MAX_NOTIFICATION_MIN_DURATION_SECS),
+            ));
+        }
+
+        if !LOG_LEVELS.contains(&self.log_level.as_str()) {
+            errors.push(FieldError::new(
+                "log_level",
+                format!("Must be one of {}", LOG_LEVELS.join(", ")),
+            ));
+        }
+
+        if self.http_api_port < 1024 {
+            errors.push(FieldError::new("http_api_port", "Must be between 1024 and 65535"));
+        }
+
+        if self.websocket_port < 1024 {
+            errors.push(FieldError::new("websocket_port", "Must be between 1024 and 65535"));
+        } else if self.websocket_port == self.http_api_port {
+            errors.push(FieldError::new("websocket_port", "Must differ from the HTTP API port"));
+        }
+
+        if let Some(origin) = self.http_api_allowed_origins.iter().find(|origin| {
+            !EXTENSION_ORIGIN_PREFIXES
+                .iter()
+                .any(|prefix| origin.len() > prefix.len() && origin.starts_with(prefix))
+        }) {
+            errors.push(FieldError::new(
+                "http_api_allowed_origins",
+                format!("{} is not a browser extension origin", origin),
+            ));
+        }
+
+        // References are checked once resolved, when a webhook is sent
+        for webhook_url in self.webhook_urls.iter().filter(|url| !keychain::has_references(url)) {
+            if let Err(error) = crate::validate_download_url(webhook_url) {
+                errors.push(FieldError::new("webhook_urls", format!("{}: {}", webhook_url, error)));
+            }
+        }
+
+        if let Some(hook) = &self.post_download_command {
+            if !Path::new(&hook.program).is_file() {
+                errors.push(FieldError::new("post_download_command", "Program does not exist"));
+            }
+            if !(1..=MAX_TIMEOUT_SECS).contains(&hook.timeout_secs) {
+                errors.push(FieldError::new(
+                    "post_download_command",
+                    format!("Timeout must be between 1 and {} seconds", MAX_TIMEOUT_SECS),
+                ));
+            }
+        }
+
+        if let Some(upload) = &self.s3_upload {
+            if let Err(error) = crate::validate_download_url(&upload.endpoint) {
+                errors.push(FieldError::new("s3_upload", format!("Endpoint: {}", error)));
+            }
+            if let Err(error) = s3::validate_bucket_name(&upload.bucket) {
+                errors.push(FieldError::new("s3_upload", format!("Bucket: {}", error)));
+            }
+            if upload.region.trim().is_empty() {
+                errors.push(FieldError::new("s3_upload", "Region is required"));
+            }
+            for name in [&upload.access_key_id_secret, &upload.secret_access_key_secret] {
+                if let Err(error) = keychain::validate_name(name) {
+                    errors.push(FieldError::new("s3_upload", error));
+                }
+            }
+        }
+
+        for login in &self.site_logins {
+            if let Err(error) = site_login::validate(login) {
+                errors.push(FieldError::new("site_logins", format!("{}: {}", login.domain, error)));
+            }
+        }
+
+        if let Some(Err(error)) = self.user_agent.as_deref().map(user_agent::validate_setting) {
+            errors.push(FieldError::new("user_agent", error));
+        }
+
+        for site in &self.site_user_agents {
+            if let Err(error) = user_agent::validate_site(site) {
+                errors.push(FieldError::new("site_user_agents", format!("{}: {}", site.domain, error)));
+            }
+        }
+
+        for (index, window) in self.bandwidth_schedule.iter().enumerate() {
+            if let Err(error) = bandwidth::validate(window) {
+                errors.push(FieldError::new("bandwidth_schedule", format!("Window {}: {}", index + 1, error)));
+            }
+        }
+
+        if !(1..=MAX_CONCURRENT_DOWNLOADS).contains(&self.domain_max_concurrent) {
+            errors.push(FieldError::new(
+                "domain_max_concurrent",
+                format!("Must be between 1 and {}", MAX_CONCURRENT_DOWNLOADS),
+            ));
+        }
+
+        if self.domain_min_delay_ms > MAX_DOMAIN_MIN_DELAY_MS {
+            errors.push(FieldError::new(
+                "domain_min_delay_ms",
+                format!("Must be at most {} ms", MAX_DOMAIN_MIN_DELAY_MS),
+            ));
+        }
+
+        if self.domain_jitter_ms > MAX_DOMAIN_JITTER_MS {
+            errors.push(FieldError::new(
+                "domain_jitter_ms",
+                format!("Must be at most {} ms", MAX_DOMAIN_JITTER_MS),
+            ));
+        }
+
+        for limit in &self.domain_limits {
+            if let Err(error) = dispatcher::validate(limit, MAX_CONCURRENT_DOWNLOADS) {
+                errors.push(FieldError::new("domain_limits", format!("{}: {}", limit.domain, error)));
+            }
+        }
+
+        if !(1..=MAX_AUTO_RETRY_INTERVAL_MINUTES).contains(&self.auto_retry_interval_minutes) {
+            errors.push(FieldError::new(
+                "auto_retry_interval_minutes",
+                format!("Must be between 1 and {} minutes", MAX_AUTO_RETRY_INTERVAL_MINUTES),
+            ));
+        }
+
+        if !(1..=MAX_AUTO_RETRY_ATTEMPTS).contains(&self.auto_retry_max_attempts) {
+            errors.push(FieldError::new(
+                "auto_retry_max_attempts",
+                format!("Must be between 1 and {}", MAX_AUTO_RETRY_ATTEMPTS),
+            ));
+        }
+
+        if !(1..=MAX_AUTO_RETRY_WINDOW_HOURS).contains(&self.auto_retry_window_hours) {
+            errors.push(FieldError::new(
+                "auto_retry_window_hours",
+                format!("Must be between 1 and {} hours", MAX_AUTO_RETRY_WINDOW_HOURS),
+            ));
+        }
+
+        if self.shutdown_grace_period_secs > MAX_SHUTDOWN_GRACE_PERIOD_SECS {
+            errors.push(FieldError::new(
+                "shutdown_grace_period_secs",
+                format!("Must be at most {} seconds", MAX_SHUTDOWN_GRACE_PERIOD_SECS),
+            ));
+        }
+
+        if self.failure_stderr_lines > MAX_STDERR_TAIL_LINES {
+            errors.push(FieldError::new(
+                "failure_stderr_lines",
+                format!("Must be at most {}", MAX_STDERR_TAIL_LINES),
+            ));
+        }
+
+        if self.min_free_memory_mb > MAX_MIN_FREE_MEMORY_MB {
+            errors.push(FieldError::new(
+                "min_free_memory_mb",
+                format!("Must be at most {} MB", MAX_MIN_FREE_MEMORY_MB),
+            ));
+        }
+
+        if self.min_free_temp_mb > MAX_MIN_FREE_TEMP_MB {
+            errors.push(FieldError::new(
+                "min_free_temp_mb",
+                format!("Must be at most {} MB", MAX_MIN_FREE_TEMP_MB),
+            ));
+        }
+
+        if !(MIN_CONFLICT_TIMEOUT_MINUTES..=MAX_CONFLICT_TIMEOUT_MINUTES).contains(&self.conflict_timeout_minutes) {
+            errors.push(FieldError::new(
+                "conflict_timeout_minutes",
+                format!(
+                    "Must be between {} and {} minutes",
+                    MIN_CONFLICT_TIMEOUT_MINUTES, MAX_CONFLICT_TIMEOUT_MINUTES
+                ),
+            ));
+        }
+
+        if !(MIN_STALL_WINDOW_SECS..=MAX_STALL_WINDOW_SECS).contains(&self.stall_window_secs) {
+            errors.push(FieldError::new(
+                "stall_window_secs",
+                format!("Must be between {} and {} seconds", MIN_STALL_WINDOW_SECS, MAX_STALL_WINDOW_SECS),
+            ));
+        }
+
+        if self.first_output_timeout_secs != 0
+            && !(MIN_FIRST_OUTPUT_WINDOW_SECS..=MAX_FIRST_OUTPUT_WINDOW_SECS).contains(&self.first_output_timeout_secs)
+        {
+            errors.push(FieldError::new(
+                "first_output_timeout_secs",
+                format!(
+                    "Must be 0 or between {} and {} seconds",
+                    MIN_FIRST_OUTPUT_WINDOW_SECS, MAX_FIRST_OUTPUT_WINDOW_SECS
+                ),
+            ));
+        }
+
+        if let Some(domain) = self
+            .clipboard_domains
+            .iter()
+            .find(|domain| domain.trim().is_empty() || domain.contains(['/', ':', ' ']))
+        {
+            errors.push(FieldError::new(
+                "clipboard_domains",
+                format!("\"{}\" is not a host name such as vimeo.com", domain),
+            ));
+        }
+
+        if let Some(name) = self
+            .redact_query_parameters
+            .iter()
+            .find(|name| name.trim().is_empty() || name.contains(['=', '&', '?', '#', ' ']))
+        {
+            errors.push(FieldError::new(
+                "redact_query_parameters",
+                format!("\"{}\" is not a query parameter name such as token", name),
+            ));
+        }
+
+        if !i18n::supported(&self.locale) {
+            errors.push(FieldError::new(
+                "locale",
+                format!("Must be one of {}", i18n::LOCALES.join(", ")),
+            ));
+        }
+
+        if !(MIN_MAX_PATH_LENGTH..=MAX_MAX_PATH_LENGTH).contains(&self.max_path_length) {
+            errors.push(FieldError::new(
+                "max_path_length",
+                format!("Must be between {} and {} characters", MIN_MAX_PATH_LENGTH, MAX_MAX_PATH_LENGTH),
+            ));
+        }
+
+        if !self.connectivity_probe_url.is_empty() {
+            if let Err(error) = crate::validate_download_url(&self.connectivity_probe_url) {
+                errors.push(FieldError::new("connectivity_probe_url", error));
+            }
+        }
+
+        if !(MIN_CHECK_INTERVAL_SECS..=MAX_CHECK_INTERVAL_SECS).contains(&self.connectivity_check_secs) {
+            errors.push(FieldError::new(
+                "connectivity_check_secs",
+                format!("Must be between {} and {} seconds", MIN_CHECK_INTERVAL_SECS, MAX_CHECK_INTERVAL_SECS),
+            ));
+        }
+
+        for directory in &self.browser_user_data_dirs {
+            if !Path::new(directory).is_absolute() {
+                errors.push(FieldError::new(
+                    "browser_user_data_dirs",
+                    format!("{} is not a full path to a browser's user data folder", directory),
+                ));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: &str, message: impl Into<String>) -> Self {
+        FieldError {
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+// Error returned to the GUI by save_settings
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SettingsError {
+    pub message: String,
+    pub field_errors: Vec<FieldError>,
+}
+
+impl SettingsError {
+    pub fn new(message: impl Into<String>) -> Self {
+        SettingsError {
+            message: message.into(),
+            field_errors: Vec::new(),
+        }
+    }
+}
+
+// Settings shared through Tauri managed state and by the long-lived native
+// host process. Jobs take a snapshot with `get`, so a reload only affects
+// jobs started after it.
+#[derive(Clone)]
+pub struct SettingsStore {
+    current: Arc<RwLock<Settings>>,
+    // Modification time of the settings file when it was last read or written
+    loaded_modified: Arc<Mutex<Option<SystemTime>>>,
+    // Whose overrides `get` applies; the GUI's active profile
+    profile: Arc<RwLock<Profile>>,
+}
+
+impl SettingsStore {
+    pub fn load() -> Self {
+        let loaded_modified = settings_file_modified();
+        let settings = load_settings();
+        logging::apply_level(&settings.log_level);
+        redact::apply_parameters(&settings.redact_query_parameters);
+        i18n::apply_locale(&settings.locale);
+        power::apply_mode(settings.prevent_sleep);
+        SettingsStore {
+            current: Arc::new(RwLock::new(settings)),
+            loaded_modified: Arc::new(Mutex::new(loaded_modified)),
+            profile: Arc::new(RwLock::new(Profile::default_profile())),
+        }
+    }
+
+    // The settings with the profile's overrides applied
+    pub fn get(&self) -> Settings {
+        self.profile.read().unwrap().apply(self.base())
+    }
+
+    // The shared settings file as saved, which `update` patches
+    pub fn base(&self) -> Settings {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn set_profile(&self, profile: Profile) {
+        *self.profile.write().unwrap() = profile;
+    }
+
+    // Cheap stat of the settings file; reloads only when another process saved it
+    pub fn reload_if_changed(&self) -> Vec<String> {
+        let modified = settings_file_modified();
+        if modified == *self.loaded_modified.lock().unwrap() {
+            return Vec::new();
+        }
+        self.reload()
+    }
+
+    // Re-read the settings file and return the names of the fields that changed
+    pub fn reload(&self) -> Vec<String> {
+        let modified = settings_file_modified();
+        let reloaded = load_settings();
+
+        let mut current = self.current.write().unwrap();
+        let changed = changed_fields(&current, &reloaded);
+        if changed.iter().any(|field| field == "log_level") {
+            logging::apply_level(&reloaded.log_level);
+        }
+        if changed.iter().any(|field| field == "redact_query_parameters") {
+            redact::apply_parameters(&reloaded.redact_query_parameters);
+        }
+        if changed.iter().any(|field| field == "locale") {
+            i18n::apply_locale(&reloaded.locale);
+        }
+        if changed.iter().any(|field| field == "prevent_sleep") {
+            power::apply_mode(reloaded.prevent_sleep);
+        }
+        *current = reloaded;
+        *self.loaded_modified.lock().unwrap() = modified;
+
+        if changed.is_empty() {
+            info!("Reloaded settings, nothing changed");
+        } else {
+            info!("Reloaded settings, changed: {}", changed.join(", "));
+        }
+        changed
+    }
+
+    // Apply a partial update, validate the result and persist it
+    pub fn update(&self, patch: Value) -> Result<Settings, SettingsError> {
+        let Value::Object(patch) = patch else {
+            return Err(SettingsError::new("Settings patch must be a JSON object"));
+        };
+
+        let mut current = self.current.write().unwrap();
+        let original = serde_json::to_value(&*current)
+            .map_err(|e| SettingsError::new(format!("Failed to serialize settings: {}", e)))?;
+        let mut merged = original.clone();
+
+        let mut field_errors = Vec::new();
+        for (field, value) in patch {
+            if field == "schema_version" {
+                continue;
+            }
+            if merged.get(&field).is_none() {
+                field_errors.push(FieldError::new(&field, "Unknown setting"));
+                continue;
+            }
+            merged[field.as_str()] = value;
+            // Check each field on its own so a type error names the field
+            if let Err(e) = serde_json::from_value::<Settings>(merged.clone()) {
+                field_errors.push(FieldError::new(&field, format!("Invalid value: {}", e)));
+                merged[field.as_str()] = original[field.as_str()].clone();
+            }
+        }
+
+        let updated = serde_json::from_value::<Settings>(merged)
+            .map_err(|e| SettingsError::new(format!("Invalid settings: {}", e)))?;
+        if let Err(errors) = updated.validate() {
+            field_errors.extend(errors);
+        }
+
+        if !field_errors.is_empty() {
+            return Err(SettingsError {
+                message: "Some settings are invalid".to_string(),
+                field_errors,
+            });
+        }
+
+        let mut updated = updated;
+        move_secrets_to_keychain(&mut updated);
+        save_settings(&updated).map_err(SettingsError::new)?;
+        logging::apply_level(&updated.log_level);
+        redact::apply_parameters(&updated.redact_query_parameters);
+        i18n::apply_locale(&updated.locale);
+        power::apply_mode(updated.prevent_sleep);
+        *current = updated.clone();
+        *self.loaded_modified.lock().unwrap() = settings_file_modified();
+        Ok(updated)
+    }
+}
+
+pub fn get_settings_path() -> Result<PathBuf, String> {
+    Ok(get_app_data_directory()?.join(SETTINGS_FILE_NAME))
+}
+
+fn settings_file_modified() -> Option<SystemTime> {
+    let path = get_settings_path().ok()?;
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+pub fn changed_fields(old: &Settings, new: &Settings) -> Vec<String> {
+    let (Ok(Value::Object(old)), Ok(Value::Object(new))) =
+        (serde_json::to_value(old), serde_json::to_value(new))
+    else {
+        return Vec::new();
+    };
+
+    new.iter()
+        .filter(|(field, value)| old.get(*field) != Some(*value))
+        .map(|(field, _)| field.clone())
+        .collect()
+}
+
+// Missing or unreadable settings fall back to defaults so the host always starts
+fn load_settings() -> Settings {
+    let path = match get_settings_path() {
+        Ok(path) => path,
+        Err(error) => {
+            warn!("{}", error);
+            return Settings::default();
+        }
+    };
+
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(_) => return Settings::default(),
+    };
+
+    let (settings, migrated) = match parse_settings(&contents) {
+        Ok(parsed) => parsed,
+        Err(error) => {
+            warn!("Ignoring invalid {}: {}", path.display(), error);
+            return Settings::default();
+        }
+    };
+
+    let mut settings = settings;
+    let moved_secrets = move_secrets_to_keychain(&mut settings);
+    if migrated || moved_secrets {
+        if let Err(error) = save_settings(&settings) {
+            warn!("{}", error);
+        }
+    }
+
+    settings
+}
+
+// Webhook URLs usually embed an access token. Plain ones are moved into the
+// keychain and replaced by a {secret:...} reference; without a usable keychain
+// they stay as they are. Returns whether anything moved.
+fn move_secrets_to_keychain(settings: &mut Settings) -> bool {
+    let mut moved = false;
+    for index in 0..settings.webhook_urls.len() {
+        if keychain::has_references(&settings.webhook_urls[index]) {
+            continue;
+        }
+        let name = (1..)
+            .map(|number| format!("webhook_url_{}", number))
+            .find(|name| {
+                let reference = keychain::reference(name);
+                !settings.webhook_urls.iter().any(|url| url.contains(&reference)) && !keychain::has_secret(name)
+            })
+            .expect("an unused secret name always exists");
+        if let Err(error) = keychain::set_secret(&name, &settings.webhook_urls[index]) {
+            warn!("Keeping webhook URLs in settings: {}", error);
+            break;
+        }
+        settings.webhook_urls[index] = keychain::reference(&name);
+        moved = true;
+    }
+    if moved {
+        info!("Moved webhook URLs from settings into the keychain");
+    }
+    moved
+}
+
+// Parse the settings file without falling back to defaults, for diagnostics.
+// Ok(None) means no settings have been saved yet.
+pub fn check_settings_file() -> Result<Option<Settings>, String> {
+    let path = get_settings_path()?;
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(format!("Failed to read {}: {}", path.display(), error)),
+    };
+
+    parse_settings(&contents)
+        .map(|(settings, _)| Some(settings))
+        .map_err(|e| format!("{} is invalid: {}", path.display(), e))
+}
+
+// Returns the settings and whether an older schema was upgraded
+fn parse_settings(contents: &str) -> Result<(Settings, bool), String> {
+    let value = serde_json::from_str::<Value>(contents).map_err(|e| e.to_string())?;
+    let (value, migrated) = upgrade_settings_value(value)?;
+    let settings = serde_json::from_value::<Settings>(value).map_err(|e| e.to_string())?;
+    Ok((settings, migrated))
+}
+
+// Bring raw settings JSON from any older schema up to the current one
+pub fn upgrade_settings_value(mut value: Value) -> Result<(Value, bool), String> {
+    let version = value
+        .get("schema_version")
+        .and_then(Value::as_u64)
+        .unwrap_or(0) as u32;
+
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "schema version {} is newer than this build supports ({})",
+            version, SCHEMA_VERSION
+        ));
+    }
+
+    migrate_settings(&mut value, version);
+    Ok((value, version < SCHEMA_VERSION))
+}
+
+fn migrate_settings(value: &mut Value, from_version: u32) {
+    let Some(object) = value.as_object_mut() else {
+        return;
+    };
+
+    // Version 0 files predate the version field; every key they had is still valid
+    if from_version < 1 {
+        object.insert("schema_version".to_string(), Value::from(1));
+    }
+}
+
+pub fn save_settings(settings: &Settings) -> Result<(), String> {
+    let path = get_settings_path()?;
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)
+            .map_err(|e| format!("Failed to create settings directory: {}", e))?;
+    }
+
+    let contents = serde_json::to_string_pretty(settings)
+        .map_err(|e| format!("Failed to serialize settings: {}", e))?;
+    // Write then rename so a crash never leaves a truncated settings file
+    let temp_path = path.with_extension("json.tmp");
+    fs::write(&temp_path, contents)
+        .map_err(|e| format!("Failed to write settings: {}", e))?;
+    fs::rename(&temp_path, &path)
+        .map_err(|e| format!("Failed to save settings: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::env;
+
+    // Default settings with the fields of `patch` replaced
+    fn with(patch: Value) -> Settings {
+        let mut value = serde_json::to_value(Settings::default()).expect("settings");
+        for (field, field_value) in patch.as_object().expect("patch") {
+            value[field] = field_value.clone();
+        }
+        serde_json::from_value(value).expect("settings")
+    }
+
+    #[test]
+    fn the_defaults_are_valid() {
+        Settings::default().validate().expect("valid");
+    }
+
+    #[test]
+    fn rejected_values_are_reported_on_their_field() {
+        let missing_vault = env::temp_dir().join("imgvault-no-such-vault").display().to_string();
+        let cases = [
+            (json!({ "vault_root": "relative/vault" }), "vault_root"),
+            (json!({ "vault_root": missing_vault }), "vault_root"),
+            (json!({ "output_template": "../%(title)s.%(ext)s" }), "output_template"),
+            (json!({ "max_concurrent_downloads": 0 }), "max_concurrent_downloads"),
+            (json!({ "max_concurrent_downloads": MAX_CONCURRENT_DOWNLOADS + 1 }), "max_concurrent_downloads"),
+            (json!({ "domain_max_concurrent": MAX_CONCURRENT_DOWNLOADS + 1 }), "domain_max_concurrent"),
+            (json!({ "domain_min_delay_ms": MAX_DOMAIN_MIN_DELAY_MS + 1 }), "domain_min_delay_ms"),
+            (json!({ "auto_retry_max_attempts": 0 }), "auto_retry_max_attempts"),
+            (json!({ "auto_retry_window_hours": MAX_AUTO_RETRY_WINDOW_HOURS + 1 }), "auto_retry_window_hours"),
+            (
+                json!({ "notification_min_duration_secs": MAX_NOTIFICATION_MIN_DURATION_SECS + 1 }),
+                "notification_min_duration_secs",
+            ),
+            (
+                json!({ "shutdown_grace_period_secs": MAX_SHUTDOWN_GRACE_PERIOD_SECS + 1 }),
+                "shutdown_grace_period_secs",
+            ),
+            (json!({ "min_image_dimensions": { "width": 0, "height": 10 } }), "min_image_dimensions"),
+            (json!({ "max_video_height": 0 }), "max_video_height"),
+            (json!({ "log_level": "loud" }), "log_level"),
+            (json!({ "http_api_port": 80 }), "http_api_port"),
+            (json!({ "websocket_port": DEFAULT_HTTP_API_PORT }), "websocket_port"),
+            (json!({ "http_api_allowed_origins": ["https://example.com"] }), "http_api_allowed_origins"),
+            (json!({ "gallery_dl_domains": ["pixiv.net/artworks"] }), "gallery_dl_domains"),
+        ];
+        for (patch, field) in cases {
+            let errors = with(patch.clone()).validate().expect_err(&patch.to_string());
+            assert!(
+                !errors.is_empty() && errors.iter().all(|error| error.field == field),
+                "{}: {:?}",
+                patch,
+                errors
+            );
+        }
+    }
+
+    #[test]
+    fn missing_fields_take_their_defaults() {
+        let defaults = serde_json::to_value(Settings::default()).expect("settings");
+
+        let (settings, migrated) = parse_settings("{}").expect("empty file");
+        assert_eq!(serde_json::to_value(&settings).expect("settings"), defaults);
+        // Files without a version predate the field
+        assert!(migrated);
+
+        let (settings, migrated) =
+            parse_settings(&json!({ "schema_version": SCHEMA_VERSION, "max_concurrent_downloads": 4 }).to_string())
+                .expect("partial file");
+        assert!(!migrated);
+        assert_eq!(settings.max_concurrent_downloads, 4);
+        let mut expected = defaults.clone();
+        expected["max_concurrent_downloads"] = json!(4);
+        assert_eq!(serde_json::to_value(&settings).expect("settings"), expected);
+    }
+
+    #[test]
+    fn a_newer_schema_is_refused() {
+        let error = parse_settings(&json!({ "schema_version": SCHEMA_VERSION + 1 }).to_string()).expect_err("newer");
+        assert!(error.contains("newer than this build"), "{}", error);
+    }
+}
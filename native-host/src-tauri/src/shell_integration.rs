@@ -0,0 +1,135 @@
+use serde::Serialize;
+use tracing::info;
+
+#[cfg(target_os = "windows")]
+use crate::drop_import::MEDIA_EXTENSIONS;
+
+// Per-extension verbs under SystemFileAssociations add to whatever program
+// owns the file type instead of taking it over
+#[cfg(target_os = "windows")]
+pub const ASSOCIATIONS_KEY_PATH: &str = r"Software\Classes\SystemFileAssociations";
+// The only key we write under each extension, and the only one removed again
+#[cfg(target_os = "windows")]
+pub const VERB_KEY_NAME: &str = "ImgVault.Import";
+#[cfg(target_os = "windows")]
+const VERB_TITLE: &str = "Send to ImgVault";
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShellIntegrationStatus {
+    pub supported: bool,
+    // Every media extension has the verb, pointing at this executable
+    pub installed: bool,
+    // Extensions whose verb exists, whichever executable it points at
+    pub extensions: Vec<String>,
+}
+
+#[cfg(target_os = "windows")]
+pub fn get_shell_integration() -> Result<ShellIntegrationStatus, String> {
+    use winreg::enums::HKEY_CURRENT_USER;
+    use winreg::RegKey;
+
+    let command = import_command()?;
+    let classes = RegKey::predef(HKEY_CURRENT_USER);
+    let mut extensions = Vec::new();
+    let mut current = 0;
+    for extension in MEDIA_EXTENSIONS {
+        let Ok(key) = classes.open_subkey(verb_command_path(extension)) else {
+            continue;
+        };
+        if key.get_value::<String, _>("").is_ok_and(|value| value == command) {
+            current += 1;
+        }
+        extensions.push(extension.to_string());
+    }
+
+    Ok(ShellIntegrationStatus {
+        supported: true,
+        installed: current == MEDIA_EXTENSIONS.len(),
+        extensions,
+    })
+}
+
+// Also repairs verbs left pointing at an executable that was moved
+#[cfg(target_os = "windows")]
+pub fn install_shell_integration() -> Result<ShellIntegrationStatus, String> {
+    use winreg::enums::HKEY_CURRENT_USER;
+    use winreg::RegKey;
+
+    let exe = std::env::current_exe().map_err(|e| format!("Failed to resolve executable path: {}", e))?;
+    let command = import_command()?;
+    let classes = RegKey::predef(HKEY_CURRENT_USER);
+    for extension in MEDIA_EXTENSIONS {
+        let (verb, _) = classes
+            .create_subkey(verb_path(extension))
+            .map_err(|e| format!("Failed to add the Explorer menu for .{}: {}", extension, e))?;
+        verb.set_value("", &VERB_TITLE)
+            .and_then(|_| verb.set_value("Icon", &format!("\"{}\",0", exe.display())))
+            .map_err(|e| format!("Failed to add the Explorer menu for .{}: {}", extension, e))?;
+        let (command_key, _) = verb
+            .create_subkey("command")
+            .map_err(|e| format!("Failed to add the Explorer menu for .{}: {}", extension, e))?;
+        command_key
+            .set_value("", &command)
+            .map_err(|e| format!("Failed to add the Explorer menu for .{}: {}", extension, e))?;
+    }
+    info!("Installed the Explorer \"{}\" menu", VERB_TITLE);
+
+    get_shell_integration()
+}
+
+// Deletes our verb keys only; the shared extension keys above them stay
+#[cfg(target_os = "windows")]
+pub fn remove_shell_integration() -> Result<ShellIntegrationStatus, String> {
+    use winreg::enums::HKEY_CURRENT_USER;
+    use winreg::RegKey;
+
+    let classes = RegKey::predef(HKEY_CURRENT_USER);
+    for extension in MEDIA_EXTENSIONS {
+        match classes.delete_subkey_all(verb_path(extension)) {
+            Ok(()) => {}
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+            Err(error) => return Err(format!("Failed to remove the Explorer menu for .{}: {}", extension, error)),
+        }
+    }
+    info!("Removed the Explorer \"{}\" menu", VERB_TITLE);
+
+    get_shell_integration()
+}
+
+#[cfg(target_os = "windows")]
+fn verb_path(extension: &str) -> String {
+    format!(r"{}\.{}\shell\{}", ASSOCIATIONS_KEY_PATH, extension, VERB_KEY_NAME)
+}
+
+#[cfg(target_os = "windows")]
+fn verb_command_path(extension: &str) -> String {
+    format!(r"{}\command", verb_path(extension))
+}
+
+#[cfg(target_os = "windows")]
+fn import_command() -> Result<String, String> {
+    let exe = std::env::current_exe().map_err(|e| format!("Failed to resolve executable path: {}", e))?;
+    Ok(format!("\"{}\" import \"%1\"", exe.display()))
+}
+
+// Nautilus scripts and Finder quick actions follow once those builds ship
+#[cfg(not(target_os = "windows"))]
+pub fn get_shell_integration() -> Result<ShellIntegrationStatus, String> {
+    Ok(ShellIntegrationStatus {
+        supported: false,
+        installed: false,
+        extensions: Vec::new(),
+    })
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn install_shell_integration() -> Result<ShellIntegrationStatus, String> {
+    info!("Explorer menu requested on an unsupported platform");
+    Err("The Send to ImgVault menu is only supported on Windows for now".to_string())
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn remove_shell_integration() -> Result<ShellIntegrationStatus, String> {
+    Err("The Send to ImgVault menu is only supported on Windows for now".to_string())
+}
@@ -0,0 +1,57 @@
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::history::History;
+use crate::jobs::JobRegistry;
+use crate::protocol::NativeResponse;
+use crate::settings::Settings;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+// Time for stopped jobs to write their queue entries and history after the kill
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The one shutdown sequence for both ways the host goes away: Chrome closing
+// the native messaging port and quitting the GUI. `announce` delivers the
+// "shutting_down" event over the port or the event stream.
+pub fn shut_down(jobs: &JobRegistry, history: &History, settings: &Settings, announce: impl FnOnce(&NativeResponse)) {
+    // Waiting and scheduled jobs stay where they are from here on
+    jobs.begin_shutdown();
+    let (running, _) = jobs.activity();
+    let grace_period = Duration::from_secs(settings.shutdown_grace_period_secs);
+    info!(running, grace_period_secs = grace_period.as_secs(), "Shutting down");
+    announce(&NativeResponse {
+        data: Some(serde_json::json!({
+            "runningJobs": running,
+            "gracePeriodSecs": grace_period.as_secs(),
+        })),
+        ..NativeResponse::job_event("shutting_down", None)
+    });
+
+    // Most fragments finish within a few seconds; a job that completes in
+    // time is recorded as usual
+    wait_until(grace_period, || jobs.activity().0 == 0);
+    let interrupted = jobs.interrupt_all();
+    if interrupted > 0 {
+        info!(interrupted, "Stopped downloads at the end of the grace period, keeping them for resume");
+    }
+    if !wait_until(DRAIN_TIMEOUT, || jobs.in_flight() == 0) {
+        warn!(in_flight = jobs.in_flight(), "Exiting before every job recorded its outcome");
+    }
+
+    if let Err(error) = history.flush() {
+        warn!("{}", error);
+    }
+    info!("Shutdown complete");
+}
+
+// False when the timeout ran out first
+fn wait_until(timeout: Duration, mut done: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !done() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    true
+}
@@ -0,0 +1,152 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Command;
+use tracing::{debug, warn};
+
+use crate::{keychain, url_host};
+use crate::settings::Settings;
+
+const NETRC_FILE_PREFIX: &str = "imgvault-netrc-";
+
+// Login yt-dlp uses for one site. The password lives in the keychain; the
+// settings file only names the secret.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SiteLogin {
+    // URLs on this host or its subdomains use the login, e.g. vimeo.com
+    pub domain: String,
+    // yt-dlp's netrc machine name for the extractor, e.g. vimeo or niconico
+    pub machine: String,
+    pub username: String,
+    pub password_secret: String,
+}
+
+// Temporary netrc file, deleted when dropped, so it is gone even when the
+// download returns early or panics
+pub struct TempNetrc {
+    path: PathBuf,
+}
+
+impl Drop for TempNetrc {
+    fn drop(&mut self) {
+        if let Err(error) = fs::remove_file(&self.path) {
+            warn!("Failed to delete temporary netrc file {}: {}", self.path.display(), error);
+        }
+    }
+}
+
+pub fn validate(login: &SiteLogin) -> Result<(), String> {
+    if login.domain.trim().is_empty() || login.domain.contains(['/', ':', ' ']) {
+        return Err("Domain must be a host name such as vimeo.com".to_string());
+    }
+    for (label, value) in [("Machine", &login.machine), ("Username", &login.username)] {
+        if value.is_empty() || value.chars().any(|c| c.is_whitespace() || c == '"') {
+            return Err(format!("{} must not be empty or contain spaces or quotes", label));
+        }
+    }
+    keychain::validate_name(&login.password_secret)
+}
+
+pub fn has_login(settings: &Settings, url: &str) -> bool {
+    settings.site_logins.iter().any(|login| matches_url(login, url))
+}
+
+fn matches_url(login: &SiteLogin, url: &str) -> bool {
+    url_host(url).is_some_and(|host| host_in_domain(&host, &login.domain))
+}
+
+// True for the domain itself and any of its subdomains
+pub fn host_in_domain(host: &str, domain: &str) -> bool {
+    let domain = domain.trim().trim_start_matches('.').to_ascii_lowercase();
+    host == domain || host.ends_with(&format!(".{}", domain))
+}
+
+// When a configured site matches the URL, write its login to a private netrc
+// file and point yt-dlp at it. Keep the returned guard alive until yt-dlp exits.
+// Errors never contain the password.
+pub fn add_netrc_argument(command: &mut Command, settings: &Settings, url: &str) -> Result<Option<TempNetrc>, String> {
+    let Some(login) = settings.site_logins.iter().find(|login| matches_url(login, url)) else {
+        return Ok(None);
+    };
+
+    let password = keychain::get_secret(&login.password_secret)
+        .map_err(|e| format!("Site login for {}: {}", login.domain, e))?
+        .ok_or_else(|| format!("Site login for {}: secret {} is not set", login.domain, login.password_secret))?;
+    // netrc has no escaping; yt-dlp would read a truncated password
+    if password.chars().any(|c| c.is_whitespace() || c == '"') {
+        return Err(format!(
+            "Site login for {}: passwords with spaces or quotes cannot be passed to yt-dlp",
+            login.domain
+        ));
+    }
+
+    let netrc = write_netrc_file(&format!(
+        "machine {} login {} password {}\n",
+        login.machine, login.username, password
+    ))?;
+    debug!(domain = %login.domain, machine = %login.machine, "Using stored site login");
+    command.arg("--netrc").arg("--netrc-location").arg(&netrc.path);
+    Ok(Some(netrc))
+}
+
+fn write_netrc_file(contents: &str) -> Result<TempNetrc, String> {
+    let mut random = [0u8; 8];
+    getrandom::getrandom(&mut random).map_err(|e| format!("Failed to create netrc file: {}", e))?;
+    let suffix = random.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+    let path = env::temp_dir().join(format!("{}{}", NETRC_FILE_PREFIX, suffix));
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(target_os = "windows")]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+        options.attributes(FILE_ATTRIBUTE_HIDDEN);
+    }
+
+    let mut file = options
+        .open(&path)
+        .map_err(|e| format!("Failed to create netrc file: {}", e))?;
+    // From here on the guard removes the file whatever happens
+    let netrc = TempNetrc { path };
+    #[cfg(target_os = "windows")]
+    restrict_to_current_user(&netrc.path)?;
+    file.write_all(contents.as_bytes())
+        .and_then(|_| file.sync_all())
+        .map_err(|e| format!("Failed to write netrc file: {}", e))?;
+    Ok(netrc)
+}
+
+// Drop the ACL entries inherited from %TEMP% and grant only the current user.
+// Done before the login is written, so no other account ever can read it.
+#[cfg(target_os = "windows")]
+fn restrict_to_current_user(path: &std::path::Path) -> Result<(), String> {
+    use std::os::windows::process::CommandExt;
+    const CREATE_NO_WINDOW: u32 = 0x08000000;
+
+    let user = env::var("USERNAME").map_err(|_| "Failed to restrict netrc file: USERNAME is not set".to_string())?;
+    let output = Command::new("icacls")
+        .arg(path)
+        .arg("/inheritance:r")
+        .arg("/grant:r")
+        .arg(format!("{}:F", user))
+        .creation_flags(CREATE_NO_WINDOW)
+        .output()
+        .map_err(|e| format!("Failed to restrict netrc file: {}", e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "Failed to restrict netrc file: icacls exited with {:?}",
+            output.status.code()
+        ))
+    }
+}
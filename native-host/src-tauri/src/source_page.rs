@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+use tracing::debug;
+
+use crate::{user_agent, validate_download_url};
+
+// Output templates may name the page a download came from; yt-dlp does not
+// know it, so the host fills it in before the template reaches yt-dlp
+pub const PAGE_TITLE_PLACEHOLDER: &str = "%(page_title)s";
+// Bytes, like yt-dlp's own %(title).180B; leaves room for the id and extension
+const MAX_TITLE_FILENAME_BYTES: usize = 150;
+const MAX_TITLE_LENGTH: usize = 1000;
+
+// The page the extension was on when a download was requested
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SourcePage {
+    #[serde(default)]
+    pub page_url: Option<String>,
+    #[serde(default)]
+    pub page_title: Option<String>,
+    // Sent as --referer instead of the page URL when given
+    #[serde(default)]
+    pub referer: Option<String>,
+    // This request's user agent, or "browser"; see user_agent::effective
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    // The browser's navigator.userAgent, for the "browser" choice
+    #[serde(default)]
+    pub browser_user_agent: Option<String>,
+}
+
+impl SourcePage {
+    // Drops page URLs that are not web pages, e.g. chrome:// or file://, and
+    // keeps titles to a sane length without control characters
+    pub fn new(page_url: Option<String>, page_title: Option<String>, referer: Option<String>) -> Self {
+        let web_url = |url: Option<String>, field: &str| {
+            url.filter(|url| match validate_download_url(url) {
+                Ok(()) => true,
+                Err(error) => {
+                    debug!("Ignoring {}: {}", field, error);
+                    false
+                }
+            })
+        };
+        let page_title = page_title
+            .map(|title| {
+                title
+                    .chars()
+                    .filter(|c| !c.is_control())
+                    .take(MAX_TITLE_LENGTH)
+                    .collect::<String>()
+                    .trim()
+                    .to_string()
+            })
+            .filter(|title| !title.is_empty());
+        SourcePage {
+            page_url: web_url(page_url, "page_url"),
+            page_title,
+            referer: web_url(referer, "referer"),
+            user_agent: None,
+            browser_user_agent: None,
+        }
+    }
+
+    // Rejects user agents that could not be sent as one header line
+    pub fn with_user_agents(mut self, user_agent: Option<String>, browser_user_agent: Option<String>) -> Result<Self, String> {
+        if let Some(value) = user_agent.as_deref() {
+            user_agent::validate_setting(value).map_err(|e| format!("Invalid user_agent: {}", e))?;
+        }
+        if let Some(value) = browser_user_agent.as_deref() {
+            user_agent::validate(value).map_err(|e| format!("Invalid browser_user_agent: {}", e))?;
+        }
+        self.user_agent = user_agent;
+        self.browser_user_agent = browser_user_agent;
+        Ok(self)
+    }
+
+    pub fn add_referer_argument(&self, command: &mut Command) {
+        if let Some(referer) = self.referer.as_deref().or(self.page_url.as_deref()) {
+            command.arg("--referer").arg(referer);
+        }
+    }
+
+    // Fills in %(page_title)s, or the literal "page" when the title is unknown
+    pub fn apply_to_output_path(&self, output_path: &str) -> String {
+        if !output_path.contains(PAGE_TITLE_PLACEHOLDER) {
+            return output_path.to_string();
+        }
+        let title = self.page_title.as_deref().map(file_name_safe).unwrap_or_default();
+        let title = truncate_at_char_boundary(&title, MAX_TITLE_FILENAME_BYTES).trim();
+        output_path.replace(PAGE_TITLE_PLACEHOLDER, if title.is_empty() { "page" } else { title })
+    }
+}
+
+// Longest prefix of at most `max_bytes` that ends on a character boundary
+pub fn truncate_at_char_boundary(value: &str, max_bytes: usize) -> &str {
+    if value.len() <= max_bytes {
+        return value;
+    }
+    let mut end = max_bytes;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    &value[..end]
+}
+
+// Characters Windows rejects in file names, plus yt-dlp's own template syntax
+fn file_name_safe(title: &str) -> String {
+    title
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            '%' => '_',
+            c => c,
+        })
+        .collect::<String>()
+        .trim_end_matches(['.', ' '])
+        .to_string()
+}
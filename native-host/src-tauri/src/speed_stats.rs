@@ -0,0 +1,175 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::downloader::Backend;
+use crate::history::History;
+use crate::jobs::parse_progress_speed;
+use crate::site_login::host_in_domain;
+use crate::{current_timestamp_millis, url_host};
+
+pub const DEFAULT_STATS_DAYS: u32 = 30;
+const MAX_STATS_DAYS: u32 = 365;
+// yt-dlp prints progress several times a second; one sample a second is plenty
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+// Peak speed is the best average over this many consecutive samples, so a
+// single burst from a cache does not count
+const PEAK_WINDOW: usize = 5;
+
+// How one completed download went, stored with its history entry
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferStats {
+    // From the request to yt-dlp starting, waiting on limits included
+    pub queue_wait_ms: i64,
+    // From yt-dlp starting to it exiting
+    pub active_ms: i64,
+    pub average_bytes_per_second: u64,
+    pub peak_bytes_per_second: u64,
+    pub backend: String,
+}
+
+// What a completed download reports about itself
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobStats {
+    #[serde(flatten)]
+    pub transfer: TransferStats,
+    // Earlier attempts of the job that failed
+    pub retries: u32,
+}
+
+impl JobStats {
+    pub fn new(transfer: TransferStats) -> Self {
+        JobStats { transfer, retries: 0 }
+    }
+}
+
+// Speed samples of one running download, taken from its progress lines and
+// kept in constant memory
+pub struct SpeedSampler {
+    backend: Backend,
+    started: Instant,
+    started_at: i64,
+    recent: [u64; PEAK_WINDOW],
+    count: u64,
+    sum: u64,
+    peak: u64,
+    last_sample: Option<Instant>,
+}
+
+impl SpeedSampler {
+    pub fn new(backend: Backend) -> Self {
+        SpeedSampler {
+            backend,
+            started: Instant::now(),
+            started_at: current_timestamp_millis(),
+            recent: [0; PEAK_WINDOW],
+            count: 0,
+            sum: 0,
+            peak: 0,
+            last_sample: None,
+        }
+    }
+
+    pub fn observe(&mut self, line: &str, now: Instant) {
+        let Some(bytes_per_second) = parse_progress_speed(line) else {
+            return;
+        };
+        if self.last_sample.is_some_and(|last| now.duration_since(last) < SAMPLE_INTERVAL) {
+            return;
+        }
+        self.last_sample = Some(now);
+        self.recent[(self.count % PEAK_WINDOW as u64) as usize] = bytes_per_second;
+        self.count += 1;
+        self.sum += bytes_per_second;
+        let window = self.count.min(PEAK_WINDOW as u64);
+        self.peak = self.peak.max(self.recent.iter().sum::<u64>() / window);
+    }
+
+    // `queued_at` is when the download was requested
+    pub fn finish(&self, queued_at: i64) -> TransferStats {
+        TransferStats {
+            queue_wait_ms: (self.started_at - queued_at).max(0),
+            active_ms: self.started.elapsed().as_millis() as i64,
+            average_bytes_per_second: self.sum.checked_div(self.count).unwrap_or(0),
+            peak_bytes_per_second: self.peak,
+            backend: self.backend.name().to_string(),
+        }
+    }
+}
+
+// One site in get_speed_stats, for the settings dashboard
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DomainSpeedStats {
+    pub domain: String,
+    pub downloads: usize,
+    pub completed: usize,
+    pub failed: usize,
+    // Completed out of completed and failed; cancelled jobs do not count
+    pub success_rate: f64,
+    pub median_bytes_per_second: Option<u64>,
+    pub median_active_ms: Option<i64>,
+}
+
+// Per-site aggregates of the downloads that finished in the last `days`,
+// busiest site first. `domain` limits them to that site and its subdomains.
+pub fn by_domain(history: &History, domain: Option<&str>, days: Option<u32>) -> Result<Vec<DomainSpeedStats>, String> {
+    let days = days.unwrap_or(DEFAULT_STATS_DAYS);
+    if !(1..=MAX_STATS_DAYS).contains(&days) {
+        return Err(format!("days must be between 1 and {}", MAX_STATS_DAYS));
+    }
+    let since = current_timestamp_millis() - days as i64 * 24 * 60 * 60 * 1000;
+
+    let mut sites: BTreeMap<String, (DomainSpeedStats, Vec<u64>, Vec<i64>)> = BTreeMap::new();
+    for row in history.transfer_rows(since)? {
+        let Some(host) = url_host(&row.url) else {
+            continue;
+        };
+        if domain.is_some_and(|domain| !host_in_domain(&host, domain)) {
+            continue;
+        }
+        let site = host.strip_prefix("www.").unwrap_or(&host).to_string();
+        let (stats, speeds, durations) = sites.entry(site.clone()).or_insert_with(|| {
+            let stats = DomainSpeedStats {
+                domain: site,
+                downloads: 0,
+                completed: 0,
+                failed: 0,
+                success_rate: 0.0,
+                median_bytes_per_second: None,
+                median_active_ms: None,
+            };
+            (stats, Vec::new(), Vec::new())
+        });
+        stats.downloads += 1;
+        match row.status.as_str() {
+            "completed" => stats.completed += 1,
+            "failed" => stats.failed += 1,
+            _ => {}
+        }
+        speeds.extend(row.average_bytes_per_second);
+        durations.extend(row.active_ms);
+    }
+
+    let mut stats = sites
+        .into_values()
+        .map(|(mut stats, mut speeds, mut durations)| {
+            let finished = stats.completed + stats.failed;
+            if finished > 0 {
+                stats.success_rate = stats.completed as f64 / finished as f64;
+            }
+            stats.median_bytes_per_second = median(&mut speeds);
+            stats.median_active_ms = median(&mut durations);
+            stats
+        })
+        .collect::<Vec<_>>();
+    stats.sort_by(|a, b| b.downloads.cmp(&a.downloads).then_with(|| a.domain.cmp(&b.domain)));
+    Ok(stats)
+}
+
+fn median<T: Ord + Copy>(values: &mut [T]) -> Option<T> {
+    values.sort_unstable();
+    values.get(values.len() / 2).copied()
+}
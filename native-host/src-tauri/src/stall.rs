@@ -0,0 +1,157 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::jobs::parse_byte_size;
+use crate::settings::Settings;
+
+pub const MIN_STALL_WINDOW_SECS: u64 = 30;
+pub const MAX_STALL_WINDOW_SECS: u64 = 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StallAction {
+    // Send a "stalled" event and let the download carry on
+    #[default]
+    Notify,
+    // Stop it and queue it again right away, as one of its auto-retry attempts
+    Restart,
+}
+
+// A job whose download crept along below stall_min_bytes_per_second for a
+// whole stall_window_secs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Stall {
+    pub bytes_per_second: u64,
+    pub window_secs: u64,
+    pub seconds_since_progress: u64,
+    // yt-dlp warned that the site throttles the download
+    pub throttled: bool,
+    pub action: StallAction,
+}
+
+impl Stall {
+    // Starts with "Download stalled", which classify_download_error relies on
+    pub fn describe(&self) -> String {
+        let mut message = format!(
+            "Download stalled at {:.1} KB/s over the last {} seconds",
+            self.bytes_per_second as f64 / 1000.0,
+            self.window_secs
+        );
+        if self.throttled {
+            message.push_str("; the site is throttling it");
+        }
+        if self.action == StallAction::Restart {
+            message.push_str("; restarting it");
+        }
+        message
+    }
+}
+
+// Watches one job's progress lines. Only the download phase counts, so slow
+// extraction before it and merging after it never look like a stall.
+pub struct StallDetector {
+    min_bytes_per_second: u64,
+    window: Duration,
+    action: StallAction,
+    // (when, bytes downloaded so far) for each progress line, oldest first
+    samples: VecDeque<(Instant, u64)>,
+    // Files yt-dlp finished earlier in this run, e.g. the video before the audio
+    finished_bytes: u64,
+    current_bytes: u64,
+    downloading_since: Option<Instant>,
+    throttled: bool,
+    // Reported once until the speed recovers
+    reported: bool,
+}
+
+impl StallDetector {
+    // None when stall detection is off
+    pub fn new(settings: &Settings) -> Option<Self> {
+        (settings.stall_min_bytes_per_second > 0).then(|| StallDetector {
+            min_bytes_per_second: settings.stall_min_bytes_per_second,
+            window: Duration::from_secs(settings.stall_window_secs),
+            action: settings.stall_action,
+            samples: VecDeque::new(),
+            finished_bytes: 0,
+            current_bytes: 0,
+            downloading_since: None,
+            throttled: false,
+            reported: false,
+        })
+    }
+
+    pub fn observe(&mut self, line: &str, now: Instant) {
+        let trimmed = line.trim();
+        if trimmed.starts_with("WARNING:") && trimmed.to_lowercase().contains("throttl") {
+            self.throttled = true;
+            return;
+        }
+        let Some((percent, total_bytes)) = parse_progress_bytes(trimmed) else {
+            return;
+        };
+
+        let bytes = (total_bytes as f64 * percent / 100.0) as u64;
+        if bytes < self.current_bytes {
+            self.finished_bytes += self.current_bytes;
+        }
+        self.current_bytes = bytes;
+        if percent >= 100.0 {
+            // Post-processing may follow without progress lines
+            self.downloading_since = None;
+            self.samples.clear();
+            return;
+        }
+        if self.downloading_since.is_none() {
+            self.downloading_since = Some(now);
+        }
+        self.samples.push_back((now, self.finished_bytes + self.current_bytes));
+        // One sample at or before the start of the window is enough
+        while self.samples.len() > 1 && now.duration_since(self.samples[1].0) >= self.window {
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn check(&mut self, now: Instant) -> Option<Stall> {
+        let since = self.downloading_since?;
+        if now.duration_since(since) < self.window {
+            return None;
+        }
+        let &(first_at, first_bytes) = self.samples.front()?;
+        let &(last_at, last_bytes) = self.samples.back()?;
+        let elapsed = now.duration_since(first_at).as_secs().max(1);
+        let bytes_per_second = (last_bytes - first_bytes) / elapsed;
+
+        if bytes_per_second >= self.min_bytes_per_second {
+            self.reported = false;
+            return None;
+        }
+        if self.reported {
+            return None;
+        }
+        self.reported = true;
+        Some(Stall {
+            bytes_per_second,
+            window_secs: self.window.as_secs(),
+            seconds_since_progress: now.duration_since(last_at).as_secs(),
+            throttled: self.throttled,
+            action: self.action,
+        })
+    }
+}
+
+// Percent and total size from a progress line such as
+// "[download]  45.3% of ~ 100.00MiB at 2.50MiB/s ETA 00:22 (frag 5/20)"
+fn parse_progress_bytes(line: &str) -> Option<(f64, u64)> {
+    let mut words = line.strip_prefix("[download]")?.split_whitespace();
+    let percent = words.next()?.strip_suffix('%')?.parse::<f64>().ok()?;
+    if words.next()? != "of" {
+        return None;
+    }
+    let size = match words.next()? {
+        "~" => words.next()?,
+        size => size.trim_start_matches('~'),
+    };
+    Some((percent, parse_byte_size(size)?))
+}
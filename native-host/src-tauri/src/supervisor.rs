@@ -0,0 +1,153 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use sysinfo::{Pid, ProcessRefreshKind, System};
+use tracing::warn;
+
+use crate::settings::Settings;
+
+pub const MIN_FIRST_OUTPUT_WINDOW_SECS: u64 = 10;
+pub const MAX_FIRST_OUTPUT_WINDOW_SECS: u64 = 60 * 60;
+// Reading a process's counters is cheap, but not every half second
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(2);
+const BYTES_PER_MB: u64 = 1024 * 1024;
+
+// Why the supervisor stopped a child
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SupervisorKill {
+    // Not a byte of output within the window after spawning
+    NoOutput { window_secs: u64 },
+    MemoryCeiling { memory_bytes: u64, ceiling_bytes: u64 },
+}
+
+impl SupervisorKill {
+    // Starts with "Stopped by supervision", which classify_download_error relies on
+    pub fn describe(&self, program: &str) -> String {
+        match self {
+            SupervisorKill::NoOutput { window_secs } => format!(
+                "Stopped by supervision: {} printed nothing within {} seconds",
+                program, window_secs
+            ),
+            SupervisorKill::MemoryCeiling { memory_bytes, ceiling_bytes } => format!(
+                "Stopped by supervision: {} used {} MB, over the {} MB memory limit",
+                program,
+                memory_bytes / BYTES_PER_MB,
+                ceiling_bytes / BYTES_PER_MB
+            ),
+        }
+    }
+}
+
+// What supervision saw of one child, kept with its history entry
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SupervisionReport {
+    pub program: String,
+    // None when the child never printed anything
+    pub first_output_ms: Option<u64>,
+    pub peak_memory_bytes: u64,
+    pub peak_cpu_percent: f32,
+    pub events: Vec<String>,
+}
+
+// Watches one child process whatever it prints: yt-dlp stuck in an extractor
+// prints nothing, so progress-based stall detection never starts. Works for
+// any downloader child, given the program name for messages.
+pub struct ChildSupervisor {
+    pid: Pid,
+    spawned_at: Instant,
+    first_output_window: Option<Duration>,
+    memory_ceiling_bytes: Option<u64>,
+    first_output_at: Option<Instant>,
+    last_sample: Option<Instant>,
+    system: System,
+    report: SupervisionReport,
+    // Reported once
+    killed: Option<SupervisorKill>,
+}
+
+impl ChildSupervisor {
+    pub fn new(program: &str, pid: u32, settings: &Settings, spawned_at: Instant) -> Self {
+        ChildSupervisor {
+            pid: Pid::from_u32(pid),
+            spawned_at,
+            first_output_window: (settings.first_output_timeout_secs > 0)
+                .then(|| Duration::from_secs(settings.first_output_timeout_secs)),
+            memory_ceiling_bytes: (settings.child_memory_limit_mb > 0)
+                .then(|| settings.child_memory_limit_mb * BYTES_PER_MB),
+            first_output_at: None,
+            last_sample: None,
+            system: System::new(),
+            report: SupervisionReport {
+                program: program.to_string(),
+                ..SupervisionReport::default()
+            },
+            killed: None,
+        }
+    }
+
+    pub fn observe_output(&mut self, now: Instant) {
+        if self.first_output_at.is_none() {
+            self.first_output_at = Some(now);
+            self.report.first_output_ms = Some(now.duration_since(self.spawned_at).as_millis() as u64);
+        }
+    }
+
+    // Some once, when the child has to be stopped
+    pub fn check(&mut self, now: Instant) -> Option<SupervisorKill> {
+        if self.killed.is_some() {
+            return None;
+        }
+        let kill = self.check_first_output(now).or_else(|| self.sample(now));
+        if let Some(kill) = kill {
+            let description = kill.describe(&self.report.program);
+            warn!("{}", description);
+            self.report.events.push(description);
+            self.killed = Some(kill);
+        }
+        kill
+    }
+
+    // Something worth keeping with the report that the supervisor did not decide
+    pub fn note(&mut self, event: String) {
+        self.report.events.push(event);
+    }
+
+    pub fn report(&self) -> SupervisionReport {
+        self.report.clone()
+    }
+
+    fn check_first_output(&self, now: Instant) -> Option<SupervisorKill> {
+        let window = self.first_output_window?;
+        (self.first_output_at.is_none() && now.duration_since(self.spawned_at) >= window)
+            .then_some(SupervisorKill::NoOutput { window_secs: window.as_secs() })
+    }
+
+    fn sample(&mut self, now: Instant) -> Option<SupervisorKill> {
+        if self.last_sample.is_some_and(|last| now.duration_since(last) < SAMPLE_INTERVAL) {
+            return None;
+        }
+        self.last_sample = Some(now);
+        // CPU usage is measured between two refreshes, so the first reads 0
+        if !self
+            .system
+            .refresh_process_specifics(self.pid, ProcessRefreshKind::new().with_cpu().with_memory())
+        {
+            return None;
+        }
+        let process = self.system.process(self.pid)?;
+        let memory_bytes = process.memory();
+        self.report.peak_memory_bytes = self.report.peak_memory_bytes.max(memory_bytes);
+        self.report.peak_cpu_percent = self.report.peak_cpu_percent.max(process.cpu_usage());
+
+        let ceiling_bytes = self.memory_ceiling_bytes?;
+        (memory_bytes > ceiling_bytes).then_some(SupervisorKill::MemoryCeiling { memory_bytes, ceiling_bytes })
+    }
+}
+
+pub fn from_column(value: Option<String>) -> Option<SupervisionReport> {
+    value.and_then(|value| serde_json::from_str(&value).ok())
+}
+
+pub fn to_column(report: Option<&SupervisionReport>) -> Option<String> {
+    report.and_then(|report| serde_json::to_string(report).ok())
+}
@@ -0,0 +1,91 @@
+use keyring::credential::{Credential, CredentialApi, CredentialBuilderApi};
+use std::any::Any;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard, Once, OnceLock};
+
+use crate::get_app_data_directory;
+
+static TURN: Mutex<()> = Mutex::new(());
+static DATA_HOME: OnceLock<PathBuf> = OnceLock::new();
+
+// The app data directory of the unit tests that need one. There is one per
+// test binary, so those tests take turns with it; each turn starts empty.
+pub(crate) struct AppData {
+    pub(crate) directory: PathBuf,
+    _turn: MutexGuard<'static, ()>,
+}
+
+pub(crate) fn app_data() -> AppData {
+    // A failed test must not fail every one after it
+    let turn = TURN.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    DATA_HOME.get_or_init(|| {
+        let data_home = env::temp_dir().join(format!("imgvault-unit-{}", std::process::id()));
+        env::set_var("XDG_DATA_HOME", &data_home);
+        env::set_var("LOCALAPPDATA", &data_home);
+        data_home
+    });
+    let directory = get_app_data_directory().expect("app data directory");
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).expect("app data directory");
+    AppData { directory, _turn: turn }
+}
+
+// Secrets kept in memory in place of the OS keychain, which test machines
+// rarely have unlocked. Stored values outlive the entry, like the real one.
+static MEMORY_SECRETS: Mutex<Option<HashMap<String, String>>> = Mutex::new(None);
+
+#[derive(Debug)]
+struct MemoryKeychain;
+
+#[derive(Debug)]
+struct MemorySecret {
+    name: String,
+}
+
+impl CredentialBuilderApi for MemoryKeychain {
+    fn build(&self, _target: Option<&str>, _service: &str, name: &str) -> keyring::Result<Box<Credential>> {
+        Ok(Box::new(MemorySecret { name: name.to_string() }))
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl CredentialApi for MemorySecret {
+    fn set_password(&self, password: &str) -> keyring::Result<()> {
+        let mut secrets = MEMORY_SECRETS.lock().unwrap();
+        secrets.get_or_insert_with(HashMap::new).insert(self.name.clone(), password.to_string());
+        Ok(())
+    }
+
+    fn get_password(&self) -> keyring::Result<String> {
+        let secrets = MEMORY_SECRETS.lock().unwrap();
+        secrets
+            .as_ref()
+            .and_then(|secrets| secrets.get(&self.name).cloned())
+            .ok_or(keyring::Error::NoEntry)
+    }
+
+    fn delete_password(&self) -> keyring::Result<()> {
+        let mut secrets = MEMORY_SECRETS.lock().unwrap();
+        secrets
+            .as_mut()
+            .and_then(|secrets| secrets.remove(&self.name))
+            .map(|_| ())
+            .ok_or(keyring::Error::NoEntry)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+// Points every keychain entry of this test binary at the memory store
+pub(crate) fn memory_keychain() {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| keyring::set_default_credential_builder(Box::new(MemoryKeychain)));
+}
@@ -0,0 +1,150 @@
+// Calendar conversions for the few places that need dates without a time crate
+
+const MILLIS_PER_DAY: i64 = 86_400_000;
+
+// Days since 1970-01-01 to a UTC calendar date (Howard Hinnant's algorithm)
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u32;
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+// The inverse of civil_from_days
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month_index = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let day_of_year = (153 * month_index + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// Parses an RFC 3339 timestamp such as 2024-05-01T22:30:00+02:00 or
+// 2024-05-01T20:30:00.5Z into Unix milliseconds
+pub fn parse_rfc3339(value: &str) -> Result<i64, String> {
+    let invalid = || format!("Invalid RFC 3339 timestamp: {}", value);
+    let value = value.trim();
+    if value.len() < 20 || !value.is_char_boundary(19) {
+        return Err(invalid());
+    }
+    let (date_time, zone) = value.split_at(19);
+    let bytes = date_time.as_bytes();
+    if bytes[4] != b'-' || bytes[7] != b'-' || !matches!(bytes[10], b'T' | b't' | b' ') || bytes[13] != b':' || bytes[16] != b':' {
+        return Err(invalid());
+    }
+    let number = |range: std::ops::Range<usize>| -> Result<i64, String> {
+        let digits = &date_time[range];
+        if digits.bytes().all(|b| b.is_ascii_digit()) {
+            digits.parse::<i64>().map_err(|_| invalid())
+        } else {
+            Err(invalid())
+        }
+    };
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    let (hour, minute, second) = (number(11..13)?, number(14..16)?, number(17..19)?);
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        _ => return Err(invalid()),
+    };
+    // A leap second is read as the last second of the minute
+    if day < 1 || day > days_in_month || hour > 23 || minute > 59 || second > 60 {
+        return Err(invalid());
+    }
+
+    // Fractional seconds are optional and only kept to the millisecond
+    let (fraction, offset) = match zone.strip_prefix('.') {
+        Some(rest) => {
+            let digits = rest.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+            if digits == 0 {
+                return Err(invalid());
+            }
+            let millis = format!("{:0<3}", &rest[..digits.min(3)]).parse::<i64>().map_err(|_| invalid())?;
+            (millis, &rest[digits..])
+        }
+        None => (0, zone),
+    };
+    let offset_minutes = match offset {
+        "Z" | "z" => 0,
+        _ => {
+            let sign = match offset.as_bytes().first() {
+                Some(b'+') => 1,
+                Some(b'-') => -1,
+                _ => return Err(invalid()),
+            };
+            let (hours, minutes) = offset[1..].split_once(':').ok_or_else(invalid)?;
+            if hours.len() != 2 || minutes.len() != 2 {
+                return Err(invalid());
+            }
+            let hours = hours.parse::<i64>().map_err(|_| invalid())?;
+            let minutes = minutes.parse::<i64>().map_err(|_| invalid())?;
+            if hours > 23 || minutes > 59 {
+                return Err(invalid());
+            }
+            sign * (hours * 60 + minutes)
+        }
+    };
+
+    let days = days_from_civil(year, month as u32, day as u32);
+    let seconds = hour * 3600 + minute * 60 + second.min(59) - offset_minutes * 60;
+    Ok(days * MILLIS_PER_DAY + seconds * 1000 + fraction)
+}
+
+// Unix milliseconds as an RFC 3339 UTC timestamp, e.g. 2024-05-01T20:30:00Z
+pub fn format_rfc3339(millis: i64) -> String {
+    let days = millis.div_euclid(MILLIS_PER_DAY);
+    let seconds = millis.rem_euclid(MILLIS_PER_DAY) / 1000;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+// Offset of local time from UTC right now, in minutes east of Greenwich
+#[cfg(target_os = "windows")]
+pub fn local_utc_offset_minutes() -> i64 {
+    use winapi::um::timezoneapi::{GetTimeZoneInformation, TIME_ZONE_INFORMATION};
+    const TIME_ZONE_ID_STANDARD: u32 = 1;
+    const TIME_ZONE_ID_DAYLIGHT: u32 = 2;
+
+    let mut info: TIME_ZONE_INFORMATION = unsafe { std::mem::zeroed() };
+    let zone = unsafe { GetTimeZoneInformation(&mut info) };
+    // Bias is minutes to add to local time to get UTC
+    let bias = match zone {
+        TIME_ZONE_ID_STANDARD => info.Bias + info.StandardBias,
+        TIME_ZONE_ID_DAYLIGHT => info.Bias + info.DaylightBias,
+        _ => info.Bias,
+    };
+    -(bias as i64)
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn local_utc_offset_minutes() -> i64 {
+    // `date +%z` prints the offset as +HHMM or -HHMM
+    let Ok(output) = std::process::Command::new("date").arg("+%z").output() else {
+        return 0;
+    };
+    let offset = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let sign = if offset.starts_with('-') { -1 } else { 1 };
+    let digits = offset.trim_start_matches(['+', '-']);
+    match (digits.get(..2).map(str::parse::<i64>), digits.get(2..4).map(str::parse::<i64>)) {
+        (Some(Ok(hours)), Some(Ok(minutes))) => sign * (hours * 60 + minutes),
+        _ => 0,
+    }
+}
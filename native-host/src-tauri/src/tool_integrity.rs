@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+use crate::webhooks::hash_file;
+use crate::{get_app_data_directory, long_path};
+
+// Copies of yt-dlp, gallery-dl and ffmpeg that ImgVault installs for itself
+// live here. Only these are checked; a binary the user installed elsewhere
+// is theirs to update.
+const TOOLS_DIRECTORY_NAME: &str = "tools";
+// File name to SHA-256, written when a tool is installed or trusted
+const CHECKSUMS_FILE_NAME: &str = "checksums.json";
+pub const TAMPERED_PREFIX: &str = "Tampered dependency";
+
+// Binaries that matched their checksum, with the modification time and size
+// they had then; hashing again only happens once either changes
+static VERIFIED: Mutex<Vec<(PathBuf, SystemTime, u64)>> = Mutex::new(Vec::new());
+
+pub fn tools_directory() -> Result<PathBuf, String> {
+    Ok(get_app_data_directory()?.join(TOOLS_DIRECTORY_NAME))
+}
+
+fn checksums_path() -> Result<PathBuf, String> {
+    Ok(tools_directory()?.join(CHECKSUMS_FILE_NAME))
+}
+
+fn load_checksums() -> Result<BTreeMap<String, String>, String> {
+    let path = checksums_path()?;
+    match fs::read_to_string(&path) {
+        Ok(text) => serde_json::from_str(&text).map_err(|e| format!("Failed to parse {}: {}", path.display(), e)),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(error) => Err(format!("Failed to read {}: {}", path.display(), error)),
+    }
+}
+
+fn save_checksums(checksums: &BTreeMap<String, String>) -> Result<(), String> {
+    let path = checksums_path()?;
+    let text = serde_json::to_string_pretty(checksums).map_err(|e| format!("Failed to serialize checksums: {}", e))?;
+    fs::create_dir_all(tools_directory()?)
+        .and_then(|_| fs::write(&path, text))
+        .map_err(|e| format!("Failed to save {}: {}", path.display(), e))
+}
+
+// The name a managed binary is recorded under, or None when `program` is
+// not one of ours. Bare names such as "yt-dlp" come from PATH and never are.
+fn managed_name(program: &Path) -> Option<String> {
+    if !program.is_absolute() {
+        return None;
+    }
+    let tools = long_path::canonical(&tools_directory().ok()?).ok()?;
+    let program = long_path::canonical(program).ok()?;
+    let relative = program.strip_prefix(&tools).ok()?;
+    Some(relative.to_string_lossy().replace('\\', "/"))
+}
+
+// Records the current hash of a managed binary, after installing or
+// updating it, or when the user vouches for a copy they patched
+pub fn record(program: &Path) -> Result<String, String> {
+    let Some(name) = managed_name(program) else {
+        return Err(format!("{} is not in ImgVault's tools folder", program.display()));
+    };
+    let (_, sha256) = hash_file(&program.display().to_string())
+        .map_err(|e| format!("Failed to hash {}: {}", program.display(), e))?;
+    let mut checksums = load_checksums()?;
+    checksums.insert(name, sha256.clone());
+    save_checksums(&checksums)?;
+    forget(program);
+    info!(program = %program.display(), sha256 = %sha256, "Recorded checksum of managed tool");
+    Ok(sha256)
+}
+
+fn forget(program: &Path) {
+    VERIFIED.lock().unwrap().retain(|(path, _, _)| path != program);
+}
+
+// Called before spawning a tool. Binaries outside the tools folder pass
+// unchecked; a managed one must still have the hash it was installed with.
+pub fn verify(program: &str) -> Result<(), String> {
+    let path = Path::new(program);
+    let Some(name) = managed_name(path) else {
+        return Ok(());
+    };
+    let metadata = fs::metadata(long_path::extended(path))
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+    let stamp = (path.to_path_buf(), modified, metadata.len());
+    if VERIFIED.lock().unwrap().contains(&stamp) {
+        return Ok(());
+    }
+
+    let Some(expected) = load_checksums()?.remove(&name) else {
+        warn!(program = %path.display(), "Managed tool has no recorded checksum; refusing to run it");
+        return Err(format!(
+            "{}: {} has no recorded checksum. Reinstall it, or trust it if you put it there yourself.",
+            TAMPERED_PREFIX,
+            path.display()
+        ));
+    };
+    let (_, actual) = hash_file(program).map_err(|e| format!("Failed to hash {}: {}", path.display(), e))?;
+    if actual != expected {
+        warn!(program = %path.display(), expected = %expected, actual = %actual, "Managed tool changed since it was installed; refusing to run it");
+        return Err(format!(
+            "{}: {} changed since it was installed (SHA-256 {} instead of {}). Reinstall it, or trust it if you patched it yourself.",
+            TAMPERED_PREFIX,
+            path.display(),
+            actual,
+            expected
+        ));
+    }
+
+    let mut verified = VERIFIED.lock().unwrap();
+    verified.retain(|(verified_path, _, _)| verified_path != path);
+    verified.push(stamp);
+    Ok(())
+}
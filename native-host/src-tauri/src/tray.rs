@@ -0,0 +1,215 @@
+use std::path::Path;
+use std::time::Duration;
+use tauri::{
+    AppHandle, CustomMenuItem, Manager, SystemTray, SystemTrayEvent, SystemTrayMenu,
+    SystemTrayMenuItem, SystemTraySubmenu,
+};
+use tracing::{info, warn};
+
+use crate::connectivity;
+use crate::gui::focus_main_window;
+use crate::history::{History, HistoryEntry};
+use crate::jobs::JobRegistry;
+use crate::process_priority::ProcessPriority;
+use crate::queue::{pause_queue_jobs, resume_queue_jobs};
+use crate::settings::SettingsStore;
+use crate::{get_vault_directory, reveal_in_file_manager};
+
+const RECENT_DOWNLOADS_LIMIT: usize = 5;
+const RECENT_ITEM_PREFIX: &str = "recent:";
+
+pub fn build_tray(history: &History, settings: &SettingsStore) -> SystemTray {
+    SystemTray::new()
+        .with_menu(build_tray_menu(&recent_downloads(history), false, settings.get().clipboard_watch))
+        .with_tooltip("ImgVault Native Host")
+}
+
+fn recent_downloads(history: &History) -> Vec<HistoryEntry> {
+    history.recent(RECENT_DOWNLOADS_LIMIT).unwrap_or_else(|error| {
+        warn!("{}", error);
+        Vec::new()
+    })
+}
+
+fn build_tray_menu(recent: &[HistoryEntry], paused: bool, watching_clipboard: bool) -> SystemTrayMenu {
+    let mut recent_menu = SystemTrayMenu::new();
+    if recent.is_empty() {
+        recent_menu = recent_menu.add_item(CustomMenuItem::new("recent_empty", "No downloads yet").disabled());
+    }
+    for entry in recent {
+        let item = CustomMenuItem::new(
+            format!("{}{}", RECENT_ITEM_PREFIX, entry.id),
+            recent_entry_title(entry),
+        );
+        recent_menu = recent_menu.add_item(if entry.file_path.is_some() { item } else { item.disabled() });
+    }
+
+    SystemTrayMenu::new()
+        .add_item(CustomMenuItem::new("show", "Show ImgVault"))
+        .add_item(CustomMenuItem::new("open_vault", "Open vault folder"))
+        .add_item(CustomMenuItem::new("toggle_pause", pause_item_title(paused)))
+        .add_item(CustomMenuItem::new("boost_downloads", "Boost current downloads"))
+        .add_item(CustomMenuItem::new("toggle_clipboard", clipboard_item_title(watching_clipboard)))
+        .add_submenu(SystemTraySubmenu::new("Recent downloads", recent_menu))
+        .add_native_item(SystemTrayMenuItem::Separator)
+        .add_item(CustomMenuItem::new("quit", "Quit"))
+}
+
+fn pause_item_title(paused: bool) -> &'static str {
+    if paused {
+        "Resume queue"
+    } else {
+        "Pause queue"
+    }
+}
+
+fn clipboard_item_title(watching: bool) -> &'static str {
+    if watching {
+        "Stop watching clipboard"
+    } else {
+        "Watch clipboard for links"
+    }
+}
+
+fn recent_entry_title(entry: &HistoryEntry) -> String {
+    let name = entry
+        .file_path
+        .as_deref()
+        .and_then(|path| Path::new(path).file_name())
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| entry.url.clone());
+
+    match entry.status.as_str() {
+        "completed" => name,
+        status => format!("{} ({})", name, status),
+    }
+}
+
+pub fn handle_tray_event(app: &AppHandle, event: SystemTrayEvent) {
+    match event {
+        SystemTrayEvent::LeftClick { .. } | SystemTrayEvent::DoubleClick { .. } => focus_main_window(app),
+        SystemTrayEvent::MenuItemClick { id, .. } => handle_menu_item(app, &id),
+        _ => {}
+    }
+}
+
+fn handle_menu_item(app: &AppHandle, id: &str) {
+    match id {
+        "show" => focus_main_window(app),
+        "open_vault" => {
+            let settings = app.state::<SettingsStore>().get();
+            let opened = get_vault_directory(&settings).and_then(|directory| reveal_in_file_manager(&directory));
+            if let Err(error) = opened {
+                warn!("Failed to open vault folder: {}", error);
+            }
+        }
+        "toggle_pause" => {
+            let jobs = app.state::<JobRegistry>();
+            let paused = !jobs.is_paused();
+            if paused {
+                pause_queue_jobs(&jobs);
+            } else if let Err(error) = resume_queue_jobs(&jobs) {
+                warn!("Failed to resume paused downloads: {}", error);
+            }
+            let _ = app.tray_handle().get_item("toggle_pause").set_title(pause_item_title(paused));
+        }
+        // Only for downloads started from this window; the browser's run in
+        // their own host processes
+        "boost_downloads" => {
+            let boosted = app.state::<JobRegistry>().set_all_process_priorities(ProcessPriority::Normal);
+            info!(boosted, "Downloads boosted to normal priority from the tray");
+        }
+        "toggle_clipboard" => {
+            let settings = app.state::<SettingsStore>();
+            let watching = !settings.get().clipboard_watch;
+            match settings.update(serde_json::json!({ "clipboard_watch": watching })) {
+                Ok(_) => {
+                    info!(watching, "Clipboard watcher toggled from the tray");
+                    let _ = app
+                        .tray_handle()
+                        .get_item("toggle_clipboard")
+                        .set_title(clipboard_item_title(watching));
+                }
+                Err(error) => warn!("Failed to toggle the clipboard watcher: {}", error.message),
+            }
+        }
+        // Exiting runs the same shutdown as closing the last window, which stops all children
+        "quit" => app.exit(0),
+        _ => {
+            if let Some(entry_id) = id.strip_prefix(RECENT_ITEM_PREFIX).and_then(|value| value.parse::<i64>().ok()) {
+                reveal_recent_download(app, entry_id);
+            }
+        }
+    }
+}
+
+fn reveal_recent_download(app: &AppHandle, entry_id: i64) {
+    let history = app.state::<History>();
+    let file_path = recent_downloads(&history)
+        .into_iter()
+        .find(|entry| entry.id == entry_id)
+        .and_then(|entry| entry.file_path);
+
+    if let Some(file_path) = file_path {
+        if let Err(error) = reveal_in_file_manager(Path::new(&file_path)) {
+            warn!("Failed to reveal {}: {}", file_path, error);
+        }
+    }
+}
+
+// Keep the tooltip and recent downloads in sync with the job registry and history,
+// including downloads finished by native host processes
+pub fn spawn_tray_updater(app: AppHandle) {
+    std::thread::spawn(move || {
+        let mut last_history_id = None;
+        let mut last_paused = false;
+        let mut last_watching_clipboard = None;
+
+        loop {
+            let jobs = app.state::<JobRegistry>();
+            let history = app.state::<History>();
+            let tray = app.tray_handle();
+
+            let (active, speed) = jobs.activity();
+            let _ = tray.set_tooltip(&tray_tooltip(active, speed, connectivity::is_offline()));
+
+            let latest_id = history.latest_id().unwrap_or(None);
+            let paused = jobs.is_paused();
+            // Also picks up the setting changed from the window or another process
+            let watching_clipboard = app.state::<SettingsStore>().get().clipboard_watch;
+            if latest_id != last_history_id
+                || paused != last_paused
+                || Some(watching_clipboard) != last_watching_clipboard
+            {
+                let _ = tray.set_menu(build_tray_menu(&recent_downloads(&history), paused, watching_clipboard));
+                last_history_id = latest_id;
+                last_paused = paused;
+                last_watching_clipboard = Some(watching_clipboard);
+            }
+
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    });
+}
+
+fn tray_tooltip(active: usize, speed: u64, offline: bool) -> String {
+    if offline {
+        return "ImgVault Native Host - offline, waiting for the network".to_string();
+    }
+    match active {
+        0 => "ImgVault Native Host - idle".to_string(),
+        1 => format!("ImgVault Native Host - 1 active download, {}", format_speed(speed)),
+        count => format!("ImgVault Native Host - {} active downloads, {}", count, format_speed(speed)),
+    }
+}
+
+fn format_speed(bytes_per_second: u64) -> String {
+    const UNITS: [&str; 4] = ["B/s", "KiB/s", "MiB/s", "GiB/s"];
+    let mut value = bytes_per_second as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
@@ -0,0 +1,341 @@
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::settings::Settings;
+use crate::{current_timestamp_millis, get_app_data_directory};
+
+const RELEASES_URL: &str = "https://api.github.com/repos/FahadBinHussain/ImgVault/releases?per_page=30";
+const CACHE_FILE_NAME: &str = "update-check.json";
+const UPDATES_DIRECTORY_NAME: &str = "updates";
+const CACHE_MAX_AGE_MILLIS: i64 = 24 * 60 * 60 * 1000;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+// Checksum files are tiny; anything bigger is not what we are looking for
+const MAX_CHECKSUM_FILE_BYTES: u64 = 64 * 1024;
+const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateChannel {
+    Stable,
+    // Also offers releases marked as pre-release on GitHub
+    Prerelease,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateInfo {
+    pub channel: UpdateChannel,
+    pub current_version: String,
+    pub latest_version: String,
+    pub update_available: bool,
+    pub release_notes: String,
+    pub release_url: String,
+    // Installer for this platform; None when the release has no matching asset
+    pub download_url: Option<String>,
+    pub asset_name: Option<String>,
+    pub checksum_url: Option<String>,
+    pub checked_at: i64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadedUpdate {
+    pub path: String,
+    pub version: String,
+    pub sha256: String,
+}
+
+#[derive(Deserialize)]
+struct GithubRelease {
+    tag_name: String,
+    #[serde(default)]
+    body: Option<String>,
+    html_url: String,
+    #[serde(default)]
+    draft: bool,
+    #[serde(default)]
+    prerelease: bool,
+    #[serde(default)]
+    assets: Vec<GithubAsset>,
+}
+
+#[derive(Deserialize)]
+struct GithubAsset {
+    name: String,
+    browser_download_url: String,
+}
+
+// Latest release on the configured channel. Results are cached for a day so
+// the GUI and every native host process do not hit the GitHub rate limit.
+pub fn check_for_updates(settings: &Settings, force: bool) -> Result<UpdateInfo, String> {
+    if !force {
+        if let Some(cached) = read_cache(settings.update_channel) {
+            if current_timestamp_millis() - cached.checked_at < CACHE_MAX_AGE_MILLIS {
+                return Ok(cached);
+            }
+        }
+    }
+
+    let info = fetch_latest_release(settings.update_channel)?;
+    if let Err(error) = write_cache(&info) {
+        warn!("Failed to cache update check: {}", error);
+    }
+    if info.update_available {
+        info!("Update available: {} -> {}", info.current_version, info.latest_version);
+    }
+    Ok(info)
+}
+
+// Answer from the cache only, so ping never waits on the network. A missing or
+// expired cache is refreshed in the background for the next caller.
+pub fn cached_update_available(settings: &Settings) -> Option<bool> {
+    let cached = read_cache(settings.update_channel);
+    let stale = cached
+        .as_ref()
+        .is_none_or(|info| current_timestamp_millis() - info.checked_at >= CACHE_MAX_AGE_MILLIS);
+    if stale {
+        let settings = settings.clone();
+        std::thread::spawn(move || {
+            if let Err(error) = check_for_updates(&settings, true) {
+                warn!("Background update check failed: {}", error);
+            }
+        });
+    }
+    cached.map(|info| info.update_available)
+}
+
+// Download the installer for the latest release and verify it against the
+// SHA-256 published with the release. Installing is left to the user.
+pub fn download_update(settings: &Settings) -> Result<DownloadedUpdate, String> {
+    let info = check_for_updates(settings, false)?;
+    if !info.update_available {
+        return Err(format!("ImgVault {} is already up to date", info.current_version));
+    }
+    let (Some(download_url), Some(asset_name)) = (&info.download_url, &info.asset_name) else {
+        return Err(format!(
+            "Release {} has no installer for this platform; download it from {}",
+            info.latest_version, info.release_url
+        ));
+    };
+    let Some(checksum_url) = &info.checksum_url else {
+        return Err(format!(
+            "Release {} publishes no checksum for {}; download it from {}",
+            info.latest_version, asset_name, info.release_url
+        ));
+    };
+
+    let agent = http_agent();
+    let expected = fetch_expected_checksum(&agent, checksum_url, asset_name)?;
+
+    // Asset names come from the network; never let one escape the updates folder
+    let file_name = Path::new(asset_name)
+        .file_name()
+        .ok_or_else(|| format!("Invalid installer name: {}", asset_name))?;
+    let directory = get_app_data_directory()?.join(UPDATES_DIRECTORY_NAME);
+    fs::create_dir_all(&directory)
+        .map_err(|e| format!("Failed to create updates directory: {}", e))?;
+    let path = directory.join(file_name);
+    let partial_path = path.with_extension("part");
+
+    let response = agent
+        .get(download_url)
+        .call()
+        .map_err(|e| format!("Failed to download {}: {}", asset_name, e))?;
+    let actual = download_to_file(response.into_reader(), &partial_path)
+        .map_err(|e| format!("Failed to download {}: {}", asset_name, e))?;
+
+    if actual != expected {
+        let _ = fs::remove_file(&partial_path);
+        return Err(format!(
+            "Checksum mismatch for {}: expected {}, got {}",
+            asset_name, expected, actual
+        ));
+    }
+
+    fs::rename(&partial_path, &path)
+        .map_err(|e| format!("Failed to save {}: {}", path.display(), e))?;
+    info!("Downloaded update {} to {}", info.latest_version, path.display());
+
+    Ok(DownloadedUpdate {
+        path: path.display().to_string(),
+        version: info.latest_version,
+        sha256: actual,
+    })
+}
+
+fn http_agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout_read(READ_TIMEOUT)
+        .user_agent(&format!("ImgVault/{}", CURRENT_VERSION))
+        .build()
+}
+
+fn fetch_latest_release(channel: UpdateChannel) -> Result<UpdateInfo, String> {
+    let body = http_agent()
+        .get(RELEASES_URL)
+        .set("Accept", "application/vnd.github+json")
+        .call()
+        .map_err(|e| format!("Failed to query GitHub releases: {}", e))?
+        .into_string()
+        .map_err(|e| format!("Failed to read GitHub releases: {}", e))?;
+    let releases = serde_json::from_str::<Vec<GithubRelease>>(&body)
+        .map_err(|e| format!("Unexpected GitHub releases response: {}", e))?;
+
+    let current = Version::parse(CURRENT_VERSION)
+        .map_err(|e| format!("Invalid host version {}: {}", CURRENT_VERSION, e))?;
+
+    let latest = releases
+        .into_iter()
+        .filter(|release| !release.draft)
+        .filter_map(|release| parse_tag(&release.tag_name).map(|version| (version, release)))
+        .filter(|(version, release)| {
+            channel == UpdateChannel::Prerelease || (!release.prerelease && version.pre.is_empty())
+        })
+        .max_by(|(a, _), (b, _)| a.cmp(b));
+
+    let Some((version, release)) = latest else {
+        return Err("No published releases found".to_string());
+    };
+
+    let installer = select_installer(&release.assets);
+    let checksum_url = installer.and_then(|asset| find_checksum_asset(&release.assets, &asset.name));
+
+    Ok(UpdateInfo {
+        channel,
+        current_version: CURRENT_VERSION.to_string(),
+        latest_version: version.to_string(),
+        update_available: version > current,
+        release_notes: release.body.unwrap_or_default(),
+        release_url: release.html_url,
+        download_url: installer.map(|asset| asset.browser_download_url.clone()),
+        asset_name: installer.map(|asset| asset.name.clone()),
+        checksum_url,
+        checked_at: current_timestamp_millis(),
+    })
+}
+
+// Tags look like "v1.2.0" or "native-host-v1.2.0"; anything else is not a host release
+fn parse_tag(tag: &str) -> Option<Version> {
+    let version = tag.trim_start_matches(|c: char| !c.is_ascii_digit());
+    Version::parse(version).ok()
+}
+
+// Installer extensions for this platform, most preferred first
+fn installer_extensions() -> &'static [&'static str] {
+    if cfg!(target_os = "windows") {
+        &[".msi", "-setup.exe", ".exe"]
+    } else if cfg!(target_os = "macos") {
+        &[".dmg"]
+    } else {
+        &[".AppImage", ".deb"]
+    }
+}
+
+fn select_installer(assets: &[GithubAsset]) -> Option<&GithubAsset> {
+    installer_extensions()
+        .iter()
+        .find_map(|extension| assets.iter().find(|asset| asset.name.ends_with(extension)))
+}
+
+// Either "<installer>.sha256" or a combined SHA256SUMS-style file
+fn find_checksum_asset(assets: &[GithubAsset], installer_name: &str) -> Option<String> {
+    let own = format!("{}.sha256", installer_name);
+    assets
+        .iter()
+        .find(|asset| asset.name == own)
+        .or_else(|| {
+            assets.iter().find(|asset| {
+                let name = asset.name.to_ascii_lowercase();
+                name.contains("sha256sums") || name == "checksums.txt"
+            })
+        })
+        .map(|asset| asset.browser_download_url.clone())
+}
+
+// Accepts both the single-hash format and "<hash>  <file name>" lines
+fn fetch_expected_checksum(agent: &ureq::Agent, url: &str, asset_name: &str) -> Result<String, String> {
+    let mut contents = String::new();
+    agent
+        .get(url)
+        .call()
+        .map_err(|e| format!("Failed to download checksum: {}", e))?
+        .into_reader()
+        .take(MAX_CHECKSUM_FILE_BYTES)
+        .read_to_string(&mut contents)
+        .map_err(|e| format!("Failed to read checksum: {}", e))?;
+
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let hash = parts.next()?;
+            let name = parts.next().map(|name| name.trim_start_matches('*'));
+            (hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()))
+                .then(|| (hash.to_ascii_lowercase(), name))
+        })
+        .find(|(_, name)| name.is_none_or(|name| name == asset_name))
+        .map(|(hash, _)| hash)
+        .ok_or_else(|| format!("No checksum for {} in the release", asset_name))
+}
+
+// Stream to disk while hashing; returns the lowercase hex SHA-256
+fn download_to_file(mut reader: impl Read, path: &Path) -> io::Result<String> {
+    let mut file = File::create(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        file.write_all(&buffer[..read])?;
+    }
+    file.sync_all()?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn cache_path() -> Result<PathBuf, String> {
+    Ok(get_app_data_directory()?.join(CACHE_FILE_NAME))
+}
+
+// Cached result for the channel, re-evaluated against the running version so
+// an already installed update is not reported again
+fn read_cache(channel: UpdateChannel) -> Option<UpdateInfo> {
+    let contents = fs::read_to_string(cache_path().ok()?).ok()?;
+    let mut info = serde_json::from_str::<UpdateInfo>(&contents).ok()?;
+    if info.channel != channel {
+        return None;
+    }
+
+    let current = Version::parse(CURRENT_VERSION).ok()?;
+    let latest = Version::parse(&info.latest_version).ok()?;
+    info.current_version = CURRENT_VERSION.to_string();
+    info.update_available = latest > current;
+    Some(info)
+}
+
+fn write_cache(info: &UpdateInfo) -> Result<(), String> {
+    let path = cache_path()?;
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)
+            .map_err(|e| format!("Failed to create app data directory: {}", e))?;
+    }
+
+    let contents = serde_json::to_string_pretty(info)
+        .map_err(|e| format!("Failed to serialize update check: {}", e))?;
+    // Several host processes may refresh at once; rename keeps the file whole
+    let temp_path = path.with_extension(format!("json.{}.tmp", std::process::id()));
+    fs::write(&temp_path, contents)
+        .map_err(|e| format!("Failed to write update check: {}", e))?;
+    fs::rename(&temp_path, &path)
+        .map_err(|e| format!("Failed to save update check: {}", e))
+}
@@ -0,0 +1,217 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::net::ToSocketAddrs;
+use std::process::Command;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use crate::diagnostics::{self, hide_console_window, CheckStatus};
+use crate::downloader::last_error_line;
+use crate::image_fetch::USER_AGENT;
+use crate::settings::Settings;
+use crate::source_page::SourcePage;
+use crate::{
+    console_text, current_timestamp_millis, domain_policy, extractors, logging, tool_integrity, url_host, user_agent,
+    validate_download_url,
+};
+
+const HEAD_TIMEOUT: Duration = Duration::from_secs(15);
+const SOCKET_TIMEOUT_SECS: u32 = 15;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiagnosisStep {
+    pub id: &'static str,
+    pub status: CheckStatus,
+    pub message: String,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UrlDiagnosis {
+    pub url: String,
+    pub generated_at: i64,
+    pub host_version: String,
+    pub steps: Vec<DiagnosisStep>,
+    // The yt-dlp extractor that took the URL, when the probe got that far
+    pub extractor: Option<String>,
+    // The first step that failed and its error, for the "why did this fail" summary
+    pub failed_step: Option<&'static str>,
+    pub first_error: Option<String>,
+}
+
+struct Probe {
+    extractor: Option<String>,
+    // None when the probe failed before picking formats
+    needs_merge: Option<bool>,
+}
+
+// Runs every check a download of `url` depends on, in the order the download
+// meets them, and returns the report as JSON with secrets and the user's home
+// directory redacted. An invalid or blocked URL ends the report there; every
+// later step still runs after a network failure, since each can fail alone.
+pub fn diagnose(settings: &Settings, url: &str, referer: Option<&str>) -> Value {
+    let mut diagnosis = UrlDiagnosis {
+        url: url.to_string(),
+        generated_at: current_timestamp_millis(),
+        host_version: env!("CARGO_PKG_VERSION").to_string(),
+        steps: Vec::new(),
+        extractor: None,
+        failed_step: None,
+        first_error: None,
+    };
+    info!(url = logging::loggable_url(url), "Diagnosing URL");
+
+    let checked = timed(&mut diagnosis, "url", || match validate_download_url(url) {
+        Ok(()) => (CheckStatus::Pass, "The link is a valid web address".to_string()),
+        Err(error) => (CheckStatus::Fail, error),
+    }) && timed(&mut diagnosis, "domain_policy", || match domain_policy::check(settings, url) {
+        Ok(()) => (CheckStatus::Pass, "The settings allow downloads from this site".to_string()),
+        Err(blocked) => (CheckStatus::Fail, blocked.to_string()),
+    });
+    if checked {
+        timed(&mut diagnosis, "dns", || resolve(url));
+        timed(&mut diagnosis, "head", || head(settings, url, None));
+        if let Some(referer) = referer {
+            timed(&mut diagnosis, "head_referer", || head(settings, url, Some(referer)));
+        }
+
+        let mut probe = Probe {
+            extractor: None,
+            needs_merge: None,
+        };
+        timed(&mut diagnosis, "yt_dlp", || probe_yt_dlp(settings, url, referer, &mut probe));
+        diagnosis.extractor = probe.extractor;
+        timed(&mut diagnosis, "ffmpeg", || match probe.needs_merge {
+            Some(true) => {
+                let check = diagnostics::check_ffmpeg();
+                (check.status, check.message)
+            }
+            Some(false) => (CheckStatus::Pass, "Not needed: the chosen format is a single file".to_string()),
+            None => (CheckStatus::Warn, "Not checked: the yt-dlp probe picked no format".to_string()),
+        });
+    }
+
+    let mut value = serde_json::to_value(diagnosis).unwrap_or(Value::Null);
+    diagnostics::redact_user(&mut value);
+    value
+}
+
+// Records the step with its duration; false when it failed
+fn timed(diagnosis: &mut UrlDiagnosis, id: &'static str, step: impl FnOnce() -> (CheckStatus, String)) -> bool {
+    let started = Instant::now();
+    let (status, message) = step();
+    if status == CheckStatus::Fail && diagnosis.failed_step.is_none() {
+        diagnosis.failed_step = Some(id);
+        diagnosis.first_error = Some(message.clone());
+    }
+    diagnosis.steps.push(DiagnosisStep {
+        id,
+        status,
+        message,
+        duration_ms: started.elapsed().as_millis() as u64,
+    });
+    status != CheckStatus::Fail
+}
+
+fn resolve(url: &str) -> (CheckStatus, String) {
+    let Some(host) = url_host(url) else {
+        return (CheckStatus::Fail, "The link has no host name".to_string());
+    };
+    // The port only matters for connecting, not for the lookup
+    match (host.as_str(), 443).to_socket_addrs() {
+        Ok(addresses) => {
+            let addresses = addresses.map(|address| address.ip().to_string()).collect::<Vec<_>>();
+            if addresses.is_empty() {
+                (CheckStatus::Fail, format!("{} has no addresses", host))
+            } else {
+                (CheckStatus::Pass, format!("{} resolves to {}", host, addresses.join(", ")))
+            }
+        }
+        Err(error) => (CheckStatus::Fail, format!("Failed to resolve {}: {}", host, error)),
+    }
+}
+
+// Many sites answer HEAD with 403 or 405 and still serve the download, so
+// an error status is only a warning
+fn head(settings: &Settings, url: &str, referer: Option<&str>) -> (CheckStatus, String) {
+    let user_agent = user_agent::effective(settings, url, &SourcePage::default());
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(HEAD_TIMEOUT)
+        .timeout_read(HEAD_TIMEOUT)
+        .user_agent(user_agent.as_deref().unwrap_or(USER_AGENT))
+        .build();
+    let mut request = agent.head(url);
+    if let Some(referer) = referer {
+        request = request.set("Referer", referer);
+    }
+    match request.call() {
+        Ok(response) => {
+            let content_type = response.header("Content-Type").unwrap_or("no content type");
+            (CheckStatus::Pass, format!("HTTP {} ({})", response.status(), content_type))
+        }
+        Err(ureq::Error::Status(status, response)) => (
+            CheckStatus::Warn,
+            format!("HTTP Error {}: {}", status, response.status_text()),
+        ),
+        Err(ureq::Error::Transport(error)) => (CheckStatus::Fail, format!("Connection failed: {}", error)),
+    }
+}
+
+// What a download would run, without downloading: the extractor yt-dlp
+// picks and whether the chosen format needs merging
+fn probe_yt_dlp(settings: &Settings, url: &str, referer: Option<&str>, probe: &mut Probe) -> (CheckStatus, String) {
+    if let Err(error) = extractors::check(settings, url).and_then(|()| tool_integrity::verify(settings.yt_dlp_program())) {
+        return (CheckStatus::Fail, error);
+    }
+    let mut command = Command::new(settings.yt_dlp_program());
+    command
+        .arg(url)
+        .arg("-v")
+        .arg("--simulate")
+        .arg("--no-playlist")
+        .arg("--socket-timeout")
+        .arg(SOCKET_TIMEOUT_SECS.to_string())
+        .arg("-f")
+        .arg(settings.default_quality.format_selector())
+        .arg("--print")
+        .arg("%(extractor_key)s")
+        .arg("--print")
+        .arg("%(format_id)s");
+    if let Some(referer) = referer {
+        command.arg("--referer").arg(referer);
+    }
+    if let Some(user_agent) = user_agent::effective(settings, url, &SourcePage::default()) {
+        command.arg("--user-agent").arg(user_agent);
+    }
+    console_text::force_utf8(&mut command);
+    hide_console_window(&mut command);
+
+    let output = match command.output() {
+        Ok(output) => output,
+        Err(error) => return (CheckStatus::Fail, format!("Failed to execute yt-dlp: {}", error)),
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut printed = stdout.lines().map(str::trim).filter(|line| !line.is_empty());
+    if output.status.success() {
+        probe.extractor = printed.next().map(str::to_string);
+        let format_id = printed.next().unwrap_or_default();
+        probe.needs_merge = Some(format_id.contains('+'));
+        return (
+            CheckStatus::Pass,
+            format!("{} would download format {}", probe.extractor.as_deref().unwrap_or("yt-dlp"), format_id),
+        );
+    }
+
+    // Without the printed fields, the first tag of -v's output names the extractor
+    probe.extractor = stderr.lines().find_map(|line| {
+        let tag = line.strip_prefix('[')?.split_once(']')?.0;
+        (!matches!(tag, "debug" | "info" | "download")).then(|| tag.to_string())
+    });
+    let error = last_error_line(&stderr)
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("yt-dlp returned exit code {:?}", output.status.code()));
+    (CheckStatus::Fail, error)
+}
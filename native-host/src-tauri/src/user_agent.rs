@@ -0,0 +1,94 @@
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+use tracing::debug;
+
+use crate::settings::Settings;
+use crate::site_login::host_in_domain;
+use crate::source_page::SourcePage;
+use crate::url_host;
+
+// In place of a user agent: send the one of the browser the request came
+// from, which the extension reports as browser_user_agent
+pub const BROWSER: &str = "browser";
+const MAX_LENGTH: usize = 512;
+
+// A user agent for the URLs on one site
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SiteUserAgent {
+    // URLs on this host or its subdomains use it, e.g. pixiv.net
+    pub domain: String,
+    // A user agent string, or "browser"
+    pub user_agent: String,
+}
+
+// One header line: nothing that could end it or start another
+pub fn validate(value: &str) -> Result<(), String> {
+    if value.trim().is_empty() {
+        return Err("User agent is empty".to_string());
+    }
+    if value.len() > MAX_LENGTH {
+        return Err(format!("User agent is longer than {} characters", MAX_LENGTH));
+    }
+    if value.chars().any(char::is_control) {
+        return Err("User agent contains line breaks or control characters".to_string());
+    }
+    Ok(())
+}
+
+pub fn validate_site(site: &SiteUserAgent) -> Result<(), String> {
+    if site.domain.trim().is_empty() || site.domain.contains(['/', ':', ' ']) {
+        return Err("Domain must be a host name such as pixiv.net".to_string());
+    }
+    validate_setting(&site.user_agent)
+}
+
+// A configured user agent: a string, or "browser"
+pub fn validate_setting(value: &str) -> Result<(), String> {
+    if value == BROWSER {
+        return Ok(());
+    }
+    validate(value)
+}
+
+// The request's own user agent, else the site's, else the default; "browser"
+// becomes the browser's when the extension sent it. None leaves the
+// backend's own user agent.
+pub fn effective(settings: &Settings, url: &str, source_page: &SourcePage) -> Option<String> {
+    let site = url_host(url).and_then(|host| {
+        settings
+            .site_user_agents
+            .iter()
+            .find(|site| host_in_domain(&host, &site.domain))
+            .map(|site| site.user_agent.as_str())
+    });
+    let chosen = source_page
+        .user_agent
+        .as_deref()
+        .or(site)
+        .or(settings.user_agent.as_deref())?;
+    if chosen != BROWSER {
+        return Some(chosen.to_string());
+    }
+    if source_page.browser_user_agent.is_none() {
+        debug!("The request carries no browser user agent; using the backend's own");
+    }
+    source_page.browser_user_agent.clone()
+}
+
+// --user-agent, which yt-dlp, gallery-dl and the image fetch child all take
+pub fn add_argument(
+    command: &mut Command,
+    settings: &Settings,
+    url: &str,
+    source_page: &SourcePage,
+    job_id: &str,
+) {
+    match effective(settings, url, source_page) {
+        Some(user_agent) => {
+            debug!(job_id, user_agent = %user_agent, "Using user agent");
+            command.arg("--user-agent").arg(user_agent);
+        }
+        None => debug!(job_id, "Using the backend's own user agent"),
+    }
+}
@@ -0,0 +1,357 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+use crate::file_lock;
+use crate::{current_timestamp_millis, get_app_data_directory};
+
+// Append-only log of every change to the vault, for tools that keep their
+// own index of it; one JSON event per line
+const EVENTS_FILE_STEM: &str = "events";
+const LOCK_FILE_NAME: &str = "events.lock";
+const MAX_EVENTS_FILE_BYTES: u64 = 8 * 1024 * 1024;
+// The current file and this many rotated ones before it
+const KEEP_ROTATED_FILES: usize = 3;
+// Enough to hold the last whole line of a file
+const TAIL_BYTES: u64 = 64 * 1024;
+pub const DEFAULT_EVENTS_LIMIT: usize = 100;
+pub const MAX_EVENTS_LIMIT: usize = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum VaultChange {
+    // A file was saved into the vault and recorded in history
+    Added { entry_id: i64, path: String, source: String },
+    // Moved into the vault by an import, from outside it
+    Moved { from: String, path: String },
+    // Removed from disk, e.g. the local copy of an uploaded file
+    Deleted { path: String },
+    // Tags an entry was recorded with
+    Tagged { entry_id: i64, tags: Vec<String> },
+    // The file of `entry_id` was replaced by a link to that of `linked_to`
+    Deduplicated { entry_id: i64, linked_to: i64, path: String, link: String, bytes: u64 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VaultEvent {
+    // 1 for the first event ever written, then one more for each
+    pub seq: u64,
+    pub at: i64,
+    #[serde(flatten)]
+    pub change: VaultChange,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VaultEvents {
+    pub events: Vec<VaultEvent>,
+    // The oldest event still kept; a consumer that is further behind than
+    // this missed the rotated-out ones and should rescan the vault
+    pub oldest_seq: Option<u64>,
+    pub latest_seq: u64,
+}
+
+// Appends `change` to the log. Nothing here fails the change itself.
+pub fn record(change: VaultChange) {
+    match append(change) {
+        Ok(seq) => debug!(seq, "Recorded vault event"),
+        Err(error) => warn!("Failed to record vault event: {}", error),
+    }
+}
+
+// The sequence number comes from the last line on disk, read under the lock
+// every process writing events takes, so it carries on across restarts and
+// between the GUI and native hosts without gaps or repeats
+fn append(change: VaultChange) -> Result<u64, String> {
+    let directory = get_app_data_directory()?;
+    fs::create_dir_all(&directory).map_err(|e| format!("Failed to create app data directory: {}", e))?;
+    let _lock = file_lock::acquire(LOCK_FILE_NAME, "vault events")?;
+    let path = events_path(&directory, 0);
+    drop_partial_line(&path)?;
+
+    let event = VaultEvent {
+        seq: latest_seq(&directory)? + 1,
+        at: current_timestamp_millis(),
+        change,
+    };
+    let mut line = serde_json::to_string(&event).map_err(|e| format!("Failed to serialize vault event: {}", e))?;
+    line.push('\n');
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    // One write call, so a crash leaves the line whole or cut short, and a
+    // cut line is dropped before the next one is written
+    let written = file
+        .write(line.as_bytes())
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    if written != line.len() {
+        return Err(format!("Failed to write {}: only {} of {} bytes written", path.display(), written, line.len()));
+    }
+    file.sync_data()
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+    if file.metadata().map(|metadata| metadata.len()).unwrap_or(0) >= MAX_EVENTS_FILE_BYTES {
+        rotate(&directory).map_err(|e| format!("Failed to rotate {}: {}", path.display(), e))?;
+    }
+    Ok(event.seq)
+}
+
+// Events after `since_seq`, oldest first, at most `limit` of them. Reads
+// take no lock: a line still being written has no newline yet and comes
+// with the next call.
+pub fn since(since_seq: u64, limit: Option<usize>) -> Result<VaultEvents, String> {
+    let limit = limit.unwrap_or(DEFAULT_EVENTS_LIMIT).clamp(1, MAX_EVENTS_LIMIT);
+    let directory = get_app_data_directory()?;
+    let mut events = Vec::new();
+    let mut oldest_seq = None;
+    let mut latest_seq = 0;
+
+    for index in (0..=KEEP_ROTATED_FILES).rev() {
+        let path = events_path(&directory, index);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            reader
+                .read_line(&mut line)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            // A line without its newline may yet be dropped as cut short
+            if !line.ends_with('\n') {
+                break;
+            }
+            let Ok(event) = serde_json::from_str::<VaultEvent>(&line) else {
+                continue;
+            };
+            // A rotation between two files could show an event twice
+            if event.seq <= latest_seq {
+                continue;
+            }
+            oldest_seq.get_or_insert(event.seq);
+            latest_seq = event.seq;
+            if event.seq > since_seq && events.len() < limit {
+                events.push(event);
+            }
+        }
+    }
+    Ok(VaultEvents {
+        events,
+        oldest_seq,
+        latest_seq,
+    })
+}
+
+fn events_path(directory: &Path, index: usize) -> PathBuf {
+    match index {
+        0 => directory.join(format!("{}.jsonl", EVENTS_FILE_STEM)),
+        index => directory.join(format!("{}.{}.jsonl", EVENTS_FILE_STEM, index)),
+    }
+}
+
+// The newest sequence number on disk; the current file is empty right
+// after a rotation, so the one before it is asked then
+fn latest_seq(directory: &Path) -> Result<u64, String> {
+    for index in 0..=KEEP_ROTATED_FILES {
+        if let Some(seq) = last_seq_in(&events_path(directory, index))? {
+            return Ok(seq);
+        }
+    }
+    Ok(0)
+}
+
+fn last_seq_in(path: &Path) -> Result<Option<u64>, String> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return Ok(None),
+    };
+    let len = file
+        .metadata()
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?
+        .len();
+    let start = len.saturating_sub(TAIL_BYTES);
+    let mut tail = Vec::new();
+    file.seek(SeekFrom::Start(start))
+        .and_then(|_| file.read_to_end(&mut tail))
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    Ok(String::from_utf8_lossy(&tail)
+        .lines()
+        .rev()
+        .find_map(|line| serde_json::from_str::<VaultEvent>(line).ok())
+        .map(|event| event.seq))
+}
+
+// Cuts off what a crash left of a line, so the next event starts on a line
+// of its own; its sequence number was never handed out
+fn drop_partial_line(path: &Path) -> Result<(), String> {
+    let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => file,
+        Err(_) => return Ok(()),
+    };
+    let len = file
+        .metadata()
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?
+        .len();
+    if len == 0 {
+        return Ok(());
+    }
+    let start = len.saturating_sub(TAIL_BYTES);
+    let mut tail = Vec::new();
+    file.seek(SeekFrom::Start(start))
+        .and_then(|_| file.read_to_end(&mut tail))
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    if tail.last() == Some(&b'\n') {
+        return Ok(());
+    }
+    let keep = match tail.iter().rposition(|byte| *byte == b'\n') {
+        Some(position) => start + position as u64 + 1,
+        None if start == 0 => 0,
+        // A line longer than the tail; nothing the host writes
+        None => return Err(format!("{} ends in a line too long to repair", path.display())),
+    };
+    warn!(path = %path.display(), bytes = len - keep, "Dropping a partly written vault event");
+    file.set_len(keep)
+        .map_err(|e| format!("Failed to repair {}: {}", path.display(), e))
+}
+
+fn rotate(directory: &Path) -> std::io::Result<()> {
+    for index in (1..KEEP_ROTATED_FILES).rev() {
+        let from = events_path(directory, index);
+        if from.exists() {
+            fs::rename(&from, events_path(directory, index + 1))?;
+        }
+    }
+    fs::rename(events_path(directory, 0), events_path(directory, 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    fn deleted(name: &str) -> VaultChange {
+        VaultChange::Deleted { path: format!("/vault/{}.png", name) }
+    }
+
+    fn seqs() -> Vec<u64> {
+        since(0, Some(MAX_EVENTS_LIMIT))
+            .expect("events")
+            .events
+            .iter()
+            .map(|event| event.seq)
+            .collect()
+    }
+
+    // Every line of the file, which must all be whole events
+    fn lines_on_disk(path: &Path) -> Vec<u64> {
+        let text = fs::read_to_string(path).expect("events file");
+        assert!(text.is_empty() || text.ends_with('\n'), "{:?} ends mid-line", text);
+        text.lines()
+            .map(|line| {
+                serde_json::from_str::<VaultEvent>(line)
+                    .unwrap_or_else(|e| panic!("{:?} is not an event: {}", line, e))
+                    .seq
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_crash_anywhere_in_a_line_loses_only_that_line() {
+        let app_data = test_support::app_data();
+        let path = events_path(&app_data.directory, 0);
+        for name in ["a", "b", "c"] {
+            append(deleted(name)).expect("append");
+        }
+        let whole = fs::read(&path).expect("events file");
+        append(deleted("cut")).expect("append");
+        let with_cut = fs::read(&path).expect("events file");
+
+        // Every length the fourth line could have been cut to, newline and
+        // all, then a restart that writes the next event
+        for cut in whole.len() + 1..with_cut.len() {
+            fs::write(&path, &with_cut[..cut]).expect("crashed file");
+            assert_eq!(seqs(), [1, 2, 3], "cut at {}", cut);
+
+            assert_eq!(append(deleted("next")).expect("append after a crash"), 4, "cut at {}", cut);
+            assert_eq!(lines_on_disk(&path), [1, 2, 3, 4], "cut at {}", cut);
+            assert_eq!(seqs(), [1, 2, 3, 4], "cut at {}", cut);
+            let events = since(3, None).expect("events");
+            assert!(
+                matches!(&events.events[0].change, VaultChange::Deleted { path } if path == "/vault/next.png"),
+                "cut at {}: {:?}",
+                cut,
+                events.events
+            );
+        }
+    }
+
+    #[test]
+    fn a_crash_before_the_first_line_starts_over_at_one() {
+        let app_data = test_support::app_data();
+        let path = events_path(&app_data.directory, 0);
+        fs::write(&path, br#"{"seq":1,"at":0,"kind":"del"#).expect("crashed file");
+        assert_eq!(seqs(), Vec::<u64>::new());
+
+        assert_eq!(append(deleted("a")).expect("append"), 1);
+        assert_eq!(lines_on_disk(&path), [1]);
+    }
+
+    #[test]
+    fn repeated_crashes_and_restarts_never_repeat_or_skip_a_seq() {
+        let app_data = test_support::app_data();
+        let path = events_path(&app_data.directory, 0);
+        for round in 0..20u64 {
+            append(deleted(&format!("kept-{}", round))).expect("append");
+            append(deleted(&format!("lost-{}", round))).expect("append");
+            // Cut the last line somewhere different each time
+            let bytes = fs::read(&path).expect("events file");
+            let last_line = bytes[..bytes.len() - 1].iter().rposition(|byte| *byte == b'\n').map_or(0, |at| at + 1);
+            let cut = last_line + 1 + (round as usize * 7) % (bytes.len() - last_line - 1);
+            fs::write(&path, &bytes[..cut]).expect("crashed file");
+        }
+        append(deleted("last")).expect("append");
+        let on_disk = lines_on_disk(&path);
+        assert_eq!(on_disk, (1..=21).collect::<Vec<_>>());
+        assert_eq!(seqs(), on_disk);
+    }
+
+    #[test]
+    fn a_crash_right_after_rotating_carries_on_from_the_rotated_file() {
+        let app_data = test_support::app_data();
+        for name in ["a", "b"] {
+            append(deleted(name)).expect("append");
+        }
+        rotate(&app_data.directory).expect("rotate");
+        assert!(!events_path(&app_data.directory, 0).exists());
+
+        assert_eq!(append(deleted("c")).expect("append"), 3);
+        assert_eq!(lines_on_disk(&events_path(&app_data.directory, 1)), [1, 2]);
+        assert_eq!(lines_on_disk(&events_path(&app_data.directory, 0)), [3]);
+        let events = since(0, None).expect("events");
+        assert_eq!(events.events.iter().map(|event| event.seq).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!((events.oldest_seq, events.latest_seq), (Some(1), 3));
+    }
+
+    #[test]
+    fn a_reader_never_sees_a_line_without_its_newline() {
+        let app_data = test_support::app_data();
+        let path = events_path(&app_data.directory, 0);
+        append(deleted("a")).expect("append");
+        append(deleted("b")).expect("append");
+        // The line parses, but the crash came before its newline did
+        let bytes = fs::read(&path).expect("events file");
+        fs::write(&path, &bytes[..bytes.len() - 1]).expect("crashed file");
+        assert_eq!(seqs(), [1]);
+        assert_eq!(since(0, None).expect("events").latest_seq, 1);
+
+        // So the seq it would have had is free for the event written next
+        assert_eq!(append(deleted("c")).expect("append"), 2);
+        assert_eq!(lines_on_disk(&path), [1, 2]);
+    }
+}
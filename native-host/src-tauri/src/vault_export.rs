@@ -0,0 +1,364 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Manager};
+use tracing::{info, warn};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::diagnostics::available_disk_space;
+use crate::history::{History, HistoryEntry};
+use crate::long_path;
+use crate::settings::Settings;
+use crate::timestamps::format_rfc3339;
+use crate::{current_timestamp_millis, get_vault_directory};
+
+pub const EXPORT_PROGRESS_EVENT: &str = "vault-export-progress";
+const EXPORT_FORMAT: &str = "imgvault-export";
+const MANIFEST_NAME: &str = "manifest.json";
+// Room for headers, sidecars and the manifest on top of the files
+const ARCHIVE_OVERHEAD_BYTES: u64 = 16 * 1024 * 1024;
+const CHUNK_BYTES: usize = 256 * 1024;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ItemExportOptions {
+    // Every file at the top of the archive instead of under its vault folders
+    #[serde(default)]
+    pub flatten: bool,
+    // A <file>.json next to each file with its history entry
+    pub include_sidecars: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportProgress {
+    pub total_files: usize,
+    pub files_done: usize,
+    pub total_bytes: u64,
+    pub bytes_done: u64,
+    pub current: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SkippedItem {
+    pub entry_id: i64,
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportSummary {
+    pub path: String,
+    pub files: usize,
+    pub bytes: u64,
+    pub skipped: Vec<SkippedItem>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    format: &'static str,
+    exported_at: String,
+    items: Vec<ManifestItem>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ManifestItem {
+    entry_id: i64,
+    path: String,
+    url: String,
+    page_url: Option<String>,
+    title: Option<String>,
+    saved_at: String,
+    size_bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sidecar: Option<String>,
+}
+
+// A selected entry whose file is on disk, with its names in the archive
+struct ExportItem {
+    entry: HistoryEntry,
+    path: PathBuf,
+    size: u64,
+    name: String,
+    sidecar: Option<String>,
+}
+
+// One export at a time; cancel stops it within a chunk and removes the
+// partial archive
+#[derive(Clone, Default)]
+pub struct VaultExporter {
+    running: Arc<AtomicBool>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl VaultExporter {
+    pub fn cancel(&self) -> bool {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.running.load(Ordering::SeqCst)
+    }
+
+    // Streams the files of the given history entries into a ZIP at
+    // `destination`, with a manifest.json listing where each came from.
+    // Entries without a file on disk are skipped and reported.
+    pub fn run(
+        &self,
+        app: &AppHandle,
+        history: &History,
+        settings: &Settings,
+        entry_ids: Vec<i64>,
+        destination: &Path,
+        options: &ItemExportOptions,
+    ) -> Result<ExportSummary, String> {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return Err("An export is already running".to_string());
+        }
+        self.cancelled.store(false, Ordering::SeqCst);
+        let result = self.export(app, history, settings, entry_ids, destination, options);
+        self.running.store(false, Ordering::SeqCst);
+        result
+    }
+
+    fn export(
+        &self,
+        app: &AppHandle,
+        history: &History,
+        settings: &Settings,
+        mut entry_ids: Vec<i64>,
+        destination: &Path,
+        options: &ItemExportOptions,
+    ) -> Result<ExportSummary, String> {
+        entry_ids.sort_unstable();
+        entry_ids.dedup();
+        let vault = get_vault_directory(settings)?;
+        let vault = long_path::canonical(&vault).unwrap_or(vault);
+        let (mut items, skipped) = collect_items(history, &entry_ids)?;
+        if items.is_empty() {
+            return Err("None of the selected items has a file to export".to_string());
+        }
+        name_items(&mut items, &vault, options);
+
+        let total_bytes = items.iter().map(|item| item.size).sum::<u64>();
+        check_free_space(destination, total_bytes + ARCHIVE_OVERHEAD_BYTES)?;
+        info!(
+            files = items.len(),
+            bytes = total_bytes,
+            destination = %destination.display(),
+            "Exporting vault items"
+        );
+
+        // Written beside the destination and renamed once complete, so a
+        // cancelled or failed export never leaves a broken archive behind
+        let part_path = PathBuf::from(format!("{}.part", destination.display()));
+        let mut progress = ExportProgress {
+            total_files: items.len(),
+            total_bytes,
+            ..ExportProgress::default()
+        };
+        let result = self.write_archive(app, &part_path, &items, &mut progress);
+        match result {
+            Ok(true) => {}
+            Ok(false) => {
+                let _ = fs::remove_file(&part_path);
+                info!("Export cancelled after {} of {} files", progress.files_done, progress.total_files);
+                return Err("Export cancelled; the partial archive was removed".to_string());
+            }
+            Err(error) => {
+                let _ = fs::remove_file(&part_path);
+                return Err(error);
+            }
+        }
+        fs::rename(&part_path, destination).map_err(|e| {
+            let _ = fs::remove_file(&part_path);
+            format!("Failed to move the archive to {}: {}", destination.display(), e)
+        })?;
+
+        Ok(ExportSummary {
+            path: destination.display().to_string(),
+            files: items.len(),
+            bytes: total_bytes,
+            skipped,
+        })
+    }
+
+    // false when cancelled part way
+    fn write_archive(&self, app: &AppHandle, part_path: &Path, items: &[ExportItem], progress: &mut ExportProgress) -> Result<bool, String> {
+        let file = File::create(part_path).map_err(|e| format!("Failed to create {}: {}", part_path.display(), e))?;
+        let mut zip = ZipWriter::new(BufWriter::new(file));
+        let write_error = |e: &dyn std::fmt::Display| format!("Failed to write {}: {}", part_path.display(), e);
+        // Images and videos are compressed already; only the metadata deflates
+        let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+        let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+
+        let mut last_report = Instant::now();
+        let mut buffer = vec![0u8; CHUNK_BYTES];
+        for item in items {
+            progress.current = Some(item.name.clone());
+            let _ = app.emit_all(EXPORT_PROGRESS_EVENT, &*progress);
+
+            let mut source = File::open(&item.path).map_err(|e| format!("Failed to read {}: {}", item.path.display(), e))?;
+            zip.start_file(item.name.as_str(), stored.large_file(item.size >= u32::MAX as u64))
+                .map_err(|e| write_error(&e))?;
+            loop {
+                if self.cancelled.load(Ordering::SeqCst) {
+                    return Ok(false);
+                }
+                let read = source
+                    .read(&mut buffer)
+                    .map_err(|e| format!("Failed to read {}: {}", item.path.display(), e))?;
+                if read == 0 {
+                    break;
+                }
+                zip.write_all(&buffer[..read]).map_err(|e| write_error(&e))?;
+                progress.bytes_done += read as u64;
+                if last_report.elapsed() >= PROGRESS_INTERVAL {
+                    last_report = Instant::now();
+                    let _ = app.emit_all(EXPORT_PROGRESS_EVENT, &*progress);
+                }
+            }
+
+            if let Some(sidecar) = &item.sidecar {
+                zip.start_file(sidecar.as_str(), deflated).map_err(|e| write_error(&e))?;
+                serde_json::to_writer_pretty(&mut zip, &item.entry).map_err(|e| write_error(&e))?;
+            }
+            progress.files_done += 1;
+        }
+
+        let manifest = Manifest {
+            format: EXPORT_FORMAT,
+            exported_at: format_rfc3339(current_timestamp_millis()),
+            items: items.iter().map(manifest_item).collect(),
+        };
+        zip.start_file(MANIFEST_NAME, deflated).map_err(|e| write_error(&e))?;
+        serde_json::to_writer_pretty(&mut zip, &manifest).map_err(|e| write_error(&e))?;
+        let writer = zip.finish().map_err(|e| write_error(&e))?;
+        let file = writer.into_inner().map_err(|e| write_error(&e.error()))?;
+        file.sync_all().map_err(|e| write_error(&e))?;
+
+        progress.current = None;
+        let _ = app.emit_all(EXPORT_PROGRESS_EVENT, &*progress);
+        Ok(true)
+    }
+}
+
+fn collect_items(history: &History, entry_ids: &[i64]) -> Result<(Vec<ExportItem>, Vec<SkippedItem>), String> {
+    let mut items = Vec::new();
+    let mut skipped = Vec::new();
+    for &entry_id in entry_ids {
+        let skip = |reason: &str| SkippedItem {
+            entry_id,
+            reason: reason.to_string(),
+        };
+        let Some(entry) = history.get(entry_id)? else {
+            skipped.push(skip("not in history"));
+            continue;
+        };
+        let Some(path) = entry.file_path.as_deref().map(PathBuf::from) else {
+            skipped.push(skip("has no saved file"));
+            continue;
+        };
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_file() => items.push(ExportItem {
+                size: metadata.len(),
+                entry,
+                path,
+                name: String::new(),
+                sidecar: None,
+            }),
+            _ => {
+                warn!(entry_id, path = %path.display(), "Skipping export of a missing file");
+                skipped.push(skip("file is missing"));
+            }
+        }
+    }
+    Ok((items, skipped))
+}
+
+// Archive names in entry order: the path inside the vault, or only the file
+// name when flattening or for files outside it. A taken name gets " (2)",
+// " (3)" and so on, so the same selection always gives the same archive.
+fn name_items(items: &mut [ExportItem], vault: &Path, options: &ItemExportOptions) {
+    let mut taken = HashSet::from([MANIFEST_NAME.to_string()]);
+    for item in items {
+        let canonical = long_path::canonical(&item.path).unwrap_or_else(|_| item.path.clone());
+        let relative = match canonical.strip_prefix(vault) {
+            Ok(relative) if !options.flatten => relative.to_path_buf(),
+            _ => PathBuf::from(item.path.file_name().unwrap_or_default()),
+        };
+        let name = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        item.name = unique_name(&name, &mut taken);
+        if options.include_sidecars {
+            item.sidecar = Some(unique_name(&format!("{}.json", item.name), &mut taken));
+        }
+    }
+}
+
+// Compared without case, since the archive may be unpacked on Windows
+fn unique_name(name: &str, taken: &mut HashSet<String>) -> String {
+    if taken.insert(name.to_lowercase()) {
+        return name.to_string();
+    }
+    let (folder, file_name) = match name.rsplit_once('/') {
+        Some((folder, file_name)) => (format!("{}/", folder), file_name),
+        None => (String::new(), name),
+    };
+    let (stem, extension) = match file_name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (file_name, String::new()),
+    };
+    (2..)
+        .map(|index| format!("{}{} ({}){}", folder, stem, index, extension))
+        .find(|candidate| taken.insert(candidate.to_lowercase()))
+        .expect("some numbered name is free")
+}
+
+fn check_free_space(destination: &Path, needed: u64) -> Result<(), String> {
+    let folder = destination
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    match available_disk_space(folder) {
+        Ok(free) if free < needed => Err(format!(
+            "Not enough space in {}: the export needs {} MiB and {} MiB are free",
+            folder.display(),
+            needed.div_ceil(1024 * 1024),
+            free / (1024 * 1024)
+        )),
+        Ok(_) => Ok(()),
+        Err(error) => {
+            warn!("Free space in {} unknown, exporting anyway: {}", folder.display(), error);
+            Ok(())
+        }
+    }
+}
+
+fn manifest_item(item: &ExportItem) -> ManifestItem {
+    ManifestItem {
+        entry_id: item.entry.id,
+        path: item.name.clone(),
+        url: item.entry.url.clone(),
+        page_url: item.entry.page_url.clone(),
+        title: item
+            .entry
+            .media_info
+            .as_ref()
+            .and_then(|info| info.title.clone())
+            .or_else(|| item.entry.page_title.clone()),
+        saved_at: format_rfc3339(item.entry.finished_at),
+        size_bytes: item.size,
+        sidecar: item.sidecar.clone(),
+    }
+}
@@ -0,0 +1,310 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Manager};
+use tracing::{info, warn};
+
+use crate::drop_import::MEDIA_EXTENSIONS;
+use crate::folder_import::list_files;
+use crate::folder_protection;
+use crate::history::{History, SavedFile};
+use crate::settings::Settings;
+use crate::timestamps::{format_rfc3339, parse_rfc3339};
+use crate::{current_timestamp_millis, get_app_data_directory, get_vault_directory};
+
+pub const VERIFY_PROGRESS_EVENT: &str = "vault-verify-progress";
+const REPORT_DIRECTORY: &str = "verify-reports";
+// Hashing reads no faster than this, so the machine stays usable meanwhile
+const MAX_BYTES_PER_SECOND: u64 = 32 * 1024 * 1024;
+const CHUNK_BYTES: usize = 1024 * 1024;
+
+// Which history entries to check
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum VerifyScope {
+    All,
+    // RFC 3339 bounds on when the download finished; either may be missing
+    DateRange { from: Option<String>, to: Option<String> },
+    // A random share of the entries, 0 to 100
+    Sample { percent: f64 },
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyProgress {
+    pub total: usize,
+    pub checked: usize,
+    pub missing: usize,
+    pub mismatched: usize,
+    pub current: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyIssue {
+    pub entry_id: i64,
+    pub file_path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actual: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// What the report file holds
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VerifyReport {
+    started_at: String,
+    finished_at: String,
+    scope: VerifyScope,
+    vault: String,
+    checked: usize,
+    ok: usize,
+    // Entries that had no hash yet; the one read now is what later runs compare to
+    newly_hashed: usize,
+    missing: Vec<VerifyIssue>,
+    mismatched: Vec<VerifyIssue>,
+    unreadable: Vec<VerifyIssue>,
+    // Cloud placeholders whose contents are not on this computer; reading
+    // them would download every one, so they are left unhashed
+    placeholders: Vec<VerifyIssue>,
+    // Media files in the vault that no history entry points at
+    unindexed: Vec<String>,
+    cancelled: bool,
+}
+
+// One verification at a time; cancel stops it within a chunk, and the report
+// of what was checked so far is still written
+#[derive(Clone, Default)]
+pub struct VaultVerifier {
+    running: Arc<AtomicBool>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl VaultVerifier {
+    pub fn cancel(&self) -> bool {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.running.load(Ordering::SeqCst)
+    }
+
+    // Re-hashes the files history points at and compares them with the
+    // recorded hashes, then lists vault files history does not know. Returns
+    // the path of the JSON report.
+    pub fn run(&self, app: &AppHandle, history: &History, settings: &Settings, scope: VerifyScope) -> Result<String, String> {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return Err("A vault verification is already running".to_string());
+        }
+        self.cancelled.store(false, Ordering::SeqCst);
+        let result = self.verify(app, history, settings, scope);
+        self.running.store(false, Ordering::SeqCst);
+        result
+    }
+
+    fn verify(&self, app: &AppHandle, history: &History, settings: &Settings, scope: VerifyScope) -> Result<String, String> {
+        let started_at = current_timestamp_millis();
+        let vault = get_vault_directory(settings)?;
+        let entries = select_entries(history, &scope)?;
+        info!(entries = entries.len(), scope = ?scope, "Verifying vault");
+
+        let mut report = VerifyReport {
+            started_at: format_rfc3339(started_at),
+            finished_at: String::new(),
+            scope,
+            vault: vault.display().to_string(),
+            checked: 0,
+            ok: 0,
+            newly_hashed: 0,
+            missing: Vec::new(),
+            mismatched: Vec::new(),
+            unreadable: Vec::new(),
+            placeholders: Vec::new(),
+            unindexed: Vec::new(),
+            cancelled: false,
+        };
+        let mut progress = VerifyProgress {
+            total: entries.len(),
+            ..VerifyProgress::default()
+        };
+        // Several entries can point at one file; it is read once
+        let mut hashes: HashMap<String, Result<String, String>> = HashMap::new();
+
+        for entry in entries {
+            progress.current = Some(entry.file_path.clone());
+            let _ = app.emit_all(VERIFY_PROGRESS_EVENT, &progress);
+
+            let issue = |expected: Option<&str>, actual: Option<&str>, error: Option<String>| VerifyIssue {
+                entry_id: entry.id,
+                file_path: entry.file_path.clone(),
+                expected: expected.map(str::to_string),
+                actual: actual.map(str::to_string),
+                error,
+            };
+            if !Path::new(&entry.file_path).is_file() {
+                report.missing.push(issue(entry.sha256.as_deref(), None, None));
+                progress.missing += 1;
+            } else if folder_protection::is_placeholder(Path::new(&entry.file_path)) {
+                report.placeholders.push(issue(
+                    entry.sha256.as_deref(),
+                    None,
+                    Some("Only a cloud placeholder is on disk, so its hash cannot be verified".to_string()),
+                ));
+            } else {
+                let actual = match hashes.get(&entry.file_path) {
+                    Some(actual) => actual.clone(),
+                    None => match self.hash_throttled(Path::new(&entry.file_path)) {
+                        Ok(Some(actual)) => Ok(actual),
+                        Ok(None) => {
+                            report.cancelled = true;
+                            break;
+                        }
+                        Err(error) => Err(error.to_string()),
+                    },
+                };
+                hashes.insert(entry.file_path.clone(), actual.clone());
+                match (actual, entry.sha256.as_deref()) {
+                    (Err(error), expected) => report.unreadable.push(issue(expected, None, Some(error))),
+                    (Ok(actual), None) => {
+                        if let Err(error) = history.set_sha256(entry.id, &actual) {
+                            warn!("{}", error);
+                        }
+                        report.newly_hashed += 1;
+                    }
+                    (Ok(actual), Some(expected)) if actual == expected => report.ok += 1,
+                    (Ok(actual), Some(expected)) => {
+                        warn!(entry_id = entry.id, path = %entry.file_path, "Hash mismatch");
+                        report.mismatched.push(issue(Some(expected), Some(&actual), None));
+                        progress.mismatched += 1;
+                    }
+                }
+            }
+            report.checked += 1;
+            progress.checked += 1;
+        }
+
+        if !report.cancelled {
+            report.unindexed = unindexed_files(history, &vault)?;
+        }
+        report.cancelled |= self.cancelled.load(Ordering::SeqCst);
+        progress.current = None;
+        let _ = app.emit_all(VERIFY_PROGRESS_EVENT, &progress);
+
+        report.finished_at = format_rfc3339(current_timestamp_millis());
+        info!(
+            checked = report.checked,
+            missing = report.missing.len(),
+            mismatched = report.mismatched.len(),
+            unindexed = report.unindexed.len(),
+            placeholders = report.placeholders.len(),
+            cancelled = report.cancelled,
+            "Vault verification finished"
+        );
+        if !report.placeholders.is_empty() {
+            warn!(
+                placeholders = report.placeholders.len(),
+                "Some files are cloud placeholders and were not verified; keep the vault on this device to check them"
+            );
+        }
+        write_report(&report, started_at)
+    }
+
+    // None when cancelled part way
+    fn hash_throttled(&self, path: &Path) -> io::Result<Option<String>> {
+        let mut file = File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; CHUNK_BYTES];
+        let started = Instant::now();
+        let mut total = 0u64;
+        loop {
+            if self.cancelled.load(Ordering::SeqCst) {
+                return Ok(None);
+            }
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            total += read as u64;
+            let due = Duration::from_secs_f64(total as f64 / MAX_BYTES_PER_SECOND as f64);
+            if let Some(wait) = due.checked_sub(started.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+        Ok(Some(format!("{:x}", hasher.finalize())))
+    }
+}
+
+fn select_entries(history: &History, scope: &VerifyScope) -> Result<Vec<SavedFile>, String> {
+    match scope {
+        VerifyScope::All => history.saved_files(None, None),
+        VerifyScope::DateRange { from, to } => {
+            let from = from.as_deref().map(parse_rfc3339).transpose()?;
+            let to = to.as_deref().map(parse_rfc3339).transpose()?;
+            history.saved_files(from, to)
+        }
+        VerifyScope::Sample { percent } => {
+            if !(0.0..=100.0).contains(percent) {
+                return Err(format!("Sample must be between 0 and 100 percent, not {}", percent));
+            }
+            let mut entries = history.saved_files(None, None)?;
+            let keep = ((entries.len() as f64 * percent / 100.0).ceil() as usize).min(entries.len());
+            shuffle(&mut entries);
+            entries.truncate(keep);
+            entries.sort_by_key(|entry| entry.id);
+            Ok(entries)
+        }
+    }
+}
+
+// Fisher-Yates with xorshift seeded from the clock; a sample only needs to
+// differ between runs, not to be unpredictable
+fn shuffle<T>(items: &mut [T]) {
+    let mut state = (current_timestamp_millis() as u64) | 1;
+    for index in (1..items.len()).rev() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        items.swap(index, (state % (index as u64 + 1)) as usize);
+    }
+}
+
+// Media files under the vault that no completed entry points at
+fn unindexed_files(history: &History, vault: &Path) -> Result<Vec<String>, String> {
+    if !vault.is_dir() {
+        return Ok(Vec::new());
+    }
+    let indexed = history
+        .saved_files(None, None)?
+        .into_iter()
+        .filter_map(|entry| fs::canonicalize(&entry.file_path).ok())
+        .collect::<HashSet<PathBuf>>();
+    Ok(list_files(vault, true)?
+        .into_iter()
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| MEDIA_EXTENSIONS.contains(&extension.to_string_lossy().to_ascii_lowercase().as_str()))
+        })
+        .filter(|path| fs::canonicalize(path).is_ok_and(|path| !indexed.contains(&path)))
+        .map(|path| path.display().to_string())
+        .collect())
+}
+
+// verify-reports/vault-verify-<time>.json in the app data folder
+fn write_report(report: &VerifyReport, started_at: i64) -> Result<String, String> {
+    let directory = get_app_data_directory()?.join(REPORT_DIRECTORY);
+    fs::create_dir_all(&directory)
+        .map_err(|e| format!("Failed to create report folder {}: {}", directory.display(), e))?;
+    // Colons are not allowed in Windows file names
+    let path = directory.join(format!("vault-verify-{}.json", format_rfc3339(started_at).replace(':', "-")));
+    let contents = serde_json::to_string_pretty(report).map_err(|e| format!("Failed to serialize report: {}", e))?;
+    fs::write(&path, contents).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    Ok(path.display().to_string())
+}
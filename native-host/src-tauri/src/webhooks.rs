@@ -0,0 +1,241 @@
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, Read};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::history::DownloadStatus;
+use crate::settings::Settings;
+use crate::{current_timestamp_millis, keychain, logging, secrets, validate_download_url};
+
+const SECRET_FILE_NAME: &str = "webhook-secret";
+pub const SIGNATURE_HEADER: &str = "X-ImgVault-Signature";
+const EVENT_HEADER: &str = "X-ImgVault-Event";
+const EVENT_NAME: &str = "job.finished";
+const TEST_EVENT_NAME: &str = "webhook.test";
+// One attempt plus this many retries, waiting twice as long before each retry
+const MAX_RETRIES: u32 = 3;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookPayload {
+    pub event: String,
+    pub job_id: String,
+    pub url: String,
+    pub file_path: Option<String>,
+    pub size_bytes: Option<u64>,
+    pub sha256: Option<String>,
+    pub status: String,
+    pub error_code: Option<String>,
+    pub finished_at: i64,
+}
+
+pub struct FinishedJob<'a> {
+    pub job_id: &'a str,
+    pub url: &'a str,
+    pub file_path: Option<&'a str>,
+    pub status: DownloadStatus,
+    pub error_code: Option<&'a str>,
+}
+
+enum DeliveryError {
+    // Worth another attempt: network trouble, 408, 429 or a 5xx
+    Retryable(String),
+    Permanent(String),
+}
+
+// POSTs the job result to every configured webhook on a background thread.
+// Delivery problems are only logged; the download itself is already finished.
+pub fn notify_job_finished(settings: &Settings, job: FinishedJob) -> Option<JoinHandle<()>> {
+    if settings.webhook_urls.is_empty() {
+        return None;
+    }
+
+    let webhook_urls = settings.webhook_urls.clone();
+    let job_id = job.job_id.to_string();
+    let url = job.url.to_string();
+    let file_path = job.file_path.map(|path| path.to_string());
+    let status = job.status;
+    let error_code = job.error_code.map(|code| code.to_string());
+    let finished_at = current_timestamp_millis();
+
+    Some(std::thread::spawn(move || {
+        // Hashing a large video takes a while, so it happens off the download thread
+        let (size_bytes, sha256) = match file_path.as_deref().filter(|_| status == DownloadStatus::Completed) {
+            Some(path) => match hash_file(path) {
+                Ok((size, hash)) => (Some(size), Some(hash)),
+                Err(error) => {
+                    warn!(job_id = %job_id, "Failed to hash downloaded file for webhooks: {}", error);
+                    (None, None)
+                }
+            },
+            None => (None, None),
+        };
+
+        let payload = WebhookPayload {
+            event: EVENT_NAME.to_string(),
+            job_id,
+            url,
+            file_path,
+            size_bytes,
+            sha256,
+            status: status.as_str().to_string(),
+            error_code,
+            finished_at,
+        };
+        let body = match serde_json::to_string(&payload) {
+            Ok(body) => body,
+            Err(error) => {
+                warn!("Failed to serialize webhook payload: {}", error);
+                return;
+            }
+        };
+        let secret = match secrets::get_or_create_secret(SECRET_FILE_NAME) {
+            Ok(secret) => secret,
+            Err(error) => {
+                warn!("Webhooks not sent: {}", error);
+                return;
+            }
+        };
+
+        // Each endpoint retries on its own schedule so one slow hook does not delay the rest
+        let deliveries = webhook_urls
+            .into_iter()
+            .map(|webhook_url| {
+                let body = body.clone();
+                let secret = secret.clone();
+                let job_id = payload.job_id.clone();
+                std::thread::spawn(move || deliver_with_retries(&webhook_url, &body, &secret, &job_id))
+            })
+            .collect::<Vec<_>>();
+        for delivery in deliveries {
+            let _ = delivery.join();
+        }
+    }))
+}
+
+// Sends a sample payload once, without retries, and returns the HTTP status
+pub fn send_test(webhook_url: &str) -> Result<u16, String> {
+    let webhook_url = resolve_webhook_url(webhook_url)?;
+
+    let payload = WebhookPayload {
+        event: TEST_EVENT_NAME.to_string(),
+        job_id: "test".to_string(),
+        url: "https://example.com/video".to_string(),
+        file_path: None,
+        size_bytes: None,
+        sha256: None,
+        status: DownloadStatus::Completed.as_str().to_string(),
+        error_code: None,
+        finished_at: current_timestamp_millis(),
+    };
+    let body = serde_json::to_string(&payload)
+        .map_err(|e| format!("Failed to serialize webhook payload: {}", e))?;
+    let secret = secrets::get_or_create_secret(SECRET_FILE_NAME)?;
+
+    post(&webhook_url, TEST_EVENT_NAME, &body, &secret).map_err(|e| format!("Failed to reach webhook: {}", e))
+}
+
+pub fn get_secret() -> Result<String, String> {
+    secrets::get_or_create_secret(SECRET_FILE_NAME)
+}
+
+// Secret references are resolved only now; logs show the unresolved setting
+fn resolve_webhook_url(webhook_url: &str) -> Result<String, String> {
+    let resolved = keychain::resolve_references(webhook_url)?;
+    validate_download_url(&resolved).map_err(|e| format!("Invalid webhook URL: {}", e))?;
+    Ok(resolved)
+}
+
+fn deliver_with_retries(webhook_url: &str, body: &str, secret: &str, job_id: &str) {
+    let target = logging::loggable_url(webhook_url);
+    let webhook_url = match resolve_webhook_url(webhook_url) {
+        Ok(webhook_url) => webhook_url,
+        Err(error) => {
+            warn!(job_id, webhook = %target, "Webhook not sent: {}", error);
+            return;
+        }
+    };
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 0..=MAX_RETRIES {
+        if attempt > 0 {
+            std::thread::sleep(backoff);
+            backoff *= 2;
+        }
+        match deliver(&webhook_url, body, secret) {
+            Ok(status) => {
+                info!(job_id, webhook = %target, status, "Webhook delivered");
+                return;
+            }
+            Err(DeliveryError::Permanent(error)) => {
+                warn!(job_id, webhook = %target, "Webhook rejected, not retrying: {}", error);
+                return;
+            }
+            Err(DeliveryError::Retryable(error)) => {
+                debug!(job_id, webhook = %target, attempt = attempt + 1, "Webhook delivery failed: {}", error);
+            }
+        }
+    }
+    warn!(job_id, webhook = %target, "Webhook delivery failed after {} attempts", MAX_RETRIES + 1);
+}
+
+fn deliver(webhook_url: &str, body: &str, secret: &str) -> Result<u16, DeliveryError> {
+    match post(webhook_url, EVENT_NAME, body, secret) {
+        Ok(status) if status == 408 || status == 429 || status >= 500 => {
+            Err(DeliveryError::Retryable(format!("HTTP {}", status)))
+        }
+        Ok(status) if status >= 400 => Err(DeliveryError::Permanent(format!("HTTP {}", status))),
+        Ok(status) => Ok(status),
+        Err(error) => Err(DeliveryError::Retryable(error)),
+    }
+}
+
+// The status of whatever the receiver answered, error statuses included;
+// Err only when nothing answered
+fn post(webhook_url: &str, event: &str, body: &str, secret: &str) -> Result<u16, String> {
+    let sent = ureq::AgentBuilder::new()
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout(REQUEST_TIMEOUT)
+        .user_agent(&format!("ImgVault/{}", env!("CARGO_PKG_VERSION")))
+        .build()
+        .post(webhook_url)
+        .set("Content-Type", "application/json")
+        .set(EVENT_HEADER, event)
+        .set(SIGNATURE_HEADER, &format!("sha256={}", sign(secret, body)))
+        .send_string(body);
+    match sent {
+        Ok(response) => Ok(response.status()),
+        Err(ureq::Error::Status(status, _)) => Ok(status),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+// Receivers recompute HMAC-SHA256 over the raw request body with the shared
+// secret and compare it to the signature header
+fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body.as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+pub(crate) fn hash_file(path: &str) -> io::Result<(u64, String)> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 64 * 1024];
+    let mut size = 0u64;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
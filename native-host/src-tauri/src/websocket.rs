@@ -0,0 +1,237 @@
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+use tungstenite::handshake::server::{Callback, ErrorResponse, Request, Response};
+use tungstenite::http;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::{Error, Message, WebSocket};
+
+use crate::jobs::JobRegistry;
+use crate::settings::SettingsStore;
+
+pub const EVENTS_PATH: &str = "/events";
+// How often idle loops wake up to flush frames and notice a shutdown
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(1);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Localhost WebSocket that streams job events to the GUI and the extension.
+// Browsers cannot set headers on a WebSocket, so the HTTP API token may also
+// be passed as ?token=.
+pub struct EventServer {
+    shutdown: Arc<AtomicBool>,
+    connections: Arc<AtomicUsize>,
+}
+
+#[derive(Clone)]
+struct Context {
+    jobs: JobRegistry,
+    settings: SettingsStore,
+    token: String,
+    port: u16,
+    shutdown: Arc<AtomicBool>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl EventServer {
+    pub fn start(jobs: JobRegistry, settings: SettingsStore, token: String, port: u16) -> Result<Self, String> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|e| match e.kind() {
+            io::ErrorKind::AddrInUse => format!(
+                "WebSocket port {} is already in use by another program; choose a different port",
+                port
+            ),
+            _ => format!("Failed to start WebSocket server on port {}: {}", port, e),
+        })?;
+        // Non-blocking accept lets the loop notice a shutdown
+        listener
+            .set_nonblocking(true)
+            .map_err(|e| format!("Failed to configure WebSocket listener: {}", e))?;
+
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let connections = Arc::new(AtomicUsize::new(0));
+        let context = Context {
+            jobs,
+            settings,
+            token,
+            port,
+            shutdown: Arc::clone(&shutdown),
+            connections: Arc::clone(&connections),
+        };
+
+        std::thread::spawn(move || {
+            while !context.shutdown.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let context = context.clone();
+                        std::thread::spawn(move || serve_connection(context, stream));
+                    }
+                    Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                        std::thread::sleep(POLL_INTERVAL);
+                    }
+                    Err(error) => {
+                        warn!("WebSocket accept failed: {}", error);
+                        std::thread::sleep(POLL_INTERVAL);
+                    }
+                }
+            }
+        });
+
+        info!("WebSocket events listening on ws://127.0.0.1:{}{}", port, EVENTS_PATH);
+        Ok(EventServer { shutdown, connections })
+    }
+
+    // Stop accepting, send every client a close frame and give them a moment to answer
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + SHUTDOWN_GRACE;
+        while self.connections.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            std::thread::sleep(POLL_INTERVAL / 2);
+        }
+    }
+}
+
+impl Drop for EventServer {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+}
+
+fn serve_connection(context: Context, stream: TcpStream) {
+    // The accepted socket inherits non-blocking mode on some platforms
+    if stream.set_nonblocking(false).is_err() || stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).is_err() {
+        return;
+    }
+
+    let mut socket = match tungstenite::accept_hdr(stream, Handshake(context.clone())) {
+        Ok(socket) => socket,
+        Err(error) => {
+            debug!("WebSocket handshake failed: {}", error);
+            return;
+        }
+    };
+    // From here on reads only wait one poll interval so queued frames go out promptly
+    if socket.get_ref().set_read_timeout(Some(POLL_INTERVAL)).is_err() {
+        return;
+    }
+
+    context.connections.fetch_add(1, Ordering::SeqCst);
+    debug!("WebSocket client connected");
+    let subscription = context.jobs.events().subscribe();
+
+    let result = loop {
+        if context.shutdown.load(Ordering::SeqCst) {
+            close(&mut socket, CloseCode::Away, "ImgVault is shutting down");
+            break Ok(());
+        }
+
+        match socket.read() {
+            Ok(Message::Close(_)) => {
+                // tungstenite queues the close reply; keep flushing until it is sent
+                let _ = socket.flush();
+                break Ok(());
+            }
+            Ok(_) => {}
+            Err(Error::Io(error))
+                if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+            Err(Error::ConnectionClosed | Error::AlreadyClosed) => break Ok(()),
+            Err(error) => break Err(error),
+        }
+
+        let mut send_result = Ok(());
+        for frame in subscription.drain() {
+            send_result = socket.send(Message::Text(frame.to_string()));
+            if send_result.is_err() {
+                break;
+            }
+        }
+        if let Err(error) = send_result {
+            break Err(error);
+        }
+    };
+
+    if let Err(error) = result {
+        debug!("WebSocket client disconnected: {}", error);
+    } else {
+        debug!("WebSocket client disconnected");
+    }
+    context.connections.fetch_sub(1, Ordering::SeqCst);
+}
+
+fn close(socket: &mut WebSocket<TcpStream>, code: CloseCode, reason: &'static str) {
+    let _ = socket.close(Some(CloseFrame { code, reason: reason.into() }));
+    // Wait briefly for the client's close reply so the shutdown is clean
+    let deadline = Instant::now() + SHUTDOWN_GRACE;
+    while Instant::now() < deadline {
+        match socket.read() {
+            Err(Error::Io(error))
+                if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+}
+
+// tungstenite's handshake callback, which rejects with a whole HTTP response
+struct Handshake(Context);
+
+impl Callback for Handshake {
+    fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+        authorize(&self.0, request).map(|()| response).map_err(|rejected| *rejected)
+    }
+}
+
+// Same checks as the HTTP API: our own Host, an allowed Origin when a browser
+// connects, and the bearer token
+fn authorize(context: &Context, request: &Request) -> Result<(), Box<ErrorResponse>> {
+    let header = |name: &str| request.headers().get(name).and_then(|value| value.to_str().ok());
+
+    if request.uri().path() != EVENTS_PATH {
+        return Err(reject(404, "Not found"));
+    }
+
+    let expected_hosts = [format!("127.0.0.1:{}", context.port), format!("localhost:{}", context.port)];
+    if !header("Host").is_some_and(|host| expected_hosts.iter().any(|expected| host.eq_ignore_ascii_case(expected))) {
+        warn!("Rejected WebSocket connection with unexpected Host header");
+        return Err(reject(403, "Forbidden host"));
+    }
+
+    if let Some(origin) = header("Origin") {
+        let origin = origin.trim_end_matches('/');
+        let allowed = context
+            .settings
+            .get()
+            .http_api_allowed_origins
+            .iter()
+            .any(|allowed| allowed.trim_end_matches('/') == origin);
+        if !allowed {
+            warn!(origin = %origin, "Rejected WebSocket connection from an origin that is not allowed");
+            return Err(reject(403, "Origin not allowed"));
+        }
+    }
+
+    let query_token = request.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("token="))
+    });
+    let header_token = header("Authorization").and_then(|value| value.strip_prefix("Bearer "));
+    let authorized = query_token
+        .or(header_token)
+        .is_some_and(|token| crate::http_api::constant_time_eq(token.trim().as_bytes(), context.token.as_bytes()));
+    if !authorized {
+        warn!("Rejected WebSocket connection without a valid token");
+        return Err(reject(401, "Missing or invalid token"));
+    }
+
+    Ok(())
+}
+
+fn reject(status: u16, message: &str) -> Box<ErrorResponse> {
+    let mut response = ErrorResponse::new(Some(message.to_string()));
+    *response.status_mut() = http::StatusCode::from_u16(status).unwrap_or(http::StatusCode::FORBIDDEN);
+    Box::new(response)
+}
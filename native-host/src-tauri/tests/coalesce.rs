@@ -0,0 +1,112 @@
+// The same download sent from two ports while the first is still being
+// dispatched, or already running, runs once; the second request follows the
+// first to its result. One port answers its messages in order, so the race
+// is between ports. The stub yt-dlp is a shell script, hence Unix only
+#![cfg(unix)]
+
+mod common;
+
+use common::{Host, Sandbox};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::thread;
+
+fn download(host: &mut Host, sandbox: &Sandbox, request_id: &str) {
+    host.send(&json!({
+        "action": "download",
+        "request_id": request_id,
+        "url": "https://videos.example/watch?v=twice",
+        "output_path": sandbox.output.join("%(title)s.%(ext)s"),
+        "upload": false,
+    }));
+}
+
+// The final answer to each request, after any queued or coalesced notices,
+// read off one port
+fn results(host: &mut Host, request_ids: &[&str]) -> HashMap<String, (Vec<Value>, Value)> {
+    let mut notices = HashMap::<String, Vec<Value>>::new();
+    let mut answers = HashMap::new();
+    while answers.len() < request_ids.len() {
+        let frame = host.recv();
+        let Some(request_id) = frame["requestId"].as_str().filter(|id| request_ids.contains(id)) else {
+            continue;
+        };
+        let request_id = request_id.to_string();
+        match frame["event"].as_str() {
+            Some("progress") => {}
+            Some("queued") => notices.entry(request_id).or_default().push(frame),
+            _ => {
+                let notices = notices.remove(&request_id).unwrap_or_default();
+                answers.insert(request_id, (notices, frame));
+            }
+        }
+    }
+    answers
+}
+
+fn assert_followed(notices: &[Value], answer: &Value, owner: &str, owner_answer: &Value) {
+    assert!(
+        notices.iter().any(|notice| notice["data"]["coalesced"] == true && notice["data"]["jobId"] == owner),
+        "no coalesced notice: {:?}",
+        notices
+    );
+    assert_eq!(answer["success"], true, "{}", answer);
+    assert_eq!(answer["data"]["jobId"], owner, "{}", answer);
+    assert_eq!(answer["filePath"], owner_answer["filePath"], "{} against {}", answer, owner_answer);
+}
+
+#[test]
+fn a_double_submit_across_hosts_runs_once() {
+    let sandbox = Sandbox::create("coalesce-hosts");
+    let runs_path = common::with_counting_yt_dlp(&sandbox);
+
+    let answers = thread::scope(|scope| {
+        ["left", "right"]
+            .map(|request_id| {
+                let sandbox = &sandbox;
+                scope.spawn(move || {
+                    let mut host = sandbox.spawn();
+                    download(&mut host, sandbox, request_id);
+                    let answer = results(&mut host, &[request_id]).remove(request_id).unwrap();
+                    let (status, _) = host.finish();
+                    assert!(status.success());
+                    (request_id, answer)
+                })
+            })
+            .map(|host| host.join().unwrap())
+    });
+
+    // Whichever claimed the job first ran it; the other followed
+    let [(left_id, (left_notices, left)), (right_id, (right_notices, right))] = answers;
+    let owned_by_left = !left_notices.iter().any(|notice| notice["data"]["coalesced"] == true);
+    if owned_by_left {
+        assert_eq!(left["success"], true, "{}", left);
+        assert_followed(&right_notices, &right, left_id, &left);
+    } else {
+        assert_eq!(right["success"], true, "{}", right);
+        assert_followed(&left_notices, &left, right_id, &right);
+    }
+    assert_eq!(common::runs(&runs_path), 1, "yt-dlp ran for the duplicate");
+}
+
+#[test]
+fn a_duplicate_sent_while_the_first_runs_follows_it() {
+    let sandbox = Sandbox::create("coalesce-running");
+    let runs_path = common::with_counting_yt_dlp(&sandbox);
+    let mut first_host = sandbox.spawn();
+    download(&mut first_host, &sandbox, "first");
+    common::wait_for_runs(&runs_path, 1);
+
+    let mut second_host = sandbox.spawn();
+    download(&mut second_host, &sandbox, "second");
+    let (_, first) = results(&mut first_host, &["first"]).remove("first").unwrap();
+    let (notices, second) = results(&mut second_host, &["second"]).remove("second").unwrap();
+
+    assert_eq!(first["success"], true, "{}", first);
+    assert_followed(&notices, &second, "first", &first);
+    assert_eq!(common::runs(&runs_path), 1, "yt-dlp ran for the duplicate");
+    for host in [first_host, second_host] {
+        let (status, _) = host.finish();
+        assert!(status.success());
+    }
+}
@@ -0,0 +1,224 @@
+// Native host processes in a data folder of their own, for the tests that
+// need the real binary
+#![allow(dead_code)]
+
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const HOST: &str = env!("CARGO_BIN_EXE_imgvault-native-host");
+pub const FRAME_TIMEOUT: Duration = Duration::from_secs(20);
+#[cfg(target_os = "windows")]
+const APP_DATA_DIR_NAME: &str = "ImgVault";
+#[cfg(not(target_os = "windows"))]
+const APP_DATA_DIR_NAME: &str = "imgvault";
+
+// Logs each run, then takes long enough that every duplicate arrives while
+// it is in flight
+#[cfg(unix)]
+const COUNTING_YT_DLP: &str = r#"#!/bin/sh
+echo "$1" >> "$(dirname "$0")/runs"
+output=""
+while [ $# -gt 0 ]; do
+    if [ "$1" = "-o" ]; then
+        shift
+        output="$1"
+    fi
+    shift
+done
+sleep 1
+path=$(echo "$output" | sed -e 's/%(title)[^a-zA-Z]*s/stub/g' -e 's/%(ext)s/mkv/g')
+echo "[download] Destination: $path"
+echo "[download] 100.0% of 4.00KiB at 1.00MiB/s ETA 00:00"
+mkdir -p "$(dirname "$path")"
+head -c 4096 /dev/zero > "$path"
+echo "$path"
+"#;
+
+// Points the sandbox's settings at COUNTING_YT_DLP; the runs file it returns
+// has a line per run
+#[cfg(unix)]
+pub fn with_counting_yt_dlp(sandbox: &Sandbox) -> PathBuf {
+    use std::os::unix::fs::PermissionsExt;
+    let yt_dlp = sandbox.root.join("yt-dlp");
+    fs::write(&yt_dlp, COUNTING_YT_DLP).expect("stub yt-dlp");
+    fs::set_permissions(&yt_dlp, fs::Permissions::from_mode(0o755)).expect("stub yt-dlp");
+    let settings = json!({
+        "vault_root": sandbox.output,
+        "notifications": "off",
+        "yt_dlp_path": yt_dlp,
+    });
+    fs::write(sandbox.app_data.join("settings.json"), settings.to_string()).expect("settings");
+    sandbox.root.join("runs")
+}
+
+pub fn runs(path: &Path) -> usize {
+    fs::read_to_string(path).map(|runs| runs.lines().count()).unwrap_or(0)
+}
+
+// Until the stub has started `count` runs
+pub fn wait_for_runs(path: &Path, count: usize) {
+    let deadline = Instant::now() + FRAME_TIMEOUT;
+    while runs(path) < count {
+        assert!(Instant::now() < deadline, "yt-dlp never started");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+// Removed when dropped
+pub struct Sandbox {
+    pub root: PathBuf,
+    pub data_home: PathBuf,
+    pub app_data: PathBuf,
+    pub output: PathBuf,
+}
+
+impl Sandbox {
+    pub fn create(name: &str) -> Sandbox {
+        let root = env::temp_dir().join(format!("imgvault-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        let data_home = root.join("data");
+        let app_data = data_home.join(APP_DATA_DIR_NAME);
+        let output = root.join("downloads");
+        fs::create_dir_all(&app_data).expect("app data folder");
+        fs::create_dir_all(&output).expect("download folder");
+        let settings = json!({ "vault_root": output, "notifications": "off" });
+        fs::write(app_data.join("settings.json"), settings.to_string()).expect("settings");
+        Sandbox {
+            root,
+            data_home,
+            app_data,
+            output,
+        }
+    }
+
+    pub fn spawn(&self) -> Host {
+        let mut child = Command::new(HOST)
+            .arg("--native")
+            .env("XDG_DATA_HOME", &self.data_home)
+            .env("LOCALAPPDATA", &self.data_home)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("host starts");
+        let stdin = child.stdin.take();
+        let mut stdout = child.stdout.take().expect("host stdout");
+        let (sender, frames) = mpsc::channel();
+        thread::spawn(move || loop {
+            match read_frame(&mut stdout) {
+                Ok(Some(frame)) => {
+                    if sender.send(Ok(frame)).is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => return,
+                Err(error) => {
+                    let _ = sender.send(Err(error));
+                    return;
+                }
+            }
+        });
+        Host { child, stdin, frames }
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+pub struct Host {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    frames: Receiver<Result<Value, String>>,
+}
+
+impl Host {
+    pub fn send(&mut self, message: &Value) {
+        let body = message.to_string();
+        let stdin = self.stdin.as_mut().expect("port is open");
+        stdin.write_all(&(body.len() as u32).to_ne_bytes()).expect("frame header");
+        stdin.write_all(body.as_bytes()).expect("frame body");
+        stdin.flush().expect("frame flush");
+    }
+
+    // The next frame the host writes
+    pub fn recv(&mut self) -> Value {
+        match self.frames.recv_timeout(FRAME_TIMEOUT) {
+            Ok(Ok(frame)) => frame,
+            Ok(Err(error)) => panic!("{}", error),
+            Err(_) => panic!("no frame from the host within {:?}", FRAME_TIMEOUT),
+        }
+    }
+
+    // Frames up to the first answer to `request_id`
+    pub fn answer(&mut self, request_id: &str) -> Value {
+        loop {
+            let frame = self.recv();
+            if frame["requestId"] == request_id && frame["event"] != "progress" {
+                return frame;
+            }
+        }
+    }
+
+    // Closes the port as Chrome does, then waits for the host to exit with
+    // every frame it wrote after the ones already received
+    pub fn finish(mut self) -> (ExitStatus, Vec<Value>) {
+        self.stdin = None;
+        let mut frames = Vec::new();
+        loop {
+            match self.frames.recv_timeout(FRAME_TIMEOUT) {
+                Ok(Ok(frame)) => frames.push(frame),
+                Ok(Err(error)) => panic!("{}", error),
+                Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) => panic!("host did not exit after its port closed"),
+            }
+        }
+        let deadline = Instant::now() + FRAME_TIMEOUT;
+        let status = loop {
+            if let Some(status) = self.child.try_wait().expect("host status") {
+                break status;
+            }
+            assert!(Instant::now() < deadline, "host did not exit after its port closed");
+            thread::sleep(Duration::from_millis(50));
+        };
+        (status, frames)
+    }
+}
+
+impl Drop for Host {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+    }
+}
+
+// One frame of stdout; None at EOF between frames, Err for anything that is
+// not a whole frame of JSON
+fn read_frame(stdout: &mut impl Read) -> Result<Option<Value>, String> {
+    let mut header = [0u8; 4];
+    let mut read = 0;
+    while read < 4 {
+        match stdout.read(&mut header[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(format!("stdout ended {} bytes into a frame header", read)),
+            Ok(count) => read += count,
+            Err(error) => return Err(format!("Failed to read stdout: {}", error)),
+        }
+    }
+    let length = u32::from_ne_bytes(header) as usize;
+    let mut body = vec![0u8; length];
+    stdout
+        .read_exact(&mut body)
+        .map_err(|e| format!("frame of {} bytes cut off: {}", length, e))?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| format!("frame is not JSON ({}): {}", e, String::from_utf8_lossy(&body)))
+}
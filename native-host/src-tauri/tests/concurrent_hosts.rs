@@ -0,0 +1,72 @@
+// Three native hosts queueing at once into the shared queue file; every job
+// must land there exactly once
+mod common;
+
+use common::Sandbox;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::fs;
+use std::thread;
+
+const HOSTS: usize = 3;
+const JOBS_PER_HOST: usize = 20;
+
+#[test]
+fn concurrent_hosts_neither_lose_nor_duplicate_jobs() {
+    let sandbox = Sandbox::create("concurrent-hosts");
+    // With the queue paused every download goes to the queue file under its lock
+    let mut pausing = sandbox.spawn();
+    pausing.send(&json!({ "action": "pause_queue", "request_id": "pause" }));
+    assert_eq!(pausing.answer("pause")["success"], true);
+    let (status, _) = pausing.finish();
+    assert!(status.success());
+
+    let outputs = thread::scope(|scope| {
+        let running = (0..HOSTS)
+            .map(|host| {
+                let sandbox = &sandbox;
+                scope.spawn(move || {
+                    let mut port = sandbox.spawn();
+                    for job in 0..JOBS_PER_HOST {
+                        port.send(&json!({
+                            "action": "download",
+                            "request_id": format!("host{}-job{}", host, job),
+                            "url": format!("http://127.0.0.1:9/{}-{}.png", host, job),
+                            "output_path": sandbox.output.join("%(title)s.%(ext)s"),
+                            "upload": false,
+                        }));
+                    }
+                    let answers = (0..JOBS_PER_HOST)
+                        .map(|job| port.answer(&format!("host{}-job{}", host, job)))
+                        .collect::<Vec<_>>();
+                    let (status, _) = port.finish();
+                    (status, answers)
+                })
+            })
+            .collect::<Vec<_>>();
+        running.into_iter().map(|host| host.join().unwrap()).collect::<Vec<_>>()
+    });
+
+    let expected = (0..HOSTS)
+        .flat_map(|host| (0..JOBS_PER_HOST).map(move |job| format!("host{}-job{}", host, job)))
+        .collect::<HashSet<_>>();
+    let mut answered = HashSet::new();
+    for (status, frames) in outputs {
+        assert!(status.success());
+        for frame in frames {
+            assert_eq!(frame["event"], "paused", "unexpected answer {}", frame);
+            assert_eq!(frame["success"], true, "job was not kept: {}", frame);
+            assert!(answered.insert(frame["requestId"].as_str().unwrap().to_string()), "answered twice: {}", frame);
+        }
+    }
+    assert_eq!(answered, expected);
+
+    let queue: Vec<Value> =
+        serde_json::from_str(&fs::read_to_string(sandbox.app_data.join("scheduled-downloads.json")).unwrap()).unwrap();
+    let queued = queue
+        .iter()
+        .map(|download| download["jobId"].as_str().unwrap().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(queued.len(), expected.len(), "jobs were lost or duplicated");
+    assert_eq!(queued.into_iter().collect::<HashSet<_>>(), expected);
+}
@@ -0,0 +1,198 @@
+// A native host hands its port to the GUI when one runs, and serves it alone
+// when the GUI exits or was not there to begin with. The test plays the GUI
+// at the endpoint the sandbox's app data names. The stub yt-dlp is a shell
+// script, hence Unix only
+#![cfg(unix)]
+
+mod common;
+
+use common::{Host, Sandbox, FRAME_TIMEOUT};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TOKEN: &str = "handover-test-token";
+// Frames about a request before its answer
+const INTERIM_EVENTS: &[&str] = &["progress", "queued", "waiting", "stalled", "warning"];
+
+// Stands in for the GUI's single-instance listener
+struct FakeGui {
+    listener: TcpListener,
+}
+
+impl FakeGui {
+    fn start(sandbox: &Sandbox) -> FakeGui {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).expect("GUI port");
+        listener.set_nonblocking(true).expect("GUI port");
+        let endpoint = json!({ "port": listener.local_addr().expect("GUI port").port(), "token": TOKEN });
+        fs::write(sandbox.app_data.join("instance.json"), endpoint.to_string()).expect("instance endpoint");
+        FakeGui { listener }
+    }
+
+    // The port of the next host to attach, handshake done
+    fn attached(&self) -> GuiPort {
+        let deadline = Instant::now() + FRAME_TIMEOUT;
+        let mut stream = loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => break stream,
+                Err(error) if error.kind() == ErrorKind::WouldBlock && Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(20))
+                }
+                Err(error) => panic!("no host attached: {}", error),
+            }
+        };
+        stream.set_nonblocking(false).expect("GUI connection");
+        stream.set_read_timeout(Some(FRAME_TIMEOUT)).expect("GUI connection");
+
+        // Byte by byte, so nothing after the line is read with it
+        let mut request = Vec::new();
+        let mut byte = [0u8; 1];
+        while byte[0] != b'\n' {
+            stream.read_exact(&mut byte).expect("attach request");
+            request.push(byte[0]);
+        }
+        let request: Value = serde_json::from_slice(&request).expect("attach request JSON");
+        assert_eq!(request["token"], TOKEN);
+        assert_eq!(request["kind"], "attach_native");
+        stream.write_all(b"{\"ok\":true,\"message\":null}\n").expect("attach reply");
+        GuiPort { stream }
+    }
+
+    fn assert_nothing_attached(&self) {
+        match self.listener.accept() {
+            Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+            Ok(_) => panic!("a host attached to the GUI"),
+            Err(error) => panic!("{}", error),
+        }
+    }
+}
+
+// The GUI's end of an attached port; dropping it is the GUI exiting
+struct GuiPort {
+    stream: TcpStream,
+}
+
+impl GuiPort {
+    fn recv(&mut self) -> Value {
+        let mut length = [0u8; 4];
+        self.stream.read_exact(&mut length).expect("relayed frame header");
+        let mut body = vec![0u8; u32::from_ne_bytes(length) as usize];
+        self.stream.read_exact(&mut body).expect("relayed frame body");
+        serde_json::from_slice(&body).expect("relayed frame JSON")
+    }
+
+    fn send(&mut self, frame: &Value) {
+        let body = frame.to_string();
+        self.stream.write_all(&(body.len() as u32).to_ne_bytes()).expect("frame header");
+        self.stream.write_all(body.as_bytes()).expect("frame body");
+    }
+}
+
+fn ping(request_id: &str) -> Value {
+    json!({ "action": "ping", "request_id": request_id })
+}
+
+fn download(sandbox: &Sandbox, request_id: &str) -> Value {
+    json!({
+        "action": "download",
+        "request_id": request_id,
+        "url": "https://videos.example/watch?v=handover",
+        "output_path": sandbox.output.join("%(title)s.%(ext)s"),
+        "upload": false,
+    })
+}
+
+fn gui_answer(request_id: &str) -> Value {
+    json!({ "success": true, "event": "complete", "requestId": request_id, "message": "answered by the GUI" })
+}
+
+// Every frame up to and with the answer to `request_id`
+fn frames_until_answer(host: &mut Host, request_id: &str) -> Vec<Value> {
+    let mut frames = Vec::new();
+    loop {
+        let frame = host.recv();
+        let answered = frame["requestId"] == request_id
+            && !frame["event"].as_str().is_some_and(|event| INTERIM_EVENTS.contains(&event));
+        frames.push(frame);
+        if answered {
+            return frames;
+        }
+    }
+}
+
+#[test]
+fn a_gui_that_exits_mid_download_hands_the_download_back() {
+    let sandbox = Sandbox::create("handover-exit");
+    let runs_path = common::with_counting_yt_dlp(&sandbox);
+    let gui = FakeGui::start(&sandbox);
+    let mut host = sandbox.spawn();
+    let mut port = gui.attached();
+
+    // Answered by the GUI
+    host.send(&ping("before"));
+    assert_eq!(port.recv()["request_id"], "before");
+    port.send(&gui_answer("before"));
+    assert_eq!(host.answer("before"), gui_answer("before"));
+
+    // Taken by the GUI, which exits part way through
+    host.send(&download(&sandbox, "download"));
+    assert_eq!(port.recv()["request_id"], "download");
+    let progress = json!({ "success": true, "event": "progress", "requestId": "download", "line": "[download]   5.0%" });
+    port.send(&progress);
+    assert_eq!(host.recv(), progress);
+    drop(port);
+
+    // So the host runs it, and answers it once
+    let frames = frames_until_answer(&mut host, "download");
+    let answer = frames.last().unwrap();
+    assert_eq!(answer["success"], true, "{}", answer);
+    assert!(fs::metadata(answer["filePath"].as_str().expect("file path")).is_ok(), "{}", answer);
+    assert_eq!(common::runs(&runs_path), 1);
+
+    // The port carries on standalone, and what the GUI answered stays answered
+    host.send(&ping("after"));
+    let mut frames = frames.into_iter().chain(frames_until_answer(&mut host, "after")).collect::<Vec<_>>();
+    let after = frames.last().unwrap();
+    assert_eq!(after["success"], true, "{}", after);
+    assert_ne!(after["message"], "answered by the GUI");
+    let (status, rest) = host.finish();
+    assert!(status.success());
+    frames.extend(rest);
+    assert!(!frames.iter().any(|frame| frame["requestId"] == "before"), "{:?}", frames);
+    assert_eq!(frames.iter().filter(|frame| frame["requestId"] == "download" && frame["event"] == "complete").count(), 1);
+}
+
+#[test]
+fn a_gui_that_starts_mid_download_leaves_the_port_to_the_host() {
+    let sandbox = Sandbox::create("handover-start");
+    let runs_path = common::with_counting_yt_dlp(&sandbox);
+    let mut host = sandbox.spawn();
+    host.send(&download(&sandbox, "standalone"));
+    common::wait_for_runs(&runs_path, 1);
+
+    // A port is handed over when it opens, never later
+    let gui = FakeGui::start(&sandbox);
+    let answer = frames_until_answer(&mut host, "standalone").pop().unwrap();
+    assert_eq!(answer["success"], true, "{}", answer);
+    host.send(&ping("still-standalone"));
+    assert_ne!(host.answer("still-standalone")["message"], "answered by the GUI");
+    gui.assert_nothing_attached();
+
+    // The next one goes to the GUI
+    let mut second = sandbox.spawn();
+    let mut port = gui.attached();
+    second.send(&ping("relayed"));
+    assert_eq!(port.recv()["request_id"], "relayed");
+    port.send(&gui_answer("relayed"));
+    assert_eq!(second.answer("relayed"), gui_answer("relayed"));
+
+    for host in [host, second] {
+        let (status, _) = host.finish();
+        assert!(status.success());
+    }
+    assert_eq!(common::runs(&runs_path), 1);
+}
+
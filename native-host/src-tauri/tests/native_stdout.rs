@@ -0,0 +1,71 @@
+// Nothing but protocol frames reaches stdout in native mode
+mod common;
+
+use common::Sandbox;
+use imgvault_native_host::native_schema::ACTIONS;
+use imgvault_native_host::native_stdout;
+use serde_json::{json, Value};
+use std::env;
+use std::process::Command;
+
+// Set when the test binary runs itself to print past the guard
+const STRAY_PRINT_ENV: &str = "IMGVAULT_TEST_STRAY_PRINT";
+
+fn assert_well_formed(frame: &Value) {
+    let fields = frame.as_object().unwrap_or_else(|| panic!("frame is not an object: {}", frame));
+    assert!(fields.get("success").is_some_and(Value::is_boolean), "frame has no boolean success: {}", frame);
+}
+
+#[test]
+fn every_action_answers_with_frames_only() {
+    let sandbox = Sandbox::create("stdout");
+    let mut host = sandbox.spawn();
+    let mut answered = Vec::new();
+    for action in ACTIONS {
+        let request_id = format!("probe-{}", action);
+        // Nothing listens on the discard port, so downloads fail fast
+        host.send(&json!({
+            "action": action,
+            "request_id": request_id,
+            "url": "http://127.0.0.1:9/probe.png",
+            "output_path": sandbox.output.join("%(title)s.%(ext)s"),
+            "upload": false,
+        }));
+        let answer = host.answer(&request_id);
+        assert_well_formed(&answer);
+        answered.push(answer);
+    }
+    // Frames after the last answer, up to the shutdown, are read the same way
+    let (_, rest) = host.finish();
+    for frame in &rest {
+        assert_well_formed(frame);
+    }
+    assert_eq!(answered.len(), ACTIONS.len());
+}
+
+// A print! that gets past the guard panics in debug builds instead of
+// corrupting the port. The guard takes over the whole process's stdout, so
+// the test runs itself in a child to do it.
+#[cfg(debug_assertions)]
+#[test]
+fn printing_past_the_guard_panics() {
+    if env::var_os(STRAY_PRINT_ENV).is_some() {
+        let runtime = tokio::runtime::Runtime::new().expect("runtime");
+        let _guarded = {
+            let _context = runtime.enter();
+            native_stdout::claim(None)
+        };
+        println!("stray output");
+        return;
+    }
+
+    let output = Command::new(env::current_exe().expect("test binary"))
+        .args(["printing_past_the_guard_panics", "--exact", "--nocapture"])
+        .env(STRAY_PRINT_ENV, "1")
+        .output()
+        .expect("test binary runs");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("failed printing to stdout"), "{}", stderr);
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("stray output"));
+}
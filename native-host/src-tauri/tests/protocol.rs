@@ -0,0 +1,249 @@
+// Native messaging framing through the library crate, without Tauri
+use imgvault_native_host::protocol::{self, IncomingFrame, NativeResponse, PortEnd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc;
+
+fn frame(body: &[u8]) -> Vec<u8> {
+    let mut frame = (body.len() as u32).to_ne_bytes().to_vec();
+    frame.extend_from_slice(body);
+    frame
+}
+
+// Everything the reader forwards from `input`, and why it stopped
+async fn read_all(mut input: impl tokio::io::AsyncRead + Unpin) -> (Vec<IncomingFrame>, PortEnd) {
+    let (sender, mut receiver) = mpsc::channel(16);
+    let end = protocol::read_native_messages(&mut input, &sender, None).await;
+    drop(sender);
+    let mut frames = Vec::new();
+    while let Some(frame) = receiver.recv().await {
+        frames.push(frame);
+    }
+    (frames, end)
+}
+
+// Hands out at most `chunk` bytes per read, the way a pipe may
+struct ShortReads<'a> {
+    data: &'a [u8],
+    chunk: usize,
+}
+
+impl AsyncRead for ShortReads<'_> {
+    fn poll_read(mut self: Pin<&mut Self>, _: &mut Context<'_>, buffer: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let length = self.chunk.min(buffer.remaining()).min(self.data.len());
+        let (read, rest) = self.data.split_at(length);
+        buffer.put_slice(read);
+        self.data = rest;
+        Poll::Ready(Ok(()))
+    }
+}
+
+// What a test expects of a frame, or of the end of the port
+fn describe(frame: &IncomingFrame) -> String {
+    match frame {
+        IncomingFrame::Message(text) => text.clone(),
+        IncomingFrame::Invalid(reason) => format!("invalid: {}", reason),
+    }
+}
+
+fn describe_end(end: &PortEnd) -> String {
+    match end {
+        PortEnd::Closed => "closed".to_string(),
+        PortEnd::Truncated(reason) => format!("truncated: {}", reason),
+    }
+}
+
+#[tokio::test]
+async fn reads_frames_however_the_stream_splits_them() {
+    let ping = frame(br#"{"action":"ping"}"#);
+    let two = [ping.clone(), frame(br#"{"action":"diagnostics"}"#)].concat();
+    let cut_body = [ping.clone(), frame(b"0123456789")[..7].to_vec()].concat();
+    let empty_between = [frame(b""), ping.clone()].concat();
+    let cases: &[(&str, &[u8], &[&str], &str)] = &[
+        ("nothing at all", b"", &[], "closed"),
+        ("one frame", &ping, &[r#"{"action":"ping"}"#], "closed"),
+        ("two frames", &two, &[r#"{"action":"ping"}"#, r#"{"action":"diagnostics"}"#], "closed"),
+        ("EOF inside the first header", &ping[..2], &[], "truncated: Frame header cut off after 2 of 4 bytes"),
+        ("EOF inside a later header", &[ping.as_slice(), &[1, 0]].concat(), &[r#"{"action":"ping"}"#], "truncated: Frame header cut off after 2 of 4 bytes"),
+        ("EOF right after a header", &ping[..4], &[], "truncated: Frame body cut off after 0 of 17 bytes"),
+        ("EOF inside a body", &cut_body, &[r#"{"action":"ping"}"#], "truncated: Frame body cut off after 3 of 10 bytes"),
+        ("a zero-length frame", &frame(b""), &["invalid: Received an empty message"], "closed"),
+        ("a zero-length frame before another", &empty_between, &["invalid: Received an empty message", r#"{"action":"ping"}"#], "closed"),
+    ];
+
+    for (name, input, expected_frames, expected_end) in cases {
+        for chunk in [1, 2, 3, 5, 4096] {
+            let (frames, end) = read_all(ShortReads { data: input, chunk }).await;
+            let frames = frames.iter().map(describe).collect::<Vec<_>>();
+            assert_eq!(frames, *expected_frames, "{} in reads of {} bytes", name, chunk);
+            assert_eq!(describe_end(&end), *expected_end, "{} in reads of {} bytes", name, chunk);
+        }
+    }
+}
+
+#[tokio::test]
+async fn reads_frames_written_through_a_narrow_pipe() {
+    let messages = (0..12).map(|index| format!(r#"{{"action":"ping","request_id":"r{}"}}"#, index)).collect::<Vec<_>>();
+    // Fewer than read_all's channel holds, since it drains only at the end
+    let (mut writer, reader) = tokio::io::duplex(3);
+    let written = messages.clone();
+    let writing = tokio::spawn(async move {
+        for message in written {
+            writer.write_all(&frame(message.as_bytes())).await.expect("write");
+        }
+        writer.write_all(&frame(b"")).await.expect("write");
+        // Dropping the writer is Chrome closing the port
+    });
+
+    let (frames, end) = read_all(reader).await;
+    writing.await.expect("writer");
+    let mut expected = messages;
+    expected.push("invalid: Received an empty message".to_string());
+    assert_eq!(frames.iter().map(describe).collect::<Vec<_>>(), expected);
+    assert_eq!(describe_end(&end), "closed");
+}
+
+#[tokio::test]
+async fn forwards_each_frame_in_order() {
+    let mut input = frame(br#"{"action":"ping"}"#);
+    input.extend(frame(br#"{"action":"get_settings","request_id":"r1"}"#));
+    let (frames, end) = read_all(input.as_slice()).await;
+
+    assert!(matches!(end, PortEnd::Closed));
+    let messages = frames
+        .iter()
+        .map(|frame| match frame {
+            IncomingFrame::Message(text) => text.as_str(),
+            IncomingFrame::Invalid(reason) => panic!("unexpected invalid frame: {}", reason),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(messages, [r#"{"action":"ping"}"#, r#"{"action":"get_settings","request_id":"r1"}"#]);
+}
+
+#[tokio::test]
+async fn reports_a_frame_that_is_not_utf8_and_carries_on() {
+    let mut input = frame(&[0xff, 0xfe]);
+    input.extend(frame(br#"{"action":"ping"}"#));
+    let (frames, end) = read_all(input.as_slice()).await;
+
+    assert!(matches!(end, PortEnd::Closed));
+    assert!(matches!(&frames[0], IncomingFrame::Invalid(_)));
+    assert!(matches!(&frames[1], IncomingFrame::Message(text) if text == r#"{"action":"ping"}"#));
+}
+
+#[test]
+fn answers_an_invalid_frame_with_an_error_code() {
+    let response = protocol::invalid_message_response("Received an empty message".to_string());
+    assert!(!response.success);
+    assert_eq!(response.event.as_deref(), Some("complete"));
+    assert_eq!(response.error_code.as_deref(), Some("invalid_message"));
+}
+
+#[test]
+fn answers_a_strict_message_that_breaks_the_schema() {
+    let response = protocol::strict_schema_response(r#"{"action":"ping","strict":true,"bogus":1}"#, false)
+        .expect("a strict message with an unknown key is rejected");
+    assert_eq!(response.error_code.as_deref(), Some("invalid_schema"));
+    assert!(protocol::strict_schema_response(r#"{"action":"ping","bogus":1}"#, false).is_none());
+}
+
+// The schema errors a strict answer lists, as "path: error"
+fn schema_errors(message: &str, strict_session: bool) -> Option<Vec<String>> {
+    let response = protocol::strict_schema_response(message, strict_session)?;
+    assert_eq!(response.error_code.as_deref(), Some("invalid_schema"), "{}", message);
+    assert!(!response.success);
+    let errors = response.data.expect("errors")["errors"]
+        .as_array()
+        .expect("errors")
+        .iter()
+        .map(|error| format!("{}: {}", error["path"].as_str().unwrap(), error["error"].as_str().unwrap()))
+        .collect();
+    Some(errors)
+}
+
+#[test]
+fn each_malformed_message_gets_every_error_it_has() {
+    // Errors come in the order of the message's keys within each kind
+    let cases: &[(&str, &[&str])] = &[
+        (r#"{"strict":true}"#, &["action: missing field"]),
+        (r#"{"action":"fly","strict":true}"#, &[r#"action: unknown action "fly""#]),
+        (r#"{"action":7,"strict":true}"#, &["action: expected string, got integer"]),
+        (r#"{"action":"ping","strict":"yes"}"#, &[]),
+        (r#"{"action":"archive_channel","strict":true,"max_items":"10"}"#, &["max_items: expected integer, got string"]),
+        (
+            r#"{"action":"download","strict":true,"url":5,"upload":"yes","force_new":1}"#,
+            &["url: expected string, got integer", "upload: expected boolean, got string", "force_new: expected boolean, got integer"],
+        ),
+        (
+            r#"{"action":"download","strict":true,"cookies_data":[{"domain":"x","host_only":true,"path":"/","secure":true,"expiration_date":0,"name":"a","value":"b"},{"domain":"x","host_only":true,"path":"/","secure":true,"expiration_date":0,"name":1,"value":"b"}]}"#,
+            &["cookies_data[1]: expected string, got integer"],
+        ),
+        (
+            r#"{"action":"download","strict":true,"cookies_data":[{"domain":"x","name":"a","value":"b"}]}"#,
+            &["cookies_data[0]: missing field `host_only`"],
+        ),
+        (
+            r#"{"action":"download","strict":true,"cookies_data":[{"domain":"x","host_only":true,"path":"/","secure":true,"expiration_date":0,"name":"a","value":"b","sameSite":"lax"}]}"#,
+            &["cookies_data[0].sameSite: unknown field"],
+        ),
+        (
+            r#"{"action":"subscribe","strict":true,"filter":{"job_ids":["a"],"domain":"x"}}"#,
+            &["filter.domain: unknown field"],
+        ),
+        (
+            r#"{"action":"download","strict":true,"dimensions":{"width":1,"height":2,"depth":3}}"#,
+            &["dimensions.depth: unknown field"],
+        ),
+        (
+            r#"{"action":"fly","strict":true,"bogus":1,"upload":"no","filter":{"domain":"x"}}"#,
+            &[r#"action: unknown action "fly""#, "upload: expected boolean, got string", "bogus: unknown field", "filter.domain: unknown field"],
+        ),
+    ];
+    for (message, expected) in cases {
+        let errors = schema_errors(message, false).unwrap_or_default();
+        assert_eq!(errors, *expected, "{}", message);
+    }
+
+    // A cookie value of the wrong type is named, never quoted
+    let errors = schema_errors(
+        r#"{"action":"download","strict":true,"cookies_data":[{"domain":"x","host_only":true,"path":"/","secure":true,"expiration_date":0,"name":"sid","value":48151623}]}"#,
+        false,
+    )
+    .expect("rejected");
+    assert_eq!(errors, ["cookies_data[0]: expected string, got integer"]);
+
+    // Only a strict session can ask for a message that has no flag of its own
+    let session_cases: &[(&str, &[&str])] = &[
+        (r#"[1,2]"#, &["message: expected object, got array"]),
+        (r#""ping""#, &["message: expected object, got string"]),
+        (r#"{"action":"ping","strict":"yes"}"#, &["strict: expected boolean, got string"]),
+    ];
+    for (message, expected) in session_cases {
+        let errors = schema_errors(message, true).unwrap_or_default();
+        assert_eq!(errors, *expected, "{}", message);
+    }
+}
+
+#[test]
+fn only_strict_messages_are_checked() {
+    let bogus = r#"{"action":"ping","bogus":1}"#;
+    assert_eq!(schema_errors(bogus, false), None);
+    assert_eq!(schema_errors(bogus, true), Some(vec!["bogus: unknown field".to_string()]));
+    // A message's own flag wins over the session's
+    assert_eq!(schema_errors(r#"{"action":"ping","bogus":1,"strict":false}"#, true), None);
+    assert_eq!(schema_errors(r#"{"action":"ping","request_id":"r1","strict":true}"#, false), None);
+    // Not JSON at all is the reader's to answer, not the schema's
+    assert_eq!(schema_errors("{", true), None);
+}
+
+#[test]
+fn responses_use_the_extension_field_names() {
+    let response: NativeResponse =
+        serde_json::from_str(r#"{"success":true,"event":"complete","requestId":"r1","filePath":"/v/a.mp4"}"#)
+            .expect("response parses");
+    let value = serde_json::to_value(&response).expect("response serializes");
+    assert_eq!(value["requestId"], "r1");
+    assert_eq!(value["filePath"], "/v/a.mp4");
+    assert!(value.get("error_code").is_none());
+}
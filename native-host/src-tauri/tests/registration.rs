@@ -0,0 +1,58 @@
+// Native messaging registration through the library crate, without Tauri
+use imgvault_native_host::registration::{self, Artifact, ArtifactKind, ArtifactStatus, RegistrationReport};
+use std::env;
+
+fn artifact(status: ArtifactStatus, required: bool, error: Option<&str>) -> Artifact {
+    Artifact {
+        kind: ArtifactKind::Manifest,
+        browser: None,
+        location: "manifest.json".to_string(),
+        required,
+        status,
+        error: error.map(str::to_string),
+    }
+}
+
+#[test]
+fn manifest_lives_in_the_app_data_folder() {
+    let data = env::temp_dir().join("imgvault-registration-test");
+    #[cfg(target_os = "windows")]
+    env::set_var("LOCALAPPDATA", &data);
+    #[cfg(not(target_os = "windows"))]
+    env::set_var("XDG_DATA_HOME", &data);
+
+    let path = registration::native_manifest_path().expect("manifest path resolves");
+    assert!(path.starts_with(&data));
+    assert_eq!(path.file_name().and_then(|name| name.to_str()), Some("manifest.json"));
+}
+
+#[test]
+fn a_failed_registration_reports_its_errors() {
+    let report = RegistrationReport {
+        success: false,
+        artifacts: vec![
+            artifact(ArtifactStatus::RolledBack, true, None),
+            artifact(ArtifactStatus::Failed, true, Some("Failed to create registry key: denied")),
+        ],
+    };
+    assert_eq!(report.into_result().unwrap_err(), "Failed to create registry key: denied");
+
+    let report = RegistrationReport {
+        success: true,
+        artifacts: vec![artifact(ArtifactStatus::Created, true, None)],
+    };
+    assert!(report.into_result().is_ok());
+}
+
+#[test]
+fn unknown_browsers_are_refused() {
+    assert!(registration::register_native_host("johjkjkidbedgjmogpekmlpfakccnoan", "netscape").is_err());
+    assert!(registration::unregister_native_host("netscape").is_err());
+}
+
+#[cfg(not(target_os = "windows"))]
+#[test]
+fn registration_is_windows_only() {
+    assert!(registration::host_artifacts().expect("artifacts list").is_empty());
+    assert!(registration::register_native_host("johjkjkidbedgjmogpekmlpfakccnoan", "chrome").is_err());
+}
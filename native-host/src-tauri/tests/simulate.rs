@@ -0,0 +1,56 @@
+// Every fixture of simulate/fixtures played by the built executable. The stub
+// yt-dlp is a shell script, hence Unix only
+#![cfg(unix)]
+
+mod common;
+
+use common::{Sandbox, HOST};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const FIXTURES_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/simulate/fixtures");
+
+fn fixtures() -> Vec<PathBuf> {
+    let mut fixtures = fs::read_dir(FIXTURES_DIR)
+        .expect("fixtures folder")
+        .map(|entry| entry.expect("fixture entry").path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+        .collect::<Vec<_>>();
+    fixtures.sort();
+    fixtures
+}
+
+fn simulate(fixture: &Path, sandbox: &Sandbox) -> Result<(), String> {
+    // The simulator's own start-up migrates settings, so it gets a data folder
+    // of its own as well as the hosts it starts
+    let output = Command::new(HOST)
+        .arg("--simulate-extension")
+        .arg(fixture)
+        .env("XDG_DATA_HOME", &sandbox.data_home)
+        .env("LOCALAPPDATA", &sandbox.data_home)
+        .output()
+        .map_err(|e| format!("Failed to start the simulator: {}", e))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    Err(format!(
+        "{} exited with {}:\n{}{}",
+        fixture.display(),
+        output.status,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    ))
+}
+
+#[test]
+fn every_fixture_passes() {
+    let fixtures = fixtures();
+    assert!(!fixtures.is_empty(), "no fixtures in {}", FIXTURES_DIR);
+    let sandbox = Sandbox::create("simulate");
+    let failures = fixtures
+        .iter()
+        .filter_map(|fixture| simulate(fixture, &sandbox).err())
+        .collect::<Vec<_>>();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
@@ -2,17 +2,35 @@ import { useState, useEffect } from 'react';
 import { invoke } from '@tauri-apps/api/tauri';
 import { listen } from '@tauri-apps/api/event';
 
+const ARTIFACT_STATUS_COLORS = {
+  created: '#065f46',
+  removed: '#065f46',
+  failed: '#991b1b',
+  rolled_back: '#92400e',
+};
+
 function App() {
   const [isRegistered, setIsRegistered] = useState(false);
   const [loading, setLoading] = useState(false);
   const [message, setMessage] = useState('');
+  // Artifacts of the last register/unregister, shown as a checklist
+  const [artifacts, setArtifacts] = useState([]);
   const [extensionId, setExtensionId] = useState('johjkjkidbedgjmogpekmlpfakccnoan');
   const [activeTab, setActiveTab] = useState('register'); // 'register' or 'logs'
   const [logs, setLogs] = useState([]);
   const [testUrl, setTestUrl] = useState('https://www.youtube.com/watch?v=1O0yazhqaxs');
   const [downloading, setDownloading] = useState(false);
+  const [activeJobId, setActiveJobId] = useState(null);
+  const [cancelling, setCancelling] = useState(false);
   const [hideWindow, setHideWindow] = useState(true);
   const [reloadingPath, setReloadingPath] = useState(false);
+  const [checkingSubscriptions, setCheckingSubscriptions] = useState(false);
+  const [diagnosing, setDiagnosing] = useState(false);
+  const [clipboardUrl, setClipboardUrl] = useState(null);
+  // Downloads waiting to be told what to do about an existing file
+  const [conflicts, setConflicts] = useState([]);
+  const [applyToAll, setApplyToAll] = useState(false);
+  const [profiles, setProfiles] = useState([]);
   const [cookieStatus, setCookieStatus] = useState({
     available: false,
     message: 'Checking cookies.txt...',
@@ -28,6 +46,12 @@ function App() {
 
       await checkRegistrationStatus();
       await refreshCookieStatus();
+      await refreshProfiles();
+      try {
+        setConflicts(await invoke('get_pending_conflicts'));
+      } catch (error) {
+        console.error('Failed to load pending conflicts:', error);
+      }
     };
 
     initializeApp();
@@ -40,9 +64,25 @@ function App() {
       };
       setLogs(prev => [...prev, logEntry]);
     });
+
+    // A link copied in another app, offered by the clipboard watcher
+    const unlistenClipboard = listen('clipboard-url', (event) => {
+      setClipboardUrl(event.payload);
+    });
+    
+    const unlistenConflict = listen('download-conflict', (event) => {
+      setConflicts(prev => [...prev.filter(c => c.jobId !== event.payload.jobId), event.payload]);
+    });
+    // Answered here, by "apply to all" or by the timeout
+    const unlistenConflictResolved = listen('download-conflict-resolved', (event) => {
+      setConflicts(prev => prev.filter(c => c.jobId !== event.payload.jobId));
+    });
     
     return () => {
       unlisten.then(fn => fn());
+      unlistenClipboard.then(fn => fn());
+      unlistenConflict.then(fn => fn());
+      unlistenConflictResolved.then(fn => fn());
     };
   }, []);
 
@@ -69,10 +109,16 @@ function App() {
     setLoading(true);
     setMessage('');
     
+    setArtifacts([]);
     try {
-      await invoke('register_host', { extensionId: extensionId.trim() });
-      setIsRegistered(true);
-      setMessage('Successfully registered ImgVault Native Host!');
+      const report = await invoke('register_host', { extensionId: extensionId.trim() });
+      setArtifacts(report.artifacts);
+      if (report.success) {
+        setIsRegistered(true);
+        setMessage('Successfully registered ImgVault Native Host!');
+      } else {
+        setMessage('Registration failed; nothing was left half-registered. See the steps below.');
+      }
       // Recheck registration status
       await checkRegistrationStatus();
     } catch (error) {
@@ -86,10 +132,16 @@ function App() {
     setLoading(true);
     setMessage('');
     
+    setArtifacts([]);
     try {
-      await invoke('unregister_host');
-      setIsRegistered(false);
-      setMessage('Successfully unregistered ImgVault Native Host!');
+      const report = await invoke('unregister_host');
+      setArtifacts(report.artifacts);
+      if (report.success) {
+        setIsRegistered(false);
+        setMessage('Successfully unregistered ImgVault Native Host!');
+      } else {
+        setMessage('Unregister failed. See the steps below.');
+      }
     } catch (error) {
       setMessage(`Unregister failed: ${error}`);
     } finally {
@@ -111,7 +163,9 @@ function App() {
       return;
     }
 
+    const jobId = `gui-${Date.now()}`;
     setDownloading(true);
+    setActiveJobId(jobId);
     addLog(`📥 Starting download: ${testUrl}`);
 
     try {
@@ -120,10 +174,13 @@ function App() {
       addLog(`📂 Output template: ${outputPath}`);
       addLog(`⏳ Executing yt-dlp...`);
 
-      const result = await invoke('test_download', { 
-        url: testUrl,
-        outputPath: outputPath,
-        hideWindow: hideWindow
+      const result = await invoke('test_download', {
+        request: {
+          url: testUrl,
+          outputPath: outputPath,
+          hideWindow: hideWindow,
+          jobId
+        }
       });
 
       addLog(`✅ Download successful!`);
@@ -143,7 +200,11 @@ function App() {
         parsedError = null;
       }
 
-      addLog(`❌ Download failed: ${parsedError?.message || error}`);
+      if (parsedError?.cancelled) {
+        addLog('⏹️ Download cancelled by user');
+      } else {
+        addLog(`❌ Download failed: ${parsedError?.message || error}`);
+      }
 
       if (parsedError?.stdout?.trim()) {
         parsedError.stdout.split(/\r?\n/).filter(Boolean).forEach((line) => addLog(`[yt-dlp stdout] ${line}`));
@@ -154,9 +215,87 @@ function App() {
       }
     } finally {
       setDownloading(false);
+      setActiveJobId(null);
+    }
+  };
+
+  const handleCancelDownload = async () => {
+    if (!activeJobId) {
+      return;
+    }
+
+    setCancelling(true);
+
+    try {
+      await invoke('cancel_download', { jobId: activeJobId });
+      addLog('⏹️ Cancelling download...');
+    } catch (error) {
+      addLog(`Failed to cancel download: ${error}`);
+    } finally {
+      setCancelling(false);
     }
   };
 
+  const handleClipboardDownload = async () => {
+    const url = clipboardUrl;
+    setClipboardUrl(null);
+
+    try {
+      await invoke('confirm_clipboard_download', { url });
+    } catch (error) {
+      addLog(`Failed to download copied link: ${error}`);
+    }
+  };
+
+  const handleClipboardDismiss = async () => {
+    setClipboardUrl(null);
+    await invoke('dismiss_clipboard_download');
+  };
+
+  const handleResolveConflict = async (jobId, decision) => {
+    try {
+      await invoke('resolve_conflict', { jobId, decision, applyToAll });
+      addLog(`📝 ${decision} chosen for the existing file of ${jobId}`);
+    } catch (error) {
+      addLog(`Failed to resolve conflict: ${error}`);
+    }
+  };
+
+  const formatSize = (bytes) => (bytes == null ? 'unknown size' : `${(bytes / 1048576).toFixed(1)} MB`);
+
+  // Dropped files reach the backend as a window event; links and text come here
+  const handleDrop = async (event) => {
+    event.preventDefault();
+    const text = event.dataTransfer.getData('text/uri-list') || event.dataTransfer.getData('text/plain');
+    if (!text) {
+      return;
+    }
+
+    try {
+      await invoke('drop_text', { items: [text] });
+    } catch (error) {
+      addLog(`Failed to handle dropped link: ${error}`);
+    }
+  };
+
+  const refreshProfiles = async () => {
+    try {
+      setProfiles(await invoke('list_profiles'));
+    } catch (error) {
+      console.error('Failed to list profiles:', error);
+    }
+  };
+
+  const handleProfileChange = async (name) => {
+    try {
+      const profile = await invoke('set_active_profile', { name });
+      addLog(`Switched to profile ${profile.name}, saving to ${profile.vaultDirectory}`);
+    } catch (error) {
+      addLog(`Failed to switch profile: ${error}`);
+    }
+    await refreshProfiles();
+  };
+
   const handleReloadPath = async () => {
     setReloadingPath(true);
 
@@ -170,6 +309,45 @@ function App() {
     }
   };
 
+  const handleCheckSubscriptions = async () => {
+    setCheckingSubscriptions(true);
+
+    try {
+      const checks = await invoke('check_subscriptions');
+      if (checks.length === 0) {
+        addLog('No subscriptions yet. Archive a channel from the extension first.');
+      }
+      checks.forEach((check) => {
+        addLog(check.error
+          ? `${check.subscription.url}: ${check.error}`
+          : `${check.subscription.url}: ${check.newItems} new item(s)`);
+      });
+    } catch (error) {
+      addLog(`Failed to check subscriptions: ${error}`);
+    } finally {
+      setCheckingSubscriptions(false);
+    }
+  };
+
+  const handleDiagnoseUrl = async () => {
+    setDiagnosing(true);
+
+    try {
+      const report = await invoke('diagnose_url', { url: testUrl.trim() });
+      report.steps.forEach((step) => {
+        addLog(`[${step.status}] ${step.id} (${step.durationMs} ms): ${step.message}`);
+      });
+      addLog(report.failedStep
+        ? `Diagnosis: ${report.failedStep} failed: ${report.firstError}`
+        : 'Diagnosis: no step failed');
+      console.log('URL diagnosis:', JSON.stringify(report, null, 2));
+    } catch (error) {
+      addLog(`Failed to diagnose URL: ${error}`);
+    } finally {
+      setDiagnosing(false);
+    }
+  };
+
   const refreshCookieStatus = async () => {
     try {
       const message = await invoke('check_cookies');
@@ -186,9 +364,66 @@ function App() {
   };
 
   return (
-    <div style={styles.container}>
+    <div style={styles.container} onDragOver={(e) => e.preventDefault()} onDrop={handleDrop}>
       <div style={styles.card}>
         <h1 style={styles.title}>ImgVault Native Host</h1>
+
+        {profiles.length > 1 && (
+          <div style={styles.profilePicker}>
+            <label style={styles.label}>Profile</label>
+            <select
+              value={profiles.find((profile) => profile.active)?.name || 'default'}
+              onChange={(e) => handleProfileChange(e.target.value)}
+              style={styles.input}
+            >
+              {profiles.map((profile) => (
+                <option key={profile.name} value={profile.name}>
+                  {profile.name}
+                </option>
+              ))}
+            </select>
+          </div>
+        )}
+
+        {clipboardUrl && (
+          <div style={styles.clipboardPrompt}>
+            <div style={styles.clipboardUrl}>Download copied link? {clipboardUrl}</div>
+            <button onClick={handleClipboardDownload} style={styles.downloadButton}>
+              Download
+            </button>
+            <button onClick={handleClipboardDismiss} style={styles.secondaryButton}>
+              Dismiss
+            </button>
+          </div>
+        )}
+        
+        {conflicts.map((conflict) => (
+          <div key={conflict.jobId} style={styles.conflictPrompt}>
+            <div style={styles.clipboardUrl}>
+              Already in the vault: {conflict.path}
+              <div style={styles.conflictDetails}>
+                Existing: {formatSize(conflict.existingSize)}
+                {conflict.existingModifiedAt && `, ${new Date(conflict.existingModifiedAt).toLocaleString()}`}
+                {conflict.existingSha256 && `, sha256 ${conflict.existingSha256.slice(0, 12)}…`}
+                {' · '}Incoming: {formatSize(conflict.incomingSize)}
+                {' · '}{conflict.defaultDecision} at {new Date(conflict.decideBy).toLocaleTimeString()}
+              </div>
+            </div>
+            <button onClick={() => handleResolveConflict(conflict.jobId, 'rename')} style={styles.downloadButton}>
+              Keep both
+            </button>
+            <button onClick={() => handleResolveConflict(conflict.jobId, 'overwrite')} style={styles.secondaryButton}>
+              Overwrite
+            </button>
+            <button onClick={() => handleResolveConflict(conflict.jobId, 'skip')} style={styles.secondaryButton}>
+              Skip
+            </button>
+            <label style={styles.conflictDetails}>
+              <input type="checkbox" checked={applyToAll} onChange={(e) => setApplyToAll(e.target.checked)} />
+              Apply to all
+            </label>
+          </div>
+        ))}
         
         {/* Tabs */}
         <div style={styles.tabs}>
@@ -235,6 +470,26 @@ function App() {
           </div>
         )}
 
+        {artifacts.length > 0 && (
+          <ul style={styles.artifactList}>
+            {artifacts.map((artifact) => (
+              <li key={`${artifact.kind}-${artifact.location}`} style={styles.artifact}>
+                <span style={{
+                  ...styles.artifactStatus,
+                  color: ARTIFACT_STATUS_COLORS[artifact.status] || '#4b5563'
+                }}>
+                  {artifact.status.replace('_', ' ')}
+                </span>
+                <span style={{ wordBreak: 'break-all' }}>
+                  {artifact.location}
+                  {!artifact.required && ' (optional)'}
+                  {artifact.error && <div style={{ color: '#991b1b' }}>{artifact.error}</div>}
+                </span>
+              </li>
+            ))}
+          </ul>
+        )}
+
         {!isRegistered && (
           <>
             <div style={{ marginBottom: '20px' }}>
@@ -346,6 +601,18 @@ function App() {
                 >
                   {downloading ? '⏳ Downloading...' : '📥 Test Download'}
                 </button>
+                {downloading && (
+                  <button
+                    onClick={handleCancelDownload}
+                    disabled={cancelling}
+                    style={{
+                      ...styles.secondaryButton,
+                      opacity: cancelling ? 0.6 : 1
+                    }}
+                  >
+                    {cancelling ? 'Cancelling...' : 'Cancel'}
+                  </button>
+                )}
                 <button
                   onClick={handleReloadPath}
                   disabled={reloadingPath}
@@ -356,6 +623,26 @@ function App() {
                 >
                   {reloadingPath ? 'Reloading...' : 'Reload PATH'}
                 </button>
+                <button
+                  onClick={handleCheckSubscriptions}
+                  disabled={checkingSubscriptions}
+                  style={{
+                    ...styles.secondaryButton,
+                    opacity: checkingSubscriptions ? 0.6 : 1
+                  }}
+                >
+                  {checkingSubscriptions ? 'Checking...' : 'Check Subscriptions'}
+                </button>
+                <button
+                  onClick={handleDiagnoseUrl}
+                  disabled={diagnosing || !testUrl.trim()}
+                  style={{
+                    ...styles.secondaryButton,
+                    opacity: diagnosing || !testUrl.trim() ? 0.6 : 1
+                  }}
+                >
+                  {diagnosing ? 'Diagnosing...' : 'Diagnose URL'}
+                </button>
               </div>
             </div>
 
@@ -447,6 +734,23 @@ const styles = {
     fontSize: '14px',
     fontWeight: '500',
   },
+  artifactList: {
+    listStyle: 'none',
+    padding: 0,
+    margin: '0 0 20px 0',
+    fontSize: '13px',
+  },
+  artifact: {
+    display: 'flex',
+    gap: '10px',
+    padding: '6px 0',
+    borderBottom: '1px solid #e5e7eb',
+  },
+  artifactStatus: {
+    minWidth: '90px',
+    fontWeight: '600',
+    textTransform: 'capitalize',
+  },
   button: {
     width: '100%',
     padding: '15px',
@@ -561,6 +865,39 @@ const styles = {
     transition: 'all 0.2s',
     whiteSpace: 'nowrap',
   },
+  clipboardPrompt: {
+    display: 'flex',
+    alignItems: 'center',
+    gap: '10px',
+    padding: '12px',
+    marginBottom: '20px',
+    backgroundColor: '#eef2ff',
+    borderRadius: '8px',
+    fontSize: '14px',
+  },
+  conflictPrompt: {
+    display: 'flex',
+    alignItems: 'center',
+    gap: '10px',
+    padding: '12px',
+    marginBottom: '20px',
+    backgroundColor: '#fef3c7',
+    borderRadius: '8px',
+    fontSize: '14px',
+  },
+  conflictDetails: {
+    fontSize: '12px',
+    color: '#6b7280',
+  },
+  profilePicker: {
+    marginBottom: '20px',
+  },
+  clipboardUrl: {
+    flex: 1,
+    overflow: 'hidden',
+    textOverflow: 'ellipsis',
+    whiteSpace: 'nowrap',
+  },
   logsHeader: {
     display: 'flex',
     justifyContent: 'space-between',
@@ -2077,6 +2077,9 @@ class ImgVaultServiceWorker {
               requestId: response.requestId || activeRequestId,
               stream: response.stream || 'stdout',
               line: response.line || '',
+              phase: response.data?.phase || null,
+              phasePercent: response.data?.phasePercent ?? null,
+              percent: response.data?.percent ?? null,
             }).catch(() => {});
             return;
           }
@@ -2148,6 +2151,25 @@ class ImgVaultServiceWorker {
                 return;
               }
 
+              // Nothing the host has can get this link; the browser downloads
+              // it instead and the host is told where the file ended up
+              if (response.error_code === 'fallback_to_browser' && response.data?.directUrl) {
+                await this.appendNativeDownloadLog(
+                  activeRequestId,
+                  response.message || 'Downloading in the browser instead',
+                  'warning',
+                  'system'
+                );
+                const filePath = await this.downloadInBrowser(activeRequestId, url, response.data);
+                await this.finishActiveNativeDownload(activeRequestId, {
+                  status: 'completed',
+                  filePath,
+                  lastMessage: 'Downloaded by the browser',
+                });
+                resolve({ ...response, success: true, filePath });
+                return;
+              }
+
               await this.finishActiveNativeDownload(activeRequestId, {
                 status: 'failed',
                 error: response.message || 'Native host download failed',
@@ -2232,6 +2254,8 @@ class ImgVaultServiceWorker {
             output_path: outputPath,
             cookies_data: cookies,
             request_id: activeRequestId,
+            // Sent when the host's user agent setting is "browser"
+            browser_user_agent: navigator.userAgent,
           });
           // console.log(`✉️ [NATIVE] Message sent to native host:`, {
           //   action: 'download',
@@ -2258,6 +2282,47 @@ class ImgVaultServiceWorker {
     }
   }
 
+  // Downloads what the native host left to the browser and, once it is
+  // saved, registers the file with the host under the same request id
+  async downloadInBrowser(requestId, url, fallback) {
+    const downloadId = await chrome.downloads.download({
+      url: fallback.directUrl,
+      ...(fallback.fileName ? { filename: fallback.fileName } : {}),
+    });
+
+    const filePath = await new Promise((resolve, reject) => {
+      const onChanged = (delta) => {
+        if (delta.id !== downloadId || !delta.state) {
+          return;
+        }
+        if (delta.state.current === 'complete') {
+          chrome.downloads.onChanged.removeListener(onChanged);
+          chrome.downloads.search({ id: downloadId }).then(
+            ([item]) => resolve(item?.filename || ''),
+            reject
+          );
+        } else if (delta.state.current === 'interrupted') {
+          chrome.downloads.onChanged.removeListener(onChanged);
+          reject(new Error('The browser download was interrupted'));
+        }
+      };
+      chrome.downloads.onChanged.addListener(onChanged);
+    });
+
+    if (filePath) {
+      try {
+        await this.handleNativeHostCommand('register_external_file', {
+          url,
+          file_path: filePath,
+          request_id: requestId,
+        });
+      } catch (error) {
+        console.warn('[NATIVE] Failed to register the browser download:', error);
+      }
+    }
+    return filePath;
+  }
+
   async resolveNativeDownloadFolder() {
     const settings = await new Promise((resolve) => {
       chrome.storage.sync.get(['downloadFolder'], (result) => {